@@ -83,5 +83,12 @@ enum GdbRequestType {
   DREQ_FILE_PREAD,
   // vFile:close packet, uses params.file_close.
   DREQ_FILE_CLOSE,
+
+  // Not in rr.
+  // vAttach packet: debug another process in the trace. Uses params.target.
+  DREQ_ATTACH,
+  // qProcessInfo/qProcessInfoPID packets: metadata about a process. Uses
+  // params.target.
+  DREQ_GET_PROCESS_INFO,
 };
 
@@ -11,6 +11,7 @@
 #include <linux/futex.h>
 #include <linux/if_bonding.h>
 #include <linux/ipc.h>
+#include <linux/kcmp.h>
 #include <linux/mqueue.h>
 #include <linux/msg.h>
 #include <linux/net.h>
@@ -9,6 +9,7 @@
 const unsigned int _VFAT_IOCTL_READDIR_BOTH = VFAT_IOCTL_READDIR_BOTH;;
 const unsigned int _FS_IOC_GETVERSION = FS_IOC_GETVERSION;
 const unsigned int _FS_IOC_GETFLAGS = FS_IOC_GETFLAGS;
+const unsigned int _FIGETBSZ = FIGETBSZ;
 const unsigned int _EVIOCGVERSION = EVIOCGVERSION;
 const unsigned int _EVIOCGID = EVIOCGID;
 const unsigned int _EVIOCGREP = EVIOCGREP;
@@ -33,7 +33,7 @@ fn main() {
 
     Config::new(".")
         .define("CMAKE_BUILD_TYPE", "Release")
-        .define("CMAKE_INSTALL_PREFIX", target_dir)
+        .define("CMAKE_INSTALL_PREFIX", &target_dir)
         .build();
 
     cc::Build::new()
@@ -192,4 +192,31 @@ fn main() {
         .run()
         .unwrap();
     println!("cargo:rerun-if-changed=schema/trace.capnp");
+
+    #[cfg(feature = "capi")]
+    generate_c_header(&target_dir);
+}
+
+/// Write the header for the C API in src/capi.rs to include/rd/rd.h under
+/// `target_dir`, next to what cmake installs there.
+#[cfg(feature = "capi")]
+fn generate_c_header(target_dir: &std::path::Path) {
+    let config = cbindgen::Config {
+        language: cbindgen::Language::C,
+        header: Some(String::from(
+            "/* Generated from src/capi.rs by build.rs with cbindgen. Don't edit. */",
+        )),
+        include_guard: Some(String::from("RD_H_")),
+        cpp_compat: true,
+        ..Default::default()
+    };
+    let header = target_dir.join("include/rd/rd.h");
+    std::fs::create_dir_all(header.parent().unwrap()).unwrap();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/capi.rs")
+        .generate()
+        .expect("Generating the C API header failed")
+        .write_to_file(header);
+    println!("cargo:rerun-if-changed=src/capi.rs");
 }
@@ -35,16 +35,20 @@ install -v -m 0644 -C target/share/rd/rd_page_64_replay "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/rd_page_32 "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/rd_page_32_replay "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/32bit-avx.xml "${PREFIX}/share/rd"
+install -v -m 0644 -C target/share/rd/32bit-avx512.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/32bit-core.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/32bit-linux.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/32bit-sse.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/64bit-avx.xml "${PREFIX}/share/rd"
+install -v -m 0644 -C target/share/rd/64bit-avx512.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/64bit-core.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/64bit-linux.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/64bit-seg.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/64bit-sse.xml "${PREFIX}/share/rd"
+install -v -m 0644 -C target/share/rd/amd64-avx-avx512-linux.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/amd64-avx-linux.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/amd64-linux.xml "${PREFIX}/share/rd"
+install -v -m 0644 -C target/share/rd/i386-avx-avx512-linux.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/i386-avx-linux.xml "${PREFIX}/share/rd"
 install -v -m 0644 -C target/share/rd/i386-linux.xml "${PREFIX}/share/rd"
 
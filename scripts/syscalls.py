@@ -1516,7 +1516,7 @@ utimensat = EmulatedSyscall(x86=320, x64=280)
 # argument. The latter system call implements the flags values described
 # above. Starting with glibc 2.9, the signalfd() wrapper function will
 # use signalfd4() where it is available.
-signalfd = EmulatedSyscall(x86=321, x64=282)
+signalfd = IrregularEmulatedSyscall(x86=321, x64=282)
 
 #  int timerfd_create(int clockid, int flags);
 #
@@ -1554,7 +1554,7 @@ timerfd_gettime = EmulatedSyscall(x86=326, x64=287, arg2="Arch::itimerspec")
 # argument. The latter system call implements the flags values described
 # above. Starting with glibc 2.9, the signalfd() wrapper function will
 # use signalfd4() where it is available.
-signalfd4 = EmulatedSyscall(x86=327, x64=289)
+signalfd4 = IrregularEmulatedSyscall(x86=327, x64=289)
 
 #  int eventfd(unsigned int initval, int flags);
 #
@@ -1652,7 +1652,7 @@ sendmmsg = IrregularEmulatedSyscall(x86=345, x64=307)
 setns = EmulatedSyscall(x86=346, x64=308)
 process_vm_readv = IrregularEmulatedSyscall(x86=347, x64=310)
 process_vm_writev = IrregularEmulatedSyscall(x86=348, x64=311)
-kcmp = EmulatedSyscall(x86=349, x64=312)
+kcmp = IrregularEmulatedSyscall(x86=349, x64=312)
 finit_module = UnsupportedSyscall(x86=350, x64=313)
 renameat2 = EmulatedSyscall(x86=353, x64=316)
 seccomp = IrregularEmulatedSyscall(x86=354, x64=317)
@@ -1666,9 +1666,9 @@ userfaultfd = UnsupportedSyscall(x86=374, x64=323)
 membarrier = EmulatedSyscall(x86=375, x64=324)
 mlock2 = UnsupportedSyscall(x86=376, x64=325)
 copy_file_range = IrregularEmulatedSyscall(x86=377, x64=326)
-preadv2 = UnsupportedSyscall(x86=378, x64=327)
-pwritev2 = UnsupportedSyscall(x86=379, x64=328)
-pkey_mprotect = UnsupportedSyscall(x86=380, x64=329)
+preadv2 = IrregularEmulatedSyscall(x86=378, x64=327)
+pwritev2 = EmulatedSyscall(x86=379, x64=328)
+pkey_mprotect = IrregularEmulatedSyscall(x86=380, x64=329)
 pkey_alloc = UnsupportedSyscall(x86=381, x64=330)
 pkey_free = UnsupportedSyscall(x86=382, x64=331)
 statx = EmulatedSyscall(x86=383, x64=332, arg5="Arch::statx")
@@ -1698,15 +1698,44 @@ sched_rr_get_interval_time64 = UnsupportedSyscall(x86=423)
 
 # x86-64 decided to skip ahead here to catchup
 pidfd_send_signal = UnsupportedSyscall(x86=424, x64=424)
-io_uring_setup = UnsupportedSyscall(x86=425, x64=425)
-io_uring_enter = UnsupportedSyscall(x86=426, x64=426)
-io_uring_register = UnsupportedSyscall(x86=427, x64=427)
-open_tree = UnsupportedSyscall(x86=428, x64=428)
-move_mount = UnsupportedSyscall(x86=429, x64=429)
-fsopen = UnsupportedSyscall(x86=430, x64=430)
-fsconfig = UnsupportedSyscall(x86=431, x64=431)
-fsmount = UnsupportedSyscall(x86=432, x64=432)
-fspick = UnsupportedSyscall(x86=433, x64=433)
+#  int io_uring_setup(u32 entries, struct io_uring_params *p);
+#  int io_uring_enter(unsigned int fd, unsigned int to_submit,
+#                     unsigned int min_complete, unsigned int flags, sigset_t *sig);
+#  int io_uring_register(unsigned int fd, unsigned int opcode, void *arg,
+#                        unsigned int nr_args);
+#
+# io_uring is never allowed to run: the kernel posts completions into memory
+# shared with the tracee, bypassing syscalls. Depending on `rd record --io-uring`
+# rd fails these with ENOSYS or stops recording.
+io_uring_setup = IrregularEmulatedSyscall(x86=425, x64=425)
+io_uring_enter = IrregularEmulatedSyscall(x86=426, x64=426)
+io_uring_register = IrregularEmulatedSyscall(x86=427, x64=427)
+
+#  int open_tree(int dfd, const char *filename, unsigned int flags);
+#  int move_mount(int from_dfd, const char *from_pathname, int to_dfd,
+#                 const char *to_pathname, unsigned int flags);
+#  int fsopen(const char *fs_name, unsigned int flags);
+#  int fsconfig(int fd, unsigned int cmd, const char *key, const void *value,
+#               int aux);
+#  int fsmount(int fs_fd, unsigned int flags, unsigned int attr_flags);
+#  int fspick(int dfd, const char *path, unsigned int flags);
+#
+# The new mount API. Container tooling probes with these (e.g. whether
+# fsopen("overlay") works) even when it ends up not mounting anything, so
+# only the results are recorded. The kernel writes nothing to tracee memory;
+# error messages are read() from the fs context fd.
+open_tree = EmulatedSyscall(x86=428, x64=428)
+move_mount = EmulatedSyscall(x86=429, x64=429)
+fsopen = EmulatedSyscall(x86=430, x64=430)
+fsconfig = EmulatedSyscall(x86=431, x64=431)
+fsmount = EmulatedSyscall(x86=432, x64=432)
+fspick = EmulatedSyscall(x86=433, x64=433)
+
+#  long clone3(struct clone_args *cl_args, size_t size);
+#
+# Like clone(), with the arguments passed in |cl_args|. Replayed as the
+# equivalent clone().
+clone3 = IrregularEmulatedSyscall(x86=435, x64=435)
 
 # restart_syscall is a little special.
 restart_syscall = RestartSyscall(x86=0, x64=219)
@@ -0,0 +1,182 @@
+//! Finding when the memory a pointer points to was allocated, as done by the
+//! `rd-alloc-origin` gdb command and the `rd-bt-origin` backtrace annotation.
+//!
+//! DIFF NOTE: rd has no index of malloc/free calls, so this works at the
+//! granularity of the trace's mapping records: the allocation origin of an
+//! address is the latest mmap/mremap/brk (or exec) before the current event
+//! that mapped memory covering it, in the process being debugged. For blocks
+//! malloc serves with mmap that is the allocation itself; for blocks carved
+//! out of the brk heap or an arena it is the call that grew the heap to
+//! include the block, and `rd-alloc-origin` finds the malloc call itself by
+//! executing backwards to the last write to the block's chunk header.
+
+use crate::{
+    event::EventType,
+    remote_ptr::{RemotePtr, Void},
+    session::address_space::kernel_mapping::KernelMapping,
+    trace::{
+        trace_frame::FrameTime,
+        trace_reader::{TraceReader, ValidateSourceFile},
+        trace_stream::MappedData,
+        trace_task_event::{TraceTaskEvent, TraceTaskEventVariant},
+    },
+};
+use libc::{pid_t, CLONE_VM};
+use std::{collections::HashMap, ffi::OsStr};
+
+pub struct AllocationOrigin {
+    /// The event whose mapping record covers the address.
+    pub time: FrameTime,
+    /// The recorded tid of the task that performed it.
+    pub tid: pid_t,
+    /// e.g. `mmap`, `brk` or the event type when it's not a syscall.
+    pub what: String,
+    pub mapping: KernelMapping,
+}
+
+/// Tracks which address space each recorded tid uses. Address spaces are
+/// numbered in the order they appear; tids that are never cloned (the initial
+/// task) get one when first seen.
+#[derive(Default)]
+struct AddressSpaces {
+    by_tid: HashMap<pid_t, usize>,
+    next: usize,
+}
+
+impl AddressSpaces {
+    fn of(&mut self, tid: pid_t) -> usize {
+        let next = &mut self.next;
+        *self.by_tid.entry(tid).or_insert_with(|| {
+            *next += 1;
+            *next
+        })
+    }
+
+    fn fresh(&mut self, tid: pid_t) {
+        self.next += 1;
+        self.by_tid.insert(tid, self.next);
+    }
+
+    fn apply(&mut self, ev: &TraceTaskEvent) {
+        match ev.event_variant() {
+            TraceTaskEventVariant::Clone(clone) => {
+                if clone.clone_flags() & CLONE_VM as u64 != 0 {
+                    let space = self.of(clone.parent_tid());
+                    self.by_tid.insert(ev.tid(), space);
+                } else {
+                    self.fresh(ev.tid());
+                }
+            }
+            // exec discards the old address space, and the exec's own
+            // mappings are recorded at the same time as the exec.
+            TraceTaskEventVariant::Exec(_) => self.fresh(ev.tid()),
+            TraceTaskEventVariant::Exit(_) => (),
+        }
+    }
+}
+
+/// Scan the trace in `trace_dir` up to and including event `now` and, for
+/// each of `addrs`, find the latest mapping record covering it in the address
+/// space that `tid` is using at `now`.
+pub fn find_allocation_origins(
+    trace_dir: &OsStr,
+    tid: pid_t,
+    now: FrameTime,
+    addrs: &[RemotePtr<Void>],
+) -> Vec<Option<AllocationOrigin>> {
+    let mut trace = TraceReader::new(Some(trace_dir));
+    let mut task_events = Vec::new();
+    loop {
+        let mut time: FrameTime = 0;
+        match trace.read_task_event(Some(&mut time)) {
+            Some(ev) if time <= now => task_events.push((time, ev)),
+            _ => break,
+        }
+    }
+
+    let mut spaces = AddressSpaces::default();
+    let mut next_task_event = task_events.iter().peekable();
+    // The latest covering mapping of each (address space, index in `addrs`).
+    // A later mapping in an address space replaces an earlier one.
+    let mut latest: HashMap<(usize, usize), AllocationOrigin> = HashMap::new();
+    while !trace.at_end() {
+        let frame = trace.read_frame();
+        if frame.time() > now {
+            break;
+        }
+        while let Some((_, ev)) = next_task_event.next_if(|(time, _)| *time <= frame.time()) {
+            spaces.apply(ev);
+        }
+        let space = spaces.of(frame.tid());
+        loop {
+            let mut data = MappedData::default();
+            let km = match trace.read_mapped_region(
+                Some(&mut data),
+                Some(ValidateSourceFile::DontValidate),
+                None,
+                None,
+                None,
+            ) {
+                Some(km) => km,
+                None => break,
+            };
+            for (i, &addr) in addrs.iter().enumerate() {
+                if !km.contains_ptr(addr) {
+                    continue;
+                }
+                if km.prot().is_empty() {
+                    // A brk that shrank the heap.
+                    latest.remove(&(space, i));
+                    continue;
+                }
+                let ev = frame.event();
+                let what = if ev.event_type() == EventType::EvSyscall {
+                    ev.syscall_event().syscall_name()
+                } else {
+                    format!("{:?}", ev.event_type())
+                };
+                latest.insert(
+                    (space, i),
+                    AllocationOrigin {
+                        time: frame.time(),
+                        tid: frame.tid(),
+                        what,
+                        mapping: km.clone(),
+                    },
+                );
+            }
+        }
+    }
+    for ev in next_task_event.map(|(_, ev)| ev) {
+        spaces.apply(ev);
+    }
+
+    let space = spaces.of(tid);
+    (0..addrs.len())
+        .map(|i| latest.remove(&(space, i)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads_share_address_spaces_and_exec_replaces_them() {
+        let mut spaces = AddressSpaces::default();
+        let parent = spaces.of(100);
+        spaces.apply(&TraceTaskEvent::for_clone(
+            101,
+            100,
+            100,
+            101,
+            CLONE_VM as u64,
+        ));
+        spaces.apply(&TraceTaskEvent::for_clone(102, 100, 100, 102, 0));
+        assert_eq!(spaces.of(101), parent);
+        assert_ne!(spaces.of(102), parent);
+        spaces.apply(&TraceTaskEvent::for_exec(101, OsStr::new("/bin/true"), &[]));
+        assert_ne!(spaces.of(101), parent);
+        assert_ne!(spaces.of(101), spaces.of(102));
+    }
+}
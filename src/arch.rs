@@ -689,6 +689,11 @@ pub trait Architecture: 'static + Default {
 
     fn get_iovec(msgdata: &Self::iovec) -> (RemotePtr<Void>, usize);
 
+    /// Returns the `ss_sp`, `ss_flags` and `ss_size` of a stack_t.
+    fn get_stack_t(ss: &Self::stack_t) -> (RemotePtr<Void>, i32, usize);
+
+    fn set_stack_t(ss: &mut Self::stack_t, ss_sp: RemotePtr<Void>, ss_flags: i32, ss_size: usize);
+
     fn set_msghdr(
         msg: &mut Self::msghdr,
         msg_control: RemotePtr<u8>,
@@ -1301,6 +1306,16 @@ impl Architecture for X86Arch {
         (msgdata.iov_base.rptr(), msgdata.iov_len as usize)
     }
 
+    fn get_stack_t(ss: &Self::stack_t) -> (RemotePtr<Void>, i32, usize) {
+        (ss.ss_sp.rptr(), ss.ss_flags, ss.ss_size as usize)
+    }
+
+    fn set_stack_t(ss: &mut Self::stack_t, ss_sp: RemotePtr<Void>, ss_flags: i32, ss_size: usize) {
+        ss.ss_sp = ss_sp.into();
+        ss.ss_flags = ss_flags;
+        ss.ss_size = ss_size.try_into().unwrap();
+    }
+
     fn usize_as_signed_long(v: usize) -> Self::signed_long {
         v as Self::signed_long
     }
@@ -1930,6 +1945,16 @@ impl Architecture for X64Arch {
         (msgdata.iov_base.rptr(), msgdata.iov_len as usize)
     }
 
+    fn get_stack_t(ss: &Self::stack_t) -> (RemotePtr<Void>, i32, usize) {
+        (ss.ss_sp.rptr(), ss.ss_flags, ss.ss_size as usize)
+    }
+
+    fn set_stack_t(ss: &mut Self::stack_t, ss_sp: RemotePtr<Void>, ss_flags: i32, ss_size: usize) {
+        ss.ss_sp = ss_sp.into();
+        ss.ss_flags = ss_flags;
+        ss.ss_size = ss_size as _;
+    }
+
     fn usize_as_signed_long(v: usize) -> Self::signed_long {
         v as Self::signed_long
     }
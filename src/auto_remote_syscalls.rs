@@ -463,10 +463,19 @@ impl<'a> AutoRemoteSyscalls<'a> {
             return;
         }
 
-        let last_stack_byte: RemotePtr<Void> = self.t.regs_ref().sp() - 1usize;
+        let sp = self.t.regs_ref().sp();
+        let last_stack_byte: RemotePtr<Void> = sp - 1usize;
+        // A signal handler running on a small alternate signal stack may have
+        // little of it left, and whatever is below the stack is not ours to
+        // use even if it's mapped.
+        let stack_start = match self.t.sigaltstack() {
+            Some(alt) if alt.contains(sp) => Some(alt.sp),
+            _ => None,
+        };
         match self.t.vm().mapping_of(last_stack_byte) {
             Some(m) => {
-                if is_usable_area(&m.map) && m.map.start() + 2048usize <= self.t.regs_ref().sp() {
+                let start = stack_start.unwrap_or_else(|| m.map.start());
+                if is_usable_area(&m.map) && start + 2048usize <= sp {
                     // 'sp' is in a stack region and there's plenty of space there. No need
                     // to fix anything.
                     return;
@@ -0,0 +1,313 @@
+//! C-callable wrappers around `replay::Replayer`, for tooling that can't link
+//! Rust (Python via ctypes, editor plugins). Built with `--features capi`,
+//! which also has build.rs generate the matching header, target/include/rd/rd.h,
+//! from this file.
+//!
+//! Build the shared library to link against with
+//! `cargo rustc --release --lib --features capi --crate-type cdylib`. Fatal
+//! replay errors abort the process rather than being reported through these
+//! functions. Functions taking an `RdReplayer` fail, as documented for each,
+//! when passed NULL.
+
+use crate::{
+    flags::Flags,
+    gdb_register::GdbRegister,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::RemotePtr,
+    replay::{ReplaySessionBuilder, Replayer},
+    session::session_inner::BreakStatus,
+};
+use libc::{c_char, c_int, c_void};
+use std::{
+    convert::TryFrom,
+    ffi::{CStr, OsStr, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
+    ptr,
+};
+
+pub const RD_STEP_ERROR: c_int = -1;
+pub const RD_STEP_OK: c_int = 0;
+pub const RD_STEP_BREAK: c_int = 1;
+pub const RD_STEP_EXITED: c_int = 2;
+
+pub const RD_STOP_BREAKPOINT: c_int = 1;
+pub const RD_STOP_WATCHPOINT: c_int = 2;
+pub const RD_STOP_SIGNAL: c_int = 3;
+pub const RD_STOP_SINGLESTEP: c_int = 4;
+
+/// Called when a step stops early: the user data pointer, the event the
+/// replay is at, and one of the `RD_STOP_*` reasons. NULL for none.
+pub type RdStopCallback = Option<extern "C" fn(*mut c_void, u64, c_int)>;
+
+/// Opaque to C.
+pub struct RdReplayer {
+    replayer: Replayer,
+    stop_callback: RdStopCallback,
+    stop_user_data: *mut c_void,
+}
+
+/// Look for rd's helper binaries and other resources under `path` (the
+/// directory containing `bin/` and `share/rd/`) instead of next to the
+/// library. Must be called before the first replay is opened; returns false
+/// otherwise.
+///
+/// # Safety
+///
+/// `path` must point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rd_set_resource_path(path: *const c_char) -> bool {
+    let mut path = CStr::from_ptr(path).to_bytes().to_vec();
+    if !path.ends_with(b"/") {
+        path.push(b'/');
+    }
+    Flags::init(Flags {
+        resource_path: Some(PathBuf::from(OsString::from_vec(path))),
+        ..Flags::default()
+    })
+}
+
+/// Start replaying the trace in `trace_dir`, or the latest trace if it is
+/// NULL. Free the result with `rd_replayer_close()`.
+///
+/// # Safety
+///
+/// `trace_dir` must be NULL or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_open(trace_dir: *const c_char) -> *mut RdReplayer {
+    let mut builder = ReplaySessionBuilder::new();
+    if !trace_dir.is_null() {
+        builder = builder.trace_dir(OsStr::from_bytes(CStr::from_ptr(trace_dir).to_bytes()));
+    }
+    Box::into_raw(Box::new(RdReplayer {
+        replayer: builder.build(),
+        stop_callback: None,
+        stop_user_data: ptr::null_mut(),
+    }))
+}
+
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_close(r: *mut RdReplayer) {
+    if !r.is_null() {
+        drop(Box::from_raw(r));
+    }
+}
+
+/// Call `callback` with `user_data` whenever a step returns `RD_STEP_BREAK`.
+/// Pass a NULL callback to remove it. Returns false if `r` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet. `callback` is called with `user_data` from inside later steps.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_set_stop_callback(
+    r: *mut RdReplayer,
+    callback: RdStopCallback,
+    user_data: *mut c_void,
+) -> bool {
+    match r.as_mut() {
+        Some(r) => {
+            r.stop_callback = callback;
+            r.stop_user_data = user_data;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run to the end of the current event or the next breakpoint/watchpoint.
+/// Returns one of the `RD_STEP_*` values; `RD_STEP_ERROR` if `r` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_step(r: *mut RdReplayer) -> c_int {
+    match r.as_mut() {
+        Some(r) => {
+            let result = r.replayer.step();
+            r.after_step(result.map(|result| result.break_status))
+        }
+        None => RD_STEP_ERROR,
+    }
+}
+
+/// Execute a single instruction. Returns one of the `RD_STEP_*` values;
+/// `RD_STEP_ERROR` if `r` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_singlestep(r: *mut RdReplayer) -> c_int {
+    match r.as_mut() {
+        Some(r) => {
+            let result = r.replayer.singlestep();
+            r.after_step(result.map(|result| result.break_status))
+        }
+        None => RD_STEP_ERROR,
+    }
+}
+
+/// The event the replay is at, or `UINT64_MAX` if `r` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_current_event(r: *const RdReplayer) -> u64 {
+    match r.as_ref() {
+        Some(r) => r.replayer.current_frame_time(),
+        None => u64::MAX,
+    }
+}
+
+/// The current task's tid as recorded, or -1 if there is none or `r` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_current_tid(r: *const RdReplayer) -> c_int {
+    match r.as_ref().and_then(|r| r.replayer.current_task()) {
+        Some(t) => t.rec_tid(),
+        None => -1,
+    }
+}
+
+/// Read general purpose register `regno` (gdb's numbering for the task's
+/// architecture) of the current task into `*value`. Returns false if `r` or
+/// `value` is NULL, or there's no current task or no such register.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet. `value` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_read_register(
+    r: *const RdReplayer,
+    regno: u32,
+    value: *mut u64,
+) -> bool {
+    let r = match r.as_ref() {
+        Some(r) if !value.is_null() => r,
+        _ => return false,
+    };
+    let regs = match r.replayer.registers() {
+        Some(regs) => regs,
+        None => return false,
+    };
+    let regno = match GdbRegister::try_from(regno) {
+        Ok(regno) => regno,
+        Err(_) => return false,
+    };
+    let mut buf = [0u8; 8];
+    match regs.read_register(&mut buf, regno) {
+        Some(_) => {
+            *value = u64::from_le_bytes(buf);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Copy up to `len` bytes of the current task's memory at `addr` into `buf`.
+/// Returns the number of bytes read, which is short if an unmapped page was
+/// reached, or -1 if there is no current task or `r` or `buf` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet. `buf` must be NULL or valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_read_memory(
+    r: *const RdReplayer,
+    addr: u64,
+    buf: *mut u8,
+    len: usize,
+) -> isize {
+    let r = match r.as_ref() {
+        Some(r) if !buf.is_null() => r,
+        _ => return -1,
+    };
+    match r.replayer.read_memory(RemotePtr::new(addr as usize), len) {
+        Some(data) => {
+            ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
+            data.len() as isize
+        }
+        None => -1,
+    }
+}
+
+/// Stop steps when any task reaches `addr`. Returns false if the breakpoint
+/// couldn't be set or `r` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_add_breakpoint(r: *mut RdReplayer, addr: u64) -> bool {
+    match r.as_ref() {
+        Some(r) => r
+            .replayer
+            .add_breakpoint(RemoteCodePtr::from(addr as usize)),
+        None => false,
+    }
+}
+
+/// Remove a breakpoint set with `rd_replayer_add_breakpoint()`. Returns false
+/// if `r` is NULL.
+///
+/// # Safety
+///
+/// `r` must be NULL or have been returned by `rd_replayer_open()` and not
+/// closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rd_replayer_remove_breakpoint(r: *mut RdReplayer, addr: u64) -> bool {
+    match r.as_ref() {
+        Some(r) => {
+            r.replayer
+                .remove_breakpoint(RemoteCodePtr::from(addr as usize));
+            true
+        }
+        None => false,
+    }
+}
+
+impl RdReplayer {
+    fn after_step(&self, maybe_break_status: Option<BreakStatus>) -> c_int {
+        let break_status = match maybe_break_status {
+            Some(break_status) => break_status,
+            None => return RD_STEP_EXITED,
+        };
+        let reason = if break_status.breakpoint_hit {
+            RD_STOP_BREAKPOINT
+        } else if !break_status.watchpoints_hit.is_empty() {
+            RD_STOP_WATCHPOINT
+        } else if break_status.signal.is_some() {
+            RD_STOP_SIGNAL
+        } else if break_status.singlestep_complete {
+            RD_STOP_SINGLESTEP
+        } else {
+            return RD_STEP_OK;
+        };
+        if let Some(callback) = self.stop_callback {
+            callback(
+                self.stop_user_data,
+                self.replayer.current_frame_time(),
+                reason,
+            );
+        }
+        RD_STEP_BREAK
+    }
+}
@@ -1,17 +1,29 @@
 use exit_result::ExitResult;
 
+pub mod assert_command;
+pub mod browse_command;
 pub mod build_id_command;
+pub mod checksum_command;
+pub mod coverage_command;
 pub mod dump_command;
+pub mod entropy_command;
 pub mod exit_result;
+pub mod fsck_command;
 pub mod gdb_command;
 pub mod gdb_command_handler;
 pub mod gdb_server;
+pub mod mount_command;
+pub mod pack_command;
 pub mod ps_command;
 pub mod rd_options;
 pub mod record_command;
 pub mod replay_command;
 pub mod rerun_command;
+pub mod rm_command;
+pub mod sched_command;
+pub mod stats_command;
 pub mod trace_info_command;
+pub mod triage_command;
 
 pub trait RdCommand {
     fn run(&mut self) -> ExitResult<()>;
@@ -0,0 +1,292 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    condition_expression::ConditionExpression,
+    gdb_expression::ExpressionTarget,
+    gdb_register::GdbRegister,
+    memory_search::Symbolizer,
+    remote_code_ptr::RemoteCodePtr,
+    replay::{ReplaySessionBuilder, Replayer},
+    session::task::Task,
+    taskish_uid::AddressSpaceUid,
+    trace::{
+        trace_dir::{trace_data_discarded, TraceDir},
+        trace_frame::FrameTime,
+    },
+};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// Replays a trace and checks the assertions of a spec file along the way:
+///
+/// ```yaml
+/// assertions:
+///   - name: counter after first foo
+///     function: foo
+///     expect: "*(i32 *)g_count == 3"
+///   - function: bar
+///     hit: 2
+///     expect: "$rdi != 0"
+///   - event: 1500
+///     expect: "*(u64 *)g_total > 100"
+/// ```
+pub struct AssertCommand {
+    spec: PathBuf,
+    trace_dir: Option<PathBuf>,
+}
+
+impl AssertCommand {
+    pub fn new(options: &RdOptions) -> AssertCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Assert { spec, trace_dir } => AssertCommand { spec, trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not an `Assert` variant!"),
+        }
+    }
+}
+
+impl RdCommand for AssertCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        let mut assertions = match load_spec(&self.spec) {
+            Ok(assertions) => assertions,
+            Err(e) => return ExitResult::err_from(e, 1),
+        };
+        let trace_dir = TraceDir::from_env().resolve(self.trace_dir.as_ref());
+        if trace_data_discarded(&trace_dir) {
+            return ExitResult::err_from(
+                Error::new(
+                    ErrorKind::Other,
+                    format!("The data of trace {:?} was discarded", trace_dir),
+                ),
+                1,
+            );
+        }
+
+        let mut replayer = ReplaySessionBuilder::new().trace_dir(&trace_dir).build();
+        check_assertions(&mut replayer, &mut assertions);
+
+        let mut failed = 0;
+        for a in &assertions {
+            match &a.outcome {
+                Some(Ok(())) => println!("PASS {}", a.name),
+                Some(Err(e)) => {
+                    failed += 1;
+                    println!("FAIL {}: {}", a.name, e);
+                }
+                None => {
+                    failed += 1;
+                    println!("FAIL {}: {}", a.name, a.trigger.not_reached());
+                }
+            }
+        }
+        if failed > 0 {
+            return ExitResult::err_from(
+                Error::new(
+                    ErrorKind::Other,
+                    format!("{} of {} assertions failed", failed, assertions.len()),
+                ),
+                1,
+            );
+        }
+        ExitResult::Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    assertions: Vec<AssertionSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AssertionSpec {
+    /// How the assertion is reported. Defaults to `expect`.
+    name: Option<String>,
+    function: Option<String>,
+    /// Which entry to `function` to check at, counting from 1.
+    hit: Option<u64>,
+    event: Option<FrameTime>,
+    expect: String,
+}
+
+enum Trigger {
+    Function {
+        name: String,
+        hit: u64,
+        hits: u64,
+        /// Where the function's breakpoint is set in each address space it's
+        /// been found in.
+        breakpoints: Vec<(AddressSpaceUid, RemoteCodePtr)>,
+    },
+    Event(FrameTime),
+}
+
+impl Trigger {
+    fn not_reached(&self) -> String {
+        match self {
+            Trigger::Function {
+                name, hit, hits, ..
+            } => format!(
+                "`{}` was entered {} times, never reaching hit {}",
+                name, hits, hit
+            ),
+            Trigger::Event(time) => format!("replay never reached event {}", time),
+        }
+    }
+}
+
+struct Assertion {
+    name: String,
+    trigger: Trigger,
+    expect: ConditionExpression,
+    /// None until the assertion has been checked.
+    outcome: Option<Result<(), String>>,
+}
+
+fn load_spec(path: &Path) -> io::Result<Vec<Assertion>> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, msg));
+    let spec: Spec =
+        serde_yaml::from_slice(&fs::read(path)?).map_err(|e| invalid(e.to_string()))?;
+    let mut assertions = Vec::new();
+    for (i, a) in spec.assertions.into_iter().enumerate() {
+        let trigger = match (a.function, a.event, a.hit) {
+            (Some(_), None, Some(0)) => {
+                return Err(invalid(format!("assertion {}: hits count from 1", i + 1)));
+            }
+            (Some(name), None, hit) => Trigger::Function {
+                name,
+                hit: hit.unwrap_or(1),
+                hits: 0,
+                breakpoints: Vec::new(),
+            },
+            (None, Some(time), None) => Trigger::Event(time),
+            _ => {
+                return Err(invalid(format!(
+                    "assertion {}: needs either a `function` (and optionally `hit`) or an `event`",
+                    i + 1
+                )));
+            }
+        };
+        let expect = a
+            .expect
+            .parse::<ConditionExpression>()
+            .map_err(|e| invalid(format!("assertion {}: {}", i + 1, e)))?;
+        assertions.push(Assertion {
+            name: a.name.unwrap_or_else(|| a.expect.clone()),
+            trigger,
+            expect,
+            outcome: None,
+        });
+    }
+    Ok(assertions)
+}
+
+/// Replay until every assertion has been checked or the trace ends.
+fn check_assertions(replayer: &mut Replayer, assertions: &mut [Assertion]) {
+    let mut symbolizer = Symbolizer::default();
+    while assertions.iter().any(|a| a.outcome.is_none()) {
+        if let Some(t) = replayer.current_task() {
+            let uid = t.vm().uid();
+            let now = replayer.current_frame_time();
+            for a in assertions.iter_mut().filter(|a| a.outcome.is_none()) {
+                match &mut a.trigger {
+                    Trigger::Event(time) => {
+                        if now >= *time {
+                            a.outcome = Some(check(&**t, &a.expect, &mut symbolizer));
+                        }
+                    }
+                    Trigger::Function {
+                        name, breakpoints, ..
+                    } => {
+                        // The function may be in a library that isn't loaded yet,
+                        // or this may be a new address space after a fork or exec.
+                        if breakpoints.iter().all(|(bp_uid, _)| *bp_uid != uid) {
+                            if let Some(addr) = symbolizer.lookup(&**t, name) {
+                                let addr = RemoteCodePtr::from_val(addr.as_usize());
+                                if replayer.add_breakpoint(addr) {
+                                    breakpoints.push((uid, addr));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let result = match replayer.step() {
+            Some(result) => result,
+            None => break,
+        };
+        if !result.break_status.breakpoint_hit {
+            continue;
+        }
+        let t = match result.break_status.task.upgrade() {
+            Some(t) => t,
+            None => continue,
+        };
+        let at = (t.vm().uid(), t.ip());
+        for a in assertions.iter_mut().filter(|a| a.outcome.is_none()) {
+            if let Trigger::Function {
+                hit,
+                hits,
+                breakpoints,
+                ..
+            } = &mut a.trigger
+            {
+                if breakpoints.contains(&at) {
+                    *hits += 1;
+                    if *hits == *hit {
+                        a.outcome = Some(check(&**t, &a.expect, &mut symbolizer));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check(
+    t: &dyn Task,
+    expect: &ConditionExpression,
+    symbolizer: &mut Symbolizer,
+) -> Result<(), String> {
+    let target = SymbolTarget {
+        t,
+        symbolizer: RefCell::new(symbolizer),
+    };
+    match expect.evaluate(&target, t.arch()) {
+        Ok(0) => Err(format!("`{}` is false", expect)),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// A task whose symbols can be used in expressions.
+struct SymbolTarget<'a> {
+    t: &'a dyn Task,
+    symbolizer: RefCell<&'a mut Symbolizer>,
+}
+
+impl<'a> ExpressionTarget for SymbolTarget<'a> {
+    fn read_memory(&self, addr: usize, buf: &mut [u8]) -> bool {
+        ExpressionTarget::read_memory(self.t, addr, buf)
+    }
+
+    fn read_register(&self, r: GdbRegister) -> Option<u64> {
+        ExpressionTarget::read_register(self.t, r)
+    }
+
+    fn symbol_address(&self, name: &str) -> Option<u64> {
+        self.symbolizer
+            .borrow_mut()
+            .lookup(self.t, name)
+            .map(|addr| addr.as_usize() as u64)
+    }
+}
@@ -0,0 +1,246 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        dump_command::DumpCommand,
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    flags::Flags,
+    trace::{
+        trace_frame::FrameTime,
+        trace_reader::{TraceReader, ValidateSourceFile},
+        trace_stream::MappedData,
+    },
+};
+use libc::pid_t;
+use std::{
+    env, io,
+    io::{stdin, stdout, BufRead, Write},
+    path::PathBuf,
+    process::Command,
+};
+
+/// Events shown per page.
+const PAGE_SIZE: usize = 20;
+
+const HELP: &str = "\
+Commands:
+  <enter>, n       next page
+  p                previous page
+  <event>          show the event in full, with its recorded data and mmaps
+  t [<tid>]        only list events of <tid>; no argument clears the filter
+  s [<syscall>]    only list calls of <syscall>; no argument clears the filter
+  g [<event>]      debug the replay at <event> (default: last event shown)
+  h                this help
+  q                quit";
+
+/// Interactive, line-oriented browser over the events of a trace.
+///
+/// The event list is loaded once; full details of a single event are read
+/// back from the trace on demand, using the same output as `rd dump`.
+pub struct BrowseCommand {
+    trace_dir: Option<PathBuf>,
+}
+
+impl BrowseCommand {
+    pub fn new(options: &RdOptions) -> BrowseCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Browse { trace_dir } => BrowseCommand { trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Browse` variant!"),
+        }
+    }
+}
+
+impl RdCommand for BrowseCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.browse(&mut stdin().lock(), &mut stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+struct EventSummary {
+    time: FrameTime,
+    tid: pid_t,
+    /// Syscall name, for syscall events
+    syscall: Option<String>,
+    description: String,
+    recorded_bytes: usize,
+}
+
+#[derive(Default)]
+struct Filter {
+    tid: Option<pid_t>,
+    syscall: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, ev: &EventSummary) -> bool {
+        self.tid.map_or(true, |tid| tid == ev.tid)
+            && self
+                .syscall
+                .as_ref()
+                .map_or(true, |name| ev.syscall.as_ref() == Some(name))
+    }
+}
+
+impl BrowseCommand {
+    fn browse(&self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        let events = self.load_events();
+        let mut filter = Filter::default();
+        // Index into the filtered list of the first event on the page.
+        let mut page_start = 0;
+        let mut last_shown: Option<FrameTime> = None;
+
+        writeln!(out, "{} events. Type `h` for help.", events.len())?;
+        loop {
+            let shown: Vec<&EventSummary> = events.iter().filter(|ev| filter.matches(ev)).collect();
+            page_start = page_start.min(shown.len().saturating_sub(1));
+            let page = &shown[page_start..shown.len().min(page_start + PAGE_SIZE)];
+            writeln!(out, "\nEVENT\tTID\tBYTES\tDESCRIPTION")?;
+            for ev in page {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    ev.time, ev.tid, ev.recorded_bytes, ev.description
+                )?;
+            }
+            if page.is_empty() {
+                writeln!(out, "(no matching events)")?;
+            }
+            last_shown = page.last().map(|ev| ev.time).or(last_shown);
+
+            write!(out, "browse> ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            let mut words = line.split_whitespace();
+            let cmd = words.next().unwrap_or("n");
+            let arg = words.next();
+            match cmd {
+                "n" => {
+                    if page_start + PAGE_SIZE < shown.len() {
+                        page_start += PAGE_SIZE;
+                    }
+                }
+                "p" => page_start = page_start.saturating_sub(PAGE_SIZE),
+                "t" => {
+                    filter.tid = match arg.map(str::parse::<pid_t>) {
+                        None => None,
+                        Some(Ok(tid)) => Some(tid),
+                        Some(Err(_)) => {
+                            writeln!(out, "Not a tid: {}", arg.unwrap())?;
+                            continue;
+                        }
+                    };
+                    page_start = 0;
+                }
+                "s" => {
+                    filter.syscall = arg.map(str::to_owned);
+                    page_start = 0;
+                }
+                "g" => {
+                    let target = match arg.map(str::parse::<FrameTime>) {
+                        None => last_shown,
+                        Some(Ok(time)) => Some(time),
+                        Some(Err(_)) => None,
+                    };
+                    match target {
+                        Some(time) => self.debug_at(time, out)?,
+                        None => writeln!(out, "Which event? Try `g <event>`")?,
+                    }
+                }
+                "h" | "help" => writeln!(out, "{}", HELP)?,
+                "q" | "quit" => return Ok(()),
+                _ => match cmd.parse::<FrameTime>() {
+                    Ok(time) => {
+                        self.show_event(time, out)?;
+                        // Keep the list where the user was looking.
+                        if let Some(pos) = shown.iter().position(|ev| ev.time >= time) {
+                            page_start = pos;
+                        }
+                    }
+                    Err(_) => writeln!(out, "Unknown command `{}`. Type `h` for help.", cmd)?,
+                },
+            }
+        }
+    }
+
+    fn load_events(&self) -> Vec<EventSummary> {
+        let mut trace = TraceReader::new(self.trace_dir.as_ref());
+        let mut events = Vec::new();
+        while !trace.at_end() {
+            let frame = trace.read_frame();
+            let ev = frame.event();
+            let syscall = if ev.is_syscall_event() {
+                Some(ev.syscall_event().syscall_name())
+            } else {
+                None
+            };
+            loop {
+                let mut data = MappedData::default();
+                if trace
+                    .read_mapped_region(
+                        Some(&mut data),
+                        Some(ValidateSourceFile::DontValidate),
+                        None,
+                        None,
+                        None,
+                    )
+                    .is_none()
+                {
+                    break;
+                }
+            }
+            let mut recorded_bytes = 0;
+            while let Some(data) = trace.read_raw_data_metadata_for_frame() {
+                recorded_bytes += data.size;
+            }
+            events.push(EventSummary {
+                time: frame.time(),
+                tid: frame.tid(),
+                syscall,
+                description: ev.to_string(),
+                recorded_bytes,
+            });
+        }
+        events
+    }
+
+    fn show_event(&self, time: FrameTime, out: &mut dyn Write) -> io::Result<()> {
+        let dump_command = DumpCommand {
+            dump_syscallbuf: true,
+            dump_task_events: true,
+            dump_recorded_data_metadata: true,
+            dump_mmaps: true,
+            raw_dump: false,
+            json: false,
+            statistics: false,
+            only_tid: None,
+            only_syscall: None,
+            trace_dir: self.trace_dir.clone(),
+            event_spec: Some((time, None)),
+        };
+        dump_command.dump(out)
+    }
+
+    /// Run `rd replay -g <time>` on the trace and wait for the debugging
+    /// session to finish.
+    fn debug_at(&self, time: FrameTime, out: &mut dyn Write) -> io::Result<()> {
+        let mut replay = Command::new(env::current_exe()?);
+        replay.args(Flags::get().to_args());
+        replay.arg("replay").arg("-g").arg(time.to_string());
+        if let Some(dir) = &self.trace_dir {
+            replay.arg(dir);
+        }
+        let status = replay.status()?;
+        if !status.success() {
+            writeln!(out, "Replay exited with {}", status)?;
+        }
+        Ok(())
+    }
+}
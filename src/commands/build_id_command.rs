@@ -12,6 +12,12 @@ use std::{
 
 pub struct BuildIdCommand;
 
+impl Default for BuildIdCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BuildIdCommand {
     pub fn new() -> BuildIdCommand {
         BuildIdCommand
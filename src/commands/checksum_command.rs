@@ -0,0 +1,147 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        trace_checksum::{check_manifest, load_key, verify_checksums, ManifestCheck},
+        trace_dir::{trace_data_discarded, TraceDir},
+    },
+};
+use std::{
+    io::{Error, ErrorKind},
+    path::PathBuf,
+};
+
+pub struct ChecksumCommand {
+    verify: bool,
+    /// MiB per second
+    rate_limit: Option<u64>,
+    key: Option<PathBuf>,
+    trace_dir: Option<PathBuf>,
+}
+
+impl ChecksumCommand {
+    pub fn new(options: &RdOptions) -> ChecksumCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Checksum {
+                verify,
+                rate_limit,
+                key,
+                trace_dir,
+            } => ChecksumCommand {
+                verify,
+                rate_limit,
+                key,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Checksum` variant!"),
+        }
+    }
+}
+
+impl RdCommand for ChecksumCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        let trace_dir = TraceDir::from_env().resolve(self.trace_dir.as_ref());
+        if trace_data_discarded(&trace_dir) {
+            return ExitResult::err_from(
+                Error::new(
+                    ErrorKind::Other,
+                    format!("The data of trace {:?} was discarded", trace_dir),
+                ),
+                1,
+            );
+        }
+
+        // Only make a key if we're going to sign a manifest with it.
+        let key = match load_key(self.key.as_deref(), self.verify) {
+            Ok(key) => key,
+            Err(e) => return ExitResult::err_from(e, 1),
+        };
+
+        if !self.verify {
+            return match check_manifest(&trace_dir, key.as_deref()) {
+                Ok(ManifestCheck::Intact) => {
+                    println!(
+                        "{:?}: files match the checksum manifest's sizes. \
+                         Run `rd checksum --verify` to check their contents.",
+                        trace_dir
+                    );
+                    ExitResult::Ok(())
+                }
+                Ok(ManifestCheck::OtherKey) => {
+                    println!(
+                        "{:?}: files match the checksum manifest's sizes. The manifest was \
+                         signed with another key, so its signature wasn't checked. Pass that \
+                         key with --key to check it.",
+                        trace_dir
+                    );
+                    ExitResult::Ok(())
+                }
+                Ok(ManifestCheck::Unsigned) => ExitResult::err_from(
+                    Error::new(
+                        ErrorKind::NotFound,
+                        "There's no key to check the checksum manifest's signature with. \
+                         Pass the key it was signed with with --key.",
+                    ),
+                    1,
+                ),
+                Ok(ManifestCheck::Missing) => ExitResult::err_from(
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "{:?} has no checksum manifest. Run `rd checksum --verify` to \
+                             create one.",
+                            trace_dir
+                        ),
+                    ),
+                    1,
+                ),
+                Err(e) => ExitResult::err_from(e, 1),
+            };
+        }
+
+        let rate_limit = self.rate_limit.map(|mib| mib.max(1) * 1024 * 1024);
+        // load_key() creates a key when asked to.
+        let key = key.unwrap();
+        let report = match verify_checksums(&trace_dir, &key, rate_limit) {
+            Ok(report) => report,
+            Err(e) => return ExitResult::err_from(e, 1),
+        };
+        if !report.problems.is_empty() {
+            for problem in &report.problems {
+                eprintln!("{}", problem);
+            }
+            return ExitResult::err_from(
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{:?} is damaged: {} problem(s) found",
+                        trace_dir,
+                        report.problems.len()
+                    ),
+                ),
+                1,
+            );
+        }
+        if report.other_key {
+            println!(
+                "The checksum manifest was signed with another key, so its signature wasn't \
+                 checked. Pass that key with --key to check it."
+            );
+        }
+        println!(
+            "{:?}: {} {} blocks in {} files",
+            trace_dir,
+            if report.created {
+                "created checksums of"
+            } else {
+                "verified"
+            },
+            report.blocks,
+            report.files
+        );
+        ExitResult::Ok(())
+    }
+}
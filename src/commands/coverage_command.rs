@@ -0,0 +1,304 @@
+use super::exit_result::ExitResult;
+use crate::{
+    assert_prerequisites,
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    divergence_repair::BenignDivergences,
+    log::LogLevel::LogInfo,
+    remote_code_ptr::RemoteCodePtr,
+    session::{
+        replay_session,
+        replay_session::{ReplaySession, ReplayStatus},
+        session_inner::RunCommand,
+        task::Task,
+        Session, SessionSharedPtr,
+    },
+    taskish_uid::TaskUid,
+    trace::trace_frame::FrameTime,
+    util::raise_resource_limits,
+};
+use libc::pid_t;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fs::File,
+    io,
+    io::{stdout, BufWriter, Write},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
+
+/// x86 instructions are at most this long. An instruction whose successor
+/// starts further away than this (or before it) ended with a jump.
+const MAX_INSN_LEN: usize = 15;
+
+/// Replays a range of events, singlestepping, and writes the addresses that
+/// were executed as coverage in drcov format, which tools such as Lighthouse
+/// and bncov read. Addresses are given relative to the binary that was mapped
+/// there during the recording.
+///
+/// Blocks are reconstructed from the instructions that were executed: a block
+/// continues for as long as execution falls through to the next instruction.
+/// The length of the last instruction of a block isn't known, so it's counted
+/// as one byte long.
+///
+/// lcov output isn't supported since it would need the binaries' line tables.
+pub struct CoverageCommand {
+    trace_start: FrameTime,
+    trace_end: FrameTime,
+    pid: Option<pid_t>,
+    output: Option<PathBuf>,
+    trace_dir: Option<PathBuf>,
+}
+
+impl CoverageCommand {
+    pub fn new(options: &RdOptions) -> CoverageCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Coverage {
+                trace_start,
+                trace_end,
+                pid,
+                output,
+                trace_dir,
+            } => CoverageCommand {
+                trace_start: trace_start.unwrap_or(FrameTime::MIN),
+                trace_end: trace_end.unwrap_or(FrameTime::MAX),
+                pid,
+                output,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Coverage` variant!"),
+        }
+    }
+}
+
+impl RdCommand for CoverageCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        assert_prerequisites(None);
+        match self.coverage() {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+struct Module {
+    path: OsString,
+    /// Where offset 0 of the file was mapped, i.e. the load bias.
+    base: usize,
+    end: usize,
+    /// Offset of each executed instruction and its length, if known.
+    insns: BTreeMap<usize, u16>,
+}
+
+#[derive(Default)]
+struct Coverage {
+    modules: Vec<Module>,
+    module_ids: HashMap<(OsString, usize), usize>,
+}
+
+impl Coverage {
+    /// Note that the instruction at `ip` executed. `next_ip` is where the task
+    /// went next.
+    fn record(&mut self, t: &dyn Task, ip: RemoteCodePtr, next_ip: RemoteCodePtr) {
+        let vm = t.vm();
+        let m = match vm.mapping_of(ip.to_data_ptr()) {
+            Some(m) => m,
+            None => return,
+        };
+        let base = m.map.start().as_usize() - m.map.file_offset_bytes() as usize;
+        let key = (m.map.fsname().to_owned(), base);
+        let modules = &mut self.modules;
+        let id = *self.module_ids.entry(key).or_insert_with(|| {
+            modules.push(Module {
+                path: m.map.fsname().to_owned(),
+                base,
+                end: m.map.end().as_usize(),
+                insns: BTreeMap::new(),
+            });
+            modules.len() - 1
+        });
+        let module = &mut self.modules[id];
+        module.end = module.end.max(m.map.end().as_usize());
+
+        let len = match next_ip.as_usize().checked_sub(ip.as_usize()) {
+            Some(len) if len > 0 && len <= MAX_INSN_LEN => len as u16,
+            _ => 0,
+        };
+        let known_len = module.insns.entry(ip.as_usize() - base).or_insert(len);
+        if *known_len == 0 {
+            *known_len = len;
+        }
+    }
+
+    /// Merge instructions that fall through to each other into blocks of
+    /// (offset, size, module id).
+    fn blocks(&self) -> Vec<(u32, u16, u16)> {
+        let mut blocks = Vec::new();
+        for (id, module) in self.modules.iter().enumerate() {
+            let mut current: Option<(usize, usize)> = None;
+            for (&offset, &len) in &module.insns {
+                let end = offset + (len as usize).max(1);
+                current = match current {
+                    Some((start, current_end))
+                        if current_end == offset && end - start <= 0xffff =>
+                    {
+                        Some((start, end))
+                    }
+                    Some((start, current_end)) => {
+                        blocks.push((start as u32, (current_end - start) as u16, id as u16));
+                        Some((offset, end))
+                    }
+                    None => Some((offset, end)),
+                };
+            }
+            if let Some((start, end)) = current {
+                blocks.push((start as u32, (end - start) as u16, id as u16));
+            }
+        }
+        blocks
+    }
+
+    fn write_drcov(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "DRCOV VERSION: 2")?;
+        writeln!(out, "DRCOV FLAVOR: rd")?;
+        writeln!(out, "Module Table: version 2, count {}", self.modules.len())?;
+        writeln!(
+            out,
+            "Columns: id, base, end, entry, checksum, timestamp, path"
+        )?;
+        for (id, module) in self.modules.iter().enumerate() {
+            write!(
+                out,
+                "{:3}, {:#018x}, {:#018x}, 0x0000000000000000, 0x00000000, 0x00000000, ",
+                id, module.base, module.end
+            )?;
+            out.write_all(module.path.as_bytes())?;
+            writeln!(out)?;
+        }
+        let blocks = self.blocks();
+        writeln!(out, "BB Table: {} bbs", blocks.len())?;
+        for (start, size, id) in blocks {
+            out.write_all(&start.to_le_bytes())?;
+            out.write_all(&size.to_le_bytes())?;
+            out.write_all(&id.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl CoverageCommand {
+    fn session_flags(&self) -> replay_session::Flags {
+        replay_session::Flags {
+            redirect_stdio: false,
+            share_private_mappings: false,
+            cpu_unbound: false,
+            repair_divergences: BenignDivergences::empty(),
+            only_process: None,
+        }
+    }
+
+    fn coverage(&self) -> io::Result<()> {
+        let session: SessionSharedPtr =
+            ReplaySession::create(self.trace_dir.as_ref(), self.session_flags());
+        let replay_session = session.as_replay().unwrap();
+        let mut coverage = Coverage::default();
+        let mut steps: u64 = 0;
+
+        // Now that we've spawned the replay, raise our resource limits if possible.
+        raise_resource_limits();
+
+        while replay_session.trace_reader().time() < self.trace_end {
+            let before_time: FrameTime = replay_session.trace_reader().time();
+            let mut traced: Option<(TaskUid, RemoteCodePtr)> = None;
+            if replay_session.done_initial_exec() && before_time >= self.trace_start {
+                if let Some(t) = replay_session.current_task() {
+                    if self.pid.map_or(true, |pid| t.tgid() == pid) {
+                        traced = Some((t.tuid(), t.ip()));
+                    }
+                }
+            }
+
+            let cmd = if traced.is_some() {
+                RunCommand::RunSinglestepFastForward
+            } else {
+                RunCommand::RunContinue
+            };
+            let result = replay_session.replay_step(cmd);
+            if result.status == ReplayStatus::ReplayExited {
+                break;
+            }
+
+            if let Some((tuid, ip)) = traced {
+                let after_time: FrameTime = replay_session.trace_reader().time();
+                if let Some(t) = replay_session.find_task_from_task_uid(tuid) {
+                    let next_ip = t.ip();
+                    // Steps that only deliver a signal or partially execute a
+                    // string instruction don't make progress.
+                    if next_ip != ip || after_time > before_time {
+                        coverage.record(&**t, ip, next_ip);
+                        steps += 1;
+                    }
+                }
+            }
+        }
+        log!(LogInfo, "Coverage finished after {} instructions", steps);
+
+        match &self.output {
+            Some(path) => {
+                let mut out = BufWriter::new(File::create(path)?);
+                coverage.write_drcov(&mut out)?;
+                out.flush()
+            }
+            None => coverage.write_drcov(&mut stdout().lock()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(insns: &[(usize, u16)]) -> Module {
+        Module {
+            path: OsString::from("/lib/libfoo.so"),
+            base: 0x1000,
+            end: 0x20000,
+            insns: insns.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn blocks_merge_fallthrough_instructions() {
+        let coverage = Coverage {
+            modules: vec![
+                module(&[(0x10, 4), (0x14, 2), (0x16, 3), (0x30, 1), (0x40, 0)]),
+                module(&[(0x10, 5)]),
+            ],
+            module_ids: HashMap::new(),
+        };
+        assert_eq!(
+            coverage.blocks(),
+            vec![(0x10, 9, 0), (0x30, 1, 0), (0x40, 1, 0), (0x10, 5, 1)]
+        );
+    }
+
+    #[test]
+    fn blocks_are_split_at_the_size_limit() {
+        let insns: Vec<(usize, u16)> = (0..0x2000).map(|i| (i * 15, 15)).collect();
+        let coverage = Coverage {
+            modules: vec![module(&insns)],
+            module_ids: HashMap::new(),
+        };
+        let blocks = coverage.blocks();
+        assert!(blocks.len() > 1);
+        let mut next = 0u32;
+        for &(start, size, id) in &blocks {
+            assert_eq!((start, id), (next, 0));
+            next = start + size as u32;
+        }
+        assert_eq!(next as usize, 0x2000 * 15);
+    }
+}
@@ -10,6 +10,7 @@ use crate::{
     log::notifying_abort,
     preload_interface::{stored_record_size, syscallbuf_hdr, syscallbuf_record},
     session::address_space::kernel_mapping::KernelMapping,
+    ticks::Ticks,
     trace::{
         trace_frame::{FrameTime, TraceFrame},
         trace_reader::{TraceReader, ValidateSourceFile},
@@ -18,14 +19,16 @@ use crate::{
         trace_task_event::{TraceTaskEvent, TraceTaskEventVariant},
     },
 };
+use libc::{dev_t, ino_t, pid_t};
 use nix::sys::mman::{MapFlags, ProtFlags};
+use serde::Serialize;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     ffi::OsString,
     io,
     io::{stdout, Write},
     mem::size_of,
-    os::unix::ffi::{OsStrExt, OsStringExt},
+    os::unix::ffi::OsStrExt,
     path::PathBuf,
 };
 
@@ -35,8 +38,10 @@ pub struct DumpCommand {
     pub dump_recorded_data_metadata: bool,
     pub dump_mmaps: bool,
     pub raw_dump: bool,
+    pub json: bool,
     pub statistics: bool,
-    pub only_tid: Option<libc::pid_t>,
+    pub only_tid: Option<pid_t>,
+    pub only_syscall: Option<String>,
     pub trace_dir: Option<PathBuf>,
     pub event_spec: Option<(FrameTime, Option<FrameTime>)>,
 }
@@ -50,8 +55,10 @@ impl DumpCommand {
                 recorded_metadata,
                 mmaps,
                 raw_dump,
+                json,
                 statistics,
                 only_tid,
+                only_syscall,
                 trace_dir,
                 event_spec,
             } => DumpCommand {
@@ -60,8 +67,10 @@ impl DumpCommand {
                 dump_recorded_data_metadata: recorded_metadata,
                 dump_mmaps: mmaps,
                 raw_dump,
+                json,
                 statistics,
                 only_tid,
+                only_syscall,
                 trace_dir,
                 event_spec,
             },
@@ -94,6 +103,14 @@ impl DumpCommand {
         let ub = trace.uncompressed_bytes();
         let cb = trace.compressed_bytes();
 
+        if self.json {
+            let stats = serde_json::json!({
+                "uncompressedBytes": ub,
+                "compressedBytes": cb,
+            });
+            return writeln!(f, "{}", stats);
+        }
+
         writeln!(
             f,
             "// Uncompressed bytes {}, compressed bytes {}, ratio {:.2}",
@@ -103,6 +120,16 @@ impl DumpCommand {
         )
     }
 
+    fn syscall_matches(&self, frame: &TraceFrame) -> bool {
+        match &self.only_syscall {
+            None => true,
+            Some(name) => {
+                frame.event().is_syscall_event()
+                    && frame.event().syscall_event().syscall_name() == *name
+            }
+        }
+    }
+
     /// Dump all events from the current to trace that match `self.event_spec` to `f`.
     ///
     /// This function is side-effect-y, in that the trace file isn't
@@ -148,15 +175,24 @@ impl DumpCommand {
             if start <= frame.time()
                 && frame.time() <= end
                 && (self.only_tid.is_none() || self.only_tid.unwrap() == frame.tid())
+                && self.syscall_matches(&frame)
             {
+                if self.json {
+                    self.dump_frame_json(trace, f, &frame, task_events.get(&frame.time()))?;
+                    continue;
+                }
                 if self.raw_dump {
                     frame.dump_raw(Some(f))?;
                 } else {
                     frame.dump(Some(f))?;
                 }
                 if self.dump_syscallbuf {
-                    unsafe {
-                        dump_syscallbuf_data(trace, f, &frame)?;
+                    for record in unsafe { syscallbuf_records(trace, &frame) } {
+                        writeln!(
+                            f,
+                            "  {{ syscall:'{}', ret:{:#x}, size:{:#x} }}",
+                            record.syscall, record.ret, record.size
+                        )?;
                     }
                 }
                 if self.dump_task_events {
@@ -180,20 +216,7 @@ impl DumpCommand {
 
                     let km = maybe_km.unwrap();
                     if self.dump_mmaps {
-                        let mut prot_flags = Vec::<u8>::new();
-                        prot_flags.extend_from_slice(b"rwxp");
-                        if !km.prot().contains(ProtFlags::PROT_READ) {
-                            prot_flags[0] = b'-';
-                        }
-                        if !km.prot().contains(ProtFlags::PROT_WRITE) {
-                            prot_flags[1] = b'-';
-                        }
-                        if !km.prot().contains(ProtFlags::PROT_EXEC) {
-                            prot_flags[2] = b'-';
-                        }
-                        if km.flags().contains(MapFlags::MAP_SHARED) {
-                            prot_flags[3] = b's';
-                        }
+                        let prot_flags = prot_flags_string(&km);
                         let mut fsname = km.fsname().to_os_string();
                         if data.source == MappedDataSource::SourceZero {
                             fsname = OsString::from("<ZERO>");
@@ -216,7 +239,7 @@ impl DumpCommand {
                                 data_file:\"",
                                 km.start().as_usize(),
                                 km.size(),
-                                OsString::from_vec(prot_flags),
+                                prot_flags,
                                 km.file_offset_bytes(),
                                 km.device(),
                                 km.inode(),
@@ -241,7 +264,7 @@ impl DumpCommand {
                                 fsname,
                                 km.start().as_usize(),
                                 km.size(),
-                                OsString::from_vec(prot_flags),
+                                prot_flags,
                                 km.file_offset_bytes(),
                                 km.device(),
                                 km.inode(),
@@ -289,6 +312,230 @@ impl DumpCommand {
     }
 }
 
+impl DumpCommand {
+    /// Write `frame`, and whatever else was asked for about it, as one line of
+    /// JSON. Reads the frame's mappings and raw data in the same order as the
+    /// human-readable dump.
+    fn dump_frame_json(
+        &self,
+        trace: &mut TraceReader,
+        f: &mut dyn Write,
+        frame: &TraceFrame,
+        maybe_task_event: Option<&TraceTaskEvent>,
+    ) -> io::Result<()> {
+        let mut dump = JsonFrame {
+            global_time: frame.time(),
+            real_time: frame.monotonic_time(),
+            tid: frame.tid(),
+            event: frame.event().to_string(),
+            syscall_state: None,
+            ticks: frame.ticks(),
+            registers: None,
+            syscallbuf: Vec::new(),
+            task_event: None,
+            mmaps: Vec::new(),
+            recorded_data: Vec::new(),
+        };
+        if frame.event().is_syscall_event() {
+            dump.syscall_state = Some(frame.event().syscall_event().state.to_string());
+        }
+        if frame.event().record_regs() {
+            dump.registers = Some(
+                frame
+                    .regs_ref()
+                    .named_values()
+                    .into_iter()
+                    .map(|(name, value)| (name, format!("{:#x}", value)))
+                    .collect(),
+            );
+        }
+        if self.dump_syscallbuf {
+            dump.syscallbuf = unsafe { syscallbuf_records(trace, frame) };
+        }
+        if self.dump_task_events {
+            dump.task_event = maybe_task_event.map(JsonTaskEvent::new);
+        }
+
+        loop {
+            let mut data = MappedData::default();
+            let km = match trace.read_mapped_region(
+                Some(&mut data),
+                Some(ValidateSourceFile::DontValidate),
+                None,
+                None,
+                None,
+            ) {
+                Some(km) => km,
+                None => break,
+            };
+            if self.dump_mmaps {
+                let map_file = if data.source == MappedDataSource::SourceZero {
+                    "<ZERO>".to_owned()
+                } else {
+                    km.fsname().to_string_lossy().into_owned()
+                };
+                dump.mmaps.push(JsonMapping {
+                    map_file,
+                    addr: km.start().as_usize(),
+                    length: km.size(),
+                    prot_flags: prot_flags_string(&km),
+                    file_offset: km.file_offset_bytes(),
+                    device: km.device(),
+                    inode: km.inode(),
+                    data_file: data.filename.to_string_lossy().into_owned(),
+                    data_offset: data.data_offset_bytes,
+                    file_size: data.file_size_bytes,
+                });
+            }
+        }
+
+        let process_raw_data = self.dump_syscallbuf || self.dump_recorded_data_metadata;
+        while process_raw_data {
+            match trace.read_raw_data_metadata_for_frame() {
+                Some(data) if self.dump_recorded_data_metadata => {
+                    dump.recorded_data.push(JsonRecordedData {
+                        tid: data.rec_tid,
+                        addr: data.addr.as_usize(),
+                        length: data.size,
+                    })
+                }
+                Some(_) => (),
+                None => break,
+            }
+        }
+
+        writeln!(f, "{}", serde_json::to_string(&dump).unwrap())
+    }
+}
+
+/// One trace frame in `rd dump --json` output. Fields that weren't asked for
+/// (or don't apply to the frame) are left out.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonFrame {
+    global_time: FrameTime,
+    real_time: f64,
+    tid: pid_t,
+    event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    syscall_state: Option<String>,
+    ticks: Ticks,
+    /// Register name to hex value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registers: Option<BTreeMap<&'static str, String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    syscallbuf: Vec<SyscallbufRecordDump>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_event: Option<JsonTaskEvent>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mmaps: Vec<JsonMapping>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    recorded_data: Vec<JsonRecordedData>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonTaskEvent {
+    /// `CLONE`, `EXEC` or `EXIT`
+    kind: &'static str,
+    tid: pid_t,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_tid: Option<pid_t>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clone_flags: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_status: Option<i32>,
+}
+
+impl JsonTaskEvent {
+    fn new(event: &TraceTaskEvent) -> JsonTaskEvent {
+        let mut dump = JsonTaskEvent {
+            kind: "",
+            tid: event.tid(),
+            parent_tid: None,
+            clone_flags: None,
+            file: None,
+            exit_status: None,
+        };
+        match event.event_variant() {
+            TraceTaskEventVariant::Clone(ev) => {
+                dump.kind = "CLONE";
+                dump.parent_tid = Some(ev.parent_tid());
+                dump.clone_flags = Some(ev.clone_flags());
+            }
+            TraceTaskEventVariant::Exec(ev) => {
+                dump.kind = "EXEC";
+                dump.file = Some(ev.file_name().to_string_lossy().into_owned());
+            }
+            TraceTaskEventVariant::Exit(ev) => {
+                dump.kind = "EXIT";
+                dump.exit_status = Some(ev.exit_status().get());
+            }
+        }
+        dump
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonMapping {
+    map_file: String,
+    addr: usize,
+    length: usize,
+    prot_flags: String,
+    file_offset: u64,
+    device: dev_t,
+    inode: ino_t,
+    data_file: String,
+    data_offset: usize,
+    file_size: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonRecordedData {
+    tid: pid_t,
+    addr: usize,
+    length: usize,
+}
+
+/// A buffered syscall in a syscallbuf flush.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SyscallbufRecordDump {
+    syscall: String,
+    ret: i64,
+    size: u32,
+}
+
+/// `rwxp`-style protection string for `km`, as in /proc/<pid>/maps.
+fn prot_flags_string(km: &KernelMapping) -> String {
+    let mut prot_flags = String::with_capacity(4);
+    prot_flags.push(if km.prot().contains(ProtFlags::PROT_READ) {
+        'r'
+    } else {
+        '-'
+    });
+    prot_flags.push(if km.prot().contains(ProtFlags::PROT_WRITE) {
+        'w'
+    } else {
+        '-'
+    });
+    prot_flags.push(if km.prot().contains(ProtFlags::PROT_EXEC) {
+        'x'
+    } else {
+        '-'
+    });
+    prot_flags.push(if km.flags().contains(MapFlags::MAP_SHARED) {
+        's'
+    } else {
+        'p'
+    });
+    prot_flags
+}
+
 impl RdCommand for DumpCommand {
     fn run(&mut self) -> ExitResult<()> {
         match self.dump(&mut stdout()) {
@@ -327,13 +574,14 @@ fn dump_task_event(out: &mut dyn Write, event: &TraceTaskEvent) -> io::Result<()
     Ok(())
 }
 
-unsafe fn dump_syscallbuf_data(
+/// The buffered syscalls in `frame`, if it is a syscallbuf flush.
+unsafe fn syscallbuf_records(
     trace: &mut TraceReader,
-    out: &mut dyn Write,
     frame: &TraceFrame,
-) -> io::Result<()> {
+) -> Vec<SyscallbufRecordDump> {
+    let mut records = Vec::new();
     if frame.event().event_type() != EventType::EvSyscallbufFlush {
-        return Ok(());
+        return records;
     }
     let buf = trace.read_raw_data();
     let mut bytes_remaining = (buf.data.len() - size_of::<syscallbuf_hdr>()) as u32;
@@ -348,19 +596,17 @@ unsafe fn dump_syscallbuf_data(
     let end_ptr = record_ptr.add(bytes_remaining as usize);
     while record_ptr.lt(&end_ptr) {
         let record = record_ptr as *const syscallbuf_record;
-        // Buffered syscalls always use the task arch
-        writeln!(
-            out,
-            "  {{ syscall:'{}', ret:{:#x}, size:{:#x} }}",
-            syscall_name((*record).syscallno as i32, frame.regs_ref().arch()),
-            (*record).ret,
-            (*record).size
-        )?;
+        records.push(SyscallbufRecordDump {
+            // Buffered syscalls always use the task arch
+            syscall: syscall_name((*record).syscallno as i32, frame.regs_ref().arch()),
+            ret: (*record).ret,
+            size: (*record).size,
+        });
         if ((*record).size as usize) < size_of::<syscallbuf_record>() {
             eprintln!("Malformed trace file (bad record size)");
             notifying_abort(backtrace::Backtrace::new());
         }
         record_ptr = record_ptr.add(stored_record_size((*record).size) as usize);
     }
-    Ok(())
+    records
 }
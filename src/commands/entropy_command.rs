@@ -0,0 +1,283 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    event::{EventType, SyscallState},
+    kernel_abi::SupportedArch,
+    kernel_metadata::syscall_name,
+    preload_interface::{stored_record_size, syscallbuf_hdr, syscallbuf_record},
+    trace::{
+        trace_frame::{FrameTime, TraceFrame},
+        trace_reader::{TraceReader, ValidateSourceFile},
+        trace_stream::MappedData,
+    },
+};
+use libc::pid_t;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io,
+    io::{stdout, Write},
+    mem::size_of,
+    path::PathBuf,
+    ptr,
+};
+
+/// Reports where the nondeterministic input captured in a trace came from:
+/// randomness, clocks, the network, other syscall results, signals and
+/// scheduling. Everything is derived from what the trace already stores, so
+/// this works on any trace.
+pub struct EntropyCommand {
+    json: bool,
+    list_events: bool,
+    trace_dir: Option<PathBuf>,
+}
+
+impl EntropyCommand {
+    pub fn new(options: &RdOptions) -> EntropyCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Entropy {
+                json,
+                list_events,
+                trace_dir,
+            } => EntropyCommand {
+                json,
+                list_events,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not an `Entropy` variant!"),
+        }
+    }
+}
+
+impl RdCommand for EntropyCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.entropy(&mut stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum EntropySource {
+    Random,
+    Time,
+    Network,
+    /// Results of every other syscall, e.g. file and pipe reads
+    SyscallOutput,
+    /// rdtsc and cpuid results
+    InstructionTrap,
+    Signal,
+    /// Preemptions, and switches between tasks at any event
+    Schedule,
+}
+
+impl EntropySource {
+    fn for_syscall(name: &str) -> EntropySource {
+        match name {
+            "getrandom" => EntropySource::Random,
+            "clock_gettime" | "clock_gettime64" | "gettimeofday" | "time" | "times"
+            | "getrusage" => EntropySource::Time,
+            "recv" | "recvfrom" | "recvmsg" | "recvmmsg" | "accept" | "accept4" => {
+                EntropySource::Network
+            }
+            _ => EntropySource::SyscallOutput,
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+struct SourceTotals {
+    events: u64,
+    bytes: u64,
+}
+
+/// One entry of the per-event ledger.
+#[derive(Serialize)]
+struct LedgerEntry {
+    event: FrameTime,
+    tid: pid_t,
+    source: EntropySource,
+    /// The syscall, for syscall sources
+    #[serde(skip_serializing_if = "Option::is_none")]
+    syscall: Option<String>,
+    bytes: u64,
+    /// True for syscalls handled by the syscall buffer
+    buffered: bool,
+}
+
+#[derive(Default, Serialize)]
+struct EntropyReport {
+    sources: BTreeMap<EntropySource, SourceTotals>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ledger: Vec<LedgerEntry>,
+}
+
+impl EntropyReport {
+    fn add(&mut self, entry: LedgerEntry, keep_entry: bool) {
+        let totals = self.sources.entry(entry.source).or_default();
+        totals.events += 1;
+        totals.bytes += entry.bytes;
+        if keep_entry {
+            self.ledger.push(entry);
+        }
+    }
+}
+
+impl EntropyCommand {
+    fn entropy(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut trace = TraceReader::new(self.trace_dir.as_ref());
+        let mut report = EntropyReport::default();
+        let mut last_tid: Option<pid_t> = None;
+
+        while !trace.at_end() {
+            let frame = trace.read_frame();
+            loop {
+                let mut data = MappedData::default();
+                let maybe_km = trace.read_mapped_region(
+                    Some(&mut data),
+                    Some(ValidateSourceFile::DontValidate),
+                    None,
+                    None,
+                    None,
+                );
+                if maybe_km.is_none() {
+                    break;
+                }
+            }
+
+            let mut entries = Vec::new();
+            let ev = frame.event();
+            match ev.event_type() {
+                EventType::EvSyscallbufFlush => {
+                    while let Some(raw) = trace.read_raw_data_for_frame() {
+                        entries.extend(buffered_syscalls(&frame, &raw.data));
+                    }
+                }
+                EventType::EvSyscall
+                    if ev.syscall_event().state == SyscallState::ExitingSyscall =>
+                {
+                    let name = ev.syscall_event().syscall_name();
+                    entries.push(LedgerEntry {
+                        event: frame.time(),
+                        tid: frame.tid(),
+                        source: EntropySource::for_syscall(&name),
+                        syscall: Some(name),
+                        bytes: recorded_bytes(&mut trace),
+                        buffered: false,
+                    });
+                }
+                event_type => {
+                    let source = match event_type {
+                        EventType::EvInstructionTrap => Some(EntropySource::InstructionTrap),
+                        EventType::EvSignal => Some(EntropySource::Signal),
+                        EventType::EvSched => Some(EntropySource::Schedule),
+                        _ => None,
+                    };
+                    let bytes = recorded_bytes(&mut trace);
+                    if let Some(source) = source {
+                        entries.push(LedgerEntry {
+                            event: frame.time(),
+                            tid: frame.tid(),
+                            source,
+                            syscall: None,
+                            bytes,
+                            buffered: false,
+                        });
+                    }
+                }
+            }
+
+            // A switch to another task that isn't already accounted for as a
+            // preemption is still a scheduling decision.
+            if last_tid.map_or(false, |tid| tid != frame.tid())
+                && ev.event_type() != EventType::EvSched
+            {
+                entries.push(LedgerEntry {
+                    event: frame.time(),
+                    tid: frame.tid(),
+                    source: EntropySource::Schedule,
+                    syscall: None,
+                    bytes: 0,
+                    buffered: false,
+                });
+            }
+            last_tid = Some(frame.tid());
+
+            for entry in entries {
+                report.add(entry, self.list_events);
+            }
+        }
+
+        if self.json {
+            let serialized = serde_json::to_string(&report).unwrap();
+            writeln!(out, "{}", serialized)?;
+            return Ok(());
+        }
+
+        writeln!(out, "SOURCE\tEVENTS\tBYTES")?;
+        for (source, totals) in &report.sources {
+            writeln!(out, "{:?}\t{}\t{}", source, totals.events, totals.bytes)?;
+        }
+        if self.list_events {
+            writeln!(out, "\nEVENT\tTID\tSOURCE\tBYTES\tSYSCALL")?;
+            for entry in &report.ledger {
+                writeln!(
+                    out,
+                    "{}\t{}\t{:?}\t{}\t{}{}",
+                    entry.event,
+                    entry.tid,
+                    entry.source,
+                    entry.bytes,
+                    entry.syscall.as_deref().unwrap_or("-"),
+                    if entry.buffered { " (buffered)" } else { "" }
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn recorded_bytes(trace: &mut TraceReader) -> u64 {
+    let mut bytes = 0;
+    while let Some(data) = trace.read_raw_data_metadata_for_frame() {
+        bytes += data.size as u64;
+    }
+    bytes
+}
+
+/// Ledger entries for the syscalls in a flushed syscallbuf. Stops at the
+/// first malformed record; `rd dump -b` reports those.
+fn buffered_syscalls(frame: &TraceFrame, buf: &[u8]) -> Vec<LedgerEntry> {
+    let mut entries = Vec::new();
+    if buf.len() < size_of::<syscallbuf_hdr>() {
+        return entries;
+    }
+    let hdr = unsafe { ptr::read_unaligned(buf.as_ptr() as *const syscallbuf_hdr) };
+    let records = &buf[size_of::<syscallbuf_hdr>()..];
+    let end = (hdr.num_rec_bytes as usize).min(records.len());
+    let arch: SupportedArch = frame.regs_ref().arch();
+    let mut offset = 0;
+    while offset + size_of::<syscallbuf_record>() <= end {
+        let record =
+            unsafe { ptr::read_unaligned(records[offset..].as_ptr() as *const syscallbuf_record) };
+        if (record.size as usize) < size_of::<syscallbuf_record>() {
+            break;
+        }
+        let name = syscall_name(record.syscallno as i32, arch);
+        entries.push(LedgerEntry {
+            event: frame.time(),
+            tid: frame.tid(),
+            source: EntropySource::for_syscall(&name),
+            syscall: Some(name),
+            bytes: (record.size as usize - size_of::<syscallbuf_record>()) as u64,
+            buffered: true,
+        });
+        offset += stored_record_size(record.size) as usize;
+    }
+    entries
+}
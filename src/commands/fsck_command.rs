@@ -0,0 +1,132 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        trace_checksum::{check_manifest, load_key, verify_checksums, ManifestCheck},
+        trace_dir::{trace_data_discarded, TraceDir},
+    },
+};
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+pub struct FsckCommand {
+    full: bool,
+    key: Option<PathBuf>,
+    trace_dir: Option<PathBuf>,
+}
+
+impl FsckCommand {
+    pub fn new(options: &RdOptions) -> FsckCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Fsck {
+                full,
+                key,
+                trace_dir,
+            } => FsckCommand {
+                full,
+                key,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Fsck` variant!"),
+        }
+    }
+}
+
+fn damaged(message: String) -> ExitResult<()> {
+    ExitResult::err_from(Error::new(ErrorKind::InvalidData, message), 1)
+}
+
+impl RdCommand for FsckCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        let trace_dir = TraceDir::from_env().resolve(self.trace_dir.as_ref());
+        let dir = Path::new(&trace_dir);
+        if !dir.join("version").is_file() {
+            if dir.join("incomplete").is_file() {
+                return damaged(format!(
+                    "{:?} is incomplete: rd is still recording it, or died while recording it",
+                    trace_dir
+                ));
+            }
+            return ExitResult::err_from(
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{:?} is not a trace directory", trace_dir),
+                ),
+                1,
+            );
+        }
+        if trace_data_discarded(&trace_dir) {
+            return damaged(format!("The data of trace {:?} was discarded", trace_dir));
+        }
+
+        let key = match load_key(self.key.as_deref(), false) {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                return ExitResult::err_from(
+                    Error::new(
+                        ErrorKind::NotFound,
+                        "There's no key to check the checksum manifest's signature with. \
+                         Pass the key it was signed with with --key.",
+                    ),
+                    1,
+                )
+            }
+            Err(e) => return ExitResult::err_from(e, 1),
+        };
+        match check_manifest(&trace_dir, Some(&key)) {
+            Ok(ManifestCheck::Intact) => (),
+            Ok(ManifestCheck::OtherKey) => println!(
+                "{:?}: the checksum manifest was signed with another key, so its signature \
+                 wasn't checked. Pass that key with --key to check it.",
+                trace_dir
+            ),
+            Ok(ManifestCheck::Missing) => {
+                return ExitResult::err_from(
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "{:?} has no checksum manifest. Run `rd checksum --verify` to \
+                             create one.",
+                            trace_dir
+                        ),
+                    ),
+                    1,
+                )
+            }
+            Ok(ManifestCheck::Unsigned) => unreachable!(),
+            Err(e) => return ExitResult::err_from(e, 1),
+        }
+
+        if self.full {
+            let report = match verify_checksums(&trace_dir, &key, None) {
+                Ok(report) => report,
+                Err(e) => return ExitResult::err_from(e, 1),
+            };
+            if !report.problems.is_empty() {
+                for problem in &report.problems {
+                    eprintln!("{}", problem);
+                }
+                return damaged(format!(
+                    "{:?} is damaged: {} problem(s) found",
+                    trace_dir,
+                    report.problems.len()
+                ));
+            }
+            println!(
+                "{:?}: OK, verified {} blocks in {} files",
+                trace_dir, report.blocks, report.files
+            );
+        } else {
+            println!(
+                "{:?}: OK. Run `rd fsck --full` to check the contents of its files too.",
+                trace_dir
+            );
+        }
+        ExitResult::Ok(())
+    }
+}
@@ -1,11 +1,27 @@
 use super::{exit_result::ExitResult, gdb_command_handler::GdbCommandHandler, RdCommand};
 use crate::{
+    allocation_origin::find_allocation_origins,
     commands::gdb_server::{Checkpoint, ExplicitCheckpoint, GdbServer},
+    condition_expression::ConditionExpression,
+    memory_search::{search_address_space, search_range, SearchPattern, Symbolizer},
+    remote_ptr::{RemotePtr, Void},
     replay_timeline::Mark,
-    session::task::Task,
+    session::{
+        address_space::{memory_range::MemoryRange, WatchType},
+        replay_session::{ReplayStatus, StepConstraints},
+        session_inner::RunCommand,
+        task::{Task, TaskSharedPtr},
+        Session,
+    },
+    taskish_uid::TaskUid,
+    ticks::Ticks,
+    trace::trace_frame::FrameTime,
+    unwind::{format_backtrace, symbolized_backtrace},
+    util::word_size,
 };
+use libc::pid_t;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::{OsStr, OsString},
     io::Write,
     ops::{Deref, DerefMut},
@@ -42,11 +58,13 @@ impl BaseGdbCommand {
     }
 
     /// Setup all the automatic auto_args for our commands.
+    ///
+    /// Safe to call more than once.
     pub fn init_auto_args() {
-        gdb_command_map_mut()
-            .get_mut("checkpoint")
-            .unwrap()
-            .add_auto_arg(&OsString::from("rd-where"));
+        let checkpoint = gdb_command_map_mut().get_mut("checkpoint").unwrap();
+        if checkpoint.auto_args().is_empty() {
+            checkpoint.add_auto_arg(&OsString::from("rd-where"));
+        }
     }
 }
 
@@ -170,6 +188,28 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         )),
     );
 
+    command_list.insert(
+        String::from("when-time"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("when-time"),
+            "Print the local wall-clock time at which the current event was recorded,\n\
+                            and the recording's monotonic clock at that point.",
+            &when_time,
+        )),
+    );
+
+    command_list.insert(
+        String::from("seek-ticks"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("seek-ticks"),
+            "Usage: seek-ticks N\n\
+                            Go to the point in the current event where the current thread's\n\
+                            tick count (see when-ticks) is N. N may be behind or ahead of\n\
+                            the current tick count.",
+            &seek_ticks,
+        )),
+    );
+
     command_list.insert(
         String::from("rd-history-push"),
         Box::new(SimpleGdbCommand::new(
@@ -197,6 +237,40 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         )),
     );
 
+    command_list.insert(
+        String::from("rd-break-event"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-break-event"),
+            "Stop when replay reaches event N, whether running forward or in reverse.\n\
+                            Without an argument, remove the event breakpoint.",
+            &rd_break_event,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-catch-sdt"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-catch-sdt"),
+            "Stop after the program hits an SDT probe of PROVIDER, or PROVIDER:NAME, when\n\
+                            running forward. The trace must have been recorded with --stap-sdt.\n\
+                            Without an argument, stop catching probes.",
+            &rd_catch_sdt,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-condition"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-condition"),
+            "Usage: rd-condition ADDR [EXPRESSION]\n\
+                            Only stop at the breakpoint or watchpoint at ADDR when EXPRESSION,\n\
+                            in rd's condition language (e.g. `$rdi == 3 && *(u32 *)$rsp > 100`),\n\
+                            holds. Applies the next time gdb inserts it, i.e. when execution\n\
+                            resumes. Without EXPRESSION, remove the condition.",
+            &rd_condition,
+        )),
+    );
+
     command_list.insert(
         String::from("checkpoint"),
         Box::new(SimpleGdbCommand::new(
@@ -225,6 +299,131 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         )),
     );
 
+    command_list.insert(
+        String::from("rd-diff-checkpoint"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-diff-checkpoint"),
+            "Usage: rd-diff-checkpoint A B [ADDR LEN]\n\
+                            Show which registers differ between checkpoints A and B and,\n\
+                            given a memory range, which bytes in it differ.\n\
+                            The current position is not changed.",
+            &rd_diff_checkpoint,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-perf"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-perf"),
+            "Usage: rd-perf [reset]\n\
+                            Show how many packets and bytes the gdb server has exchanged\n\
+                            and how long memory reads, memory writes and replay steps\n\
+                            took, and how reverse singlesteps were carried out.\n\
+                            'rd-perf reset' starts counting from zero again.",
+            &rd_perf,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-follow-fork"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-follow-fork"),
+            "Usage: rd-follow-fork [on|off]\n\
+                            When on, debugging switches to the child when the debugged\n\
+                            process forks, and a checkpoint is created at the fork.",
+            &rd_follow_fork,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-follow-exec"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-follow-exec"),
+            "Usage: rd-follow-exec [on|off]\n\
+                            When on, execution may continue past an exec of the debugged\n\
+                            process: gdb is told about the new image, and a checkpoint is\n\
+                            created at the exec. Running backwards over a followed exec stops\n\
+                            just before it, back in the old image.",
+            &rd_follow_exec,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-go-preemption-stops"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-go-preemption-stops"),
+            "Usage: rd-go-preemption-stops [on|off]\n\
+                            When off (the default), the SIGURGs the Go runtime sends itself\n\
+                            to preempt goroutines don't stop execution.",
+            &rd_go_preemption_stops,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-find"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-find"),
+            "Usage: rd-find TYPE VALUE\n\
+                            Search the memory of every process being replayed for VALUE.\n\
+                            TYPE is u16, u32, u64, i16, i32, i64, f32 or f64, with a 'be'\n\
+                            suffix (e.g. u32be) for big endian, 'str' for a string, 'hex'\n\
+                            for hex bytes where '?' matches any nibble (e.g. 'de ?d be ef'),\n\
+                            or 're' for a regex matched against runs of printable characters.",
+            &rd_find,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-search-mem"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-search-mem"),
+            "Usage: rd-search-mem [-a] START END TYPE VALUE\n\
+                            Search the current process's memory from START up to END for\n\
+                            VALUE, with TYPE as for rd-find. Prints the first match, or with\n\
+                            -a every match.",
+            &rd_search_mem,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-alloc-origin"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-alloc-origin"),
+            "Usage: rd-alloc-origin ADDR...\n\
+                            For each ADDR, the start of a malloc'ed block, print the event and\n\
+                            the frames of the malloc call that returned it, found by executing\n\
+                            backwards to the last write to the block's malloc chunk header,\n\
+                            and the event at which its memory was last mapped (by mmap, brk\n\
+                            etc.) in the current process.\n\
+                            'rd-bt-origin' does this for the pointers in the backtrace.",
+            &rd_alloc_origin,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-unwind"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-unwind"),
+            "Usage: rd-unwind [MAX-FRAMES]\n\
+                            Print a backtrace of the current thread computed by rd from the\n\
+                            unwind info of the binaries saved in the trace, falling back to\n\
+                            frame pointers. Shows at most MAX-FRAMES (default 64) frames.",
+            &rd_unwind,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-next-write"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-next-write"),
+            "Usage: rd-next-write ADDR LEN [EVENTS]\n\
+                            Find the next write to LEN bytes at ADDR by replaying ahead at\n\
+                            most EVENTS events (default 10000) in a copy of the current\n\
+                            session. The current position is not changed.",
+            &rd_next_write,
+        )),
+    );
+
     command_list
 }
 
@@ -280,6 +479,74 @@ fn when_tid(_: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
     OsString::from_vec(rets)
 }
 
+fn when_time(_: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+
+    let replay_t = t.as_replay_task().unwrap();
+    let frame = replay_t.current_trace_frame();
+    let mut rets = Vec::<u8>::new();
+    match frame.realtime() {
+        Some(realtime) => write!(rets, "Recorded at: {}", format_local_time(realtime)),
+        None => write!(
+            rets,
+            "Recorded at: unknown (trace has no wall-clock timestamps)"
+        ),
+    }
+    .unwrap();
+    write!(rets, "\nMonotonic time (s): {:.6}", frame.monotonic_time()).unwrap();
+    OsString::from_vec(rets)
+}
+
+/// `secs` since the Unix epoch as e.g. "2021-04-01 09:30:00.123456 +0200".
+fn format_local_time(secs: f64) -> String {
+    let whole = secs.floor();
+    let t = whole as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return format!("{:.6} s since the epoch", secs);
+    }
+    let offset_min = tm.tm_gmtoff / 60;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06} {}{:02}{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        (((secs - whole) * 1e6) as u32).min(999_999),
+        if offset_min < 0 { '-' } else { '+' },
+        offset_min.abs() / 60,
+        offset_min.abs() % 60
+    )
+}
+
+fn seek_ticks(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let maybe_ticks = args
+        .get(1)
+        .and_then(|arg| str::from_utf8(arg.as_bytes()).ok())
+        .and_then(|arg| str::parse::<Ticks>(arg.trim()).ok());
+    let ticks = match maybe_ticks {
+        Some(ticks) => ticks,
+        None => return GdbCommandHandler::cmd_error("Usage: seek-ticks N"),
+    };
+    // `t` may not survive the seek.
+    let tuid = t.tuid();
+    if gdb_server.timeline_unwrap_mut().seek_to_ticks(tuid, ticks) {
+        OsString::from(format!("Current tick: {}", ticks))
+    } else {
+        GdbCommandHandler::cmd_error(format!(
+            "The current thread doesn't reach tick {} in this event.",
+            ticks
+        ))
+    }
+}
+
 static mut BACK_STACK: Vec<Mark> = Vec::new();
 static mut CURRENT_HISTORY_CP: Option<Mark> = None;
 static mut FORWARD_STACK: Vec<Mark> = Vec::new();
@@ -341,9 +608,498 @@ fn forward(gdb_server: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString
     OsString::new()
 }
 
-fn invoke_checkpoint(gdb_server: &mut GdbServer, _t: &dyn Task, args: &[OsString]) -> OsString {
-    static NEXT_CHECKPOINT_ID: AtomicU64 = AtomicU64::new(1);
-    let where_ = &args[1];
+fn rd_break_event(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let arg = match args.get(1) {
+        Some(arg) => arg,
+        None => {
+            gdb_server.break_at_event = None;
+            return OsString::from("Event breakpoint removed.");
+        }
+    };
+    let maybe_event = str::from_utf8(arg.as_bytes())
+        .ok()
+        .and_then(|arg| str::parse::<FrameTime>(arg.trim()).ok());
+    match maybe_event {
+        Some(event) => {
+            gdb_server.break_at_event = Some(event);
+            OsString::from(format!("Will stop at event {}.", event))
+        }
+        None => GdbCommandHandler::cmd_error("Usage: rd-break-event [N]"),
+    }
+}
+
+fn rd_perf(gdb_server: &mut GdbServer, _t: &dyn Task, args: &[OsString]) -> OsString {
+    match args.get(1).map(|arg| arg.as_bytes()) {
+        None => {
+            let mut out = gdb_server.dbg_unwrap().stats().to_string();
+            if gdb_server.timeline_is_running() {
+                let reverse_steps = *gdb_server.timeline_unwrap().reverse_step_stats();
+                out.push_str(&format!("\n{}", reverse_steps));
+            }
+            OsString::from(out)
+        }
+        Some(b"reset") => {
+            gdb_server.dbg_unwrap_mut().stats_mut().reset();
+            if gdb_server.timeline_is_running() {
+                gdb_server.timeline_unwrap_mut().reset_reverse_step_stats();
+            }
+            OsString::from("Performance counters reset.")
+        }
+        Some(_) => GdbCommandHandler::cmd_error("Usage: rd-perf [reset]"),
+    }
+}
+
+fn rd_follow_fork(gdb_server: &mut GdbServer, _t: &dyn Task, args: &[OsString]) -> OsString {
+    if !parse_on_off(args, &mut gdb_server.follow_fork) {
+        return GdbCommandHandler::cmd_error("Usage: rd-follow-fork [on|off]");
+    }
+    if gdb_server.follow_fork {
+        OsString::from("Following forks.")
+    } else {
+        OsString::from("Not following forks.")
+    }
+}
+
+fn rd_follow_exec(gdb_server: &mut GdbServer, _t: &dyn Task, args: &[OsString]) -> OsString {
+    if !parse_on_off(args, &mut gdb_server.follow_exec) {
+        return GdbCommandHandler::cmd_error("Usage: rd-follow-exec [on|off]");
+    }
+    if gdb_server.follow_exec {
+        OsString::from("Following execs.")
+    } else {
+        OsString::from("Not following execs.")
+    }
+}
+
+fn rd_go_preemption_stops(
+    gdb_server: &mut GdbServer,
+    _t: &dyn Task,
+    args: &[OsString],
+) -> OsString {
+    if !parse_on_off(args, &mut gdb_server.stop_on_go_preemption) {
+        return GdbCommandHandler::cmd_error("Usage: rd-go-preemption-stops [on|off]");
+    }
+    if gdb_server.stop_on_go_preemption {
+        OsString::from("Stopping for Go preemption signals.")
+    } else {
+        OsString::from("Not stopping for Go preemption signals.")
+    }
+}
+
+/// Don't flood gdb with output when a search matches too often.
+const MAX_FIND_MATCHES: usize = 100;
+
+fn rd_find(_: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    const USAGE: &str = "Usage: rd-find TYPE VALUE";
+    if args.len() < 3 {
+        return GdbCommandHandler::cmd_error(USAGE);
+    }
+    let kind = String::from_utf8_lossy(args[1].as_bytes());
+    // gdb splits arguments on whitespace; put a string or regex back together.
+    let value = args[2..]
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let pattern = match SearchPattern::parse(&kind, &value) {
+        Ok(pattern) => pattern,
+        Err(e) => return GdbCommandHandler::cmd_error(e),
+    };
+
+    let mut out = Vec::new();
+    let mut symbolizer = Symbolizer::default();
+    let mut matches = 0;
+    let mut searched = HashSet::new();
+    let tasks: Vec<TaskSharedPtr> = t.session().tasks().values().cloned().collect();
+    for task in tasks {
+        if !searched.insert(task.vm().uid()) {
+            continue;
+        }
+        let complete = search_address_space(&**task, &pattern, &mut |addr| {
+            if matches == MAX_FIND_MATCHES {
+                return false;
+            }
+            matches += 1;
+            writeln!(
+                out,
+                "{}: process {}, {}",
+                addr,
+                task.tgid(),
+                symbolizer.describe(&**task, addr)
+            )
+            .unwrap();
+            true
+        });
+        if !complete {
+            write!(out, "Stopped after {} matches.", MAX_FIND_MATCHES).unwrap();
+            return OsString::from_vec(out);
+        }
+    }
+    write!(out, "{} match(es).", matches).unwrap();
+    OsString::from_vec(out)
+}
+
+fn rd_search_mem(_: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    const USAGE: &str = "Usage: rd-search-mem [-a] START END TYPE VALUE";
+    let mut args = &args[1..];
+    let all = args.first().map_or(false, |arg| arg == "-a");
+    if all {
+        args = &args[1..];
+    }
+    if args.len() < 4 {
+        return GdbCommandHandler::cmd_error(USAGE);
+    }
+    let range = match (parse_number(&args[0]), parse_number(&args[1])) {
+        (Some(start), Some(end)) if start < end => {
+            MemoryRange::from_range(RemotePtr::new(start as usize), RemotePtr::new(end as usize))
+        }
+        _ => return GdbCommandHandler::cmd_error(USAGE),
+    };
+    let kind = String::from_utf8_lossy(args[2].as_bytes());
+    let value = args[3..]
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let pattern = match SearchPattern::parse(&kind, &value) {
+        Ok(pattern) => pattern,
+        Err(e) => return GdbCommandHandler::cmd_error(e),
+    };
+
+    let mut out = Vec::new();
+    let mut symbolizer = Symbolizer::default();
+    let mut matches = 0;
+    let complete = search_range(t, range, &pattern, &mut |addr| {
+        if matches == MAX_FIND_MATCHES {
+            return false;
+        }
+        matches += 1;
+        writeln!(out, "{}: {}", addr, symbolizer.describe(t, addr)).unwrap();
+        all
+    });
+    if matches == 0 {
+        write!(out, "Pattern not found.").unwrap();
+    } else if all && !complete {
+        write!(out, "Stopped after {} matches.", MAX_FIND_MATCHES).unwrap();
+    } else if all {
+        write!(out, "{} match(es).", matches).unwrap();
+    } else {
+        // Drop the trailing newline.
+        out.pop();
+    }
+    OsString::from_vec(out)
+}
+
+fn rd_alloc_origin(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let maybe_addrs: Option<Vec<RemotePtr<Void>>> = args[1..]
+        .iter()
+        .map(|arg| parse_number(arg).map(|addr| RemotePtr::new(addr as usize)))
+        .collect();
+    let addrs = match maybe_addrs {
+        Some(addrs) if !addrs.is_empty() => addrs,
+        _ => return GdbCommandHandler::cmd_error("Usage: rd-alloc-origin ADDR..."),
+    };
+
+    let now = t.as_replay_task().unwrap().current_trace_frame().time();
+    let trace_dir = t.session().as_replay().unwrap().trace_reader().dir();
+    let origins = find_allocation_origins(&trace_dir, t.rec_tid(), now, &addrs);
+    // `t` may not survive the reverse execution below.
+    let tuid = t.tuid();
+    let header_size = word_size(t.arch());
+    let mut out = Vec::new();
+    for (&addr, maybe_origin) in addrs.iter().zip(origins) {
+        let origin = match maybe_origin {
+            Some(origin) => origin,
+            None => {
+                writeln!(out, "{}: not mapped before event {}", addr, now).unwrap();
+                continue;
+            }
+        };
+        write!(out, "{}: ", addr).unwrap();
+        if let Some(call) = find_malloc_call(gdb_server, tuid, addr - header_size, origin.time) {
+            write!(
+                out,
+                "allocated at event {} by tid {} in {}; ",
+                call.time, call.tid, call.backtrace
+            )
+            .unwrap();
+        }
+        write!(
+            out,
+            "{} at event {} by tid {}, mapping {}-{}",
+            origin.what,
+            origin.time,
+            origin.tid,
+            origin.mapping.start(),
+            origin.mapping.end()
+        )
+        .unwrap();
+        if !origin.mapping.fsname().is_empty() {
+            out.push(b' ');
+            out.extend_from_slice(origin.mapping.fsname().as_bytes());
+        }
+        out.push(b'\n');
+    }
+    out.pop();
+    OsString::from_vec(out)
+}
+
+/// Where `find_malloc_call` found a block to have been allocated.
+struct MallocCall {
+    time: FrameTime,
+    tid: pid_t,
+    /// The innermost frames at the allocation, separated by ` <- `.
+    backtrace: String,
+}
+
+/// Give up on finding a malloc call after this many other stops.
+const MAX_MALLOC_CALL_STOPS: usize = 64;
+
+/// Frames of the allocating call shown by `rd-alloc-origin`.
+const MALLOC_CALL_FRAMES: usize = 6;
+
+/// Find the malloc call that returned the block whose malloc chunk header,
+/// the word before the block, is at `header`: the last write to the header
+/// before the current position, found by executing backwards with a
+/// watchpoint on it, stopping at event `mapped_at` when its memory was
+/// mapped. malloc implementations like glibc's and jemalloc's with inline
+/// headers write it when they hand the block out; with others this finds
+/// nothing, or whatever last wrote there. The current position is restored
+/// afterwards.
+fn find_malloc_call(
+    gdb_server: &mut GdbServer,
+    tuid: TaskUid,
+    header: RemotePtr<Void>,
+    mapped_at: FrameTime,
+) -> Option<MallocCall> {
+    let mut timeline = gdb_server.timeline_unwrap_mut();
+    let size = word_size(
+        timeline
+            .current_session()
+            .find_task_from_task_uid(tuid)?
+            .arch(),
+    );
+    let here = timeline.mark();
+    let added = {
+        let t = timeline.current_session().find_task_from_task_uid(tuid)?;
+        let rt = t.as_replay_task().unwrap();
+        // Leave the user's own watchpoint there alone.
+        !timeline.has_watchpoint_at_address(rt, header, size, WatchType::WatchWrite)
+            && timeline.add_watchpoint(rt, header, size, WatchType::WatchWrite, None)
+    };
+
+    let mut found = None;
+    for _ in 0..MAX_MALLOC_CALL_STOPS {
+        let result = timeline.reverse_continue(&|_| true, &|| false);
+        if result.status == ReplayStatus::ReplayExited
+            || timeline.current_session().current_frame_time() < mapped_at
+        {
+            break;
+        }
+        let hit = result
+            .break_status
+            .data_watchpoints_hit()
+            .iter()
+            .any(|w| w.addr == header);
+        if !hit {
+            continue;
+        }
+        if let Some(writer) = result.break_status.task.upgrade() {
+            let backtrace: Vec<String> = symbolized_backtrace(&*writer, MALLOC_CALL_FRAMES)
+                .into_iter()
+                .map(|(_, location)| location)
+                .collect();
+            found = Some(MallocCall {
+                time: timeline.current_session().current_frame_time(),
+                tid: writer.rec_tid(),
+                backtrace: backtrace.join(" <- "),
+            });
+        }
+        break;
+    }
+
+    timeline.seek_to_mark(&here);
+    if added {
+        let t = timeline.current_session().find_task_from_task_uid(tuid)?;
+        timeline.remove_watchpoint(
+            t.as_replay_task().unwrap(),
+            header,
+            size,
+            WatchType::WatchWrite,
+        );
+    }
+    found
+}
+
+/// How many frames `rd-unwind` prints when not told otherwise.
+const DEFAULT_UNWIND_FRAMES: usize = 64;
+
+fn rd_unwind(_: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    let max_frames = match args.get(1) {
+        None => DEFAULT_UNWIND_FRAMES,
+        Some(arg) => match parse_number(arg) {
+            Some(n) if n > 0 => n as usize,
+            _ => return GdbCommandHandler::cmd_error("Usage: rd-unwind [MAX-FRAMES]"),
+        },
+    };
+    let mut backtrace = format_backtrace(t, max_frames);
+    backtrace.pop();
+    OsString::from(backtrace)
+}
+
+/// How far `rd-next-write` looks ahead when not told otherwise.
+const DEFAULT_NEXT_WRITE_EVENTS: FrameTime = 10000;
+
+fn rd_next_write(_: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    const USAGE: &str = "Usage: rd-next-write ADDR LEN [EVENTS]";
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let maybe_numbers: Option<Vec<u64>> = args[1..].iter().map(|arg| parse_number(arg)).collect();
+    let (addr, len, events) = match maybe_numbers.as_deref() {
+        Some(&[addr, len]) if len > 0 => (addr, len, DEFAULT_NEXT_WRITE_EVENTS),
+        Some(&[addr, len, events]) if len > 0 && events > 0 => (addr, len, events),
+        _ => return GdbCommandHandler::cmd_error(USAGE),
+    };
+    let addr = RemotePtr::<Void>::new(addr as usize);
+    let len = len as usize;
+
+    let session = t.session();
+    let replay = session.as_replay().unwrap();
+    if !replay.can_clone() {
+        return GdbCommandHandler::cmd_error(
+            "Can't look ahead from here; step to another instruction and try again",
+        );
+    }
+    let now = replay.current_frame_time();
+    let clone = replay.clone_replay();
+    let lookahead = clone.as_replay().unwrap();
+    let ct = match clone.find_task_from_rec_tid(t.rec_tid()) {
+        Some(ct) => ct,
+        None => return GdbCommandHandler::cmd_error("Current task not found"),
+    };
+    // The clone has the user's watchpoints too; only ours should stop it.
+    for vm in clone.vms() {
+        vm.remove_all_watchpoints();
+    }
+    // The clone is a replay, so ranges the debug registers can't cover are
+    // watched by protecting their pages instead. What's left to fail is
+    // programming the clone's tasks at all.
+    if !ct.vm().add_watchpoint(addr, len, WatchType::WatchWrite) {
+        return GdbCommandHandler::cmd_error(format!(
+            "Can't watch {} bytes at {}; the look-ahead's tasks rejected the watchpoint",
+            len, addr
+        ));
+    }
+
+    let mut constraints = StepConstraints::new(RunCommand::RunContinue);
+    constraints.stop_at_time = now.saturating_add(events);
+    loop {
+        let result = lookahead.replay_step_with_constraints(&constraints);
+        if result.status == ReplayStatus::ReplayExited {
+            return OsString::from(format!(
+                "{}+{:#x} is not written again before the end of the trace.",
+                addr, len
+            ));
+        }
+        if !result.break_status.data_watchpoints_hit().is_empty() {
+            let writer = result.break_status.task.upgrade().unwrap();
+            let mut out = Vec::new();
+            write!(
+                out,
+                "{}+{:#x} is written at event {} by tid {}, stopping at ip {}",
+                addr,
+                len,
+                lookahead.current_frame_time(),
+                writer.rec_tid(),
+                writer.ip()
+            )
+            .unwrap();
+            for v in &result.break_status.watchpoint_values {
+                write!(out, "\n  {:02x?} -> {:02x?}", v.old_value, v.new_value).unwrap();
+            }
+            return OsString::from_vec(out);
+        }
+        if lookahead.current_frame_time() >= constraints.stop_at_time {
+            return OsString::from(format!(
+                "{}+{:#x} is not written in the next {} events.",
+                addr, len, events
+            ));
+        }
+    }
+}
+
+/// Set `setting` from an optional `on` or `off` argument. Returns false if
+/// the argument is something else.
+fn parse_on_off(args: &[OsString], setting: &mut bool) -> bool {
+    match args.get(1).map(|arg| arg.as_bytes()) {
+        None => true,
+        Some(b"on") => {
+            *setting = true;
+            true
+        }
+        Some(b"off") => {
+            *setting = false;
+            true
+        }
+        Some(_) => false,
+    }
+}
+
+pub(super) static NEXT_CHECKPOINT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn rd_catch_sdt(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let probe = match args.get(1) {
+        Some(arg) => String::from_utf8_lossy(arg.as_bytes()).trim().to_owned(),
+        None => {
+            gdb_server.catch_sdt = None;
+            return OsString::from("Not catching SDT probes.");
+        }
+    };
+    let reply = format!("Will stop at {} probes.", probe);
+    gdb_server.catch_sdt = Some(probe);
+    OsString::from(reply)
+}
+
+fn rd_condition(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    const USAGE: &str = "Usage: rd-condition ADDR [EXPRESSION]";
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let addr = match args.get(1).and_then(|arg| parse_number(arg)) {
+        Some(addr) => RemotePtr::<Void>::new(addr as usize),
+        None => return GdbCommandHandler::cmd_error(USAGE),
+    };
+    if args.len() == 2 {
+        gdb_server.rd_conditions.remove(&addr);
+        return OsString::from(format!("Removed the condition at {}.", addr));
+    }
+    let source = args[2..]
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    match source.parse::<ConditionExpression>() {
+        Ok(condition) => {
+            let reply = format!("Will only stop at {} when `{}` holds.", addr, condition);
+            gdb_server.rd_conditions.insert(addr, condition);
+            OsString::from(reply)
+        }
+        Err(e) => GdbCommandHandler::cmd_error(format!("Bad condition: {}", e)),
+    }
+}
+
+fn invoke_checkpoint(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    let where_ = &checkpoint_location(t, &args[1]);
     let checkpoint_id = NEXT_CHECKPOINT_ID.fetch_add(1, Ordering::SeqCst);
 
     let e = if gdb_server.timeline_unwrap().can_add_checkpoint() {
@@ -364,29 +1120,38 @@ fn invoke_checkpoint(gdb_server: &mut GdbServer, _t: &dyn Task, args: &[OsString
     OsString::from_vec(rets)
 }
 
+/// The debugger's description of where `t` is when it has a source line,
+/// otherwise `t`'s ip resolved to a symbol by rd. Debuggers without symbols
+/// for the code only manage `0x... in ?? ()`.
+fn checkpoint_location(t: &dyn Task, debugger_where: &OsStr) -> OsString {
+    if debugger_where.as_bytes().windows(4).any(|w| w == b" at ") {
+        return debugger_where.to_owned();
+    }
+    let ip = t.ip().to_data_ptr::<Void>();
+    OsString::from(format!(
+        "{:#x} in {}",
+        ip.as_usize(),
+        Symbolizer::default().describe(t, ip)
+    ))
+}
+
 fn invoke_delete_checkpoint(
     gdb_server: &mut GdbServer,
     _t: &dyn Task,
     args: &[OsString],
 ) -> OsString {
-    let id: u64 = str::parse::<u64>(str::from_utf8(args[1].as_bytes()).unwrap()).unwrap();
-    // Clone it because we want to then delete it
-    let it = gdb_server.checkpoints.get(&id).cloned();
-    match it {
-        Some(checkpoint) => {
-            if checkpoint.is_explicit == ExplicitCheckpoint::Explicit {
-                gdb_server
-                    .timeline_unwrap_mut()
-                    .remove_explicit_checkpoint(&checkpoint.mark);
-            }
-            gdb_server.checkpoints.remove(&id);
-            let ret = format!("Deleted checkpoint {}.", id);
-            OsString::from(ret)
-        }
-        None => {
-            let ret = format!("No checkpoint number {}.", id);
-            OsString::from(ret)
-        }
+    let maybe_id = args
+        .get(1)
+        .and_then(|arg| str::from_utf8(arg.as_bytes()).ok())
+        .and_then(|arg| str::parse::<u64>(arg.trim()).ok());
+    let id = match maybe_id {
+        Some(id) => id,
+        None => return GdbCommandHandler::cmd_error("Usage: delete checkpoint N"),
+    };
+    if gdb_server.delete_checkpoint(id) {
+        OsString::from(format!("Deleted checkpoint {}.", id))
+    } else {
+        GdbCommandHandler::cmd_error(format!("No checkpoint number {}.", id))
     }
 }
 
@@ -399,10 +1164,191 @@ fn invoke_info_checkpoints(
         return OsString::from("No checkpoints.");
     }
     let mut out = Vec::new();
-    out.extend_from_slice(b"ID\tWhen\tWhere");
+    out.extend_from_slice(b"ID\tWhen\tTicks\tMemory\tWhere");
     for (&id, c) in gdb_server.checkpoints.iter() {
-        write!(out, "\n{}\t{}\t", id, c.mark.time()).unwrap();
+        let memory = match c.memory_cost() {
+            Some(bytes) => format_memory_size(bytes),
+            None => "-".into(),
+        };
+        write!(out, "\n{}\t{}\t{}\t{}\t", id, c.time(), c.ticks(), memory).unwrap();
         out.extend_from_slice(c.where_.as_bytes());
     }
     OsString::from_vec(out)
 }
+
+/// Don't flood gdb with output when comparing large ranges.
+const MAX_MEMORY_DIFF_RUNS: usize = 32;
+
+/// The most memory rd-diff-checkpoint reads from each checkpoint.
+const MAX_MEMORY_DIFF_LEN: usize = 16 * 1024 * 1024;
+
+fn rd_diff_checkpoint(gdb_server: &mut GdbServer, _t: &dyn Task, args: &[OsString]) -> OsString {
+    const USAGE: &str = "Usage: rd-diff-checkpoint A B [ADDR LEN]";
+    let maybe_numbers: Option<Vec<u64>> = args[1..].iter().map(|arg| parse_number(arg)).collect();
+    let (a, b, maybe_range) = match maybe_numbers.as_deref() {
+        Some(&[a, b]) => (a, b, None),
+        Some(&[a, b, addr, len]) => (
+            a,
+            b,
+            Some((RemotePtr::<Void>::new(addr as usize), len as usize)),
+        ),
+        _ => return GdbCommandHandler::cmd_error(USAGE),
+    };
+    if let Some((_, len)) = maybe_range {
+        if len > MAX_MEMORY_DIFF_LEN {
+            return GdbCommandHandler::cmd_error(format!(
+                "Can't compare more than {} bytes of memory.",
+                MAX_MEMORY_DIFF_LEN
+            ));
+        }
+    }
+    let (ca, cb) = match (gdb_server.get_checkpoint(a), gdb_server.get_checkpoint(b)) {
+        (Some(ca), Some(cb)) => (ca, cb),
+        (None, _) => return GdbCommandHandler::cmd_error(format!("No checkpoint number {}.", a)),
+        (_, None) => return GdbCommandHandler::cmd_error(format!("No checkpoint number {}.", b)),
+    };
+
+    let mut out = Vec::new();
+    write!(
+        out,
+        "Checkpoint {} (event {}) -> checkpoint {} (event {}):",
+        a,
+        ca.time(),
+        b,
+        cb.time()
+    )
+    .unwrap();
+    let regs_a = ca.mark.regs();
+    let regs_b = cb.mark.regs();
+    if regs_a.arch() != regs_b.arch() {
+        write!(
+            out,
+            "\n  The checkpoints are stopped in tasks of different architectures."
+        )
+        .unwrap();
+    } else {
+        let diffs = regs_a.differences(&regs_b);
+        if diffs.is_empty() {
+            write!(out, "\n  No register differs.").unwrap();
+        }
+        for (name, val_a, val_b) in diffs {
+            write!(out, "\n  {}: {:#x} -> {:#x}", name, val_a, val_b).unwrap();
+        }
+    }
+
+    if let Some((addr, len)) = maybe_range {
+        let memory = read_checkpoint_memory(ca, addr, len)
+            .and_then(|mem_a| Ok((mem_a, read_checkpoint_memory(cb, addr, len)?)));
+        match memory {
+            Ok((mem_a, mem_b)) => write_memory_diff(&mut out, addr, &mem_a, &mem_b),
+            Err(e) => write!(out, "\n  Can't compare memory: {}", e).unwrap(),
+        }
+    }
+    OsString::from_vec(out)
+}
+
+/// Read `len` bytes at `addr` in the task gdb was focused on when `c` was
+/// created. This is done in a throwaway clone of the checkpoint's session so
+/// that neither the checkpoint nor the current position is disturbed.
+fn read_checkpoint_memory(
+    c: &Checkpoint,
+    addr: RemotePtr<Void>,
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    let session = match c.mark.checkpoint_session() {
+        Some(session) => session,
+        None => return Err(format!("no session was saved at event {}", c.time())),
+    };
+    let clone = session.as_replay().unwrap().clone_replay();
+    let t = match clone.find_task_from_task_uid(c.last_continue_tuid) {
+        Some(t) => t,
+        None => {
+            return Err(format!(
+                "task {} doesn't exist at event {}",
+                c.last_continue_tuid.tid(),
+                c.time()
+            ))
+        }
+    };
+    let mut buf = vec![0u8; len];
+    match t.read_bytes_fallible(addr, &mut buf) {
+        Ok(nread) if nread == len => Ok(buf),
+        _ => Err(format!(
+            "{} bytes at {} aren't all readable at event {}",
+            len,
+            addr,
+            c.time()
+        )),
+    }
+}
+
+/// Write each run of differing bytes as `addr: old bytes -> new bytes`.
+fn write_memory_diff(out: &mut Vec<u8>, addr: RemotePtr<Void>, mem_a: &[u8], mem_b: &[u8]) {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < mem_a.len() {
+        if mem_a[i] == mem_b[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < mem_a.len() && mem_a[i] != mem_b[i] {
+            i += 1;
+        }
+        runs.push(start..i);
+    }
+
+    let ndiffering: usize = runs.iter().map(|r| r.len()).sum();
+    write!(
+        out,
+        "\n  Memory {}+{:#x}: {} byte(s) differ",
+        addr,
+        mem_a.len(),
+        ndiffering
+    )
+    .unwrap();
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    for r in runs.iter().take(MAX_MEMORY_DIFF_RUNS) {
+        write!(
+            out,
+            "\n  {}: {} -> {}",
+            addr + r.start,
+            hex(&mem_a[r.clone()]),
+            hex(&mem_b[r.clone()])
+        )
+        .unwrap();
+    }
+    if runs.len() > MAX_MEMORY_DIFF_RUNS {
+        write!(
+            out,
+            "\n  ... and {} more",
+            runs.len() - MAX_MEMORY_DIFF_RUNS
+        )
+        .unwrap();
+    }
+}
+
+/// Parse a decimal or 0x-prefixed hexadecimal number.
+fn parse_number(arg: &OsStr) -> Option<u64> {
+    let s = str::from_utf8(arg.as_bytes()).ok()?.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn format_memory_size(bytes: usize) -> String {
+    const KB: usize = 1024;
+    const MB: usize = 1024 * KB;
+    if bytes >= MB {
+        format!("{:.1}MB", bytes as f64 / MB as f64)
+    } else {
+        format!("{}KB", bytes / KB)
+    }
+}
@@ -3,11 +3,15 @@ use crate::{
         gdb_command::{gdb_command_map, BaseGdbCommand, GdbCommand},
         gdb_server::GdbServer,
     },
-    log::LogDebug,
+    log::{LogDebug, LogWarn},
     session::task::Task,
     util::{find, str16_to_usize},
 };
-use std::{ffi::OsString, io::Write, os::unix::ffi::OsStringExt};
+use std::{
+    ffi::{OsStr, OsString},
+    io::Write,
+    os::unix::ffi::OsStringExt,
+};
 
 pub struct GdbCommandHandler;
 
@@ -23,19 +27,10 @@ python
 import re
 
 def gdb_unescape(string):
-    result = ""
-    pos = 0
-    while pos < len(string):
-        result += chr(int(string[pos:pos+2], 16))
-        pos += 2
-    return result
+    return bytes.fromhex(string).decode('utf-8', 'replace')
 
 def gdb_escape(string):
-    result = ""
-    pos = 0
-    for curr_char in string:
-        result += format(ord(curr_char), '02x')
-    return result
+    return string.encode('utf-8', 'surrogateescape').hex()
 
 class RDWhere(gdb.Command):
     """Helper to get the location for checkpoints/history. Used by auto-args"""
@@ -83,8 +78,15 @@ class RDCmd(gdb.Command):
         if not rv_match:
             gdb.write("Response error: " + rv)
             return
-        response = gdb_unescape(rv_match.group(1))
-        gdb.write(response)
+        response = rv_match.group(1)
+        if response.startswith("E"):
+            raise gdb.GdbError(gdb_unescape(response[1:]))
+        if self.cmd_name in rd_seek_commands:
+            # gdb doesn't know the replay moved under it.
+            gdb.execute("maint flush register-cache", to_string=True)
+        gdb.write(gdb_unescape(response))
+
+rd_seek_commands = ['back', 'forward', 'seek-ticks']
 
 def history_push(p):
     gdb.execute("rd-history-push", to_string=True)
@@ -112,6 +114,63 @@ class RDSetSuppressRunHook(gdb.Command):
 RDHookRun()
 RDSetSuppressRunHook()
 
+class RDBacktraceOrigin(gdb.Command):
+    """Print the backtrace and, for each non-null pointer argument or local,
+the malloc call that allocated the memory it points to and the event at
+which that memory was mapped (see rd-alloc-origin)."""
+    def __init__(self):
+        gdb.Command.__init__(self, 'rd-bt-origin',
+                             gdb.COMMAND_USER, gdb.COMPLETE_NONE, False)
+
+    def frame_pointers(self, frame):
+        pointers = []
+        try:
+            block = frame.block()
+        except RuntimeError:
+            return pointers
+        while block:
+            for sym in block:
+                if not (sym.is_argument or sym.is_variable):
+                    continue
+                try:
+                    value = sym.value(frame)
+                    if value.type.strip_typedefs().code != gdb.TYPE_CODE_PTR:
+                        continue
+                    addr = int(value)
+                except (gdb.error, RuntimeError):
+                    continue
+                if addr != 0:
+                    pointers.append((sym.print_name, addr))
+            if block.function:
+                break
+            block = block.superblock
+        return pointers
+
+    def invoke(self, arg, from_tty):
+        frames = []
+        frame = gdb.newest_frame()
+        while frame:
+            frames.append((frame, self.frame_pointers(frame)))
+            frame = frame.older()
+        addrs = sorted(set(addr for _, pointers in frames for _, addr in pointers))
+        origins = {}
+        if addrs:
+            out = gdb.execute('rd-alloc-origin ' + ' '.join(hex(a) for a in addrs),
+                              to_string=True)
+            for line in out.splitlines():
+                addr, _, origin = line.partition(': ')
+                origins[int(addr, 16)] = origin
+        for level, (frame, pointers) in enumerate(frames):
+            sal = frame.find_sal()
+            where = ''
+            if sal.symtab:
+                where = ' at %s:%d' % (sal.symtab.filename, sal.line)
+            gdb.write('#%-3d0x%016x in %s%s\n' % (level, frame.pc(), frame.name() or '??', where))
+            for name, addr in pointers:
+                gdb.write('      %s = 0x%x: %s\n' % (name, addr, origins.get(addr, '?')))
+
+RDBacktraceOrigin()
+
 #Automatically push an history entry when the program execution stops
 #(signal, breakpoint).This is fired before an interactive prompt is shown.
 #Disabled for now since it's not fully working.
@@ -139,20 +198,110 @@ end
         ss
     }
 
+    /// The lldb counterpart of gdb_macros(). lldb has nothing like gdb's
+    /// `define`/`document`, so the rd commands are registered from Python and
+    /// reach rd through `process plugin packet send` rather than `maint packet`.
+    ///
+    /// There is no lldb equivalent of the `restart` macro: lldb never sends the
+    /// vRun packet we use to restart replay.
+    pub fn lldb_macros() -> String {
+        BaseGdbCommand::init_auto_args();
+        let mut ss = String::new();
+        let s = r##"import lldb
+import re
+import shlex
+
+def rd_escape(string):
+    return string.encode('utf-8', 'surrogateescape').hex()
+
+def rd_unescape(string):
+    return bytes.fromhex(string).decode('utf-8', 'replace')
+
+def rd_run(debugger, command):
+    ret = lldb.SBCommandReturnObject()
+    debugger.GetCommandInterpreter().HandleCommand(command, ret)
+    return ret.GetOutput() or ""
+
+def rd_where(debugger, command, result, internal_dict):
+    """Helper to get the location for checkpoints/history. Used by auto-args"""
+    frame = debugger.GetSelectedTarget().GetProcess().GetSelectedThread().GetFrameAtIndex(0)
+    if not frame.IsValid():
+        result.write("???")
+        return
+    rv = "0x%x in %s" % (frame.GetPC(), frame.GetFunctionName() or "??")
+    line_entry = frame.GetLineEntry()
+    if line_entry.IsValid():
+        rv += " at %s:%d" % (line_entry.GetFileSpec().GetFilename(), line_entry.GetLine())
+    result.write(rv)
+
+def rd_make_cmd(cmd_name, auto_args, post_cmd):
+    def invoke(debugger, command, result, internal_dict):
+        packet = "qRDCmd:" + rd_escape(cmd_name)
+        for auto_arg in auto_args:
+            packet += ":" + rd_escape(rd_run(debugger, auto_arg))
+        for arg in shlex.split(command):
+            packet += ":" + rd_escape(arg)
+        rv = rd_run(debugger, "process plugin packet send " + packet)
+        rv_match = re.search('response: (.*)', rv)
+        if not rv_match:
+            result.write("Response error: " + rv)
+            return
+        response = rv_match.group(1).strip()
+        if response.startswith("E"):
+            result.SetError(rd_unescape(response[1:]))
+            return
+        result.write(rd_unescape(response))
+        if post_cmd:
+            result.write(rd_run(debugger, post_cmd))
+    return invoke
+
+def rd_add_cmd(cmd_name, auto_args, docs, post_cmd = None):
+    # lldb command names can't contain spaces, so e.g. `info checkpoints`
+    # becomes `info-checkpoints`.
+    lldb_name = cmd_name.replace(' ', '-')
+    func = 'rd_cmd_' + lldb_name.replace('-', '_')
+    globals()[func] = rd_make_cmd(cmd_name, auto_args, post_cmd)
+    docs = docs.replace('\n', ' ').replace('"', '\\"')
+    lldb.debugger.HandleCommand('command script add -h "%s" -f %s %s' % (docs, func, lldb_name))
+
+lldb.debugger.HandleCommand('command script add -f rd_where rd-where')
+lldb.debugger.HandleCommand('settings set prompt "(rd) "')
+"##;
+
+        ss.push_str(s);
+
+        for it in gdb_command_map().values() {
+            ss.push_str(&lldb_macro_binding(&**it));
+        }
+
+        ss
+    }
+
     /// Process an incoming GDB payload of the following form:
     ///   <command name>:<arg1>:<arg2>:...
+    /// where the name and each argument are hex encoded, two characters per
+    /// byte, so arguments may contain ':' or bytes that aren't valid UTF-8.
+    ///
+    /// The reply is the hex encoded response, or for errors, `E` followed by
+    /// the hex encoded message. Hex digits are lower case so the two can't be
+    /// confused.
     ///
     /// NOTE: RD Commands are typically sent with the qRDCmd: prefix which
     /// should have been stripped already.
     pub fn process_command(gdb_server: &mut GdbServer, t: &dyn Task, payload: &[u8]) -> Vec<u8> {
-        let args = parse_cmd(payload);
-        let name = args[0].clone().into_string().unwrap();
-        let maybe_cmd = Self::command_for_name(&name);
+        let args = match parse_cmd(payload) {
+            Some(args) if !args.is_empty() => args,
+            _ => {
+                log!(LogWarn, "Malformed rd command payload {:?}", payload);
+                return error_reply(b"Malformed rd command.");
+            }
+        };
+        let maybe_cmd = args[0].to_str().and_then(Self::command_for_name);
         match maybe_cmd {
             None => {
                 let mut msg: Vec<u8> = Vec::new();
-                writeln!(msg, "Command {:?} not found.", args[0]).unwrap();
-                gdb_escape(&msg)
+                write!(msg, "Command {:?} not found.", args[0]).unwrap();
+                error_reply(&msg)
             }
             Some(cmd) => {
                 log!(LogDebug, "invoking command: {:?}", cmd.name());
@@ -164,7 +313,11 @@ end
                 }
 
                 log!(LogDebug, "cmd response: {:?}", resp);
-                let mut res = OsString::into_vec(resp);
+                let res = OsString::into_vec(resp);
+                if let Some(msg) = res.strip_prefix(CMD_ERROR_PREFIX) {
+                    return error_reply(msg);
+                }
+                let mut res = res;
                 res.push(b'\n');
                 gdb_escape(&res)
             }
@@ -183,6 +336,22 @@ end
     pub fn cmd_end_diversion() -> OsString {
         OsString::from("RDCmd_EndDiversion")
     }
+
+    /// Return value for commands that failed. gdb reports `msg` as an error,
+    /// which e.g. stops a gdb script that ran the command.
+    pub fn cmd_error<T: AsRef<OsStr>>(msg: T) -> OsString {
+        let mut ret = OsString::from_vec(CMD_ERROR_PREFIX.to_vec());
+        ret.push(msg);
+        ret
+    }
+}
+
+const CMD_ERROR_PREFIX: &[u8] = b"RDCmd_Error:";
+
+fn error_reply(msg: &[u8]) -> Vec<u8> {
+    let mut reply = vec![b'E'];
+    reply.extend_from_slice(&gdb_escape(msg));
+    reply
 }
 
 /// Use the simplest two hex character by byte encoding
@@ -194,32 +363,40 @@ fn gdb_escape(s: &[u8]) -> Vec<u8> {
     ss
 }
 
-fn gdb_unescape(mut s: &[u8]) -> Vec<u8> {
-    assert_eq!(s.len() % 2, 0);
+/// None if `s` isn't valid hex
+fn gdb_unescape(mut s: &[u8]) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
     let mut ss = Vec::new();
     while s.len() >= 2 {
-        let mut ignore = Default::default();
-        let val: u8 = str16_to_usize(&s[0..2], &mut ignore).unwrap() as u8;
-        assert_eq!(ignore.len(), 0);
-        ss.push(val);
+        let mut rest = Default::default();
+        let val = str16_to_usize(&s[0..2], &mut rest).ok()?;
+        if !rest.is_empty() {
+            return None;
+        }
+        ss.push(val as u8);
         s = &s[2..];
     }
-    ss
+    Some(ss)
 }
 
-fn parse_cmd(mut s: &[u8]) -> Vec<OsString> {
+/// Split and decode a `<name>:<arg1>:...` payload. An argument after the last
+/// ':' is kept even if it is empty, so `rd-foo ""` has one (empty) argument.
+fn parse_cmd(mut s: &[u8]) -> Option<Vec<OsString>> {
     let mut args: Vec<OsString> = Vec::new();
     while let Some(pos) = find(s, b":") {
-        args.push(OsString::from_vec(gdb_unescape(&s[0..pos])));
+        args.push(OsString::from_vec(gdb_unescape(&s[0..pos])?));
         s = &s[pos + 1..];
     }
-    if !s.is_empty() {
-        args.push(OsString::from_vec(gdb_unescape(s)));
+    if !s.is_empty() || !args.is_empty() {
+        args.push(OsString::from_vec(gdb_unescape(s)?));
     }
-    args
+    Some(args)
 }
 
-fn gdb_macro_binding(cmd: &dyn GdbCommand) -> String {
+/// Format the auto args of `cmd` as a Python list literal
+fn python_auto_args(cmd: &dyn GdbCommand) -> String {
     let mut auto_args_str = String::from("[");
     for (i, arg) in cmd.auto_args().iter().enumerate() {
         if i > 0 {
@@ -228,6 +405,11 @@ fn gdb_macro_binding(cmd: &dyn GdbCommand) -> String {
         auto_args_str.push_str(&format!("{:?}", arg));
     }
     auto_args_str.push(']');
+    auto_args_str
+}
+
+fn gdb_macro_binding(cmd: &dyn GdbCommand) -> String {
+    let auto_args_str = python_auto_args(cmd);
     let mut ret = format!("python RDCmd('{}', {})\n", cmd.name(), auto_args_str);
     if !cmd.docs().is_empty() {
         ret.push_str(&format!("document {}\n{}\nend\n", cmd.name(), cmd.docs()));
@@ -235,3 +417,40 @@ fn gdb_macro_binding(cmd: &dyn GdbCommand) -> String {
 
     ret
 }
+
+fn lldb_macro_binding(cmd: &dyn GdbCommand) -> String {
+    let auto_args_str = python_auto_args(cmd);
+    // Mirrors the hookpost-back/hookpost-forward definitions in gdb_macros()
+    let post_cmd = match cmd.name() {
+        "back" | "forward" => ", 'frame info'",
+        _ => "",
+    };
+    format!(
+        "rd_add_cmd('{}', {}, {:?}{})\n",
+        cmd.name(),
+        auto_args_str,
+        cmd.docs(),
+        post_cmd
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn parse_cmd_round_trip() {
+        let args: [&[u8]; 4] = [b"rd-cmd", b"a:b", b"", b"\xff\xfe"];
+        let payload: Vec<Vec<u8>> = args.iter().map(|a| gdb_escape(a)).collect();
+        let parsed = parse_cmd(&payload.join(&b':')).unwrap();
+        let parsed: Vec<&[u8]> = parsed.iter().map(|a| a.as_bytes()).collect();
+        assert_eq!(parsed, args);
+    }
+
+    #[test]
+    fn parse_cmd_rejects_bad_hex() {
+        assert!(parse_cmd(b"7264:6").is_none());
+        assert!(parse_cmd(b"72zz").is_none());
+    }
+}
@@ -1,15 +1,18 @@
 #[allow(unused_imports)]
 use crate::{
-    bindings::signal::siginfo_t,
+    bindings::signal::{siginfo_t, SI_TKILL},
     breakpoint_condition::BreakpointCondition,
-    commands::gdb_command_handler::GdbCommandHandler,
+    commands::{gdb_command::NEXT_CHECKPOINT_ID, gdb_command_handler::GdbCommandHandler},
+    condition_expression::ConditionExpression,
+    event::{Event, EventType},
     extra_registers::ExtraRegisters,
     gdb_connection::{
-        GdbActionType, GdbConnection, GdbConnectionFeatures, GdbContAction, GdbRegisterValue,
-        GdbRegisterValueData, GdbRequest, GdbRequestType, GdbRestartType, GdbThreadId, DREQ_CONT,
-        DREQ_DETACH, DREQ_FILE_CLOSE, DREQ_FILE_OPEN, DREQ_FILE_PREAD, DREQ_FILE_SETFS,
-        DREQ_GET_AUXV, DREQ_GET_CURRENT_THREAD, DREQ_GET_EXEC_FILE, DREQ_GET_IS_THREAD_ALIVE,
-        DREQ_GET_MEM, DREQ_GET_OFFSETS, DREQ_GET_REG, DREQ_GET_REGS, DREQ_GET_STOP_REASON,
+        GdbActionType, GdbConnection, GdbConnectionFeatures, GdbContAction, GdbProcessInfo,
+        GdbRegisterValue, GdbRegisterValueData, GdbRequest, GdbRequestType, GdbRestartType,
+        GdbThreadId, DREQ_ATTACH, DREQ_CONT, DREQ_DETACH, DREQ_FILE_CLOSE, DREQ_FILE_OPEN,
+        DREQ_FILE_PREAD, DREQ_FILE_SETFS, DREQ_GET_AUXV, DREQ_GET_CURRENT_THREAD,
+        DREQ_GET_EXEC_FILE, DREQ_GET_IS_THREAD_ALIVE, DREQ_GET_MEM, DREQ_GET_OFFSETS,
+        DREQ_GET_PROCESS_INFO, DREQ_GET_REG, DREQ_GET_REGS, DREQ_GET_STOP_REASON,
         DREQ_GET_THREAD_EXTRA_INFO, DREQ_GET_THREAD_LIST, DREQ_INTERRUPT, DREQ_NONE, DREQ_QSYMBOL,
         DREQ_RD_CMD, DREQ_READ_SIGINFO, DREQ_REMOVE_HW_BREAK, DREQ_REMOVE_RDWR_WATCH,
         DREQ_REMOVE_RD_WATCH, DREQ_REMOVE_SW_BREAK, DREQ_REMOVE_WR_WATCH, DREQ_RESTART,
@@ -18,21 +21,27 @@ use crate::{
         DREQ_SET_SW_BREAK, DREQ_SET_WR_WATCH, DREQ_TLS, DREQ_WRITE_SIGINFO,
     },
     gdb_expression::{GdbExpression, GdbExpressionValue},
-    gdb_register::{GdbRegister, DREG_64_YMM15H, DREG_ORIG_EAX, DREG_ORIG_RAX, DREG_YMM7H},
+    gdb_register::{
+        GdbRegister, DREG_64_YMM15H, DREG_64_ZMM31H, DREG_ORIG_EAX, DREG_ORIG_RAX, DREG_RD_EVENT,
+        DREG_RD_TICKS, DREG_YMM7H, DREG_ZMM7H,
+    },
+    gdb_target_desc::cpu_features,
     kernel_abi::{syscall_number_for_execve, SupportedArch},
     log::dump_rd_stack,
     log::{LogDebug, LogError, LogInfo, LogWarn},
+    memory_search::{search_range, SearchPattern},
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
+    remote_memory::PageCache,
     remote_ptr::{RemotePtr, Void},
     replay_timeline::{self, Mark, ReplayTimeline, ReplayTimelineSharedPtr, RunDirection},
     scoped_fd::{ScopedFd, ScopedFdSharedPtr, ScopedFdSharedWeakPtr},
     session::{
         address_space::{
             address_space::BREAKPOINT_INSN, memory_range::MemoryRange, BreakpointType,
-            MappingFlags, WatchType,
+            MappingFlags, WatchConfig, WatchType,
         },
-        diversion_session::{DiversionSession, DiversionStatus},
+        diversion_session::{DiversionSession, DiversionStatus, SteppingClock},
         replay_session::{ReplayResult, ReplaySession, ReplayStatus},
         session_inner::{BreakStatus, RunCommand},
         task::{
@@ -46,23 +55,25 @@ use crate::{
     sig::Sig,
     taskish_uid::{TaskUid, ThreadGroupUid},
     thread_db::ThreadDb,
+    ticks::Ticks,
     trace::trace_frame::FrameTime,
     util::read_to_end,
     util::write_all,
     util::{
-        cpuid, create_temporary_file, find, flat_env, floor_page_size, open_socket, page_size,
-        to_cstring_array, trace_instructions_up_to_event, u8_slice, u8_slice_mut, word_size,
-        ProbePort, AVX_FEATURE_FLAG, CPUID_GETFEATURES, OSXSAVE_FEATURE_FLAG,
+        create_temporary_file, flat_env, open_socket, to_cstring_array,
+        trace_instructions_up_to_event, u8_slice, u8_slice_mut, word_size, xcr0,
+        xsave_layout_from_trace, xsave_native_layout, ProbePort,
     },
 };
 use libc::{pid_t, SIGKILL, SIGTRAP};
 use nix::{
     errno::{errno, Errno},
+    fcntl::OFlag,
     sys::{
         mman::{MapFlags, ProtFlags},
         stat::{major, minor},
     },
-    unistd::{dup, execvpe, getpid, read, unlink, write},
+    unistd::{access, dup, execvpe, getpid, read, unlink, write, AccessFlags},
     Error,
 };
 use std::{
@@ -83,6 +94,8 @@ use std::{
     ptr,
     ptr::copy_nonoverlapping,
     rc::Rc,
+    sync::atomic::Ordering,
+    time::Instant,
 };
 
 const LOCALHOST_ADDR: &'static str = "127.0.0.1";
@@ -136,6 +149,29 @@ impl Default for ConnectionFlags {
     }
 }
 
+/// The most memory-write data we hold back before writing it to the tracee.
+const MAX_PENDING_MEM_WRITE: usize = 16 * 1024 * 1024;
+
+/// Consecutive gdb memory writes to contiguous addresses in a diversion,
+/// acknowledged but not yet written to the tracee.
+struct PendingMemWrite {
+    tuid: TaskUid,
+    addr: RemotePtr<Void>,
+    data: Vec<u8>,
+}
+
+impl PendingMemWrite {
+    /// Whether a write of `len` bytes by `tuid` to `addr` can be appended.
+    fn extends_to(&self, tuid: TaskUid, addr: RemotePtr<Void>, len: usize) -> bool {
+        self.tuid == tuid
+            && self.addr + self.data.len() == addr
+            && self.data.len() + len <= MAX_PENDING_MEM_WRITE
+    }
+}
+
+/// How far the emulated clocks advance on each read during a diversion.
+const DIVERSION_CLOCK_STEP_NS: i64 = 1000;
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub(super) enum ExplicitCheckpoint {
     Explicit,
@@ -169,6 +205,23 @@ impl Checkpoint {
             where_: where_.to_owned(),
         }
     }
+
+    /// The event this checkpoint was created at.
+    pub fn time(&self) -> FrameTime {
+        self.mark.time()
+    }
+
+    /// The tick count of the current task when this checkpoint was created.
+    pub fn ticks(&self) -> Ticks {
+        self.mark.ticks()
+    }
+
+    /// How much memory keeping this checkpoint alive costs. None for
+    /// checkpoints that don't hold a session of their own (see
+    /// ExplicitCheckpoint::NotExplicit).
+    pub fn memory_cost(&self) -> Option<usize> {
+        self.mark.checkpoint_memory_cost()
+    }
 }
 
 pub type GdbConnectionSharedPtr = Rc<RefCell<GdbConnection>>;
@@ -178,9 +231,10 @@ pub struct GdbServer {
     /// dbg is initially null. Once the debugger connection is established, it
     /// never changes.
     dbg: Option<GdbConnectionSharedPtr>,
-    /// When dbg is non-null, the ThreadGroupUid of the task being debugged. Never
-    /// changes once the connection is established --- we don't currently
-    /// support switching gdb between debuggee processes.
+    /// When dbg is non-null, the ThreadGroupUid of the task being debugged.
+    /// Stops are only reported for this thread group. It changes when gdb
+    /// attaches to or resumes a thread of another process (see
+    /// `switch_debuggee()`).
     /// NOTE: @TODO Zero if not set. Change to option?
     debuggee_tguid: ThreadGroupUid,
     /// ThreadDb for debuggee ThreadGroup
@@ -216,22 +270,57 @@ pub struct GdbServer {
     /// The pid for gdb's last vFile:setfs
     /// NOTE: @TODO Zero if not set. Change to option?
     file_scope_pid: pid_t,
+    /// Set by `rd-break-event`: stop when execution reaches this event, in
+    /// either direction.
+    /// DIFF NOTE: Not present in rr.
+    pub(super) break_at_event: Option<FrameTime>,
+    /// Set by `rd-catch-sdt`: stop after the debuggee hits an SDT probe of this
+    /// provider, or `provider:name`, when running forward.
+    /// DIFF NOTE: Not present in rr.
+    pub(super) catch_sdt: Option<String>,
+    /// Set by `rd-follow-fork`: when the debuggee forks, debug the child.
+    /// DIFF NOTE: Not present in rr.
+    pub(super) follow_fork: bool,
+    /// Set by `rd-follow-exec`: when the debuggee execs, keep debugging it
+    /// instead of refusing to go past the exec.
+    /// DIFF NOTE: Not present in rr.
+    pub(super) follow_exec: bool,
+    /// Set by `rd-go-preemption-stops`: report the SIGURGs the Go runtime
+    /// sends itself to preempt goroutines, which can happen thousands of times
+    /// a second.
+    /// DIFF NOTE: Not present in rr, which always reports them.
+    pub(super) stop_on_go_preemption: bool,
+    /// Set by `rd-condition`: conditions in rd's own expression language for
+    /// the breakpoints and watchpoints at these addresses. They apply when gdb
+    /// next inserts the breakpoint or watchpoint.
+    /// DIFF NOTE: Not present in rr.
+    pub(super) rd_conditions: HashMap<RemotePtr<Void>, ConditionExpression>,
+    /// Tracee memory read by the debugger since the last request that could
+    /// have changed it.
+    /// DIFF NOTE: Not present in rr.
+    mem_cache: PageCache,
+    /// gdb writes memory in packet-sized pieces (e.g. for `restore`). Writes
+    /// to contiguous addresses are gathered here and written in one go before
+    /// anything else is done with the diversion. See `flush_mem_writes()`.
+    /// DIFF NOTE: Not present in rr.
+    pending_mem_write: Option<PendingMemWrite>,
 }
 
 impl GdbServer {
-    fn thread_db_mut_unwrap(&mut self) -> &mut ThreadDb {
-        self.thread_db.as_mut().unwrap()
-    }
-
-    fn thread_db_unwrap(&self) -> &ThreadDb {
-        self.thread_db.as_ref().unwrap()
+    /// The ThreadDb of the debuggee, loading libthread_db if necessary. None if
+    /// libthread_db isn't available.
+    fn thread_db_mut(&mut self) -> Option<&mut ThreadDb> {
+        if self.thread_db.is_none() {
+            self.thread_db = ThreadDb::new(self.debuggee_tguid.tid());
+        }
+        self.thread_db.as_deref_mut()
     }
 
-    fn dbg_unwrap(&self) -> Ref<GdbConnection> {
+    pub(super) fn dbg_unwrap(&self) -> Ref<GdbConnection> {
         self.dbg.as_ref().unwrap().borrow()
     }
 
-    fn dbg_unwrap_mut(&mut self) -> RefMut<GdbConnection> {
+    pub(super) fn dbg_unwrap_mut(&mut self) -> RefMut<GdbConnection> {
         self.dbg.as_ref().unwrap().borrow_mut()
     }
 
@@ -260,6 +349,8 @@ impl GdbServer {
             dbg: Default::default(),
             debuggee_tguid: Default::default(),
             thread_db: Default::default(),
+            mem_cache: Default::default(),
+            pending_mem_write: None,
             last_continue_tuid: Default::default(),
             last_query_tuid: Default::default(),
             final_event: u64::MAX,
@@ -275,6 +366,12 @@ impl GdbServer {
             symbols_loc: Default::default(),
             files: Default::default(),
             file_scope_pid: Default::default(),
+            break_at_event: None,
+            catch_sdt: None,
+            follow_fork: false,
+            follow_exec: false,
+            stop_on_go_preemption: false,
+            rd_conditions: Default::default(),
         }
     }
 
@@ -291,6 +388,8 @@ impl GdbServer {
             file_scope_pid: 0,
             target: Default::default(),
             thread_db: Default::default(),
+            mem_cache: Default::default(),
+            pending_mem_write: None,
             stop_siginfo: Default::default(),
             in_debuggee_end_state: Default::default(),
             timeline: Default::default(),
@@ -299,6 +398,12 @@ impl GdbServer {
             symbols: Default::default(),
             symbols_loc: Default::default(),
             files: Default::default(),
+            break_at_event: None,
+            catch_sdt: None,
+            follow_fork: false,
+            follow_exec: false,
+            stop_on_go_preemption: false,
+            rd_conditions: Default::default(),
         }
     }
 
@@ -390,7 +495,7 @@ impl GdbServer {
 
         let listen_fd: ScopedFd;
         let t_tgid: pid_t;
-        let t_arch: SupportedArch;
+        let t_cpu_features: u32;
         {
             // We MUST have a current task
             let t = self
@@ -400,16 +505,18 @@ impl GdbServer {
                 .unwrap();
 
             t_tgid = t.tgid();
-            t_arch = t.arch();
+            t_cpu_features = get_cpu_features(self.timeline_unwrap().current_session(), t.arch());
             listen_fd = open_socket(&flags.dbg_host, &mut port, probe);
 
             if flags.debugger_params_write_pipe.is_some() {
-                let c_exe_image = CString::new(t.vm().exe_image().as_bytes()).unwrap();
+                let c_exe_image = CString::new(exec_file(&**t).as_bytes()).unwrap();
                 let len = c_exe_image.as_bytes_with_nul().len();
                 assert!(len <= libc::PATH_MAX as usize);
                 let mut exe_image = [0u8; libc::PATH_MAX as usize];
                 exe_image[0..len].copy_from_slice(&c_exe_image.as_bytes_with_nul());
                 let mut host = [0u8; 16];
+                // Leave room for the terminating NUL that launch_gdb() looks for
+                assert!(flags.dbg_host.len() < host.len());
                 host[0..flags.dbg_host.len()].copy_from_slice(flags.dbg_host.as_bytes());
                 let params = DebuggerParams {
                     exe_image,
@@ -421,7 +528,7 @@ impl GdbServer {
                 // DIFF NOTE: This is a debug_assert in rr
                 assert_eq!(nwritten, mem::size_of_val(&params));
             } else {
-                eprintln!("Launch gdb with");
+                eprintln!("Launch debugger with");
                 write_debugger_launch_command(
                     &**t,
                     &flags.dbg_host,
@@ -450,7 +557,7 @@ impl GdbServer {
             log!(LogDebug, "initializing debugger connection");
             self.dbg = Some(Rc::new(RefCell::new(await_connection(
                 t_tgid,
-                t_arch,
+                t_cpu_features,
                 &listen_fd,
                 GdbConnectionFeatures::default(),
             ))));
@@ -481,9 +588,6 @@ impl GdbServer {
         gdb_binary_file_path: &Path,
         gdb_options: &[OsString],
     ) {
-        let macros = gdb_rd_macros();
-        let gdb_command_file = create_gdb_command_file(macros);
-
         let mut params = DebuggerParams::default();
         let mut res;
         loop {
@@ -501,28 +605,39 @@ impl GdbServer {
         assert_eq!(res.unwrap(), mem::size_of_val(&params));
 
         let mut args = vec![gdb_binary_file_path.into()];
-        push_default_gdb_options(&mut args);
-        args.push("-x".into());
-        args.push(gdb_command_file);
-        let mut did_set_remote = false;
         let host = OsStr::from_bytes(params.host.split(|&c| c == 0).next().unwrap())
             .to_str()
             .unwrap();
         let exe_image =
             OsStr::from_bytes(params.exe_image.split(|&c| c == 0).next().unwrap()).to_owned();
-        for i in 0..gdb_options.len() {
-            if !did_set_remote
-                && gdb_options[i].as_bytes() == b"-ex"
-                && i + 1 < gdb_options.len()
-                && needs_target(&gdb_options[i + 1])
-            {
+        if is_lldb(gdb_binary_file_path) {
+            // lldb runs `-o` commands in order, so connect before running any
+            // commands the user passed.
+            let lldb_script_file = create_gdb_command_file(lldb_rd_macros());
+            push_default_lldb_options(&mut args);
+            push_lldb_script_cmd(&mut args, &lldb_script_file);
+            push_lldb_remote_cmd(&mut args, host, params.port);
+            args.extend_from_slice(gdb_options);
+        } else {
+            let gdb_command_file = create_gdb_command_file(gdb_rd_macros());
+            push_default_gdb_options(&mut args);
+            args.push("-x".into());
+            args.push(gdb_command_file);
+            let mut did_set_remote = false;
+            for i in 0..gdb_options.len() {
+                if !did_set_remote
+                    && gdb_options[i].as_bytes() == b"-ex"
+                    && i + 1 < gdb_options.len()
+                    && needs_target(&gdb_options[i + 1])
+                {
+                    push_target_remote_cmd(&mut args, host, params.port);
+                    did_set_remote = true;
+                }
+                args.push(gdb_options[i].clone());
+            }
+            if !did_set_remote {
                 push_target_remote_cmd(&mut args, host, params.port);
-                did_set_remote = true;
             }
-            args.push(gdb_options[i].clone());
-        }
-        if !did_set_remote {
-            push_target_remote_cmd(&mut args, host, params.port);
         }
         args.push(exe_image);
 
@@ -537,7 +652,7 @@ impl GdbServer {
             &to_cstring_array(&args),
             &to_cstring_array(&flat_env(&env)),
         )
-        .unwrap_or_else(|_| fatal!("Failed to exec gdb."));
+        .unwrap_or_else(|_| fatal!("Failed to exec {:?}.", gdb_binary_file_path));
     }
 
     /// Start a debugging connection for |t| and return when there are no
@@ -583,9 +698,10 @@ impl GdbServer {
             eprint!("Launch gdb with\n  ");
             write_debugger_launch_command(t, LOCALHOST_ADDR, port, Path::new("gdb"), &mut stderr());
         }
-        let tgid = t.tgid();
-        let arch = t.arch();
-        let dbg = await_connection(tgid, arch, &listen_fd, features);
+        // Go by this machine: `t` may not be replaying, and if it is, the trace
+        // reader may be in use by whatever went wrong.
+        let native_features = cpu_features(t.arch(), xsave_native_layout(), xcr0());
+        let dbg = await_connection(t.tgid(), native_features, &listen_fd, features);
 
         GdbServer::new_from(dbg, t).process_debugger_requests(None);
     }
@@ -610,20 +726,122 @@ impl GdbServer {
         }
     }
 
+    /// Make the process of `t` the one whose stops are reported to the debugger.
+    ///
+    /// DIFF NOTE: rr never changes the debuggee once the debugger has connected.
+    fn switch_debuggee(&mut self, t: &dyn Task) {
+        let tguid = t.thread_group().borrow().tguid();
+        if tguid == self.debuggee_tguid {
+            return;
+        }
+        self.debuggee_tguid = tguid;
+        // libthread_db state is per process.
+        self.thread_db = None;
+        self.dbg_unwrap_mut().set_debuggee_tgid(t.tgid());
+    }
+
+    /// The debugger's next request. Forgets cached tracee memory unless the
+    /// request is one that can't change it.
+    fn get_request(&mut self) -> GdbRequest {
+        let req = self.dbg_unwrap_mut().get_request();
+        if !preserves_memory(req.type_) {
+            self.mem_cache.clear();
+        }
+        req
+    }
+
+    /// Write out the memory writes gdb has made in `session` that haven't been
+    /// written yet. gdb has already been told they succeeded, so a failure can
+    /// only be logged.
+    fn flush_mem_writes(&mut self, session: &dyn Session) {
+        let pending = match self.pending_mem_write.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let t = match session.find_task_from_task_uid(pending.tuid) {
+            Some(t) => t,
+            None => {
+                log!(
+                    LogError,
+                    "Task died before {} bytes could be written to {}",
+                    pending.data.len(),
+                    pending.addr
+                );
+                return;
+            }
+        };
+        let start = Instant::now();
+        let mut ok = true;
+        t.write_bytes_helper(
+            pending.addr,
+            &pending.data,
+            Some(&mut ok),
+            WriteFlags::empty(),
+        );
+        if !ok {
+            log!(
+                LogError,
+                "Failed to write {} bytes to {}",
+                pending.data.len(),
+                pending.addr
+            );
+        }
+        self.mem_cache.clear();
+        self.dbg_unwrap_mut()
+            .stats_mut()
+            .mem_writes
+            .record(start.elapsed());
+    }
+
+    /// A task of the process `pid`, or of the process that was last resumed if
+    /// `pid` isn't a real pid.
+    fn process_task(&self, session: &dyn Session, pid: pid_t) -> Option<TaskSharedPtr> {
+        if pid > 0 {
+            session
+                .find_thread_group_from_pid(pid)
+                .and_then(|tg| tg.borrow().task_set().iter().next())
+        } else {
+            session.find_task_from_task_uid(self.last_continue_tuid)
+        }
+    }
+
+    /// The value of `which` for `t` if it is one of rd's pseudo-registers (see
+    /// gdb_target_desc.rs).
+    fn rd_pseudo_reg(&self, t: &dyn Task, which: GdbRegister) -> Option<GdbRegisterValue> {
+        let value = match which {
+            DREG_RD_EVENT => self.current_event(),
+            DREG_RD_TICKS => t.tick_count(),
+            _ => return None,
+        };
+        Some(GdbRegisterValue {
+            name: which,
+            value: GdbRegisterValueData::Value8(value),
+            defined: true,
+            size: 8,
+        })
+    }
+
     fn dispatch_regs_request(&mut self, regs: &Registers, extra_regs: &ExtraRegisters) {
         // Send values for all the registers we sent XML register descriptions for.
-        // Those descriptions are controlled by GdbConnection::cpu_features().
-        let have_avx = (self.dbg_unwrap().cpu_features() & GdbConnection::CPU_AVX) != 0;
+        // Those descriptions are controlled by GdbConnection::cpu_features(),
+        // which reflects the recording's xsave layout (see get_cpu_features()).
+        let features = self.dbg_unwrap().cpu_features();
+        let have_avx = (features & GdbConnection::CPU_AVX) != 0;
+        let have_avx512 = (features & GdbConnection::CPU_AVX512) != 0;
         let end = match regs.arch() {
             SupportedArch::X86 => {
-                if have_avx {
+                if have_avx512 {
+                    DREG_ZMM7H
+                } else if have_avx {
                     DREG_YMM7H
                 } else {
                     DREG_ORIG_EAX
                 }
             }
             SupportedArch::X64 => {
-                if have_avx {
+                if have_avx512 {
+                    DREG_64_ZMM31H
+                } else if have_avx {
                     DREG_64_YMM15H
                 } else {
                     DREG_ORIG_RAX
@@ -692,6 +910,22 @@ impl GdbServer {
                 self.dbg_unwrap_mut().reply_get_offsets();
                 return;
             }
+            DREQ_ATTACH => {
+                let maybe_tg = session.find_thread_group_from_pid(req.target.pid);
+                let maybe_t = maybe_tg
+                    .as_ref()
+                    .and_then(|tg| tg.borrow().task_set().iter().next());
+                match maybe_t {
+                    Some(t) => {
+                        self.switch_debuggee(&**t);
+                        self.last_continue_tuid = t.tuid();
+                        self.last_query_tuid = t.tuid();
+                        self.dbg_unwrap_mut().reply_attach(Some(get_threadid(&**t)));
+                    }
+                    None => self.dbg_unwrap_mut().reply_attach(None),
+                }
+                return;
+            }
             DREQ_GET_THREAD_LIST => {
                 let mut tids: Vec<GdbThreadId> = Vec::new();
                 if state != ReportState::ReportThreadsDead {
@@ -712,16 +946,12 @@ impl GdbServer {
                 if let Some(t) = maybe_t {
                     debug_assert_eq!(t.thread_group().borrow().tguid(), self.debuggee_tguid);
                     let threadid = get_threadid(&**t);
-                    self.dbg_unwrap_mut()
-                        .notify_stop(threadid, None, RemotePtr::null());
+                    self.dbg_unwrap_mut().notify_stop(threadid, None, None);
                     self.last_query_tuid = t.tuid();
                     self.last_continue_tuid = t.tuid();
                 } else {
-                    self.dbg_unwrap_mut().notify_stop(
-                        GdbThreadId::default(),
-                        None,
-                        RemotePtr::null(),
-                    );
+                    self.dbg_unwrap_mut()
+                        .notify_stop(GdbThreadId::default(), None, None);
                 }
                 self.stop_siginfo = Default::default();
                 return;
@@ -731,25 +961,30 @@ impl GdbServer {
                 // name on gdb's command line, but the user might start gdb manually
                 // and this is easy to support in some other debugger or
                 // configuration needs it.
-                let mut maybe_t = None;
                 // DIFF NOTE: @TODO This is simply req.target.tid in rr
                 // Since -1 will succeed there, a > 0 requirement has been added.
-                if req.target.tid > 0 {
-                    let maybe_tg = session.find_thread_group_from_pid(req.target.tid);
-                    if let Some(tg) = maybe_tg {
-                        maybe_t = Some(tg.borrow().task_set().iter().next().unwrap());
-                    }
-                } else {
-                    maybe_t = session.find_task_from_task_uid(self.last_continue_tuid);
-                }
+                let maybe_t = self.process_task(session, req.target.tid);
                 if let Some(t) = maybe_t {
-                    self.dbg_unwrap_mut()
-                        .reply_get_exec_file(t.vm().exe_image());
+                    let exec_file = exec_file(&**t);
+                    self.dbg_unwrap_mut().reply_get_exec_file(&exec_file);
                 } else {
                     self.dbg_unwrap_mut().reply_get_exec_file(OsStr::new(""));
                 }
                 return;
             }
+            DREQ_GET_PROCESS_INFO => {
+                let maybe_info = self.process_task(session, req.target.pid).map(|t| {
+                    let parent_pid = t.thread_group().borrow().parent().map(|p| p.borrow().tgid);
+                    GdbProcessInfo {
+                        pid: t.tgid(),
+                        parent_pid,
+                        name: exec_file(&**t),
+                    }
+                });
+                self.dbg_unwrap_mut()
+                    .reply_get_process_info(maybe_info.as_ref());
+                return;
+            }
             DREQ_FILE_SETFS => {
                 // Only the filesystem as seen by the remote stub is supported currently
                 self.file_scope_pid = req.file_setfs().pid;
@@ -827,6 +1062,10 @@ impl GdbServer {
                 return;
             }
             DREQ_SET_CONTINUE_THREAD => {
+                if let Some(t) = maybe_target.as_ref() {
+                    // Resuming a thread of another process means debugging it.
+                    self.switch_debuggee(&***t);
+                }
                 self.dbg_unwrap_mut()
                     .reply_select_thread(maybe_target.is_some());
                 return;
@@ -853,14 +1092,17 @@ impl GdbServer {
                 return;
             }
             DREQ_GET_MEM => {
+                let start = Instant::now();
                 let mut mem: Vec<u8> = vec![0u8; req.mem().len];
-                let nread = target.read_bytes_fallible(req.mem().addr, &mut mem);
+                let nread = self.mem_cache.read(&**target, req.mem().addr, &mut mem);
                 mem.resize(max(0, nread.unwrap_or(0)), 0u8);
                 target
                     .vm()
                     .replace_breakpoints_with_original_values(&mut mem, req.mem().addr);
                 Self::maybe_intercept_mem_request(&**target, req, &mut mem);
-                self.dbg_unwrap_mut().reply_get_mem(&mem);
+                let mut dbg = self.dbg_unwrap_mut();
+                dbg.stats_mut().mem_reads.record(start.elapsed());
+                dbg.reply_get_mem(&mem);
                 return;
             }
             DREQ_SET_MEM => {
@@ -889,19 +1131,38 @@ impl GdbServer {
                     req.mem().len,
                     req.mem().addr
                 );
-                // TODO fallible
-                target.write_bytes_helper(
-                    req.mem().addr,
-                    &req.mem().data,
-                    None,
-                    WriteFlags::empty(),
-                );
+                // gdb won't send the next piece until this one is acknowledged,
+                // so acknowledge it now and only write once gdb asks for
+                // something else. This way a large `restore` costs one write
+                // (and, for read-only mappings, one round of remote mprotects)
+                // instead of one per packet.
+                let tuid = target.tuid();
+                match self.pending_mem_write.as_mut() {
+                    Some(pending) if pending.extends_to(tuid, req.mem().addr, req.mem().len) => {
+                        pending.data.extend_from_slice(&req.mem().data);
+                    }
+                    _ => {
+                        self.flush_mem_writes(session);
+                        self.pending_mem_write = Some(PendingMemWrite {
+                            tuid,
+                            addr: req.mem().addr,
+                            data: req.mem().data.clone(),
+                        });
+                    }
+                }
                 self.dbg_unwrap_mut().reply_set_mem(true);
                 return;
             }
             DREQ_SEARCH_MEM => {
                 let range = MemoryRange::new_range(req.mem().addr, req.mem().len);
-                let found_addr = search_memory(&**target, range, &req.mem().data);
+                let mut found_addr = None;
+                if !req.mem().data.is_empty() {
+                    let pattern = SearchPattern::Bytes(req.mem().data.clone());
+                    search_range(&**target, range, &pattern, &mut |addr| {
+                        found_addr = Some(addr);
+                        false
+                    });
+                }
                 self.dbg_unwrap_mut().reply_search_mem(
                     found_addr.is_some(),
                     found_addr.unwrap_or(RemotePtr::null()),
@@ -909,8 +1170,12 @@ impl GdbServer {
                 return;
             }
             DREQ_GET_REG => {
-                let reg =
-                    Self::get_reg(&target.regs_ref(), &target.extra_regs_ref(), req.reg().name);
+                let reg = match self.rd_pseudo_reg(&**target, req.reg().name) {
+                    Some(reg) => reg,
+                    None => {
+                        Self::get_reg(&target.regs_ref(), &target.extra_regs_ref(), req.reg().name)
+                    }
+                };
                 self.dbg_unwrap_mut().reply_get_reg(&reg);
                 return;
             }
@@ -919,6 +1184,11 @@ impl GdbServer {
                 return;
             }
             DREQ_SET_REG => {
+                if self.rd_pseudo_reg(&**target, req.reg().name).is_some() {
+                    log!(LogError, "Attempt to write rd pseudo-register");
+                    self.dbg_unwrap_mut().reply_set_reg(false);
+                    return;
+                }
                 if !session.is_diversion() {
                     // gdb sets orig_eax to -1 during a restart. For a
                     // replay session this is not correct (we might be
@@ -938,13 +1208,38 @@ impl GdbServer {
                     self.dbg_unwrap_mut().reply_set_reg(false);
                     return;
                 }
+                let mut ok = true;
                 if req.reg().defined {
                     let mut regs = target.regs();
-                    regs.write_register(req.reg().value(), req.reg().name);
-                    target.set_regs(&regs);
+                    let mut extra_regs = target.extra_regs_ref().clone();
+                    let mut buf = [0u8; GdbRegisterValue::MAX_SIZE];
+                    let value = req.reg().value();
+                    if let Some(size) = regs.read_register(&mut buf, req.reg().name) {
+                        ok = value.len() <= size;
+                        if ok {
+                            // A short value only replaces the low-order bytes.
+                            buf[0..value.len()].copy_from_slice(value);
+                            regs.write_register(&buf[0..size], req.reg().name);
+                            target.set_regs(&regs);
+                        }
+                    } else if extra_regs.read_register(&mut buf, req.reg().name).is_some() {
+                        ok = extra_regs.write_register(value, req.reg().name);
+                        if ok {
+                            target.set_extra_regs(&extra_regs);
+                        }
+                    } else {
+                        ok = false;
+                    }
+                    if !ok {
+                        log!(
+                            LogWarn,
+                            "Can't write {} bytes to register {}",
+                            req.reg().size,
+                            req.reg().name
+                        );
+                    }
                 }
-                self.dbg_unwrap_mut()
-                    .reply_set_reg(true /*currently infallible*/);
+                self.dbg_unwrap_mut().reply_set_reg(ok);
                 return;
             }
             DREQ_GET_STOP_REASON => {
@@ -971,7 +1266,7 @@ impl GdbServer {
                 let ok = self.timeline_unwrap_mut().add_breakpoint(
                     replay_task.as_replay_task().unwrap(),
                     req.watch().addr.to_code_ptr(),
-                    breakpoint_condition(req),
+                    breakpoint_condition(req, &self.rd_conditions),
                 );
                 if ok
                     && !session
@@ -997,7 +1292,7 @@ impl GdbServer {
                     req.watch().addr,
                     req.watch().kind,
                     watchpoint_type(req.type_),
-                    breakpoint_condition(req),
+                    breakpoint_condition(req, &self.rd_conditions),
                 );
                 if ok
                     && !session
@@ -1105,19 +1400,19 @@ impl GdbServer {
                 // copy.  When gdb sends a plain "qSymbol::" packet, because gdb
                 // has detected some change in the inferior state that might
                 // enable more symbol lookups, we restart the iterator.
-                if self.thread_db.is_none() {
-                    self.thread_db = Some(ThreadDb::new(self.debuggee_tguid.tid()));
-                }
-
                 let name = OsStr::from_bytes(req.sym().name.as_bytes()).to_owned();
                 if req.sym().has_address {
                     // Got a response holding a previously-requested symbol's name
                     // and address.
-                    self.thread_db_mut_unwrap()
-                        .register_symbol(name, req.sym().address);
+                    if let Some(thread_db) = self.thread_db_mut() {
+                        thread_db.register_symbol(name, req.sym().address);
+                    }
                 } else if name.as_bytes().is_empty() {
                     // Plain "qSymbol::" request.
-                    self.symbols = self.thread_db_mut_unwrap().get_symbols_and_clear_map();
+                    self.symbols = match self.thread_db_mut() {
+                        Some(thread_db) => thread_db.get_symbols_and_clear_map(),
+                        None => Vec::new(),
+                    };
                     self.symbols_loc = Some(0);
                 }
 
@@ -1132,17 +1427,16 @@ impl GdbServer {
                 return;
             }
             DREQ_TLS => {
-                if self.thread_db.is_none() {
-                    self.thread_db = Some(ThreadDb::new(self.debuggee_tguid.tid()));
-                }
                 let tg_shr = target.thread_group();
                 let mut tg = tg_shr.borrow_mut();
-                let maybe_addr = self.thread_db.as_mut().unwrap().get_tls_address(
-                    &mut tg,
-                    target.rec_tid.get(),
-                    req.tls().offset,
-                    req.tls().load_module,
-                );
+                let maybe_addr = self.thread_db_mut().and_then(|thread_db| {
+                    thread_db.get_tls_address(
+                        &mut tg,
+                        target.rec_tid.get(),
+                        req.tls().offset,
+                        req.tls().load_module,
+                    )
+                });
                 self.dbg_unwrap_mut().reply_tls_addr(maybe_addr);
                 return;
             }
@@ -1274,7 +1568,7 @@ impl GdbServer {
 
         let mut maybe_checkpoint_to_restore = None;
         if req.restart().type_ == GdbRestartType::RestartFromCheckpoint {
-            let maybe_it = self.checkpoints.get(&req.restart().param).cloned();
+            let maybe_it = self.get_checkpoint(req.restart().param).cloned();
             match maybe_it {
                 None => {
                     println!("Checkpoint {} not found.", req.restart().param_str);
@@ -1358,7 +1652,7 @@ impl GdbServer {
     fn process_debugger_requests(&mut self, maybe_state: Option<ReportState>) -> GdbRequest {
         loop {
             let state = maybe_state.unwrap_or(ReportState::ReportNormal);
-            let mut req = self.dbg_unwrap_mut().get_request();
+            let mut req = self.get_request();
             req.suppress_debugger_stop = false;
             self.try_lazy_reverse_singlesteps(&mut req);
 
@@ -1513,13 +1807,24 @@ impl GdbServer {
                 } else {
                     None
                 };
-                self.dbg_unwrap_mut()
-                    .notify_stop(threadid, maybe_sig, RemotePtr::null());
+                self.dbg_unwrap_mut().notify_stop(threadid, maybe_sig, None);
                 self.stop_siginfo = Default::default();
                 return ContinueOrStop::ContinueDebugging;
             }
         }
 
+        let before_event = self.current_event();
+        let sdt_probe_to_catch = if req.cont().run_direction == RunDirection::RunForward {
+            self.sdt_probe_to_catch()
+        } else {
+            None
+        };
+        let children_before = if self.follow_fork && !req.suppress_debugger_stop {
+            self.debuggee_children()
+        } else {
+            Vec::new()
+        };
+        let step_start = Instant::now();
         if req.cont().run_direction == RunDirection::RunForward {
             if is_in_exec(&self.timeline_unwrap()).is_some()
                 && self
@@ -1532,9 +1837,20 @@ impl GdbServer {
                     .tguid()
                     == self.debuggee_tguid
             {
-                // Don't go any further forward. maybe_notify_stop will generate a
-                // stop.
-                result = ReplayResult::default();
+                if self.follow_exec {
+                    // Finish the exec and report it, so gdb loads the new image.
+                    result = self
+                        .timeline_unwrap_mut()
+                        .replay_step_forward(RunCommand::RunContinue, self.target.event);
+                    if result.status != ReplayStatus::ReplayExited {
+                        self.notify_exec();
+                        return ContinueOrStop::ContinueDebugging;
+                    }
+                } else {
+                    // Don't go any further forward. maybe_notify_stop will generate a
+                    // stop.
+                    result = ReplayResult::default();
+                }
             } else {
                 let mut signal_to_deliver: Option<Sig> = None;
                 let task = self
@@ -1544,10 +1860,14 @@ impl GdbServer {
                     .unwrap();
                 let command: RunCommand =
                     compute_run_command_from_actions(&**task, &req, &mut signal_to_deliver);
+                let stop_at_time = match self.break_at_event {
+                    Some(event) if event > before_event => event,
+                    _ => self.target.event,
+                };
                 // Ignore gdb's |signal_to_deliver|; we just have to follow the replay.
                 result = self
                     .timeline_unwrap_mut()
-                    .replay_step_forward(command, self.target.event);
+                    .replay_step_forward(command, stop_at_time);
             }
         } else {
             let mut allowed_tasks: Vec<AllowedTasks> = Vec::new();
@@ -1599,13 +1919,36 @@ impl GdbServer {
                 _ => debug_assert!(false),
             }
         }
+        self.dbg_unwrap_mut()
+            .stats_mut()
+            .replay_steps
+            .record(step_start.elapsed());
 
         if result.status == ReplayStatus::ReplayExited {
             return self.handle_exited_state(last_resume_request);
         }
 
+        if self.follow_fork
+            && !req.suppress_debugger_stop
+            && req.cont().run_direction == RunDirection::RunForward
+        {
+            let debuggee_children = self.debuggee_children();
+            let maybe_forked = debuggee_children
+                .into_iter()
+                .find(|tguid| !children_before.contains(tguid));
+            if let Some(child_tguid) = maybe_forked {
+                if self.notify_fork(child_tguid) {
+                    return ContinueOrStop::ContinueDebugging;
+                }
+            }
+        }
+
         if !req.suppress_debugger_stop {
-            self.maybe_notify_stop(&req, &result.break_status);
+            if !self.maybe_notify_event_break(before_event)
+                && !self.maybe_notify_sdt_catch(sdt_probe_to_catch)
+            {
+                self.maybe_notify_stop(&req, &result.break_status);
+            }
         }
         if req.cont().run_direction == RunDirection::RunForward
             && is_last_thread_exit(&result.break_status)
@@ -1673,16 +2016,33 @@ impl GdbServer {
             self.maybe_notify_stop(req, &break_status);
 
             loop {
-                *req = self.dbg_unwrap_mut().get_request();
+                *req = self.get_request();
                 req.suppress_debugger_stop = false;
-                if req.type_ != DREQ_GET_REGS {
+                // Register queries for some other thread can't be answered from
+                // the mark. Let them be handled normally after we've seeked.
+                if !matches_threadid(&***t, req.target) {
                     break;
                 }
-                log!(LogDebug, "  using lazy reverse-singlestep registers");
-                self.dispatch_regs_request(
-                    &now.as_ref().unwrap().regs(),
-                    &now.as_ref().unwrap().extra_regs(),
-                );
+                match req.type_ {
+                    DREQ_GET_REGS => {
+                        log!(LogDebug, "  using lazy reverse-singlestep registers");
+                        self.dispatch_regs_request(
+                            &now.as_ref().unwrap().regs(),
+                            &now.as_ref().unwrap().extra_regs(),
+                        );
+                    }
+                    // rd's pseudo-registers are answered once we've seeked.
+                    DREQ_GET_REG if self.rd_pseudo_reg(&***t, req.reg().name).is_none() => {
+                        log!(LogDebug, "  using lazy reverse-singlestep register");
+                        let reg = Self::get_reg(
+                            &now.as_ref().unwrap().regs(),
+                            &now.as_ref().unwrap().extra_regs(),
+                            req.reg().name,
+                        );
+                        self.dbg_unwrap_mut().reply_get_reg(&reg);
+                    }
+                    _ => break,
+                }
             }
         }
 
@@ -1703,7 +2063,10 @@ impl GdbServer {
         req: &mut GdbRequest,
     ) -> bool {
         loop {
-            *req = self.dbg_unwrap_mut().get_request();
+            *req = self.get_request();
+            if req.type_ != DREQ_SET_MEM {
+                self.flush_mem_writes(diversion_session);
+            }
 
             if req.is_resume_request() {
                 return *diversion_refcount > 0;
@@ -1793,6 +2156,15 @@ impl GdbServer {
                 .apply_breakpoints_and_watchpoints();
         }
         let diversion_session = replay.clone_diversion();
+        // Functions called from the debugger that read the clock see the
+        // recorded time, and see the same times every time they're called here.
+        diversion_session
+            .as_diversion()
+            .unwrap()
+            .set_clock(Some(Box::new(SteppingClock::at_frame(
+                &replay.current_trace_frame(),
+                DIVERSION_CLOCK_STEP_NS,
+            ))));
         let mut diversion_refcount: usize = 1;
         let saved_query_tuid = self.last_query_tuid;
 
@@ -1808,8 +2180,7 @@ impl GdbServer {
                 // an immediate stop.
                 let thread_id =
                     get_threadid_from_tuid(&**diversion_session, self.last_continue_tuid);
-                self.dbg_unwrap_mut()
-                    .notify_stop(thread_id, None, RemotePtr::null());
+                self.dbg_unwrap_mut().notify_stop(thread_id, None, None);
                 self.stop_siginfo = Default::default();
                 self.last_query_tuid = self.last_continue_tuid;
                 continue;
@@ -1855,13 +2226,28 @@ impl GdbServer {
     /// report it. `req` is the resume request that generated the stop.
     fn maybe_notify_stop(&mut self, req: &GdbRequest, break_status: &BreakStatus) {
         let mut do_stop = false;
-        let mut watch_addr: RemotePtr<Void> = Default::default();
+        let mut maybe_watch: Option<WatchConfig> = None;
         if !break_status.watchpoints_hit.is_empty() {
             do_stop = true;
             self.stop_siginfo = Default::default();
             self.stop_siginfo.si_signo = SIGTRAP;
-            watch_addr = break_status.watchpoints_hit[0].addr;
-            log!(LogDebug, "Stopping for watchpoint at {}", watch_addr);
+            let watch = reported_watchpoint(break_status);
+            log!(
+                LogDebug,
+                "Stopping for {:?} watchpoint at {}",
+                watch.type_,
+                watch.addr
+            );
+            for v in &break_status.watchpoint_values {
+                log!(
+                    LogDebug,
+                    "  watchpoint at {} changed from {:02x?} to {:02x?}",
+                    v.addr,
+                    v.old_value,
+                    v.new_value
+                );
+            }
+            maybe_watch = Some(watch);
         }
         if break_status.breakpoint_hit || break_status.singlestep_complete {
             do_stop = true;
@@ -1873,10 +2259,16 @@ impl GdbServer {
                 log!(LogDebug, "Stopping for singlestep");
             }
         }
-        if break_status.signal.is_some() {
-            do_stop = true;
-            self.stop_siginfo = **break_status.signal.as_ref().unwrap();
-            log!(LogDebug, "Stopping for signal {}", self.stop_siginfo);
+        if let Some(siginfo) = break_status.signal.as_ref() {
+            if !self.stop_on_go_preemption
+                && is_go_preemption_signal(siginfo, self.debuggee_tguid.tid())
+            {
+                log!(LogDebug, "Not stopping for Go preemption signal");
+            } else {
+                do_stop = true;
+                self.stop_siginfo = **siginfo;
+                log!(LogDebug, "Stopping for signal {}", self.stop_siginfo);
+            }
         }
         if is_last_thread_exit(break_status) && self.dbg_unwrap().features().reverse_execution {
             do_stop = true;
@@ -1897,13 +2289,28 @@ impl GdbServer {
             }
         }
         let mut maybe_t = break_status.task.upgrade();
-        let maybe_in_exec_task = is_in_exec(&self.timeline_unwrap());
+        let maybe_in_exec_task = is_in_exec(&self.timeline_unwrap()).filter(|t| {
+            // debug_one_step will report the exec once it's done.
+            !self.follow_exec || t.thread_group().borrow().tguid() != self.debuggee_tguid
+        });
         if let Some(in_exec_task) = maybe_in_exec_task {
             do_stop = true;
             self.stop_siginfo = Default::default();
             maybe_t = Some(in_exec_task);
             log!(LogDebug, "Stopping at exec");
         }
+        let mut maybe_pre_exec_image: Option<OsString> = None;
+        if break_status.reverse_exec_boundary {
+            do_stop = true;
+            self.stop_siginfo = Default::default();
+            self.stop_siginfo.si_signo = SIGTRAP;
+            let t = break_status.task_unwrap();
+            // The debugger still has the post-exec image loaded. Report an exec
+            // of the image we're back in so it switches to that one.
+            maybe_pre_exec_image = Some(exec_file(&**t));
+            maybe_t = Some(t);
+            log!(LogDebug, "Stopping before exec while running backwards");
+        }
         if do_stop {
             let t = maybe_t.unwrap();
             if t.thread_group().borrow().tguid() == self.debuggee_tguid {
@@ -1911,25 +2318,238 @@ impl GdbServer {
                 // that might have triggered before resuming.
                 let signo = self.stop_siginfo.si_signo;
                 let threadid = get_threadid(&**t);
-                self.dbg_unwrap_mut()
-                    .notify_stop(threadid, Sig::try_from(signo).ok(), watch_addr);
+                match maybe_pre_exec_image {
+                    Some(exe_image) => {
+                        // libthread_db state belongs to the post-exec image.
+                        self.thread_db = None;
+                        self.dbg_unwrap_mut().notify_exec(threadid, &exe_image);
+                    }
+                    None => self.dbg_unwrap_mut().notify_stop(
+                        threadid,
+                        Sig::try_from(signo).ok(),
+                        maybe_watch,
+                    ),
+                }
                 self.last_continue_tuid = t.tuid();
                 self.last_query_tuid = t.tuid();
             }
         }
     }
 
-    /// Return the checkpoint stored as |checkpoint_id| or nullptr if there
+    /// The processes the debuggee has forked that are still around.
+    fn debuggee_children(&self) -> Vec<ThreadGroupUid> {
+        let maybe_tg = self
+            .timeline_unwrap()
+            .current_session()
+            .find_thread_group_from_tguid(self.debuggee_tguid);
+        let mut children = Vec::new();
+        if let Some(tg) = maybe_tg {
+            let tg = tg.borrow();
+            for child in tg.children() {
+                children.push(child.borrow().tguid());
+            }
+        }
+        children
+    }
+
+    /// The debuggee has just finished an exec: report it to gdb as a stop.
+    fn notify_exec(&mut self) {
+        let t = self
+            .timeline_unwrap()
+            .current_session()
+            .current_task()
+            .unwrap();
+        let exe_image = t.vm().exe_image().to_owned();
+        log!(LogDebug, "Following exec of {:?}", exe_image);
+        // libthread_db state belongs to the old image.
+        self.thread_db = None;
+        self.stop_siginfo = Default::default();
+        self.stop_siginfo.si_signo = SIGTRAP;
+        self.last_continue_tuid = t.tuid();
+        self.last_query_tuid = t.tuid();
+        // Running backwards from here has to switch the debugger back to the
+        // old image.
+        let exec_event = t.vm().first_run_event();
+        self.timeline_unwrap_mut()
+            .add_reverse_exec_boundary_event(exec_event);
+        self.add_transition_checkpoint(format!("exec of {:?}", exe_image));
+        self.dbg_unwrap_mut()
+            .notify_exec(get_threadid(&**t), &exec_file(&**t));
+    }
+
+    /// The debuggee has just forked the process `child_tguid`: make the child
+    /// the debuggee and report a stop in it. Returns false if the child has no
+    /// task to stop in.
+    fn notify_fork(&mut self, child_tguid: ThreadGroupUid) -> bool {
+        let maybe_tg = self
+            .timeline_unwrap()
+            .current_session()
+            .find_thread_group_from_tguid(child_tguid);
+        let mut maybe_child = None;
+        if let Some(tg) = maybe_tg {
+            maybe_child = tg.borrow().task_set().iter().next();
+        }
+        let child = match maybe_child {
+            Some(child) => child,
+            None => return false,
+        };
+        let parent_pid = self.debuggee_tguid.tid();
+        log!(
+            LogDebug,
+            "Following fork of {} into {}",
+            parent_pid,
+            child.tgid()
+        );
+        self.switch_debuggee(&**child);
+        self.stop_siginfo = Default::default();
+        self.stop_siginfo.si_signo = SIGTRAP;
+        self.last_continue_tuid = child.tuid();
+        self.last_query_tuid = child.tuid();
+        self.add_transition_checkpoint(format!("fork of {} into {}", parent_pid, child.tgid()));
+        self.dbg_unwrap_mut()
+            .notify_stop(get_threadid(&**child), Some(sig::SIGTRAP), None);
+        true
+    }
+
+    /// Add a checkpoint where the debuggee changed, so that it's easy to get
+    /// back there.
+    fn add_transition_checkpoint(&mut self, where_: String) {
+        let e = if self.timeline_unwrap().can_add_checkpoint() {
+            ExplicitCheckpoint::Explicit
+        } else {
+            ExplicitCheckpoint::NotExplicit
+        };
+        let checkpoint = Checkpoint::new(
+            &mut self.timeline_unwrap_mut(),
+            self.last_continue_tuid,
+            e,
+            OsStr::new(&where_),
+        );
+        let checkpoint_id = NEXT_CHECKPOINT_ID.fetch_add(1, Ordering::SeqCst);
+        log!(LogInfo, "Checkpoint {} at {}", checkpoint_id, where_);
+        self.checkpoints.insert(checkpoint_id, checkpoint);
+    }
+
+    fn current_event(&self) -> FrameTime {
+        self.timeline_unwrap()
+            .current_session()
+            .current_trace_frame()
+            .time()
+    }
+
+    /// If the last step (forward or reverse, starting at `before_event`) went
+    /// past the `rd-break-event` event, go back to the start of that event and
+    /// report a stop there. Returns true if we stopped.
+    fn maybe_notify_event_break(&mut self, before_event: FrameTime) -> bool {
+        let event = match self.break_at_event {
+            Some(event) => event,
+            None => return false,
+        };
+        let after_event = self.current_event();
+        let reached_forward = before_event < event && event <= after_event;
+        let reached_backward = after_event < event && event < before_event;
+        if !reached_forward && !reached_backward {
+            return false;
+        }
+        if after_event != event {
+            self.timeline_unwrap_mut().seek_to_before_event(event);
+        }
+
+        let maybe_t = self
+            .timeline_unwrap()
+            .current_session()
+            .find_task_from_task_uid(self.last_continue_tuid);
+        let t = match maybe_t {
+            Some(t) => t,
+            None => return false,
+        };
+        eprint!(
+            "\n\
+         --------------------------------------------------\n\
+         ---> Reached event {} (rd-break-event).\n\
+         --------------------------------------------------\n",
+            event
+        );
+        self.stop_siginfo = Default::default();
+        self.stop_siginfo.si_signo = SIGTRAP;
+        let threadid = get_threadid(&**t);
+        self.dbg_unwrap_mut()
+            .notify_stop(threadid, Some(sig::SIGTRAP), None);
+        true
+    }
+
+    /// If the frame replay is about to complete is a hit of an SDT probe
+    /// `rd-catch-sdt` asked for, its time, tid and event.
+    fn sdt_probe_to_catch(&self) -> Option<(FrameTime, pid_t, Event)> {
+        let wanted = self.catch_sdt.as_ref()?;
+        let timeline = self.timeline_unwrap();
+        let frame = timeline.current_session().current_trace_frame();
+        if frame.event().event_type() != EventType::EvSdtProbe {
+            return None;
+        }
+        let probe = frame.event().sdt_probe_event();
+        if *wanted != probe.provider && *wanted != format!("{}:{}", probe.provider, probe.name) {
+            return None;
+        }
+        Some((frame.time(), frame.tid(), frame.event().clone()))
+    }
+
+    /// If the last step completed the probe hit `sdt_probe_to_catch()`
+    /// returned before it, report a stop in the task that hit it, which is
+    /// right after the probe. Returns true if we stopped.
+    fn maybe_notify_sdt_catch(&mut self, probe_hit: Option<(FrameTime, pid_t, Event)>) -> bool {
+        let (time, tid, ev) = match probe_hit {
+            Some(probe_hit) => probe_hit,
+            None => return false,
+        };
+        if self.current_event() <= time {
+            return false;
+        }
+        let maybe_t = self
+            .timeline_unwrap()
+            .current_session()
+            .find_task_from_rec_tid(tid);
+        let t = match maybe_t {
+            Some(t) if t.thread_group().borrow().tguid() == self.debuggee_tguid => t,
+            _ => return false,
+        };
+        eprint!(
+            "\n\
+         --------------------------------------------------\n\
+         ---> Hit {} at event {} (rd-catch-sdt).\n\
+         --------------------------------------------------\n",
+            ev, time
+        );
+        self.stop_siginfo = Default::default();
+        self.stop_siginfo.si_signo = SIGTRAP;
+        let threadid = get_threadid(&**t);
+        self.dbg_unwrap_mut()
+            .notify_stop(threadid, Some(sig::SIGTRAP), None);
+        true
+    }
+
+    /// Return the checkpoint stored as |checkpoint_id| or None if there
     /// isn't one.
-    /// @TODO Where is the implementation?
-    fn get_checkpoint(_checkpoint_id: u32) -> SessionSharedPtr {
-        unimplemented!()
+    /// DIFF NOTE: rr declares this but never implements it. We return the
+    /// gdb `Checkpoint` rather than a session.
+    pub(super) fn get_checkpoint(&self, checkpoint_id: u64) -> Option<&Checkpoint> {
+        self.checkpoints.get(&checkpoint_id)
     }
 
     /// Delete the checkpoint stored as |checkpoint_id| if it exists, or do
     /// nothing if it doesn't exist.
-    fn delete_checkpoint(_checkpoint_id: u32) {
-        unimplemented!()
+    /// DIFF NOTE: Returns whether a checkpoint was actually deleted.
+    pub(super) fn delete_checkpoint(&mut self, checkpoint_id: u64) -> bool {
+        match self.checkpoints.remove(&checkpoint_id) {
+            Some(checkpoint) => {
+                if checkpoint.is_explicit == ExplicitCheckpoint::Explicit {
+                    self.timeline_unwrap_mut()
+                        .remove_explicit_checkpoint(&checkpoint.mark);
+                }
+                true
+            }
+            None => false,
+        }
     }
 
     /// Handle GDB file open requests. If we can serve this read request, add
@@ -2006,6 +2626,11 @@ impl GdbServer {
                 }
                 _ => return -1,
             }
+        } else {
+            content = open_saved_file(session, pathname.as_os_str());
+            if !content.is_open() {
+                return -1;
+            }
         }
         let mut ret_fd: i32 = 0;
         while self.files.get(&ret_fd).is_some() {
@@ -2016,6 +2641,33 @@ impl GdbServer {
     }
 }
 
+/// Files the trace has its own copy of (mapped files that were cloned, copied
+/// or hardlinked into the trace directory, and locale files the tracees
+/// opened) are read from that copy, so gdb sees what the tracees saw even if
+/// the file has changed since or isn't installed on this machine.
+fn open_saved_file(session: &dyn Session, pathname: &OsStr) -> ScopedFd {
+    for vm in session.vms() {
+        for (_, m) in &vm.maps() {
+            if m.recorded_map.fsname() == pathname && m.map.fsname() != pathname {
+                let fd = ScopedFd::open_path(m.map.fsname(), OFlag::O_RDONLY);
+                if fd.is_open() {
+                    return fd;
+                }
+            }
+        }
+    }
+    for t in session.tasks().values() {
+        for monitor in t.fd_table().monitors() {
+            if let Some(m) = monitor.borrow().as_locale_file_monitor() {
+                if m.path() == pathname {
+                    return ScopedFd::open_path(m.backing_file_name(), OFlag::O_RDONLY);
+                }
+            }
+        }
+    }
+    ScopedFd::new()
+}
+
 fn generate_fake_proc_maps(t: &dyn Task) -> ScopedFd {
     let file = create_temporary_file(b"rd-fake-proc-maps-XXXXXX");
     unlink(file.name.as_os_str()).unwrap();
@@ -2197,19 +2849,41 @@ fn write_debugger_launch_command(
     out: &mut dyn Write,
 ) {
     let mut options: Vec<OsString> = Vec::new();
-    push_default_gdb_options(&mut options);
-    push_target_remote_cmd(&mut options, dbg_host, port);
+    if is_lldb(debugger_name) {
+        // The script is only readable for as long as this process is alive,
+        // which is exactly as long as it's any use.
+        let lldb_script_file = create_gdb_command_file(lldb_rd_macros());
+        push_default_lldb_options(&mut options);
+        push_lldb_script_cmd(&mut options, &lldb_script_file);
+        push_lldb_remote_cmd(&mut options, dbg_host, port);
+    } else {
+        push_default_gdb_options(&mut options);
+        push_target_remote_cmd(&mut options, dbg_host, port);
+    }
     out.write_all(debugger_name.as_os_str().as_bytes()).unwrap();
     for opt in &options {
-        out.write_all(b" '").unwrap();
-        out.write_all(opt.as_bytes()).unwrap();
-        out.write_all(b"'").unwrap();
+        out.write_all(b" ").unwrap();
+        write_shell_quoted(opt, out);
     }
     out.write_all(b" ").unwrap();
-    out.write_all(t.vm().exe_image().as_bytes()).unwrap();
+    write_shell_quoted(t.vm().exe_image(), out);
     out.write_all(b"\n").unwrap();
 }
 
+/// Write `arg` single-quoted so that the printed command can be pasted into a
+/// shell as-is, even if `arg` itself contains spaces or single quotes.
+fn write_shell_quoted(arg: &OsStr, out: &mut dyn Write) {
+    out.write_all(b"'").unwrap();
+    for &c in arg.as_bytes() {
+        if c == b'\'' {
+            out.write_all(b"'\\''").unwrap();
+        } else {
+            out.write_all(&[c]).unwrap();
+        }
+    }
+    out.write_all(b"'").unwrap();
+}
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 enum ReportState {
     ReportNormal,
@@ -2231,12 +2905,19 @@ impl Default for ContinueOrStop {
 
 lazy_static! {
     static ref GDB_RD_MACROS: String = gdb_rd_macros_init();
+    static ref LLDB_RD_MACROS: String = GdbCommandHandler::lldb_macros();
 }
 
 fn gdb_rd_macros() -> &'static str {
     &*GDB_RD_MACROS
 }
 
+/// Python script that gives lldb the rd commands that gdb_rd_macros() gives
+/// gdb.
+fn lldb_rd_macros() -> &'static str {
+    &*LLDB_RD_MACROS
+}
+
 /// Special-sauce macros defined by rd when launching the gdb client,
 /// which implement functionality outside of the gdb remote protocol.
 /// (Don't stare at them too long or you'll go blind ;).)
@@ -2255,6 +2936,11 @@ fn gdb_rd_macros_init() -> String {
     // issue the "stepi" command, then gdb refuses to restart
     // execution.
     //
+    // The target-async workaround has to be applied before gdb connects:
+    // gdb refuses to change that setting while the inferior is live. So it
+    // is chosen from the version of the gdb running this script rather than
+    // from the features it reports to rd in qSupported.
+    //
     // Try both "set target-async" and "maint set target-async" since
     // that changed recently.
     let s: &'static str = r##"
@@ -2376,32 +3062,35 @@ fn is_in_patch_stubs(t: &dyn Task, ip: RemoteCodePtr) -> bool {
 /// DIFF NOTE: Just takes the task params it needs
 fn await_connection(
     tgid: pid_t,
-    arch: SupportedArch,
+    cpu_features: u32,
     listen_fd: &ScopedFd,
     features: GdbConnectionFeatures,
 ) -> GdbConnection {
     let mut dbg = GdbConnection::new(tgid, features);
-    dbg.set_cpu_features(get_cpu_features(arch));
+    dbg.set_cpu_features(cpu_features);
     dbg.await_debugger(listen_fd);
     dbg
 }
 
-fn get_cpu_features(arch: SupportedArch) -> u32 {
-    let mut cpu_features = match arch {
-        SupportedArch::X86 => 0,
-        SupportedArch::X64 => GdbConnection::CPU_64BIT,
-    };
-
-    let avx_cpuid_flags = AVX_FEATURE_FLAG | OSXSAVE_FEATURE_FLAG;
-    let cpuid_data = cpuid(CPUID_GETFEATURES, 0);
-    // We're assuming here that AVX support on the system making the recording
-    // is the same as the AVX support during replay. But if that's not true,
-    // rd is totally broken anyway.
-    if (cpuid_data.ecx & avx_cpuid_flags) == avx_cpuid_flags {
-        cpu_features |= GdbConnection::CPU_AVX;
-    }
+/// The cpu features to describe to gdb when debugging a task of `replay`.
+/// They come from the recording machine's xsave layout rather than this
+/// machine's, so gdb is offered the registers the recorded program could
+/// actually use.
+fn get_cpu_features(replay: &ReplaySession, arch: SupportedArch) -> u32 {
+    let trace = replay.trace_reader();
+    cpu_features(
+        arch,
+        &xsave_layout_from_trace(trace.cpuid_records()),
+        trace.xcr0(),
+    )
+}
 
-    cpu_features
+/// The Go runtime preempts goroutines by sending their thread SIGURG with
+/// tgkill. Other programs hardly ever send themselves SIGURG.
+fn is_go_preemption_signal(siginfo: &siginfo_t, tgid: pid_t) -> bool {
+    siginfo.si_signo == libc::SIGURG
+        && siginfo.si_code == SI_TKILL
+        && unsafe { siginfo._sifields._kill.si_pid } == tgid
 }
 
 fn is_in_exec(timeline: &ReplayTimeline) -> Option<TaskSharedPtr> {
@@ -2417,6 +3106,52 @@ fn is_in_exec(timeline: &ReplayTimeline) -> Option<TaskSharedPtr> {
     }
 }
 
+/// Requests that can't change tracee memory (or which process the debugger
+/// sees), so memory cached by earlier requests stays valid.
+fn preserves_memory(type_: GdbRequestType) -> bool {
+    matches!(
+        type_,
+        DREQ_GET_MEM
+            | DREQ_SEARCH_MEM
+            | DREQ_GET_REG
+            | DREQ_GET_REGS
+            | DREQ_GET_AUXV
+            | DREQ_GET_EXEC_FILE
+            | DREQ_GET_PROCESS_INFO
+            | DREQ_GET_CURRENT_THREAD
+            | DREQ_GET_IS_THREAD_ALIVE
+            | DREQ_GET_THREAD_EXTRA_INFO
+            | DREQ_GET_THREAD_LIST
+            | DREQ_GET_STOP_REASON
+            | DREQ_GET_OFFSETS
+            | DREQ_SET_QUERY_THREAD
+            | DREQ_TLS
+    )
+}
+
+/// The file the debugger should load `t`'s executable from. That is the
+/// recorded exe image if it still exists, otherwise the copy of it saved in
+/// the trace (if any), so a debugger on another host still finds the binary.
+/// The file to give the debugger for `t`'s executable.
+///
+/// DIFF NOTE: rr only falls back to the copy of the executable in the trace
+/// when the original can't be read. rd prefers the copy, which is what was
+/// actually run: the original may have changed since, e.g. been modified or
+/// quarantined by an antivirus, which is what `--isolate-file-access` guards
+/// against.
+fn exec_file(t: &dyn Task) -> OsString {
+    let exe_image = t.vm().exe_image().to_owned();
+    for (_, m) in &t.vm().maps() {
+        if m.recorded_map.fsname() == exe_image
+            && m.map.fsname() != exe_image
+            && access(m.map.fsname(), AccessFlags::R_OK).is_ok()
+        {
+            return m.map.fsname().to_owned();
+        }
+    }
+    exe_image
+}
+
 fn get_threadid(t: &dyn Task) -> GdbThreadId {
     GdbThreadId::new(t.tgid(), t.rec_tid())
 }
@@ -2434,79 +3169,76 @@ fn is_last_thread_exit(break_status: &BreakStatus) -> bool {
             == 1
 }
 
+/// The watchpoint to tell gdb about when `break_status` stopped for several.
+/// gdb can only be told about one, so prefer a data watchpoint whose value
+/// changed, since that is the one gdb will print old/new values for.
+fn reported_watchpoint(break_status: &BreakStatus) -> WatchConfig {
+    let hit = &break_status.watchpoints_hit;
+    let is_data = |w: &&WatchConfig| w.type_ != WatchType::WatchExec;
+    *hit.iter()
+        .filter(is_data)
+        .find(|w| {
+            break_status
+                .watchpoint_values
+                .iter()
+                .any(|v| v.addr == w.addr)
+        })
+        .or_else(|| hit.iter().find(is_data))
+        .unwrap_or(&hit[0])
+}
+
 struct GdbBreakpointCondition {
     expressions: Vec<GdbExpression>,
+    /// Set with `rd-condition`. It must hold as well as the gdb conditions.
+    rd_condition: Option<ConditionExpression>,
 }
 
 impl GdbBreakpointCondition {
-    pub fn new(bytecodes: &[Vec<u8>]) -> GdbBreakpointCondition {
+    pub fn new(
+        bytecodes: &[Vec<u8>],
+        rd_condition: Option<ConditionExpression>,
+    ) -> GdbBreakpointCondition {
         let mut expressions = Vec::new();
         for b in bytecodes {
             expressions.push(GdbExpression::new(b));
         }
-        Self { expressions }
+        Self {
+            expressions,
+            rd_condition,
+        }
     }
 }
 
 impl BreakpointCondition for GdbBreakpointCondition {
     fn evaluate(&self, t: &dyn Task) -> bool {
-        for e in &self.expressions {
-            let mut v: GdbExpressionValue = Default::default();
-            // Break if evaluation fails or the result is nonzero
-            if !e.evaluate(t, &mut v) || v.i != 0 {
-                return true;
-            }
-        }
-        false
+        // Break if evaluation fails or the result is nonzero
+        let gdb_condition_holds = self.expressions.is_empty()
+            || self.expressions.iter().any(|e| {
+                let mut v: GdbExpressionValue = Default::default();
+                !e.evaluate(t, &mut v) || v.i != 0
+            });
+        gdb_condition_holds
+            && self
+                .rd_condition
+                .as_ref()
+                .map_or(true, |c| BreakpointCondition::evaluate(c, t))
     }
 }
 
-fn breakpoint_condition(request: &GdbRequest) -> Option<Box<dyn BreakpointCondition>> {
-    if request.watch().conditions.is_empty() {
+fn breakpoint_condition(
+    request: &GdbRequest,
+    rd_conditions: &HashMap<RemotePtr<Void>, ConditionExpression>,
+) -> Option<Box<dyn BreakpointCondition>> {
+    let rd_condition = rd_conditions.get(&request.watch().addr).cloned();
+    if request.watch().conditions.is_empty() && rd_condition.is_none() {
         return None;
     }
     Some(Box::new(GdbBreakpointCondition::new(
         &request.watch().conditions,
+        rd_condition,
     )))
 }
 
-fn search_memory(t: &dyn Task, where_: MemoryRange, find_s: &[u8]) -> Option<RemotePtr<Void>> {
-    // DIFF NOTE: This assert is not present in rd
-    assert_ne!(find_s.len(), 0);
-    let mut buf = Vec::<u8>::new();
-    buf.resize(page_size() + find_s.len() - 1, 0);
-    for (_, m) in &t.vm().maps() {
-        let mut r = MemoryRange::from_range(m.map.start(), m.map.end() + (find_s.len() - 1))
-            .intersect(where_);
-        // We basically read page by page here, but we read past the end of the
-        // page to handle the case where a found string crosses page boundaries.
-        // This approach isn't great for handling long search strings but gdb's find
-        // command isn't really suited to that.
-        // Reading page by page lets us avoid problems where some pages in a
-        // mapping aren't readable (e.g. reading beyond end of file).
-        while r.size() >= find_s.len() {
-            let l = min(buf.len(), r.size());
-            let res = t.read_bytes_fallible(r.start(), &mut buf[0..l]);
-            match res {
-                Ok(nread) if nread >= find_s.len() => {
-                    let maybe_offset = find(&buf[0..nread], find_s);
-                    if let Some(off) = maybe_offset {
-                        let result = Some(r.start() + off);
-                        return result;
-                    }
-                }
-                // @TODO Check again. This means that any Err(()) might be ignored. Is this what we want?
-                _ => (),
-            }
-            r = MemoryRange::from_range(
-                min(r.end(), floor_page_size(r.start()) + page_size()),
-                r.end(),
-            );
-        }
-    }
-    None
-}
-
 fn get_threadid_from_tuid(session: &dyn Session, tuid: TaskUid) -> GdbThreadId {
     let maybe_t = session.find_task_from_task_uid(tuid);
     let pid = match maybe_t {
@@ -2586,6 +3318,39 @@ fn push_target_remote_cmd(vec: &mut Vec<OsString>, host: &str, port: u16) {
     vec.push(OsString::from_vec(ss));
 }
 
+/// lldb speaks the same remote protocol but takes different command line
+/// options, so tell the two apart by the name of the debugger binary.
+fn is_lldb(debugger_name: &Path) -> bool {
+    debugger_name
+        .file_name()
+        .map_or(false, |f| f.to_string_lossy().contains("lldb"))
+}
+
+fn push_default_lldb_options(vec: &mut Vec<OsString>) {
+    // See push_default_gdb_options() for why we need a long remote-reply
+    // timeout. `-O` commands run before the target is created.
+    vec.push("-O".into());
+    vec.push("settings set plugin.process.gdb-remote.packet-timeout 10000".into());
+}
+
+/// lldb can only source Python through a path ending in `.py`, which the
+/// /proc/<pid>/fd/<fd> path of our command file isn't, so exec() it instead.
+fn push_lldb_script_cmd(vec: &mut Vec<OsString>, script_file: &OsStr) {
+    vec.push("-O".into());
+    let mut ss = Vec::<u8>::new();
+    ss.extend_from_slice(b"script exec(open('");
+    ss.extend_from_slice(script_file.as_bytes());
+    ss.extend_from_slice(b"').read())");
+    vec.push(OsString::from_vec(ss));
+}
+
+fn push_lldb_remote_cmd(vec: &mut Vec<OsString>, host: &str, port: u16) {
+    vec.push("-o".into());
+    let mut ss = Vec::<u8>::new();
+    write!(ss, "gdb-remote {}:{}", host, port).unwrap();
+    vec.push(OsString::from_vec(ss));
+}
+
 #[derive(Default)]
 struct AllowedTasks {
     /// tid 0 means 'any member of debuggee_tguid'
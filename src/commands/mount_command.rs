@@ -0,0 +1,343 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    fuse::{Attr, FileKind, Filesystem, FuseMount, Inode, ROOT_INODE},
+    remote_ptr::{RemotePtr, Void},
+    replay::ReplaySessionBuilder,
+    session::{task::TaskSharedPtr, Session},
+    trace::{
+        trace_frame::FrameTime,
+        trace_reader::{TraceReader, ValidateSourceFile},
+        trace_stream::{MappedData, MappedDataSource},
+    },
+};
+use libc::pid_t;
+use nix::errno::Errno;
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io,
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::{Path, PathBuf},
+};
+
+const EVENT_INODE: Inode = 2;
+const MAPS_TXT_INODE: Inode = 3;
+const MAPS_DIR_INODE: Inode = 4;
+const FILES_DIR_INODE: Inode = 5;
+/// Inodes from here on are the entries of /maps, then those of /files.
+const FIRST_ENTRY_INODE: Inode = 16;
+
+/// Replays a trace to an event and serves the memory of a process at that
+/// point, and the files the trace saved, as a read-only FUSE filesystem:
+///
+/// ```text
+/// <mnt>/event                  the event number
+/// <mnt>/maps.txt               the memory map, like /proc/<pid>/maps
+/// <mnt>/maps/<start>-<end>     the contents of each mapping
+/// <mnt>/files/<name>           files the trace saved copies of
+/// ```
+///
+/// Serves until the filesystem is unmounted with `fusermount -u <mnt>`.
+pub struct MountCommand {
+    goto_event: FrameTime,
+    pid: Option<pid_t>,
+    trace_dir: PathBuf,
+    mount_point: PathBuf,
+}
+
+impl MountCommand {
+    pub fn new(options: &RdOptions) -> MountCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Mount {
+                goto_event,
+                pid,
+                trace_dir,
+                mount_point,
+            } => MountCommand {
+                goto_event,
+                pid,
+                trace_dir,
+                mount_point,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Mount` variant!"),
+        }
+    }
+}
+
+impl RdCommand for MountCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.mount() {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+struct MapEntry {
+    name: OsString,
+    start: RemotePtr<Void>,
+    size: usize,
+}
+
+struct SavedFile {
+    name: OsString,
+    path: OsString,
+    size: u64,
+}
+
+struct TraceFs {
+    event: FrameTime,
+    task: TaskSharedPtr,
+    maps_txt: Vec<u8>,
+    maps: Vec<MapEntry>,
+    files: Vec<SavedFile>,
+}
+
+impl MountCommand {
+    fn mount(&self) -> io::Result<()> {
+        let mut replayer = ReplaySessionBuilder::new()
+            .trace_dir(&self.trace_dir)
+            .build();
+        while replayer.current_frame_time() < self.goto_event {
+            if replayer.step().is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("The trace ends before event {}", self.goto_event),
+                ));
+            }
+        }
+        let maybe_task = match self.pid {
+            Some(pid) => replayer.session().find_task_from_rec_tid(pid),
+            None => replayer.current_task(),
+        };
+        let task = maybe_task.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such task at event {}", replayer.current_frame_time()),
+            )
+        })?;
+
+        let mut maps_txt = Vec::new();
+        let mut maps = Vec::new();
+        for (_, m) in &task.vm().maps() {
+            maps_txt.extend_from_slice(m.map.to_string().as_bytes());
+            maps_txt.push(b'\n');
+            maps.push(MapEntry {
+                name: OsString::from(format!(
+                    "{:x}-{:x}",
+                    m.map.start().as_usize(),
+                    m.map.end().as_usize()
+                )),
+                start: m.map.start(),
+                size: m.map.size(),
+            });
+        }
+
+        let mut fs = TraceFs {
+            event: replayer.current_frame_time(),
+            task,
+            maps_txt,
+            maps,
+            files: saved_files(&self.trace_dir, self.goto_event),
+        };
+        let mount = FuseMount::new(&self.mount_point)?;
+        eprintln!(
+            "Serving event {} at {:?}. Unmount it with `fusermount -u {}` when done.",
+            fs.event,
+            self.mount_point,
+            self.mount_point.display()
+        );
+        mount.serve(&mut fs)
+    }
+}
+
+/// The files the trace saved copies of that had been mapped by `goto_event`,
+/// named after the file that was mapped.
+fn saved_files(trace_dir: &Path, goto_event: FrameTime) -> Vec<SavedFile> {
+    let mut trace = TraceReader::new(Some(trace_dir));
+    let dir = trace.dir();
+    let mut files: Vec<SavedFile> = Vec::new();
+    let mut seen = HashSet::new();
+    while !trace.at_end() {
+        let frame = trace.read_frame();
+        if frame.time() > goto_event {
+            break;
+        }
+        loop {
+            let mut data = MappedData::default();
+            let km = match trace.read_mapped_region(
+                Some(&mut data),
+                Some(ValidateSourceFile::DontValidate),
+                None,
+                None,
+                None,
+            ) {
+                Some(km) => km,
+                None => break,
+            };
+            if data.source != MappedDataSource::SourceFile
+                || !data.filename.as_bytes().starts_with(dir.as_bytes())
+                || !seen.insert(data.filename.clone())
+            {
+                continue;
+            }
+            let size = match File::open(&data.filename).and_then(|f| f.metadata()) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            let base_name = Path::new(km.fsname())
+                .file_name()
+                .unwrap_or_else(|| OsStr::new("unnamed"))
+                .to_owned();
+            // Different files can have the same name.
+            let mut name = base_name.clone();
+            let mut n = 1;
+            while files.iter().any(|f| f.name == name) {
+                n += 1;
+                name = base_name.clone();
+                name.push(format!("~{}", n));
+            }
+            files.push(SavedFile {
+                name,
+                path: data.filename,
+                size,
+            });
+        }
+        // Skip the frame's raw data.
+        while trace.read_raw_data_metadata_for_frame().is_some() {}
+    }
+    files
+}
+
+impl TraceFs {
+    fn event_txt(&self) -> Vec<u8> {
+        format!("{}\n", self.event).into_bytes()
+    }
+
+    fn map_inode(&self, i: usize) -> Inode {
+        FIRST_ENTRY_INODE + i as Inode
+    }
+
+    fn file_inode(&self, i: usize) -> Inode {
+        FIRST_ENTRY_INODE + (self.maps.len() + i) as Inode
+    }
+
+    fn root_entries(&self) -> Vec<(OsString, Attr)> {
+        vec![
+            (
+                "event".into(),
+                file_attr(EVENT_INODE, self.event_txt().len() as u64),
+            ),
+            (
+                "maps.txt".into(),
+                file_attr(MAPS_TXT_INODE, self.maps_txt.len() as u64),
+            ),
+            ("maps".into(), dir_attr(MAPS_DIR_INODE)),
+            ("files".into(), dir_attr(FILES_DIR_INODE)),
+        ]
+    }
+}
+
+impl Filesystem for TraceFs {
+    fn lookup(&mut self, parent: Inode, name: &OsStr) -> Result<Attr, Errno> {
+        self.readdir(parent)?
+            .into_iter()
+            .find(|(entry_name, _)| entry_name == name)
+            .map(|(_, attr)| attr)
+            .ok_or(Errno::ENOENT)
+    }
+
+    fn getattr(&mut self, ino: Inode) -> Result<Attr, Errno> {
+        match ino {
+            ROOT_INODE | MAPS_DIR_INODE | FILES_DIR_INODE => Ok(dir_attr(ino)),
+            _ => self
+                .root_entries()
+                .into_iter()
+                .chain(self.readdir(MAPS_DIR_INODE)?)
+                .chain(self.readdir(FILES_DIR_INODE)?)
+                .map(|(_, attr)| attr)
+                .find(|attr| attr.ino == ino)
+                .ok_or(Errno::ENOENT),
+        }
+    }
+
+    fn readdir(&mut self, ino: Inode) -> Result<Vec<(OsString, Attr)>, Errno> {
+        match ino {
+            ROOT_INODE => Ok(self.root_entries()),
+            MAPS_DIR_INODE => Ok(self
+                .maps
+                .iter()
+                .enumerate()
+                .map(|(i, m)| (m.name.clone(), file_attr(self.map_inode(i), m.size as u64)))
+                .collect()),
+            FILES_DIR_INODE => Ok(self
+                .files
+                .iter()
+                .enumerate()
+                .map(|(i, f)| (f.name.clone(), file_attr(self.file_inode(i), f.size)))
+                .collect()),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn read(&mut self, ino: Inode, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let whole = |data: &[u8]| {
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            data[start..end].to_vec()
+        };
+        match ino {
+            EVENT_INODE => return Ok(whole(&self.event_txt())),
+            MAPS_TXT_INODE => return Ok(whole(&self.maps_txt)),
+            _ => (),
+        }
+        if ino < FIRST_ENTRY_INODE {
+            return Err(Errno::EISDIR);
+        }
+
+        let i = (ino - FIRST_ENTRY_INODE) as usize;
+        if let Some(m) = self.maps.get(i) {
+            let offset = (offset as usize).min(m.size);
+            let mut buf = vec![0u8; (size as usize).min(m.size - offset)];
+            // Guard pages and the like can't be read.
+            let nread = self
+                .task
+                .read_bytes_fallible(m.start + offset, &mut buf)
+                .map_err(|_| Errno::EIO)?;
+            buf.truncate(nread);
+            return Ok(buf);
+        }
+        match self.files.get(i - self.maps.len()) {
+            Some(f) => {
+                let mut buf = vec![0u8; size as usize];
+                let nread = File::open(&f.path)
+                    .and_then(|file| file.read_at(&mut buf, offset))
+                    .map_err(|_| Errno::EIO)?;
+                buf.truncate(nread);
+                Ok(buf)
+            }
+            None => Err(Errno::ENOENT),
+        }
+    }
+}
+
+fn file_attr(ino: Inode, size: u64) -> Attr {
+    Attr {
+        ino,
+        kind: FileKind::RegularFile,
+        size,
+    }
+}
+
+fn dir_attr(ino: Inode) -> Attr {
+    Attr {
+        ino,
+        kind: FileKind::Directory,
+        size: 0,
+    }
+}
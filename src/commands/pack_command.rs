@@ -0,0 +1,66 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{
+        trace_checksum::discard_checksums,
+        trace_codec::TraceCodec,
+        trace_dir::{recompress_trace, trace_data_discarded, TraceDir},
+        trace_pack::pack_trace,
+    },
+};
+use std::{
+    io::{Error, ErrorKind},
+    path::PathBuf,
+};
+
+pub struct PackCommand {
+    recompress: Option<TraceCodec>,
+    trace_dir: Option<PathBuf>,
+}
+
+impl PackCommand {
+    pub fn new(options: &RdOptions) -> PackCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Pack {
+                recompress,
+                trace_dir,
+            } => PackCommand {
+                recompress,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Pack` variant!"),
+        }
+    }
+}
+
+impl RdCommand for PackCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        let trace_dir = TraceDir::from_env().resolve(self.trace_dir.as_ref());
+        if trace_data_discarded(&trace_dir) {
+            return ExitResult::err_from(
+                Error::new(
+                    ErrorKind::Other,
+                    format!("The data of trace {:?} was discarded", trace_dir),
+                ),
+                1,
+            );
+        }
+        // The trace's files are about to change.
+        if let Err(e) = discard_checksums(&trace_dir) {
+            return ExitResult::err_from(e, 1);
+        }
+        let result = match self.recompress {
+            Some(codec) => {
+                pack_trace(&trace_dir, codec).and_then(|_| recompress_trace(&trace_dir, codec))
+            }
+            None => pack_trace(&trace_dir, TraceCodec::default()),
+        };
+        match result {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
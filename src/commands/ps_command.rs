@@ -48,13 +48,11 @@ impl PsCommand {
         let mut trace = TraceReader::new(self.trace_dir.as_ref());
         writeln!(out, "PID\tPPID\tEXIT\tCMD")?;
 
-        let mut events: Vec<TraceTaskEvent> = Vec::new();
-        while let Some(r) = trace.read_task_event(None) {
-            events.push(r);
-        }
-
-        let not_exec = !matches!(events[0].event_variant(), TraceTaskEventVariant::Exec(_));
-        if events.is_empty() || not_exec {
+        let events: Vec<TraceTaskEvent> = trace.task_events().collect();
+        let first_is_exec = events.first().map_or(false, |e| {
+            matches!(e.event_variant(), TraceTaskEventVariant::Exec(_))
+        });
+        if !first_is_exec {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Invalid Trace. No task events found or the first task event was not an Exec",
@@ -78,7 +76,7 @@ impl PsCommand {
 
             match e.event_variant() {
                 TraceTaskEventVariant::Clone(c)
-                    if (c.clone_flags() & libc::CLONE_THREAD != libc::CLONE_THREAD) =>
+                    if (c.clone_flags() & libc::CLONE_THREAD as u64 == 0) =>
                 {
                     let pid = tid_to_pid[&e.tid()];
                     write!(out, "{}", e.tid())?;
@@ -115,7 +113,7 @@ impl PsCommand {
 fn update_tid_to_pid_map(tid_to_pid: &mut TidPidMap, e: &TraceTaskEvent) {
     match e.event_variant() {
         TraceTaskEventVariant::Clone(c) => {
-            if c.clone_flags() & libc::CLONE_THREAD == libc::CLONE_THREAD {
+            if c.clone_flags() & libc::CLONE_THREAD as u64 != 0 {
                 // thread clone. Record thread's pid.
                 tid_to_pid.insert(e.tid(), c.parent_tid());
             } else {
@@ -1,13 +1,20 @@
 use crate::{
-    commands::rerun_command::TraceFields,
+    commands::{record_command::RecordChildrenPolicy, rerun_command::TraceFields},
+    condition_expression::ConditionExpression,
+    divergence_repair::BenignDivergences,
+    error_injector::InjectErrorSpec,
+    file_monitor::cgroup_file_monitor::CgroupLimit,
     flags::{Checksum, DumpOn},
     kernel_metadata::signal_name,
     kernel_supplement::NUM_SIGNALS,
-    scheduler::TicksHowMany,
-    session::record_session::TraceUuid,
+    scheduler::{TicksHowMany, TICKS_PER_MS},
+    session::record_session::{DisableCPUIDFeatures, IoUringPolicy, TraceUuid},
     sig::Sig,
     ticks::Ticks,
-    trace::trace_frame::FrameTime,
+    trace::{
+        trace_codec::TraceCodec, trace_dir::TraceDir, trace_frame::FrameTime,
+        trace_reader::trace_recorded_without_pmu,
+    },
     util::{find, page_size},
 };
 use libc::pid_t;
@@ -49,6 +56,17 @@ pub struct RdOptions {
     )]
     pub disable_ptrace_exit_events: bool,
 
+    /// Don't use hardware performance counters. This lets rd record and replay on machines
+    /// where the PMU is unavailable (e.g. high `perf_event_paranoid` settings or VMs without
+    /// a virtual PMU) at the cost of speed: ticks count singlestepped instructions instead,
+    /// so tasks that may need to be preempted or interrupted by a signal handler (any task
+    /// of a multi-threaded or multi-process tracee, or one with signal handlers) are
+    /// singlestepped, and syscall buffering is disabled. Other tasks run freely and receive
+    /// asynchronous signals at their next system call. Traces recorded with this option
+    /// are replayed the same way without passing it again.
+    #[structopt(long = "no-pmu")]
+    pub no_pmu: bool,
+
     /// Specify the paths that rd should use to find files such as rd_page_*.  These files
     /// should be located in `<resource-path>/bin`, `<resource-path>/lib[64]`, and
     /// `<resource-path>/share` as appropriate.
@@ -61,6 +79,14 @@ pub struct RdOptions {
     #[structopt(short = "A", long = "microarch")]
     pub microarch: Option<String>,
 
+    /// Count ticks with the raw PMU event <event> (in `perf stat --event=r<event>`
+    /// notation, e.g. `r5111c4`) instead of the microarchitecture's default
+    /// retired-conditional-branches (or taken-branches) event. For CPUs and VMs where the
+    /// default event isn't available or is encoded differently. The event must count the
+    /// same thing as the default one; record and replay must use equivalent events.
+    #[structopt(long = "ticks-event", parse(try_from_str = parse_ticks_event))]
+    pub ticks_event: Option<u64>,
+
     /// Force rd to do some things that don't seem like good ideas, for example launching
     /// an interactive emergency debugger if stderr isn't a tty.
     #[structopt(short = "F", long = "force-things")]
@@ -163,6 +189,38 @@ fn parse_checksum(checksum_s: &str) -> Result<Checksum, Box<dyn Error>> {
     }
 }
 
+fn parse_io_uring_policy(policy_s: &str) -> Result<IoUringPolicy, Box<dyn Error>> {
+    match policy_s {
+        "disable" => Ok(IoUringPolicy::Disable),
+        "refuse" => Ok(IoUringPolicy::Refuse),
+        _ => Err(Box::new(clap::Error::with_description(
+            "Only `disable` or `refuse` is valid here",
+            clap::ErrorKind::InvalidValue,
+        ))),
+    }
+}
+
+fn parse_trace_codec(codec_s: &str) -> Result<TraceCodec, Box<dyn Error>> {
+    codec_s.parse::<TraceCodec>().map_err(|e| {
+        Box::new(clap::Error::with_description(
+            &e,
+            clap::ErrorKind::InvalidValue,
+        )) as Box<dyn Error>
+    })
+}
+
+fn parse_record_children_policy(policy_s: &str) -> Result<RecordChildrenPolicy, Box<dyn Error>> {
+    match policy_s {
+        "none" => Ok(RecordChildrenPolicy::None),
+        "failed" => Ok(RecordChildrenPolicy::Failed),
+        "all" => Ok(RecordChildrenPolicy::All),
+        _ => Err(Box::new(clap::Error::with_description(
+            "Only `none`, `failed` or `all` is valid here",
+            clap::ErrorKind::InvalidValue,
+        ))),
+    }
+}
+
 fn parse_dump_on(dump_on_s: &str) -> Result<DumpOn, Box<dyn Error>> {
     if dump_on_s == "ALL" {
         Ok(DumpOn::DumpOnAll)
@@ -221,6 +279,11 @@ pub enum RdSubCommand {
         #[structopt(short = "r", long = "raw")]
         raw_dump: bool,
 
+        /// Dump each trace frame as one line of JSON, with the syscallbuf, task
+        /// event, mmap and recorded data information asked for as fields of it
+        #[structopt(long, conflicts_with = "raw-dump")]
+        json: bool,
+
         /// Dump statistics about the trace
         #[structopt(short = "s")]
         statistics: bool,
@@ -229,6 +292,10 @@ pub enum RdSubCommand {
         #[structopt(short = "t", long = "tid")]
         only_tid: Option<libc::pid_t>,
 
+        /// Dump only entries to and exits from the named syscall, e.g. `openat`
+        #[structopt(long = "syscall")]
+        only_syscall: Option<String>,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
 
@@ -274,7 +341,8 @@ pub enum RdSubCommand {
         #[structopt(short = "i", long = "interpreter")]
         interpreter: Option<String>,
 
-        /// Use <debugger-file> as the debugger command
+        /// Use <debugger-file> as the debugger command. If its file name contains
+        /// `lldb` it is launched with lldb's command line options instead of gdb's
         #[structopt(short = "d", long = "debugger")]
         debugger_file: Option<PathBuf>,
 
@@ -295,6 +363,12 @@ pub enum RdSubCommand {
         #[structopt(short = "k", long = "keep-listening")]
         keep_listening: bool,
 
+        /// Replay a trace from an untrusted source in a sandbox: no network access (except
+        /// loopback), a read-only filesystem and a private, empty temporary directory.
+        /// Requires unprivileged user namespaces. Can't be used with --dbgport or --dbghost
+        #[structopt(long = "sandbox")]
+        sandbox: bool,
+
         /// When true make all private mappings shared with the tracee by default
         /// to test the corresponding code.
         #[structopt(long = "share-private-mappings")]
@@ -310,6 +384,24 @@ pub enum RdSubCommand {
         #[structopt(short = "u", long = "cpu-unbound")]
         cpu_unbound: bool,
 
+        #[structopt(
+            long = "repair-divergence",
+            number_of_values = 1,
+            parse(try_from_str = parse_benign_divergences),
+            help = "Instead of aborting, patch the replay to match the recording when it diverges\n\
+                    in one of these benign ways, and log the repair.\n\
+                    Where <repair-divergence> := <kind>[,<kind>...]\n\
+                    <kind>: eflags | syscall-clobbered (rcx/r11) | fpu-pointers (x87 FOP/FIP/FDP) | all\n\
+                    Can be given multiple times. Other divergences are still fatal."
+        )]
+        repair_divergence: Vec<BenignDivergences>,
+
+        /// Where <only-process> := <pid>. Once the recorded process <pid> has been created,
+        /// only replay it and its threads; the other processes are killed. Writes they make
+        /// to memory shared with <pid> are not reproduced
+        #[structopt(long = "only-process", parse(try_from_str = parse_pid))]
+        only_process: Option<pid_t>,
+
         /// Execute gdb commands from <gdb-x-file>
         #[structopt(short = "x", long = "gdb-x")]
         gdb_x_file: Option<OsString>,
@@ -318,6 +410,16 @@ pub enum RdSubCommand {
         #[structopt(long = "stats", parse(try_from_str = parse_stats))]
         stats: Option<u32>,
 
+        /// While debugging, checkpoint every <checkpoint-interval> events when replaying forward
+        /// so that reverse execution doesn't have to replay from the start of the trace
+        #[structopt(long = "checkpoint-interval")]
+        checkpoint_interval: Option<FrameTime>,
+
+        /// Evict the least recently useful checkpoints taken by --checkpoint-interval when
+        /// together they use more than <checkpoint-memory-budget> MB of memory
+        #[structopt(long = "checkpoint-memory-budget")]
+        checkpoint_memory_budget: Option<usize>,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
 
@@ -339,12 +441,20 @@ pub enum RdSubCommand {
         #[structopt(short = "c", long = "num-cpu-ticks", parse(try_from_str = parse_num_cpu_ticks))]
         num_cpu_ticks: Option<Ticks>,
 
+        /// Interrupt a task after it has run for roughly <switch-interval> milliseconds
+        /// (default 10). This is converted to a number of 'CPU ticks' assuming 50,000 ticks
+        /// per millisecond, so it is only approximate. --num-cpu-ticks takes precedence
+        #[structopt(long = "switch-interval", parse(try_from_str = parse_switch_interval))]
+        switch_interval: Option<Ticks>,
+
         #[structopt(long="disable-cpuid-features", parse(try_from_str = parse_disable_cpuid_features),
-           help="Where <disable-cpuid-features> := <CCC>[,<DDD>]\n\
-                 Mask out CPUID EAX=1 feature bits\n\
-                 <CCC>: Bitmask of bits to clear from ECX\n\
-                 <DDD>: Bitmask of bits to clear from EDX")]
-        disable_cpuid_features: Option<(u32, u32)>,
+           help="Where <disable-cpuid-features> := <mask>[,<mask>...]\n\
+                 Mask out CPUID feature bits\n\
+                 <mask>: <leaf>[.<subleaf>]:<register>=<bits>, e.g. 0x7.0:ebx=0x10000, to clear\n\
+                 <bits> from <register> (eax, ebx, ecx or edx) of CPUID EAX=<leaf>, ECX=<subleaf>\n\
+                 (any subleaf if omitted), or `avx512` for all the AVX-512 bits.\n\
+                 For compatibility <CCC>[,<DDD>] clears the CPUID EAX=1 ECX and EDX bits.")]
+        disable_cpuid_features: Option<DisableCPUIDFeatures>,
 
         #[structopt(long="disable-cpuid-features-ext", parse(try_from_str = parse_disable_cpuid_features_ext),
            help="Where <disable-cpuid-features-ext> := <BBB>[,<CCC>[,<DDD>]]\n\
@@ -364,6 +474,38 @@ pub enum RdSubCommand {
         #[structopt(short = "h", long = "chaos")]
         chaos_mode: bool,
 
+        #[structopt(
+            long = "inject-errors",
+            number_of_values = 1,
+            parse(try_from_str = parse_inject_error_spec),
+            help = "Make matching syscalls fail during recording.\n\
+                    Where <inject-errors> := <syscall>[:<nth>][:fd><fd>]=<errno>\n\
+                    e.g. `write:3:fd>2=ENOSPC` fails the 3rd write() to an fd > 2 with ENOSPC.\n\
+                    Without <nth> every matching call fails. A call the kernel restarts after\n\
+                    a signal only counts once. Can be given multiple times.\n\
+                    Disables the syscall buffer."
+        )]
+        inject_errors: Vec<InjectErrorSpec>,
+
+        /// Generate the data returned by getrandom() and reads of /dev/urandom and
+        /// /dev/random from <seed>, so recordings with the same seed see the same
+        /// "random" bytes. Useful for fuzzing workflows
+        #[structopt(long = "seed")]
+        seed: Option<u64>,
+
+        #[structopt(
+            long = "cgroup-limit",
+            parse(try_from_str = parse_cgroup_limit),
+            number_of_values = 1,
+            help = "Make reads of a cgroup interface file return <cgroup-limit> instead of the\n\
+                    real limit, to reproduce programs that size thread pools or caches from their\n\
+                    cgroup. Where <cgroup-limit> := <file>=<value>\n\
+                    e.g. `memory.max=2G` or `cpu.max=200000 100000`. Memory sizes may use a\n\
+                    K/M/G/T suffix. Applies to that file in every cgroup under /sys/fs/cgroup.\n\
+                    Can be given multiple times."
+        )]
+        cgroup_limits: Vec<CgroupLimit>,
+
         /// block <ignore-signal> from being delivered to tracees. Probably only useful
         /// for unit tests.
         #[structopt(short = "i", long = "ignore-signal", parse(try_from_str = parse_signal_name))]
@@ -381,12 +523,88 @@ pub enum RdSubCommand {
         #[structopt(long = "no-read-cloning")]
         no_read_cloning: bool,
 
-        /// pretend to have N cores (rd will still only run on a single core). Overrides
-        /// random setting from --chaos.
+        /// disable address space layout randomization in the tracees with
+        /// personality(ADDR_NO_RANDOMIZE), so each run of a program lays out its memory
+        /// the same way. The choice is recorded in the trace and replay follows it.
+        #[structopt(long = "no-aslr", overrides_with = "aslr")]
+        no_aslr: bool,
+
+        /// leave address space layout randomization enabled in the tracees (the default).
+        /// Whichever of --aslr and --no-aslr comes last wins
+        #[structopt(long = "aslr", overrides_with = "no_aslr")]
+        aslr: bool,
+
+        /// don't patch syscalls in <no-patch-library> to go through the syscall buffer.
+        /// Matches a mapped file's full path or file name, or its build-id if given as
+        /// `buildid:<hex>`. Can be given multiple times. The libraries left unpatched are
+        /// listed in the trace header (see `rd trace-info`).
+        #[structopt(long = "no-patch-library", number_of_values = 1)]
+        no_patch_library: Vec<String>,
+
+        /// keep a single copy of each executable or library copied into traces, in an
+        /// `objects` directory next to the traces, and hardlink it into every trace that
+        /// needs it. Saves space when recording the same large binaries repeatedly.
+        #[structopt(long = "share-copied-files")]
+        share_copied_files: bool,
+
+        /// make replay independent of the files the tracees map, for hosts where an
+        /// antivirus or indexer (a fanotify listener) might modify, quarantine or lock
+        /// them. Mapped files that can't be cloned are copied into the trace instead of
+        /// being hardlinked or read from their original location at replay, and the
+        /// debugger is given the copy of the executable. This makes traces larger. The
+        /// debugger still loads shared libraries' symbols from their original paths. It can't stop such agents from delaying the tracees' file
+        /// accesses or making them fail while recording: those results are recorded and
+        /// replayed faithfully, so pause the agents if they change what the program does.
+        #[structopt(long = "isolate-file-access")]
+        isolate_file_access: bool,
+
+        /// plant a breakpoint at each SystemTap SDT (USDT) probe in the executables
+        /// and libraries the tracees map, and record every probe hit with its argument
+        /// values. The hits show up in `rd dump` and `rd stats`, and `rd-catch-sdt`
+        /// stops at them in the debugger. Probes guarded by a semaphore only fire if
+        /// something else enables them.
+        #[structopt(long = "stap-sdt")]
+        stap_sdt: bool,
+
+        /// what to do when a tracee uses io_uring, which rd can't record: `disable` makes
+        /// io_uring_setup() fail with ENOSYS so programs fall back to regular syscalls,
+        /// `refuse` stops recording with an error.
+        #[structopt(
+            long = "io-uring",
+            default_value = "disable",
+            parse(try_from_str = parse_io_uring_policy)
+        )]
+        io_uring: IoUringPolicy,
+
+        /// which processes to keep the data of, for test runners that record many
+        /// runs: `failed` keeps it for the processes that exited with a non-zero status
+        /// or were killed by a signal and their ancestors, so they stay replayable, and
+        /// `none` never keeps it. The other processes only keep their process tree and
+        /// exit statuses (as shown by `rd ps`).
+        #[structopt(
+            long = "record-children",
+            default_value = "all",
+            parse(try_from_str = parse_record_children_policy)
+        )]
+        record_children: RecordChildrenPolicy,
+
+        /// how to compress the trace: `brotli`, `zstd` or `lz4` (compressing less in
+        /// return for less CPU time, in that order), or `none` to trade disk space
+        /// for no time spent compressing while recording.
+        #[structopt(
+            long = "compression",
+            default_value = "brotli",
+            parse(try_from_str = parse_trace_codec)
+        )]
+        compression: TraceCodec,
+
+        /// pretend to have N cores in sysconf(_SC_NPROCESSORS_ONLN) and the
+        /// sched_getaffinity() mask (rd will still only run on a single core).
+        /// Overrides random setting from --chaos.
         #[structopt(long = "num-cores", parse(try_from_str = parse_num_cores))]
         num_cores: Option<u32>,
 
-        /// set the output trace directory. _RD_TRACE_DIR gets ignored.
+        /// set the output trace directory. RD_TRACE_DIR gets ignored.
         /// Directory name is given name, not the application name.
         #[structopt(short = "o", long = "output-trace-dir")]
         output_trace_dir: Option<OsString>,
@@ -461,8 +679,15 @@ pub enum RdSubCommand {
         #[structopt(long = "copy-preload-src")]
         copy_preload_src: bool,
 
+        /// Attach to the running process PID and record it from its current
+        /// state onwards, instead of starting a program. The process must be
+        /// 64-bit. rd detaches from it when recording ends.
+        #[structopt(long = "attach", conflicts_with = "exe", parse(try_from_str = parse_pid))]
+        attach: Option<pid_t>,
+
         /// Program being recorded
-        exe: OsString,
+        #[structopt(required_unless = "attach")]
+        exe: Option<OsString>,
 
         /// Optional command line arguments passed to the program being recorded
         /// Use a single `--` to prevent rd from confusing the command line switches being
@@ -504,10 +729,18 @@ pub enum RdSubCommand {
         /// instruction repetitions are treated as a single instruction if not
         /// interrupted. A 'singlestep' includes events such as system-call-exit
         /// where tracee state changes without any user-level instructions actually
-        /// being executed
-        #[structopt(long = "singlestep", parse(try_from_str = crate::commands::rerun_command::parse_regs))]
+        /// being executed. `gp`, `xmm` and `ymm` are short for the `x16` forms and
+        /// `seg` selects all six segment registers. `--regs` is an alias for this
+        /// option, e.g. `--regs=event,icount,gp,flags`
+        #[structopt(long = "singlestep", alias = "regs", parse(try_from_str = crate::commands::rerun_command::parse_regs))]
         singlestep_regs: Option<TraceFields>,
 
+        /// Only output a singlestep record when <condition> holds after the step,
+        /// e.g. `$rdi == 3 && *(u32 *)($rsp + 8) > 100`. See condition_expression.rs
+        /// for the syntax
+        #[structopt(short = "c", long, parse(try_from_str = parse_condition))]
+        condition: Option<ConditionExpression>,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
     },
@@ -525,6 +758,230 @@ pub enum RdSubCommand {
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
     },
+
+    /// Print the scheduling history of the trace: which task ran between which events
+    /// and why it was descheduled.
+    #[structopt(name = "sched")]
+    Sched {
+        /// Output the history in JSON format instead of a table
+        #[structopt(short = "j", long)]
+        json: bool,
+
+        /// Only print the scheduling slices of the specified tid
+        #[structopt(short = "t", long = "tid")]
+        only_tid: Option<libc::pid_t>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Summarize a trace: events by type, syscall counts, recorded data and ticks per
+    /// task, recorded data per fd read from and per mapping, and how long the recording
+    /// took.
+    #[structopt(name = "stats")]
+    Stats {
+        /// Output the summary in JSON format
+        #[structopt(short = "j", long)]
+        json: bool,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Summarize the nondeterministic input a trace captured, by source: randomness, clocks,
+    /// the network, other syscall results, rdtsc/cpuid, signals and scheduling.
+    #[structopt(name = "entropy")]
+    Entropy {
+        /// Output the report in JSON format
+        #[structopt(short = "j", long)]
+        json: bool,
+
+        /// Also list every event that brought in nondeterministic input
+        #[structopt(short = "e", long = "events")]
+        list_events: bool,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Interactively browse the events of a trace: page through them, filter by task or
+    /// syscall, inspect an event's recorded data, and start debugging at an event.
+    #[structopt(name = "browse")]
+    Browse {
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Replay to an event and mount a read-only FUSE filesystem at <mount-point> showing
+    /// the memory mappings of a process at that point and the files the trace saved.
+    /// Unmount it with `fusermount -u <mount-point>`.
+    #[structopt(name = "mount")]
+    Mount {
+        /// Replay to <event-num> before showing the process's memory
+        #[structopt(short = "g", long = "goto", parse(try_from_str = parse_goto_event))]
+        goto_event: FrameTime,
+
+        /// Show the memory of the task with this recorded tid instead of the task
+        /// running at the event
+        #[structopt(short = "p", long)]
+        pid: Option<pid_t>,
+
+        /// Which directory is the trace data in?
+        trace_dir: PathBuf,
+
+        /// Where to mount the filesystem
+        mount_point: PathBuf,
+    },
+
+    /// Singlestep through a range of trace events and write which code was executed
+    /// as drcov coverage, e.g. for Lighthouse. This is slow: expect the replay to
+    /// take thousands of times as long as the recording.
+    #[structopt(name = "coverage")]
+    Coverage {
+        #[structopt(short = "s", long, help = "Start recording coverage at <trace-start>")]
+        trace_start: Option<FrameTime>,
+
+        #[structopt(short = "e", long, help = "Stop recording coverage at <trace-end>")]
+        trace_end: Option<FrameTime>,
+
+        /// Only record the coverage of the process with this recorded pid
+        #[structopt(short = "p", long)]
+        pid: Option<pid_t>,
+
+        /// Write the coverage to this file instead of stdout
+        #[structopt(short = "o", long)]
+        output: Option<PathBuf>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Find the crash in each trace and group the traces by crash signature: the fatal
+    /// signal, the kind of memory a fault was in and the symbolized top stack frames.
+    /// Prints a JSON report with the most common crash first. Replays each trace up to
+    /// its crash.
+    #[structopt(name = "triage")]
+    Triage {
+        /// How many stack frames make up a signature
+        #[structopt(short = "n", long, default_value = "5")]
+        frames: usize,
+
+        /// The traces to triage
+        #[structopt(required = true)]
+        trace_dirs: Vec<PathBuf>,
+    },
+
+    /// Replay a trace and check the assertions in a YAML spec file, for regression
+    /// tests that run against a trace instead of a debugger. Each assertion has an
+    /// `expect` condition, in the language of `rd rerun --condition` (see
+    /// condition_expression.rs), where other names are ELF symbols' addresses. It is
+    /// checked either when `function` is entered for the `hit`th time (default 1) or
+    /// when replay reaches trace `event`. Prints a PASS or FAIL line per assertion and
+    /// exits with 1 if any failed or were never reached.
+    #[structopt(name = "assert")]
+    Assert {
+        /// The YAML spec, e.g. `assertions: [{function: foo, expect: "*(i32 *)g_count == 3"}]`
+        spec: PathBuf,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Make a trace self-contained so it can be copied to another machine and
+    /// replayed there: copy every file its mappings are replayed from into the trace
+    /// directory, deduplicated by content, and point the trace at the copies.
+    #[structopt(name = "pack")]
+    Pack {
+        /// Also recompress every block of the trace with this codec (`brotli`, `zstd`,
+        /// `lz4` or `none`), e.g. to shrink a trace recorded with `--compression=none`
+        #[structopt(long, parse(try_from_str = parse_trace_codec))]
+        recompress: Option<TraceCodec>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Check a trace for damage against the checksums of its blocks, which are kept
+    /// in a signed `checksums` manifest in the trace directory. Without --verify only
+    /// the quick check `rd fsck` and replay also do is made: that the manifest's
+    /// signature is good and the files it lists have the right sizes.
+    #[structopt(name = "checksum")]
+    Checksum {
+        /// Read the whole trace and compare every block with the manifest, creating
+        /// the manifest if there is none. An interrupted run resumes where it stopped.
+        #[structopt(long)]
+        verify: bool,
+
+        /// With --verify, read at most this many MiB per second
+        #[structopt(long, value_name = "MiB/s")]
+        rate_limit: Option<u64>,
+
+        /// The key to sign and check the manifest with. Defaults to $RD_MANIFEST_KEY,
+        /// or ~/.config/rd/manifest-key, which is created if needed
+        #[structopt(long)]
+        key: Option<PathBuf>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Check that a trace is complete and undamaged using its signed checksum
+    /// manifest (see `rd checksum`): that the recording finished, that the manifest's
+    /// signature is good and that every file it lists has the right size.
+    #[structopt(name = "fsck")]
+    Fsck {
+        /// Also read the whole trace and compare every block with the manifest
+        #[structopt(long)]
+        full: bool,
+
+        /// The key the manifest was signed with. Defaults to $RD_MANIFEST_KEY, or
+        /// ~/.config/rd/manifest-key
+        #[structopt(long)]
+        key: Option<PathBuf>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Delete a trace, and any files in the shared object store
+    /// (`rd record --share-copied-files`) that no remaining trace uses.
+    #[structopt(name = "rm")]
+    Rm {
+        /// The trace to delete
+        trace_dir: PathBuf,
+    },
+}
+
+impl RdSubCommand {
+    /// Does this command record or replay tracees (and so need ptrace and, unless
+    /// --no-pmu, the PMU)?
+    pub fn runs_tracees(&self) -> bool {
+        matches!(
+            self,
+            RdSubCommand::Record { .. }
+                | RdSubCommand::Replay { .. }
+                | RdSubCommand::ReRun { .. }
+                | RdSubCommand::Mount { .. }
+                | RdSubCommand::Coverage { .. }
+                | RdSubCommand::Triage { .. }
+                | RdSubCommand::Assert { .. }
+        )
+    }
+
+    /// Does this command replay a trace that was recorded with --no-pmu? Such a
+    /// trace is always replayed without the PMU, whether --no-pmu is passed
+    /// again or not.
+    pub fn replays_trace_recorded_without_pmu(&self) -> bool {
+        let trace_dir = match self {
+            RdSubCommand::Replay { trace_dir, .. }
+            | RdSubCommand::ReRun { trace_dir, .. }
+            | RdSubCommand::Coverage { trace_dir, .. }
+            | RdSubCommand::Assert { trace_dir, .. } => trace_dir.as_ref(),
+            RdSubCommand::Mount { trace_dir, .. } => Some(trace_dir),
+            _ => return false,
+        };
+        trace_recorded_without_pmu(&TraceDir::from_env().resolve(trace_dir))
+    }
 }
 
 fn parse_env_name_val(maybe_name_val: &OsStr) -> Result<(OsString, OsString), OsString> {
@@ -625,6 +1082,23 @@ fn parse_num_cpu_ticks(maybe_num_ticks: &str) -> Result<Ticks, Box<dyn Error>> {
     }
 }
 
+/// Convert a --switch-interval in milliseconds into a tick budget.
+fn parse_switch_interval(maybe_ms: &str) -> Result<Ticks, Box<dyn Error>> {
+    let ms = maybe_ms.parse::<f64>()?;
+    let ticks = ms * TICKS_PER_MS;
+    if !(1.0..=TicksHowMany::MaxMaxTicks as u64 as f64).contains(&ticks) {
+        return Err(Box::new(clap::Error::with_description(
+            &format!(
+                "Switch interval must be between {} and {} milliseconds",
+                1.0 / TICKS_PER_MS,
+                TicksHowMany::MaxMaxTicks as u64 as f64 / TICKS_PER_MS
+            ),
+            clap::ErrorKind::InvalidValue,
+        )));
+    }
+    Ok(ticks as Ticks)
+}
+
 fn parse_syscallbuf_size(maybe_size: &str) -> Result<usize, Box<dyn Error>> {
     match maybe_size.parse::<usize>() {
         Err(e) => Err(Box::new(e)),
@@ -642,6 +1116,63 @@ fn parse_syscallbuf_size(maybe_size: &str) -> Result<usize, Box<dyn Error>> {
     }
 }
 
+fn parse_inject_error_spec(maybe_spec: &str) -> Result<InjectErrorSpec, Box<dyn Error>> {
+    match maybe_spec.parse::<InjectErrorSpec>() {
+        Err(e) => Err(Box::new(clap::Error::with_description(
+            &e,
+            clap::ErrorKind::InvalidValue,
+        ))),
+        Ok(spec) => Ok(spec),
+    }
+}
+
+fn parse_cgroup_limit(maybe_limit: &str) -> Result<CgroupLimit, Box<dyn Error>> {
+    match maybe_limit.parse::<CgroupLimit>() {
+        Err(e) => Err(Box::new(clap::Error::with_description(
+            &e,
+            clap::ErrorKind::InvalidValue,
+        ))),
+        Ok(limit) => Ok(limit),
+    }
+}
+
+fn parse_condition(maybe_expr: &str) -> Result<ConditionExpression, Box<dyn Error>> {
+    match maybe_expr.parse::<ConditionExpression>() {
+        Err(e) => Err(Box::new(clap::Error::with_description(
+            &e,
+            clap::ErrorKind::InvalidValue,
+        ))),
+        Ok(expr) => Ok(expr),
+    }
+}
+
+fn parse_benign_divergences(kinds: &str) -> Result<BenignDivergences, Box<dyn Error>> {
+    match kinds.parse::<BenignDivergences>() {
+        Err(e) => Err(Box::new(clap::Error::with_description(
+            &e,
+            clap::ErrorKind::InvalidValue,
+        ))),
+        Ok(benign) => Ok(benign),
+    }
+}
+
+fn parse_ticks_event(maybe_event: &str) -> Result<u64, Box<dyn Error>> {
+    let hex = maybe_event
+        .strip_prefix('r')
+        .or_else(|| maybe_event.strip_prefix("0x"))
+        .unwrap_or(maybe_event);
+    match u64::from_str_radix(hex, 16) {
+        Ok(event) if event != 0 => Ok(event),
+        _ => Err(Box::new(clap::Error::with_description(
+            &format!(
+                "`{}` is not a raw PMU event like r5101c4 or 0x5101c4",
+                maybe_event
+            ),
+            clap::ErrorKind::InvalidValue,
+        ))),
+    }
+}
+
 fn parse_num_cores(maybe_num_cores: &str) -> Result<u32, Box<dyn Error>> {
     match maybe_num_cores.parse::<u32>() {
         Err(e) => Err(Box::new(e)),
@@ -753,16 +1284,14 @@ fn parse_disable_cpuid_features_xsave(
 
 fn parse_disable_cpuid_features(
     disable_cpuid_features: &str,
-) -> Result<(u32, u32), Box<dyn Error>> {
-    let feat: Vec<&str> = disable_cpuid_features.trim().splitn(2, ',').collect();
-    let u1 = parse_u32(&feat[0])?;
-    let u2: u32;
-    if feat.len() == 1 {
-        u2 = 0;
-    } else {
-        u2 = parse_u32(&feat[1])?;
+) -> Result<DisableCPUIDFeatures, Box<dyn Error>> {
+    match disable_cpuid_features.parse::<DisableCPUIDFeatures>() {
+        Err(e) => Err(Box::new(clap::Error::with_description(
+            &e,
+            clap::ErrorKind::InvalidValue,
+        ))),
+        Ok(features) => Ok(features),
     }
-    Ok((u1, u2))
 }
 
 fn parse_disable_cpuid_features_ext(
@@ -6,18 +6,28 @@ use crate::{
         rd_options::{RdOptions, RdSubCommand},
         RdCommand,
     },
+    error_injector::InjectErrorSpec,
+    file_monitor::cgroup_file_monitor::CgroupLimit,
     log::{notifying_abort, LogInfo, LogWarn},
+    perf_counters::pmu_disabled,
+    preflight::Environment,
     scheduler::TicksHowMany,
     session::record_session::{
-        DisableCPUIDFeatures, RecordResult, RecordSession, SyscallBuffering, TraceUuid,
+        DisableCPUIDFeatures, IoUringPolicy, RecordResult, RecordSession, SyscallBuffering,
+        TraceUuid,
     },
     sig,
     sig::Sig,
     ticks::Ticks,
+    trace::{
+        trace_codec::TraceCodec,
+        trace_dir::discard_trace_data,
+        trace_splice::{retain_failed_processes, Retained},
+    },
     util::{check_for_leaks, page_size, running_under_rd, write_all, BindCPU},
     wait_status::{WaitStatus, WaitType},
 };
-use libc::{prctl, PR_SET_DUMPABLE, STDERR_FILENO};
+use libc::{pid_t, prctl, PR_SET_DUMPABLE, STDERR_FILENO};
 use nix::{
     sys::signal::{kill, sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal},
     unistd::{geteuid, getpid, Uid},
@@ -31,6 +41,22 @@ use std::{
     sync::atomic::{AtomicBool, Ordering},
 };
 
+/// Which recordings `rd record --record-children` keeps the data of.
+///
+/// A failed process is replayed along with its ancestors, so those keep their
+/// data too (see `trace_splice`). Discarded data leaves the process tree and
+/// exit statuses behind.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordChildrenPolicy {
+    /// Always discard the data.
+    None,
+    /// Keep the data of the processes that exited with a non-zero status or
+    /// were killed by a signal, and of their ancestors.
+    Failed,
+    /// Always keep the data.
+    All,
+}
+
 /// DIFF NOTE: Many struct members are Option<> when compared to rr equivalents.
 pub struct RecordCommand {
     pub extra_env: Vec<(OsString, OsString)>,
@@ -64,6 +90,33 @@ pub struct RecordCommand {
     /// Whether to use read-cloning optimization during recording.
     pub use_read_cloning: bool,
 
+    /// Whether to disable address space layout randomization in the tracees.
+    pub disable_aslr: bool,
+
+    /// Libraries, by path, file name or `buildid:<hex>`, whose syscalls should
+    /// never be patched to use the syscall buffer.
+    pub no_patch_libraries: Vec<String>,
+
+    /// Whether to hardlink copied executables and libraries from a store shared
+    /// between traces instead of copying them into each trace.
+    pub share_copied_files: bool,
+
+    /// Whether to copy every mapped file that can't be cloned into the trace,
+    /// so that replay never reads files outside the trace.
+    pub isolate_file_access: bool,
+
+    /// Whether to plant breakpoints at SDT probes and record their hits.
+    pub stap_sdt: bool,
+
+    /// Whether io_uring is disabled for the tracees or stops the recording.
+    pub io_uring_policy: IoUringPolicy,
+
+    /// Whether to keep the trace data once recording is done.
+    pub record_children: RecordChildrenPolicy,
+
+    /// Codec the trace's blocks are compressed with.
+    pub compression: TraceCodec,
+
     /// Whether tracee processes in record and replay are allowed to run on any logical CPU.
     pub bind_cpu: BindCPU,
 
@@ -73,6 +126,15 @@ pub struct RecordCommand {
     /// Whether to enable chaos mode in the scheduler
     pub chaos: bool,
 
+    /// Syscalls to fail artificially during recording
+    pub inject_errors: Vec<InjectErrorSpec>,
+
+    /// Seed for the data returned by getrandom() and /dev/(u)random reads
+    pub seed: Option<u64>,
+
+    /// Spoofed contents of cgroup interface files
+    pub cgroup_limits: Vec<CgroupLimit>,
+
     /// Controls number of cores reported to recorded process.
     pub num_cores: Option<u32>,
 
@@ -94,6 +156,9 @@ pub struct RecordCommand {
     /// The signal to use for syscallbuf desched events
     pub syscallbuf_desched_sig: Sig,
 
+    /// The process to attach to, if any, instead of spawning `args`
+    pub attach: Option<pid_t>,
+
     // The exe and exe_args
     pub args: Vec<OsString>,
 }
@@ -116,18 +181,32 @@ impl RecordCommand {
     pub fn new(options: &RdOptions) -> RecordCommand {
         match options.cmd.clone() {
             RdSubCommand::Record {
+                attach,
                 exe,
                 exe_args,
                 force_syscall_buffer,
                 num_cpu_ticks,
+                switch_interval,
                 disable_cpuid_features,
                 disable_cpuid_features_ext,
                 disable_cpuid_features_xsave,
                 chaos_mode,
+                inject_errors,
+                seed,
+                cgroup_limits,
                 ignore_signal,
                 no_syscall_buffer,
                 no_file_cloning,
                 no_read_cloning,
+                no_aslr,
+                aslr,
+                no_patch_library,
+                share_copied_files,
+                isolate_file_access,
+                stap_sdt,
+                io_uring,
+                record_children,
+                compression,
                 num_cores,
                 output_trace_dir,
                 print_trace_dir_fd,
@@ -146,7 +225,14 @@ impl RecordCommand {
                 copy_preload_src,
             } => RecordCommand {
                 extra_env: env.unwrap_or(Vec::new()),
-                max_ticks: num_cpu_ticks.unwrap_or(TicksHowMany::DefaultMaxTicks as u64),
+                max_ticks: {
+                    if num_cpu_ticks.is_some() && switch_interval.is_some() {
+                        log!(LogWarn, "--num-cpu-ticks and --switch-interval are contradictory. Giving preference to --num-cpu-ticks");
+                    }
+                    num_cpu_ticks
+                        .or(switch_interval)
+                        .unwrap_or(TicksHowMany::DefaultMaxTicks as u64)
+                },
                 ignore_sig: ignore_signal,
                 continue_through_sig: continue_through_signal,
                 // Generally speaking the `force_syscall_buffer` and the `no_syscall_buffer`
@@ -165,15 +251,29 @@ impl RecordCommand {
                     }
                 },
                 syscall_buffer_size: syscall_buffer_size.unwrap_or(1024 * 1024),
-                disable_cpuid_features: DisableCPUIDFeatures::from(
-                    disable_cpuid_features.unwrap_or((0, 0)),
-                    disable_cpuid_features_ext.unwrap_or((0, 0, 0)),
-                    disable_cpuid_features_xsave.unwrap_or(0),
-                ),
+                disable_cpuid_features: {
+                    let mut features = DisableCPUIDFeatures::from(
+                        (0, 0),
+                        disable_cpuid_features_ext.unwrap_or((0, 0, 0)),
+                        disable_cpuid_features_xsave.unwrap_or(0),
+                    );
+                    if let Some(more) = disable_cpuid_features {
+                        features.extend(&more);
+                    }
+                    features
+                },
                 print_trace_dir_fd,
                 output_trace_dir,
                 use_file_cloning: !no_file_cloning,
                 use_read_cloning: !no_read_cloning,
+                disable_aslr: no_aslr && !aslr,
+                no_patch_libraries: no_patch_library,
+                share_copied_files,
+                isolate_file_access,
+                stap_sdt,
+                io_uring_policy: io_uring,
+                record_children,
+                compression,
                 // Generally speaking the `cpu_unbound` and `bind_to_cpu` options
                 // are contradictory and an error should result if both options were
                 // used on the commandline. For now we give priority to `bind_to_cpu`.
@@ -199,6 +299,9 @@ impl RecordCommand {
                     }
                     chaos_mode
                 },
+                inject_errors,
+                seed,
+                cgroup_limits,
                 num_cores,
                 wait_for_all: wait,
                 ignore_nested: ignore_error,
@@ -207,8 +310,9 @@ impl RecordCommand {
                 trace_id: Box::new(trace_id.unwrap_or(TraceUuid::generate_new())),
                 copy_preload_src,
                 syscallbuf_desched_sig: syscall_buffer_sig.unwrap_or(sig::SIGPWR),
+                attach,
                 args: {
-                    let mut args = vec![exe];
+                    let mut args: Vec<OsString> = exe.into_iter().collect();
                     args.extend(exe_args);
                     args
                 },
@@ -224,8 +328,15 @@ impl RecordCommand {
     fn record(&self) -> WaitStatus {
         log!(LogInfo, "Start recording...");
 
-        let session = RecordSession::create(self);
+        let session = match self.attach {
+            Some(pid) => RecordSession::attach(self, pid),
+            None => RecordSession::create(self),
+        };
         let rec_session = session.as_record().unwrap();
+        if rec_session.done_initial_exec() {
+            // An attached process starts out in the state an exec would leave it in.
+            rec_session.trace_writer().make_latest_trace();
+        }
 
         match self.print_trace_dir_fd {
             Some(fd) => {
@@ -244,7 +355,7 @@ impl RecordCommand {
 
         // Install signal handlers after creating the session, to ensure they're not
         // inherited by the tracee.
-        install_signal_handlers();
+        install_signal_handlers(self.attach.is_some());
 
         let mut step_result: RecordResult;
         loop {
@@ -259,8 +370,18 @@ impl RecordCommand {
         }
 
         rec_session.terminate_recording();
+        let trace_dir = rec_session.trace_writer().dir();
+
+        for &(tgid, sig, event) in rec_session.core_dump_deaths().iter() {
+            eprintln!(
+                "rd: Process {} was killed by core-dumping signal {} at event {}. Its core dump \
+                 was suppressed.\n    Debug it with `rd replay -g {}`, where gdb's `gcore` \
+                 can write one.",
+                tgid, sig, event, event
+            );
+        }
 
-        match step_result {
+        let status = match step_result {
             RecordResult::StepContinue => {
                 // SIGTERM interrupted us.
                 WaitStatus::for_fatal_sig(sig::SIGTERM)
@@ -271,23 +392,66 @@ impl RecordCommand {
                 eprintln!("\n{:?}", message);
                 WaitStatus::for_exit_code(EX_UNAVAILABLE as i32)
             }
+        };
+
+        match self.record_children {
+            RecordChildrenPolicy::All => (),
+            RecordChildrenPolicy::Failed => {
+                match retain_failed_processes(
+                    &trace_dir,
+                    status.exit_code() != Some(0),
+                    self.compression,
+                ) {
+                    Ok(Retained::Nothing) => discard_data(&trace_dir),
+                    Ok(Retained::Everything) | Ok(Retained::Failed { .. }) => (),
+                    // The substreams are only replaced once they've all been
+                    // rewritten, so an error usually leaves the trace as it was.
+                    Err(e) => eprintln!(
+                        "rd: Failed to drop the data of the processes of trace {:?} that \
+                         didn't fail: {}",
+                        trace_dir, e
+                    ),
+                }
+            }
+            RecordChildrenPolicy::None => discard_data(&trace_dir),
         }
+
+        status
+    }
+}
+
+fn discard_data(trace_dir: &OsStr) {
+    log!(LogInfo, "Discarding the data of trace {:?}", trace_dir);
+    if let Err(e) = discard_trace_data(trace_dir) {
+        eprintln!(
+            "rd: Failed to discard the data of trace {:?}: {}",
+            trace_dir, e
+        );
     }
 }
 
+/// An attached process usually isn't in our process group, so a ^C at the
+/// terminal wouldn't reach it and end the recording. When `attached`, SIGINT
+/// stops the recording like SIGTERM does.
+///
 /// DIFF NOTE: In rr the success of sigaction() is not checked. In rd, we do an unwrap().
-fn install_signal_handlers() {
+fn install_signal_handlers(attached: bool) {
     let sa = SigAction::new(
         SigHandler::Handler(handle_SIGTERM),
         SaFlags::empty(),
         SigSet::empty(),
     );
     unsafe { sigaction(Signal::SIGTERM, &sa) }.unwrap();
+    if attached {
+        unsafe { sigaction(Signal::SIGINT, &sa) }.unwrap();
+    }
 
     let sa = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
     unsafe {
         sigaction(Signal::SIGHUP, &sa).unwrap();
-        sigaction(Signal::SIGINT, &sa).unwrap();
+        if !attached {
+            sigaction(Signal::SIGINT, &sa).unwrap();
+        }
         sigaction(Signal::SIGABRT, &sa).unwrap();
         sigaction(Signal::SIGQUIT, &sa).unwrap();
     }
@@ -321,6 +485,54 @@ impl RdCommand for RecordCommand {
             );
         }
 
+        if pmu_disabled() {
+            eprintln!(
+                "rd: Recording without perf counters (--no-pmu). Tasks that may need to be\n\
+                 preempted or interrupted by a signal handler are singlestepped, which is slow."
+            );
+            if self.use_syscall_buffer == SyscallBuffering::EnableSycallBuf {
+                log!(
+                    LogInfo,
+                    "Disabling syscall buffering because the PMU is disabled"
+                );
+                self.use_syscall_buffer = SyscallBuffering::DisableSyscallBuf;
+            }
+            // Switch tasks at every opportunity since time slices never expire.
+            self.always_switch = true;
+        }
+
+        if !self.inject_errors.is_empty()
+            && self.use_syscall_buffer == SyscallBuffering::EnableSycallBuf
+        {
+            // Buffered syscalls never stop for ptrace so we couldn't count or fail them.
+            log!(
+                LogInfo,
+                "Disabling syscall buffering because --inject-errors is used"
+            );
+            self.use_syscall_buffer = SyscallBuffering::DisableSyscallBuf;
+        }
+
+        if self.attach.is_some() {
+            if self.use_syscall_buffer == SyscallBuffering::EnableSycallBuf {
+                // The preload library can't be injected into a process that has
+                // already started.
+                log!(
+                    LogInfo,
+                    "Disabling syscall buffering because --attach is used"
+                );
+                self.use_syscall_buffer = SyscallBuffering::DisableSyscallBuf;
+            }
+            if self.disable_aslr {
+                log!(
+                    LogWarn,
+                    "--no-aslr has no effect with --attach: the process is already laid out"
+                );
+                self.disable_aslr = false;
+            }
+        }
+
+        Environment::get().check_for_record(self.isolate_file_access);
+
         assert_prerequisites(Some(match self.use_syscall_buffer {
             SyscallBuffering::EnableSycallBuf => true,
             SyscallBuffering::DisableSyscallBuf => false,
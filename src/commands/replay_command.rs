@@ -2,9 +2,12 @@ use crate::{
     assert_prerequisites,
     bindings::kernel::{gettimeofday, timeval},
     commands::{gdb_server, RdCommand},
+    divergence_repair::BenignDivergences,
     flags::Flags,
     kernel_metadata::errno_name,
     log::{LogDebug, LogInfo},
+    replay_timeline::PeriodicCheckpoints,
+    sandbox::enter_replay_sandbox,
     scoped_fd::ScopedFd,
     session::{
         replay_session,
@@ -12,7 +15,11 @@ use crate::{
         SessionSharedPtr,
     },
     trace::{
-        trace_frame::FrameTime, trace_reader::TraceReader, trace_task_event::TraceTaskEventType,
+        trace_checksum::{check_manifest, load_key, ManifestCheck},
+        trace_dir::TraceDir,
+        trace_frame::FrameTime,
+        trace_reader::TraceReader,
+        trace_task_event::TraceTaskEventType,
     },
     util::{check_for_leaks, find, running_under_rd},
 };
@@ -96,9 +103,21 @@ pub struct ReplayCommand {
     /// to test the corresponding code.
     share_private_mappings: bool,
 
+    /// Divergences from the recording to patch over instead of aborting.
+    repair_divergences: BenignDivergences,
+
+    /// Only replay this recorded process once it has been created.
+    only_process: Option<pid_t>,
+
     /// When Some(_), display statistics every N steps.
     dump_interval: Option<u32>,
 
+    /// Checkpoints to take while the user debugs forward.
+    periodic_checkpoints: PeriodicCheckpoints,
+
+    /// Replay inside the sandbox from `sandbox.rs`.
+    sandbox: bool,
+
     trace_dir: Option<PathBuf>,
 }
 
@@ -118,7 +137,11 @@ impl Default for ReplayCommand {
             redirect: true,
             cpu_unbound: false,
             share_private_mappings: false,
+            repair_divergences: BenignDivergences::empty(),
+            only_process: None,
             dump_interval: None,
+            periodic_checkpoints: Default::default(),
+            sandbox: false,
             gdb_options: vec![],
             trace_dir: None,
         }
@@ -142,12 +165,17 @@ impl ReplayCommand {
                 dbghost,
                 dbgport,
                 keep_listening,
+                sandbox,
                 trace_event,
                 cpu_unbound,
                 gdb_x_file,
                 stats,
+                checkpoint_interval,
+                checkpoint_memory_budget,
                 trace_dir,
                 share_private_mappings,
+                repair_divergence,
+                only_process,
             } => {
                 let mut flags = ReplayCommand::default();
 
@@ -170,6 +198,7 @@ impl ReplayCommand {
                 }
 
                 flags.keep_listening = keep_listening;
+                flags.sandbox = sandbox;
                 if let Some(opt) = debugger_option {
                     flags.gdb_options.push(opt);
                 }
@@ -221,8 +250,19 @@ impl ReplayCommand {
                     flags.dump_interval = stats;
                 }
 
+                flags.periodic_checkpoints = PeriodicCheckpoints {
+                    interval: checkpoint_interval,
+                    memory_budget: checkpoint_memory_budget.map(|mb| mb * 1024 * 1024),
+                };
+
                 flags.cpu_unbound = cpu_unbound;
 
+                flags.repair_divergences = repair_divergence
+                    .iter()
+                    .fold(BenignDivergences::empty(), |acc, &d| acc | d);
+
+                flags.only_process = only_process;
+
                 if let Some(inter) = interpreter {
                     flags.gdb_options.push("-i".into());
                     flags.gdb_options.push(OsString::from(inter));
@@ -241,6 +281,8 @@ impl ReplayCommand {
             redirect_stdio: self.redirect,
             share_private_mappings: self.share_private_mappings,
             cpu_unbound: self.cpu_unbound,
+            repair_divergences: self.repair_divergences,
+            only_process: self.only_process,
         }
     }
 
@@ -342,7 +384,11 @@ impl ReplayCommand {
                     debugger_params_write_pipe: None,
                     debugger_name: self.gdb_binary_file_path.clone(),
                 };
-                GdbServer::new(session, &target).serve_replay(&conn_flags);
+                let mut server = GdbServer::new(session, &target);
+                server
+                    .timeline_unwrap_mut()
+                    .set_periodic_checkpoints(self.periodic_checkpoints);
+                server.serve_replay(&conn_flags);
             }
 
             check_for_leaks();
@@ -384,6 +430,9 @@ impl ReplayCommand {
                         debugger_name: self.gdb_binary_file_path.clone(),
                     };
                     let mut server = GdbServer::new(session, &target);
+                    server
+                        .timeline_unwrap_mut()
+                        .set_periodic_checkpoints(self.periodic_checkpoints);
                     let sa = SigAction::new(
                         SigHandler::Handler(handle_sigint_in_child),
                         SaFlags::SA_RESTART,
@@ -554,6 +603,47 @@ impl RdCommand for ReplayCommand {
             );
         }
 
+        // Quick integrity check, if `rd checksum --verify` has made a manifest.
+        // Without the key the manifest was signed with, only the sizes of the
+        // files can be checked.
+        let trace_dir = TraceDir::from_env().resolve(self.trace_dir.as_ref());
+        let key = load_key(None, false).ok().flatten();
+        match check_manifest(&trace_dir, key.as_deref()) {
+            Ok(ManifestCheck::OtherKey) if !Flags::get().suppress_environment_warnings => {
+                eprintln!(
+                    "rd: The checksum manifest of {:?} was signed with another key, e.g. on \
+                     another machine, so only the sizes of the trace's files were checked.",
+                    trace_dir
+                );
+            }
+            Err(e) if !Flags::get().suppress_environment_warnings => {
+                eprintln!(
+                    "rd: {}\n\
+                     The trace may be damaged and replay may fail. \
+                     Run `rd checksum --verify` to check every block.",
+                    e
+                );
+            }
+            _ => (),
+        }
+
+        if self.sandbox {
+            // The debugger would have to reach us from outside the sandbox's
+            // network namespace.
+            if self.dbg_port.is_some() || self.dbg_host != ReplayCommand::default().dbg_host {
+                return ExitResult::err_from(
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Cannot use --sandbox with --dbgport (-s) or --dbghost (-h).",
+                    ),
+                    4,
+                );
+            }
+            if let Err(e) = enter_replay_sandbox(&trace_dir) {
+                return ExitResult::err_from(e, 1);
+            }
+        }
+
         self.replay()
     }
 }
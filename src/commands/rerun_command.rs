@@ -10,6 +10,8 @@ use crate::{
         rd_options::{RdOptions, RdSubCommand},
         RdCommand,
     },
+    condition_expression::ConditionExpression,
+    divergence_repair::BenignDivergences,
     event::{Event, EventType},
     flags::Flags,
     gdb_register::{DREG_64_XMM0, DREG_64_YMM0H, DREG_XMM0, DREG_YMM0H},
@@ -242,6 +244,7 @@ pub struct ReRunCommand {
     trace_end: FrameTime,
     function: Option<RemoteCodePtr>,
     singlestep_trace: Vec<TraceField>,
+    condition: Option<ConditionExpression>,
     raw_dump: bool,
     cpu_unbound: bool,
     trace_dir: Option<PathBuf>,
@@ -286,27 +289,34 @@ pub(super) fn parse_regs(regs_s: &str) -> Result<TraceFields, clap::Error> {
                 kind: TraceFieldKind::TraceOrigAx,
                 reg_num: 0,
             });
-        } else if reg == "gp_x16" {
+        } else if reg == "gp_x16" || reg == "gp" {
             for i in 0u8..16 {
                 registers.push(TraceField {
                     kind: TraceFieldKind::TraceGpReg,
                     reg_num: i,
                 });
             }
-        } else if reg == "xmm_x16" {
+        } else if reg == "xmm_x16" || reg == "xmm" {
             for i in 0u8..16 {
                 registers.push(TraceField {
                     kind: TraceFieldKind::TraceXmmReg,
                     reg_num: i,
                 });
             }
-        } else if reg == "ymm_x16" {
+        } else if reg == "ymm_x16" || reg == "ymm" {
             for i in 0u8..16 {
                 registers.push(TraceField {
                     kind: TraceFieldKind::TraceYmmReg,
                     reg_num: i,
                 });
             }
+        } else if reg == "seg" {
+            for i in 0u8..6 {
+                registers.push(TraceField {
+                    kind: TraceFieldKind::TraceSegReg,
+                    reg_num: i,
+                });
+            }
         } else if let Some(i) = find_gp_reg(reg) {
             registers.push(TraceField {
                 kind: TraceFieldKind::TraceGpReg,
@@ -343,12 +353,14 @@ impl ReRunCommand {
                 cpu_unbound,
                 function_addr,
                 singlestep_regs,
+                condition,
                 trace_dir,
             } => ReRunCommand {
                 trace_start: trace_start.unwrap_or(FrameTime::MIN),
                 trace_end: trace_end.unwrap_or(FrameTime::MAX),
                 function: function_addr.map(|a| a.into()),
                 singlestep_trace: singlestep_regs.map_or(Vec::new(), |r| r.0),
+                condition,
                 raw_dump: raw,
                 cpu_unbound,
                 trace_dir,
@@ -362,6 +374,8 @@ impl ReRunCommand {
             redirect_stdio: false,
             share_private_mappings: false,
             cpu_unbound: self.cpu_unbound,
+            repair_divergences: BenignDivergences::empty(),
+            only_process: None,
         }
     }
 
@@ -534,6 +548,13 @@ impl ReRunCommand {
         instruction_count: u64,
         out: &mut dyn Write,
     ) -> io::Result<()> {
+        if let Some(condition) = &self.condition {
+            // A condition that can't be evaluated (e.g. it reads unmapped
+            // memory) doesn't hold.
+            if !matches!(condition.evaluate(t, t.arch()), Ok(v) if v != 0) {
+                return Ok(());
+            }
+        }
         let mut got_gp_regs = false;
         let mut gp_regs: RegsData = unsafe { mem::zeroed() };
         let mut first = true;
@@ -0,0 +1,37 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::trace_dir::TraceDir,
+};
+use std::path::PathBuf;
+
+pub struct RmCommand {
+    trace_dir: PathBuf,
+}
+
+impl RmCommand {
+    pub fn new(options: &RdOptions) -> RmCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Rm { trace_dir } => RmCommand { trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Rm` variant!"),
+        }
+    }
+}
+
+impl RdCommand for RmCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        let traces = TraceDir::from_env();
+        let trace_dir = traces.resolve(Some(&self.trace_dir));
+        match traces.remove_trace(&trace_dir) {
+            Ok(0) => ExitResult::Ok(()),
+            Ok(removed) => {
+                println!("Deleted {} unused shared object(s)", removed);
+                ExitResult::Ok(())
+            }
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    ticks::Ticks,
+    trace::{
+        trace_frame::{FrameTime, TraceFrame},
+        trace_reader::TraceReader,
+    },
+};
+use libc::pid_t;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io,
+    io::{stdout, Write},
+    path::PathBuf,
+};
+
+/// Reconstructs the scheduling history of a trace.
+///
+/// Whenever the recorder's scheduler switches from one task to another it
+/// stores the switch, with the reason it stopped running the first task, in
+/// the next trace frame. Each slice runs from one switch to the next.
+pub struct SchedCommand {
+    json: bool,
+    only_tid: Option<pid_t>,
+    trace_dir: Option<PathBuf>,
+}
+
+impl SchedCommand {
+    pub fn new(options: &RdOptions) -> SchedCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Sched {
+                json,
+                only_tid,
+                trace_dir,
+            } => SchedCommand {
+                json,
+                only_tid,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Sched` variant!"),
+        }
+    }
+}
+
+impl RdCommand for SchedCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.sched(&mut stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+/// The trace events between two recorded task switches, while `tid` was the
+/// task the scheduler ran. Events recorded for other tasks in that time (e.g.
+/// a signal delivered to, or the exit of, a task that isn't running) don't
+/// end the slice and are included in it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SchedSlice {
+    tid: pid_t,
+    /// Global time of the first event in this slice
+    start_event: FrameTime,
+    /// Global time of the last event in this slice
+    end_event: FrameTime,
+    /// Number of events in this slice, of any task
+    events: u64,
+    /// Ticks executed by the task during this slice
+    ticks: Ticks,
+    /// Why the task stopped running at the end of this slice
+    reason: String,
+}
+
+impl SchedCommand {
+    fn sched(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut trace = TraceReader::new(self.trace_dir.as_ref());
+        if !trace.switches_recorded() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} was recorded by a version of rd that didn't store task switches",
+                    trace.dir()
+                ),
+            ));
+        }
+        let mut slices = sched_slices(std::iter::from_fn(|| {
+            if trace.at_end() {
+                None
+            } else {
+                Some(trace.read_frame())
+            }
+        }));
+
+        if let Some(tid) = self.only_tid {
+            slices.retain(|s| s.tid == tid);
+        }
+
+        if self.json {
+            let serialized = serde_json::to_string(&slices).unwrap();
+            writeln!(out, "{}", serialized)?;
+            return Ok(());
+        }
+
+        writeln!(out, "START\tEND\tTID\tEVENTS\tTICKS\tREASON")?;
+        for s in &slices {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                s.start_event, s.end_event, s.tid, s.events, s.ticks, s.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Split `frames` into the runs of events between task switches.
+fn sched_slices(frames: impl Iterator<Item = TraceFrame>) -> Vec<SchedSlice> {
+    let mut slices: Vec<SchedSlice> = Vec::new();
+    // Latest tick count of each task
+    let mut ticks: HashMap<pid_t, Ticks> = HashMap::new();
+    // The current slice, and its task's tick count when it started
+    let mut current: Option<(SchedSlice, Ticks)> = None;
+
+    for frame in frames {
+        for switch in frame.switches() {
+            if let Some((slice, start_ticks)) = current.take() {
+                slices.push(finish_slice(
+                    slice,
+                    start_ticks,
+                    &ticks,
+                    switch.reason.to_string(),
+                ));
+            }
+            current = Some(start_slice(switch.to_tid, frame.time(), &ticks));
+        }
+        ticks.insert(frame.tid(), frame.ticks());
+
+        let (slice, _) =
+            current.get_or_insert_with(|| start_slice(frame.tid(), frame.time(), &ticks));
+        slice.end_event = frame.time();
+        slice.events += 1;
+    }
+
+    if let Some((slice, start_ticks)) = current.take() {
+        slices.push(finish_slice(
+            slice,
+            start_ticks,
+            &ticks,
+            "end-of-trace".into(),
+        ));
+    }
+    slices
+}
+
+fn start_slice(tid: pid_t, time: FrameTime, ticks: &HashMap<pid_t, Ticks>) -> (SchedSlice, Ticks) {
+    let slice = SchedSlice {
+        tid,
+        start_event: time,
+        end_event: time,
+        events: 0,
+        ticks: 0,
+        reason: String::new(),
+    };
+    (slice, ticks.get(&tid).copied().unwrap_or(0))
+}
+
+fn finish_slice(
+    mut slice: SchedSlice,
+    start_ticks: Ticks,
+    ticks: &HashMap<pid_t, Ticks>,
+    reason: String,
+) -> SchedSlice {
+    let end_ticks = ticks.get(&slice.tid).copied().unwrap_or(0);
+    slice.ticks = end_ticks.saturating_sub(start_ticks);
+    slice.reason = reason;
+    slice
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        event::Event,
+        scheduler::{SwitchReason, TaskSwitch},
+    };
+
+    fn frame(time: FrameTime, tid: pid_t, ticks: Ticks) -> TraceFrame {
+        TraceFrame::new_with(time, tid, Event::sched(), ticks, 0.0, 0.0)
+    }
+
+    fn switched(mut frame: TraceFrame, from_tid: pid_t, reason: SwitchReason) -> TraceFrame {
+        let to_tid = frame.tid();
+        frame.set_switches(vec![TaskSwitch {
+            from_tid,
+            to_tid,
+            reason,
+        }]);
+        frame
+    }
+
+    #[test]
+    fn slices_follow_task_switches() {
+        let slices = sched_slices(
+            vec![
+                frame(1, 10, 100),
+                frame(2, 10, 250),
+                switched(frame(3, 11, 40), 10, SwitchReason::TimesliceExpired),
+                switched(frame(4, 10, 300), 11, SwitchReason::Blocked),
+                // An event recorded for another task doesn't end the slice.
+                frame(5, 11, 40),
+                frame(6, 10, 320),
+                switched(frame(7, 11, 90), 10, SwitchReason::Exited),
+            ]
+            .into_iter(),
+        );
+        let summary: Vec<_> = slices
+            .iter()
+            .map(|s| (s.tid, s.start_event, s.end_event, s.events, s.ticks))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (10, 1, 2, 2, 250),
+                (11, 3, 3, 1, 40),
+                (10, 4, 6, 3, 70),
+                (11, 7, 7, 1, 50)
+            ]
+        );
+        let reasons: Vec<_> = slices.iter().map(|s| s.reason.as_str()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "preempted (tick limit)",
+                "blocked",
+                "exited",
+                "end-of-trace"
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_trace_has_no_slices() {
+        assert!(sched_slices(std::iter::empty()).is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    event::{EventType, SyscallState},
+    ticks::Ticks,
+    trace::{
+        trace_reader::{TraceReader, ValidateSourceFile},
+        trace_stream::{MappedData, MappedDataSource},
+    },
+};
+use libc::pid_t;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io,
+    io::{stdout, Write},
+    path::PathBuf,
+};
+
+/// Summarizes a trace in a single streaming pass over its frames, mmaps and
+/// raw data records. No session is created and no tracee runs.
+pub struct StatsCommand {
+    json: bool,
+    trace_dir: Option<PathBuf>,
+}
+
+impl StatsCommand {
+    pub fn new(options: &RdOptions) -> StatsCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Stats { json, trace_dir } => StatsCommand { json, trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Stats` variant!"),
+        }
+    }
+}
+
+impl RdCommand for StatsCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.stats(&mut stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceStats {
+    events: u64,
+    /// Wall-clock time between the first and last event during recording
+    duration_secs: f64,
+    events_by_type: BTreeMap<String, u64>,
+    /// Number of completed calls of each syscall
+    syscalls: BTreeMap<String, u64>,
+    /// Number of hits of each SDT probe, as `provider:name`
+    sdt_probe_hits: BTreeMap<String, u64>,
+    /// Bytes of memory contents saved in the trace, by recorded tid
+    recorded_bytes_by_tid: BTreeMap<pid_t, u64>,
+    /// Bytes saved for unbuffered reads from each fd, keyed by `tid:fd`.
+    /// Buffered syscalls don't record their arguments, so data read through
+    /// the syscallbuf is only counted in `recorded_bytes_by_tid`.
+    recorded_bytes_by_fd: BTreeMap<String, u64>,
+    /// Ticks executed by each task over the whole recording
+    ticks_by_tid: BTreeMap<pid_t, Ticks>,
+    /// Number of mappings, by where replay gets their contents from
+    mmaps_by_source: BTreeMap<String, u64>,
+    /// Bytes of mapped memory saved in the trace itself
+    mmap_trace_bytes: u64,
+    /// The same, by mapped file (or `[anonymous]` etc.)
+    mmap_trace_bytes_by_file: BTreeMap<String, u64>,
+}
+
+impl StatsCommand {
+    fn stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut trace = TraceReader::new(self.trace_dir.as_ref());
+        let mut stats = TraceStats::default();
+        let mut first_time: Option<f64> = None;
+        let mut last_time = 0.0;
+
+        while !trace.at_end() {
+            let frame = trace.read_frame();
+            stats.events += 1;
+            first_time.get_or_insert(frame.monotonic_time());
+            last_time = frame.monotonic_time();
+
+            let ev = frame.event();
+            *stats
+                .events_by_type
+                .entry(ev.event_type().to_string())
+                .or_insert(0) += 1;
+            // The fd whose data any raw data of this frame was read from.
+            let mut read_fd = None;
+            if ev.event_type() == EventType::EvSyscall
+                && ev.syscall_event().state == SyscallState::ExitingSyscall
+            {
+                let name = ev.syscall_event().syscall_name();
+                if reads_from_fd(&name) {
+                    read_fd = Some(frame.regs_ref().arg1() as i32);
+                }
+                *stats.syscalls.entry(name).or_insert(0) += 1;
+            }
+            if ev.event_type() == EventType::EvSdtProbe {
+                let probe = ev.sdt_probe_event();
+                *stats
+                    .sdt_probe_hits
+                    .entry(format!("{}:{}", probe.provider, probe.name))
+                    .or_insert(0) += 1;
+            }
+            // Frame ticks are the task's running total.
+            stats.ticks_by_tid.insert(frame.tid(), frame.ticks());
+
+            loop {
+                let mut data = MappedData::default();
+                let maybe_km = trace.read_mapped_region(
+                    Some(&mut data),
+                    Some(ValidateSourceFile::DontValidate),
+                    None,
+                    None,
+                    None,
+                );
+                let km = match maybe_km {
+                    Some(km) => km,
+                    None => break,
+                };
+                let source = match data.source {
+                    MappedDataSource::SourceTrace => {
+                        stats.mmap_trace_bytes += km.size() as u64;
+                        *stats
+                            .mmap_trace_bytes_by_file
+                            .entry(km.fsname().to_string_lossy().into_owned())
+                            .or_insert(0) += km.size() as u64;
+                        "trace"
+                    }
+                    MappedDataSource::SourceFile => "file",
+                    MappedDataSource::SourceZero => "zero",
+                };
+                *stats.mmaps_by_source.entry(source.into()).or_insert(0) += 1;
+            }
+
+            while let Some(data) = trace.read_raw_data_metadata_for_frame() {
+                *stats.recorded_bytes_by_tid.entry(data.rec_tid).or_insert(0) += data.size as u64;
+                if let Some(fd) = read_fd {
+                    *stats
+                        .recorded_bytes_by_fd
+                        .entry(format!("{}:{}", data.rec_tid, fd))
+                        .or_insert(0) += data.size as u64;
+                }
+            }
+        }
+        stats.duration_secs = last_time - first_time.unwrap_or(last_time);
+
+        if self.json {
+            let serialized = serde_json::to_string(&stats).unwrap();
+            writeln!(out, "{}", serialized)?;
+            return Ok(());
+        }
+
+        writeln!(out, "Events: {}", stats.events)?;
+        writeln!(out, "Duration: {:.3}s", stats.duration_secs)?;
+        writeln!(
+            out,
+            "Recorded bytes: {}",
+            stats.recorded_bytes_by_tid.values().sum::<u64>()
+        )?;
+        writeln!(
+            out,
+            "Mapped bytes saved in trace: {}",
+            stats.mmap_trace_bytes
+        )?;
+
+        writeln!(out, "\nEVENT TYPE\tCOUNT")?;
+        for (name, count) in sorted_by_count(&stats.events_by_type) {
+            writeln!(out, "{}\t{}", name, count)?;
+        }
+        writeln!(out, "\nSYSCALL\tCOUNT")?;
+        for (name, count) in sorted_by_count(&stats.syscalls) {
+            writeln!(out, "{}\t{}", name, count)?;
+        }
+        if !stats.sdt_probe_hits.is_empty() {
+            writeln!(out, "\nSDT PROBE\tHITS")?;
+            for (name, count) in sorted_by_count(&stats.sdt_probe_hits) {
+                writeln!(out, "{}\t{}", name, count)?;
+            }
+        }
+        writeln!(out, "\nTID\tTICKS\tRECORDED BYTES")?;
+        for (tid, ticks) in &stats.ticks_by_tid {
+            let bytes = stats.recorded_bytes_by_tid.get(tid).copied().unwrap_or(0);
+            writeln!(out, "{}\t{}\t{}", tid, ticks, bytes)?;
+        }
+        if !stats.recorded_bytes_by_fd.is_empty() {
+            writeln!(out, "\nTID:FD\tBYTES READ")?;
+            for (fd, bytes) in sorted_by_count(&stats.recorded_bytes_by_fd) {
+                writeln!(out, "{}\t{}", fd, bytes)?;
+            }
+        }
+        writeln!(out, "\nMMAP SOURCE\tCOUNT")?;
+        for (source, count) in &stats.mmaps_by_source {
+            writeln!(out, "{}\t{}", source, count)?;
+        }
+        if !stats.mmap_trace_bytes_by_file.is_empty() {
+            writeln!(out, "\nMAPPED FILE\tBYTES SAVED IN TRACE")?;
+            for (file, bytes) in sorted_by_count(&stats.mmap_trace_bytes_by_file) {
+                writeln!(out, "{}\t{}", file, bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Most frequent first.
+fn sorted_by_count(counts: &BTreeMap<String, u64>) -> Vec<(&String, &u64)> {
+    let mut v: Vec<(&String, &u64)> = counts.iter().collect();
+    v.sort_by(|a, b| b.1.cmp(a.1));
+    v
+}
+
+/// Syscalls whose first argument is the fd the data they write to tracee
+/// memory comes from.
+fn reads_from_fd(syscall: &str) -> bool {
+    matches!(
+        syscall,
+        "read"
+            | "pread64"
+            | "readv"
+            | "preadv"
+            | "preadv2"
+            | "recvfrom"
+            | "recvmsg"
+            | "recvmmsg"
+            | "getdents"
+            | "getdents64"
+    )
+}
@@ -4,6 +4,7 @@ use crate::{
         rd_options::{RdOptions, RdSubCommand},
         RdCommand,
     },
+    divergence_repair::BenignDivergences,
     perf_counters::TicksSemantics,
     session::{
         replay_session::{Flags, ReplaySession, ReplayStatus},
@@ -35,6 +36,9 @@ struct TraceHeader {
     xcr0: u64,
     bind_to_cpu: i32,
     cpuid_faulting: bool,
+    aslr_disabled: bool,
+    attached: bool,
+    unpatched_libraries: Vec<String>,
     ticks_semantics: String,
     cpuid_records: Vec<[u32; 6]>,
     environ: Vec<String>,
@@ -48,6 +52,13 @@ impl RdCommand for TraceInfoCommand {
         let xcr0 = trace.xcr0();
         let bind_to_cpu = trace.bound_to_cpu();
         let cpuid_faulting = trace.uses_cpuid_faulting();
+        let aslr_disabled = trace.aslr_disabled();
+        let attached = trace.attached();
+        let unpatched_libraries: Vec<String> = trace
+            .unpatched_libraries()
+            .iter()
+            .map(|l| l.to_string_lossy().into_owned())
+            .collect();
         let ticks_semantics = match trace.ticks_semantics() {
             TicksSemantics::TicksRetiredConditionalBranches => "rcb".into(),
             TicksSemantics::TicksTakenBranches => "branches".into(),
@@ -60,27 +71,35 @@ impl RdCommand for TraceInfoCommand {
             ]);
         }
 
-        let flags = Flags {
-            redirect_stdio: false,
-            share_private_mappings: false,
-            cpu_unbound: true,
-        };
-        let session = ReplaySession::create(self.trace_dir.as_ref(), flags);
-        let replay_session = session.as_replay().unwrap();
-
-        let environ: Vec<CString>;
-        loop {
-            let result = replay_session.replay_step(RunCommand::RunContinue);
-            if replay_session.done_initial_exec() {
-                environ = read_env(&**replay_session.current_task().unwrap());
-                break;
-            }
+        // The environment is read off the initial stack after the exec. An
+        // attached process's stack has moved on since.
+        let mut environ: Vec<CString> = Vec::new();
+        if !attached {
+            let flags = Flags {
+                redirect_stdio: false,
+                share_private_mappings: false,
+                cpu_unbound: true,
+                repair_divergences: BenignDivergences::empty(),
+                only_process: None,
+            };
+            let session = ReplaySession::create(self.trace_dir.as_ref(), flags);
+            let replay_session = session.as_replay().unwrap();
+            loop {
+                let result = replay_session.replay_step(RunCommand::RunContinue);
+                if replay_session.done_initial_exec() {
+                    environ = read_env(&**replay_session.current_task().unwrap());
+                    break;
+                }
 
-            if result.status == ReplayStatus::ReplayExited {
-                return ExitResult::err_from(
-                    io::Error::new(io::ErrorKind::Other, "Replay finished before initial exec!"),
-                    1,
-                );
+                if result.status == ReplayStatus::ReplayExited {
+                    return ExitResult::err_from(
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            "Replay finished before initial exec!",
+                        ),
+                        1,
+                    );
+                }
             }
         }
         let environ_strings: Vec<String> = environ
@@ -92,6 +111,9 @@ impl RdCommand for TraceInfoCommand {
             xcr0,
             bind_to_cpu: bind_to_cpu.map_or(-1, |c| c.try_into().unwrap()),
             cpuid_faulting,
+            aslr_disabled,
+            attached,
+            unpatched_libraries,
             ticks_semantics,
             cpuid_records,
             environ: environ_strings,
@@ -0,0 +1,218 @@
+use super::exit_result::ExitResult;
+use crate::{
+    bindings::signal::siginfo_t,
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    event::{EventType, SignalResolvedDisposition},
+    kernel_metadata::{sicode_name, signal_name},
+    remote_ptr::RemotePtr,
+    replay::ReplaySessionBuilder,
+    session::{task::Task, Session},
+    trace::{
+        trace_dir::{trace_data_discarded, TraceDir},
+        trace_frame::{FrameTime, TraceFrame},
+        trace_reader::TraceReader,
+    },
+    unwind::symbolized_backtrace,
+};
+use libc::pid_t;
+use serde::Serialize;
+use std::{
+    ffi::OsStr,
+    io,
+    io::{stdout, Write},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
+
+/// Faults below this address are reported as null pointer dereferences.
+const NEAR_NULL_LIMIT: usize = 0x10000;
+
+/// Finds the crash in each of a set of traces and groups the traces that
+/// crashed the same way, so that one trace per group can be debugged.
+///
+/// A trace crashed if some task got a signal whose disposition was fatal. The
+/// first such signal is replayed to, and its signature is the signal, its
+/// si_code, what kind of memory a fault was in and the symbolized top frames
+/// of the stack. None of these depend on where ASLR put things, so traces of
+/// the same bug recorded on different runs get the same signature.
+pub struct TriageCommand {
+    frames: usize,
+    trace_dirs: Vec<PathBuf>,
+}
+
+impl TriageCommand {
+    pub fn new(options: &RdOptions) -> TriageCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Triage { frames, trace_dirs } => TriageCommand { frames, trace_dirs },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Triage` variant!"),
+        }
+    }
+}
+
+impl RdCommand for TriageCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.triage(&mut stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct CrashSignature {
+    signal: String,
+    code: String,
+    /// For faults: `near-null`, `unmapped`, `anonymous`, or the name of the
+    /// mapping (e.g. `[stack]` or a file) the faulting address was in.
+    fault_region: Option<String>,
+    frames: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Crash {
+    trace_dir: String,
+    event: FrameTime,
+    tid: pid_t,
+    fault_address: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CrashGroup {
+    signature: CrashSignature,
+    count: usize,
+    traces: Vec<Crash>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TriageReport {
+    /// Most common crash first.
+    groups: Vec<CrashGroup>,
+    no_crash: Vec<String>,
+    /// Traces that can't be replayed, e.g. because their data was discarded.
+    unavailable: Vec<String>,
+}
+
+impl TriageCommand {
+    fn triage(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut report = TriageReport {
+            groups: Vec::new(),
+            no_crash: Vec::new(),
+            unavailable: Vec::new(),
+        };
+        let trace_dir = TraceDir::from_env();
+        for dir in &self.trace_dirs {
+            let resolved = trace_dir.resolve(Some(dir));
+            let name = String::from_utf8_lossy(resolved.as_bytes()).into_owned();
+            if trace_data_discarded(&resolved) {
+                report.unavailable.push(name);
+                continue;
+            }
+            let frame = match find_fatal_signal(&resolved) {
+                Some(frame) => frame,
+                None => {
+                    report.no_crash.push(name);
+                    continue;
+                }
+            };
+            let (signature, fault_address) = match self.crash_signature(&resolved, &frame) {
+                Some(result) => result,
+                None => {
+                    report.unavailable.push(name);
+                    continue;
+                }
+            };
+            let crash = Crash {
+                trace_dir: name,
+                event: frame.time(),
+                tid: frame.tid(),
+                fault_address: fault_address.map(|addr| format!("{:#x}", addr)),
+            };
+            match report.groups.iter_mut().find(|g| g.signature == signature) {
+                Some(group) => {
+                    group.count += 1;
+                    group.traces.push(crash);
+                }
+                None => report.groups.push(CrashGroup {
+                    signature,
+                    count: 1,
+                    traces: vec![crash],
+                }),
+            }
+        }
+        report.groups.sort_by(|a, b| b.count.cmp(&a.count));
+
+        let serialized = serde_json::to_string_pretty(&report).unwrap();
+        writeln!(out, "{}", serialized)
+    }
+
+    /// Replay to `frame` and work out the signature of the crash there.
+    fn crash_signature(
+        &self,
+        trace_dir: &OsStr,
+        frame: &TraceFrame,
+    ) -> Option<(CrashSignature, Option<usize>)> {
+        let mut replayer = ReplaySessionBuilder::new().trace_dir(trace_dir).build();
+        while replayer.current_frame_time() < frame.time() {
+            replayer.step()?;
+        }
+        let task = replayer.session().find_task_from_rec_tid(frame.tid())?;
+
+        let siginfo = &frame.event().signal_event().siginfo;
+        let fault_address = fault_address(siginfo);
+        let signature = CrashSignature {
+            signal: signal_name(siginfo.si_signo),
+            code: sicode_name(siginfo.si_code, siginfo.si_signo),
+            fault_region: fault_address.map(|addr| fault_region(&**task, addr)),
+            frames: symbolized_backtrace(&**task, self.frames)
+                .into_iter()
+                .map(|(_, location)| location)
+                .collect(),
+        };
+        Some((signature, fault_address))
+    }
+}
+
+/// The first event at which a task got a signal that killed it.
+fn find_fatal_signal(trace_dir: &OsStr) -> Option<TraceFrame> {
+    let mut trace = TraceReader::new(Some(trace_dir));
+    while !trace.at_end() {
+        let frame = trace.read_frame();
+        let event = frame.event();
+        if matches!(
+            event.event_type(),
+            EventType::EvSignal | EventType::EvSignalDelivery
+        ) && event.signal_event().disposition == SignalResolvedDisposition::DispositionFatal
+        {
+            return Some(frame);
+        }
+    }
+    None
+}
+
+fn fault_address(siginfo: &siginfo_t) -> Option<usize> {
+    match siginfo.si_signo {
+        libc::SIGILL | libc::SIGFPE | libc::SIGSEGV | libc::SIGBUS => {
+            Some(unsafe { siginfo._sifields._sigfault.si_addr } as usize)
+        }
+        _ => None,
+    }
+}
+
+fn fault_region(t: &dyn Task, addr: usize) -> String {
+    if addr < NEAR_NULL_LIMIT {
+        return "near-null".into();
+    }
+    let vm = t.vm();
+    match vm.mapping_of(RemotePtr::new(addr)) {
+        None => "unmapped".into(),
+        Some(m) if m.map.fsname().is_empty() => "anonymous".into(),
+        Some(m) => m.map.fsname().to_string_lossy().into_owned(),
+    }
+}
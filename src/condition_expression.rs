@@ -0,0 +1,592 @@
+//! A small expression language for breakpoint and watchpoint conditions that
+//! rd parses and evaluates itself, so clients that can't generate gdb agent
+//! bytecode (see gdb_expression.rs) still get conditional stops. `rd rerun
+//! --condition` and the `rd-condition` gdb command use it.
+//!
+//! ```text
+//! $rdi == 3 && *(u32 *)($rsp + 8) > 100
+//! (i8)$eax < 0 || *(u64 *)$rsi != 0x7f0000001000
+//! ```
+//!
+//! - Registers are written with an optional `$`: `rax`..`r15`, `rip`, `eflags`
+//!   and their 32-bit forms (`eax`, `r8d`..). `ip`, `sp` and `fp` name the
+//!   instruction, stack and frame pointers for either architecture.
+//! - Any other name is the address of an ELF symbol, e.g. `*(i32 *)g_count`.
+//!   Only some targets can look symbols up (`rd assert` can, breakpoint
+//!   conditions can't); elsewhere evaluating a symbol fails.
+//! - Numbers are decimal or `0x` hexadecimal.
+//! - `(T)e` converts `e` to `T`, one of `u8` `u16` `u32` `u64` `i8` `i16`
+//!   `i32` `i64`. `*(T *)e` reads a `T` from memory at `e`; a bare `*e` reads
+//!   a `u64`. There is no pointer arithmetic scaling: `(u32 *)p + 1` is `p + 1`.
+//! - Operators are C's, with C precedence: unary `- ! ~ *`, `* / %`, `+ -`,
+//!   `<< >>`, `< <= > >=`, `== !=`, `&`, `^`, `|`, `&&`, `||`.
+//!
+//! Values are 64-bit and arithmetic wraps. Comparisons, division and `>>` are
+//! signed; use a cast to an unsigned type before comparing to get an
+//! unsigned comparison of narrower values.
+
+use crate::{
+    breakpoint_condition::BreakpointCondition, gdb_expression::ExpressionTarget, gdb_register::*,
+    kernel_abi::SupportedArch, log::LogWarn, session::task::Task,
+};
+use std::{fmt, str::FromStr};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Type {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+}
+
+impl Type {
+    fn from_name(name: &str) -> Option<Type> {
+        Some(match name {
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "u64" => Type::U64,
+            "i8" => Type::I8,
+            "i16" => Type::I16,
+            "i32" => Type::I32,
+            "i64" => Type::I64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Type::U8 | Type::I8 => 1,
+            Type::U16 | Type::I16 => 2,
+            Type::U32 | Type::I32 => 4,
+            Type::U64 | Type::I64 => 8,
+        }
+    }
+
+    /// Truncate `v` to this type and extend it back to 64 bits.
+    fn convert(self, v: i64) -> i64 {
+        match self {
+            Type::U8 => v as u8 as i64,
+            Type::U16 => v as u16 as i64,
+            Type::U32 => v as u32 as i64,
+            Type::I8 => v as i8 as i64,
+            Type::I16 => v as i16 as i64,
+            Type::I32 => v as i32 as i64,
+            Type::U64 | Type::I64 => v,
+        }
+    }
+}
+
+/// A register name and where to find it on each architecture. `mask`
+/// selects the low bits for the 32-bit names of 64-bit registers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Register {
+    x64: Option<GdbRegister>,
+    x86: Option<GdbRegister>,
+    mask: u64,
+}
+
+const WORD: u64 = u64::MAX;
+const LOW32: u64 = u32::MAX as u64;
+
+fn find_register(name: &str) -> Option<Register> {
+    let (x64, x86, mask) = match name {
+        "rax" => (Some(DREG_RAX), Some(DREG_EAX), WORD),
+        "rbx" => (Some(DREG_RBX), Some(DREG_EBX), WORD),
+        "rcx" => (Some(DREG_RCX), Some(DREG_ECX), WORD),
+        "rdx" => (Some(DREG_RDX), Some(DREG_EDX), WORD),
+        "rsi" => (Some(DREG_RSI), Some(DREG_ESI), WORD),
+        "rdi" => (Some(DREG_RDI), Some(DREG_EDI), WORD),
+        "rbp" | "fp" => (Some(DREG_RBP), Some(DREG_EBP), WORD),
+        "rsp" | "sp" => (Some(DREG_RSP), Some(DREG_ESP), WORD),
+        "rip" | "ip" | "pc" => (Some(DREG_RIP), Some(DREG_EIP), WORD),
+        "eax" => (Some(DREG_RAX), Some(DREG_EAX), LOW32),
+        "ebx" => (Some(DREG_RBX), Some(DREG_EBX), LOW32),
+        "ecx" => (Some(DREG_RCX), Some(DREG_ECX), LOW32),
+        "edx" => (Some(DREG_RDX), Some(DREG_EDX), LOW32),
+        "esi" => (Some(DREG_RSI), Some(DREG_ESI), LOW32),
+        "edi" => (Some(DREG_RDI), Some(DREG_EDI), LOW32),
+        "ebp" => (Some(DREG_RBP), Some(DREG_EBP), LOW32),
+        "esp" => (Some(DREG_RSP), Some(DREG_ESP), LOW32),
+        "eip" => (Some(DREG_RIP), Some(DREG_EIP), LOW32),
+        "eflags" | "rflags" => (Some(DREG_64_EFLAGS), Some(DREG_EFLAGS), LOW32),
+        "r8" => (Some(DREG_R8), None, WORD),
+        "r9" => (Some(DREG_R9), None, WORD),
+        "r10" => (Some(DREG_R10), None, WORD),
+        "r11" => (Some(DREG_R11), None, WORD),
+        "r12" => (Some(DREG_R12), None, WORD),
+        "r13" => (Some(DREG_R13), None, WORD),
+        "r14" => (Some(DREG_R14), None, WORD),
+        "r15" => (Some(DREG_R15), None, WORD),
+        _ => {
+            // r8d..r15d
+            let r = find_register(name.strip_suffix('d')?)?;
+            if r.x86.is_some() || r.mask != WORD {
+                return None;
+            }
+            return Some(Register { mask: LOW32, ..r });
+        }
+    };
+    Some(Register { x64, x86, mask })
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum UnaryOp {
+    Neg,
+    LogNot,
+    BitNot,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Equal,
+    NotEqual,
+    BitAnd,
+    BitXor,
+    BitOr,
+    LogAnd,
+    LogOr,
+}
+
+impl BinaryOp {
+    fn from_token(token: &str) -> Option<BinaryOp> {
+        Some(match token {
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Rem,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "<<" => BinaryOp::Shl,
+            ">>" => BinaryOp::Shr,
+            "<" => BinaryOp::Less,
+            "<=" => BinaryOp::LessEq,
+            ">" => BinaryOp::Greater,
+            ">=" => BinaryOp::GreaterEq,
+            "==" => BinaryOp::Equal,
+            "!=" => BinaryOp::NotEqual,
+            "&" => BinaryOp::BitAnd,
+            "^" => BinaryOp::BitXor,
+            "|" => BinaryOp::BitOr,
+            "&&" => BinaryOp::LogAnd,
+            "||" => BinaryOp::LogOr,
+            _ => return None,
+        })
+    }
+
+    /// Higher binds tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Less | BinaryOp::LessEq | BinaryOp::Greater | BinaryOp::GreaterEq => 7,
+            BinaryOp::Equal | BinaryOp::NotEqual => 6,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitOr => 3,
+            BinaryOp::LogAnd => 2,
+            BinaryOp::LogOr => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Expr {
+    Const(i64),
+    Reg(Register),
+    Symbol(String),
+    /// A value cast to `T *`. It only affects a dereference of it.
+    Pointer(Type, Box<Expr>),
+    Load(Type, Box<Expr>),
+    Cast(Type, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+fn tokenize(s: &str) -> Result<Vec<&str>, String> {
+    const TWO_CHAR_OPS: [&str; 8] = ["<<", ">>", "<=", ">=", "==", "!=", "&&", "||"];
+    let bytes = s.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == b'_' || c == b'$' {
+            let start = i;
+            i += 1;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(&s[start..i]);
+        } else if TWO_CHAR_OPS
+            .iter()
+            .any(|op| bytes[i..].starts_with(op.as_bytes()))
+        {
+            tokens.push(&s[i..i + 2]);
+            i += 2;
+        } else if b"()*/%+-<>&^|!~".contains(&c) {
+            tokens.push(&s[i..i + 1]);
+            i += 1;
+        } else {
+            return Err(format!("Unexpected `{}`", &s[i..]));
+        }
+    }
+    Ok(tokens)
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            Some(t) => Err(format!("Expected `{}`, found `{}`", token, t)),
+            None => Err(format!("Expected `{}` at end of expression", token)),
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek().and_then(BinaryOp::from_token) {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// If the next tokens are `( T )` or `( T * )`, consume them.
+    fn cast(&mut self) -> Result<Option<(Type, bool)>, String> {
+        let ty = match (self.peek(), self.tokens.get(self.pos + 1)) {
+            (Some("("), Some(name)) => match Type::from_name(name) {
+                Some(ty) => ty,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        self.pos += 2;
+        let is_pointer = self.peek() == Some("*");
+        if is_pointer {
+            self.pos += 1;
+        }
+        self.expect(")")?;
+        Ok(Some((ty, is_pointer)))
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if let Some((ty, is_pointer)) = self.cast()? {
+            let e = Box::new(self.unary()?);
+            return Ok(if is_pointer {
+                Expr::Pointer(ty, e)
+            } else {
+                Expr::Cast(ty, e)
+            });
+        }
+        let op = match self.peek() {
+            Some("-") => UnaryOp::Neg,
+            Some("!") => UnaryOp::LogNot,
+            Some("~") => UnaryOp::BitNot,
+            Some("*") => {
+                self.pos += 1;
+                return Ok(match self.unary()? {
+                    Expr::Pointer(ty, e) => Expr::Load(ty, e),
+                    e => Expr::Load(Type::U64, Box::new(e)),
+                });
+            }
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => return Err("Unexpected end of expression".into()),
+        };
+        if token == "(" {
+            let e = self.binary(0)?;
+            self.expect(")")?;
+            return Ok(e);
+        }
+        if token.as_bytes()[0].is_ascii_digit() {
+            let v = match token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+            {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => token.parse::<u64>(),
+            };
+            return match v {
+                Ok(v) => Ok(Expr::Const(v as i64)),
+                Err(_) => Err(format!("Bad number `{}`", token)),
+            };
+        }
+        match token.strip_prefix('$') {
+            Some(name) => match find_register(name) {
+                Some(r) => Ok(Expr::Reg(r)),
+                None => Err(format!("Unknown register `{}`", token)),
+            },
+            None => Ok(find_register(token)
+                .map(Expr::Reg)
+                .unwrap_or_else(|| Expr::Symbol(token.to_owned()))),
+        }
+    }
+}
+
+/// A parsed condition. Parse one with `str::parse`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConditionExpression {
+    source: String,
+    expr: Expr,
+}
+
+impl FromStr for ConditionExpression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected `{}` in `{}`", token, s));
+        }
+        Ok(ConditionExpression {
+            source: s.to_owned(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for ConditionExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl ConditionExpression {
+    /// Evaluate against `target`, whose registers are laid out for `arch`.
+    /// Fails if a register or memory can't be read or on division by zero.
+    pub fn evaluate<T: ExpressionTarget + ?Sized>(
+        &self,
+        target: &T,
+        arch: SupportedArch,
+    ) -> Result<i64, String> {
+        eval(&self.expr, target, arch)
+    }
+}
+
+fn eval<T: ExpressionTarget + ?Sized>(
+    e: &Expr,
+    target: &T,
+    arch: SupportedArch,
+) -> Result<i64, String> {
+    Ok(match e {
+        Expr::Const(v) => *v,
+        Expr::Reg(r) => {
+            let reg = match arch {
+                SupportedArch::X64 => r.x64,
+                SupportedArch::X86 => r.x86,
+            };
+            match reg.and_then(|reg| target.read_register(reg)) {
+                Some(v) => (v & r.mask) as i64,
+                None => return Err(format!("Register not available on {:?}", arch)),
+            }
+        }
+        Expr::Symbol(name) => match target.symbol_address(name) {
+            Some(addr) => addr as i64,
+            None => return Err(format!("Unknown symbol `{}`", name)),
+        },
+        Expr::Pointer(_, e) => eval(e, target, arch)?,
+        Expr::Load(ty, e) => {
+            let addr = eval(e, target, arch)? as usize;
+            let mut buf = [0u8; 8];
+            if !target.read_memory(addr, &mut buf[0..ty.size()]) {
+                return Err(format!("Cannot read {} bytes at {:#x}", ty.size(), addr));
+            }
+            ty.convert(i64::from_le_bytes(buf))
+        }
+        Expr::Cast(ty, e) => ty.convert(eval(e, target, arch)?),
+        Expr::Unary(op, e) => {
+            let v = eval(e, target, arch)?;
+            match op {
+                UnaryOp::Neg => v.wrapping_neg(),
+                UnaryOp::LogNot => (v == 0) as i64,
+                UnaryOp::BitNot => !v,
+            }
+        }
+        Expr::Binary(BinaryOp::LogAnd, a, b) => {
+            (eval(a, target, arch)? != 0 && eval(b, target, arch)? != 0) as i64
+        }
+        Expr::Binary(BinaryOp::LogOr, a, b) => {
+            (eval(a, target, arch)? != 0 || eval(b, target, arch)? != 0) as i64
+        }
+        Expr::Binary(op, a, b) => {
+            let a = eval(a, target, arch)?;
+            let b = eval(b, target, arch)?;
+            match op {
+                BinaryOp::Mul => a.wrapping_mul(b),
+                BinaryOp::Div | BinaryOp::Rem if b == 0 => {
+                    return Err("Division by zero".into());
+                }
+                BinaryOp::Div => a.wrapping_div(b),
+                BinaryOp::Rem => a.wrapping_rem(b),
+                BinaryOp::Add => a.wrapping_add(b),
+                BinaryOp::Sub => a.wrapping_sub(b),
+                BinaryOp::Shl => a.wrapping_shl(b as u32),
+                BinaryOp::Shr => a.wrapping_shr(b as u32),
+                BinaryOp::Less => (a < b) as i64,
+                BinaryOp::LessEq => (a <= b) as i64,
+                BinaryOp::Greater => (a > b) as i64,
+                BinaryOp::GreaterEq => (a >= b) as i64,
+                BinaryOp::Equal => (a == b) as i64,
+                BinaryOp::NotEqual => (a != b) as i64,
+                BinaryOp::BitAnd => a & b,
+                BinaryOp::BitXor => a ^ b,
+                BinaryOp::BitOr => a | b,
+                BinaryOp::LogAnd | BinaryOp::LogOr => unreachable!(),
+            }
+        }
+    })
+}
+
+impl BreakpointCondition for ConditionExpression {
+    fn evaluate(&self, t: &dyn Task) -> bool {
+        // Like gdb's conditions, stop if the condition can't be evaluated.
+        match ConditionExpression::evaluate(self, t, t.arch()) {
+            Ok(v) => v != 0,
+            Err(e) => {
+                log!(LogWarn, "Condition `{}` failed: {}", self, e);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSP: u64 = 0x7ffe_0000;
+
+    /// Eight bytes of stack at rsp, and rdi = 3.
+    struct FakeTarget {
+        stack: [u8; 16],
+    }
+
+    impl ExpressionTarget for FakeTarget {
+        fn read_memory(&self, addr: usize, buf: &mut [u8]) -> bool {
+            let offset = addr.wrapping_sub(RSP as usize);
+            if offset + buf.len() > self.stack.len() {
+                return false;
+            }
+            buf.copy_from_slice(&self.stack[offset..offset + buf.len()]);
+            true
+        }
+
+        fn read_register(&self, r: GdbRegister) -> Option<u64> {
+            match r {
+                DREG_RSP => Some(RSP),
+                DREG_RDI => Some(0xffff_ffff_0000_0003),
+                _ => None,
+            }
+        }
+
+        fn symbol_address(&self, name: &str) -> Option<u64> {
+            match name {
+                "g_count" => Some(RSP + 8),
+                _ => None,
+            }
+        }
+    }
+
+    fn eval(s: &str) -> Result<i64, String> {
+        let mut stack = [0u8; 16];
+        stack[8..12].copy_from_slice(&(-200i32).to_le_bytes());
+        let e: ConditionExpression = s.parse()?;
+        e.evaluate(&FakeTarget { stack }, SupportedArch::X64)
+    }
+
+    #[test]
+    fn precedence_and_arithmetic() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("1 << 4 | 1 == 1"), Ok(17));
+        assert_eq!(eval("-7 / 2"), Ok(-3));
+        assert_eq!(eval("!0 && ~0 == -1"), Ok(1));
+        assert!(eval("1 / 0").is_err());
+    }
+
+    #[test]
+    fn registers_memory_and_casts() {
+        assert_eq!(eval("$edi == 3"), Ok(1));
+        assert_eq!(eval("rdi == 3"), Ok(0));
+        assert_eq!(eval("*(i32 *)($rsp + 8)"), Ok(-200));
+        assert_eq!(eval("*(u32 *)($rsp + 8) > 100"), Ok(1));
+        assert_eq!(eval("(u8)*(i32 *)(sp + 8)"), Ok(56));
+        assert!(eval("*(u64 *)($rsp + 12)").is_err());
+        assert!(eval("$rax").is_err());
+        // The right hand side isn't evaluated, so its bad read doesn't matter.
+        assert_eq!(eval("0 && *0"), Ok(0));
+    }
+
+    #[test]
+    fn symbols() {
+        assert_eq!(eval("*(i32 *)g_count == -200"), Ok(1));
+        assert_eq!(eval("g_count - sp"), Ok(8));
+        assert!(eval("g_cont").is_err());
+        assert_eq!(eval("$rsp == rsp"), Ok(1));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("$xyz == 1".parse::<ConditionExpression>().is_err());
+        assert!("(1 + 2".parse::<ConditionExpression>().is_err());
+        assert!("1 2".parse::<ConditionExpression>().is_err());
+        assert!("(u32 *".parse::<ConditionExpression>().is_err());
+        assert!("1 @ 2".parse::<ConditionExpression>().is_err());
+    }
+
+    #[test]
+    fn register_names() {
+        assert_eq!(find_register("r8d").map(|r| r.mask), Some(LOW32));
+        assert_eq!(find_register("r15").map(|r| r.mask), Some(WORD));
+        assert_eq!(find_register("r8dd"), None);
+        assert_eq!(find_register("eaxd"), None);
+        assert!("$r8dd == 1".parse::<ConditionExpression>().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    extra_registers::{ExtraRegisters, Format},
+    log::LogWarn,
+    registers::{MismatchBehavior, Registers},
+    session::task::{replay_task::ReplayTask, Task},
+};
+use std::{ops::Range, str::FromStr};
+
+/// Bytes of the legacy FXSAVE region holding FOP, FIP (+FCS) and FDP (+FDS).
+/// These record where the last x87 instruction was and what it touched, which
+/// can differ between recording and replay (e.g. when the kernel or a
+/// different CPU executes some x87 code on the tracee's behalf) without the
+/// tracee being any the wiser.
+const FPU_POINTER_BYTES: Range<usize> = 6..24;
+
+bitflags! {
+    /// Divergences that `rd replay --repair-divergence` patches over instead of
+    /// aborting. Any other difference from the recording is still fatal.
+    #[derive(Default)]
+    pub struct BenignDivergences: u32 {
+        /// eflags differs from the recording.
+        const EFLAGS = 1 << 0;
+
+        /// rcx/r11 (ecx on x86) differ. The kernel clobbers these on syscall
+        /// entry and what is left in them depends on the kernel's exit path.
+        const SYSCALL_CLOBBERED = 1 << 1;
+
+        /// The x87 FOP/FIP/FDP fields differ.
+        const FPU_POINTERS = 1 << 2;
+    }
+}
+
+impl FromStr for BenignDivergences {
+    type Err = String;
+
+    /// Parse a comma separated list of `eflags`, `syscall-clobbered`,
+    /// `fpu-pointers` or `all`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = BenignDivergences::empty();
+        for name in s.split(',') {
+            result |= match name.trim() {
+                "eflags" => BenignDivergences::EFLAGS,
+                "syscall-clobbered" => BenignDivergences::SYSCALL_CLOBBERED,
+                "fpu-pointers" => BenignDivergences::FPU_POINTERS,
+                "all" => BenignDivergences::all(),
+                other => {
+                    return Err(format!(
+                        "Unknown divergence `{}`. Expected one of `eflags`, \
+                         `syscall-clobbered`, `fpu-pointers` or `all`",
+                        other
+                    ))
+                }
+            };
+        }
+        Ok(result)
+    }
+}
+
+/// If `t`'s registers differ from `rec_regs` only in ways allowed by `benign`,
+/// set them to the recorded values, log what was changed and return true.
+/// Otherwise leave `t` alone and return false.
+pub fn repair_regs(t: &ReplayTask, rec_regs: &Registers, benign: BenignDivergences) -> bool {
+    let regs = t.regs_ref().clone();
+    let mut repaired = regs.clone();
+    if benign.contains(BenignDivergences::EFLAGS) {
+        repaired.set_flags(rec_regs.flags());
+    }
+    if benign.contains(BenignDivergences::SYSCALL_CLOBBERED) {
+        repaired.set_cx(rec_regs.cx());
+        if let Registers::X64(_) = rec_regs {
+            repaired.set_r11(rec_regs.r11());
+        }
+    }
+
+    if !Registers::compare_register_files(
+        Some(t),
+        "repaired",
+        &repaired,
+        "recorded",
+        rec_regs,
+        MismatchBehavior::ExpectMismatches,
+    ) {
+        return false;
+    }
+
+    let diffs = regs.differences(&repaired);
+    if diffs.is_empty() {
+        return true;
+    }
+    for (name, replaying, recorded) in diffs {
+        log!(
+            LogWarn,
+            "Repaired benign divergence at event {}: {} replaying:{:#x} recorded:{:#x}",
+            t.current_frame_time(),
+            name,
+            replaying,
+            recorded
+        );
+    }
+    t.set_regs(&repaired);
+    true
+}
+
+/// Copy the x87 instruction and data pointers in `rec_extra_regs` into `t`
+/// if they differ.
+pub fn repair_fpu_pointers(t: &ReplayTask, rec_extra_regs: &ExtraRegisters) {
+    if rec_extra_regs.format() != Format::XSave {
+        return;
+    }
+    let mut extra_regs = t.extra_regs_ref().clone();
+    if extra_regs.format() != Format::XSave
+        || extra_regs.data_.len() < FPU_POINTER_BYTES.end
+        || rec_extra_regs.data_.len() < FPU_POINTER_BYTES.end
+    {
+        return;
+    }
+
+    let replaying = extra_regs.data_[FPU_POINTER_BYTES].to_vec();
+    if !patch_fpu_pointers(&mut extra_regs.data_, &rec_extra_regs.data_) {
+        return;
+    }
+
+    log!(
+        LogWarn,
+        "Repaired benign divergence at event {}: x87 FOP/FIP/FDP replaying:{:02x?} recorded:{:02x?}",
+        t.current_frame_time(),
+        replaying,
+        &rec_extra_regs.data_[FPU_POINTER_BYTES]
+    );
+    t.set_extra_regs(&extra_regs);
+}
+
+/// Copy the FOP/FIP/FDP bytes, including the padding after the FCS and FDS
+/// selectors, of the XSAVE area `recorded` into `data`. Returns false if they
+/// were the same already.
+fn patch_fpu_pointers(data: &mut [u8], recorded: &[u8]) -> bool {
+    let recorded = &recorded[FPU_POINTER_BYTES];
+    if data[FPU_POINTER_BYTES] == *recorded {
+        return false;
+    }
+    data[FPU_POINTER_BYTES].copy_from_slice(recorded);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_benign_divergences() {
+        assert_eq!(
+            "eflags,fpu-pointers".parse::<BenignDivergences>(),
+            Ok(BenignDivergences::EFLAGS | BenignDivergences::FPU_POINTERS)
+        );
+        assert_eq!(
+            "all".parse::<BenignDivergences>(),
+            Ok(BenignDivergences::all())
+        );
+        assert!("eflags,rip".parse::<BenignDivergences>().is_err());
+    }
+
+    #[test]
+    fn patch_fpu_pointers_only_touches_pointer_bytes() {
+        let recorded: Vec<u8> = (0..32).collect();
+
+        // Only the reserved bytes after FCS (14..16) and FDS (22..24) differ.
+        let mut data = recorded.clone();
+        data[14] = 0xff;
+        data[23] = 0xff;
+        assert!(patch_fpu_pointers(&mut data, &recorded));
+        assert_eq!(data, recorded);
+        assert!(!patch_fpu_pointers(&mut data, &recorded));
+
+        // FCW (0..2) and MXCSR (24..28) are not repaired.
+        let mut data = recorded.clone();
+        data[0] = 0xff;
+        data[10] = 0xff;
+        data[24] = 0xff;
+        assert!(patch_fpu_pointers(&mut data, &recorded));
+        assert_eq!(data[10], recorded[10]);
+        assert_eq!((data[0], data[24]), (0xff, 0xff));
+    }
+}
@@ -0,0 +1,240 @@
+use crate::{
+    kernel_abi::SupportedArch,
+    kernel_metadata::{errno_name, syscall_name},
+    log::LogWarn,
+};
+use std::{fmt, str::FromStr};
+
+/// These syscalls are never allowed to fail artificially: rd's own processing
+/// of them (task creation, exec, exit, signal frame teardown) assumes the
+/// kernel actually ran them, and replay re-executes the memory map changes
+/// and expects them to have the recorded result. That includes the mmap
+/// family and brk: an injected failure turns them into gettid, so the
+/// mapping never changes during recording but replay would still try to
+/// reproduce it.
+const NON_INJECTABLE_SYSCALLS: [&str; 19] = [
+    "clone",
+    "clone3",
+    "fork",
+    "vfork",
+    "execve",
+    "execveat",
+    "exit",
+    "exit_group",
+    "sigreturn",
+    "rt_sigreturn",
+    "restart_syscall",
+    "mprotect",
+    "pkey_mprotect",
+    "madvise",
+    "mmap",
+    "mmap2",
+    "munmap",
+    "mremap",
+    "brk",
+];
+
+/// A single `--inject-errors` rule.
+///
+/// Syntax: `<syscall>[:<nth>][:fd><fd>]=<errno>`, e.g. `write:3:fd>2=ENOSPC`
+/// makes the 3rd write() to an fd greater than 2 fail with ENOSPC. Without
+/// `<nth>` every matching call fails. `<errno>` is a name like `EIO` or a number.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InjectErrorSpec {
+    syscall: String,
+    nth: Option<u64>,
+    /// Only match calls whose first argument (the fd, for the syscalls this is
+    /// useful for) is greater than this.
+    fd_above: Option<i32>,
+    errno: i32,
+}
+
+impl InjectErrorSpec {
+    fn matches(&self, name: &str, arg1: isize) -> bool {
+        self.syscall == name
+            && match self.fd_above {
+                Some(fd) => arg1 > fd as isize,
+                None => true,
+            }
+    }
+}
+
+impl fmt::Display for InjectErrorSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.syscall)?;
+        if let Some(nth) = self.nth {
+            write!(f, ":{}", nth)?;
+        }
+        if let Some(fd) = self.fd_above {
+            write!(f, ":fd>{}", fd)?;
+        }
+        write!(f, "={}", errno_name(self.errno))
+    }
+}
+
+impl FromStr for InjectErrorSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (lhs, errno_str) = match s.rfind('=') {
+            Some(pos) => (&s[0..pos], &s[pos + 1..]),
+            None => return Err(format!("Could not find `=<errno>` in `{}`", s)),
+        };
+        let errno = parse_errno(errno_str)
+            .ok_or_else(|| format!("Unknown errno `{}` in `{}`", errno_str, s))?;
+
+        let mut parts = lhs.split(':');
+        let syscall = parts.next().unwrap_or("").trim().to_owned();
+        if syscall.is_empty() {
+            return Err(format!("No syscall name in `{}`", s));
+        }
+        if NON_INJECTABLE_SYSCALLS.contains(&syscall.as_str()) || syscall.starts_with("rdcall") {
+            return Err(format!("Can't inject errors into `{}`", syscall));
+        }
+
+        let mut nth = None;
+        let mut fd_above = None;
+        for part in parts {
+            if let Some(fd) = part.strip_prefix("fd>") {
+                match fd.parse::<i32>() {
+                    Ok(fd) => fd_above = Some(fd),
+                    Err(_) => return Err(format!("Bad fd filter `{}` in `{}`", part, s)),
+                }
+            } else {
+                match part.parse::<u64>() {
+                    Ok(n) if n > 0 => nth = Some(n),
+                    _ => return Err(format!("Bad occurrence `{}` in `{}`", part, s)),
+                }
+            }
+        }
+
+        Ok(InjectErrorSpec {
+            syscall,
+            nth,
+            fd_above,
+            errno,
+        })
+    }
+}
+
+fn parse_errno(s: &str) -> Option<i32> {
+    if let Ok(n) = s.parse::<i32>() {
+        return if n > 0 { Some(n) } else { None };
+    }
+    // errno values top out well below this.
+    (1..4096).find(|&e| errno_name(e) == s)
+}
+
+/// Decides which syscalls get an artificial error during recording. The
+/// failure is recorded like any other syscall result, so the run replays
+/// exactly.
+#[derive(Default)]
+pub struct ErrorInjector {
+    /// Each rule with the number of calls it has matched so far.
+    rules: Vec<(InjectErrorSpec, u64)>,
+}
+
+impl ErrorInjector {
+    pub fn new(specs: &[InjectErrorSpec]) -> ErrorInjector {
+        for spec in specs {
+            let known = (0..1024).any(|n| {
+                syscall_name(n, SupportedArch::X64) == spec.syscall
+                    || syscall_name(n, SupportedArch::X86) == spec.syscall
+            });
+            if !known {
+                log!(
+                    LogWarn,
+                    "--inject-errors {}: unknown syscall `{}`, ignoring",
+                    spec,
+                    spec.syscall
+                );
+            }
+        }
+        ErrorInjector {
+            rules: specs.iter().map(|s| (s.clone(), 0)).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Count this call against every matching rule and return the errno to fail
+    /// it with, if any rule fires. Every matching rule counts the call, even
+    /// when an earlier one already fired.
+    pub fn maybe_inject(
+        &mut self,
+        syscallno: i32,
+        arch: SupportedArch,
+        arg1: isize,
+    ) -> Option<i32> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        self.maybe_inject_named(&syscall_name(syscallno, arch), arg1)
+    }
+
+    fn maybe_inject_named(&mut self, name: &str, arg1: isize) -> Option<i32> {
+        let mut result = None;
+        for (spec, count) in self.rules.iter_mut() {
+            if !spec.matches(name, arg1) {
+                continue;
+            }
+            *count += 1;
+            let fire = match spec.nth {
+                Some(nth) => *count == nth,
+                None => true,
+            };
+            if fire && result.is_none() {
+                result = Some(spec.errno);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_inject_error_spec() {
+        let spec: InjectErrorSpec = "write:3:fd>2=ENOSPC".parse().unwrap();
+        assert_eq!(
+            spec,
+            InjectErrorSpec {
+                syscall: "write".into(),
+                nth: Some(3),
+                fd_above: Some(2),
+                errno: libc::ENOSPC,
+            }
+        );
+        let spec: InjectErrorSpec = "openat=13".parse().unwrap();
+        assert_eq!(spec.nth, None);
+        assert_eq!(spec.errno, libc::EACCES);
+
+        assert!("write".parse::<InjectErrorSpec>().is_err());
+        assert!("write=ENOTANERRNO".parse::<InjectErrorSpec>().is_err());
+        assert!("write:0=EIO".parse::<InjectErrorSpec>().is_err());
+        assert!("=EIO".parse::<InjectErrorSpec>().is_err());
+        assert!("execve=EIO".parse::<InjectErrorSpec>().is_err());
+        assert!("mprotect=ENOMEM".parse::<InjectErrorSpec>().is_err());
+        assert!("madvise=EINVAL".parse::<InjectErrorSpec>().is_err());
+        assert!("mmap=ENOMEM".parse::<InjectErrorSpec>().is_err());
+        assert!("munmap:2=EINVAL".parse::<InjectErrorSpec>().is_err());
+        assert!("mremap=ENOMEM".parse::<InjectErrorSpec>().is_err());
+        assert!("brk=ENOMEM".parse::<InjectErrorSpec>().is_err());
+    }
+
+    #[test]
+    fn injector_counts_matching_calls() {
+        let mut injector = ErrorInjector {
+            rules: vec![("write:2:fd>2=EIO".parse().unwrap(), 0)],
+        };
+        assert_eq!(injector.maybe_inject_named("write", 3), None);
+        assert_eq!(injector.maybe_inject_named("write", 1), None);
+        assert_eq!(injector.maybe_inject_named("read", 3), None);
+        assert_eq!(injector.maybe_inject_named("write", 4), Some(libc::EIO));
+        assert_eq!(injector.maybe_inject_named("write", 3), None);
+    }
+}
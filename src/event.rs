@@ -2,8 +2,8 @@ use crate::{
     bindings::signal::siginfo_t,
     event::EventType::{
         EvDesched, EvExit, EvGrowMap, EvInstructionTrap, EvNoop, EvPatchSyscall, EvSched,
-        EvSeccompTrap, EvSentinel, EvSyscall, EvSyscallInterruption, EvSyscallbufAbortCommit,
-        EvSyscallbufFlush, EvSyscallbufReset, EvTraceTermination,
+        EvSdtProbe, EvSeccompTrap, EvSentinel, EvSyscall, EvSyscallInterruption,
+        EvSyscallbufAbortCommit, EvSyscallbufFlush, EvSyscallbufReset, EvTraceTermination,
     },
     kernel_abi::{is_execve_syscall, SupportedArch},
     kernel_metadata::{is_sigreturn, signal_name, syscall_name},
@@ -82,6 +82,8 @@ pub enum EventType {
     /// Map memory pages due to a (future) memory access. This is associated
     /// with a mmap entry for the new pages.
     EvGrowMap,
+    /// A planted SDT probe was hit. Use .sdt_probe_event.
+    EvSdtProbe,
     /// Use .signal_event.
     EvSignal,
     EvSignalDelivery,
@@ -105,6 +107,15 @@ pub struct DeschedEventData {
     pub rec: RemotePtr<syscallbuf_record>,
 }
 
+/// The probe and argument values of an `EvSdtProbe` event.
+#[derive(Clone)]
+pub struct SdtProbeEventData {
+    pub provider: String,
+    pub name: String,
+    /// None for arguments we couldn't evaluate.
+    pub args: Vec<Option<i64>>,
+}
+
 #[derive(Clone)]
 pub struct SyscallbufFlushEventData {
     pub mprotect_records: Vec<mprotect_record>,
@@ -230,6 +241,8 @@ pub struct OpenedFd {
     pub fd: i32,
     pub device: dev_t,
     pub inode: ino_t,
+    /// Where the trace's copy of a locale file is, empty for other files.
+    pub backing_file_name: OsString,
 }
 
 #[derive(Clone)]
@@ -303,6 +316,7 @@ impl SyscallEventData {
 pub enum EventExtraData {
     NoExtraData,
     DeschedEvent(DeschedEventData),
+    SdtProbeEvent(SdtProbeEventData),
     SignalEvent(SignalEventData),
     SyscallEvent(SyscallEventData),
     SyscallbufFlushEvent(SyscallbufFlushEventData),
@@ -359,6 +373,7 @@ impl Display for EventType {
             EventType::EvSyscallbufReset => "SYSCALLBUF_RESET",
             EventType::EvPatchSyscall => "PATCH_SYSCALL",
             EventType::EvGrowMap => "GROW_MAP",
+            EventType::EvSdtProbe => "SDT_PROBE",
             EventType::EvSignal => "SIGNAL",
             EventType::EvSignalDelivery => "SIGNAL_DELIVERY",
             EventType::EvSignalHandler => "SIGNAL_HANDLER",
@@ -377,6 +392,13 @@ impl Event {
         }
     }
 
+    pub fn new_sdt_probe_event(ev: SdtProbeEventData) -> Event {
+        Event {
+            event_type: EvSdtProbe,
+            event_extra_data: EventExtraData::SdtProbeEvent(ev),
+        }
+    }
+
     pub fn new_signal_event(type_: EventType, ev: SignalEventData) -> Event {
         Event {
             event_type: type_,
@@ -425,6 +447,7 @@ impl Event {
             EventType::EvInstructionTrap
                 | EventType::EvPatchSyscall
                 | EventType::EvSched
+                | EventType::EvSdtProbe
                 | EventType::EvSyscall
                 | EventType::EvSignal
                 | EventType::EvSignalDelivery
@@ -498,6 +521,25 @@ impl Event {
                 )
                 .unwrap_or(());
             }
+            EventType::EvSdtProbe => {
+                let probe = self.sdt_probe_event();
+                let args: Vec<String> = probe
+                    .args
+                    .iter()
+                    .map(|arg| match arg {
+                        Some(v) => v.to_string(),
+                        None => "?".into(),
+                    })
+                    .collect();
+                write!(
+                    ss,
+                    ": {}:{}({})",
+                    probe.provider,
+                    probe.name,
+                    args.join(", ")
+                )
+                .unwrap_or(());
+            }
             _ => {
                 // No auxiliary information.
             }
@@ -611,6 +653,13 @@ impl Event {
         }
     }
 
+    pub fn sdt_probe_event(&self) -> &SdtProbeEventData {
+        match &self.event_extra_data {
+            EventExtraData::SdtProbeEvent(ev) => ev,
+            _ => panic!("Not an SDT probe event"),
+        }
+    }
+
     pub fn signal_event(&self) -> &SignalEventData {
         match &self.event_extra_data {
             EventExtraData::SignalEvent(ev) => ev,
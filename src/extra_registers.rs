@@ -8,7 +8,13 @@ use crate::{
 };
 use std::{convert::TryInto, fmt::Write, io, mem::size_of, ptr::copy_nonoverlapping};
 
-const AVX_FEATURE_BIT: usize = 2;
+const X87_FEATURE_BIT: usize = 0;
+const SSE_FEATURE_BIT: usize = 1;
+pub const AVX_FEATURE_BIT: usize = 2;
+/// AVX-512 needs all three of these.
+pub const OPMASK_FEATURE_BIT: usize = 5;
+pub const ZMM_HI256_FEATURE_BIT: usize = 6;
+pub const HI16_ZMM_FEATURE_BIT: usize = 7;
 
 const XSAVE_HEADER_OFFSET: usize = 512;
 const XSAVE_HEADER_SIZE: usize = 64;
@@ -29,6 +35,8 @@ const ST_REG_SPACE: usize = 16;
 const XMM_REGS_OFFSET: usize = 160;
 const XMM_REG_SPACE: usize = 16;
 
+const MXCSR_OFFSET: usize = 24;
+
 const XSAVE_FEATURE_PKRU: usize = 9;
 
 /// The Intel documentation says that the following layout is only valid in
@@ -47,6 +55,13 @@ const FXSAVE_387_CTRL_OFFSETS: [usize; 8] = [
     6,  // DREG_64_FOP
 ];
 
+/// How many bytes of each of the above are actually stored. gdb always
+/// transfers 4. The tag word is stored in its abridged one-bit-per-register
+/// form; see `full_tag_word()`.
+const FXSAVE_387_CTRL_SIZES: [usize; 8] = [2, 2, 1, 2, 4, 2, 4, 2];
+const FTAG_INDEX: usize = 2;
+const FOP_INDEX: usize = 7;
+
 const XINUSE_OFFSET: usize = 512;
 
 /// On a x86 64-bit kernel, these structures are initialized by an XSAVE64 or
@@ -288,6 +303,23 @@ impl ExtraRegisters {
             return None;
         }
 
+        if let Some(i) = x87_control_index(self.arch_, regno) {
+            if self.is_empty() {
+                return None;
+            }
+            let off = FXSAVE_387_CTRL_OFFSETS[i];
+            let mut value = [0u8; 4];
+            value[0..FXSAVE_387_CTRL_SIZES[i]]
+                .copy_from_slice(&self.data_[off..off + FXSAVE_387_CTRL_SIZES[i]]);
+            let value = match i {
+                FTAG_INDEX => full_tag_word(&self.data_),
+                FOP_INDEX => u32::from_le_bytes(value) & 0x7ff,
+                _ => u32::from_le_bytes(value),
+            };
+            buf[0..4].copy_from_slice(&value.to_le_bytes());
+            return Some(4);
+        }
+
         let reg_data = xsave_register_data(self.arch_, regno);
         // @TODO check this. rr returns size even if offset is bad.
         if reg_data.offset.is_none() || self.is_empty() {
@@ -312,6 +344,106 @@ impl ExtraRegisters {
         Some(reg_data.size)
     }
 
+    /// Like `Registers::write_register()`, except for "extra registers"
+    /// (x87, SSE, AVX and AVX-512). A `value` shorter than the register
+    /// replaces only its low-order bytes, as gdb expects when it writes e.g.
+    /// one lane of a vector register. Writing a register of an XSAVE feature
+    /// that isn't in use (e.g. the upper half of a ymm register before any AVX
+    /// instruction ran) marks the feature as in use, with all its other
+    /// registers in their initial state.
+    ///
+    /// Returns false if `regno` can't be written or `value` is too long.
+    ///
+    /// DIFF NOTE: rr has no equivalent; it refuses to write extra registers.
+    pub fn write_register(&mut self, value: &[u8], regno: GdbRegister) -> bool {
+        if self.format_ != Format::XSave || self.is_empty() {
+            return false;
+        }
+
+        if let Some(i) = x87_control_index(self.arch_, regno) {
+            if value.len() > 4 || !self.claim_xsave_feature(X87_FEATURE_BIT) {
+                return false;
+            }
+            let mut full = [0u8; 4];
+            self.read_register(&mut full, regno);
+            full[0..value.len()].copy_from_slice(value);
+            let value = u32::from_le_bytes(full);
+            let stored = match i {
+                FTAG_INDEX => abridged_tag_word(value),
+                FOP_INDEX => value & 0x7ff,
+                _ => value,
+            };
+            let off = FXSAVE_387_CTRL_OFFSETS[i];
+            let size = FXSAVE_387_CTRL_SIZES[i];
+            self.data_[off..off + size].copy_from_slice(&stored.to_le_bytes()[0..size]);
+            return true;
+        }
+
+        let reg_data = xsave_register_data(self.arch_, regno);
+        let off = match reg_data.offset {
+            Some(off) => off,
+            None => return false,
+        };
+        if value.len() > reg_data.size || off + reg_data.size > self.data_.len() {
+            return false;
+        }
+
+        let bit = match reg_data.xsave_feature_bit {
+            Some(bit) => bit,
+            None if off == MXCSR_OFFSET || off >= XMM_REGS_OFFSET => SSE_FEATURE_BIT,
+            None => X87_FEATURE_BIT,
+        };
+        if !self.claim_xsave_feature(bit) {
+            return false;
+        }
+
+        self.data_[off..off + value.len()].copy_from_slice(value);
+        true
+    }
+
+    /// Make sure XSAVE feature `bit` is marked in use, so the kernel loads its
+    /// registers from our data. The kernel reports a feature in its initial
+    /// state when its XSTATE_BV bit is clear, whatever the buffer contains, so
+    /// put it in that state before claiming it.
+    ///
+    /// Returns false if our data has no room for the feature.
+    fn claim_xsave_feature(&mut self, bit: usize) -> bool {
+        if self.data_.len() < XSAVE_HEADER_END {
+            // Plain FXSAVE data: x87 and SSE state are always loaded.
+            return bit == X87_FEATURE_BIT || bit == SSE_FEATURE_BIT;
+        }
+        let features = xsave_features(&self.data_);
+        if features & (1 << bit) != 0 {
+            return true;
+        }
+        match bit {
+            X87_FEATURE_BIT => {
+                self.data_[0..MXCSR_OFFSET].fill(0);
+                self.data_[ST_REGS_OFFSET..XMM_REGS_OFFSET].fill(0);
+                self.data_[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+            }
+            SSE_FEATURE_BIT => {
+                self.data_[XMM_REGS_OFFSET..XMM_REGS_OFFSET + 16 * XMM_REG_SPACE].fill(0);
+            }
+            _ => {
+                // The initial state of AVX and AVX-512 is all zeros.
+                let area = match xsave_native_layout().feature_layouts.get(bit) {
+                    Some(area) if area.size > 0 => *area,
+                    _ => return false,
+                };
+                let start = area.offset as usize;
+                let end = start + area.size as usize;
+                if end > self.data_.len() {
+                    return false;
+                }
+                self.data_[start..end].fill(0);
+            }
+        }
+        self.data_[XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + 8]
+            .copy_from_slice(&(features | (1 << bit)).to_le_bytes());
+        true
+    }
+
     /// Get a user_fpregs_struct for a particular Arch from these ExtraRegisters.
     pub fn get_user_fpregs_struct(&self, arch: SupportedArch) -> Vec<u8> {
         debug_assert_eq!(self.format_, Format::XSave);
@@ -555,6 +687,10 @@ fn xsave_register_data(arch: SupportedArch, regno_param: GdbRegister) -> RegData
                 regno = ((regno - DREG_XMM0).unwrap() + DREG_64_XMM0).unwrap();
             } else if regno >= DREG_YMM0H && regno <= DREG_YMM7H {
                 regno = ((regno - DREG_YMM0H).unwrap() + DREG_64_YMM0H).unwrap();
+            } else if regno >= DREG_K0 && regno <= DREG_K7 {
+                regno = ((regno - DREG_K0).unwrap() + DREG_64_K0).unwrap();
+            } else if regno >= DREG_ZMM0H && regno <= DREG_ZMM7H {
+                regno = ((regno - DREG_ZMM0H).unwrap() + DREG_64_ZMM0H).unwrap();
             } else if regno < DREG_FIRST_FXSAVE_REG || regno > DREG_LAST_FXSAVE_REG {
                 return RegData::default();
             } else if regno == DREG_MXCSR {
@@ -604,6 +740,10 @@ fn xsave_register_data(arch: SupportedArch, regno_param: GdbRegister) -> RegData
         return result;
     }
 
+    if regno >= DREG_64_XMM16 && regno <= DREG_64_ZMM31H {
+        return avx512_register_data(regno);
+    }
+
     if regno < DREG_64_FIRST_FXSAVE_REG || regno > DREG_64_LAST_FXSAVE_REG {
         return RegData::default();
     }
@@ -621,6 +761,89 @@ fn xsave_register_data(arch: SupportedArch, regno_param: GdbRegister) -> RegData
     )
 }
 
+/// Where AVX-512 register `regno` (a 64-bit register number) is in our
+/// native XSAVE layout. Unlike AVX's, the offsets of these features vary
+/// between CPUs.
+fn avx512_register_data(regno: GdbRegister) -> RegData {
+    let index = |low: GdbRegister| (regno - low).unwrap().as_usize();
+    let (bit, offset, size) = if regno >= DREG_64_XMM16 && regno <= DREG_64_XMM31 {
+        (HI16_ZMM_FEATURE_BIT, 64 * index(DREG_64_XMM16), 16)
+    } else if regno >= DREG_64_YMM16H && regno <= DREG_64_YMM31H {
+        (HI16_ZMM_FEATURE_BIT, 64 * index(DREG_64_YMM16H) + 16, 16)
+    } else if regno >= DREG_64_K0 && regno <= DREG_64_K7 {
+        (OPMASK_FEATURE_BIT, 8 * index(DREG_64_K0), 8)
+    } else if regno >= DREG_64_ZMM0H && regno <= DREG_64_ZMM15H {
+        (ZMM_HI256_FEATURE_BIT, 32 * index(DREG_64_ZMM0H), 32)
+    } else {
+        debug_assert!(regno >= DREG_64_ZMM16H && regno <= DREG_64_ZMM31H);
+        (HI16_ZMM_FEATURE_BIT, 64 * index(DREG_64_ZMM16H) + 32, 32)
+    };
+    match xsave_native_layout().feature_layouts.get(bit) {
+        Some(area) if area.size > 0 => RegData {
+            offset: Some(area.offset as usize + offset),
+            size,
+            xsave_feature_bit: Some(bit),
+        },
+        _ => RegData::default(),
+    }
+}
+
+/// Which of FXSAVE_387_CTRL_OFFSETS `regno` is, if it's an x87 control
+/// register.
+fn x87_control_index(arch: SupportedArch, regno: GdbRegister) -> Option<usize> {
+    let (first, last) = match arch {
+        X86 => (DREG_FCTRL, DREG_FOP),
+        X64 => (DREG_64_FCTRL, DREG_64_FOP),
+    };
+    if regno < first || regno > last {
+        return None;
+    }
+    Some((regno - first).unwrap().as_usize())
+}
+
+/// The full x87 tag word gdb expects (two bits per physical register:
+/// valid, zero, special or empty), from the abridged one (one bit per
+/// register: empty or not) FXSAVE stores. This is what gdb itself does when
+/// it reads an FXSAVE area.
+fn full_tag_word(data: &[u8]) -> u32 {
+    const VALID: u32 = 0;
+    const ZERO: u32 = 1;
+    const SPECIAL: u32 = 2;
+    const EMPTY: u32 = 3;
+
+    let abridged = data[FXSAVE_387_CTRL_OFFSETS[FTAG_INDEX]];
+    let top = (u16::from_le_bytes([data[2], data[3]]) >> 11) as usize & 7;
+    let mut tags = 0;
+    for fpreg in 0..8 {
+        let tag = if abridged & (1 << fpreg) == 0 {
+            EMPTY
+        } else {
+            // FXSAVE stores the registers in stack order, starting at st(0).
+            let st = ST_REGS_OFFSET + ST_REG_SPACE * ((fpreg + 8 - top) % 8);
+            let raw = &data[st..st + 10];
+            let integer = raw[7] & 0x80 != 0;
+            let exponent = u16::from_le_bytes([raw[8], raw[9]]) & 0x7fff;
+            let fraction = u64::from_le_bytes(raw[0..8].try_into().unwrap()) & !(1 << 63);
+            match exponent {
+                0x7fff => SPECIAL,
+                0 if fraction == 0 && !integer => ZERO,
+                0 => SPECIAL,
+                _ if integer => VALID,
+                _ => SPECIAL,
+            }
+        };
+        tags |= tag << (2 * fpreg);
+    }
+    tags
+}
+
+/// The abridged form of gdb's full x87 tag word. See `full_tag_word()`.
+fn abridged_tag_word(tags: u32) -> u32 {
+    (0..8)
+        .filter(|fpreg| (tags >> (2 * fpreg)) & 3 != 3)
+        .fold(0, |abridged, fpreg| abridged | (1 << fpreg))
+}
+
 // Note: uses usize for variables instead of i32 as in rr
 fn reg_in_range(
     regno: GdbRegister,
@@ -777,3 +1000,45 @@ fn write_reg(
     }
     write!(f, "{}:0x{}", name, out_str)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// XSAVE data with the x87 and SSE features in use.
+    fn xsave_regs() -> ExtraRegisters {
+        let mut data = vec![0u8; XSAVE_HEADER_END];
+        data[XSAVE_HEADER_OFFSET] = (1 << X87_FEATURE_BIT) | (1 << SSE_FEATURE_BIT);
+        ExtraRegisters {
+            format_: Format::XSave,
+            arch_: X64,
+            data_: data,
+        }
+    }
+
+    #[test]
+    fn short_writes_replace_low_bytes() {
+        let mut regs = xsave_regs();
+        let xmm1 = XMM_REGS_OFFSET + XMM_REG_SPACE;
+        regs.data_[xmm1..xmm1 + 16].fill(0xaa);
+        assert!(regs.write_register(&[1, 2, 3, 4], DREG_64_XMM1));
+        let mut buf = [0u8; 16];
+        assert_eq!(regs.read_register(&mut buf, DREG_64_XMM1), Some(16));
+        assert_eq!(buf[0..4], [1, 2, 3, 4]);
+        assert_eq!(buf[4..16], [0xaa; 12]);
+
+        regs.data_[0..2].copy_from_slice(&0x1234u16.to_le_bytes());
+        assert!(regs.write_register(&[0x7f], DREG_64_FCTRL));
+        assert_eq!(regs.read_register(&mut buf, DREG_64_FCTRL), Some(4));
+        assert_eq!(u32::from_le_bytes(buf[0..4].try_into().unwrap()), 0x127f);
+    }
+
+    #[test]
+    fn long_writes_are_rejected() {
+        let mut regs = xsave_regs();
+        assert!(!regs.write_register(&[0u8; 17], DREG_64_XMM1));
+        assert!(!regs.write_register(&[0u8; 5], DREG_64_FCTRL));
+        let xmm_end = XMM_REGS_OFFSET + 16 * XMM_REG_SPACE;
+        assert!(regs.data_[XMM_REGS_OFFSET..xmm_end].iter().all(|&b| b == 0));
+    }
+}
@@ -186,6 +186,10 @@ impl FdTable {
         self.fds.borrow().get(&fd).cloned()
     }
 
+    pub fn monitors(&self) -> Vec<FileMonitorSharedPtr> {
+        self.fds.borrow().values().cloned().collect()
+    }
+
     /// Regenerate syscallbuf_fds_disabled in task `t`.
     /// Called during initialization of the preload library.
     pub fn init_syscallbuf_fds_disabled(&self, t: &dyn Task) {
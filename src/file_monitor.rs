@@ -8,6 +8,7 @@ use crate::{
     session::task::{record_task::RecordTask, Task},
     util::get_fd_offset,
 };
+use locale_file_monitor::LocaleFileMonitor;
 use mmapped_file_monitor::MmappedFileMonitor;
 use std::{
     cell::RefCell,
@@ -15,12 +16,16 @@ use std::{
     rc::{Rc, Weak},
 };
 
+pub mod anon_fd_monitor;
 pub mod base_file_monitor;
+pub mod cgroup_file_monitor;
+pub mod locale_file_monitor;
 pub mod magic_save_data_monitor;
 pub mod mmapped_file_monitor;
 pub mod preserve_file_monitor;
 pub mod proc_fd_dir_monitor;
 pub mod proc_mem_monitor;
+pub mod random_file_monitor;
 pub mod stdio_monitor;
 pub mod virtual_perf_counter_monitor;
 
@@ -33,12 +38,18 @@ pub struct FileMonitorInner;
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FileMonitorType {
     Base,
+    Cgroup,
+    EventFd,
+    Locale,
     MagicSaveData,
     Mmapped,
     Preserve,
     ProcFd,
     ProcMem,
+    Random,
+    SignalFd,
     Stdio,
+    TimerFd,
     VirtualPerfCounter,
 }
 
@@ -67,6 +78,8 @@ pub struct LazyOffset<'b, 'a: 'b> {
     /// DIFF NOTE: @TODO in rr this is an i64
     /// Keeping it as an i32 to be consistent with elsewhere.
     syscallno: i32,
+    /// Where a FileMonitor that emulated a read wants the file position to be.
+    new_file_position: Option<u64>,
 }
 
 impl<'b, 'a: 'b> LazyOffset<'b, 'a> {
@@ -75,14 +88,53 @@ impl<'b, 'a: 'b> LazyOffset<'b, 'a> {
     }
 
     pub fn new(t: &'a dyn Task, regs: &'b Registers, syscallno: i32) -> LazyOffset<'b, 'a> {
-        LazyOffset { t, regs, syscallno }
+        LazyOffset {
+            t,
+            regs,
+            syscallno,
+            new_file_position: None,
+        }
+    }
+
+    /// True if the syscall reads at the file position rather than at an offset
+    /// passed to it (i.e. read/readv, or preadv2 with an offset of -1).
+    pub fn uses_file_position(&self) -> bool {
+        let arch = self.t.arch();
+        rd_arch_function_selfless!(
+            is_file_position_read_syscall_arch,
+            arch,
+            self.syscallno,
+            self.regs
+        )
+    }
+
+    /// The current file position of the fd being read. Only available while
+    /// recording.
+    pub fn file_position(&self) -> u64 {
+        ed_assert!(
+            self.t,
+            self.t.session().is_recording(),
+            "Can only read a file descriptor's offset while recording"
+        );
+        get_fd_offset(self.t.tid(), self.regs.arg1_signed() as i32)
+    }
+
+    /// An emulated read doesn't move the fd's file position. Call this to have
+    /// it moved to `pos` once the syscall is done.
+    pub fn set_file_position(&mut self, pos: u64) {
+        self.new_file_position = Some(pos);
+    }
+
+    pub fn new_file_position(&self) -> Option<u64> {
+        self.new_file_position
     }
 
     /// DIFF NOTE: In rr this returns an i64. We return a Option<u64>.
     /// Need to be careful with the logic here
     pub fn retrieve(&mut self, needed_for_replay: bool) -> Option<u64> {
         let is_replay = self.t.session().is_replaying();
-        let is_implicit_offset = is_implict_offset_syscall(self.t.arch(), self.syscallno);
+        let is_implicit_offset =
+            is_implict_offset_syscall(self.t.arch(), self.syscallno, self.regs);
         ed_assert!(self.t, needed_for_replay || !is_replay);
         // There is no way we can figure out this information now, so retrieve it
         // from the trace (we record it below under the same circumstance).
@@ -111,38 +163,72 @@ impl<'b, 'a: 'b> LazyOffset<'b, 'a> {
     }
 }
 
-fn is_implicit_offset_syscall_arch<Arch: Architecture>(syscallno: i32) -> bool {
-    syscallno == Arch::WRITEV || syscallno == Arch::WRITE
+fn is_implicit_offset_syscall_arch<Arch: Architecture>(syscallno: i32, regs: &Registers) -> bool {
+    syscallno == Arch::WRITEV
+        || syscallno == Arch::WRITE
+        || is_file_position_v2_syscall_arch::<Arch>(syscallno, regs)
 }
 
-fn is_implict_offset_syscall(arch: SupportedArch, syscallno: i32) -> bool {
-    rd_arch_function_selfless!(is_implicit_offset_syscall_arch, arch, syscallno)
-}
-
-fn retrieve_offset_arch<Arch: Architecture>(
-    t: &dyn Task,
+fn is_file_position_read_syscall_arch<Arch: Architecture>(
     syscallno: i32,
     regs: &Registers,
-) -> Option<u64> {
-    // DIFF NOTE: @TODO This is tricky. off_t is signed. Different from how rr does this.
-    // But a negative offset for these system calls does not make sense...
+) -> bool {
+    syscallno == Arch::READV
+        || syscallno == Arch::READ
+        || (syscallno == Arch::PREADV2 && is_file_position_v2_syscall_arch::<Arch>(syscallno, regs))
+}
+
+/// preadv2 and pwritev2 use (and move) the file position, like readv and
+/// writev, when their offset is -1.
+fn is_file_position_v2_syscall_arch<Arch: Architecture>(syscallno: i32, regs: &Registers) -> bool {
+    (syscallno == Arch::PREADV2 || syscallno == Arch::PWRITEV2)
+        && explicit_offset_arch::<Arch>(syscallno, regs) == Some(-1)
+}
+
+fn is_implict_offset_syscall(arch: SupportedArch, syscallno: i32, regs: &Registers) -> bool {
+    rd_arch_function_selfless!(is_implicit_offset_syscall_arch, arch, syscallno, regs)
+}
+
+/// The offset argument of the pread/pwrite family of syscalls, or None for
+/// other syscalls.
+fn explicit_offset_arch<Arch: Architecture>(syscallno: i32, regs: &Registers) -> Option<i64> {
     if syscallno == Arch::PWRITE64
         || syscallno == Arch::PWRITEV
+        || syscallno == Arch::PWRITEV2
         || syscallno == Arch::PREAD64
         || syscallno == Arch::PREADV
+        || syscallno == Arch::PREADV2
     {
-        let offset = if size_of::<Arch::unsigned_word>() == 4 {
+        Some(if size_of::<Arch::unsigned_word>() == 4 {
             regs.arg4() as i64 | ((regs.arg5_signed() as i64) << 32)
         } else {
             regs.arg4_signed() as i64
-        };
+        })
+    } else {
+        None
+    }
+}
 
-        if offset < 0 {
-            None
-        } else {
-            Some(offset as u64)
+fn retrieve_offset_arch<Arch: Architecture>(
+    t: &dyn Task,
+    syscallno: i32,
+    regs: &Registers,
+) -> Option<u64> {
+    // DIFF NOTE: @TODO This is tricky. off_t is signed. Different from how rr does this.
+    // But a negative offset for these system calls does not make sense, except
+    // the -1 of preadv2/pwritev2, which uses the file position like writev.
+    let is_file_position_v2 = is_file_position_v2_syscall_arch::<Arch>(syscallno, regs);
+    match explicit_offset_arch::<Arch>(syscallno, regs) {
+        Some(offset) if !is_file_position_v2 => {
+            return if offset < 0 {
+                None
+            } else {
+                Some(offset as u64)
+            };
         }
-    } else if syscallno == Arch::WRITEV || syscallno == Arch::WRITE {
+        _ => (),
+    }
+    if syscallno == Arch::WRITEV || syscallno == Arch::WRITE || is_file_position_v2 {
         ed_assert!(
             t,
             t.session().is_recording(),
@@ -150,8 +236,8 @@ fn retrieve_offset_arch<Arch: Architecture>(
         );
         let fd: i32 = regs.arg1_signed() as i32;
         let offset = get_fd_offset(t.tid(), fd);
-        // The pos we just read, was after the write completed. Luckily, we do
-        // know how many bytes were written.
+        // The pos we just read, was after the write (or read) completed. Luckily,
+        // we do know how many bytes were written.
         // DIFF NOTE: This is slightly different from the rr approach.
         if offset < regs.syscall_result() as u64 {
             None
@@ -183,6 +269,10 @@ pub trait FileMonitor {
         None
     }
 
+    fn as_locale_file_monitor(&self) -> Option<&LocaleFileMonitor> {
+        None
+    }
+
     fn as_virtual_perf_counter_monitor(&self) -> Option<&VirtualPerfCounterMonitor> {
         None
     }
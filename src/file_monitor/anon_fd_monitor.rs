@@ -0,0 +1,65 @@
+use crate::{
+    event::Switchable,
+    file_monitor::{FileMonitor, FileMonitorType},
+    session::task::Task,
+};
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AnonFdKind {
+    EventFd,
+    SignalFd,
+    TimerFd,
+}
+
+impl AnonFdKind {
+    /// The kind of fd whose /proc/<pid>/fd/ link reads `path`, if it's one of
+    /// these.
+    pub fn from_path(path: &OsStr) -> Option<AnonFdKind> {
+        match path.as_bytes() {
+            b"anon_inode:[eventfd]" => Some(AnonFdKind::EventFd),
+            b"anon_inode:[signalfd]" => Some(AnonFdKind::SignalFd),
+            b"anon_inode:[timerfd]" => Some(AnonFdKind::TimerFd),
+            _ => None,
+        }
+    }
+}
+
+/// Installed on eventfd, signalfd and timerfd file descriptors, whether the
+/// tracee created them or received them over a socket.
+///
+/// Their state (the counter, the pending signals, the timer's expirations)
+/// lives in the kernel and changes without the tracee touching memory, so
+/// nothing about them can be reconstructed during replay: every read and
+/// write is replayed from the trace. Like any monitor, this one also disables
+/// syscall buffering for the fd, so each of those operations is a traced
+/// syscall that rd sees.
+pub struct AnonFdMonitor {
+    kind: AnonFdKind,
+}
+
+impl AnonFdMonitor {
+    pub fn new(kind: AnonFdKind) -> AnonFdMonitor {
+        AnonFdMonitor { kind }
+    }
+}
+
+impl FileMonitor for AnonFdMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        match self.kind {
+            AnonFdKind::EventFd => FileMonitorType::EventFd,
+            AnonFdKind::SignalFd => FileMonitorType::SignalFd,
+            AnonFdKind::TimerFd => FileMonitorType::TimerFd,
+        }
+    }
+
+    /// Writes to a signalfd or timerfd fail with EINVAL without blocking, so
+    /// there's no point letting other tasks run meanwhile. An eventfd write
+    /// blocks when the counter would overflow.
+    fn will_write(&self, _t: &dyn Task) -> Switchable {
+        match self.kind {
+            AnonFdKind::EventFd => Switchable::AllowSwitch,
+            AnonFdKind::SignalFd | AnonFdKind::TimerFd => Switchable::PreventSwitch,
+        }
+    }
+}
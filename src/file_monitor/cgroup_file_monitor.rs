@@ -0,0 +1,166 @@
+use crate::{
+    file_monitor::{FileMonitor, FileMonitorType, LazyOffset, Range},
+    session::task::{task_inner::WriteFlags, Task},
+};
+use std::{
+    cmp::min,
+    ffi::OsStr,
+    fmt::{self, Display},
+    os::unix::ffi::OsStrExt,
+    str::FromStr,
+};
+
+/// A `--cgroup-limit` spec: reads of the cgroup interface file `file_name`
+/// (e.g. `memory.max`, `cpu.max`) under /sys/fs/cgroup return `value`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CgroupLimit {
+    pub file_name: String,
+    pub value: String,
+}
+
+impl CgroupLimit {
+    /// True if `path` is the interface file this limit spoofs, in any cgroup.
+    pub fn matches(&self, path: &OsStr) -> bool {
+        let path = path.as_bytes();
+        path.starts_with(b"/sys/fs/cgroup/")
+            && path.rsplit(|&c| c == b'/').next() == Some(self.file_name.as_bytes())
+    }
+
+    /// What a read of the file from offset 0 returns.
+    pub fn contents(&self) -> Vec<u8> {
+        format!("{}\n", self.value).into_bytes()
+    }
+}
+
+impl FromStr for CgroupLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (file_name, value) = match s.find('=') {
+            Some(pos) => (s[0..pos].trim(), s[pos + 1..].trim()),
+            None => return Err(format!("Could not find `=<value>` in `{}`", s)),
+        };
+        if file_name.is_empty() || file_name.contains('/') {
+            return Err(format!(
+                "`{}` should be the name of a cgroup file e.g. `memory.max`",
+                file_name
+            ));
+        }
+        if value.is_empty() {
+            return Err(format!("No value in `{}`", s));
+        }
+        // Byte counts like memory.max may use a K/M/G/T suffix, as in
+        // systemd's MemoryMax=. "max" and other values are used verbatim.
+        let value = match parse_size(value) {
+            Some(bytes) if file_name.starts_with("memory.") => bytes.to_string(),
+            _ => value.to_owned(),
+        };
+        Ok(CgroupLimit {
+            file_name: file_name.to_owned(),
+            value,
+        })
+    }
+}
+
+/// The spec again, as stored in the trace header.
+impl Display for CgroupLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.file_name, self.value)
+    }
+}
+
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        b'T' | b't' => (&s[..s.len() - 1], 40),
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Installed on cgroup interface files that have a `--cgroup-limit` when
+/// recording. Reads are emulated with the spoofed contents instead of reaching
+/// the kernel; the data ends up in the trace like any other read, so nothing
+/// needs to be emulated at replay.
+pub struct CgroupFileMonitor {
+    contents: Vec<u8>,
+}
+
+impl CgroupFileMonitor {
+    pub fn new(contents: Vec<u8>) -> CgroupFileMonitor {
+        CgroupFileMonitor { contents }
+    }
+}
+
+impl FileMonitor for CgroupFileMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        FileMonitorType::Cgroup
+    }
+
+    fn emulate_read(
+        &self,
+        ranges: &[Range],
+        lazy_offset: &mut LazyOffset,
+        result: &mut usize,
+    ) -> bool {
+        let uses_file_position = lazy_offset.uses_file_position();
+        // read()/readv() continue from the fd's real file position, so that
+        // lseek() on the fd works as usual.
+        let offset = if uses_file_position {
+            lazy_offset.file_position() as usize
+        } else {
+            lazy_offset.retrieve(false).unwrap() as usize
+        };
+        let t = lazy_offset.task();
+
+        let mut pos = min(offset, self.contents.len());
+        for r in ranges {
+            let len = min(r.length, self.contents.len() - pos);
+            if len == 0 {
+                break;
+            }
+            let mut ok = true;
+            t.write_bytes_helper(
+                r.data,
+                &self.contents[pos..pos + len],
+                Some(&mut ok),
+                WriteFlags::empty(),
+            );
+            if !ok {
+                // Let the kernel perform the read (and report EFAULT) instead.
+                return false;
+            }
+            pos += len;
+        }
+        *result = pos - min(offset, self.contents.len());
+        if uses_file_position && *result > 0 {
+            lazy_offset.set_file_position(pos as u64);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cgroup_limit() {
+        let limit: CgroupLimit = "memory.max=2G".parse().unwrap();
+        assert_eq!(limit.value, "2147483648");
+        assert!(limit.matches(OsStr::new("/sys/fs/cgroup/user.slice/memory.max")));
+        assert!(!limit.matches(OsStr::new("/sys/fs/cgroup/user.slice/memory.max2")));
+        assert!(!limit.matches(OsStr::new("/tmp/memory.max")));
+        let limit: CgroupLimit = "memory.max=max".parse().unwrap();
+        assert_eq!(limit.contents(), b"max\n");
+        let limit: CgroupLimit = "cpu.max=200000 100000".parse().unwrap();
+        assert_eq!(limit.value, "200000 100000");
+        assert_eq!(limit.to_string().parse::<CgroupLimit>(), Ok(limit));
+        assert!("memory.max".parse::<CgroupLimit>().is_err());
+        assert!("/sys/fs/cgroup/memory.max=1G"
+            .parse::<CgroupLimit>()
+            .is_err());
+    }
+}
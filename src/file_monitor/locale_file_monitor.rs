@@ -0,0 +1,97 @@
+use crate::{
+    file_monitor::{FileMonitor, FileMonitorType, LazyOffset, Range},
+    scoped_fd::ScopedFd,
+    session::task::{task_inner::WriteFlags, Task},
+    util::read_to_end,
+};
+use nix::fcntl::OFlag;
+use std::ffi::{OsStr, OsString};
+
+/// Installed on compiled locale data and message catalogs (see
+/// `util::is_locale_file`) that a tracee opens. When recording, the file is
+/// saved in the trace directory first and reads are emulated from that copy,
+/// so what the tracee saw is exactly what the trace holds no matter which
+/// locales the replaying machine has installed. At replay the read data comes
+/// from the trace as usual; the monitor remembers where the saved copy is so
+/// gdb's vFile requests can be served from it.
+pub struct LocaleFileMonitor {
+    /// The name the tracee opened the file under
+    path: OsString,
+    /// Absolute name of the saved copy
+    backing_file_name: OsString,
+    file: ScopedFd,
+}
+
+impl LocaleFileMonitor {
+    pub fn new(path: &OsStr, backing_file_name: &OsStr) -> LocaleFileMonitor {
+        LocaleFileMonitor {
+            path: path.to_owned(),
+            backing_file_name: backing_file_name.to_owned(),
+            file: ScopedFd::open_path(backing_file_name, OFlag::O_RDONLY),
+        }
+    }
+
+    pub fn path(&self) -> &OsStr {
+        &self.path
+    }
+
+    pub fn backing_file_name(&self) -> &OsStr {
+        &self.backing_file_name
+    }
+}
+
+impl FileMonitor for LocaleFileMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        FileMonitorType::Locale
+    }
+
+    fn as_locale_file_monitor(&self) -> Option<&LocaleFileMonitor> {
+        Some(self)
+    }
+
+    fn emulate_read(
+        &self,
+        ranges: &[Range],
+        lazy_offset: &mut LazyOffset,
+        result: &mut usize,
+    ) -> bool {
+        if !self.file.is_open() {
+            return false;
+        }
+        let uses_file_position = lazy_offset.uses_file_position();
+        let offset = if uses_file_position {
+            lazy_offset.file_position()
+        } else {
+            lazy_offset.retrieve(false).unwrap()
+        };
+        let t = lazy_offset.task();
+
+        let mut pos = offset;
+        for r in ranges {
+            let mut buf = vec![0u8; r.length];
+            let nread = match read_to_end(&self.file, pos, &mut buf) {
+                Ok(nread) => nread,
+                // Let the kernel perform the read instead.
+                Err(_) => return false,
+            };
+            if nread == 0 {
+                break;
+            }
+            let mut ok = true;
+            t.write_bytes_helper(r.data, &buf[0..nread], Some(&mut ok), WriteFlags::empty());
+            if !ok {
+                // Let the kernel perform the read (and report EFAULT) instead.
+                return false;
+            }
+            pos += nread as u64;
+            if nread < r.length {
+                break;
+            }
+        }
+        *result = (pos - offset) as usize;
+        if uses_file_position && *result > 0 {
+            lazy_offset.set_file_position(pos);
+        }
+        true
+    }
+}
@@ -22,8 +22,9 @@ use std::{
     ptr,
 };
 
-/// A FileMonitor to intercept enumerations of /proc/<pid>/fd so that entries
-/// for rd's private fds can be hidden when <pid> is a tracee.
+/// A FileMonitor to intercept enumerations of /proc/<pid>/fd and
+/// /proc/<pid>/fdinfo so that entries for rd's private fds can be hidden when
+/// <pid> is a tracee.
 pub struct ProcFdDirMonitor {
     /// None if this does not refer to a tracee's proc fd
     /// DIFF NOTE: in rr this is a "0" instead of None.
@@ -57,12 +58,14 @@ impl ProcFdDirMonitor {
         let maybe_proc = components.next();
         let maybe_tid_os_str = components.next();
         let maybe_fd = components.next();
-        if (maybe_rootdir, maybe_proc, maybe_fd)
+        let is_fd_dir = maybe_fd == Some(Component::Normal(OsStr::new("fd")))
+            || maybe_fd == Some(Component::Normal(OsStr::new("fdinfo")));
+        if (maybe_rootdir, maybe_proc)
             == (
                 Some(Component::RootDir),
                 Some(Component::Normal(OsStr::new("proc"))),
-                Some(Component::Normal(OsStr::new("fd"))),
             )
+            && is_fd_dir
         {
             match maybe_tid_os_str {
                 Some(Component::Normal(tid_os_str)) => {
@@ -0,0 +1,64 @@
+use crate::{
+    file_monitor::{FileMonitor, FileMonitorType, LazyOffset, Range},
+    session::task::{task_inner::WriteFlags, Task},
+};
+use std::cmp::min;
+
+/// Installed on /dev/urandom and /dev/random when recording with `--seed`.
+/// Reads are emulated with bytes from the session's seeded generator instead
+/// of reaching the kernel, so every recording made with the same seed sees the
+/// same data.
+pub struct RandomFileMonitor;
+
+impl RandomFileMonitor {
+    pub fn new() -> RandomFileMonitor {
+        RandomFileMonitor
+    }
+}
+
+impl FileMonitor for RandomFileMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        FileMonitorType::Random
+    }
+
+    fn emulate_read(
+        &self,
+        ranges: &[Range],
+        lazy_offset: &mut LazyOffset,
+        result: &mut usize,
+    ) -> bool {
+        let t = lazy_offset.task();
+        let total: usize = ranges.iter().map(|r| r.length).sum();
+        let bytes = match t
+            .session()
+            .as_record()
+            .and_then(|rs| rs.seeded_random_bytes(total))
+        {
+            Some(bytes) => bytes,
+            None => return false,
+        };
+
+        // There may be fewer bytes than asked for, making this a short read.
+        let mut consumed = 0;
+        for r in ranges {
+            let len = min(r.length, bytes.len() - consumed);
+            if len == 0 {
+                break;
+            }
+            let mut ok = true;
+            t.write_bytes_helper(
+                r.data,
+                &bytes[consumed..consumed + len],
+                Some(&mut ok),
+                WriteFlags::empty(),
+            );
+            if !ok {
+                // Let the kernel perform the read (and report EFAULT) instead.
+                return false;
+            }
+            consumed += len;
+        }
+        *result = consumed;
+        true
+    }
+}
@@ -1,11 +1,26 @@
-use crate::{commands::rd_options::RdOptions, trace::trace_frame::FrameTime};
-use std::path::PathBuf;
+use crate::{
+    commands::rd_options::RdOptions, preflight::Environment, trace::trace_frame::FrameTime,
+};
+use std::{
+    ffi::OsString,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
 use structopt::StructOpt;
 
 lazy_static! {
-    static ref FLAGS: Flags = init_flags();
+    static ref FLAGS: Flags = {
+        FLAGS_IN_USE.store(true, Ordering::SeqCst);
+        PRESET_FLAGS.lock().unwrap().take().unwrap_or_default()
+    };
+    static ref PRESET_FLAGS: Mutex<Option<Flags>> = Mutex::new(None);
 }
 
+static FLAGS_IN_USE: AtomicBool = AtomicBool::new(false);
+
 /// When to generate or check memory checksums. One of ChecksumNone,
 /// ChecksumSyscall or ChecksumAll, or ChecksumAt(<a positive integer representing the
 /// event time at which to start checksumming>).
@@ -50,8 +65,13 @@ pub struct Flags {
     /// Don't listen for PTRACE_EVENT_EXIT events, to test how rd handles
     /// missing PTRACE_EVENT_EXITs.
     pub disable_ptrace_exit_events: bool,
+    /// Don't use hardware performance counters; ticks count singlestepped
+    /// instructions instead (see `PerfCounters::read_ticks`).
+    pub no_pmu: bool,
     /// User override for architecture detection, e.g. when running under valgrind.
     pub forced_uarch: Option<String>,
+    /// User override for the raw PMU event that counts ticks.
+    pub ticks_event: Option<u64>,
     /// User override for the path to page files and other resources.
     pub resource_path: Option<PathBuf>,
 }
@@ -60,11 +80,104 @@ impl Flags {
     pub fn get() -> &'static Flags {
         &*FLAGS
     }
+
+    /// Use `flags` from now on. The `rd` binary passes what `init_flags()`
+    /// parsed from its command line; other programs get the defaults unless
+    /// they call this. Returns false, and changes nothing, if something has
+    /// already read the flags.
+    pub fn init(flags: Flags) -> bool {
+        let mut preset = PRESET_FLAGS.lock().unwrap();
+        if FLAGS_IN_USE.load(Ordering::SeqCst) {
+            return false;
+        }
+        *preset = Some(flags);
+        true
+    }
+
+    /// The global command-line options that give these flags, for running
+    /// another rd, e.g. the `rd replay` that `rd browse` starts.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        match self.checksum {
+            Checksum::ChecksumNone => (),
+            Checksum::ChecksumSyscall => args.push("--checksum=on-syscalls".into()),
+            Checksum::ChecksumAll => args.push("--checksum=on-all-events".into()),
+            Checksum::ChecksumAt(time) => args.push(format!("--checksum={}", time).into()),
+        }
+        match self.dump_on {
+            None => (),
+            Some(DumpOn::DumpOnAll) => args.push("--dump-on=ALL".into()),
+            Some(DumpOn::DumpOnRdtsc) => args.push("--dump-on=RDTSC".into()),
+            Some(DumpOn::DumpOnSignal(sig)) => args.push(format!("--dump-on=-{}", sig).into()),
+            Some(DumpOn::DumpOnSyscall(no)) => args.push(format!("--dump-on={}", no).into()),
+        }
+        if let Some(time) = self.dump_at {
+            args.push(format!("--dump-at={}", time).into());
+        }
+        let switches = [
+            (self.force_things, "--force-things"),
+            (self.mark_stdio, "--mark-stdio"),
+            (self.extra_compat, "--extra-compat"),
+            (self.check_cached_mmaps, "--check-cached-mmaps"),
+            (
+                self.suppress_environment_warnings,
+                "--suppress-environment-warnings",
+            ),
+            (self.fatal_errors_and_warnings, "--fatal-errors"),
+            (self.disable_cpuid_faulting, "--disable-cpuid-faulting"),
+            (
+                self.disable_ptrace_exit_events,
+                "--disable-ptrace-exit_events",
+            ),
+            (self.no_pmu, "--no-pmu"),
+        ];
+        for &(on, switch) in switches.iter() {
+            if on {
+                args.push(switch.into());
+            }
+        }
+        if let Some(uarch) = &self.forced_uarch {
+            args.push("--microarch".into());
+            args.push(uarch.into());
+        }
+        if let Some(event) = self.ticks_event {
+            args.push(format!("--ticks-event=r{:x}", event).into());
+        }
+        if let Some(path) = &self.resource_path {
+            args.push("--resource-path".into());
+            args.push(path.into());
+        }
+        args
+    }
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            checksum: Checksum::ChecksumNone,
+            dump_on: None,
+            dump_at: None,
+            force_things: false,
+            mark_stdio: false,
+            extra_compat: false,
+            check_cached_mmaps: false,
+            suppress_environment_warnings: false,
+            fatal_errors_and_warnings: false,
+            disable_cpuid_faulting: false,
+            disable_ptrace_exit_events: false,
+            no_pmu: false,
+            forced_uarch: None,
+            ticks_event: None,
+            resource_path: None,
+        }
+    }
 }
 
 pub fn init_flags() -> Flags {
-    let options = RdOptions::from_args();
+    flags_from_options(RdOptions::from_args())
+}
 
+fn flags_from_options(options: RdOptions) -> Flags {
     Flags {
         checksum: options.checksum.unwrap_or(Checksum::ChecksumNone),
         dump_on: options.dump_on,
@@ -77,7 +190,45 @@ pub fn init_flags() -> Flags {
         fatal_errors_and_warnings: options.fatal_errors,
         disable_cpuid_faulting: options.disable_cpuid_faulting,
         disable_ptrace_exit_events: options.disable_ptrace_exit_events,
+        // Rather than fail inside perf counter setup, behave as if --no-pmu was
+        // passed when the machine has no usable PMU at all. A trace recorded
+        // with --no-pmu says so in its header, so it doesn't have to be passed
+        // again to replay it.
+        no_pmu: options.no_pmu
+            || (options.cmd.runs_tracees() && Environment::get().pmu_unavailable())
+            || options.cmd.replays_trace_recorded_without_pmu(),
         forced_uarch: options.microarch,
+        ticks_event: options.ticks_event,
         resource_path: options.resource_path,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_args_round_trips() {
+        let flags = Flags {
+            checksum: Checksum::ChecksumAt(42),
+            dump_on: Some(DumpOn::DumpOnSignal(11)),
+            dump_at: Some(7),
+            mark_stdio: true,
+            fatal_errors_and_warnings: true,
+            disable_ptrace_exit_events: true,
+            forced_uarch: Some("Ivy Bridge".into()),
+            ticks_event: Some(0x5101c4),
+            resource_path: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/").into()),
+            ..Flags::default()
+        };
+        let mut argv: Vec<OsString> = vec!["rd".into()];
+        argv.extend(flags.to_args());
+        argv.push("ps".into());
+        let parsed = flags_from_options(RdOptions::from_iter(argv));
+        assert_eq!(parsed.to_args(), flags.to_args());
+        assert_eq!(parsed.checksum, flags.checksum);
+        assert_eq!(parsed.dump_on, flags.dump_on);
+        assert_eq!(parsed.ticks_event, flags.ticks_event);
+        assert!(Flags::default().to_args().is_empty());
+    }
+}
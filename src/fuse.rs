@@ -0,0 +1,567 @@
+//! A minimal, read-only FUSE server that speaks the kernel protocol on
+//! /dev/fuse directly (see include/uapi/linux/fuse.h), so rd doesn't need
+//! libfuse. It implements just enough to browse a tree of regular files and
+//! directories: lookup, getattr, readdir, open and read.
+//!
+//! Mounting goes through the setuid `fusermount3` (or `fusermount`) helper,
+//! like libfuse does, so it works without root.
+
+use crate::{
+    log::{LogDebug, LogWarn},
+    scoped_fd::ScopedFd,
+    util::{u8_slice, u8_slice_mut},
+};
+use libc::{S_IFDIR, S_IFREG};
+use nix::{
+    errno::Errno,
+    sys::{
+        socket::{
+            recvmsg, socketpair, AddressFamily, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+        },
+        uio::IoVec,
+    },
+    unistd::{getgid, getuid, read, write},
+    Error,
+};
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    mem::size_of,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+pub type Inode = u64;
+
+/// The inode of the mount point itself.
+pub const ROOT_INODE: Inode = 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    Directory,
+    RegularFile,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Attr {
+    pub ino: Inode,
+    pub kind: FileKind,
+    pub size: u64,
+}
+
+/// The read-only filesystem served by `FuseMount::serve()`. Errors are
+/// reported to the kernel as errnos.
+pub trait Filesystem {
+    /// The entry called `name` in directory `parent`.
+    fn lookup(&mut self, parent: Inode, name: &OsStr) -> Result<Attr, Errno>;
+
+    fn getattr(&mut self, ino: Inode) -> Result<Attr, Errno>;
+
+    /// The entries of directory `ino`, without "." and "..".
+    fn readdir(&mut self, ino: Inode) -> Result<Vec<(OsString, Attr)>, Errno>;
+
+    /// Up to `size` bytes of file `ino` at `offset`. Less data than asked
+    /// for means end of file.
+    fn read(&mut self, ino: Inode, offset: u64, size: u32) -> Result<Vec<u8>, Errno>;
+}
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+/// Largest read we ask the kernel to send us. /dev/fuse reads need a buffer
+/// big enough for this plus the request header.
+const MAX_READ: u32 = 128 * 1024;
+
+/// How long, in seconds, the kernel may cache our replies. Nothing ever
+/// changes while mounted.
+const CACHE_TIMEOUT: u64 = 3600;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// FuseOpenOut::open_flags: the file contents can stay in the page cache
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseInHeader {
+    len: u32,
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseOutHeader {
+    len: u32,
+    error: i32,
+    unique: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseInitIn {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseInitOut {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    max_background: u16,
+    congestion_threshold: u16,
+    max_write: u32,
+    time_gran: u32,
+    max_pages: u16,
+    map_alignment: u16,
+    flags2: u32,
+    unused: [u32; 7],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseAttr {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseEntryOut {
+    nodeid: u64,
+    generation: u64,
+    entry_valid: u64,
+    attr_valid: u64,
+    entry_valid_nsec: u32,
+    attr_valid_nsec: u32,
+    attr: FuseAttr,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseAttrOut {
+    attr_valid: u64,
+    attr_valid_nsec: u32,
+    dummy: u32,
+    attr: FuseAttr,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseOpenOut {
+    fh: u64,
+    open_flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseReadIn {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    read_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseKstatfs {
+    blocks: u64,
+    bfree: u64,
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+    padding: u32,
+    spare: [u32; 6],
+}
+
+/// The fixed part of a directory entry in a FUSE_READDIR reply. The name
+/// follows, padded to 8 bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FuseDirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    type_: u32,
+}
+
+/// A mounted FUSE filesystem. Dropping this unmounts it.
+pub struct FuseMount {
+    fd: ScopedFd,
+    mount_point: PathBuf,
+    uid: u32,
+    gid: u32,
+}
+
+impl FuseMount {
+    /// Mount an (empty, until `serve()` is called) read-only filesystem at
+    /// `mount_point`.
+    pub fn new(mount_point: &Path) -> io::Result<FuseMount> {
+        let (ours, theirs) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .map_err(nix_to_io)?;
+        let ours = ScopedFd::from_raw(ours);
+        let theirs = ScopedFd::from_raw(theirs);
+
+        // fusermount opens /dev/fuse, mounts it and sends us the fd over the
+        // socket in _FUSE_COMMFD.
+        let mut status = None;
+        for helper in &["fusermount3", "fusermount"] {
+            match Command::new(helper)
+                .env("_FUSE_COMMFD", theirs.as_raw().to_string())
+                .arg("-o")
+                .arg("ro,nosuid,nodev,fsname=rd,subtype=rd")
+                .arg("--")
+                .arg(mount_point)
+                .status()
+            {
+                Ok(s) => {
+                    status = Some(s);
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        match status {
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Couldn't find fusermount3 or fusermount. Is FUSE installed?",
+                ))
+            }
+            Some(s) if !s.success() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("fusermount failed: {}", s),
+                ))
+            }
+            Some(_) => (),
+        }
+        drop(theirs);
+
+        let fd = receive_fd(ours.as_raw())?;
+        Ok(FuseMount {
+            fd: ScopedFd::from_raw(fd),
+            mount_point: mount_point.to_owned(),
+            uid: getuid().as_raw(),
+            gid: getgid().as_raw(),
+        })
+    }
+
+    /// Answer requests for `fs` until the filesystem is unmounted, e.g. with
+    /// `fusermount -u`.
+    pub fn serve(&self, fs: &mut dyn Filesystem) -> io::Result<()> {
+        let mut buf = vec![0u8; MAX_READ as usize + 4096];
+        loop {
+            let nread = match read(self.fd.as_raw(), &mut buf) {
+                Ok(nread) => nread,
+                // ENOENT: the request was interrupted before we read it
+                Err(Error::Sys(Errno::EINTR)) | Err(Error::Sys(Errno::ENOENT)) => continue,
+                // The filesystem was unmounted
+                Err(Error::Sys(Errno::ENODEV)) => return Ok(()),
+                Err(e) => return Err(nix_to_io(e)),
+            };
+            if nread < size_of::<FuseInHeader>() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Short read from /dev/fuse",
+                ));
+            }
+            let header: FuseInHeader = read_struct(&buf);
+            let arg = &buf[size_of::<FuseInHeader>()..nread];
+            log!(
+                LogDebug,
+                "FUSE request {} for inode {}",
+                header.opcode,
+                header.nodeid
+            );
+            if header.opcode == FUSE_DESTROY {
+                return Ok(());
+            }
+            self.dispatch(fs, &header, arg)?;
+        }
+    }
+
+    fn dispatch(
+        &self,
+        fs: &mut dyn Filesystem,
+        header: &FuseInHeader,
+        arg: &[u8],
+    ) -> io::Result<()> {
+        let ino = header.nodeid;
+        let result = match header.opcode {
+            FUSE_INIT => {
+                let init_in: FuseInitIn = read_struct(arg);
+                if init_in.major != FUSE_KERNEL_VERSION {
+                    log!(
+                        LogWarn,
+                        "Unsupported FUSE protocol version {}.{}",
+                        init_in.major,
+                        init_in.minor
+                    );
+                    Err(Errno::EPROTO)
+                } else {
+                    let init_out = FuseInitOut {
+                        major: FUSE_KERNEL_VERSION,
+                        minor: FUSE_KERNEL_MINOR_VERSION,
+                        max_readahead: init_in.max_readahead,
+                        max_write: MAX_READ,
+                        time_gran: 1,
+                        ..Default::default()
+                    };
+                    Ok(u8_slice(&init_out).to_vec())
+                }
+            }
+            // No reply expected
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return Ok(()),
+            FUSE_LOOKUP => {
+                let name = arg.split(|&b| b == 0).next().unwrap_or(&[]);
+                fs.lookup(ino, OsStr::from_bytes(name)).map(|attr| {
+                    let entry_out = FuseEntryOut {
+                        nodeid: attr.ino,
+                        entry_valid: CACHE_TIMEOUT,
+                        attr_valid: CACHE_TIMEOUT,
+                        attr: self.fuse_attr(&attr),
+                        ..Default::default()
+                    };
+                    u8_slice(&entry_out).to_vec()
+                })
+            }
+            FUSE_GETATTR => fs.getattr(ino).map(|attr| {
+                let attr_out = FuseAttrOut {
+                    attr_valid: CACHE_TIMEOUT,
+                    attr: self.fuse_attr(&attr),
+                    ..Default::default()
+                };
+                u8_slice(&attr_out).to_vec()
+            }),
+            FUSE_OPEN | FUSE_OPENDIR => {
+                let open_out = FuseOpenOut {
+                    open_flags: FOPEN_KEEP_CACHE,
+                    ..Default::default()
+                };
+                Ok(u8_slice(&open_out).to_vec())
+            }
+            FUSE_READ => {
+                let read_in: FuseReadIn = read_struct(arg);
+                fs.read(ino, read_in.offset, read_in.size.min(MAX_READ))
+            }
+            FUSE_READDIR => {
+                let read_in: FuseReadIn = read_struct(arg);
+                fs.readdir(ino)
+                    .map(|entries| dirents(&entries, read_in.offset, read_in.size as usize))
+            }
+            FUSE_RELEASE | FUSE_RELEASEDIR => Ok(Vec::new()),
+            FUSE_STATFS => {
+                let statfs_out = FuseKstatfs {
+                    bsize: 4096,
+                    frsize: 4096,
+                    namelen: 255,
+                    ..Default::default()
+                };
+                Ok(u8_slice(&statfs_out).to_vec())
+            }
+            _ => Err(Errno::ENOSYS),
+        };
+        self.reply(header.unique, result)
+    }
+
+    fn reply(&self, unique: u64, result: Result<Vec<u8>, Errno>) -> io::Result<()> {
+        let (error, data) = match result {
+            Ok(data) => (0, data),
+            Err(errno) => (-(errno as i32), Vec::new()),
+        };
+        let out_header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + data.len()) as u32,
+            error,
+            unique,
+        };
+        let mut packet = u8_slice(&out_header).to_vec();
+        packet.extend_from_slice(&data);
+        match write(self.fd.as_raw(), &packet) {
+            Ok(_) => Ok(()),
+            // The request was interrupted and the kernel no longer wants a reply
+            Err(Error::Sys(Errno::ENOENT)) => Ok(()),
+            Err(e) => Err(nix_to_io(e)),
+        }
+    }
+
+    fn fuse_attr(&self, attr: &Attr) -> FuseAttr {
+        let (mode, nlink) = match attr.kind {
+            FileKind::Directory => (S_IFDIR | 0o555, 2),
+            FileKind::RegularFile => (S_IFREG | 0o444, 1),
+        };
+        FuseAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: (attr.size + 511) / 512,
+            mode,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            blksize: 4096,
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        // Harmless if the user already unmounted it.
+        for helper in &["fusermount3", "fusermount"] {
+            let result = Command::new(helper)
+                .arg("-u")
+                .arg("-q")
+                .arg("-z")
+                .arg("--")
+                .arg(&self.mount_point)
+                .status();
+            if result.is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+/// Encode the directory entries from index `offset` on, as many as fit in
+/// `size` bytes. Each entry's `off` is the index of the one after it.
+fn dirents(entries: &[(OsString, Attr)], offset: u64, size: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (i, (name, attr)) in entries.iter().enumerate().skip(offset as usize) {
+        let name = name.as_bytes();
+        let entry_len = size_of::<FuseDirent>() + name.len();
+        let padded_len = (entry_len + 7) & !7;
+        if out.len() + padded_len > size {
+            break;
+        }
+        let dirent = FuseDirent {
+            ino: attr.ino,
+            off: i as u64 + 1,
+            namelen: name.len() as u32,
+            type_: match attr.kind {
+                FileKind::Directory => libc::DT_DIR as u32,
+                FileKind::RegularFile => libc::DT_REG as u32,
+            },
+        };
+        out.extend_from_slice(u8_slice(&dirent));
+        out.extend_from_slice(name);
+        out.resize(out.len() + padded_len - entry_len, 0);
+    }
+    out
+}
+
+/// The fd sent by fusermount over `sock`.
+fn receive_fd(sock: RawFd) -> io::Result<RawFd> {
+    let mut byte = [0u8; 1];
+    let iov = [IoVec::from_mut_slice(&mut byte)];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+    let msg = recvmsg(sock, &iov, Some(&mut cmsg_buffer), MsgFlags::empty()).map_err(nix_to_io)?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(&fd) = fds.first() {
+                return Ok(fd);
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "fusermount didn't send a /dev/fuse fd",
+    ))
+}
+
+/// Read a `T` from the start of `buf`, zero-filling whatever `buf` is too
+/// short for. Older kernels send shorter versions of some structs.
+fn read_struct<T: Copy + Default>(buf: &[u8]) -> T {
+    let mut val = T::default();
+    let len = buf.len().min(size_of::<T>());
+    u8_slice_mut(&mut val)[..len].copy_from_slice(&buf[..len]);
+    val
+}
+
+fn nix_to_io(e: Error) -> io::Error {
+    match e {
+        Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirents_are_padded_and_resumable() {
+        let attr = Attr {
+            ino: 2,
+            kind: FileKind::RegularFile,
+            size: 0,
+        };
+        let entries = vec![
+            (OsString::from("a"), attr),
+            (OsString::from("event"), attr),
+            (OsString::from("maps"), attr),
+        ];
+        let all = dirents(&entries, 0, 4096);
+        let entry_size = size_of::<FuseDirent>() + 8;
+        assert_eq!(all.len(), 3 * entry_size);
+
+        // Only room for two, and the next call picks up at the third.
+        let first = dirents(&entries, 0, 2 * entry_size + 4);
+        assert_eq!(first.len(), 2 * entry_size);
+        let rest = dirents(&entries, 2, 4096);
+        assert_eq!(rest.len(), entry_size);
+        let dirent: FuseDirent = read_struct(&rest);
+        assert_eq!(dirent.off, 3);
+        assert_eq!(dirent.namelen, 4);
+    }
+}
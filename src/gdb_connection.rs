@@ -3,35 +3,38 @@
 //! <http://sourceware.org/gdb/onlinedocs/gdb/Packets.html>
 
 use crate::{
+    gdb_perf::GdbPerfStats,
     gdb_register::GdbRegister,
+    gdb_target_desc::{read_target_desc, target_description},
     log::LogLevel::{LogDebug, LogError, LogInfo, LogWarn},
     registers::MAX_REG_SIZE_BYTES,
     remote_ptr::{RemotePtr, Void},
     replay_timeline::RunDirection,
     scoped_fd::ScopedFd,
-    session::SessionSharedPtr,
+    session::{
+        address_space::{WatchConfig, WatchType},
+        SessionSharedPtr,
+    },
+    sig,
     sig::Sig,
-    util,
-    util::{resource_path, str0_to_isize, str16_to_isize, str16_to_usize, u8_slice, u8_slice_mut},
+    util::{str0_to_isize, str16_to_isize, str16_to_usize, u8_slice, u8_slice_mut},
 };
 use libc::pid_t;
 use memchr::memchr;
 use nix::{
     errno::Errno,
-    fcntl::OFlag,
     poll::{poll, PollFd, PollFlags},
     sys::socket::accept,
-    unistd,
-    unistd::read,
-    Error,
+    unistd, Error,
 };
 use std::{
-    convert::TryInto,
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
     ffi::{OsStr, OsString},
     fmt::{self, Display, Write as OtherWrite},
     io::Write,
     mem::size_of_val,
-    os::unix::ffi::{OsStrExt, OsStringExt},
+    os::unix::ffi::OsStrExt,
 };
 
 include!(concat!(
@@ -174,6 +177,15 @@ impl Display for GdbThreadId {
     }
 }
 
+/// What lldb's qProcessInfo and qProcessInfoPID packets report about a
+/// process.
+pub struct GdbProcessInfo {
+    pub pid: pid_t,
+    pub parent_pid: Option<pid_t>,
+    /// The executable to load symbols from.
+    pub name: OsString,
+}
+
 #[derive(Default, Clone)]
 pub struct GdbRequest {
     pub type_: GdbRequestType,
@@ -241,6 +253,8 @@ impl GdbRequest {
             | DREQ_GET_THREAD_LIST
             | DREQ_INTERRUPT
             | DREQ_WRITE_SIGINFO
+            | DREQ_ATTACH
+            | DREQ_GET_PROCESS_INFO
             | DREQ_DETACH => GdbRequestValue::GdbRequestNoAddlData,
             _ => panic!("Unknown DREQ: {}", type_),
         };
@@ -615,6 +629,31 @@ pub mod gdb_request {
     }
 }
 
+/// What the connected client said it supports in its qSupported packet.
+/// Protocol behavior that depends on the client should be decided from these
+/// rather than from its version number, which distributions patch freely.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct GdbClientFeatures {
+    /// The multiprocess extension
+    pub multiprocess: bool,
+    /// The `exec` stop reason
+    pub exec_events: bool,
+}
+
+impl GdbClientFeatures {
+    fn from_qsupported(args: &[u8]) -> GdbClientFeatures {
+        let mut features = GdbClientFeatures::default();
+        for feature in args.split(|&c| c == b';') {
+            match feature {
+                b"multiprocess+" => features.multiprocess = true,
+                b"exec-events+" => features.exec_events = true,
+                _ => (),
+            }
+        }
+        features
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct GdbConnectionFeatures {
     pub reverse_execution: bool,
@@ -655,14 +694,38 @@ pub struct GdbConnection {
     outbuf: Vec<u8>,
     features_: GdbConnectionFeatures,
     connection_alive_: bool,
-    /// client supports multiprocess extension
-    multiprocess_supported_: bool,
+    client_features_: GdbClientFeatures,
+    /// true when gdb has asked for non-stop mode with QNonStop:1.
+    ///
+    /// DIFF NOTE: rr only supports all-stop mode. A replay can't run one thread
+    /// while leaving the others stopped, so rd speaks the non-stop protocol but
+    /// keeps all-stop semantics: resuming any thread runs the replay, and when
+    /// one thread stops every thread is reported stopped.
+    non_stop: bool,
+    /// The resume request that a request gdb sent while the target was running
+    /// interrupted. It's resumed once that request has been answered.
+    /// Only used in non-stop mode.
+    running_resume: Option<GdbRequest>,
+    /// true from sending a %Stop notification until gdb's vStopped requests
+    /// have drained `pending_stop_replies`.
+    stop_notification_pending: bool,
+    /// Stop replies waiting for gdb to ask for them with vStopped.
+    pending_stop_replies: VecDeque<Vec<u8>>,
+    /// The threads of the debuggee gdb was last told about. In non-stop mode
+    /// all of them are reported stopped whenever one of them stops.
+    known_threads: Vec<GdbThreadId>,
+    /// true if the pending DREQ_GET_PROCESS_INFO came from qProcessInfoPID,
+    /// which lldb expects in a different format from qProcessInfo.
+    process_info_by_pid: bool,
+    stats: GdbPerfStats,
 }
 
 impl GdbConnection {
     pub const CPU_64BIT: u32 = 0x1;
     pub const CPU_AVX: u32 = 0x2;
     pub const CPU_64BIT_AND_CPU_AVX: u32 = 0x1 | 0x2;
+    /// Only ever set along with CPU_AVX.
+    pub const CPU_AVX512: u32 = 0x4;
 
     pub fn new(tgid: pid_t, features: GdbConnectionFeatures) -> GdbConnection {
         GdbConnection {
@@ -679,7 +742,14 @@ impl GdbConnection {
             inbuf: Default::default(),
             packetend: Default::default(),
             outbuf: Default::default(),
-            multiprocess_supported_: Default::default(),
+            client_features_: Default::default(),
+            non_stop: false,
+            running_resume: None,
+            stop_notification_pending: false,
+            pending_stop_replies: Default::default(),
+            known_threads: Default::default(),
+            process_info_by_pid: false,
+            stats: Default::default(),
         }
     }
 
@@ -780,6 +850,12 @@ impl GdbConnection {
                 return self.req.clone();
             }
 
+            if self.req.is_resume_request() && !self.sniff_packet() {
+                // In non-stop mode gdb can send requests while the target
+                // is running. They've been answered, so keep running.
+                return self.req.clone();
+            }
+
             // The packet we got was "internal", gdb details.
             // Nothing for the target to do yet.  Keep waiting.
         }
@@ -794,8 +870,9 @@ impl GdbConnection {
 
         let mut buf = Vec::<u8>::new();
         write!(buf, "W{:02x}", code).unwrap();
-        self.write_packet_bytes(&buf);
+        self.send_stop(buf);
 
+        self.running_resume = None;
         self.consume_request();
     }
 
@@ -809,8 +886,9 @@ impl GdbConnection {
 
         let mut buf = Vec::<u8>::new();
         write!(buf, "X{:02x}", sig.as_raw()).unwrap();
-        self.write_packet_bytes(&buf);
+        self.send_stop(buf);
 
+        self.running_resume = None;
         self.consume_request();
     }
 
@@ -821,7 +899,7 @@ impl GdbConnection {
         &mut self,
         thread: GdbThreadId,
         maybe_sig: Option<Sig>,
-        watch_addr: RemotePtr<u8>,
+        maybe_watch: Option<WatchConfig>,
     ) {
         debug_assert!(self.req.is_resume_request() || self.req.type_ == DREQ_INTERRUPT);
 
@@ -836,7 +914,32 @@ impl GdbConnection {
             // the next stop we're willing to tell gdb about.
             return;
         }
-        self.send_stop_reply_packet(thread, maybe_sig, watch_addr);
+        let reply = self.stop_reply(thread, maybe_sig, maybe_watch);
+        self.finish_stop(thread, reply);
+    }
+
+    /// Notify the host that `thread` stopped because its process exec'd
+    /// `exe_image`. gdb loads the symbols of the new image if it understands
+    /// the `exec` stop reason, otherwise this is reported like a breakpoint.
+    pub fn notify_exec(&mut self, thread: GdbThreadId, exe_image: &OsStr) {
+        debug_assert!(self.req.is_resume_request() || self.req.type_ == DREQ_INTERRUPT);
+
+        let mut reply = self.stop_reply(thread, Some(sig::SIGTRAP), None);
+        if self.client_features_.exec_events {
+            reply.extend_from_slice(b"exec:");
+            for b in exe_image.as_bytes() {
+                write!(reply, "{:02x}", b).unwrap();
+            }
+            reply.push(b';');
+        }
+        self.finish_stop(thread, reply);
+    }
+
+    fn finish_stop(&mut self, thread: GdbThreadId, reply: Vec<u8>) {
+        self.send_stop(reply);
+        if self.non_stop {
+            self.queue_other_threads_stopped(thread);
+        }
 
         // This isn't documented in the gdb remote protocol, but if we
         // don't do this, gdb will sometimes continue to send requests
@@ -863,6 +966,7 @@ impl GdbConnection {
         }
 
         self.query_thread = self.resume_thread;
+        self.running_resume = None;
         self.consume_request();
     }
 
@@ -881,7 +985,7 @@ impl GdbConnection {
         debug_assert_eq!(DREQ_GET_CURRENT_THREAD, self.req.type_);
 
         let mut buf = Vec::<u8>::new();
-        if self.multiprocess_supported_ {
+        if self.client_features_.multiprocess {
             write!(buf, "QCp{:02x}.{:02x}", thread.pid, thread.tid).unwrap();
         } else {
             write!(buf, "QC{:02x}", thread.tid).unwrap();
@@ -1074,23 +1178,103 @@ impl GdbConnection {
     pub fn reply_get_stop_reason(&mut self, which: GdbThreadId, maybe_sig: Option<Sig>) {
         debug_assert_eq!(DREQ_GET_STOP_REASON, self.req.type_);
 
-        self.send_stop_reply_packet(which, maybe_sig, RemotePtr::null());
+        let reply = self.stop_reply(which, maybe_sig, None);
+        self.write_packet_bytes(&reply);
+        if self.non_stop {
+            // gdb collects the stop replies of the other threads with vStopped.
+            self.stop_notification_pending = true;
+            self.pending_stop_replies.clear();
+            self.queue_other_threads_stopped(which);
+        }
+
+        self.consume_request();
+    }
+
+    /// Reply to the DREQ_ATTACH request. `maybe_thread` is a thread of the
+    /// process that is now being debugged, or None if there is no such process.
+    pub fn reply_attach(&mut self, maybe_thread: Option<GdbThreadId>) {
+        debug_assert_eq!(DREQ_ATTACH, self.req.type_);
+
+        match maybe_thread {
+            Some(thread) => {
+                let reply = self.stop_reply(thread, None, None);
+                self.write_packet_bytes(&reply);
+                self.resume_thread = thread;
+                self.query_thread = thread;
+            }
+            None => self.write_packet_bytes(b"E01"),
+        }
+
+        self.consume_request();
+    }
+
+    /// Reply to the DREQ_GET_PROCESS_INFO request. `maybe_info` is None if
+    /// there is no such process.
+    pub fn reply_get_process_info(&mut self, maybe_info: Option<&GdbProcessInfo>) {
+        debug_assert_eq!(DREQ_GET_PROCESS_INFO, self.req.type_);
+
+        match maybe_info {
+            Some(info) => {
+                let mut reply = Vec::<u8>::new();
+                if self.process_info_by_pid {
+                    // The format of lldb-server's qProcessInfoPID reply.
+                    write!(reply, "pid:{};", info.pid).unwrap();
+                    if let Some(ppid) = info.parent_pid {
+                        write!(reply, "ppid:{};", ppid).unwrap();
+                    }
+                    write!(reply, "name:").unwrap();
+                    for &b in info.name.as_bytes() {
+                        write!(reply, "{:02x}", b).unwrap();
+                    }
+                    write!(reply, ";").unwrap();
+                } else {
+                    // The format of debugserver's qProcessInfo reply.
+                    write!(reply, "pid:{:x};", info.pid).unwrap();
+                    if let Some(ppid) = info.parent_pid {
+                        write!(reply, "parent-pid:{:x};", ppid).unwrap();
+                    }
+                }
+                self.write_target_info(&mut reply);
+                self.write_packet_bytes(&reply);
+            }
+            None => self.write_packet_bytes(b"E01"),
+        }
 
         self.consume_request();
     }
 
+    pub fn stats(&self) -> &GdbPerfStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut GdbPerfStats {
+        &mut self.stats
+    }
+
+    /// Make `tgid` the process that stops are reported for.
+    pub fn set_debuggee_tgid(&mut self, tgid: pid_t) {
+        log!(LogDebug, "Now debugging tgid {}", tgid);
+        self.tgid = tgid;
+    }
+
     /// `threads` contains the list of live threads.
+    ///
+    /// DIFF NOTE: When gdb supports the multiprocess extension the threads of
+    /// every process are listed, so that gdb can switch to another process
+    /// with Hg/Hc or vAttach. rr only lists the debuggee's threads.
     pub fn reply_get_thread_list(&mut self, threads: &[GdbThreadId]) {
         debug_assert_eq!(DREQ_GET_THREAD_LIST, self.req.type_);
+        let tgid = self.tgid;
+        self.known_threads = threads.iter().copied().filter(|t| t.pid == tgid).collect();
         if threads.is_empty() {
             self.write_packet_bytes(b"l");
         } else {
             let mut buf = vec![b'm'];
             for &t in threads {
-                if self.tgid != t.pid {
+                if self.tgid != t.pid && !self.client_features_.multiprocess {
                     continue;
                 }
-                if self.multiprocess_supported_ {
+                if self.client_features_.multiprocess {
                     // Note the trailing `,`
                     write!(buf, "p{:02x}.{:02x},", t.pid, t.tid).unwrap();
                 } else {
@@ -1324,6 +1508,7 @@ impl GdbConnection {
                 self.connection_alive_ = false;
             }
             Ok(nread) => {
+                self.stats.bytes_read += nread as u64;
                 self.inbuf.extend_from_slice(&buf[0..nread]);
             }
         }
@@ -1354,6 +1539,7 @@ impl GdbConnection {
                     return;
                 }
                 Ok(nwritten) => {
+                    self.stats.bytes_written += nwritten as u64;
                     write_index += nwritten;
                 }
             }
@@ -1374,9 +1560,19 @@ impl GdbConnection {
     }
 
     fn write_packet_bytes(&mut self, data: &[u8]) {
+        self.write_framed_bytes(b"$", data);
+    }
+
+    /// Notifications are framed like packets but start with '%', and gdb
+    /// doesn't acknowledge them.
+    fn write_notification_bytes(&mut self, data: &[u8]) {
+        self.write_framed_bytes(b"%", data);
+    }
+
+    fn write_framed_bytes(&mut self, start: &[u8], data: &[u8]) {
         let mut checksum: u8 = 0;
 
-        self.write_data_raw(b"$");
+        self.write_data_raw(start);
         for &b in data {
             checksum = checksum.overflowing_add(b).0;
         }
@@ -1528,6 +1724,7 @@ impl GdbConnection {
             self.write_data_raw(b"+");
             self.write_flush();
         }
+        self.stats.packet_received();
     }
 
     /// Return true if we need to do something in a debugger request,
@@ -1628,13 +1825,13 @@ impl GdbConnection {
                 return false;
             }
 
-            let filename = if annex != b"" && annex != b"target.xml" {
-                annex
+            if annex != b"" && annex != b"target.xml" {
+                let target_desc = read_target_desc(annex).unwrap();
+                self.write_xfer_response(&target_desc, offset, len);
             } else {
-                target_description_name(self.cpu_features_)
-            };
-            let target_desc = read_target_desc(filename).unwrap();
-            self.write_xfer_response(&target_desc, offset, len);
+                let target_desc = target_description(self.cpu_features_);
+                self.write_xfer_response(&target_desc, offset, len);
+            }
             return false;
         }
 
@@ -1642,6 +1839,26 @@ impl GdbConnection {
         false
     }
 
+    /// Append the target description fields of lldb's qHostInfo and
+    /// qProcessInfo replies to `info`.
+    fn write_target_info(&self, info: &mut Vec<u8>) {
+        let (triple, ptrsize): (&[u8], usize) = if self.cpu_features_ & Self::CPU_64BIT != 0 {
+            (b"x86_64-pc-linux-gnu", 8)
+        } else {
+            (b"i386-pc-linux-gnu", 4)
+        };
+        write!(info, "triple:").unwrap();
+        for &b in triple {
+            write!(info, "{:02x}", b).unwrap();
+        }
+        write!(
+            info,
+            ";ostype:linux;vendor:pc;endian:little;ptrsize:{};",
+            ptrsize
+        )
+        .unwrap();
+    }
+
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
     fn query(&mut self, payload: &[u8]) -> bool {
@@ -1716,6 +1933,44 @@ impl GdbConnection {
             return true;
         }
 
+        if name == b"HostInfo" {
+            // lldb asks for this before anything else and refuses to
+            // connect if it can't work out the target's triple.
+            log!(LogDebug, "lldb asks for host info");
+            let mut info = Vec::<u8>::new();
+            self.write_target_info(&mut info);
+            self.write_packet_bytes(&info);
+            return false;
+        }
+
+        if name == b"ProcessInfo" {
+            log!(LogDebug, "lldb asks for process info");
+            self.req = GdbRequest::new(DREQ_GET_PROCESS_INFO);
+            self.req.target = self.query_thread;
+            self.process_info_by_pid = false;
+            return true;
+        }
+
+        if name == b"ProcessInfoPID" {
+            let maybe_pid = maybe_args
+                .and_then(|args| std::str::from_utf8(args).ok())
+                .and_then(|s| s.parse::<pid_t>().ok())
+                .filter(|&pid| pid > 0);
+            match maybe_pid {
+                Some(pid) => {
+                    log!(LogDebug, "lldb asks for info about process {}", pid);
+                    self.req = GdbRequest::new(DREQ_GET_PROCESS_INFO);
+                    self.req.target = GdbThreadId::new(pid, -1);
+                    self.process_info_by_pid = true;
+                    return true;
+                }
+                None => {
+                    self.write_packet_bytes(b"E01");
+                    return false;
+                }
+            }
+        }
+
         if b'P' == name[0] {
             // The docs say not to use this packet ...
             self.write_packet_bytes(b"");
@@ -1724,10 +1979,9 @@ impl GdbConnection {
 
         if name == b"Supported" {
             let args = maybe_args.unwrap();
-            // TODO process these
             log!(LogDebug, "gdb supports {:?}", OsStr::from_bytes(args));
 
-            self.multiprocess_supported_ = util::find(args, b"multiprocess+").is_some();
+            self.client_features_ = GdbClientFeatures::from_qsupported(args);
 
             let mut supported = Vec::<u8>::new();
             // Encourage gdb to use very large packets since we support any packet size
@@ -1741,7 +1995,9 @@ impl GdbConnection {
                  ;qXfer:siginfo:read+\
                  ;qXfer:siginfo:write+\
                  ;multiprocess+\
+                 ;exec-events+\
                  ;ConditionalBreakpoints+\
+                 ;QNonStop+\
                  ;vContSupported+"
             )
             .unwrap();
@@ -1864,6 +2120,19 @@ impl GdbConnection {
         if name == b"StartNoAckMode" {
             self.write_packet_bytes(b"OK");
             self.no_ack = true;
+        } else if name == b"NonStop" {
+            self.non_stop = maybe_args_loc.map(|l| &payload[l + 1..]) == Some(&b"1"[..]);
+            log!(LogDebug, "gdb sets non-stop mode to {}", self.non_stop);
+            self.stop_notification_pending = false;
+            self.pending_stop_replies.clear();
+            self.write_packet_bytes(b"OK");
+        } else if name == b"ThreadSuffixSupported"
+            || name == b"ListThreadsInStopReply"
+            || name == b"EnableErrorStrings"
+        {
+            // lldb probes for these extensions on every connection. It copes
+            // fine with them being unsupported, so don't log them as unhandled.
+            self.write_packet_bytes(b"");
         } else {
             unhandled_req!(
                 self,
@@ -1890,6 +2159,8 @@ impl GdbConnection {
             let mut args = maybe_args.unwrap();
             let mut actions: Vec<GdbContAction> = Vec::new();
             let mut maybe_default_action: Option<GdbContAction> = None;
+            // Threads gdb wants stopped with `t`, in non-stop mode.
+            let mut stop_targets: Vec<GdbThreadId> = Vec::new();
 
             while !args.is_empty() {
                 let mut cmd = Vec::new();
@@ -1948,6 +2219,10 @@ impl GdbConnection {
                     b's' => {
                         action = GdbActionType::ActionStep;
                     }
+                    b't' if self.non_stop && cmd.len() == 1 => {
+                        stop_targets.push(if is_default { GdbThreadId::ALL } else { target });
+                        continue;
+                    }
                     _ => {
                         unhandled_req!(
                             self,
@@ -1998,6 +2273,14 @@ impl GdbConnection {
                 actions.push(default_action);
             }
 
+            if !stop_targets.is_empty() {
+                if !actions.is_empty() {
+                    unhandled_req!(self, "Unhandled vCont mixing `t` with resume actions");
+                    return false;
+                }
+                return self.stop_threads(&stop_targets);
+            }
+
             self.req = GdbRequest::new(DREQ_CONT);
             self.req.cont_mut().run_direction = RunDirection::RunForward;
             self.req.cont_mut().actions = actions;
@@ -2006,7 +2289,18 @@ impl GdbConnection {
 
         if name == b"Cont?" {
             log!(LogDebug, "gdb queries which continue commands we support");
-            self.write_packet_bytes(b"vCont;c;C;s;S;");
+            self.write_packet_bytes(b"vCont;c;C;s;S;t;");
+            return false;
+        }
+
+        if name == b"Stopped" {
+            match self.pending_stop_replies.pop_front() {
+                Some(reply) => self.write_packet_bytes(&reply),
+                None => {
+                    self.stop_notification_pending = false;
+                    self.write_packet_bytes(b"OK");
+                }
+            }
             return false;
         }
 
@@ -2020,6 +2314,30 @@ impl GdbConnection {
             return false;
         }
 
+        if name == b"Attach" {
+            let mut args = maybe_args.unwrap_or_default();
+            let maybe_pid = str16_to_isize(args, &mut args)
+                .ok()
+                .and_then(|pid| pid_t::try_from(pid).ok())
+                .filter(|&pid| pid > 0 && args.is_empty());
+            let pid = match maybe_pid {
+                Some(pid) => pid,
+                None => {
+                    log!(
+                        LogWarn,
+                        "Malformed vAttach packet {}",
+                        String::from_utf8_lossy(payload)
+                    );
+                    self.write_packet_bytes(b"E01");
+                    return false;
+                }
+            };
+            self.req = GdbRequest::new(DREQ_ATTACH);
+            self.req.target = GdbThreadId::new(pid, -1);
+            log!(LogDebug, "gdb asks to attach to {}", self.req.target.pid);
+            return true;
+        }
+
         if name == b"Run" {
             let mut args = maybe_args.unwrap();
             self.req = GdbRequest::new(DREQ_RESTART);
@@ -2232,6 +2550,12 @@ impl GdbConnection {
             return true;
         }
 
+        self.running_resume = if self.non_stop && self.req.is_resume_request() {
+            Some(self.req.clone())
+        } else {
+            None
+        };
+
         let request = self.inbuf[1];
         let request_c: char = request.into();
         // @TODO Avoid this??
@@ -2495,24 +2819,91 @@ impl GdbConnection {
         // If we processed the request internally, consume it.
         if !ret {
             self.consume_request();
+        } else if self.non_stop && self.req.is_resume_request() {
+            // In non-stop mode resume requests are acknowledged right away and
+            // the stop is reported later with a notification.
+            self.running_resume = None;
+            self.write_packet_bytes(b"OK");
+            self.write_flush();
         }
 
         ret
     }
 
     fn consume_request(&mut self) {
-        self.req = GdbRequest::new(DREQ_NONE);
+        self.req = match &self.running_resume {
+            Some(req) => req.clone(),
+            None => GdbRequest::new(DREQ_NONE),
+        };
         self.write_flush()
     }
 
-    fn send_stop_reply_packet(
-        &mut self,
+    /// Handle `vCont;t`. Return true if the target has to stop running.
+    fn stop_threads(&mut self, targets: &[GdbThreadId]) -> bool {
+        log!(LogDebug, "gdb asks to stop {} thread(s)", targets.len());
+        self.write_packet_bytes(b"OK");
+        match self.running_resume.take() {
+            Some(_) => {
+                // We can only stop every thread, which notify_stop() will
+                // report.
+                self.write_flush();
+                self.req = GdbRequest::new(DREQ_INTERRUPT);
+                true
+            }
+            None => {
+                // Everything is already stopped. gdb still expects a
+                // notification for each thread.
+                for &target in targets {
+                    let threads = if target.tid <= 0 {
+                        self.known_threads.clone()
+                    } else {
+                        vec![target]
+                    };
+                    for thread in threads {
+                        let reply = self.stop_reply(thread, None, None);
+                        self.send_stop(reply);
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// Report a stop to gdb: as the reply to the resume request in all-stop
+    /// mode, or as a %Stop notification in non-stop mode. If gdb hasn't yet
+    /// collected the stops of the last notification, queue it for vStopped.
+    fn send_stop(&mut self, reply: Vec<u8>) {
+        if !self.non_stop {
+            self.write_packet_bytes(&reply);
+        } else if self.stop_notification_pending {
+            self.pending_stop_replies.push_back(reply);
+        } else {
+            let mut buf = b"Stop:".to_vec();
+            buf.extend_from_slice(&reply);
+            self.write_notification_bytes(&buf);
+            self.stop_notification_pending = true;
+        }
+    }
+
+    /// Queue stop replies for the threads other than `thread`: they stop
+    /// whenever it does.
+    fn queue_other_threads_stopped(&mut self, thread: GdbThreadId) {
+        for other in self.known_threads.clone() {
+            if other != thread {
+                let reply = self.stop_reply(other, None, None);
+                self.pending_stop_replies.push_back(reply);
+            }
+        }
+    }
+
+    fn stop_reply(
+        &self,
         thread: GdbThreadId,
         maybe_sig: Option<Sig>,
-        watch_addr: RemotePtr<u8>,
-    ) {
+        maybe_watch: Option<WatchConfig>,
+    ) -> Vec<u8> {
         let mut buf = Vec::<u8>::new();
-        if self.multiprocess_supported_ {
+        if self.client_features_.multiprocess {
             write!(
                 buf,
                 "T{:02x}thread:p{:02x}.{:02x};",
@@ -2531,11 +2922,22 @@ impl GdbConnection {
             .unwrap();
         }
 
-        if !watch_addr.is_null() {
-            write!(buf, "watch:{:x};", watch_addr.as_usize()).unwrap();
+        if let Some(watch) = maybe_watch {
+            // gdb looks the address up in its own watchpoint list and prints
+            // the old and new values itself. Read watchpoints are implemented
+            // as access watchpoints so they are reported as such.
+            match watch.type_ {
+                WatchType::WatchWrite => {
+                    write!(buf, "watch:{:x};", watch.addr.as_usize()).unwrap();
+                }
+                WatchType::WatchReadWrite => {
+                    write!(buf, "awatch:{:x};", watch.addr.as_usize()).unwrap();
+                }
+                WatchType::WatchExec => (),
+            }
         }
 
-        self.write_packet_bytes(&buf);
+        buf
     }
 
     fn send_file_error_reply(&mut self, system_errno: i32) {
@@ -2845,40 +3247,6 @@ fn read_binary_data(payload: &[u8], data: &mut Vec<u8>) {
     }
 }
 
-fn target_description_name(cpu_features: u32) -> &'static [u8] {
-    // This doesn't scale, but it's what gdb does...
-    match cpu_features {
-        0 => b"i386-linux.xml",
-        GdbConnection::CPU_64BIT => b"amd64-linux.xml",
-        GdbConnection::CPU_AVX => b"i386-avx-linux.xml",
-        GdbConnection::CPU_64BIT_AND_CPU_AVX => b"amd64-avx-linux.xml",
-        _ => fatal!("Unknown features"),
-    }
-}
-
-fn read_target_desc(file_name: &[u8]) -> Result<Vec<u8>, Error> {
-    let mut path = resource_path().as_bytes().to_vec();
-    path.extend_from_slice(b"share/rd/");
-    path.extend_from_slice(file_name);
-    let f = ScopedFd::open_path(path.as_slice(), OFlag::O_RDONLY);
-    // DIFF NOTE: This is a debug assert in rr. Why?
-    assert!(f.is_open(), "Could not open {:?}", OsString::from_vec(path));
-    let mut buf = [0u8; 4 * 1024];
-    let mut text_buf = Vec::<u8>::with_capacity(4 * 1024);
-    loop {
-        let bytes_result = read(f.as_raw(), &mut buf);
-        match bytes_result {
-            Err(e) => return Err(e),
-            Ok(0) => break,
-            Ok(nread) => {
-                text_buf.extend_from_slice(&buf[0..nread]);
-            }
-        }
-    }
-
-    Ok(text_buf)
-}
-
 /// Read the encoded register value in `strp` into `reg`.  `strp` may
 /// be mutated.
 fn read_reg_value(strp: &mut &[u8], reg: &mut GdbRegisterValue) {
@@ -2891,7 +3259,8 @@ fn read_reg_value(strp: &mut &[u8], reg: &mut GdbRegisterValue) {
     }
 
     reg.defined = true;
-    assert_eq!(numstr.len() % 2, 0);
+    parser_assert_eq!(numstr.len() % 2, 0);
+    parser_assert!(numstr.len() / 2 <= GdbRegisterValue::MAX_SIZE);
     reg.size = numstr.len() / 2;
     let mut buf = [0u8; GdbRegisterValue::MAX_SIZE];
     let mut new_sl: &[u8] = Default::default();
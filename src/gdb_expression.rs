@@ -1,11 +1,14 @@
 use crate::{
-    commands::gdb_server::GdbServer,
-    gdb_connection::GdbRegisterValue,
-    gdb_register::GdbRegister,
-    remote_ptr::RemotePtr,
-    session::task::{task_common::read_val_mem, Task},
+    commands::gdb_server::GdbServer, gdb_connection::GdbRegisterValue, gdb_register::GdbRegister,
+    remote_ptr::RemotePtr, session::task::Task,
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    intrinsics::transmute,
+    io::{stdout, Write},
+    mem::size_of,
 };
-use std::{convert::TryFrom, intrinsics::transmute, mem::size_of};
 use Opcode::*;
 
 /// Extracted from
@@ -78,6 +81,42 @@ impl GdbExpressionValue {
     }
 }
 
+/// What an expression can read from the debuggee. Evaluation goes through
+/// this rather than `Task` directly so that it can be tested without a tracee.
+pub trait ExpressionTarget {
+    /// Fill `buf` from memory at `addr`. Returns false if that isn't all
+    /// readable.
+    fn read_memory(&self, addr: usize, buf: &mut [u8]) -> bool;
+    /// The value of register `r`, zero extended, if it's known.
+    fn read_register(&self, r: GdbRegister) -> Option<u64>;
+    /// The address of the ELF symbol `name`, for targets that can look
+    /// symbols up.
+    fn symbol_address(&self, _name: &str) -> Option<u64> {
+        None
+    }
+}
+
+impl<'a> ExpressionTarget for dyn Task + 'a {
+    fn read_memory(&self, addr: usize, buf: &mut [u8]) -> bool {
+        matches!(self.read_bytes_fallible(RemotePtr::from(addr), buf), Ok(n) if n == buf.len())
+    }
+
+    fn read_register(&self, r: GdbRegister) -> Option<u64> {
+        let extra_regs = self.extra_regs_ref().clone();
+        let v: GdbRegisterValue = GdbServer::get_reg(&self.regs_ref(), &extra_regs, r);
+        if !v.defined {
+            return None;
+        }
+        match v.size {
+            1 => Some(v.value1() as u64),
+            2 => Some(v.value2() as u64),
+            4 => Some(v.value4() as u64),
+            8 => Some(v.value8()),
+            _ => None,
+        }
+    }
+}
+
 /// gdb has a simple bytecode language for writing expressions to be evaluated
 /// in a remote target. This class implements evaluation of such expressions.
 /// See <https://sourceware.org/gdb/current/onlinedocs/gdb/Agent-Expressions.html>
@@ -98,6 +137,14 @@ impl GdbExpression {
     /// If evaluation succeeds, store the final result in result and return true.
     /// Otherwise return false.
     pub fn evaluate(&self, t: &dyn Task, result: &mut GdbExpressionValue) -> bool {
+        self.evaluate_in(t, result)
+    }
+
+    pub fn evaluate_in<T: ExpressionTarget + ?Sized>(
+        &self,
+        target: &T,
+        result: &mut GdbExpressionValue,
+    ) -> bool {
         if self.bytecode_variants.is_empty() {
             return false;
         }
@@ -110,7 +157,7 @@ impl GdbExpression {
                 if steps >= 10000 || state.error {
                     return false;
                 }
-                state.step(t);
+                state.step(target);
                 steps += 1;
             }
 
@@ -118,6 +165,11 @@ impl GdbExpression {
             if state.error {
                 return false;
             }
+            if !state.output.is_empty() {
+                let mut out = stdout();
+                let _ = out.write_all(&state.output);
+                let _ = out.flush();
+            }
             if first {
                 *result = v;
                 first = false;
@@ -138,6 +190,12 @@ struct ExpressionState<'a> {
     pc: usize,
     error: bool,
     end: bool,
+    /// DIFF NOTE: rd doesn't support tracepoints so gdb never defines trace
+    /// state variables. `setv`/`getv` work within one evaluation, and
+    /// variables that haven't been set read as 0.
+    trace_state_variables: HashMap<u16, i64>,
+    /// What `printf` (used by `dprintf` with `dprintf-style agent`) printed.
+    output: Vec<u8>,
 }
 
 struct BinaryOperands {
@@ -159,6 +217,8 @@ impl<'a> ExpressionState<'a> {
             pc: 0,
             error: false,
             end: false,
+            trace_state_variables: HashMap::new(),
+            output: Vec::new(),
         }
     }
 
@@ -245,19 +305,57 @@ impl<'a> ExpressionState<'a> {
         v
     }
 
-    pub fn load<T: Into<u64>>(&mut self, t: &dyn Task) {
+    /// Replace the address on top of the stack with the `N` byte (little
+    /// endian) value at it.
+    pub fn load<const N: usize, T: ExpressionTarget + ?Sized>(&mut self, target: &T) {
         let addr = self.pop().i as usize;
         if self.error {
             // Don't do unnecessary syscalls if we're already in an error state.
             return;
         }
-        let mut ok = true;
-        let v: T = read_val_mem(t, RemotePtr::from(addr), Some(&mut ok));
-        if !ok {
+        let mut buf = [0u8; 8];
+        if !target.read_memory(addr, &mut buf[0..N]) {
             self.set_error();
             return;
         }
-        self.push(v.into() as i64);
+        self.push(i64::from_le_bytes(buf));
+    }
+
+    /// Pop `n` values, with the first one popped last.
+    pub fn pop_n(&mut self, n: usize) -> Vec<i64> {
+        if n > self.stack.len() {
+            self.set_error();
+            return vec![0; n];
+        }
+        self.stack
+            .split_off(self.stack.len() - n)
+            .iter()
+            .map(|v| v.i)
+            .collect()
+    }
+
+    /// `printf numargs string`: the string is prefixed with its length,
+    /// which includes the terminating NUL.
+    pub fn printf<T: ExpressionTarget + ?Sized>(&mut self, target: &T) {
+        let nargs = self.fetch_u8() as usize;
+        let len = self.fetch_u16() as usize;
+        if self.error || len == 0 || self.pc + len > self.bytecode.len() {
+            self.set_error();
+            return;
+        }
+        let format = &self.bytecode[self.pc..self.pc + len - 1];
+        self.pc += len;
+        // The function and channel are above the arguments. We always print
+        // to stdout.
+        self.pop_n(2);
+        let args = self.pop_n(nargs);
+        if self.error {
+            return;
+        }
+        match format_printf(&unescape(format), &args, target) {
+            Some(s) => self.output.extend_from_slice(&s),
+            None => self.set_error(),
+        }
     }
 
     pub fn pick(&mut self, offset: usize) {
@@ -268,11 +366,16 @@ impl<'a> ExpressionState<'a> {
         self.push(self.stack[self.stack.len() - 1 - offset].i);
     }
 
-    pub fn step(&mut self, t: &dyn Task) {
+    pub fn step<T: ExpressionTarget + ?Sized>(&mut self, target: &T) {
         debug_assert!(!self.error);
         let operands: BinaryOperands;
-        // @TODO Will the catch all case `_` deal with even invalid enum values?
-        match unsafe { transmute(self.fetch_u8()) } {
+        let op = self.fetch_u8();
+        if op < OP_float as u8 || op == 0x31 || op > OP_printf as u8 {
+            // Not an opcode. Transmuting it to an Opcode would be UB.
+            self.set_error();
+            return;
+        }
+        match unsafe { transmute::<u8, Opcode>(op) } {
             OP_add => {
                 operands = self.pop_a_b();
                 self.push(operands.a.overflowing_add(operands.b).0)
@@ -303,7 +406,7 @@ impl<'a> ExpressionState<'a> {
             OP_rem_signed => {
                 operands = self.pop_a_b();
                 let b = self.nonzero(operands.b);
-                self.push(operands.a % b)
+                self.push(operands.a.wrapping_rem(b))
             }
 
             OP_rem_unsigned => {
@@ -394,13 +497,51 @@ impl<'a> ExpressionState<'a> {
                 self.push(a & n_mask)
             }
 
-            OP_ref8 => self.load::<u8>(t),
+            OP_ref8 => self.load::<1, T>(target),
+
+            OP_ref16 => self.load::<2, T>(target),
+
+            OP_ref32 => self.load::<4, T>(target),
+
+            OP_ref64 => self.load::<8, T>(target),
+
+            // DIFF NOTE: Like gdbserver, we don't support floating point.
+            // gdb doesn't emit these.
+            OP_float | OP_ref_float | OP_ref_double | OP_ref_long_double | OP_l_to_d
+            | OP_d_to_l => self.set_error(),
+
+            // Conditions are evaluated without collecting trace data, so the
+            // trace opcodes only need to have their stack effects.
+            OP_trace | OP_tracenz => {
+                self.pop_a_b();
+            }
+
+            OP_trace_quick => {
+                self.fetch_u8();
+            }
 
-            OP_ref16 => self.load::<u16>(t),
+            OP_trace16 => {
+                self.fetch_u16();
+            }
 
-            OP_ref32 => self.load::<u32>(t),
+            OP_tracev => {
+                self.fetch_u16();
+            }
 
-            OP_ref64 => self.load::<u64>(t),
+            OP_getv => {
+                let n = self.fetch_u16();
+                let v = self.trace_state_variables.get(&n).copied().unwrap_or(0);
+                self.push(v)
+            }
+
+            OP_setv => {
+                let n = self.fetch_u16();
+                let v = self.pop_a();
+                self.trace_state_variables.insert(n, v);
+                self.push(v)
+            }
+
+            OP_printf => self.printf(target),
 
             OP_dup => self.pick(0),
 
@@ -465,32 +606,371 @@ impl<'a> ExpressionState<'a> {
                         return;
                     }
                 };
-                let extra_regs = t.extra_regs_ref().clone();
-                let v: GdbRegisterValue = GdbServer::get_reg(&t.regs_ref(), &extra_regs, r);
-
-                if !v.defined {
-                    self.set_error();
-                    return;
-                }
-
-                match v.size {
-                    1 => self.push(v.value1() as i64),
-                    2 => self.push(v.value2() as i64),
-                    4 => self.push(v.value4() as i64),
-                    8 => self.push(v.value8() as i64),
-                    _ => {
-                        self.set_error();
-                    }
+                match target.read_register(r) {
+                    Some(v) => self.push(v as i64),
+                    None => self.set_error(),
                 }
             }
 
             OP_end => {
                 self.end = true;
             }
-            // @TODO Does the transmute play well with this?
-            _ => {
-                self.set_error();
+        }
+    }
+}
+
+/// Expand the C escape sequences in a `printf` format string. gdb sends them
+/// as they were written, e.g. `\t%d\n` is six bytes long.
+fn unescape(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] != b'\\' || i + 1 == s.len() {
+            out.push(s[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let c = match s[i] {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'e' => 0x1b,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            b'0'..=b'7' => {
+                let mut v = 0u8;
+                let start = i;
+                while i < s.len() && i < start + 3 && (b'0'..=b'7').contains(&s[i]) {
+                    v = v.wrapping_mul(8).wrapping_add(s[i] - b'0');
+                    i += 1;
+                }
+                out.push(v);
+                continue;
+            }
+            c => c,
+        };
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+/// Longest string `%s` reads from the debuggee.
+const MAX_PRINTF_STRING: usize = 4096;
+
+/// Format `args` according to the C printf format string `format`. Supports
+/// the flags, field widths, precisions, length modifiers and conversions
+/// (other than floating point ones) that gdb accepts for `dprintf`. Returns
+/// None if the format string is bad or an argument can't be read.
+fn format_printf<T: ExpressionTarget + ?Sized>(
+    format: &[u8],
+    args: &[i64],
+    target: &T,
+) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut args = args.iter().copied();
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let mut left = false;
+        let mut zero = false;
+        let mut plus = false;
+        let mut space = false;
+        let mut alternate = false;
+        while let Some(&c) = format.get(i) {
+            match c {
+                b'-' => left = true,
+                b'0' => zero = true,
+                b'+' => plus = true,
+                b' ' => space = true,
+                b'#' => alternate = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        let mut width = 0usize;
+        while let Some(c @ b'0'..=b'9') = format.get(i) {
+            width = width * 10 + (c - b'0') as usize;
+            i += 1;
+        }
+        let mut precision = None;
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            let mut p = 0usize;
+            while let Some(c @ b'0'..=b'9') = format.get(i) {
+                p = p * 10 + (c - b'0') as usize;
+                i += 1;
+            }
+            precision = Some(p);
+        }
+        // Values are already 64 bits; only narrowing modifiers matter.
+        let mut bits = 32;
+        while let Some(&c) = format.get(i) {
+            match c {
+                b'h' => bits = if bits == 16 { 8 } else { 16 },
+                b'l' | b'z' | b'j' | b't' => bits = 64,
+                _ => break,
+            }
+            i += 1;
+        }
+        let conversion = *format.get(i)?;
+        i += 1;
+        if conversion == b'%' {
+            out.push(b'%');
+            continue;
+        }
+        let arg = args.next()?;
+        let unsigned = if bits == 64 {
+            arg as u64
+        } else {
+            arg as u64 & ((1u64 << bits) - 1)
+        };
+        let signed = if bits == 64 {
+            arg
+        } else {
+            ((unsigned << (64 - bits)) as i64) >> (64 - bits)
+        };
+        let (sign, mut digits, prefix): (&str, Vec<u8>, &str) = match conversion {
+            b'd' | b'i' => {
+                let sign = if signed < 0 {
+                    "-"
+                } else if plus {
+                    "+"
+                } else if space {
+                    " "
+                } else {
+                    ""
+                };
+                (
+                    sign,
+                    (signed.wrapping_abs() as u64).to_string().into_bytes(),
+                    "",
+                )
+            }
+            b'u' => ("", unsigned.to_string().into_bytes(), ""),
+            b'o' => ("", format!("{:o}", unsigned).into_bytes(), ""),
+            b'x' => (
+                "",
+                format!("{:x}", unsigned).into_bytes(),
+                if alternate && unsigned != 0 { "0x" } else { "" },
+            ),
+            b'X' => (
+                "",
+                format!("{:X}", unsigned).into_bytes(),
+                if alternate && unsigned != 0 { "0X" } else { "" },
+            ),
+            b'p' => ("", format!("{:x}", arg as u64).into_bytes(), "0x"),
+            b'c' => ("", vec![arg as u8], ""),
+            b's' => {
+                let max = precision.take().unwrap_or(MAX_PRINTF_STRING);
+                ("", read_c_string(target, arg as usize, max)?, "")
+            }
+            _ => return None,
+        };
+        if let Some(p) = precision {
+            if conversion != b'c' && digits.len() < p {
+                let mut padded = vec![b'0'; p - digits.len()];
+                padded.append(&mut digits);
+                digits = padded;
             }
         }
+        let pad = width.saturating_sub(sign.len() + prefix.len() + digits.len());
+        let zero_pad =
+            zero && !left && precision.is_none() && conversion != b's' && conversion != b'c';
+        if !left && !zero_pad {
+            out.resize(out.len() + pad, b' ');
+        }
+        out.extend_from_slice(sign.as_bytes());
+        out.extend_from_slice(prefix.as_bytes());
+        if zero_pad {
+            out.resize(out.len() + pad, b'0');
+        }
+        out.extend_from_slice(&digits);
+        if left {
+            out.resize(out.len() + pad, b' ');
+        }
+    }
+    Some(out)
+}
+
+/// Read at most `max` bytes of the NUL terminated string at `addr`.
+fn read_c_string<T: ExpressionTarget + ?Sized>(
+    target: &T,
+    addr: usize,
+    max: usize,
+) -> Option<Vec<u8>> {
+    let mut s = Vec::new();
+    let mut c = [0u8];
+    while s.len() < max {
+        if !target.read_memory(addr + s.len(), &mut c) {
+            return None;
+        }
+        if c[0] == 0 {
+            break;
+        }
+        s.push(c[0]);
+    }
+    Some(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb_register::DREG_RBP;
+
+    const RBP: u64 = 0x7ffd_0000_1000;
+
+    /// Memory at `base`, and rbp.
+    struct FakeTarget {
+        base: usize,
+        memory: Vec<u8>,
+    }
+
+    impl FakeTarget {
+        /// A frame whose locals are just below rbp.
+        fn with_frame(locals: &[u8]) -> FakeTarget {
+            FakeTarget {
+                base: RBP as usize - locals.len(),
+                memory: locals.to_vec(),
+            }
+        }
+    }
+
+    impl ExpressionTarget for FakeTarget {
+        fn read_memory(&self, addr: usize, buf: &mut [u8]) -> bool {
+            if addr < self.base || addr + buf.len() > self.base + self.memory.len() {
+                return false;
+            }
+            let offset = addr - self.base;
+            buf.copy_from_slice(&self.memory[offset..offset + buf.len()]);
+            true
+        }
+
+        fn read_register(&self, r: GdbRegister) -> Option<u64> {
+            if r == DREG_RBP {
+                Some(RBP)
+            } else {
+                None
+            }
+        }
+    }
+
+    fn eval(bytecode: &[u8], target: &FakeTarget) -> Option<i64> {
+        let mut v = GdbExpressionValue::default();
+        if GdbExpression::new(bytecode).evaluate_in(target, &mut v) {
+            Some(v.i)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn local_equals_constant() {
+        // gdb's bytecode for `x == 5` with `int x` at -20(%rbp).
+        let bytecode = [
+            0x26, 0x00, 0x06, 0x22, 0xec, 0x16, 0x08, 0x02, 0x19, 0x16, 0x20, 0x22, 0x05, 0x13,
+            0x27,
+        ];
+        let mut locals = vec![0u8; 20];
+        locals[0..4].copy_from_slice(&5i32.to_le_bytes());
+        assert_eq!(eval(&bytecode, &FakeTarget::with_frame(&locals)), Some(1));
+        locals[0..4].copy_from_slice(&(-5i32).to_le_bytes());
+        assert_eq!(eval(&bytecode, &FakeTarget::with_frame(&locals)), Some(0));
+    }
+
+    #[test]
+    fn short_circuit_and() {
+        // gdb's bytecode for `p != 0 && p->len > 3` with `p` at -8(%rbp) and
+        // `len` an int at offset 8 of what it points to.
+        let bytecode = [
+            0x26, 0x00, 0x06, 0x22, 0xf8, 0x16, 0x08, 0x02, 0x1a, 0x22, 0x00, 0x13, 0x0e, 0x0e,
+            0x20, 0x00, 0x2d, 0x26, 0x00, 0x06, 0x22, 0xf8, 0x16, 0x08, 0x02, 0x1a, 0x22, 0x08,
+            0x02, 0x19, 0x16, 0x20, 0x22, 0x03, 0x2b, 0x14, 0x0e, 0x20, 0x00, 0x2d, 0x22, 0x01,
+            0x21, 0x00, 0x2f, 0x22, 0x00, 0x27,
+        ];
+        // p is null, so p->len must not be read.
+        let null = vec![0u8; 8];
+        assert_eq!(eval(&bytecode, &FakeTarget::with_frame(&null)), Some(0));
+
+        // The struct is at the start of the "frame", p just below rbp.
+        let mut locals = vec![0u8; 24];
+        let p = RBP - 24;
+        locals[16..24].copy_from_slice(&p.to_le_bytes());
+        locals[8..12].copy_from_slice(&5i32.to_le_bytes());
+        assert_eq!(eval(&bytecode, &FakeTarget::with_frame(&locals)), Some(1));
+        locals[8..12].copy_from_slice(&2i32.to_le_bytes());
+        assert_eq!(eval(&bytecode, &FakeTarget::with_frame(&locals)), Some(0));
+    }
+
+    #[test]
+    fn errors() {
+        let target = FakeTarget::with_frame(&[]);
+        // Not an opcode.
+        assert_eq!(eval(&[0x31, 0x27], &target), None);
+        // Unreadable memory.
+        assert_eq!(eval(&[0x22, 0x10, 0x19, 0x27], &target), None);
+        // Unknown register.
+        assert_eq!(eval(&[0x26, 0x00, 0x00, 0x27], &target), None);
+        // Floating point.
+        assert_eq!(eval(&[0x01, 0x27], &target), None);
+        // i64::MIN % -1 and division by zero.
+        let min_rem = [
+            0x25, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x22, 0xff, 0x16, 0x08, 0x07, 0x27,
+        ];
+        assert_eq!(eval(&min_rem, &target), Some(0));
+        assert_eq!(eval(&[0x22, 0x01, 0x22, 0x00, 0x05, 0x27], &target), None);
+    }
+
+    #[test]
+    fn trace_ops_and_state_variables() {
+        let target = FakeTarget::with_frame(&[]);
+        // setv 1 (7); pop; trace_quick 4; tracev 1; getv 1; getv 2; add; end
+        let bytecode = [
+            0x22, 0x07, 0x2d, 0x00, 0x01, 0x29, 0x0d, 0x04, 0x2e, 0x00, 0x01, 0x2c, 0x00, 0x01,
+            0x2c, 0x00, 0x02, 0x02, 0x27,
+        ];
+        assert_eq!(eval(&bytecode, &target), Some(7));
+    }
+
+    #[test]
+    fn printf() {
+        let mut locals = b"hi\0".to_vec();
+        locals.resize(8, 0);
+        let target = FakeTarget::with_frame(&locals);
+        let s = RBP as i64 - 8;
+        assert_eq!(
+            format_printf(
+                &unescape(br"%s %5d|%-4x|%04u %#x %c%%\n"),
+                &[s, -42, 255, 7, 16, b'!' as i64],
+                &target
+            ),
+            Some(b"hi   -42|ff  |0007 0x10 !%\n".to_vec())
+        );
+        assert_eq!(
+            format_printf(b"%hhd %ld", &[0x1ff, -1], &target),
+            Some(b"-1 -1".to_vec())
+        );
+        assert_eq!(format_printf(b"%d", &[], &target), None);
+
+        // printf 1 "%d\n" with function and channel 0, then `1` as the result.
+        let bytecode = [
+            0x22, 0x2a, 0x22, 0x00, 0x22, 0x00, 0x34, 0x01, 0x00, 0x05, b'%', b'd', b'\\', b'n',
+            0x00, 0x22, 0x01, 0x27,
+        ];
+        let mut state = ExpressionState::new(&bytecode);
+        while !state.end && !state.error {
+            state.step(&target);
+        }
+        assert!(!state.error);
+        assert_eq!(state.pop().i, 1);
+        assert_eq!(state.output, b"42\n");
     }
 }
@@ -0,0 +1,166 @@
+//! Counters and timings for the gdb server, so that it's possible to tell why
+//! an interactive session feels slow. `rd-perf` shows them in gdb and, with
+//! `RD_LOG=gdb_perf:info`, they are also logged every `LOG_INTERVAL`.
+
+use crate::log::LogInfo;
+use std::{
+    fmt::{self, Display},
+    time::{Duration, Instant},
+};
+
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Upper bounds of the histogram buckets of a `Timing`. The last bucket holds
+/// everything slower.
+const BUCKET_LIMITS: [Duration; 6] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+const BUCKET_NAMES: [&str; 7] = ["<10us", "<100us", "<1ms", "<10ms", "<100ms", "<1s", ">=1s"];
+
+/// How often something took how long.
+#[derive(Clone, Default)]
+pub struct Timing {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    buckets: [u64; BUCKET_NAMES.len()],
+}
+
+impl Timing {
+    pub fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+        let bucket = BUCKET_LIMITS
+            .iter()
+            .position(|&limit| elapsed < limit)
+            .unwrap_or(BUCKET_LIMITS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::default()
+        } else {
+            self.total / self.count as u32
+        }
+    }
+}
+
+impl Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (total {:?}, mean {:?}, max {:?})",
+            self.count,
+            self.total,
+            self.mean(),
+            self.max
+        )?;
+        if self.count > 0 {
+            write!(f, "\n   ")?;
+            for (name, n) in BUCKET_NAMES.iter().zip(self.buckets.iter()) {
+                write!(f, " {}: {}", name, n)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct GdbPerfStats {
+    since: Instant,
+    last_logged: Instant,
+    pub packets: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Serving gdb's memory reads.
+    pub mem_reads: Timing,
+    /// Writing gdb's (combined) memory writes to the tracee.
+    pub mem_writes: Timing,
+    /// Running the replay forward or backward for a resume request.
+    pub replay_steps: Timing,
+}
+
+impl Default for GdbPerfStats {
+    fn default() -> Self {
+        let now = Instant::now();
+        GdbPerfStats {
+            since: now,
+            last_logged: now,
+            packets: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            mem_reads: Timing::default(),
+            mem_writes: Timing::default(),
+            replay_steps: Timing::default(),
+        }
+    }
+}
+
+impl GdbPerfStats {
+    /// Call when a packet has been received from gdb.
+    pub fn packet_received(&mut self) {
+        self.packets += 1;
+        if self.last_logged.elapsed() >= LOG_INTERVAL {
+            self.last_logged = Instant::now();
+            log!(LogInfo, "gdb server performance:\n{}", self);
+        }
+    }
+
+    /// Start counting from zero again.
+    pub fn reset(&mut self) {
+        *self = GdbPerfStats::default();
+    }
+}
+
+impl Display for GdbPerfStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.since.elapsed().as_secs_f64();
+        let per_sec = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+        writeln!(f, "Over the last {:.1}s:", secs)?;
+        writeln!(
+            f,
+            "  packets from gdb: {} ({:.1}/s)",
+            self.packets,
+            per_sec(self.packets)
+        )?;
+        writeln!(
+            f,
+            "  bytes read: {} ({:.0}/s), bytes written: {} ({:.0}/s)",
+            self.bytes_read,
+            per_sec(self.bytes_read),
+            self.bytes_written,
+            per_sec(self.bytes_written)
+        )?;
+        writeln!(f, "  memory reads: {}", self.mem_reads)?;
+        writeln!(f, "  memory writes: {}", self.mem_writes)?;
+        write!(f, "  replay steps: {}", self.replay_steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings_land_in_their_bucket() {
+        let mut t = Timing::default();
+        t.record(Duration::from_micros(5));
+        t.record(Duration::from_micros(500));
+        t.record(Duration::from_secs(2));
+        assert_eq!(t.count(), 3);
+        assert_eq!(t.buckets, [1, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(t.max, Duration::from_secs(2));
+        assert_eq!(t.mean(), Duration::from_micros(2_000_505) / 3);
+    }
+}
@@ -0,0 +1,254 @@
+//! The target description (`target.xml`) rd sends to gdb.
+//!
+//! gdb's stock description for the debuggee's architecture is read from
+//! `share/rd`, its `xi:include`s are inlined so gdb gets everything in one
+//! transfer, and an `org.rd.replay` feature is added. That feature describes
+//! rd's pseudo-registers, which hold the replay position:
+//!
+//!  - `rd_event`: the current event number, as printed by `when`
+//!  - `rd_ticks`: the tick count of the thread being queried, as printed by `when-ticks`
+//!
+//! so scripts can read e.g. `$rd_event` instead of parsing command output.
+//! They are read-only.
+//!
+//! Descriptions are built once for each set of `GdbConnection` cpu features
+//! and shared by all connections. During replay those features describe the
+//! machine the trace was recorded on (see `cpu_features()`), not this one.
+
+use crate::{
+    extra_registers::{
+        AVX_FEATURE_BIT, HI16_ZMM_FEATURE_BIT, OPMASK_FEATURE_BIT, ZMM_HI256_FEATURE_BIT,
+    },
+    gdb_connection::GdbConnection,
+    gdb_register::{DREG_RD_EVENT, DREG_RD_TICKS},
+    kernel_abi::SupportedArch,
+    scoped_fd::ScopedFd,
+    util::{find, resource_path, XSaveLayout},
+};
+use nix::{fcntl::OFlag, unistd::read, Error};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io::Write,
+    os::unix::ffi::OsStringExt,
+    sync::{Arc, Mutex},
+};
+
+/// Name of the feature describing rd's pseudo-registers.
+pub const RD_FEATURE_NAME: &str = "org.rd.replay";
+
+lazy_static! {
+    static ref TARGET_DESCRIPTIONS: Mutex<HashMap<u32, Arc<Vec<u8>>>> = Mutex::new(HashMap::new());
+}
+
+/// The `GdbConnection::CPU_*` flags for a machine with `xsave_layout` and
+/// `xcr0`. AVX and AVX-512 registers are only described if the CPU supports
+/// them and the OS enabled them.
+pub fn cpu_features(arch: SupportedArch, xsave_layout: &XSaveLayout, xcr0: u64) -> u32 {
+    let mut cpu_features = match arch {
+        SupportedArch::X86 => 0,
+        SupportedArch::X64 => GdbConnection::CPU_64BIT,
+    };
+    let enabled = xsave_layout.supported_feature_bits & xcr0;
+    let avx = 1u64 << AVX_FEATURE_BIT;
+    let avx512 =
+        (1u64 << OPMASK_FEATURE_BIT) | (1 << ZMM_HI256_FEATURE_BIT) | (1 << HI16_ZMM_FEATURE_BIT);
+    if enabled & avx != 0 {
+        cpu_features |= GdbConnection::CPU_AVX;
+        if enabled & avx512 == avx512 {
+            cpu_features |= GdbConnection::CPU_AVX512;
+        }
+    }
+    cpu_features
+}
+
+/// The full target description for `cpu_features` (a combination of
+/// `GdbConnection::CPU_*` flags).
+pub fn target_description(cpu_features: u32) -> Arc<Vec<u8>> {
+    let mut descriptions = TARGET_DESCRIPTIONS.lock().unwrap();
+    descriptions
+        .entry(cpu_features)
+        .or_insert_with(|| {
+            let top = read_target_desc(target_description_name(cpu_features)).unwrap();
+            let mut desc = inline_includes(&top, &|name| read_target_desc(name).unwrap());
+            add_rd_feature(&mut desc, cpu_features);
+            Arc::new(desc)
+        })
+        .clone()
+}
+
+fn target_description_name(cpu_features: u32) -> &'static [u8] {
+    // This doesn't scale, but it's what gdb does...
+    match cpu_features {
+        0 => b"i386-linux.xml",
+        GdbConnection::CPU_64BIT => b"amd64-linux.xml",
+        GdbConnection::CPU_AVX => b"i386-avx-linux.xml",
+        GdbConnection::CPU_64BIT_AND_CPU_AVX => b"amd64-avx-linux.xml",
+        f if f == GdbConnection::CPU_AVX | GdbConnection::CPU_AVX512 => {
+            b"i386-avx-avx512-linux.xml"
+        }
+        f if f == GdbConnection::CPU_64BIT_AND_CPU_AVX | GdbConnection::CPU_AVX512 => {
+            b"amd64-avx-avx512-linux.xml"
+        }
+        _ => fatal!("Unknown features"),
+    }
+}
+
+/// Read one of the description files installed in `share/rd`.
+pub fn read_target_desc(file_name: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut path = resource_path().as_bytes().to_vec();
+    path.extend_from_slice(b"share/rd/");
+    path.extend_from_slice(file_name);
+    let f = ScopedFd::open_path(path.as_slice(), OFlag::O_RDONLY);
+    // DIFF NOTE: This is a debug assert in rr. Why?
+    assert!(f.is_open(), "Could not open {:?}", OsString::from_vec(path));
+    let mut buf = [0u8; 4 * 1024];
+    let mut text_buf = Vec::<u8>::with_capacity(4 * 1024);
+    loop {
+        let bytes_result = read(f.as_raw(), &mut buf);
+        match bytes_result {
+            Err(e) => return Err(e),
+            Ok(0) => break,
+            Ok(nread) => {
+                text_buf.extend_from_slice(&buf[0..nread]);
+            }
+        }
+    }
+
+    Ok(text_buf)
+}
+
+/// Replace each `<xi:include href="..."/>` in `desc` with the `<feature>`
+/// element of the file it names, as read by `read_file`.
+fn inline_includes(desc: &[u8], read_file: &dyn Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+    const INCLUDE_START: &[u8] = b"<xi:include href=\"";
+    let mut out = Vec::with_capacity(desc.len());
+    let mut rest = desc;
+    while let Some(start) = find(rest, INCLUDE_START) {
+        out.extend_from_slice(&rest[..start]);
+        let href_start = start + INCLUDE_START.len();
+        let href_len = find(&rest[href_start..], b"\"")
+            .unwrap_or_else(|| fatal!("Unterminated xi:include in target description"));
+        let included = read_file(&rest[href_start..href_start + href_len]);
+        out.extend_from_slice(feature_element(&included));
+        let end = find(&rest[href_start..], b"/>")
+            .unwrap_or_else(|| fatal!("Unterminated xi:include in target description"));
+        rest = &rest[href_start + end + 2..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+/// The root `<feature>` element of an included description file, without the
+/// XML declaration, DOCTYPE and copyright comment before it.
+fn feature_element(file: &[u8]) -> &[u8] {
+    const FEATURE_END: &[u8] = b"</feature>";
+    match (find(file, b"<feature"), rfind(file, FEATURE_END)) {
+        (Some(start), Some(end)) if start < end => &file[start..end + FEATURE_END.len()],
+        _ => fatal!("Included target description has no <feature> element"),
+    }
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+fn add_rd_feature(desc: &mut Vec<u8>, cpu_features: u32) {
+    let end =
+        rfind(desc, b"</target>").unwrap_or_else(|| fatal!("Target description has no </target>"));
+    let mut feature = Vec::new();
+    // Generated descriptions differ between cpu feature sets; say which one
+    // this is to make mismatches easy to spot in `maint print xml-tdesc`.
+    write!(
+        feature,
+        "  <!-- rd cpu_features={:#x} -->\n\
+         \x20 <feature name=\"{}\">\n\
+         \x20   <reg name=\"rd_event\" bitsize=\"64\" type=\"uint64\" regnum=\"{}\" \
+         group=\"rd\" save-restore=\"no\"/>\n\
+         \x20   <reg name=\"rd_ticks\" bitsize=\"64\" type=\"uint64\" regnum=\"{}\" \
+         group=\"rd\" save-restore=\"no\"/>\n\
+         \x20 </feature>\n",
+        cpu_features, RD_FEATURE_NAME, DREG_RD_EVENT, DREG_RD_TICKS
+    )
+    .unwrap();
+    desc.splice(end..end, feature);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOP: &[u8] = b"<?xml version=\"1.0\"?>\n\
+        <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+        <target>\n\
+        \x20 <architecture>i386:x86-64</architecture>\n\
+        \x20 <xi:include href=\"a.xml\"/>\n\
+        \x20 <xi:include href=\"b.xml\"/>\n\
+        </target>\n";
+
+    fn read_file(name: &[u8]) -> Vec<u8> {
+        let mut file = b"<?xml version=\"1.0\"?>\n<!-- (C) -->\n\
+            <!DOCTYPE feature SYSTEM \"gdb-target.dtd\">\n<feature name=\""
+            .to_vec();
+        file.extend_from_slice(name);
+        file.extend_from_slice(b"\">\n  <reg name=\"r\" bitsize=\"64\"/>\n</feature>\n");
+        file
+    }
+
+    #[test]
+    fn avx_needs_recorded_support_and_xcr0() {
+        let layout = |supported_feature_bits| XSaveLayout {
+            full_size: 832,
+            supported_feature_bits,
+            feature_layouts: Vec::new(),
+        };
+        assert_eq!(
+            cpu_features(SupportedArch::X64, &layout(0x7), 0x7),
+            GdbConnection::CPU_64BIT_AND_CPU_AVX
+        );
+        assert_eq!(
+            cpu_features(SupportedArch::X86, &layout(0x7), 0x7),
+            GdbConnection::CPU_AVX
+        );
+        // The OS didn't enable AVX.
+        assert_eq!(
+            cpu_features(SupportedArch::X64, &layout(0x7), 0x3),
+            GdbConnection::CPU_64BIT
+        );
+        // The recording CPU had no AVX.
+        assert_eq!(cpu_features(SupportedArch::X86, &layout(0x3), 0x7), 0);
+        assert_eq!(
+            cpu_features(SupportedArch::X64, &layout(0xe7), 0xe7),
+            GdbConnection::CPU_64BIT_AND_CPU_AVX | GdbConnection::CPU_AVX512
+        );
+        // AVX-512 needs all three of its xsave features.
+        assert_eq!(
+            cpu_features(SupportedArch::X64, &layout(0xe7), 0x67),
+            GdbConnection::CPU_64BIT_AND_CPU_AVX
+        );
+    }
+
+    #[test]
+    fn includes_are_inlined() {
+        let desc = inline_includes(TOP, &read_file);
+        let desc = String::from_utf8(desc).unwrap();
+        assert!(!desc.contains("xi:include"));
+        assert!(!desc.contains("(C)"));
+        assert_eq!(desc.matches("<!DOCTYPE").count(), 1);
+        let a = desc.find("<feature name=\"a.xml\">").unwrap();
+        let b = desc.find("<feature name=\"b.xml\">").unwrap();
+        assert!(a < b && b < desc.find("</target>").unwrap());
+    }
+
+    #[test]
+    fn rd_feature_is_last() {
+        let mut desc = inline_includes(TOP, &read_file);
+        add_rd_feature(&mut desc, GdbConnection::CPU_64BIT);
+        let desc = String::from_utf8(desc).unwrap();
+        let rd = desc.find(RD_FEATURE_NAME).unwrap();
+        assert!(desc.find("b.xml").unwrap() < rd);
+        assert!(rd < desc.find("</target>").unwrap());
+        assert!(desc.contains(&format!("regnum=\"{}\"", DREG_RD_TICKS)));
+        assert!(desc.contains("cpu_features=0x1"));
+    }
+}
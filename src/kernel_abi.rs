@@ -360,6 +360,23 @@ pub mod common {
 
     pub use super::aligned_u64;
     pub type ptr64<T> = super::Ptr<aligned_u64, T>;
+
+    /// The argument of clone3(). Its layout is the same on all architectures.
+    #[repr(C)]
+    #[derive(Copy, Clone, Default)]
+    pub struct clone_args {
+        pub flags: __u64,
+        pub pidfd: __u64,
+        pub child_tid: __u64,
+        pub parent_tid: __u64,
+        pub exit_signal: __u64,
+        pub stack: __u64,
+        pub stack_size: __u64,
+        pub tls: __u64,
+        pub set_tid: __u64,
+        pub set_tid_size: __u64,
+        pub cgroup: __u64,
+    }
 }
 
 pub mod w64 {
@@ -273,7 +273,7 @@ macro_rules! case {
     }};
 }
 
-fn sicode_name(code: i32, sig: i32) -> String {
+pub fn sicode_name(code: i32, sig: i32) -> String {
     case!(
         code, bindings, signal, SI_USER, SI_KERNEL, SI_QUEUE, SI_TIMER, SI_MESGQ, SI_ASYNCIO,
         SI_SIGIO, SI_TKILL, SI_ASYNCNL
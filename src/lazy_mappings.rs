@@ -0,0 +1,295 @@
+//! Demand-paged replay of large recorded mappings.
+//!
+//! Some traces record the full contents of big private anonymous mappings,
+//! e.g. every mapping that exists at exec time. Writing gigabytes of recorded
+//! data into the tracee through its mem fd before it runs makes replay slow to
+//! start and makes every page resident whether or not the replay touches it.
+//!
+//! Instead, when a raw data record exactly covers a private anonymous mapping
+//! of at least `MIN_LAZY_MAPPING_SIZE` bytes, we copy the record into an
+//! unlinked temporary file (the "staging file") and replace the mapping with a
+//! private mapping of that file. The kernel then only reads a page in when the
+//! tracee (or rd, or a syscall) first touches it, and tracee writes go to
+//! private copies as before. Staging files are cached in a `StagingFiles`
+//! shared by a `ReplaySession` and its clones, so when replay goes back to a
+//! checkpoint or to the start of the trace (e.g. `run` in gdb) the data is
+//! mapped again without being decompressed.
+//!
+//! A private file mapping behaves like the anonymous mapping it replaces
+//! except for a few operations that reveal the backing file: MADV_DONTNEED
+//! brings back the recorded data instead of zeroes, some madvise advice is
+//! refused, and an mremap that grows the mapping past the end of the file
+//! would leave pages that SIGBUS. Before replaying those we "materialize" the
+//! affected mappings: copy their current contents into a real anonymous
+//! mapping, as rd already does when it has to grow a mapping of a file.
+
+use crate::{
+    auto_remote_syscalls::AutoRemoteSyscalls,
+    log::LogLevel::LogDebug,
+    remote_memory::{write_vectored, WriteRequest},
+    remote_ptr::{RemotePtr, Void},
+    replay_syscall::finish_direct_mmap,
+    scoped_fd::ScopedFd,
+    session::{
+        address_space::{address_space::Mapping, kernel_mapping::KernelMapping},
+        task::{replay_task::ReplayTask, task_inner::WriteFlags, Task},
+    },
+    trace::trace_frame::FrameTime,
+    util::{create_temporary_file, floor_page_size, page_size},
+};
+use libc::{dev_t, ino_t};
+use nix::{
+    fcntl::OFlag,
+    sys::{mman::MapFlags, stat::fstat},
+    unistd::{getpid, unlink},
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{BufWriter, Write},
+    mem::ManuallyDrop,
+    os::unix::io::FromRawFd,
+    rc::Rc,
+};
+
+/// Smaller records are written into the tracee directly; mapping a file
+/// costs several remote syscalls.
+const MIN_LAZY_MAPPING_SIZE: usize = 16 * 1024 * 1024;
+
+/// Raw data records are identified by the directory of their trace, the
+/// frame they belong to and the range they cover.
+type RecordKey = (OsString, FrameTime, usize, usize);
+
+struct StagingFile {
+    fd: ScopedFd,
+    device: dev_t,
+    inode: ino_t,
+}
+
+pub type StagingFilesSharedPtr = Rc<StagingFiles>;
+
+/// The staging files of a replay session. The files are deleted when the last
+/// session sharing them is dropped.
+#[derive(Default)]
+pub struct StagingFiles {
+    files: RefCell<HashMap<RecordKey, StagingFile>>,
+}
+
+impl StagingFiles {
+    pub fn create() -> StagingFilesSharedPtr {
+        Rc::new(StagingFiles::default())
+    }
+
+    /// Does `km` map one of our staging files?
+    fn is_staged(&self, km: &KernelMapping) -> bool {
+        self.files
+            .borrow()
+            .values()
+            .any(|f| f.device == km.device() && f.inode == km.inode())
+    }
+}
+
+/// If the next raw data record of `t`'s current frame should be paged in on
+/// demand, consume it and map it into the tracee. Returns false, leaving the
+/// record alone, if the record should be written into the tracee normally.
+pub fn map_raw_data_lazily(t: &ReplayTask) -> bool {
+    let (addr, size) = match t.trace_reader().peek_raw_data_metadata_for_frame() {
+        Some(rec) if rec.rec_tid == t.rec_tid() && rec.size >= MIN_LAZY_MAPPING_SIZE => {
+            (rec.addr, rec.size)
+        }
+        _ => return false,
+    };
+    let mapping = match t.vm().mapping_of(addr) {
+        Some(m) => m.clone(),
+        None => return false,
+    };
+    if mapping.map.start() != addr
+        || mapping.map.size() != size
+        || !mapping
+            .map
+            .flags()
+            .contains(MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS)
+        || mapping.map.flags().contains(MapFlags::MAP_GROWSDOWN)
+        || mapping.emu_file.is_some()
+        || mapping.local_addr.is_some()
+        || mapping.monitored_shared_memory.is_some()
+    {
+        return false;
+    }
+
+    let key = (
+        t.trace_reader().dir(),
+        t.current_trace_frame().time(),
+        addr.as_usize(),
+        size,
+    );
+    let session = t.session();
+    let staging_files = session.as_replay().unwrap().staging_files();
+    if staging_files.files.borrow().contains_key(&key) {
+        t.trace_reader_mut().read_raw_data_metadata_for_frame();
+    } else {
+        let staged = stage_raw_data(t);
+        staging_files.files.borrow_mut().insert(key.clone(), staged);
+    }
+    let staging_file_name = staging_files.files.borrow()[&key].proc_path();
+
+    log!(
+        LogDebug,
+        "  mapping {} bytes of recorded data at {} on demand",
+        size,
+        addr
+    );
+    let flags = mapping.map.flags() & !MapFlags::MAP_ANONYMOUS;
+    let mut remote = AutoRemoteSyscalls::new(t);
+    // Like EmuFs, have the tracee open our fd through procfs so the staging
+    // file never needs a name.
+    let (real_file, real_file_name) = finish_direct_mmap(
+        &mut remote,
+        addr,
+        size,
+        mapping.map.prot(),
+        flags,
+        &staging_file_name,
+        OFlag::O_RDONLY,
+        0,
+    );
+    remote.task().vm().map(
+        remote.task(),
+        addr,
+        size,
+        mapping.map.prot(),
+        flags,
+        0,
+        &real_file_name,
+        real_file.st_dev,
+        real_file.st_ino,
+        None,
+        Some(&mapping.recorded_map),
+        None,
+        None,
+        None,
+    );
+    // `map()` protected the pages of software watchpoints again, but the
+    // breakpoints in the range were replaced by the recorded data.
+    remote
+        .task()
+        .vm()
+        .maybe_update_breakpoints(remote.task(), RemotePtr::cast(addr), size);
+    true
+}
+
+/// Copy the next raw data record into a new, unlinked staging file.
+fn stage_raw_data(t: &ReplayTask) -> StagingFile {
+    let file = create_temporary_file(b"rd-lazy-mapping-XXXXXX");
+    unlink(file.name.as_os_str()).unwrap();
+    // `file.fd` keeps the file alive, don't let `File` close it.
+    let staging = ManuallyDrop::new(unsafe { File::from_raw_fd(file.fd.as_raw()) });
+    let mut out = BufWriter::new(&*staging);
+    let copied = t
+        .trace_reader_mut()
+        .read_raw_data_for_frame_into(&mut out)
+        .and_then(|rec| out.flush().map(|_| rec));
+    match copied {
+        Ok(Some(_)) => (),
+        Ok(None) => fatal!("Expected raw data, found none"),
+        Err(e) => fatal!(
+            "Can't copy recorded data to {:?}: {:?}. Is there enough space in the \
+             temporary file directory?",
+            file.name,
+            e
+        ),
+    }
+    let st = fstat(file.fd.as_raw()).unwrap();
+    StagingFile {
+        fd: file.fd,
+        device: st.st_dev,
+        inode: st.st_ino,
+    }
+}
+
+impl StagingFile {
+    fn proc_path(&self) -> OsString {
+        OsString::from(format!("/proc/{}/fd/{}", getpid(), self.fd.as_raw()))
+    }
+}
+
+/// Replace every mapping overlapping `[start, start + len)` that is backed by a
+/// staging file with an anonymous mapping with the same contents. Call this
+/// before replaying an operation on the range that would behave differently
+/// for a file mapping.
+pub fn materialize_range(t: &ReplayTask, start: RemotePtr<Void>, len: usize) {
+    let end = start + len;
+    let session = t.session();
+    let staging_files = session.as_replay().unwrap().staging_files();
+    let mut staged: Vec<Mapping> = Vec::new();
+    for (_, m) in &t.vm().maps_containing_or_after(floor_page_size(start)) {
+        if m.map.start() >= end {
+            break;
+        }
+        if staging_files.is_staged(&m.map) {
+            staged.push(m.clone());
+        }
+    }
+    for m in staged {
+        materialize(t, &m);
+    }
+}
+
+fn materialize(t: &ReplayTask, m: &Mapping) {
+    log!(LogDebug, "  materializing lazily mapped {}", m.map);
+    let start = m.map.start();
+    let size = m.map.size();
+    let flags = m.map.flags() | MapFlags::MAP_ANONYMOUS;
+    // Pages may have been written since they were mapped, so read the current
+    // contents rather than the staging file.
+    let mut buf = vec![0u8; size];
+    t.read_bytes_helper(start, &mut buf, None);
+    let mut remote = AutoRemoteSyscalls::new(t);
+    remote.infallible_mmap_syscall(
+        Some(start),
+        size,
+        m.map.prot(),
+        flags | MapFlags::MAP_FIXED,
+        -1,
+        0,
+    );
+    remote.task().vm().map(
+        remote.task(),
+        start,
+        size,
+        m.map.prot(),
+        flags,
+        0,
+        OsStr::new(""),
+        KernelMapping::NO_DEVICE,
+        KernelMapping::NO_INODE,
+        None,
+        Some(&m.recorded_map),
+        None,
+        None,
+        None,
+    );
+    // The new mapping is all zeroes. Only write the runs of pages that aren't,
+    // so untouched pages stay unallocated.
+    let is_zero_page = |offset: usize| buf[offset..offset + page_size()].iter().all(|&b| b == 0);
+    let mut runs = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        if is_zero_page(offset) {
+            offset += page_size();
+            continue;
+        }
+        let mut run_end = offset + page_size();
+        while run_end < size && !is_zero_page(run_end) {
+            run_end += page_size();
+        }
+        runs.push(WriteRequest {
+            addr: start + offset,
+            buf: &buf[offset..run_end],
+        });
+        offset = run_end;
+    }
+    write_vectored(remote.task(), &runs, None, WriteFlags::empty());
+}
@@ -0,0 +1,150 @@
+//! rd records and replays Linux processes. Most of it is driven through the
+//! `rd` binary (see main.rs); `replay::ReplaySessionBuilder`, and `capi` with
+//! `--features capi`, let other programs replay traces themselves.
+//!
+//! Embedders get default `flags::Flags` unless they call `Flags::init` first,
+//! e.g. to point `resource_path` at an rd installation.
+#![feature(get_mut_unchecked)]
+#![feature(map_first_last)]
+#![feature(llvm_asm)]
+#![feature(raw_ref_op)]
+#![feature(termination_trait_lib)]
+#![feature(associated_type_defaults)]
+#![feature(slice_ptr_get)]
+#![feature(array_methods)]
+#![feature(arc_new_cyclic)]
+#![feature(format_args_capture)]
+// Can disallow some of these in the future
+#![allow(clippy::upper_case_acronyms)]
+#![allow(clippy::redundant_static_lifetimes)]
+#![allow(clippy::assertions_on_constants)]
+#![allow(clippy::single_match)]
+#![allow(clippy::collapsible_if)]
+#![allow(clippy::manual_range_contains)]
+#![allow(clippy::module_inception)]
+#![allow(clippy::enum_variant_names)]
+#![allow(clippy::needless_range_loop)]
+#![allow(clippy::too_many_arguments)]
+#![allow(clippy::wrong_self_convention)]
+#![allow(clippy::let_and_return)]
+#![allow(clippy::collapsible_else_if)]
+#![allow(clippy::needless_return)]
+#![allow(clippy::or_fun_call)]
+#![allow(clippy::needless_lifetimes)]
+// @TODO To many results for "never used". Disable for now.
+#![allow(dead_code)]
+
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate raw_cpuid;
+#[macro_use]
+extern crate static_assertions;
+#[macro_use]
+extern crate memoffset;
+
+#[macro_use]
+mod log;
+#[macro_use]
+mod arch;
+#[macro_use]
+pub mod kernel_abi;
+mod allocation_origin;
+#[macro_use]
+mod auto_remote_syscalls;
+mod breakpoint_condition;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod condition_expression;
+#[macro_use]
+pub mod remote_ptr;
+mod arch_structs;
+mod bindings;
+pub mod flags;
+mod kernel_metadata;
+pub mod perf_counters;
+#[macro_use]
+pub mod registers;
+pub mod commands;
+mod core;
+mod cpuid_bug_detector;
+mod divergence_repair;
+mod emu_fs;
+mod error_injector;
+mod event;
+mod extra_registers;
+mod fast_forward;
+mod fd_table;
+mod file_monitor;
+mod fuse;
+mod gdb_connection;
+mod gdb_expression;
+mod gdb_perf;
+pub mod gdb_register;
+mod gdb_target_desc;
+mod kernel_supplement;
+mod lazy_mappings;
+mod memory_search;
+mod monitored_shared_memory;
+mod monkey_patcher;
+pub mod preflight;
+mod preload_interface;
+mod preload_interface_arch;
+mod priority_tup;
+mod rd;
+mod record_signal;
+mod record_syscall;
+pub mod remote_code_ptr;
+mod remote_memory;
+pub mod replay;
+mod replay_compat;
+mod replay_syscall;
+mod replay_timeline;
+mod return_address_list;
+mod sandbox;
+mod scheduler;
+mod scoped_fd;
+mod seccomp_bpf;
+mod seccomp_filter_rewriter;
+pub mod session;
+mod sig;
+mod stap_sdt;
+mod taskish_uid;
+mod thread_db;
+mod thread_group;
+mod ticks;
+pub mod trace;
+mod trace_capnp;
+mod unwind;
+pub mod util;
+mod wait_status;
+mod weak_ptr_set;
+
+use nix::sys::utsname::uname;
+
+pub fn assert_prerequisites(maybe_use_syscall_buffer: Option<bool>) {
+    let use_syscall_buffer = maybe_use_syscall_buffer.unwrap_or(false);
+    let unm = uname();
+    let release = unm.release();
+    let parts: Vec<&str> = release.split('.').collect();
+    if parts.len() < 2 {
+        fatal!("Could not parse kernel version string. Got: `{}`", release);
+    }
+
+    let maybe_major = parts[0].parse::<u32>();
+    let maybe_minor = parts[1].parse::<u32>();
+    if maybe_major.is_err() || maybe_minor.is_err() {
+        fatal!("Could not parse kernel version string. Got: `{}`", release);
+    }
+
+    let (major, minor) = (maybe_major.unwrap(), maybe_minor.unwrap());
+    if (major, minor) < (3, 4) {
+        fatal!("Kernel doesn't support necessary ptrace functionality; need 3.4.0 or better.");
+    }
+
+    if use_syscall_buffer && (major, minor) < (3, 5) {
+        fatal!("Your kernel does not support syscall filtering; please use the -n option while recording");
+    }
+}
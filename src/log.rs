@@ -614,8 +614,10 @@ fn dump_last_events(trace_stream: &TraceStream, f: &mut dyn Write) -> io::Result
         dump_recorded_data_metadata: true,
         dump_mmaps: true,
         raw_dump: false,
+        json: false,
         statistics: false,
         only_tid: None,
+        only_syscall: None,
         trace_dir: Some(PathBuf::from(trace_stream.dir())),
         event_spec: Some((start, Some(end))),
     };
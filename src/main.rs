@@ -1,152 +1,40 @@
-#![feature(get_mut_unchecked)]
-#![feature(map_first_last)]
-#![feature(llvm_asm)]
-#![feature(raw_ref_op)]
 #![feature(termination_trait_lib)]
-#![feature(associated_type_defaults)]
-#![feature(slice_ptr_get)]
-#![feature(array_methods)]
-#![feature(arc_new_cyclic)]
-#![feature(format_args_capture)]
-// Can disallow some of these in the future
-#![allow(clippy::upper_case_acronyms)]
-#![allow(clippy::redundant_static_lifetimes)]
-#![allow(clippy::assertions_on_constants)]
-#![allow(clippy::single_match)]
-#![allow(clippy::collapsible_if)]
-#![allow(clippy::manual_range_contains)]
-#![allow(clippy::module_inception)]
-#![allow(clippy::enum_variant_names)]
-#![allow(clippy::needless_range_loop)]
-#![allow(clippy::too_many_arguments)]
-#![allow(clippy::wrong_self_convention)]
-#![allow(clippy::let_and_return)]
-#![allow(clippy::collapsible_else_if)]
-#![allow(clippy::needless_return)]
-#![allow(clippy::or_fun_call)]
-#![allow(clippy::needless_lifetimes)]
-// @TODO To many results for "never used". Disable for now.
-#![allow(dead_code)]
 
-#[macro_use]
-extern crate bitflags;
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate raw_cpuid;
-#[macro_use]
-extern crate static_assertions;
-#[macro_use]
-extern crate memoffset;
-
-#[macro_use]
-mod log;
-#[macro_use]
-mod arch;
-#[macro_use]
-mod kernel_abi;
-#[macro_use]
-mod auto_remote_syscalls;
-mod breakpoint_condition;
-#[macro_use]
-mod remote_ptr;
-mod arch_structs;
-mod bindings;
-mod flags;
-mod kernel_metadata;
-mod perf_counters;
-#[macro_use]
-mod registers;
-mod commands;
-mod core;
-mod cpuid_bug_detector;
-mod emu_fs;
-mod event;
-mod extra_registers;
-mod fast_forward;
-mod fd_table;
-mod file_monitor;
-mod gdb_connection;
-mod gdb_expression;
-mod gdb_register;
-mod kernel_supplement;
-mod monitored_shared_memory;
-mod monkey_patcher;
-mod preload_interface;
-mod preload_interface_arch;
-mod priority_tup;
-mod rd;
-mod record_signal;
-mod record_syscall;
-mod remote_code_ptr;
-mod replay_syscall;
-mod replay_timeline;
-mod return_address_list;
-mod scheduler;
-mod scoped_fd;
-mod seccomp_bpf;
-mod seccomp_filter_rewriter;
-mod session;
-mod sig;
-mod taskish_uid;
-mod thread_db;
-mod thread_group;
-mod ticks;
-mod trace;
-mod trace_capnp;
-mod util;
-mod wait_status;
-mod weak_ptr_set;
-
-use crate::{
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use rand::random;
+use rd::{
     commands::{
+        assert_command::AssertCommand,
+        browse_command::BrowseCommand,
         build_id_command::BuildIdCommand,
+        checksum_command::ChecksumCommand,
+        coverage_command::CoverageCommand,
         dump_command::DumpCommand,
+        entropy_command::EntropyCommand,
+        exit_result::ExitResult,
+        fsck_command::FsckCommand,
+        mount_command::MountCommand,
+        pack_command::PackCommand,
         ps_command::PsCommand,
         rd_options::{RdOptions, RdSubCommand},
+        record_command::RecordCommand,
+        replay_command::ReplayCommand,
         rerun_command::ReRunCommand,
+        rm_command::RmCommand,
+        sched_command::SchedCommand,
+        stats_command::StatsCommand,
         trace_info_command::TraceInfoCommand,
+        triage_command::TriageCommand,
         RdCommand,
     },
-    perf_counters::init_pmu,
+    flags::{init_flags, Flags},
+    perf_counters::{init_pmu, pmu_disabled},
+    preflight::Environment,
     util::raise_resource_limits,
 };
-use commands::{
-    exit_result::ExitResult, record_command::RecordCommand, replay_command::ReplayCommand,
-};
-use nix::sys::{
-    signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
-    utsname::uname,
-};
-use rand::random;
 use std::os::raw::c_uint;
 use structopt::StructOpt;
 
-pub fn assert_prerequisites(maybe_use_syscall_buffer: Option<bool>) {
-    let use_syscall_buffer = maybe_use_syscall_buffer.unwrap_or(false);
-    let unm = uname();
-    let release = unm.release();
-    let parts: Vec<&str> = release.split('.').collect();
-    if parts.len() < 2 {
-        fatal!("Could not parse kernel version string. Got: `{}`", release);
-    }
-
-    let maybe_major = parts[0].parse::<u32>();
-    let maybe_minor = parts[1].parse::<u32>();
-    if maybe_major.is_err() || maybe_minor.is_err() {
-        fatal!("Could not parse kernel version string. Got: `{}`", release);
-    }
-
-    let (major, minor) = (maybe_major.unwrap(), maybe_minor.unwrap());
-    if (major, minor) < (3, 4) {
-        fatal!("Kernel doesn't support necessary ptrace functionality; need 3.4.0 or better.");
-    }
-
-    if use_syscall_buffer && (major, minor) < (3, 5) {
-        fatal!("Your kernel does not support syscall filtering; please use the -n option while recording");
-    }
-}
-
 fn main() -> ExitResult<()> {
     // In rust SIGPIPE is ignored. See https://github.com/rust-lang/rust/issues/62569
     // Undo this.
@@ -158,11 +46,20 @@ fn main() -> ExitResult<()> {
 
     raise_resource_limits();
     let options = RdOptions::from_args();
+    Flags::init(init_flags());
     if options.output_options_chosen {
         eprintln!("{:?}", options);
     }
 
-    init_pmu();
+    if options.cmd.runs_tracees() {
+        Environment::get().check_for_record_or_replay(
+            !options.no_pmu && !options.cmd.replays_trace_recorded_without_pmu(),
+        );
+    }
+
+    if !pmu_disabled() {
+        init_pmu();
+    }
     match &options.cmd {
         RdSubCommand::BuildId => return BuildIdCommand::new().run(),
         RdSubCommand::Dump { .. } => {
@@ -180,6 +77,42 @@ fn main() -> ExitResult<()> {
         RdSubCommand::Ps { .. } => {
             return PsCommand::new(&options).run();
         }
+        RdSubCommand::Sched { .. } => {
+            return SchedCommand::new(&options).run();
+        }
+        RdSubCommand::Stats { .. } => {
+            return StatsCommand::new(&options).run();
+        }
+        RdSubCommand::Browse { .. } => {
+            return BrowseCommand::new(&options).run();
+        }
+        RdSubCommand::Entropy { .. } => {
+            return EntropyCommand::new(&options).run();
+        }
+        RdSubCommand::Mount { .. } => {
+            return MountCommand::new(&options).run();
+        }
+        RdSubCommand::Coverage { .. } => {
+            return CoverageCommand::new(&options).run();
+        }
+        RdSubCommand::Pack { .. } => {
+            return PackCommand::new(&options).run();
+        }
+        RdSubCommand::Triage { .. } => {
+            return TriageCommand::new(&options).run();
+        }
+        RdSubCommand::Assert { .. } => {
+            return AssertCommand::new(&options).run();
+        }
+        RdSubCommand::Checksum { .. } => {
+            return ChecksumCommand::new(&options).run();
+        }
+        RdSubCommand::Fsck { .. } => {
+            return FsckCommand::new(&options).run();
+        }
+        RdSubCommand::Rm { .. } => {
+            return RmCommand::new(&options).run();
+        }
         RdSubCommand::Record { .. } => {
             return RecordCommand::new(&options).run();
         }
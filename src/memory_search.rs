@@ -0,0 +1,557 @@
+//! Searching tracee memory for values, strings, masked byte patterns and
+//! regular expressions, as done by the `rd-find` and `rd-search-mem` gdb
+//! commands and gdb's own `find`.
+
+use crate::{
+    remote_memory::{read_vectored, ReadRequest},
+    remote_ptr::{RemotePtr, Void},
+    session::{address_space::memory_range::MemoryRange, task::Task},
+    util::{floor_page_size, page_size},
+};
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use regex::bytes::Regex;
+use std::{
+    cmp::min,
+    collections::HashMap,
+    convert::TryFrom,
+    ffi::{OsStr, OsString},
+    fs,
+    os::unix::ffi::OsStrExt,
+};
+
+/// Memory is read this much at a time.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Regex matches are only guaranteed to be found if they are at most this long.
+/// Longer matches that straddle a chunk boundary are cut short or missed.
+const MAX_REGEX_MATCH_LEN: usize = 4096;
+
+pub enum SearchPattern {
+    /// An exact byte sequence, e.g. the encoding of a typed value.
+    Bytes(Vec<u8>),
+    /// A byte sequence where only the bits set in `mask` have to match.
+    /// `bytes` has the other bits cleared.
+    Masked { bytes: Vec<u8>, mask: Vec<u8> },
+    /// A regex applied to each run of printable ASCII.
+    Regex(Regex),
+}
+
+impl SearchPattern {
+    /// Parse `rd-find` arguments: `<type> <value>` where `<type>` is one of
+    /// `u16`, `u32`, `u64`, `i16`, `i32`, `i64`, `f32`, `f64` (little endian,
+    /// add a `be` suffix for big endian, e.g. `u32be`), `str` for a literal
+    /// string, `hex` for hex bytes where `?` matches any nibble (e.g.
+    /// `de ad ?? e?`) or `re` for a regex over printable memory.
+    pub fn parse(kind: &str, value: &str) -> Result<SearchPattern, String> {
+        let (base, big_endian) = match kind.strip_suffix("be") {
+            Some(base) if !base.is_empty() => (base, true),
+            _ => (kind, false),
+        };
+        let bad_value = || format!("`{}` is not a valid {} value", value, base);
+        let out_of_range = || format!("`{}` is out of range for {}", value, base);
+        let unsigned = || parse_unsigned(value).ok_or_else(bad_value);
+        let signed = || parse_signed(value).ok_or_else(bad_value);
+        let mut bytes = match base {
+            "u16" => u16::try_from(unsigned()?)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            "u32" => u32::try_from(unsigned()?)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            "u64" => unsigned()?.to_le_bytes().to_vec(),
+            "i16" => i16::try_from(signed()?)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            "i32" => i32::try_from(signed()?)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            "i64" => signed()?.to_le_bytes().to_vec(),
+            "f32" => {
+                let v = value.parse::<f32>().map_err(|_| bad_value())?;
+                // Rust parses values too large for an f32 as infinity.
+                if v.is_infinite() && value.parse::<f64>().map_or(false, f64::is_finite) {
+                    return Err(out_of_range());
+                }
+                v.to_le_bytes().to_vec()
+            }
+            "f64" => value
+                .parse::<f64>()
+                .map_err(|_| bad_value())?
+                .to_le_bytes()
+                .to_vec(),
+            "str" if !big_endian => value.as_bytes().to_vec(),
+            "hex" if !big_endian => return parse_hex(value),
+            "re" if !big_endian => {
+                return match Regex::new(value) {
+                    Ok(re) => Ok(SearchPattern::Regex(re)),
+                    Err(e) => Err(format!("Bad regex: {}", e)),
+                }
+            }
+            _ => return Err(format!("Unknown search type `{}`", kind)),
+        };
+        if bytes.is_empty() {
+            return Err("Can't search for an empty string".into());
+        }
+        if big_endian {
+            bytes.reverse();
+        }
+        Ok(SearchPattern::Bytes(bytes))
+    }
+
+    /// How many bytes past the end of a chunk need to be read to find all the
+    /// matches that start inside it.
+    fn overlap(&self) -> usize {
+        match self {
+            SearchPattern::Bytes(bytes) | SearchPattern::Masked { bytes, .. } => bytes.len() - 1,
+            SearchPattern::Regex(_) => MAX_REGEX_MATCH_LEN,
+        }
+    }
+
+    /// Append the offsets of the matches in `buf` that start before `limit`
+    /// to `found`.
+    pub fn find_in(&self, buf: &[u8], limit: usize, found: &mut Vec<usize>) {
+        match self {
+            SearchPattern::Bytes(bytes) => {
+                if buf.len() < bytes.len() {
+                    return;
+                }
+                for offset in memchr::memchr_iter(bytes[0], &buf[..buf.len() - bytes.len() + 1]) {
+                    if offset >= limit {
+                        break;
+                    }
+                    if buf[offset..].starts_with(bytes) {
+                        found.push(offset);
+                    }
+                }
+            }
+            SearchPattern::Masked { bytes, mask } => {
+                if buf.len() < bytes.len() {
+                    return;
+                }
+                let end = min(limit, buf.len() - bytes.len() + 1);
+                let matches_at = |offset: usize| {
+                    buf[offset..offset + bytes.len()]
+                        .iter()
+                        .zip(bytes.iter().zip(mask))
+                        .all(|(b, (v, m))| b & m == *v)
+                };
+                // Only look closely where the first fully specified byte matches.
+                match mask.iter().position(|&m| m == 0xff) {
+                    Some(anchor) => {
+                        for i in memchr::memchr_iter(bytes[anchor], &buf[anchor..end + anchor]) {
+                            if matches_at(i) {
+                                found.push(i);
+                            }
+                        }
+                    }
+                    None => found.extend((0..end).filter(|&offset| matches_at(offset))),
+                }
+            }
+            SearchPattern::Regex(re) => {
+                let mut run_start = 0;
+                for run in buf.split(|&c| !is_printable(c)) {
+                    if run_start >= limit {
+                        break;
+                    }
+                    for m in re.find_iter(run) {
+                        if m.start() != m.end() && run_start + m.start() < limit {
+                            found.push(run_start + m.start());
+                        }
+                    }
+                    run_start += run.len() + 1;
+                }
+            }
+        }
+    }
+}
+
+/// Parse hex bytes, ignoring whitespace, where `?` stands for any nibble.
+fn parse_hex(value: &str) -> Result<SearchPattern, String> {
+    let digits: Vec<char> = value.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return Err(format!("`{}` is not a whole number of hex bytes", value));
+    }
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    let mut mask = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        let mut byte = 0u8;
+        let mut byte_mask = 0u8;
+        for &c in pair {
+            byte <<= 4;
+            byte_mask <<= 4;
+            if c != '?' {
+                byte |= c
+                    .to_digit(16)
+                    .ok_or_else(|| format!("`{}` is not a hex digit or '?'", c))?
+                    as u8;
+                byte_mask |= 0xf;
+            }
+        }
+        bytes.push(byte);
+        mask.push(byte_mask);
+    }
+    if mask.iter().all(|&m| m == 0) {
+        return Err("A pattern of only wildcards matches everywhere".into());
+    }
+    if mask.iter().all(|&m| m == 0xff) {
+        return Ok(SearchPattern::Bytes(bytes));
+    }
+    Ok(SearchPattern::Masked { bytes, mask })
+}
+
+fn is_printable(c: u8) -> bool {
+    c == b'\t' || (b' '..=b'~').contains(&c)
+}
+
+fn parse_unsigned(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u64>().ok(),
+    }
+}
+
+/// Returns None if `s` isn't a number or doesn't fit in an i64.
+fn parse_signed(s: &str) -> Option<i64> {
+    match s.strip_prefix('-') {
+        Some(abs) => match parse_unsigned(abs)? {
+            v if v == 1 << 63 => Some(i64::MIN),
+            v => i64::try_from(v).ok().map(|v| -v),
+        },
+        None => i64::try_from(parse_unsigned(s)?).ok(),
+    }
+}
+
+/// Search every mapping of `t`'s address space for `pattern`. Calls `found`
+/// with the address of each match until it returns false. Returns false if
+/// the search was stopped that way.
+pub fn search_address_space(
+    t: &dyn Task,
+    pattern: &SearchPattern,
+    found: &mut dyn FnMut(RemotePtr<Void>) -> bool,
+) -> bool {
+    let everything = MemoryRange::from_range(RemotePtr::null(), RemotePtr::new(usize::MAX));
+    search_range(t, everything, pattern, found)
+}
+
+/// Like `search_address_space`, but only reports matches that lie entirely
+/// within `range`.
+pub fn search_range(
+    t: &dyn Task,
+    range: MemoryRange,
+    pattern: &SearchPattern,
+    found: &mut dyn FnMut(RemotePtr<Void>) -> bool,
+) -> bool {
+    // Adjacent mappings are searched as one range so matches can straddle them.
+    let mut ranges: Vec<MemoryRange> = Vec::new();
+    for (_, m) in &t.vm().maps() {
+        if m.map.is_vsyscall() {
+            continue;
+        }
+        let r = MemoryRange::from_range(m.map.start(), m.map.end()).intersect(range);
+        if r.size() == 0 {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end() == r.start() => {
+                *last = MemoryRange::from_range(last.start(), r.end())
+            }
+            _ => ranges.push(r),
+        }
+    }
+
+    let mut buf = vec![0u8; CHUNK_SIZE + pattern.overlap()];
+    let mut batch_buf = vec![0u8; CHUNK_SIZE];
+    let mut offsets = Vec::new();
+    let mut i = 0;
+    while i < ranges.len() {
+        // Processes can have thousands of small mappings. Read as many of
+        // them as fit in a chunk with a single syscall.
+        let mut batch_end = i;
+        let mut batch_size = 0;
+        while batch_end < ranges.len() && batch_size + ranges[batch_end].size() <= CHUNK_SIZE {
+            batch_size += ranges[batch_end].size();
+            batch_end += 1;
+        }
+        if batch_end - i < 2 {
+            if !search_chunked(t, ranges[i], pattern, &mut buf, &mut offsets, found) {
+                return false;
+            }
+            i += 1;
+            continue;
+        }
+
+        let mut reqs: Vec<ReadRequest> = Vec::new();
+        let mut rest = &mut batch_buf[..];
+        for r in &ranges[i..batch_end] {
+            let (piece, tail) = rest.split_at_mut(r.size());
+            reqs.push(ReadRequest::new(r.start(), piece));
+            rest = tail;
+        }
+        read_vectored(t, &mut reqs);
+        for (&range, req) in ranges[i..batch_end].iter().zip(&reqs) {
+            if req.nread < range.size() {
+                // Let the chunked search skip the unreadable parts.
+                if !search_chunked(t, range, pattern, &mut buf, &mut offsets, found) {
+                    return false;
+                }
+                continue;
+            }
+            offsets.clear();
+            pattern.find_in(req.buf, req.buf.len(), &mut offsets);
+            for &offset in &offsets {
+                if !found(range.start() + offset) {
+                    return false;
+                }
+            }
+        }
+        i = batch_end;
+    }
+    true
+}
+
+/// Search `range` a chunk at a time, reading each chunk into `buf` (which
+/// must hold a chunk plus the pattern's overlap).
+fn search_chunked(
+    t: &dyn Task,
+    range: MemoryRange,
+    pattern: &SearchPattern,
+    buf: &mut [u8],
+    offsets: &mut Vec<usize>,
+    found: &mut dyn FnMut(RemotePtr<Void>) -> bool,
+) -> bool {
+    let overlap = pattern.overlap();
+    let mut start = range.start();
+    while start < range.end() {
+        let fresh = min(CHUNK_SIZE, range.end() - start);
+        let l = min(fresh + overlap, range.end() - start);
+        let nread = match t.read_bytes_fallible(start, &mut buf[0..l]) {
+            Ok(nread) => nread,
+            Err(()) => 0,
+        };
+        offsets.clear();
+        pattern.find_in(&buf[0..nread], min(fresh, nread), offsets);
+        for &offset in offsets.iter() {
+            if !found(start + offset) {
+                return false;
+            }
+        }
+        start = if nread < fresh {
+            // Skip the unreadable page (e.g. beyond the end of a file).
+            min(range.end(), floor_page_size(start + nread) + page_size())
+        } else {
+            start + fresh
+        };
+    }
+    true
+}
+
+/// The ELF symbols of a file, and how its file offsets map to its addresses.
+struct FileSymbols {
+    /// (file offset, vaddr, size) of each PT_LOAD segment
+    segments: Vec<(u64, u64, u64)>,
+    /// (vaddr, size, name), sorted by vaddr
+    symbols: Vec<(u64, u64, String)>,
+    /// vaddr of each symbol, by name
+    addresses: HashMap<String, u64>,
+}
+
+impl FileSymbols {
+    fn load(path: &OsStr) -> Option<FileSymbols> {
+        let data = fs::read(path).ok()?;
+        let elf = Elf::parse(&data).ok()?;
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .map(|ph| (ph.p_offset, ph.p_vaddr, ph.p_memsz))
+            .collect();
+        let mut symbols: Vec<(u64, u64, String)> = elf
+            .syms
+            .iter()
+            .map(|sym| (sym, &elf.strtab))
+            .chain(elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab)))
+            .filter(|(sym, _)| sym.st_value != 0 && sym.st_size != 0)
+            .filter_map(|(sym, strtab)| match strtab.get(sym.st_name) {
+                Some(Ok(name)) => Some((sym.st_value, sym.st_size, name.to_owned())),
+                _ => None,
+            })
+            .collect();
+        let addresses = symbols
+            .iter()
+            .map(|(vaddr, _, name)| (name.clone(), *vaddr))
+            .collect();
+        symbols.sort();
+        symbols.dedup_by_key(|s| s.0);
+        Some(FileSymbols {
+            segments,
+            symbols,
+            addresses,
+        })
+    }
+
+    /// The address of the symbol `name` when the start of the file is mapped
+    /// at `start`.
+    fn address_of(&self, name: &str, start: u64) -> Option<u64> {
+        let vaddr = *self.addresses.get(name)?;
+        let (_, base, _) = self.segments.iter().find(|(offset, _, _)| *offset == 0)?;
+        Some(start.wrapping_sub(*base).wrapping_add(vaddr))
+    }
+
+    fn symbolize(&self, file_offset: u64) -> Option<String> {
+        let vaddr = self
+            .segments
+            .iter()
+            .find(|(offset, _, size)| *offset <= file_offset && file_offset < offset + size)
+            .map(|(offset, vaddr, _)| file_offset - offset + vaddr)?;
+        let i = match self.symbols.binary_search_by_key(&vaddr, |s| s.0) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let (start, size, name) = &self.symbols[i];
+        if vaddr < start + size {
+            Some(format!("{}+{:#x}", name, vaddr - start))
+        } else {
+            None
+        }
+    }
+}
+
+/// Describes addresses as a mapping (and symbol, when the mapped file has
+/// one covering the address) plus an offset. Parsed symbol tables are cached.
+#[derive(Default)]
+pub struct Symbolizer {
+    files: HashMap<OsString, Option<FileSymbols>>,
+}
+
+impl Symbolizer {
+    pub fn describe(&mut self, t: &dyn Task, addr: RemotePtr<Void>) -> String {
+        let vm = t.vm();
+        let m = match vm.mapping_of(addr) {
+            Some(m) => m,
+            None => return "unmapped".into(),
+        };
+        let fsname = m.map.fsname();
+        if fsname.is_empty() {
+            return format!("anonymous+{:#x}", addr - m.map.start());
+        }
+        if !fsname.as_bytes().starts_with(b"/") {
+            // [heap], [stack] etc.
+            return format!("{}+{:#x}", fsname.to_string_lossy(), addr - m.map.start());
+        }
+
+        let file_offset = (addr - m.map.start()) as u64 + m.map.file_offset_bytes();
+        let maybe_symbol = self
+            .files
+            .entry(fsname.to_owned())
+            .or_insert_with(|| FileSymbols::load(fsname))
+            .as_ref()
+            .and_then(|symbols| symbols.symbolize(file_offset));
+        match maybe_symbol {
+            Some(symbol) => format!("{} in {}", symbol, fsname.to_string_lossy()),
+            None => format!("{}+{:#x}", fsname.to_string_lossy(), file_offset),
+        }
+    }
+
+    /// The address of the symbol `name` in the first ELF file mapped in `t`
+    /// that defines it.
+    pub fn lookup(&mut self, t: &dyn Task, name: &str) -> Option<RemotePtr<Void>> {
+        for (_, m) in &t.vm().maps() {
+            let fsname = m.map.fsname();
+            if m.map.file_offset_bytes() != 0 || !fsname.as_bytes().starts_with(b"/") {
+                continue;
+            }
+            let maybe_addr = self
+                .files
+                .entry(fsname.to_owned())
+                .or_insert_with(|| FileSymbols::load(fsname))
+                .as_ref()
+                .and_then(|symbols| symbols.address_of(name, m.map.start().as_usize() as u64));
+            if let Some(addr) = maybe_addr {
+                return Some(RemotePtr::from(addr as usize));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_all(pattern: &SearchPattern, buf: &[u8]) -> Vec<usize> {
+        let mut found = Vec::new();
+        pattern.find_in(buf, buf.len(), &mut found);
+        found
+    }
+
+    #[test]
+    fn typed_patterns() {
+        let buf = [0u8, 0x78, 0x56, 0x34, 0x12, 0x12, 0x34, 0x56, 0x78];
+        let le = SearchPattern::parse("u32", "0x12345678").unwrap();
+        assert_eq!(find_all(&le, &buf), vec![1]);
+        let be = SearchPattern::parse("u32be", "305419896").unwrap();
+        assert_eq!(find_all(&be, &buf), vec![5]);
+        let f = SearchPattern::parse("f64", "1.5").unwrap();
+        assert_eq!(find_all(&f, &1.5f64.to_le_bytes()), vec![0]);
+        let neg = SearchPattern::parse("i16", "-2").unwrap();
+        assert_eq!(find_all(&neg, &[0xfe, 0xff]), vec![0]);
+        assert!(SearchPattern::parse("u32", "x").is_err());
+        assert!(SearchPattern::parse("strbe", "x").is_err());
+        assert!(SearchPattern::parse("u128", "1").is_err());
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        assert!(SearchPattern::parse("u16", "0x10000").is_err());
+        assert!(SearchPattern::parse("u16", "0xffff").is_ok());
+        assert!(SearchPattern::parse("u32be", "4294967296").is_err());
+        assert!(SearchPattern::parse("i16", "32768").is_err());
+        assert!(SearchPattern::parse("i16", "-32769").is_err());
+        assert!(SearchPattern::parse("i16", "-32768").is_ok());
+        assert!(SearchPattern::parse("i32", "0x80000000").is_err());
+        assert!(SearchPattern::parse("i64", "0xffffffffffffffff").is_err());
+        assert!(SearchPattern::parse("i64", "-9223372036854775809").is_err());
+        let min = SearchPattern::parse("i64", "-9223372036854775808").unwrap();
+        assert_eq!(find_all(&min, &i64::MIN.to_le_bytes()), vec![0]);
+        assert!(SearchPattern::parse("u64", "18446744073709551616").is_err());
+        assert!(SearchPattern::parse("f32", "1e39").is_err());
+        assert!(SearchPattern::parse("f32", "inf").is_ok());
+    }
+
+    #[test]
+    fn masked_patterns() {
+        let masked = SearchPattern::parse("hex", "de ?d be e?").unwrap();
+        let buf = [
+            0u8, 0xde, 0xad, 0xbe, 0xef, 0xde, 0x0d, 0xbe, 0xe0, 0xde, 0xad, 0xbf,
+        ];
+        assert_eq!(find_all(&masked, &buf), vec![1, 5]);
+        let leading_wildcard = SearchPattern::parse("hex", "??ad").unwrap();
+        assert_eq!(find_all(&leading_wildcard, &buf), vec![1, 9]);
+        assert!(matches!(
+            SearchPattern::parse("hex", "dead").unwrap(),
+            SearchPattern::Bytes(_)
+        ));
+        assert!(SearchPattern::parse("hex", "dea").is_err());
+        assert!(SearchPattern::parse("hex", "????").is_err());
+        assert!(SearchPattern::parse("hex", "zz").is_err());
+    }
+
+    #[test]
+    fn regex_stays_within_printable_runs() {
+        let re = SearchPattern::parse("re", "ab+c").unwrap();
+        assert_eq!(find_all(&re, b"xabbc\0abc\x01ab\0c"), vec![1, 6]);
+    }
+
+    #[test]
+    fn matches_past_limit_are_left_for_the_next_chunk() {
+        let pattern = SearchPattern::parse("str", "ab").unwrap();
+        let mut found = Vec::new();
+        pattern.find_in(b"abxab", 3, &mut found);
+        assert_eq!(found, vec![0]);
+    }
+}
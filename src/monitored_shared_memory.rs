@@ -13,6 +13,12 @@
 //! Currently we check the real memory after each syscall exit. This ensures
 //! that if the tracee is woken up by some IPC mechanism (or after sched_yield),
 //! it will get a chance to see updated memory values.
+//!
+//! Futexes in the shadow buffer would no longer be shared with the non-tracee,
+//! so record_syscall uses `real_addr` to redirect futex wakes to the real
+//! memory, and makes futex waits return at once (a spurious wakeup): the
+//! tracee then sees the updated values and waits again, instead of sleeping on
+//! memory the other process never wakes.
 
 use crate::{
     auto_remote_syscalls::{AutoRemoteSyscalls, PreserveContents},
@@ -106,6 +112,16 @@ impl MonitoredSharedMemory {
         }
     }
 
+    /// If `addr` is in monitored memory, the address in rd of the real memory
+    /// behind the tracee's shadow copy of it.
+    pub fn real_addr(t: &RecordTask, addr: RemotePtr<Void>) -> Option<*const u8> {
+        let vm = t.vm();
+        let m = vm.mapping_of(addr)?;
+        let mm = m.monitored_shared_memory.as_ref()?;
+        let offset = addr.as_usize() - m.map.start().as_usize();
+        Some(mm.borrow().real_mem[offset..].as_ptr())
+    }
+
     /// This feature is currently unsupported
     pub fn subrange(&self, _start: usize, _size: usize) -> MonitoredSharedMemorySharedPtr {
         assert!(
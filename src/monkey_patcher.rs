@@ -1,10 +1,11 @@
 use crate::{
     arch::{Architecture, X64Arch, X86Arch},
     auto_remote_syscalls::AutoRemoteSyscalls,
+    commands::build_id_command::BuildIdCommand,
     flags::Flags,
     kernel_abi::{get_syscall_instruction_arch, syscall_instruction_length, SupportedArch},
     kernel_metadata::syscall_name,
-    log::{LogDebug, LogWarn},
+    log::{LogDebug, LogInfo, LogWarn},
     preload_interface::{
         syscall_patch_hook, NEXT_INSTRUCTION_BYTES_LEN, SYSCALLBUF_LIB_FILENAME_32,
         SYSCALLBUF_LIB_FILENAME_BASE, SYSCALLBUF_LIB_FILENAME_PADDED,
@@ -44,7 +45,7 @@ use nix::{
 use object::{self, Object};
 use std::{
     cmp::min,
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     convert::TryInto,
     ffi::{OsStr, OsString},
     mem::size_of,
@@ -86,6 +87,10 @@ pub struct MonkeyPatcher {
     /// The addresses of the instructions following syscalls that we've tried
     /// (or are currently trying) to patch.
     tried_to_patch_syscall_addresses: HashSet<RemoteCodePtr>,
+
+    /// Whether each mapped file we've found a syscall in is excluded from
+    /// patching by --no-patch-library.
+    excluded_from_patching: HashMap<OsString, bool>,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -115,6 +120,7 @@ impl Default for MonkeyPatcher {
             syscallbuf_stubs: Default::default(),
             syscall_hooks: vec![],
             tried_to_patch_syscall_addresses: Default::default(),
+            excluded_from_patching: Default::default(),
         }
     }
 }
@@ -186,6 +192,11 @@ impl MonkeyPatcher {
             return false;
         }
 
+        if self.is_excluded_from_patching(t, ip) {
+            self.tried_to_patch_syscall_addresses.insert(ip);
+            return false;
+        }
+
         // We could examine the current syscall number and if it's not one that
         // we support syscall buffering for, refuse to patch the syscall instruction.
         // This would, on the face of it, reduce overhead since patching the
@@ -315,6 +326,33 @@ impl MonkeyPatcher {
         }
     }
 
+    /// DIFF NOTE: rr has no way to exclude libraries from syscall patching.
+    fn is_excluded_from_patching(&mut self, t: &RecordTask, ip: RemoteCodePtr) -> bool {
+        let session = t.session();
+        let patterns = session.as_record().unwrap().no_patch_libraries();
+        if patterns.is_empty() {
+            return false;
+        }
+        let fsname = match t.vm().mapping_of(ip.to_data_ptr::<Void>()) {
+            Some(m) => m.map.fsname().to_owned(),
+            None => return false,
+        };
+        if let Some(&excluded) = self.excluded_from_patching.get(&fsname) {
+            return excluded;
+        }
+        let excluded = patterns.iter().any(|p| library_matches(p, &fsname));
+        if excluded {
+            log!(
+                LogInfo,
+                "Not patching syscalls in {:?}, excluded by --no-patch-library",
+                fsname
+            );
+            t.trace_writer_mut().add_unpatched_library(&fsname);
+        }
+        self.excluded_from_patching.insert(fsname, excluded);
+        excluded
+    }
+
     pub fn init_dynamic_syscall_patching(
         &mut self,
         t: &RecordTask,
@@ -1126,6 +1164,21 @@ fn patch_dl_runtime_resolve(
     log!(LogDebug, "  patched in jump monkey patch successfully");
 }
 
+/// Does `pattern`, as given to --no-patch-library, select the file at `fsname`?
+fn library_matches(pattern: &str, fsname: &OsStr) -> bool {
+    let path = Path::new(fsname);
+    match pattern.strip_prefix("buildid:") {
+        Some(build_id) => match BuildIdCommand::build_id(path) {
+            Ok(id) if !id.is_empty() => {
+                let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+                hex.eq_ignore_ascii_case(build_id)
+            }
+            _ => false,
+        },
+        None => path == Path::new(pattern) || path.file_name() == Some(OsStr::new(pattern)),
+    }
+}
+
 fn file_may_need_instrumentation(map: &address_space::Mapping) -> bool {
     let file_path = Path::new(map.map.fsname());
 
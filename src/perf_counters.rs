@@ -10,7 +10,10 @@ use crate::{
     flags::Flags,
     log::LogLevel::{LogDebug, LogInfo, LogWarn},
     scoped_fd::ScopedFd,
-    session::task::task_inner::TaskInner,
+    session::{
+        address_space::BreakpointType,
+        task::{is_singlestep_resume, task_inner::TaskInner},
+    },
     sig,
     sig::Sig,
     ticks::Ticks,
@@ -18,7 +21,7 @@ use crate::{
 };
 use libc::{c_ulong, fcntl, ioctl, pid_t, EACCES, EINVAL, ENOENT, F_SETFL, O_ASYNC};
 use nix::{
-    errno::errno,
+    errno::{errno, Errno},
     poll::{poll, PollFd, PollFlags},
     unistd::read,
 };
@@ -31,6 +34,12 @@ lazy_static! {
     static ref PMU_ATTRIBUTES: PmuAttributes = get_init_attributes();
 }
 
+/// True if rd was asked not to use the hardware performance counters at all
+/// (`--no-pmu`). In that case no counters are ever opened and ticks are always 0.
+pub fn pmu_disabled() -> bool {
+    Flags::get().no_pmu
+}
+
 pub fn init_pmu() {
     // PMU_BUGS_AND_EXTRA/PMU_ATTRIBUTES are static ref and get initialized lazily
     // Here as a side effect they get initialized by storing in a boolean.
@@ -201,7 +210,11 @@ fn get_cpu_microarch() -> CpuMicroarch {
              For post-Ryzen CPUs, please file a Github issue."
         );
     } else {
-        clean_fatal!("Intel CPU type {:#x} unknown", cpu_type);
+        clean_fatal!(
+            "Intel CPU type {:#x} unknown. If it's a variant of a known\n\
+             microarchitecture, pass that with --microarch.",
+            cpu_type
+        );
     }
 }
 
@@ -362,7 +375,19 @@ fn get_init_attributes() -> PmuAttributes {
     } else {
         skid_size = pmu.skid_size;
         pmu_flags = pmu.flags;
-        ticks_attr = new_perf_event_attr(PERF_TYPE_RAW, pmu.rcb_cntr_event as u64);
+        let ticks_event = match Flags::get().ticks_event {
+            Some(event) => {
+                log!(
+                    LogInfo,
+                    "Counting ticks with event r{:x} instead of r{:x}",
+                    event,
+                    pmu.rcb_cntr_event
+                );
+                event
+            }
+            None => pmu.rcb_cntr_event as u64,
+        };
+        ticks_attr = new_perf_event_attr(PERF_TYPE_RAW, ticks_event);
         if pmu.minus_ticks_cntr_event != 0 {
             minus_ticks_attr = Some(new_perf_event_attr(
                 PERF_TYPE_RAW,
@@ -655,7 +680,17 @@ fn start_counter(tid: pid_t, group_fd: i32, attr: &mut perf_event_attr) -> (Scop
                  are perf events enabled? Try 'perf record'."
             );
         }
-        fatal!("Failed to initialize counter");
+        if attr.type_ == PERF_TYPE_RAW {
+            fatal!(
+                "Failed to open performance counter for raw event r{:x}: {}.\n\
+                 If this CPU or VM doesn't provide that event, pass --ticks-event\n\
+                 with the one that counts the same thing here, or --microarch if\n\
+                 the CPU was misdetected.",
+                attr.config,
+                Errno::last()
+            );
+        }
+        fatal!("Failed to initialize counter: {}", Errno::last());
     }
 
     (ScopedFd::from_raw(fd), disabled_txcp)
@@ -712,12 +747,13 @@ fn check_working_counters() -> bool {
             "\nGot {} branch events, expected at least {}.\n\n\
              The hardware performance counter seems to not be working. Check\n\
              that hardware performance counters are working by running:\n\
-             perf stat --event=r{:#x} true\n\
+             perf stat --event=r{:x} true\n\
              in a linux shell and checking that it reports a nonzero number of events.\n\
-             If performance counters seem to be working with 'perf', file an\n\
-             rd issue, otherwise check your hardware/OS/VM configuration. Also\n\
-             check that other software is not using performance counters on\n\
-             this CPU.",
+             If your CPU or VM counts conditional branches with a different event,\n\
+             pass it with --ticks-event. If performance counters seem to be working\n\
+             with 'perf', file an rd issue, otherwise check your hardware/OS/VM\n\
+             configuration. Also check that other software is not using performance\n\
+             counters on this CPU.",
             events,
             NUM_BRANCHES,
             config
@@ -799,6 +835,14 @@ impl PerfCounters {
     /// before the task is allowed to run again.
     /// `ticks_period` of zero means don't interrupt at all.
     pub fn reset(&mut self, param_ticks_period: Ticks) {
+        if pmu_disabled() {
+            // Nothing to program. Time slices never expire.
+            self.started = true;
+            self.counting = true;
+            self.counting_period = 0;
+            return;
+        }
+
         let mut ticks_period: u64 = param_ticks_period;
         if ticks_period == 0 && !always_recreate_counters() {
             // We can't switch a counter between sampling and non-sampling via
@@ -965,6 +1009,9 @@ impl PerfCounters {
         }
 
         self.counting = false;
+        if pmu_disabled() {
+            return;
+        }
         if always_recreate_counters() {
             self.stop()
         } else {
@@ -984,9 +1031,10 @@ impl PerfCounters {
 
     /// Return the number of ticks we need for an emulated branch.
     pub fn ticks_for_unconditional_indirect_branch(_task: &TaskInner) -> Ticks {
-        if PMU_ATTRIBUTES
-            .pmu_flags
-            .contains(PmuFlags::PMU_TICKS_TAKEN_BRANCHES)
+        if !pmu_disabled()
+            && PMU_ATTRIBUTES
+                .pmu_flags
+                .contains(PmuFlags::PMU_TICKS_TAKEN_BRANCHES)
         {
             1
         } else {
@@ -996,9 +1044,10 @@ impl PerfCounters {
 
     /// Return the number of ticks we need for a direct call.
     pub fn ticks_for_direct_call(_task: &TaskInner) -> Ticks {
-        if PMU_ATTRIBUTES
-            .pmu_flags
-            .contains(PmuFlags::PMU_TICKS_TAKEN_BRANCHES)
+        if !pmu_disabled()
+            && PMU_ATTRIBUTES
+                .pmu_flags
+                .contains(PmuFlags::PMU_TICKS_TAKEN_BRANCHES)
         {
             1
         } else {
@@ -1008,10 +1057,22 @@ impl PerfCounters {
 
     /// Read the current value of the ticks counter.
     /// `t` is used for debugging purposes.
+    ///
+    /// Without a PMU one tick is one singlestepped instruction: we count 1
+    /// when `t` was singlestepped and stopped with the step's SIGTRAP, and 0
+    /// otherwise.
     pub fn read_ticks(&self, t: &TaskInner) -> Ticks {
         if !self.started || !self.counting {
             return 0;
         }
+        if pmu_disabled() {
+            // Stepping onto an `int3` we planted doesn't execute a tracee
+            // instruction.
+            let stepped = is_singlestep_resume(t.how_last_execution_resumed())
+                && t.maybe_stop_sig() == sig::SIGTRAP
+                && t.vm().get_breakpoint_type_for_retired_insn(t.ip()) == BreakpointType::BkptNone;
+            return if stepped { 1 } else { 0 };
+        }
 
         if self.fd_ticks_in_transaction.is_open() {
             let transaction_ticks = read_counter(&self.fd_ticks_in_transaction);
@@ -1105,6 +1166,10 @@ impl PerfCounters {
     }
 
     pub fn supports_ticks_semantics(ticks_semantics: TicksSemantics) -> bool {
+        if pmu_disabled() {
+            // No ticks are counted, so any trace ticks semantics will do.
+            return true;
+        }
         match ticks_semantics {
             TicksRetiredConditionalBranches => {
                 PMU_ATTRIBUTES.pmu_flags.contains(PmuFlags::PMU_TICKS_RCB)
@@ -1116,6 +1181,9 @@ impl PerfCounters {
     }
 
     pub fn default_ticks_semantics() -> TicksSemantics {
+        if pmu_disabled() {
+            return TicksSemantics::default();
+        }
         if PMU_ATTRIBUTES
             .pmu_flags
             .contains(PmuFlags::PMU_TICKS_TAKEN_BRANCHES)
@@ -1131,6 +1199,9 @@ impl PerfCounters {
     /// When an interrupt is requested, at most this many ticks may elapse before
     /// the interrupt is delivered.
     pub fn skid_size() -> Ticks {
+        if pmu_disabled() {
+            return 0;
+        }
         PMU_ATTRIBUTES.skid_size
     }
 
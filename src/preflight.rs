@@ -0,0 +1,220 @@
+use crate::{
+    flags::Flags,
+    util::{cpuid, CPUID_GETFEATURES, CPUID_GETVENDORSTRING, CPUID_HYPERVISOR},
+};
+use std::{fs, os::unix::ffi::OsStrExt, path::Path};
+
+const CPUID_ARCH_PERFMON: u32 = 0xa;
+
+lazy_static! {
+    static ref ENVIRONMENT: Environment = Environment::detect();
+}
+
+/// The parts of the machine rd runs on that decide whether record/replay can
+/// work at all, detected once before any session is set up so we can give
+/// useful advice instead of failing deep inside session setup.
+///
+/// Detection must not log: it runs while `Flags` is being initialized.
+pub struct Environment {
+    /// Windows Subsystem for Linux 1. Its ptrace emulation can't support rd.
+    wsl1: bool,
+    /// WSL2 runs a real Linux kernel in a VM that has no virtual PMU.
+    wsl2: bool,
+    /// Hypervisor vendor signature from CPUID, if running in a VM.
+    hypervisor: Option<String>,
+    /// Whether the CPU reports any hardware performance counters.
+    pmu_exposed: bool,
+    /// `/proc/sys/kernel/yama/ptrace_scope` is 3: no process may ptrace.
+    ptrace_disabled: bool,
+    /// rd itself runs under a seccomp filter, e.g. docker's default profile,
+    /// which often blocks perf_event_open() and ptrace().
+    seccomp_filtered: bool,
+    in_container: bool,
+}
+
+impl Environment {
+    pub fn get() -> &'static Environment {
+        &*ENVIRONMENT
+    }
+
+    fn detect() -> Environment {
+        let osrelease = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        let ptrace_scope =
+            fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope").unwrap_or_default();
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let cgroup = fs::read_to_string("/proc/1/cgroup").unwrap_or_default();
+
+        Environment {
+            wsl1: osrelease.contains("Microsoft"),
+            wsl2: osrelease.contains("microsoft"),
+            hypervisor: hypervisor_vendor(),
+            pmu_exposed: pmu_exposed(),
+            ptrace_disabled: ptrace_scope.trim() == "3",
+            seccomp_filtered: status
+                .lines()
+                .any(|l| l.starts_with("Seccomp:") && l[8..].trim() == "2"),
+            in_container: Path::new("/.dockerenv").exists()
+                || Path::new("/run/.containerenv").exists()
+                || ["docker", "kubepods", "lxc", "containerd"]
+                    .iter()
+                    .any(|c| cgroup.contains(c)),
+        }
+    }
+
+    /// True if hardware performance counters certainly can't work here, so
+    /// record and replay should behave as if `--no-pmu` had been passed.
+    pub fn pmu_unavailable(&self) -> bool {
+        self.wsl2 || !self.pmu_exposed
+    }
+
+    /// Why hardware performance counters can't work here, if they can't.
+    pub fn pmu_unavailable_reason(&self) -> Option<String> {
+        if !self.pmu_unavailable() {
+            return None;
+        }
+        let why = if self.wsl2 {
+            "WSL2 doesn't give its VM a virtual PMU".to_owned()
+        } else {
+            match &self.hypervisor {
+                Some(vendor) => format!(
+                    "the hypervisor ({}) doesn't expose a virtual PMU; \
+                     enable vPMU / CPU performance counters for this VM",
+                    vendor
+                ),
+                None => "the CPU reports no hardware performance counters".to_owned(),
+            }
+        };
+        Some(why)
+    }
+
+    /// Refuse to run in environments rd can't work in, and explain any
+    /// degraded mode we chose automatically. Called by the record and replay
+    /// commands before creating a session.
+    pub fn check_for_record_or_replay(&self, pmu_requested: bool) {
+        if self.wsl1 {
+            clean_fatal!(
+                "rd can't run under Windows Subsystem for Linux 1, whose ptrace emulation\n\
+                 is incomplete. Use WSL2 (`wsl --set-version <distro> 2`) or a Linux VM."
+            );
+        }
+        if self.ptrace_disabled {
+            clean_fatal!(
+                "ptrace is disabled on this system (`/proc/sys/kernel/yama/ptrace_scope` is 3)\n\
+                 and can only be re-enabled by rebooting with a different setting."
+            );
+        }
+
+        let warn = !Flags::get().suppress_environment_warnings;
+        match self.pmu_unavailable_reason() {
+            Some(why) if pmu_requested && warn => eprintln!(
+                "rd: Hardware performance counters are unavailable: {}.\n\
+                 Continuing as if --no-pmu had been passed.",
+                why
+            ),
+            _ => (),
+        }
+        if self.seccomp_filtered && warn {
+            eprintln!(
+                "rd: rd is running under a seccomp filter{}, which may block the\n\
+                 ptrace() and perf_event_open() calls rd needs. If setup fails, run the\n\
+                 container with `--cap-add=SYS_PTRACE --security-opt seccomp=unconfined`.",
+                if self.in_container {
+                    " (probably from a container runtime)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    /// Warn about fanotify listeners: antivirus scanners and file indexers
+    /// see (and with permission events, gate) every file access the tracees
+    /// make. Called by the record command only, since replay doesn't touch
+    /// the files the tracees opened.
+    ///
+    /// Only listeners in processes we may inspect are found, so one running
+    /// as another user goes unnoticed unless rd runs as root. Agents built on
+    /// LSM hooks instead of fanotify can't be detected at all.
+    pub fn check_for_record(&self, isolate_file_access: bool) {
+        if Flags::get().suppress_environment_warnings {
+            return;
+        }
+        let listeners = fanotify_listeners();
+        if listeners.is_empty() {
+            return;
+        }
+        eprintln!(
+            "rd: These processes are watching file accesses with fanotify: {}.\n\
+             Antivirus scanners and indexers can delay the tracees' file accesses or make\n\
+             them fail. The recording captures whatever happened, but it may not be what\n\
+             happens without them, so pause them if the program behaves differently.{}",
+            listeners.join(", "),
+            if isolate_file_access {
+                ""
+            } else {
+                "\nPass --isolate-file-access so that replay doesn't depend on files they may\n\
+                 modify or quarantine."
+            }
+        );
+    }
+}
+
+/// The `comm (pid)` of each process we can see that has a fanotify fd open.
+fn fanotify_listeners() -> Vec<String> {
+    let mut listeners = Vec::new();
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return listeners,
+    };
+    for entry in procs.flatten() {
+        let pid = entry.file_name();
+        if !pid.as_bytes().iter().all(u8::is_ascii_digit) {
+            continue;
+        }
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            // Not ours to look at.
+            Err(_) => continue,
+        };
+        let has_fanotify = fds.flatten().any(|fd| {
+            fs::read_link(fd.path()).map_or(false, |target| {
+                target.as_os_str() == "anon_inode:[fanotify]"
+            })
+        });
+        if has_fanotify {
+            let comm = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            listeners.push(format!("{} ({})", comm.trim(), pid.to_string_lossy()));
+        }
+    }
+    listeners
+}
+
+fn hypervisor_vendor() -> Option<String> {
+    if cpuid(CPUID_GETFEATURES, 0).ecx & (1 << 31) == 0 {
+        return None;
+    }
+    let data = cpuid(CPUID_HYPERVISOR, 0);
+    let mut vendor = Vec::new();
+    for reg in &[data.ebx, data.ecx, data.edx] {
+        vendor.extend_from_slice(&reg.to_le_bytes());
+    }
+    let vendor = String::from_utf8_lossy(&vendor);
+    Some(vendor.trim_end_matches('\0').to_owned())
+}
+
+fn pmu_exposed() -> bool {
+    let vendor = cpuid(CPUID_GETVENDORSTRING, 0);
+    let is_intel =
+        vendor.ebx == 0x756e_6547 && vendor.edx == 0x4965_6e69 && vendor.ecx == 0x6c65_746e;
+    if !is_intel {
+        // Only Intel describes its counters in a CPUID leaf we can check. AMD
+        // CPUs always have the legacy counters architecturally.
+        return true;
+    }
+    if vendor.eax < CPUID_ARCH_PERFMON {
+        return false;
+    }
+    // Architectural performance monitoring version 0 means no counters. This
+    // is what VMs without a virtual PMU report.
+    cpuid(CPUID_ARCH_PERFMON, 0).eax & 0xff != 0
+}
@@ -309,6 +309,9 @@ struct preload_globals {
   /* Random seed that can be used for various purposes. DO NOT READ from rr
      during replay, because this field does not exist in old traces. */
   uint64_t random_seed;
+  /* 1 if reads of /dev/urandom and /dev/random are emulated because rd is
+     recording with --seed. Set by rd. */
+  unsigned char seeded_random;
 };
 
 /**
@@ -633,6 +636,21 @@ inline static int is_gcrypt_deny_file(const char* filename) {
   return streq("/etc/gcrypt/hwf.deny", filename);
 }
 
+/* Reads of these are emulated when recording with --seed, so rd must then see
+ * the open to install its monitor. See preload_globals::seeded_random. */
+inline static int is_dev_random(const char* filename) {
+  return streq("/dev/urandom", filename) || streq("/dev/random", filename);
+}
+
+/* rd saves a copy of these in the trace and installs a monitor that serves
+ * reads from it, so it must see the open. Keep in sync with util::is_locale_file. */
+inline static int is_locale_file(const char* filename) {
+  return strprefix("/usr/lib/locale/", filename) ||
+         strprefix("/usr/lib64/locale/", filename) ||
+         strprefix("/usr/share/locale/", filename) ||
+         strprefix("/usr/share/locale-langpack/", filename);
+}
+
 inline static int is_terminal(const char* filename) {
   return strprefix("/dev/tty", filename) || strprefix("/dev/pts", filename);
 }
@@ -655,7 +673,22 @@ inline static int is_proc_fd_dir(const char* filename) {
     fd_bit--;
   }
 
-  return strprefix("/fd", fd_bit - 3);
+  return strprefix("/fd", fd_bit - 3) ||
+         (len >= 13 && strprefix("/fdinfo", fd_bit - 7));
+}
+
+inline static int is_proc_fdinfo_file(const char* filename) {
+  if (!strprefix("/proc/", filename)) {
+    return 0;
+  }
+
+  const char* p;
+  for (p = filename + 6; *p; ++p) {
+    if (strprefix("/fdinfo/", p)) {
+      return 1;
+    }
+  }
+  return 0;
 }
 
 /**
@@ -669,7 +702,8 @@ inline static int allow_buffered_open(const char* filename) {
   return filename &&
          !is_blacklisted_filename(filename) && !is_gcrypt_deny_file(filename) &&
          !is_terminal(filename) && !is_proc_mem_file(filename) &&
-         !is_proc_fd_dir(filename);
+         !is_proc_fd_dir(filename) && !is_proc_fdinfo_file(filename) &&
+         !is_locale_file(filename);
 }
 
 #endif /* RR_PRELOAD_INTERFACE_H_ */
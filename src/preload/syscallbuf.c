@@ -1743,7 +1743,8 @@ static int check_file_open_ok(const struct syscall_info* call, int ret, int did_
   long link_ret = sys_readlink(&readlink_call);
   if (link_ret >= 0 && link_ret < (ssize_t)sizeof(link)) {
     link[link_ret] = 0;
-    if (allow_buffered_open(link)) {
+    if (allow_buffered_open(link) &&
+        !(globals.seeded_random && is_dev_random(link))) {
       return ret;
     }
   }
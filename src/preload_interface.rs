@@ -180,6 +180,9 @@ pub struct preload_globals {
     /// Random seed that can be used for various purposes. DO NOT READ from rd
     /// during replay, because this field does not exist in old traces.
     pub random_seed: u64,
+    /// 1 if reads of /dev/urandom and /dev/random are emulated because rd is
+    /// recording with --seed. Set by rd.
+    pub seeded_random: u8,
 }
 
 /// The syscall buffer comprises an array of these variable-length
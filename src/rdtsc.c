@@ -8,3 +8,7 @@
 unsigned long long rdtsc(void) { return __rdtsc(); }
 
 
+
+// Like rdtsc() but also returns IA32_TSC_AUX (usually the cpu number), which
+// rdtscp leaves in ecx.
+unsigned long long rdtscp(unsigned int* aux) { return __rdtscp(aux); }
@@ -7,7 +7,8 @@ use crate::{
         signal::{siginfo_t, POLL_IN},
     },
     event::{
-        DeschedEventData, Event, EventType, SignalDeterministic, SignalEventData, SyscallEventData,
+        DeschedEventData, Event, EventType, SdtProbeEventData, SignalDeterministic,
+        SignalEventData, SyscallEventData,
     },
     file_monitor::virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
     kernel_abi::{
@@ -34,9 +35,10 @@ use crate::{
         },
     },
     sig::{self, Sig},
+    stap_sdt::sdt_arg_value,
     util::{
-        ceil_page_size, cpuid, floor_page_size, page_size, signal_bit, trapped_instruction_at,
-        trapped_instruction_len, TrappedInstruction,
+        ceil_page_size, cpuid, floor_page_size, is_kernel_trap, page_size, signal_bit,
+        trapped_instruction_at, trapped_instruction_len, TrappedInstruction,
     },
     wait_status::WaitStatus,
 };
@@ -57,6 +59,7 @@ use std::{
 
 extern "C" {
     fn rdtsc() -> u64;
+    fn rdtscp(aux: *mut u32) -> u64;
 }
 
 pub const SIGCHLD_SYNTHETIC: i32 = 0xbeadf00du32 as i32;
@@ -161,6 +164,23 @@ pub fn handle_signal(
             return (SignalHandled::DeferSignal, si);
         }
 
+        // Replay finds the point at which an asynchronous signal was delivered
+        // using the tick count. Without perf counters that only identifies the
+        // point if the task was singlestepped since its last event, so
+        // otherwise hold the signal back until the next system call.
+        if perf_counters::pmu_disabled()
+            && deterministic == SignalDeterministic::NondeterministicSig
+            && !t.ev().is_syscall_event()
+            && t.stepping_for_ticks.get() != Some(true)
+        {
+            log!(
+                LogDebug,
+                "Deferring {} until the next syscall because the PMU is disabled",
+                sig
+            );
+            return (SignalHandled::DeferSignal, si);
+        }
+
         if !t.set_siginfo_for_synthetic_sigchld(&mut si) {
             return (SignalHandled::DeferSignal, si);
         }
@@ -301,13 +321,26 @@ fn try_handle_trapped_instruction(t: &RecordTask, si: &siginfo_t) -> bool {
     ed_assert!(t, len > 0);
 
     let mut r: Registers = t.regs_ref().clone();
-    if trapped_instruction == TrappedInstruction::Rdtsc
-        || trapped_instruction == TrappedInstruction::Rdtscp
-    {
+    if trapped_instruction == TrappedInstruction::Rdtsc {
         let current_time = unsafe { rdtsc() };
         r.set_rdtsc_output(current_time);
 
         log!(LogDebug, " trapped for rdtsc: returning {}", current_time);
+    } else if trapped_instruction == TrappedInstruction::Rdtscp {
+        // rdtscp also writes IA32_TSC_AUX to ecx. The tracee sees whatever our
+        // cpu's value is, and replay restores it from the recorded registers
+        // like the timestamp itself.
+        let mut tsc_aux: u32 = 0;
+        let current_time = unsafe { rdtscp(&mut tsc_aux) };
+        r.set_rdtsc_output(current_time);
+        r.set_cx(tsc_aux as usize);
+
+        log!(
+            LogDebug,
+            " trapped for rdtscp: returning {} (aux {})",
+            current_time,
+            tsc_aux
+        );
     } else if trapped_instruction == TrappedInstruction::CpuId {
         let eax = r.syscallno() as u32;
         let ecx = r.cx() as u32;
@@ -502,6 +535,40 @@ fn get_stub_scratch_1(t: &RecordTask) -> RemoteCodePtr {
     rd_arch_function_selfless!(get_stub_scratch_1_arch, arch, t)
 }
 
+/// Called when we get a SIGTRAP. If it came from a breakpoint that
+/// `rd record --stap-sdt` planted at an SDT probe site, record the probe hit
+/// and return true: the SIGTRAP should be hidden from the application. The
+/// breakpoint replaced the probe's `nop`, so `t` is now where it would be after
+/// executing it and can simply carry on.
+pub fn handle_sdt_probe_breakpoint(t: &RecordTask) -> bool {
+    if !is_kernel_trap(t.get_siginfo().si_code) {
+        return false;
+    }
+    let addr = t.ip().decrement_by_bkpt_insn_length(t.arch());
+    let probe = match t.vm().sdt_probe_at(addr) {
+        Some(probe) => probe,
+        None => return false,
+    };
+    log!(
+        LogDebug,
+        "Hit SDT probe {}:{} at {}",
+        probe.provider,
+        probe.name,
+        addr
+    );
+
+    let args = probe.args.iter().map(|arg| sdt_arg_value(t, arg)).collect();
+    restore_sighandler_if_not_default(t, sig::SIGTRAP);
+    t.push_event(Event::new_sdt_probe_event(SdtProbeEventData {
+        provider: probe.provider.clone(),
+        name: probe.name.clone(),
+        args,
+    }));
+    t.record_current_event();
+    t.pop_event(EventType::EvSdtProbe);
+    true
+}
+
 /// This function is responsible for handling breakpoints we set in syscallbuf
 /// code to detect sigprocmask calls and syscallbuf exit. It's called when we
 /// get a SIGTRAP. Returns true if the SIGTRAP was called by one of our
@@ -13,39 +13,39 @@ use crate::{
         fcntl,
         kernel::{
             semid64_ds, seminfo, shmid64_ds, user_desc, vfs_cap_data, CAP_SYS_ADMIN, FIOASYNC,
-            FIOCLEX, FIONBIO, FIONCLEX, GETALL, GETNCNT, GETPID, GETVAL, GETZCNT, IPC_64, IPC_INFO,
-            IPC_RMID, IPC_SET, IPC_STAT, MSGCTL, MSGGET, MSGRCV, MSGSND, MSG_INFO, MSG_STAT,
-            NT_FPREGSET, NT_PRSTATUS, NT_X86_XSTATE, SEMCTL, SEMGET, SEMOP, SEMTIMEDOP, SEM_INFO,
-            SEM_STAT, SETALL, SETVAL, SG_GET_VERSION_NUM, SG_IO, SHMAT, SHMCTL, SHMDT, SHMGET,
-            SHM_INFO, SHM_LOCK, SHM_STAT, SHM_UNLOCK, SIOCADDMULTI, SIOCADDRT, SIOCBONDINFOQUERY,
-            SIOCBRADDBR, SIOCBRADDIF, SIOCBRDELBR, SIOCBRDELIF, SIOCDELMULTI, SIOCDELRT,
-            SIOCETHTOOL, SIOCGIFADDR, SIOCGIFBRDADDR, SIOCGIFCONF, SIOCGIFDSTADDR, SIOCGIFFLAGS,
-            SIOCGIFHWADDR, SIOCGIFINDEX, SIOCGIFMAP, SIOCGIFMETRIC, SIOCGIFMTU, SIOCGIFNAME,
-            SIOCGIFNETMASK, SIOCGIFPFLAGS, SIOCGIFTXQLEN, SIOCGIWESSID, SIOCGIWFREQ, SIOCGIWMODE,
-            SIOCGIWNAME, SIOCGIWRATE, SIOCGIWSENS, SIOCGSTAMP, SIOCGSTAMPNS, SIOCSIFADDR,
-            SIOCSIFBRDADDR, SIOCSIFDSTADDR, SIOCSIFFLAGS, SIOCSIFHWADDR, SIOCSIFHWBROADCAST,
-            SIOCSIFMAP, SIOCSIFMETRIC, SIOCSIFMTU, SIOCSIFNAME, SIOCSIFNETMASK, SIOCSIFPFLAGS,
-            SIOCSIFTXQLEN, SUBCMDSHIFT, SYS_ACCEPT, SYS_ACCEPT4, SYS_BIND, SYS_CONNECT,
-            SYS_GETPEERNAME, SYS_GETSOCKNAME, SYS_GETSOCKOPT, SYS_LISTEN, SYS_RECV, SYS_RECVFROM,
-            SYS_RECVMMSG, SYS_RECVMSG, SYS_SEND, SYS_SENDMMSG, SYS_SENDMSG, SYS_SENDTO,
-            SYS_SETSOCKOPT, SYS_SHUTDOWN, SYS_SOCKET, SYS_SOCKETPAIR, S_ISGID, S_ISUID, TCFLSH,
-            TCGETA, TCGETS, TCSBRK, TCSBRKP, TCSETA, TCSETAF, TCSETAW, TCSETS, TCSETSF, TCSETSW,
-            TCXONC, TIOCCBRK, TIOCCONS, TIOCEXCL, TIOCGETD, TIOCGLCKTRMIOS, TIOCGPGRP, TIOCGSID,
-            TIOCGWINSZ, TIOCINQ, TIOCNOTTY, TIOCNXCL, TIOCOUTQ, TIOCPKT, TIOCSBRK, TIOCSCTTY,
-            TIOCSETD, TIOCSLCKTRMIOS, TIOCSPGRP, TIOCSTI, TIOCSWINSZ, USBDEVFS_URB_TYPE_ISO,
-            V4L2_MEMORY_MMAP, _IOC_READ, _IOC_SIZEMASK, _IOC_SIZESHIFT, _LINUX_CAPABILITY_U32S_1,
-            _LINUX_CAPABILITY_U32S_2, _LINUX_CAPABILITY_U32S_3, _LINUX_CAPABILITY_VERSION_1,
-            _LINUX_CAPABILITY_VERSION_2, _LINUX_CAPABILITY_VERSION_3, _SNDRV_CTL_IOCTL_CARD_INFO,
-            _SNDRV_CTL_IOCTL_PVERSION, _VIDIOC_DQBUF, _VIDIOC_ENUMINPUT, _VIDIOC_ENUM_FMT,
-            _VIDIOC_ENUM_FRAMEINTERVALS, _VIDIOC_ENUM_FRAMESIZES, _VIDIOC_G_CTRL, _VIDIOC_G_FMT,
-            _VIDIOC_G_OUTPUT, _VIDIOC_G_PARM, _VIDIOC_QBUF, _VIDIOC_QUERYBUF, _VIDIOC_QUERYCAP,
-            _VIDIOC_QUERYCTRL, _VIDIOC_REQBUFS, _VIDIOC_S_CTRL, _VIDIOC_S_FMT, _VIDIOC_S_PARM,
-            _VIDIOC_TRY_FMT,
+            FIOCLEX, FIONBIO, FIONCLEX, FIOQSIZE, GETALL, GETNCNT, GETPID, GETVAL, GETZCNT, IPC_64,
+            IPC_INFO, IPC_RMID, IPC_SET, IPC_STAT, KCMP_FILE, MSGCTL, MSGGET, MSGRCV, MSGSND,
+            MSG_INFO, MSG_STAT, NT_FPREGSET, NT_PRSTATUS, NT_X86_XSTATE, SEMCTL, SEMGET, SEMOP,
+            SEMTIMEDOP, SEM_INFO, SEM_STAT, SETALL, SETVAL, SG_GET_VERSION_NUM, SG_IO, SHMAT,
+            SHMCTL, SHMDT, SHMGET, SHM_INFO, SHM_LOCK, SHM_STAT, SHM_UNLOCK, SIOCADDMULTI,
+            SIOCADDRT, SIOCBONDINFOQUERY, SIOCBRADDBR, SIOCBRADDIF, SIOCBRDELBR, SIOCBRDELIF,
+            SIOCDELMULTI, SIOCDELRT, SIOCETHTOOL, SIOCGIFADDR, SIOCGIFBRDADDR, SIOCGIFCONF,
+            SIOCGIFDSTADDR, SIOCGIFFLAGS, SIOCGIFHWADDR, SIOCGIFINDEX, SIOCGIFMAP, SIOCGIFMETRIC,
+            SIOCGIFMTU, SIOCGIFNAME, SIOCGIFNETMASK, SIOCGIFPFLAGS, SIOCGIFTXQLEN, SIOCGIWESSID,
+            SIOCGIWFREQ, SIOCGIWMODE, SIOCGIWNAME, SIOCGIWRATE, SIOCGIWSENS, SIOCGSTAMP,
+            SIOCGSTAMPNS, SIOCSIFADDR, SIOCSIFBRDADDR, SIOCSIFDSTADDR, SIOCSIFFLAGS, SIOCSIFHWADDR,
+            SIOCSIFHWBROADCAST, SIOCSIFMAP, SIOCSIFMETRIC, SIOCSIFMTU, SIOCSIFNAME, SIOCSIFNETMASK,
+            SIOCSIFPFLAGS, SIOCSIFTXQLEN, SUBCMDSHIFT, SYS_ACCEPT, SYS_ACCEPT4, SYS_BIND,
+            SYS_CONNECT, SYS_GETPEERNAME, SYS_GETSOCKNAME, SYS_GETSOCKOPT, SYS_LISTEN, SYS_RECV,
+            SYS_RECVFROM, SYS_RECVMMSG, SYS_RECVMSG, SYS_SEND, SYS_SENDMMSG, SYS_SENDMSG,
+            SYS_SENDTO, SYS_SETSOCKOPT, SYS_SHUTDOWN, SYS_SOCKET, SYS_SOCKETPAIR, S_ISGID, S_ISUID,
+            TCFLSH, TCGETA, TCGETS, TCSBRK, TCSBRKP, TCSETA, TCSETAF, TCSETAW, TCSETS, TCSETSF,
+            TCSETSW, TCXONC, TIOCCBRK, TIOCCONS, TIOCEXCL, TIOCGETD, TIOCGLCKTRMIOS, TIOCGPGRP,
+            TIOCGSID, TIOCGWINSZ, TIOCINQ, TIOCMGET, TIOCNOTTY, TIOCNXCL, TIOCOUTQ, TIOCPKT,
+            TIOCSBRK, TIOCSCTTY, TIOCSETD, TIOCSLCKTRMIOS, TIOCSPGRP, TIOCSTI, TIOCSWINSZ,
+            USBDEVFS_URB_TYPE_ISO, V4L2_MEMORY_MMAP, _IOC_READ, _IOC_SIZEMASK, _IOC_SIZESHIFT,
+            _LINUX_CAPABILITY_U32S_1, _LINUX_CAPABILITY_U32S_2, _LINUX_CAPABILITY_U32S_3,
+            _LINUX_CAPABILITY_VERSION_1, _LINUX_CAPABILITY_VERSION_2, _LINUX_CAPABILITY_VERSION_3,
+            _SNDRV_CTL_IOCTL_CARD_INFO, _SNDRV_CTL_IOCTL_PVERSION, _VIDIOC_DQBUF,
+            _VIDIOC_ENUMINPUT, _VIDIOC_ENUM_FMT, _VIDIOC_ENUM_FRAMEINTERVALS,
+            _VIDIOC_ENUM_FRAMESIZES, _VIDIOC_G_CTRL, _VIDIOC_G_FMT, _VIDIOC_G_OUTPUT,
+            _VIDIOC_G_PARM, _VIDIOC_QBUF, _VIDIOC_QUERYBUF, _VIDIOC_QUERYCAP, _VIDIOC_QUERYCTRL,
+            _VIDIOC_REQBUFS, _VIDIOC_S_CTRL, _VIDIOC_S_FMT, _VIDIOC_S_PARM, _VIDIOC_TRY_FMT,
         },
         misc_for_ioctl::{
             _EVIOCGEFFECTS, _EVIOCGID, _EVIOCGKEYCODE, _EVIOCGKEY_0, _EVIOCGLED_0, _EVIOCGMASK,
             _EVIOCGMTSLOTS_0, _EVIOCGNAME_0, _EVIOCGPHYS_0, _EVIOCGPROP_0, _EVIOCGREP,
-            _EVIOCGSND_0, _EVIOCGSW_0, _EVIOCGUNIQ_0, _EVIOCGVERSION, _FS_IOC_GETFLAGS,
+            _EVIOCGSND_0, _EVIOCGSW_0, _EVIOCGUNIQ_0, _EVIOCGVERSION, _FIGETBSZ, _FS_IOC_GETFLAGS,
             _FS_IOC_GETVERSION, _JSIOCGAXES, _JSIOCGAXMAP, _JSIOCGBTNMAP, _JSIOCGBUTTONS,
             _JSIOCGNAME_0, _JSIOCGVERSION, _VFAT_IOCTL_READDIR_BOTH,
         },
@@ -78,47 +78,60 @@ use crate::{
         signal::{siginfo_t as siginfo_t_signal, SI_USER},
     },
     event::{
-        Event, EventType, OpenedFd, SignalDeterministic, SignalEventData, Switchable, SyscallState,
+        Event, EventType, OpenedFd, SignalDeterministic, SignalEventData, Switchable,
+        SyscallEventData, SyscallState,
     },
     extra_registers::Format,
     fd_table::FdTable,
     file_monitor::{
-        self, base_file_monitor::BaseFileMonitor, mmapped_file_monitor::MmappedFileMonitor,
-        proc_fd_dir_monitor::ProcFdDirMonitor, proc_mem_monitor::ProcMemMonitor,
-        stdio_monitor::StdioMonitor, virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+        self,
+        anon_fd_monitor::{AnonFdKind, AnonFdMonitor},
+        base_file_monitor::BaseFileMonitor,
+        cgroup_file_monitor::CgroupFileMonitor,
+        locale_file_monitor::LocaleFileMonitor,
+        mmapped_file_monitor::MmappedFileMonitor,
+        proc_fd_dir_monitor::ProcFdDirMonitor,
+        proc_mem_monitor::ProcMemMonitor,
+        random_file_monitor::RandomFileMonitor,
+        stdio_monitor::StdioMonitor,
+        virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
         FileMonitor, LazyOffset, Range,
     },
     kernel_abi::{
-        common, is_at_syscall_instruction, is_clone_syscall, is_exit_group_syscall,
+        common::{self, clone_args},
+        is_at_syscall_instruction, is_clone3_syscall, is_clone_syscall, is_exit_group_syscall,
         is_exit_syscall, is_vfork_syscall, syscall_instruction_length, syscall_number_for_close,
-        syscall_number_for_munmap, syscall_number_for_openat, syscall_number_for_pause,
-        syscall_number_for_rt_sigprocmask, x64, x86, CloneTLSType, FcntlOperation,
-        MmapCallingSemantics, Ptr, SelectCallingSemantics, SupportedArch,
+        syscall_number_for_execve, syscall_number_for_munmap, syscall_number_for_openat,
+        syscall_number_for_pause, syscall_number_for_rt_sigprocmask, x64, x86, CloneTLSType,
+        FcntlOperation, MmapCallingSemantics, Ptr, SelectCallingSemantics, SupportedArch,
     },
     kernel_metadata::{
         errno_name, is_sigreturn, ptrace_req_name, shm_flags_to_mmap_prot, syscall_name,
     },
     kernel_supplement::{
-        sig_set_t, BPF_MAP_CREATE, BPF_MAP_DELETE_ELEM, BPF_MAP_UPDATE_ELEM, BPF_PROG_LOAD,
-        BTRFS_IOC_CLONE_, BTRFS_IOC_CLONE_RANGE_, NUM_SIGNALS, PTRACE_OLDSETOPTIONS,
-        SECCOMP_SET_MODE_FILTER, SECCOMP_SET_MODE_STRICT, SO_SET_REPLACE, _HCIGETDEVINFO,
-        _HCIGETDEVLIST, _TIOCGEXCL, _TIOCGPKT, _TIOCGPTLCK, _TIOCGPTN, _TIOCGPTPEER, _TIOCSPTLCK,
-        _TUNATTACHFILTER, _TUNDETACHFILTER, _TUNGETFEATURES, _TUNGETFILTER, _TUNGETIFF,
-        _TUNGETSNDBUF, _TUNGETVNETBE, _TUNGETVNETHDRSZ, _TUNGETVNETLE, _TUNSETDEBUG, _TUNSETGROUP,
-        _TUNSETIFF, _TUNSETIFINDEX, _TUNSETLINK, _TUNSETNOCSUM, _TUNSETOFFLOAD, _TUNSETOWNER,
-        _TUNSETPERSIST, _TUNSETQUEUE, _TUNSETSNDBUF, _TUNSETTXFILTER, _TUNSETVNETBE,
-        _TUNSETVNETHDRSZ, _TUNSETVNETLE, _USBDEVFS_ALLOC_STREAMS, _USBDEVFS_CLAIMINTERFACE,
-        _USBDEVFS_CLEAR_HALT, _USBDEVFS_CONTROL, _USBDEVFS_DISCARDURB, _USBDEVFS_DISCONNECT_CLAIM,
-        _USBDEVFS_FREE_STREAMS, _USBDEVFS_GETDRIVER, _USBDEVFS_GET_CAPABILITIES, _USBDEVFS_IOCTL,
-        _USBDEVFS_REAPURB, _USBDEVFS_REAPURBNDELAY, _USBDEVFS_RELEASEINTERFACE, _USBDEVFS_RESET,
-        _USBDEVFS_SETCONFIGURATION, _USBDEVFS_SETINTERFACE, _USBDEVFS_SUBMITURB,
+        page_region, pm_scan_arg, sig_set_t, BPF_MAP_CREATE, BPF_MAP_DELETE_ELEM,
+        BPF_MAP_UPDATE_ELEM, BPF_PROG_LOAD, BTRFS_IOC_CLONE_, BTRFS_IOC_CLONE_RANGE_, MADV_COLD,
+        MADV_COLLAPSE, MADV_KEEPONFORK, MADV_PAGEOUT, MADV_POPULATE_READ, MADV_POPULATE_WRITE,
+        MADV_WIPEONFORK, NUM_SIGNALS, PR_SET_SYSCALL_USER_DISPATCH, PR_SYS_DISPATCH_OFF,
+        PR_SYS_DISPATCH_ON, PTRACE_OLDSETOPTIONS, SECCOMP_SET_MODE_FILTER, SECCOMP_SET_MODE_STRICT,
+        SO_SET_REPLACE, _HCIGETDEVINFO, _HCIGETDEVLIST, _PAGEMAP_SCAN, _TIOCGEXCL, _TIOCGPKT,
+        _TIOCGPTLCK, _TIOCGPTN, _TIOCGPTPEER, _TIOCSPTLCK, _TUNATTACHFILTER, _TUNDETACHFILTER,
+        _TUNGETFEATURES, _TUNGETFILTER, _TUNGETIFF, _TUNGETSNDBUF, _TUNGETVNETBE, _TUNGETVNETHDRSZ,
+        _TUNGETVNETLE, _TUNSETDEBUG, _TUNSETGROUP, _TUNSETIFF, _TUNSETIFINDEX, _TUNSETLINK,
+        _TUNSETNOCSUM, _TUNSETOFFLOAD, _TUNSETOWNER, _TUNSETPERSIST, _TUNSETQUEUE, _TUNSETSNDBUF,
+        _TUNSETTXFILTER, _TUNSETVNETBE, _TUNSETVNETHDRSZ, _TUNSETVNETLE, _USBDEVFS_ALLOC_STREAMS,
+        _USBDEVFS_CLAIMINTERFACE, _USBDEVFS_CLEAR_HALT, _USBDEVFS_CONTROL, _USBDEVFS_DISCARDURB,
+        _USBDEVFS_DISCONNECT_CLAIM, _USBDEVFS_FREE_STREAMS, _USBDEVFS_GETDRIVER,
+        _USBDEVFS_GET_CAPABILITIES, _USBDEVFS_IOCTL, _USBDEVFS_REAPURB, _USBDEVFS_REAPURBNDELAY,
+        _USBDEVFS_RELEASEINTERFACE, _USBDEVFS_RESET, _USBDEVFS_SETCONFIGURATION,
+        _USBDEVFS_SETINTERFACE, _USBDEVFS_SUBMITURB,
     },
     log::{LogDebug, LogInfo, LogWarn},
     monitored_shared_memory::MonitoredSharedMemory,
     monkey_patcher::MmapMode,
     preload_interface::{
         syscallbuf_hdr, syscallbuf_record, SYS_rdcall_init_buffers, SYS_rdcall_init_preload,
-        SYS_rdcall_notify_control_msg, SYS_rdcall_notify_syscall_hook_exit,
+        SYS_rdcall_notify_control_msg, SYS_rdcall_notify_syscall_hook_exit, SYS_rdcall_reload_auxv,
     },
     preload_interface_arch::rdcall_init_buffers_params,
     rd::RD_RESERVED_ROOT_DIR_FD,
@@ -131,26 +144,29 @@ use crate::{
         address_space::{
             address_space::AddressSpace, kernel_mapping::KernelMapping, read_kernel_mapping,
         },
-        record_session::set_arch_siginfo,
+        record_session::{set_arch_siginfo, IoUringPolicy},
         session_inner::SessionInner,
         task::{
             record_task::{EmulatedStopType, RecordTask, WaitType},
-            task_common::{read_mem, read_val_mem, write_mem, write_val_mem},
+            task_common::{read_mem, read_val_mem, will_sigreturn, write_mem, write_val_mem},
             task_inner::{ResumeRequest, TicksRequest, WaitRequest, WriteFlags},
             Task, TaskSharedPtr, TaskSharedWeakPtr,
         },
     },
     sig,
     sig::Sig,
+    stap_sdt::plant_sdt_probes,
     trace::{
         trace_stream::TraceRemoteFd,
-        trace_task_event::TraceTaskEvent,
+        trace_task_event::{AttachedThread, TraceTaskEvent},
         trace_writer::{MappingOrigin, RecordInTrace},
     },
     util::{
         ceil_page_size, clone_flags_to_task_flags, copy_file, create_temporary_file,
-        extract_clone_parameters, has_effective_caps, is_proc_fd_dir, is_proc_mem_file, page_size,
-        read_auxv, u8_slice_mut, word_at, word_size, write_all, CloneParameters,
+        extract_clone3_parameters, extract_clone_parameters, has_effective_caps, is_dev_random,
+        is_locale_file, is_proc_fd_dir, is_proc_mem_file, page_size, parse_proc_fdinfo_file,
+        read_auxv, read_clone_args, u8_slice_mut, word_at, word_size, write_all, CloneParameters,
+        CLONE_CLEAR_SIGHAND, CLONE_PIDFD,
     },
     wait_status::WaitStatus,
     weak_ptr_set::WeakPtrSet,
@@ -160,9 +176,9 @@ use file_monitor::FileMonitorType;
 use libc::{
     cpu_set_t, getxattr, id_t, idtype_t, memcmp, pid_t, sockaddr_un, socklen_t, SYS_tgkill,
     ADDR_COMPAT_LAYOUT, ADDR_LIMIT_32BIT, ADDR_LIMIT_3GB, ADDR_NO_RANDOMIZE, AF_UNIX, AT_ENTRY,
-    CLONE_PARENT, CLONE_THREAD, CLONE_UNTRACED, CLONE_VFORK, CLONE_VM, EACCES, EFAULT, EINVAL, EIO,
-    ENODATA, ENODEV, ENOENT, ENOPROTOOPT, ENOSYS, ENOTBLK, ENOTSUP, ENOTTY, EPERM, ESRCH,
-    FDPIC_FUNCPTRS, FUTEX_CMD_MASK, FUTEX_CMP_REQUEUE, FUTEX_CMP_REQUEUE_PI, FUTEX_LOCK_PI,
+    CLONE_PARENT, CLONE_THREAD, CLONE_UNTRACED, CLONE_VFORK, CLONE_VM, EACCES, EBADF, EFAULT,
+    EINVAL, EIO, ENODATA, ENODEV, ENOENT, ENOPROTOOPT, ENOSYS, ENOTBLK, ENOTSUP, ENOTTY, EPERM,
+    ESRCH, FDPIC_FUNCPTRS, FUTEX_CMD_MASK, FUTEX_CMP_REQUEUE, FUTEX_CMP_REQUEUE_PI, FUTEX_LOCK_PI,
     FUTEX_TRYLOCK_PI, FUTEX_UNLOCK_PI, FUTEX_WAIT, FUTEX_WAIT_BITSET, FUTEX_WAIT_REQUEUE_PI,
     FUTEX_WAKE, FUTEX_WAKE_BITSET, FUTEX_WAKE_OP, GRND_NONBLOCK, IPPROTO_IP, IPPROTO_IPV6,
     KEYCTL_ASSUME_AUTHORITY, KEYCTL_CHOWN, KEYCTL_CLEAR, KEYCTL_DESCRIBE, KEYCTL_DH_COMPUTE,
@@ -175,8 +191,8 @@ use libc::{
     MADV_SEQUENTIAL, MADV_SOFT_OFFLINE, MADV_UNMERGEABLE, MADV_WILLNEED, MAP_32BIT, MAP_FIXED,
     MAP_GROWSDOWN, MMAP_PAGE_ZERO, MSG_DONTWAIT, O_DIRECT, O_RDONLY, PRIO_PROCESS, P_ALL, P_PGID,
     P_PID, Q_GETFMT, Q_GETINFO, Q_GETQUOTA, Q_QUOTAOFF, Q_QUOTAON, Q_SETINFO, Q_SETQUOTA, Q_SYNC,
-    READ_IMPLIES_EXEC, SCM_RIGHTS, SECCOMP_MODE_FILTER, SECCOMP_MODE_STRICT, SHORT_INODE, SIGCHLD,
-    SIGKILL, SIGSTOP, SIG_BLOCK, SOL_PACKET, SOL_SOCKET, STDERR_FILENO, STDIN_FILENO,
+    READ_IMPLIES_EXEC, SCM_RIGHTS, SECCOMP_MODE_FILTER, SECCOMP_MODE_STRICT, SEEK_SET, SHORT_INODE,
+    SIGCHLD, SIGKILL, SIGSTOP, SIG_BLOCK, SOL_PACKET, SOL_SOCKET, STDERR_FILENO, STDIN_FILENO,
     STDOUT_FILENO, STICKY_TIMEOUTS, S_IWUSR, UNAME26, WHOLE_SECONDS, WNOHANG, WNOWAIT, WUNTRACED,
 };
 use mem::size_of_val;
@@ -195,12 +211,15 @@ use std::{
     convert::{TryFrom, TryInto},
     env,
     ffi::{CStr, OsStr, OsString},
-    fs::read_dir,
+    fs::{self, read_dir},
     intrinsics::{copy_nonoverlapping, transmute},
     mem::{self, size_of},
     os::{
         raw::c_uint,
-        unix::ffi::{OsStrExt, OsStringExt},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{FileExt, MetadataExt},
+        },
     },
     path::Path,
     rc::Rc,
@@ -258,6 +277,24 @@ fn rec_prepare_syscall_internal(t: &RecordTask) -> Switchable {
     })
 }
 
+/// Advice values newer than the libc we build against. These don't change the
+/// contents of memory as seen by this process (MADV_PAGEOUT'd pages are read
+/// back from swap or the backing file), so they're safe to let through.
+/// MADV_WIPEONFORK only affects the children of future forks; it's performed
+/// again at replay so the children see the same zeroed pages.
+fn is_content_preserving_madvise(advice: u32) -> bool {
+    matches!(
+        advice,
+        MADV_WIPEONFORK
+            | MADV_KEEPONFORK
+            | MADV_COLD
+            | MADV_PAGEOUT
+            | MADV_POPULATE_READ
+            | MADV_POPULATE_WRITE
+            | MADV_COLLAPSE
+    )
+}
+
 /// DIFF NOTE: Does not take separate TaskSyscallState param
 /// as that can be gotten from t directly
 fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers) -> Switchable {
@@ -301,6 +338,23 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::PreventSwitch;
     }
 
+    if let Some(errno) = t.session().as_record().unwrap().maybe_inject_error(t) {
+        log!(
+            LogInfo,
+            "Injecting {} into {}",
+            errno_name(errno),
+            syscall_name(sys, Arch::arch())
+        );
+        // Hijack the syscall so it has no effect. The real syscall number is
+        // restored in rec_process_syscall_arch().
+        let mut r: Registers = regs.clone();
+        r.set_original_syscallno(Arch::GETTID as isize);
+        t.set_regs(&r);
+        syscall_state.emulate_result_signed(-errno as isize);
+        syscall_state.error_injected = true;
+        return Switchable::PreventSwitch;
+    }
+
     include!(concat!(
         env!("OUT_DIR"),
         "/syscall_record_case_generated.rs"
@@ -352,6 +406,18 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::PreventSwitch;
     }
 
+    if sys == Arch::SIGNALFD || sys == Arch::SIGNALFD4 {
+        // Don't let the tracee dequeue TIME_SLICE_SIGNAL or
+        // SYSCALLBUF_DESCHED_SIGNAL through a signalfd.
+        syscall_state.reg_parameter::<Arch::kernel_sigset_t>(
+            2,
+            Some(ArgMode::In),
+            Some(Box::new(protect_rd_sigs)),
+        );
+
+        return Switchable::PreventSwitch;
+    }
+
     if sys == Arch::WRITE || sys == Arch::WRITEV {
         let fd = regs.arg1_signed() as i32;
         return t.fd_table().will_write(t, fd);
@@ -530,6 +596,34 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
                 syscall_state.emulate_result(0);
             }
 
+            PR_SET_SYSCALL_USER_DISPATCH => {
+                // Syscall user dispatch sends every syscall made outside one
+                // address range to the tracee's SIGSYS handler. That would include
+                // the syscalls the syscallbuf and rd itself make on the tracee's
+                // behalf, which can't be moved into that range. So never enable it,
+                // and fail the way kernels without it do: programs that use it
+                // (e.g. Wine) have a fallback for those.
+                // DIFF NOTE: rr lets this prctl reach the kernel.
+                let mut r: Registers = regs.clone();
+                r.set_arg1_signed(-1);
+                t.set_regs(&r);
+                match regs.arg2() as u32 {
+                    PR_SYS_DISPATCH_OFF => syscall_state.emulate_result(0),
+                    PR_SYS_DISPATCH_ON => {
+                        log!(
+                            LogWarn,
+                            "Task {} tried to enable syscall user dispatch \
+                             (PR_SET_SYSCALL_USER_DISPATCH), which rd can't record; \
+                             failing it with EINVAL. If the program doesn't fall back \
+                             to something else, it can't be recorded.",
+                            t.tid()
+                        );
+                        syscall_state.emulate_result_signed(-EINVAL as isize);
+                    }
+                    _ => syscall_state.emulate_result_signed(-EINVAL as isize),
+                }
+            }
+
             _ => {
                 syscall_state.expect_errno = EINVAL;
             }
@@ -554,8 +648,24 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
     // addresses.
     if sys == Arch::FUTEX_TIME64 || sys == Arch::FUTEX {
         let op = regs.arg2_signed() as i32;
+        let futex_addr = RemotePtr::<Void>::from(regs.arg1());
+        if let Some(real_addr) = MonitoredSharedMemory::real_addr(t, futex_addr) {
+            return prepare_monitored_futex(t, &mut syscall_state, regs, real_addr);
+        }
         match op & FUTEX_CMD_MASK {
-            FUTEX_WAIT | FUTEX_WAIT_BITSET => return Switchable::AllowSwitch,
+            FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+                // A process we don't trace may have changed the futex word
+                // in shared memory before waking us. Record it, so that
+                // replay sees the value we woke up to.
+                let shared = t
+                    .vm()
+                    .mapping_of(futex_addr)
+                    .map_or(false, |m| m.map.flags().contains(MapFlags::MAP_SHARED));
+                if shared {
+                    syscall_state.reg_parameter::<u32>(1, Some(ArgMode::InOutNoScratch), None);
+                }
+                return Switchable::AllowSwitch;
+            }
 
             FUTEX_CMP_REQUEUE | FUTEX_WAKE_OP => {
                 syscall_state.reg_parameter::<i32>(5, Some(ArgMode::InOutNoScratch), None);
@@ -596,7 +706,7 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::PreventSwitch;
     }
 
-    if sys == Arch::MPROTECT {
+    if sys == Arch::MPROTECT || sys == Arch::PKEY_MPROTECT {
         // Since we're stripping MAP_GROWSDOWN from kernel mmap calls, we need
         // to implement PROT_GROWSDOWN ourselves.
         t.vm().fixup_mprotect_growsdown_parameters(t);
@@ -642,6 +752,7 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
             r.set_arg1_signed(-1);
             t.set_regs(&r);
             record_ranges(t, &ranges, result);
+            update_file_position(syscall_state, fd, &offset);
             syscall_state.emulate_result(result);
 
             return Switchable::PreventSwitch;
@@ -662,6 +773,20 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::PreventSwitch;
     }
 
+    if sys == SYS_rdcall_reload_auxv as i32 {
+        // An rd we're recording has finished setting up one of its replay
+        // tracees, whose auxv is only now final.
+        let tid = regs.arg1() as pid_t;
+        match t.session().find_task_from_rec_tid(tid) {
+            Some(target) => {
+                target.vm().save_auxv(&**target);
+                syscall_state.emulate_result(0);
+            }
+            None => syscall_state.emulate_result_signed(-ESRCH as isize),
+        }
+        return Switchable::PreventSwitch;
+    }
+
     if sys == Arch::SIGACTION || sys == Arch::RT_SIGACTION {
         syscall_state.reg_parameter::<kernel_sigaction<Arch>>(
             2,
@@ -809,6 +934,7 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
              record again with -n to fix this"
         );
 
+        will_sigreturn(t, sys);
         t.invalidate_sigmask();
         return Switchable::PreventSwitch;
     }
@@ -877,6 +1003,30 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::PreventSwitch;
     }
 
+    if sys == Arch::IO_URING_SETUP || sys == Arch::IO_URING_ENTER || sys == Arch::IO_URING_REGISTER
+    {
+        if t.session().as_record().unwrap().io_uring_policy() == IoUringPolicy::Refuse {
+            clean_fatal!(
+                "Tracee {} called {}, but io_uring can't be recorded: the kernel completes\n\
+                 requests by writing to memory shared with the tracee, bypassing syscalls.\n\
+                 Record with `--io-uring disable` to make the program fall back to regular\n\
+                 syscalls.",
+                t.tid(),
+                syscall_name(sys, Arch::arch())
+            );
+        }
+        // Prevent the call from running and fake an ENOSYS return, as on a
+        // kernel without io_uring. Programs using io_uring (e.g. via liburing)
+        // are expected to fall back to regular syscalls. Ring fds can't exist
+        // since io_uring_setup never succeeds, so io_uring_enter/register
+        // only reach the kernel with a bad fd.
+        let mut r: Registers = regs.clone();
+        r.set_arg1_signed(-1);
+        t.set_regs(&r);
+        syscall_state.emulate_result_signed(-ENOSYS as isize);
+        return Switchable::PreventSwitch;
+    }
+
     if sys == Arch::MEMFD_CREATE {
         let name = t.read_c_str(regs.arg1().into());
         if is_blacklisted_memfd(&name) {
@@ -915,7 +1065,7 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::PreventSwitch;
     }
 
-    if sys == Arch::FORK || sys == Arch::VFORK || sys == Arch::CLONE {
+    if sys == Arch::FORK || sys == Arch::VFORK || sys == Arch::CLONE || sys == Arch::CLONE3 {
         prepare_clone::<Arch>(t, &mut syscall_state);
         return Switchable::AllowSwitch;
     }
@@ -1005,6 +1155,7 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
             | MADV_REMOVE | MADV_DONTFORK | MADV_DOFORK | MADV_SOFT_OFFLINE | MADV_HWPOISON
             | MADV_MERGEABLE | MADV_UNMERGEABLE | MADV_HUGEPAGE | MADV_NOHUGEPAGE
             | MADV_DONTDUMP | MADV_DODUMP => (),
+            advice if is_content_preserving_madvise(advice as u32) => (),
             MADV_FREE => {
                 // MADV_FREE introduces nondeterminism --- the kernel zeroes the
                 // pages when under memory pressure. So we don't allow it.
@@ -1146,6 +1297,33 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
     }
 
     if sys == Arch::GETRANDOM {
+        if let Some(bytes) = t
+            .session()
+            .as_record()
+            .unwrap()
+            .seeded_random_bytes(regs.arg2())
+        {
+            // Possibly a short read, like the kernel's.
+            let len = bytes.len();
+            let mut ok = true;
+            t.write_bytes_helper(
+                RemotePtr::from(regs.arg1()),
+                &bytes,
+                Some(&mut ok),
+                WriteFlags::empty(),
+            );
+            if ok {
+                // Don't perform this syscall.
+                let mut r: Registers = regs.clone();
+                r.set_arg1_signed(-1);
+                t.set_regs(&r);
+                let ranges = [Range::new(RemotePtr::from(regs.arg1()), len)];
+                record_ranges(t, &ranges, len);
+                syscall_state.emulate_result(len);
+                return Switchable::PreventSwitch;
+            }
+            // Let the kernel report EFAULT.
+        }
         syscall_state.reg_parameter_with_size(
             1,
             ParamSize::from_syscall_result_with_size::<i32>(regs.arg2()),
@@ -1159,6 +1337,23 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         };
     }
 
+    if sys == Arch::KCMP {
+        // Comparing against one of rd's private fds must behave as if that fd
+        // did not exist in the tracee, otherwise the result could differ from
+        // an unrecorded run.
+        if regs.arg3() as u32 == KCMP_FILE
+            && (is_rd_fd_of(t, regs.arg1_signed() as pid_t, regs.arg4_signed() as i32)
+                || is_rd_fd_of(t, regs.arg2_signed() as pid_t, regs.arg5_signed() as i32))
+        {
+            let mut r: Registers = regs.clone();
+            // Cancel the syscall by passing an invalid pid.
+            r.set_arg1_signed(-1);
+            t.set_regs(&r);
+            syscall_state.emulate_result_signed(-EBADF as isize);
+        }
+        return Switchable::PreventSwitch;
+    }
+
     if sys == Arch::SYSFS {
         let option = regs.arg1() as i32;
         match option {
@@ -1733,7 +1928,8 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
 
     // ssize_t readv(int fd, const struct iovec *iov, int iovcnt);
     // ssize_t preadv(int fd, const struct iovec *iov, int iovcnt, off_t offset);
-    if sys == Arch::READV || sys == Arch::PREADV {
+    // ssize_t preadv2(int fd, const struct iovec *iov, int iovcnt, off_t offset, int flags);
+    if sys == Arch::READV || sys == Arch::PREADV || sys == Arch::PREADV2 {
         let fd = regs.arg1_signed() as i32;
         let iovcnt = regs.arg3() as u32 as usize;
         let iovecsp_void = syscall_state.reg_parameter_with_size(
@@ -1763,6 +1959,7 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
             r.set_arg1_signed(-1);
             t.set_regs(&r);
             record_ranges(t, &ranges, result);
+            update_file_position(syscall_state, fd, &offset);
             syscall_state.emulate_result(result);
             return Switchable::PreventSwitch;
         }
@@ -1865,6 +2062,56 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
     Switchable::PreventSwitch
 }
 
+/// A futex op on a word in MonitoredSharedMemory, whose tracee copy is only a
+/// shadow of `real_addr`. Wakes are performed by rd on the real memory, so
+/// they reach the non-tracee's waiters; waits return immediately as a spurious
+/// wakeup, since nothing would wake the shadow copy.
+/// DIFF NOTE: rr lets these reach the kernel, where they act on the shadow.
+fn prepare_monitored_futex(
+    t: &RecordTask,
+    syscall_state: &mut TaskSyscallState,
+    regs: &Registers,
+    real_addr: *const u8,
+) -> Switchable {
+    let op = regs.arg2_signed() as i32;
+    let mut r: Registers = regs.clone();
+    r.set_arg2_signed(-1);
+    t.set_regs(&r);
+    match op & FUTEX_CMD_MASK {
+        FUTEX_WAKE | FUTEX_WAKE_BITSET => {
+            // The other process's futex is keyed on the shared page, so it
+            // can't be private.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_futex,
+                    real_addr,
+                    op & FUTEX_CMD_MASK,
+                    regs.arg3() as u32,
+                    0usize,
+                    0usize,
+                    regs.arg6() as u32,
+                )
+            };
+            if ret < 0 {
+                syscall_state.emulate_result_signed(-(errno() as isize));
+            } else {
+                syscall_state.emulate_result(ret as usize);
+            }
+        }
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => syscall_state.emulate_result(0),
+        _ => {
+            log!(
+                LogWarn,
+                "futex op {:#x} on shared memory monitored for {} isn't supported",
+                op,
+                t.tid()
+            );
+            syscall_state.emulate_result_signed(-ENOSYS as isize);
+        }
+    }
+    Switchable::PreventSwitch
+}
+
 fn is_blacklisted_memfd(name: &CStr) -> bool {
     matches!(name.to_str(), Ok(name_str) if name_str == "pulseaudio")
 }
@@ -1876,9 +2123,10 @@ fn maybe_blacklist_connect<Arch: Architecture>(
 ) -> Switchable {
     let mut addr: sockaddr_un = unsafe { mem::zeroed() };
     let len = min(size_of_val(&addr), addrlen as usize);
-    // DIFF NOTE: In rr there is no check for error. Here we unwrap().
+    // A bad `addr_ptr` is not our problem: the kernel will fail the connect()
+    // with EFAULT. All we lose is the (zeroed) address we'd check below.
     t.read_bytes_fallible(addr_ptr, &mut u8_slice_mut(&mut addr)[0..len])
-        .unwrap();
+        .ok();
     // Ensure null termination;
     addr.sun_path[size_of_val(&addr.sun_path) - 1] = 0;
     if addr.sun_family as i32 == AF_UNIX {
@@ -1891,7 +2139,7 @@ fn maybe_blacklist_connect<Arch: Architecture>(
         }
     }
 
-    Switchable::PreventSwitch
+    Switchable::AllowSwitch
 }
 
 fn is_blacklisted_socket(filename_in: &[i8; 108]) -> Option<&str> {
@@ -2019,6 +2267,17 @@ fn record_ranges(t: &RecordTask, ranges: &[file_monitor::Range], size: usize) {
     }
 }
 
+/// An emulated read doesn't move the fd's file position. If the FileMonitor
+/// asked for it to be moved, do that once the syscall is done.
+fn update_file_position(syscall_state: &mut TaskSyscallState, fd: i32, offset: &LazyOffset) {
+    if let Some(pos) = offset.new_file_position() {
+        syscall_state.after_syscall_action(Box::new(move |t| {
+            let mut remote = AutoRemoteSyscalls::new(t);
+            remote.infallible_lseek_syscall(fd, pos as i64, SEEK_SET);
+        }));
+    }
+}
+
 fn prepare_mmap_register_params(t: &RecordTask) {
     let mut r: Registers = t.regs_ref().clone();
     if t.session().as_record().unwrap().enable_chaos()
@@ -2145,7 +2404,7 @@ fn check_signals_while_exiting(t: &RecordTask) {
             // and stashed. Since these signals are unblockable they take
             // effect no matter what and we don't need to deliver them to an exiting
             // thread.
-            let siginfo = unsafe { (*s).siginfo };
+            let siginfo = s.siginfo;
             let sig = siginfo.si_signo;
             ed_assert!(
                 t,
@@ -2179,7 +2438,9 @@ pub fn rec_prepare_restart_syscall(t: &RecordTask) {
 }
 
 pub fn rec_prepare_restart_syscall_internal(t: &RecordTask) {
-    let arch = t.arch();
+    // Like rec_process_syscall(), dispatch on the architecture of the syscall
+    // rather than the task: an x86-64 task can make x86 syscalls through int $0x80.
+    let arch = t.ev().syscall_event().arch();
     rd_arch_function_selfless!(rec_prepare_restart_syscall_arch, arch, t);
 }
 
@@ -2204,12 +2465,15 @@ fn rec_prepare_restart_syscall_arch<Arch: Architecture>(t: &RecordTask) {
             .unwrap()
             .process_syscall_results(t);
     }
+    // These syscalls install a temporary signal mask that the kernel restores
+    // when they are interrupted.
     if sys == Arch::PPOLL
         || sys == Arch::PPOLL_TIME64
         || sys == Arch::PSELECT6
         || sys == Arch::PSELECT6_TIME64
         || sys == Arch::SIGSUSPEND
         || sys == Arch::RT_SIGSUSPEND
+        || sys == Arch::EPOLL_PWAIT
     {
         t.invalidate_sigmask();
     }
@@ -2282,6 +2546,15 @@ pub fn rec_process_syscall_arch<Arch: Architecture>(
         return;
     }
 
+    if syscall_state.error_injected {
+        // The syscall never ran. process_syscall_results() will set the
+        // injected error as the result.
+        let mut r: Registers = t.regs_ref().clone();
+        r.set_original_syscallno(syscall_state.syscall_entry_registers.original_syscallno());
+        t.set_regs(&r);
+        return;
+    }
+
     log!(
         LogDebug,
         "{}: processing: {} -- time: {}",
@@ -2341,7 +2614,7 @@ pub fn rec_process_syscall_arch<Arch: Architecture>(
     // Here we handle syscalls that need work that can only happen after the
     // syscall completes --- and that our TaskSyscallState infrastructure can't
     // handle.
-    if sys == Arch::FORK || sys == Arch::VFORK || sys == Arch::CLONE {
+    if sys == Arch::FORK || sys == Arch::VFORK || sys == Arch::CLONE || sys == Arch::CLONE3 {
         // On a 3.19.0-39-generic #44-Ubuntu kernel we have observed clone()
         // clearing the parity flag internally.
         let mut r: Registers = t.regs_ref().clone();
@@ -2772,17 +3045,23 @@ pub fn rec_process_syscall_arch<Arch: Architecture>(
         || sys == Arch::FCNTL64
         || sys == Arch::FUTEX_TIME64
         || sys == Arch::FUTEX
+        || sys == Arch::GETRANDOM
         || sys == Arch::IOCTL
         || sys == Arch::IO_SETUP
+        || sys == Arch::IO_URING_ENTER
+        || sys == Arch::IO_URING_REGISTER
+        || sys == Arch::IO_URING_SETUP
         || sys == Arch::MADVISE
         || sys == Arch::MEMFD_CREATE
         || sys == Arch::PREAD64
         || sys == Arch::PREADV
+        || sys == Arch::PREADV2
         || sys == Arch::PTRACE
         || sys == Arch::READ
         || sys == Arch::READV
         || sys == Arch::SCHED_SETAFFINITY
         || sys == Arch::MPROTECT
+        || sys == Arch::PKEY_MPROTECT
     {
         // Restore the registers that we may have altered.
         let mut r: Registers = t.regs_ref().clone();
@@ -2877,6 +3156,19 @@ pub fn rec_process_syscall_arch<Arch: Architecture>(
         return;
     }
 
+    if sys == Arch::EVENTFD
+        || sys == Arch::EVENTFD2
+        || sys == Arch::SIGNALFD
+        || sys == Arch::SIGNALFD4
+        || sys == Arch::TIMERFD_CREATE
+    {
+        if !t.regs_ref().syscall_failed() {
+            let fd = t.regs_ref().syscall_result_signed() as i32;
+            handle_opened_file(t, fd, 0);
+        }
+        return;
+    }
+
     if sys == Arch::OPEN || sys == Arch::OPENAT {
         let mut r: Registers = t.regs_ref().clone();
         if r.syscall_failed() {
@@ -2900,7 +3192,7 @@ pub fn rec_process_syscall_arch<Arch: Architecture>(
             };
             let pathname = handle_opened_file(t, fd, flags);
             let gcrypt = is_gcrypt_deny_file(&pathname);
-            if gcrypt || is_blacklisted_filename(&pathname) {
+            if gcrypt || is_blacklisted_filename(&pathname) || is_rd_fdinfo_file(t, &pathname) {
                 {
                     let mut remote = AutoRemoteSyscalls::new(t);
                     rd_infallible_syscall!(remote, syscall_number_for_close(remote.arch()), fd);
@@ -2982,6 +3274,25 @@ fn is_blacklisted_filename(filename_os: &OsStr) -> bool {
     }
 }
 
+/// Returns true if `fd` is one of rd's private fds in the tracee `pid`.
+fn is_rd_fd_of(t: &RecordTask, pid: pid_t, fd: i32) -> bool {
+    if pid == t.rec_tid() {
+        return t.fd_table().is_rd_fd(fd);
+    }
+    match t.session().find_task_from_rec_tid(pid) {
+        Some(target) => target.fd_table().is_rd_fd(fd),
+        None => false,
+    }
+}
+
+/// The fdinfo of rd's private fds must not be visible to the tracee.
+fn is_rd_fdinfo_file(t: &RecordTask, filename_os: &OsStr) -> bool {
+    match parse_proc_fdinfo_file(filename_os, t.tgid(), t.rec_tid()) {
+        Some((pid, fd)) => is_rd_fd_of(t, pid, fd),
+        None => false,
+    }
+}
+
 fn is_gcrypt_deny_file(f: &OsStr) -> bool {
     f.as_bytes() == b"/etc/gcrypt/hwf.deny"
 }
@@ -2992,6 +3303,7 @@ fn handle_opened_file(t: &RecordTask, fd: i32, flags: i32) -> OsString {
 
     // This must be kept in sync with replay_syscall's handle_opened_files.
     let mut file_monitor: Option<Box<dyn FileMonitor>> = None;
+    let mut backing_file_name = OsString::new();
     if is_mapped_shared(t, &st) && is_writable(t, fd) {
         // This is quite subtle. Because open(2) is Switchable::AllowSwitch, we could have been
         // descheduled after entering the syscall we're now exiting. If that happened,
@@ -3028,6 +3340,33 @@ fn handle_opened_file(t: &RecordTask, fd: i32, flags: i32) -> OsString {
     } else if is_proc_fd_dir(&pathname) {
         log!(LogInfo, "Installing ProcFdDirMonitor for {}", fd);
         file_monitor = Some(Box::new(ProcFdDirMonitor::new(t, &pathname)));
+    } else if is_dev_random(&pathname) && t.session().as_record().unwrap().is_seeded() {
+        log!(LogInfo, "Installing RandomFileMonitor for {}", fd);
+        file_monitor = Some(Box::new(RandomFileMonitor::new()));
+    } else if let Some(limit) = t.session().as_record().unwrap().cgroup_limit(&pathname) {
+        log!(LogInfo, "Installing CgroupFileMonitor for {}", fd);
+        file_monitor = Some(Box::new(CgroupFileMonitor::new(limit.contents())));
+    } else if is_locale_file(&pathname) {
+        // Which locales are installed differs between otherwise identical
+        // systems, and the output of locale-aware programs depends on it.
+        let maybe_saved = t.trace_writer_mut().save_opened_file(t, &pathname, &st);
+        if let Some(name) = maybe_saved {
+            log!(LogInfo, "Installing LocaleFileMonitor for {}", fd);
+            let mut path = OsString::new();
+            if !name.as_bytes().starts_with(b"/") {
+                path.push(t.trace_writer().dir());
+                path.push("/");
+            }
+            path.push(&name);
+            file_monitor = Some(Box::new(LocaleFileMonitor::new(&pathname, &path)));
+            backing_file_name = name;
+        }
+    } else if let Some(kind) = AnonFdKind::from_path(&pathname) {
+        // signalfd() on an fd that is already a signalfd just changes its mask.
+        if !t.fd_table().is_monitoring(fd) {
+            log!(LogInfo, "Installing AnonFdMonitor ({:?}) for {}", kind, fd);
+            file_monitor = Some(Box::new(AnonFdMonitor::new(kind)));
+        }
     } else if flags & O_DIRECT != 0 {
         // O_DIRECT can impose unknown alignment requirements, in which case
         // syscallbuf records will not be properly aligned and will cause I/O
@@ -3046,6 +3385,7 @@ fn handle_opened_file(t: &RecordTask, fd: i32, flags: i32) -> OsString {
                     fd,
                     device: st.st_dev,
                     inode: st.st_ino,
+                    backing_file_name,
                 });
             }
             t.fd_table().add_monitor(t, fd, mon);
@@ -3323,6 +3663,10 @@ fn process_mmap(
         let m = t.vm().mapping_of(addr).unwrap().clone();
         MonitoredSharedMemory::maybe_monitor(t, file_name.as_os_str(), m, fd, offset);
     }
+
+    if t.session().as_record().unwrap().stap_sdt() {
+        plant_sdt_probes(t, &km);
+    }
 }
 
 fn monitor_fd_for_mapping(
@@ -3626,6 +3970,16 @@ fn process_execve(t: &RecordTask, syscall_state: &mut TaskSyscallState) {
         t.record_remote(p, page_size());
     }
 
+    if t.session().as_record().unwrap().stap_sdt() {
+        let mut mappings = Vec::new();
+        for (_, m) in &t.vm().maps() {
+            mappings.push(m.map.clone());
+        }
+        for km in &mappings {
+            plant_sdt_probes(t, km);
+        }
+    }
+
     // Patch LD_PRELOAD and VDSO after saving the mappings. Replay will apply
     // patches to the saved mappings.
     t.vm()
@@ -3636,6 +3990,251 @@ fn process_execve(t: &RecordTask, syscall_state: &mut TaskSyscallState) {
 
     init_scratch_memory(t, Some(ScratchAddrType::FixedAddress));
 }
+
+/// Record the state of the process rd just attached to as the exit of an
+/// execve of `exe`, so that replay sets the process up the way it does
+/// after a real exec. The process must already have been through
+/// `post_exec_for_exe()` and have the rd page mapped. Replay recreates the
+/// process's other `threads` from the registers recorded for them.
+pub fn record_attach_snapshot(
+    t: &RecordTask,
+    threads: &[&RecordTask],
+    exe: &OsStr,
+    cmd_line: &[OsString],
+    auxv: Vec<u8>,
+) {
+    let arch = t.arch();
+    let mut syscall = SyscallEventData::new(syscall_number_for_execve(arch), arch);
+    syscall.regs = t.regs();
+    syscall.state = SyscallState::EnteringSyscall;
+    t.push_event(Event::new_syscall_event(syscall));
+    t.record_current_event();
+    t.ev_mut().syscall_event_mut().state = SyscallState::ExitingSyscall;
+
+    // Files the process already had open may need monitoring, like files it
+    // opens later.
+    let mut fds: Vec<i32> = read_dir(format!("/proc/{}/fd", t.tid()))
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    fds.sort_unstable();
+    for fd in fds {
+        if !t.fd_table().is_monitoring(fd) {
+            handle_opened_file(t, fd, 0);
+        }
+    }
+
+    for start in &[
+        AddressSpace::rd_page_start(),
+        AddressSpace::preload_thread_locals_start(),
+    ] {
+        let km: KernelMapping = t.vm().mapping_of(*start).unwrap().map.clone();
+        let mode = t.trace_writer_mut().write_mapped_region(
+            t,
+            &km,
+            &km.fake_stat(),
+            &[],
+            Some(MappingOrigin::RdBufferMapping),
+            None,
+        );
+        ed_assert_eq!(t, mode, RecordInTrace::DontRecordInTrace);
+    }
+
+    let exe_entry = find_exe_entry(arch, &auxv);
+    ed_assert!(t, !exe_entry.is_null(), "AT_ENTRY not found");
+    let mut ev = TraceTaskEvent::for_exec(t.tid(), exe, cmd_line);
+    let mut maybe_vvar: Option<KernelMapping> = None;
+    let mut stacks: Vec<KernelMapping> = Vec::new();
+    for (_, m) in &t.vm().maps() {
+        let km = m.map.clone();
+        if km.start() <= exe_entry && exe_entry < km.end() {
+            ev.exec_variant_mut().set_exe_base(km.start());
+        }
+        if km.is_stack() {
+            stacks.push(km);
+        } else if km.is_vvar() {
+            maybe_vvar = Some(km);
+        }
+    }
+    ed_assert!(t, !ev.exec_variant().exe_base().is_null());
+    ev.exec_variant_mut().set_auxv(auxv);
+    ev.exec_variant_mut().set_attached_threads(
+        threads
+            .iter()
+            .map(|tt| AttachedThread {
+                tid: tt.rec_tid(),
+                regs: tt.regs(),
+                extra_regs: tt.extra_regs_ref().clone(),
+            })
+            .collect(),
+    );
+    t.trace_writer_mut().write_task_event(&ev);
+
+    // The rest is as in process_execve().
+    {
+        let mut remote =
+            AutoRemoteSyscalls::new_with_mem_params(t, MemParamsEnabled::DisableMemoryParams);
+        if let Some(vvar) = maybe_vvar {
+            let munmap_no: i32 = syscall_number_for_munmap(remote.arch());
+            rd_infallible_syscall!(remote, munmap_no, vvar.start().as_usize(), vvar.size());
+            remote
+                .task()
+                .vm()
+                .unmap(remote.task(), vvar.start(), vvar.size());
+        }
+
+        for km in &stacks {
+            let mode = remote
+                .task()
+                .as_rec_unwrap()
+                .trace_writer_mut()
+                .write_mapped_region(
+                    remote.task().as_rec_unwrap(),
+                    km,
+                    &km.fake_stat(),
+                    &[],
+                    Some(MappingOrigin::ExecMapping),
+                    None,
+                );
+            ed_assert_eq!(remote.task(), mode, RecordInTrace::RecordInTrace);
+            let buf = read_mem(remote.task(), km.start(), km.size(), None);
+            remote.task().as_rec_unwrap().trace_writer_mut().write_raw(
+                remote.task().rec_tid(),
+                &buf,
+                km.start(),
+            );
+
+            let flags = (km.flags() & !MapFlags::MAP_GROWSDOWN) | MapFlags::MAP_ANONYMOUS;
+            let munmap_no: i32 = syscall_number_for_munmap(remote.arch());
+            rd_infallible_syscall!(remote, munmap_no, km.start().as_usize(), km.size());
+            if remote
+                .task()
+                .vm()
+                .mapping_of(km.start() - page_size())
+                .is_none()
+            {
+                rd_infallible_syscall!(
+                    remote,
+                    munmap_no,
+                    km.start().as_usize() - page_size(),
+                    page_size()
+                );
+            }
+            remote.infallible_mmap_syscall(Some(km.start()), km.size(), km.prot(), flags, -1, 0);
+            write_mem(remote.task(), km.start(), &buf, None);
+        }
+    }
+
+    for (_, m) in &t.vm().maps() {
+        let km = m.map.clone();
+        if km.start() == AddressSpace::rd_page_start()
+            || km.start() == AddressSpace::preload_thread_locals_start()
+            || km.is_stack()
+            || km.is_vsyscall()
+        {
+            continue;
+        }
+        let st = match stat::stat(km.fsname()) {
+            Err(_) => {
+                let mut fake_st = km.fake_stat();
+                fake_st.st_size = 0;
+                fake_st
+            }
+            Ok(st) => st,
+        };
+        // A MAP_SHARED|MAP_ANONYMOUS mapping has no file to map at replay,
+        // only its contents.
+        let origin = if km.fsname() == "/dev/zero (deleted)" {
+            MappingOrigin::SyscallMapping
+        } else {
+            MappingOrigin::ExecMapping
+        };
+        let mode = t
+            .trace_writer_mut()
+            .write_mapped_region(t, &km, &st, &[], Some(origin), None);
+        if km.prot().is_empty() {
+            // Guard pages and reservations; there's nothing in them to read.
+            continue;
+        }
+        if mode == RecordInTrace::RecordInTrace {
+            record_changed_pages(t, &km, None);
+        } else {
+            // Replay maps the file again, so only record what the process has
+            // changed since it mapped it.
+            let file = fs::File::open(km.fsname()).ok().filter(|f| {
+                f.metadata()
+                    .map(|md| md.dev() == km.device() && md.ino() == km.inode())
+                    .unwrap_or(false)
+            });
+            match file {
+                Some(file) => record_changed_pages(t, &km, Some(&file)),
+                None => record_changed_pages(t, &km, None),
+            }
+        }
+    }
+
+    t.vm()
+        .monkeypatcher()
+        .unwrap()
+        .borrow_mut()
+        .patch_after_exec(t);
+
+    // Don't let the scratch memory land on whatever the process has at the
+    // fixed address.
+    init_scratch_memory(t, Some(ScratchAddrType::DynamicAddress));
+    for tt in threads {
+        init_scratch_memory(tt, Some(ScratchAddrType::DynamicAddress));
+    }
+
+    t.record_current_event();
+    t.pop_syscall();
+}
+
+/// Record the pages of `km` whose contents differ from `file`'s, at the
+/// mapping's offset, or that aren't all zeroes if there's no file.
+fn record_changed_pages(t: &RecordTask, km: &KernelMapping, file: Option<&fs::File>) {
+    const CHUNK_PAGES: usize = 256;
+    let page = page_size();
+    // The start of the run of changed pages not recorded yet, if any.
+    let mut run_start: Option<RemotePtr<Void>> = None;
+    let mut file_buf = vec![0u8; CHUNK_PAGES * page];
+    let mut addr = km.start();
+    while addr < km.end() {
+        let len = min(CHUNK_PAGES * page, km.end() - addr);
+        let mut ok = true;
+        let buf = read_mem(t, addr, len, Some(&mut ok));
+        if !ok {
+            // Pages past the end of the mapped file can't be read, and can't
+            // have been changed.
+            break;
+        }
+        let file_data = &mut file_buf[0..len];
+        file_data.iter_mut().for_each(|b| *b = 0);
+        if let Some(f) = file {
+            let offset = km.file_offset_bytes() + (addr - km.start()) as u64;
+            let mut done = 0;
+            while done < len {
+                match f.read_at(&mut file_data[done..], offset + done as u64) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => done += n,
+                }
+            }
+        }
+        for (i, chunk) in buf.chunks(page).enumerate() {
+            let page_addr = addr + i * page;
+            if chunk != &file_data[i * page..(i + 1) * page] {
+                run_start.get_or_insert(page_addr);
+            } else if let Some(start) = run_start.take() {
+                t.record_remote(start, page_addr - start);
+            }
+        }
+        addr += len;
+    }
+    if let Some(start) = run_start {
+        t.record_remote(start, addr - start);
+    }
+}
+
 /// Pointer used when running in WINE. Memory below this address is
 /// unmapped by WINE immediately after exec, so start the scratch buffer
 /// here.
@@ -3748,9 +4347,12 @@ fn check_privileged_exe(t: &RecordTask) {
 }
 
 fn get_exe_entry(t: &RecordTask) -> RemotePtr<Void> {
-    let v = read_auxv(t);
+    find_exe_entry(t.arch(), &read_auxv(t))
+}
+
+fn find_exe_entry(arch: SupportedArch, v: &[u8]) -> RemotePtr<Void> {
     let mut i: usize = 0;
-    let wsize: usize = word_size(t.arch());
+    let wsize: usize = word_size(arch);
     while (i + 1) * wsize * 2 <= v.len() {
         if word_at(&v[i * 2 * wsize..i * 2 * wsize + wsize]) == AT_ENTRY {
             // @TODO Instead of try_into() should this just be `as usize` ?
@@ -3859,6 +4461,9 @@ pub struct TaskSyscallState {
 
     /// Miscellaneous saved data that can be used by particular syscalls
     saved_data: Vec<u8>,
+
+    /// When true, --inject-errors hijacked this syscall so it never ran.
+    error_injected: bool,
 }
 
 impl TaskSyscallState {
@@ -3882,6 +4487,7 @@ impl TaskSyscallState {
             preparation_done: false,
             scratch_enabled: false,
             saved_data: Default::default(),
+            error_injected: false,
         }
     }
 
@@ -4934,7 +5540,34 @@ fn prepare_ioctl<Arch: Architecture>(
             return Switchable::PreventSwitch;
         }
 
-        TIOCINQ | TIOCOUTQ | TIOCGETD => {
+        TIOCINQ | TIOCOUTQ | TIOCGETD | TIOCMGET => {
+            syscall_state.reg_parameter::<i32>(3, None, None);
+            return Switchable::PreventSwitch;
+        }
+
+        FIOQSIZE => {
+            syscall_state.reg_parameter::<loff_t>(3, None, None);
+            return Switchable::PreventSwitch;
+        }
+
+        // Self-checkpointing programs use this to find the pages they've
+        // dirtied. The kernel writes up to vec_len page_regions to `vec` and
+        // updates walk_end.
+        _PAGEMAP_SCAN => {
+            let argp = syscall_state.reg_parameter::<pm_scan_arg>(3, Some(ArgMode::InOut), None);
+            let arg = read_val_mem(t, argp, None);
+            syscall_state.mem_ptr_parameter_with_size(
+                t,
+                remote_ptr_field!(argp, pm_scan_arg, vec),
+                ParamSize::from(arg.vec_len as usize * size_of::<page_region>()),
+                None,
+                None,
+            );
+            return Switchable::PreventSwitch;
+        }
+
+        // Has no _IOC_READ bit (or any dir bits at all) but writes an int.
+        _FIGETBSZ => {
             syscall_state.reg_parameter::<i32>(3, None, None);
             return Switchable::PreventSwitch;
         }
@@ -5312,6 +5945,10 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
     let original_syscall = r.original_syscallno() as i32;
     let ptrace_event;
     let mut maybe_termination_signal = Some(sig::SIGCHLD);
+    // The clone_args of a clone3 call the kernel will accept. `flags` is
+    // their first field.
+    let mut maybe_clone3_args: Option<clone_args> = None;
+    let clone3_flags_addr = RemotePtr::<u64>::from(r.arg1());
 
     if is_clone_syscall(original_syscall, r.arch()) {
         params = extract_clone_parameters(t);
@@ -5319,13 +5956,24 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
         r.set_arg1((flags & !CLONE_UNTRACED) as usize);
         t.set_regs(&r);
         maybe_termination_signal = Sig::try_from(flags & 0xff).ok();
-        if flags & CLONE_VFORK != 0 {
-            ptrace_event = PTRACE_EVENT_VFORK;
-        } else if maybe_termination_signal == Some(sig::SIGCHLD) {
-            ptrace_event = PTRACE_EVENT_FORK;
-        } else {
-            ptrace_event = PTRACE_EVENT_CLONE;
+        ptrace_event = clone_ptrace_event(flags, maybe_termination_signal);
+    } else if is_clone3_syscall(original_syscall, r.arch()) {
+        // If the kernel is going to fail the call, let it; that's dealt with
+        // below like any other failed clone.
+        maybe_clone3_args = read_clone_args(t);
+        let args = maybe_clone3_args.unwrap_or_default();
+        if args.flags & CLONE_UNTRACED as u64 != 0 {
+            write_val_mem(
+                t,
+                clone3_flags_addr,
+                &(args.flags & !(CLONE_UNTRACED as u64)),
+                None,
+            );
         }
+        params = extract_clone3_parameters(&args);
+        flags = args.flags as i32;
+        maybe_termination_signal = Sig::try_from(args.exit_signal as i32).ok();
+        ptrace_event = clone_ptrace_event(flags, maybe_termination_signal);
     } else if is_vfork_syscall(original_syscall, r.arch()) {
         ptrace_event = PTRACE_EVENT_VFORK;
         flags = CLONE_VM | CLONE_VFORK | SIGCHLD;
@@ -5358,7 +6006,8 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
             // the syscall so that we're in the same state as the normal execution
             // path.
             t.ev_mut().syscall_event_mut().failed_during_preparation = true;
-            // Restore register we might have changed
+            // Restore register and clone_args we might have changed
+            restore_clone3_flags(t, clone3_flags_addr, maybe_clone3_args);
             r.set_arg1(syscall_state.syscall_entry_registers.arg1());
             r.set_syscallno(Arch::GETTID as isize);
             r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
@@ -5406,6 +6055,16 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
         None,
     );
     let new_task = new_task_shr_ptr.as_rec_unwrap();
+    if maybe_clone3_args.map_or(false, |args| args.flags & CLONE_CLEAR_SIGHAND != 0) {
+        // The kernel refuses CLONE_CLEAR_SIGHAND with CLONE_SIGHAND, so the
+        // new task has its own copy of the handlers.
+        let arch = new_task.arch();
+        new_task
+            .sighandlers
+            .borrow()
+            .borrow_mut()
+            .reset_user_handlers(arch);
+    }
 
     // Restore modified registers in cloned task
     let mut new_r: Registers = new_task.regs();
@@ -5417,8 +6076,11 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
     new_task.set_termination_signal(maybe_termination_signal);
 
     // record child id here
-    if is_clone_syscall(original_syscall, r.arch()) {
-        let child_params: CloneParameters = extract_clone_parameters(new_task);
+    if is_clone_syscall(original_syscall, r.arch()) || maybe_clone3_args.is_some() {
+        let child_params: CloneParameters = match maybe_clone3_args {
+            Some(args) => extract_clone3_parameters(&args),
+            None => extract_clone_parameters(new_task),
+        };
         t.record_remote_even_if_null_for(params.ptid);
 
         if Arch::CLONE_TLS_TYPE == CloneTLSType::UserDescPointer {
@@ -5429,13 +6091,27 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
         }
         new_task.record_remote_even_if_null_for(child_params.ptid);
         new_task.record_remote_even_if_null_for(child_params.ctid);
+        if let Some(args) = maybe_clone3_args {
+            // clone3 has its own field for the pidfd, where clone uses ptid.
+            let pidfd = if flags & CLONE_PIDFD != 0 {
+                RemotePtr::<i32>::from(args.pidfd as usize)
+            } else {
+                RemotePtr::null()
+            };
+            t.record_remote_even_if_null_for(pidfd);
+        }
     }
     t.trace_writer_mut()
         .write_task_event(&TraceTaskEvent::for_clone(
             new_task.tid(),
             t.tid(),
+            t.own_namespace_rec_tid.get(),
             new_task.own_namespace_rec_tid.get(),
-            flags,
+            match maybe_clone3_args {
+                Some(args) => args.flags,
+                // The kernel only looks at the low 32 bits of clone(2)'s flags.
+                None => flags as u32 as u64,
+            },
         ));
 
     init_scratch_memory(new_task, None);
@@ -5462,6 +6138,7 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
     // Restore our register modifications now, so that the emulated ptracer will
     // see the original registers without our modifications if it inspects them
     // in the ptrace event.
+    restore_clone3_flags(t, clone3_flags_addr, maybe_clone3_args);
     r = t.regs_ref().clone();
     r.set_arg1(syscall_state.syscall_entry_registers.arg1());
     r.set_original_syscallno(syscall_state.syscall_entry_registers.original_syscallno());
@@ -5473,6 +6150,32 @@ fn prepare_clone<Arch: Architecture>(t: &RecordTask, syscall_state: &mut TaskSys
     // `t` will go to the exit of the syscall, as expected.
 }
 
+/// The ptrace event the kernel reports for a clone with `flags` and
+/// `maybe_termination_signal`.
+fn clone_ptrace_event(flags: i32, maybe_termination_signal: Option<Sig>) -> u32 {
+    if flags & CLONE_VFORK != 0 {
+        PTRACE_EVENT_VFORK
+    } else if maybe_termination_signal == Some(sig::SIGCHLD) {
+        PTRACE_EVENT_FORK
+    } else {
+        PTRACE_EVENT_CLONE
+    }
+}
+
+/// Put back the CLONE_UNTRACED that `prepare_clone()` cleared from a clone3
+/// call's flags, if any.
+fn restore_clone3_flags(
+    t: &RecordTask,
+    flags_addr: RemotePtr<u64>,
+    maybe_clone3_args: Option<clone_args>,
+) {
+    if let Some(args) = maybe_clone3_args {
+        if args.flags & CLONE_UNTRACED as u64 != 0 {
+            write_val_mem(t, flags_addr, &args.flags, None);
+        }
+    }
+}
+
 fn ptrace_option_for_event(ptrace_event: u32) -> u32 {
     match ptrace_event {
         PTRACE_EVENT_FORK => PTRACE_O_TRACEFORK,
@@ -77,9 +77,9 @@ pub const X86_DF_FLAG: usize = 1 << 10;
 pub const X86_RF_FLAG: usize = 1 << 16;
 pub const X86_ID_FLAG: usize = 1 << 21;
 
-// Max register size
+// Max register size, that of the upper halves of the AVX-512 zmm registers
 // DIFF NOTE: Called MAX_SIZE in rr and within the Registers struct
-pub const MAX_REG_SIZE_BYTES: usize = 16;
+pub const MAX_REG_SIZE_BYTES: usize = 32;
 
 #[derive(Clone)]
 pub enum Registers {
@@ -319,6 +319,38 @@ impl Registers {
         )
     }
 
+    /// The registers whose values differ between `self` and `other`, as
+    /// (name, value in `self`, value in `other`), in gdb register order.
+    ///
+    /// Unlike `compare_register_files()` no register is masked out: this is
+    /// for showing the user what changed, not for detecting divergence.
+    pub fn differences(&self, other: &Registers) -> Vec<(&'static str, u64, u64)> {
+        debug_assert_eq!(self.arch(), other.arch());
+        let mut diffs = Vec::new();
+        for (_, rv) in self.get_regs_info().iter() {
+            if rv.nbytes == 0 {
+                continue;
+            }
+            let (val1, val2) = match self {
+                X86(regs1_x86) => (
+                    rv.u32_into_x86(regs1_x86) as u64,
+                    rv.u32_into_x86(other.x86()) as u64,
+                ),
+                X64(regs1_x64) if rv.nbytes == 8 => {
+                    (rv.u64_into_x64(regs1_x64), rv.u64_into_x64(other.x64()))
+                }
+                X64(regs1_x64) => (
+                    rv.u32_into_x64(regs1_x64) as u64,
+                    rv.u32_into_x64(other.x64()) as u64,
+                ),
+            };
+            if val1 != val2 {
+                diffs.push((rv.name, val1, val2));
+            }
+        }
+        diffs
+    }
+
     /// Write the value for register `regno` into `buf`, which should
     /// be large enough to hold any register supported by the target.
     /// Return the size of the register in bytes. If None is returned it
@@ -872,6 +904,10 @@ impl Registers {
         x64.r10 = value;
     }
 
+    pub fn r11(&self) -> u64 {
+        self.x64().r11
+    }
+
     pub fn set_r11(&mut self, value: u64) {
         let mut x64 = self.x64_mut();
         x64.r11 = value;
@@ -1106,6 +1142,26 @@ impl Registers {
         self.write_register_file_for_trace(f, TraceStyle::Annotated)
     }
 
+    /// The name and value of every register we can read, in gdb register order.
+    pub fn named_values(&self) -> Vec<(&'static str, u64)> {
+        let mut values = Vec::new();
+        for rv in self.get_regs_info().values() {
+            let ptr = match self {
+                X86(regs_struct) => rv.pointer_into_x86(regs_struct),
+                X64(regs_struct) => rv.pointer_into_x64(regs_struct),
+            };
+            let value = unsafe {
+                match rv.nbytes {
+                    4 => *(ptr as *const u32) as u64,
+                    8 => *(ptr as *const u64),
+                    _ => continue,
+                }
+            };
+            values.push((rv.name, value));
+        }
+        values
+    }
+
     fn write_single_register(
         &self,
         f: &mut dyn Write,
@@ -0,0 +1,290 @@
+//! Batched reads and writes of tracee memory.
+//!
+//! `Task::read_bytes_fallible` costs a syscall per call, which adds up when
+//! something reads many small, scattered pieces of memory: a memory search
+//! over a process with thousands of small mappings, or gdb examining memory
+//! with `x/` one word at a time. `read_vectored` and `write_vectored` move
+//! any number of pieces with a single process_vm_readv/process_vm_writev,
+//! and `PageCache` uses them to serve small reads from whole pages fetched in
+//! batches.
+//!
+//! process_vm_readv/writev stop at the first piece they can't access. Unlike
+//! /proc/<pid>/mem they also respect page protections, so e.g. they can't
+//! write to code or read PROT_NONE pages. Whatever they couldn't transfer is
+//! retried with the task's regular (per-piece) memory access.
+
+use crate::{
+    remote_ptr::{RemotePtr, Void},
+    session::task::{task_inner::WriteFlags, Task},
+    taskish_uid::AddressSpaceUid,
+    util::{floor_page_size, page_size},
+};
+use nix::{
+    sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec},
+    unistd::Pid,
+};
+use std::{
+    cmp::{max, min},
+    collections::HashMap,
+};
+
+/// The kernel limits how many iovecs a single call can take.
+const IOV_MAX: usize = 1024;
+
+/// `PageCache` fetches this many pages after the ones asked for (within the
+/// same mapping), since debuggers tend to read sequentially.
+const READAHEAD_PAGES: usize = 16;
+
+/// `PageCache` starts over when it holds more pages than this.
+const MAX_CACHED_PAGES: usize = 4096;
+
+pub struct ReadRequest<'a> {
+    pub addr: RemotePtr<Void>,
+    pub buf: &'a mut [u8],
+    /// Set by `read_vectored` to how many bytes at the start of `buf` were
+    /// read.
+    pub nread: usize,
+}
+
+impl<'a> ReadRequest<'a> {
+    pub fn new(addr: RemotePtr<Void>, buf: &'a mut [u8]) -> ReadRequest<'a> {
+        ReadRequest {
+            addr,
+            buf,
+            nread: 0,
+        }
+    }
+}
+
+pub struct WriteRequest<'a> {
+    pub addr: RemotePtr<Void>,
+    pub buf: &'a [u8],
+}
+
+/// Read every request in `reqs`, setting each one's `nread`. Like
+/// `Task::read_bytes_fallible`, a request may be read only partially.
+pub fn read_vectored(t: &dyn Task, reqs: &mut [ReadRequest]) {
+    let mut i = 0;
+    while i < reqs.len() {
+        let batch_end = min(reqs.len(), i + IOV_MAX);
+        let done = readv_batch(t, &mut reqs[i..batch_end]);
+        if done == batch_end - i {
+            i = batch_end;
+            continue;
+        }
+        // reqs[i + done] was cut short. Read what's left of it the slow way,
+        // then carry on with the rest of the batch.
+        let r = &mut reqs[i + done];
+        let addr = r.addr + r.nread;
+        r.nread += t
+            .read_bytes_fallible(addr, &mut r.buf[r.nread..])
+            .unwrap_or(0);
+        i += done + 1;
+    }
+}
+
+/// Read as many of `reqs` as process_vm_readv will, in order. Returns how
+/// many were read completely.
+fn readv_batch(t: &dyn Task, reqs: &mut [ReadRequest]) -> usize {
+    let remote: Vec<RemoteIoVec> = reqs
+        .iter()
+        .map(|r| RemoteIoVec {
+            base: r.addr.as_usize(),
+            len: r.buf.len(),
+        })
+        .collect();
+    let nread = {
+        let local: Vec<IoVec<&mut [u8]>> = reqs
+            .iter_mut()
+            .map(|r| IoVec::from_mut_slice(&mut *r.buf))
+            .collect();
+        // On failure (including ENOSYS and EPERM) everything is retried the
+        // slow way.
+        process_vm_readv(Pid::from_raw(t.tid()), &local, &remote).unwrap_or(0)
+    };
+    let lens: Vec<usize> = remote.iter().map(|r| r.len).collect();
+    distribute(&lens, nread, |i, n| reqs[i].nread = n)
+}
+
+/// Write every request in `reqs`. If they can't all be written, then if
+/// `maybe_ok` is None, asserts, otherwise sets the inner mutable bool to
+/// false.
+pub fn write_vectored(
+    t: &dyn Task,
+    reqs: &[WriteRequest],
+    maybe_ok: Option<&mut bool>,
+    flags: WriteFlags,
+) {
+    let mut all_ok = true;
+    let mut i = 0;
+    while i < reqs.len() {
+        let batch_end = min(reqs.len(), i + IOV_MAX);
+        let batch = &reqs[i..batch_end];
+        let local: Vec<IoVec<&[u8]>> = batch.iter().map(|r| IoVec::from_slice(r.buf)).collect();
+        let remote: Vec<RemoteIoVec> = batch
+            .iter()
+            .map(|r| RemoteIoVec {
+                base: r.addr.as_usize(),
+                len: r.buf.len(),
+            })
+            .collect();
+        let nwritten = process_vm_writev(Pid::from_raw(t.tid()), &local, &remote).unwrap_or(0);
+        let mut short = None;
+        let lens: Vec<usize> = remote.iter().map(|r| r.len).collect();
+        let done = distribute(&lens, nwritten, |j, n| {
+            if n > 0 {
+                t.vm().notify_written(batch[j].addr, n, flags);
+            }
+            if n < batch[j].buf.len() {
+                short = Some(n);
+            }
+        });
+        if done == batch.len() {
+            i = batch_end;
+            continue;
+        }
+        // Pages process_vm_writev can't write (e.g. read-only code) can still
+        // be written through the mem fd.
+        let r = &batch[done];
+        let n = short.unwrap_or(0);
+        t.write_bytes_helper(r.addr + n, &r.buf[n..], Some(&mut all_ok), flags);
+        i += done + 1;
+    }
+    if !all_ok {
+        match maybe_ok {
+            Some(ok) => *ok = false,
+            None => ed_assert!(t, false, "Should have written all requested memory"),
+        }
+    }
+}
+
+/// Split the `total` bytes a vectored syscall transferred over pieces of the
+/// lengths `lens`, in order, calling `set(piece index, bytes)` for each
+/// piece up to and including the first one that wasn't transferred
+/// completely. Returns how many pieces were transferred completely.
+fn distribute(lens: &[usize], total: usize, mut set: impl FnMut(usize, usize)) -> usize {
+    let mut left = total;
+    for (i, &len) in lens.iter().enumerate() {
+        let n = min(len, left);
+        set(i, n);
+        if n < len {
+            return i;
+        }
+        left -= n;
+    }
+    lens.len()
+}
+
+/// Whole pages of one address space, for serving many small reads with few
+/// syscalls. The owner must `clear()` the cache whenever tracee memory may
+/// have changed, e.g. when the tracee runs.
+#[derive(Default)]
+pub struct PageCache {
+    vm: Option<AddressSpaceUid>,
+    /// The readable bytes at the start of each page we've read, by page
+    /// address.
+    pages: HashMap<usize, Vec<u8>>,
+}
+
+impl PageCache {
+    pub fn clear(&mut self) {
+        self.vm = None;
+        self.pages.clear();
+    }
+
+    /// Like `Task::read_bytes_fallible`. Reads bigger than the readahead
+    /// window bypass the cache.
+    pub fn read(
+        &mut self,
+        t: &dyn Task,
+        addr: RemotePtr<Void>,
+        buf: &mut [u8],
+    ) -> Result<usize, ()> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if buf.len() > READAHEAD_PAGES * page_size() {
+            let mut reqs = [ReadRequest::new(addr, buf)];
+            read_vectored(t, &mut reqs);
+            return match reqs[0].nread {
+                0 => Err(()),
+                nread => Ok(nread),
+            };
+        }
+        let uid = t.vm().uid();
+        if self.vm != Some(uid) || self.pages.len() > MAX_CACHED_PAGES {
+            self.clear();
+            self.vm = Some(uid);
+        }
+        self.fetch(t, addr, buf.len());
+
+        let mut copied = 0;
+        while copied < buf.len() {
+            let p = addr.as_usize() + copied;
+            let page = &self.pages[&floor_page_size(p)];
+            let offset = p - floor_page_size(p);
+            if offset >= page.len() {
+                break;
+            }
+            let n = min(page.len() - offset, buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&page[offset..offset + n]);
+            copied += n;
+            if offset + n < page_size() && copied < buf.len() {
+                // The rest of this page couldn't be read.
+                break;
+            }
+        }
+        match copied {
+            0 => Err(()),
+            copied => Ok(copied),
+        }
+    }
+
+    /// Make sure every page of `[addr, addr + len)` is cached, fetching the
+    /// missing ones (and some following ones) in one batch.
+    fn fetch(&mut self, t: &dyn Task, addr: RemotePtr<Void>, len: usize) {
+        let first_page = floor_page_size(addr.as_usize());
+        let mut end = addr.as_usize() + len;
+        if let Some(mapping_end) = t.vm().mapping_of(addr).map(|m| m.map.end().as_usize()) {
+            end = max(end, min(mapping_end, end + READAHEAD_PAGES * page_size()));
+        }
+        let missing: Vec<usize> = (first_page..end)
+            .step_by(page_size())
+            .filter(|p| !self.pages.contains_key(p))
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+        let mut bufs: Vec<Vec<u8>> = missing.iter().map(|_| vec![0u8; page_size()]).collect();
+        let mut reqs: Vec<ReadRequest> = missing
+            .iter()
+            .zip(bufs.iter_mut())
+            .map(|(&p, buf)| ReadRequest::new(RemotePtr::from(p), buf))
+            .collect();
+        read_vectored(t, &mut reqs);
+        let nreads: Vec<usize> = reqs.iter().map(|r| r.nread).collect();
+        for ((p, mut buf), nread) in missing.into_iter().zip(bufs).zip(nreads) {
+            buf.truncate(nread);
+            self.pages.insert(p, buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(lens: &[usize], total: usize) -> (usize, Vec<(usize, usize)>) {
+        let mut got = Vec::new();
+        let done = distribute(lens, total, |i, n| got.push((i, n)));
+        (done, got)
+    }
+
+    #[test]
+    fn distribute_transfers() {
+        assert_eq!(split(&[4, 8, 2], 14), (3, vec![(0, 4), (1, 8), (2, 2)]));
+        assert_eq!(split(&[4, 8, 2], 7), (1, vec![(0, 4), (1, 3)]));
+        assert_eq!(split(&[4, 8, 2], 12), (2, vec![(0, 4), (1, 8), (2, 0)]));
+        assert_eq!(split(&[4, 8], 0), (0, vec![(0, 0)]));
+    }
+}
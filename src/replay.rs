@@ -0,0 +1,181 @@
+//! A small API for driving a replay from Rust code rather than through
+//! `rd replay` and a debugger.
+//!
+//! Programs using it get default `Flags`. rd looks for its helper binaries
+//! relative to the executable, so a program that isn't installed next to rd
+//! needs to call `Flags::init` with a `resource_path` before building a
+//! replayer.
+
+use crate::{
+    assert_prerequisites,
+    divergence_repair::BenignDivergences,
+    registers::Registers,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    session::{
+        address_space::{BreakpointType, WatchType},
+        replay_session::{self, ReplayResult, ReplaySession, ReplayStatus},
+        session_inner::RunCommand,
+        task::TaskSharedPtr,
+        SessionSharedPtr,
+    },
+    trace::trace_frame::FrameTime,
+};
+use std::path::{Path, PathBuf};
+
+/// Opens a trace for programmatic replay, without a debugger or gdb server in
+/// between. For example, to find the first event at which a variable changes:
+///
+/// ```ignore
+/// let mut replayer = ReplaySessionBuilder::new().trace_dir(dir).build();
+/// replayer.add_watchpoint(addr, 8, WatchType::WatchWrite);
+/// while let Some(result) = replayer.step() {
+///     if !result.break_status.watchpoints_hit.is_empty() {
+///         println!("changed at event {}", replayer.current_frame_time());
+///         break;
+///     }
+/// }
+/// ```
+pub struct ReplaySessionBuilder {
+    trace_dir: Option<PathBuf>,
+    flags: replay_session::Flags,
+}
+
+impl Default for ReplaySessionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplaySessionBuilder {
+    pub fn new() -> ReplaySessionBuilder {
+        ReplaySessionBuilder {
+            trace_dir: None,
+            flags: replay_session::Flags {
+                redirect_stdio: false,
+                share_private_mappings: false,
+                cpu_unbound: false,
+                repair_divergences: BenignDivergences::empty(),
+                only_process: None,
+            },
+        }
+    }
+
+    /// Replay the trace in `dir`. Without this the latest trace is used.
+    pub fn trace_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.trace_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Echo tracee writes to stdout/stderr, as `rd replay` does.
+    pub fn redirect_stdio(mut self, redirect_stdio: bool) -> Self {
+        self.flags.redirect_stdio = redirect_stdio;
+        self
+    }
+
+    /// Let tracees run on any CPU rather than the one the trace was recorded on.
+    pub fn cpu_unbound(mut self, cpu_unbound: bool) -> Self {
+        self.flags.cpu_unbound = cpu_unbound;
+        self
+    }
+
+    pub fn build(self) -> Replayer {
+        assert_prerequisites(None);
+        Replayer {
+            session: ReplaySession::create(self.trace_dir.as_ref(), self.flags),
+            exited: false,
+        }
+    }
+}
+
+/// A replay in progress. Tasks can be inspected between steps. Breakpoints and
+/// watchpoints make `step()` stop early, as reported by the returned
+/// `ReplayResult::break_status`.
+pub struct Replayer {
+    session: SessionSharedPtr,
+    exited: bool,
+}
+
+impl Replayer {
+    pub fn session(&self) -> &ReplaySession {
+        self.session.as_replay().unwrap()
+    }
+
+    /// The trace event we're currently at.
+    pub fn current_frame_time(&self) -> FrameTime {
+        self.session().current_frame_time()
+    }
+
+    /// Run to the end of the current trace event, or until a breakpoint or
+    /// watchpoint is hit. Returns `None` once all tracees have exited.
+    pub fn step(&mut self) -> Option<ReplayResult> {
+        self.run(RunCommand::RunContinue)
+    }
+
+    /// Execute one instruction (or reach the end of the current event).
+    pub fn singlestep(&mut self) -> Option<ReplayResult> {
+        self.run(RunCommand::RunSinglestep)
+    }
+
+    fn run(&mut self, command: RunCommand) -> Option<ReplayResult> {
+        if self.exited {
+            return None;
+        }
+        let result = self.session().replay_step(command);
+        if result.status == ReplayStatus::ReplayExited {
+            self.exited = true;
+        }
+        Some(result)
+    }
+
+    pub fn current_task(&self) -> Option<TaskSharedPtr> {
+        self.session().current_task()
+    }
+
+    /// Registers of the current task.
+    pub fn registers(&self) -> Option<Registers> {
+        self.current_task().map(|t| t.regs_ref().clone())
+    }
+
+    /// Read up to `len` bytes of the current task's memory at `addr`. Stops
+    /// short at the first unmapped page.
+    pub fn read_memory(&self, addr: RemotePtr<Void>, len: usize) -> Option<Vec<u8>> {
+        let t = self.current_task()?;
+        let mut buf = vec![0u8; len];
+        let nread = t.read_bytes_fallible(addr, &mut buf).ok()?;
+        buf.truncate(nread);
+        Some(buf)
+    }
+
+    /// Breakpoints and watchpoints are set in the current task's address space.
+    pub fn add_breakpoint(&self, addr: RemoteCodePtr) -> bool {
+        match self.current_task() {
+            Some(t) => t.vm().add_breakpoint(addr, BreakpointType::BkptUser),
+            None => false,
+        }
+    }
+
+    pub fn remove_breakpoint(&self, addr: RemoteCodePtr) {
+        if let Some(t) = self.current_task() {
+            t.vm().remove_breakpoint(addr, BreakpointType::BkptUser);
+        }
+    }
+
+    pub fn add_watchpoint(
+        &self,
+        addr: RemotePtr<Void>,
+        num_bytes: usize,
+        type_: WatchType,
+    ) -> bool {
+        match self.current_task() {
+            Some(t) => t.vm().add_watchpoint(addr, num_bytes, type_),
+            None => false,
+        }
+    }
+
+    pub fn remove_watchpoint(&self, addr: RemotePtr<Void>, num_bytes: usize, type_: WatchType) {
+        if let Some(t) = self.current_task() {
+            t.vm().remove_watchpoint(addr, num_bytes, type_);
+        }
+    }
+}
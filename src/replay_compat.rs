@@ -0,0 +1,574 @@
+use crate::{
+    flags::Flags,
+    perf_counters::PerfCounters,
+    preload_interface::{syscallbuf_hdr, PRELOAD_THREAD_LOCALS_SIZE, SYSCALLBUF_PROTOCOL_VERSION},
+    session::{
+        record_session::{CPUIDMask, CPUIDRegister},
+        session_inner::SessionInner,
+    },
+    trace::trace_reader::TraceReader,
+    util::{
+        all_cpuid_records, find_cpuid_record, xcr0, CPUIDData, CPUIDRecord,
+        CPUID_GETEXTENDEDFEATURES, CPUID_GETFEATURES, CPUID_GETVENDORSTRING, CPUID_GETXSAVE,
+        CPUID_INTELBRANDSTRING, CPUID_INTELBRANDSTRINGEND, OSXSAVE_FEATURE_FLAG,
+        XSAVEC_FEATURE_FLAG,
+    },
+};
+use std::mem::size_of;
+
+#[derive(Copy, Clone)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// A CPUID feature bit whose absence at replay time breaks a tracee that saw
+/// it during recording.
+struct Feature {
+    leaf: u32,
+    subleaf: u32,
+    reg: Reg,
+    bit: u32,
+    name: &'static str,
+    /// Only a handful of programs use this feature, so a missing feature is
+    /// worth a warning but not worth refusing to replay.
+    rarely_used: bool,
+}
+
+const fn feature(leaf: u32, reg: Reg, bit: u32, name: &'static str) -> Feature {
+    Feature {
+        leaf,
+        subleaf: 0,
+        reg,
+        bit,
+        name,
+        rarely_used: false,
+    }
+}
+
+const fn rare_feature(leaf: u32, reg: Reg, bit: u32, name: &'static str) -> Feature {
+    Feature {
+        rarely_used: true,
+        ..feature(leaf, reg, bit, name)
+    }
+}
+
+/// Features that libc, the dynamic loader and common libraries pick code
+/// paths by, so a tracee is very likely to have executed them.
+const FEATURES: &[Feature] = &[
+    feature(CPUID_GETFEATURES, Reg::Ecx, 0, "sse3"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 1, "pclmulqdq"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 9, "ssse3"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 12, "fma"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 13, "cx16"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 19, "sse4.1"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 20, "sse4.2"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 22, "movbe"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 23, "popcnt"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 25, "aes"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 26, "xsave"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 28, "avx"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 29, "f16c"),
+    feature(CPUID_GETFEATURES, Reg::Ecx, 30, "rdrand"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 3, "bmi1"),
+    rare_feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 4, "hle"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 5, "avx2"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 8, "bmi2"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 9, "erms"),
+    rare_feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 11, "rtm"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 16, "avx512f"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 17, "avx512dq"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 18, "rdseed"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 19, "adx"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 23, "clflushopt"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 24, "clwb"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 28, "avx512cd"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 29, "sha"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 30, "avx512bw"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ebx, 31, "avx512vl"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ecx, 1, "avx512vbmi"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ecx, 9, "vaes"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ecx, 10, "vpclmulqdq"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Ecx, 11, "avx512vnni"),
+    rare_feature(CPUID_GETEXTENDEDFEATURES, Reg::Ecx, 22, "rdpid"),
+    feature(CPUID_GETEXTENDEDFEATURES, Reg::Edx, 4, "fsrm"),
+];
+
+fn has_feature(records: &[CPUIDRecord], f: &Feature) -> bool {
+    match find_cpuid_record(records, f.leaf, f.subleaf) {
+        None => false,
+        Some(rec) => {
+            let value = match f.reg {
+                Reg::Ebx => rec.out.ebx,
+                Reg::Ecx => rec.out.ecx,
+                Reg::Edx => rec.out.edx,
+            };
+            value & (1 << f.bit) != 0
+        }
+    }
+}
+
+/// The features the trace's CPUID records advertise but `ours` doesn't.
+fn missing_features(recorded: &[CPUIDRecord], ours: &[CPUIDRecord]) -> Vec<&'static Feature> {
+    FEATURES
+        .iter()
+        .filter(|f| has_feature(recorded, f) && !has_feature(ours, f))
+        .collect()
+}
+
+/// The `--disable-cpuid-features` argument that hides `features`.
+fn disable_cpuid_features_arg(recorded: &[CPUIDRecord], features: &[&Feature]) -> String {
+    let mut masks: Vec<CPUIDMask> = Vec::new();
+    for f in features {
+        let register = match f.reg {
+            Reg::Ebx => CPUIDRegister::Ebx,
+            Reg::Ecx => CPUIDRegister::Ecx,
+            Reg::Edx => CPUIDRegister::Edx,
+        };
+        // Leaves without subleaves were recorded with ECX=0xffffffff.
+        let subleaf = match find_cpuid_record(recorded, f.leaf, f.subleaf) {
+            Some(rec) if rec.ecx_in == std::u32::MAX => None,
+            _ => Some(f.subleaf),
+        };
+        match masks
+            .iter_mut()
+            .find(|m| m.leaf == f.leaf && m.subleaf == subleaf && m.register == register)
+        {
+            Some(m) => m.bits |= 1 << f.bit,
+            None => masks.push(CPUIDMask::new(f.leaf, subleaf, register, 1 << f.bit)),
+        }
+    }
+    let masks: Vec<String> = masks.iter().map(|m| m.to_string()).collect();
+    masks.join(",")
+}
+
+/// Family, model and stepping, the "microarchitecture" we require to match
+/// when we can't intercept CPUID.
+fn cpu_type(records: &[CPUIDRecord]) -> Option<u32> {
+    find_cpuid_record(records, CPUID_GETFEATURES, 0).map(|rec| rec.out.eax & 0xF0FF0)
+}
+
+/// A human readable name for the CPU the records were gathered on: the brand
+/// string if there is one, otherwise the vendor and family/model.
+fn cpu_description(records: &[CPUIDRecord]) -> String {
+    let mut brand = Vec::new();
+    for leaf in CPUID_INTELBRANDSTRING..=CPUID_INTELBRANDSTRINGEND {
+        match find_cpuid_record(records, leaf, 0) {
+            Some(rec) => {
+                for reg in &[rec.out.eax, rec.out.ebx, rec.out.ecx, rec.out.edx] {
+                    brand.extend_from_slice(&reg.to_le_bytes());
+                }
+            }
+            None => break,
+        }
+    }
+    let brand = String::from_utf8_lossy(&brand);
+    let brand = brand.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if !brand.is_empty() {
+        return brand.to_owned();
+    }
+
+    let mut vendor = Vec::new();
+    if let Some(rec) = find_cpuid_record(records, CPUID_GETVENDORSTRING, 0) {
+        for reg in &[rec.out.ebx, rec.out.edx, rec.out.ecx] {
+            vendor.extend_from_slice(&reg.to_le_bytes());
+        }
+    }
+    format!(
+        "{} CPU with family/model {:#x}",
+        String::from_utf8_lossy(&vendor).trim_matches('\0'),
+        cpu_type(records).unwrap_or(0)
+    )
+}
+
+/// The first XSAVE component enabled both in the trace and here whose size,
+/// offset or (when both sides use XSAVEC) alignment differs.
+fn xsave_layout_mismatch(
+    recorded: &[CPUIDRecord],
+    recorded_xcr0: u64,
+    ours: &[CPUIDRecord],
+    our_xcr0: u64,
+    check_alignment: bool,
+) -> Option<u32> {
+    // Check that sizes and offsets of supported XSAVE areas area all identical.
+    // An Intel employee promised this on a mailing list...
+    // https://lists.xen.org/archives/html/xen-devel/2013-09/msg00484.html
+    for feature in 2u32..=63 {
+        if (recorded_xcr0 & our_xcr0 & (1u64 << feature as u64)) == 0 {
+            continue;
+        }
+        let data: CPUIDData = match find_cpuid_record(ours, CPUID_GETXSAVE, feature) {
+            Some(rec) => rec.out,
+            None => continue,
+        };
+        match find_cpuid_record(recorded, CPUID_GETXSAVE, feature) {
+            Some(rec)
+                if rec.out.eax == data.eax
+                    && rec.out.ebx == data.ebx
+                    && (!check_alignment || (rec.out.ecx & 2) == (data.ecx & 2)) => {}
+            _ => return Some(feature),
+        }
+    }
+    None
+}
+
+fn xsave_enabled_in(records: &[CPUIDRecord]) -> bool {
+    find_cpuid_record(records, CPUID_GETFEATURES, 0)
+        .map_or(false, |rec| rec.out.ecx & OSXSAVE_FEATURE_FLAG != 0)
+}
+
+fn xsavec_in(records: &[CPUIDRecord]) -> bool {
+    find_cpuid_record(records, CPUID_GETXSAVE, 1)
+        .map_or(false, |rec| rec.out.eax & XSAVEC_FEATURE_FLAG != 0)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Severity {
+    /// Replay may still work, e.g. if the tracee never used the feature.
+    Warning,
+    /// Replay is certain to diverge, so don't start it.
+    Fatal,
+}
+
+struct Problem {
+    severity: Severity,
+    message: String,
+}
+
+/// The result of checking a trace's recorded machine capabilities (CPUID,
+/// XSAVE layout, ticks semantics and syscallbuf ABI) against this machine and
+/// this build of rd. ReplaySession refuses to start on fatal problems and
+/// prints the others, so users get told why a replay can't work instead of
+/// watching it diverge.
+pub struct CompatReport {
+    problems: Vec<Problem>,
+}
+
+impl CompatReport {
+    pub fn check(trace: &TraceReader) -> CompatReport {
+        let mut report = CompatReport {
+            problems: Vec::new(),
+        };
+        let ours = all_cpuid_records();
+        report.check_cpuid(trace, &ours);
+        report.check_xsave(trace, &ours);
+        report.check_ticks_semantics(trace);
+        report.check_syscallbuf_abi(trace);
+        report
+    }
+
+    /// True if nothing prevents replaying the trace here.
+    pub fn is_compatible(&self) -> bool {
+        self.problems.iter().all(|p| p.severity != Severity::Fatal)
+    }
+
+    /// Print any warnings and exit with an error if the trace can't be
+    /// replayed here.
+    pub fn enforce(&self) {
+        if !Flags::get().suppress_environment_warnings {
+            for p in &self.problems {
+                if p.severity == Severity::Warning {
+                    eprintln!("rd: warning: {}\n", p.message);
+                }
+            }
+        }
+        if !self.is_compatible() {
+            let messages: Vec<&str> = self
+                .problems
+                .iter()
+                .filter(|p| p.severity == Severity::Fatal)
+                .map(|p| p.message.as_str())
+                .collect();
+            clean_fatal!("{}", messages.join("\n\n"));
+        }
+    }
+
+    fn add(&mut self, severity: Severity, message: String) {
+        self.problems.push(Problem { severity, message });
+    }
+
+    fn check_cpuid(&mut self, trace: &TraceReader, ours: &[CPUIDRecord]) {
+        let recorded = trace.cpuid_records();
+        if cpu_type(recorded).is_none() {
+            self.add(
+                Severity::Fatal,
+                "Trace has no CPUID records; it is probably corrupted.".into(),
+            );
+            return;
+        }
+
+        if trace.uses_cpuid_faulting() && !SessionInner::has_cpuid_faulting() {
+            self.add(
+                Severity::Fatal,
+                "Trace was recorded with CPUID faulting enabled, but this\n\
+                 system does not support CPUID faulting. Replay on a machine\n\
+                 that supports it, or re-record with --disable-cpuid-faulting."
+                    .into(),
+            );
+        }
+        if !SessionInner::has_cpuid_faulting() && cpu_type(recorded) != cpu_type(ours) {
+            self.add(
+                Severity::Fatal,
+                format!(
+                    "Trace was recorded on a machine with different CPUID values\n\
+                     and CPUID faulting is not enabled; replay will not work.\n\
+                     Recorded on: {}\n\
+                     Replaying on: {}",
+                    cpu_description(recorded),
+                    cpu_description(ours)
+                ),
+            );
+            // Every other difference is moot.
+            return;
+        }
+
+        let missing = missing_features(recorded, ours);
+        let names = |rare: bool| -> Vec<&'static str> {
+            missing
+                .iter()
+                .filter(|f| f.rarely_used == rare)
+                .map(|f| f.name)
+                .collect()
+        };
+        let common = names(false);
+        if !common.is_empty() {
+            let mut message = format!(
+                "Tracees saw CPU features that this machine lacks: {}.\n\
+                 Code selected for those features will fault during replay.\n\
+                 Recorded on: {}\n\
+                 Replaying on: {}",
+                common.join(", "),
+                cpu_description(recorded),
+                cpu_description(ours)
+            );
+            let lost: Vec<&Feature> = missing.iter().copied().filter(|f| !f.rarely_used).collect();
+            message.push_str(&format!(
+                "\nRe-record with --disable-cpuid-features {} to hide them from tracees.",
+                disable_cpuid_features_arg(recorded, &lost)
+            ));
+            self.add(Severity::Fatal, message);
+        }
+        let rare = names(true);
+        if !rare.is_empty() {
+            self.add(
+                Severity::Warning,
+                format!(
+                    "Tracees saw CPU features that this machine lacks: {}.\n\
+                     Replay will diverge if the tracee used them.",
+                    rare.join(", ")
+                ),
+            );
+        }
+    }
+
+    fn check_xsave(&mut self, trace: &TraceReader, ours: &[CPUIDRecord]) {
+        let recorded = trace.cpuid_records();
+        if !xsave_enabled_in(recorded) {
+            // Tracee couldn't use XSAVE so everything should be fine.
+            // If it didn't detect absence of XSAVE and actually executed an XSAVE
+            // and got a fault then replay will probably diverge :-(
+            return;
+        }
+        if !xsave_enabled_in(ours) {
+            // Replaying on a super old CPU that doesn't even support XSAVE!
+            self.add(
+                Severity::Warning,
+                "Tracees had XSAVE but XSAVE is not available now;\n\
+                 replay will probably fail because the glibc dynamic loader uses XSAVE."
+                    .into(),
+            );
+            return;
+        }
+
+        let tracee_xcr0 = trace.xcr0();
+        let our_xcr0 = xcr0();
+        let tracee_xsavec = xsavec_in(recorded);
+        let our_xsavec = xsavec_in(ours);
+        if tracee_xsavec && !our_xsavec {
+            self.add(
+                Severity::Warning,
+                "Tracees had XSAVEC but XSAVEC is not available now;\n\
+                 replay will probably fail because the glibc dynamic loader uses XSAVEC."
+                    .into(),
+            );
+        }
+        if tracee_xcr0 != our_xcr0 {
+            // If the tracee used XSAVE instructions which write different components
+            // to XSAVE instructions executed on our CPU, or examines XCR0 directly,
+            // This will cause divergence. The dynamic linker examines XCR0 so this
+            // is nearly guaranteed.
+            self.add(
+                Severity::Warning,
+                format!(
+                    "Trace XCR0 value {:#x} != our XCR0 value {:#x} (missing here: {:#x});\n\
+                     replay will probably fail because the glibc dynamic loader examines XCR0.",
+                    tracee_xcr0,
+                    our_xcr0,
+                    tracee_xcr0 & !our_xcr0
+                ),
+            );
+        }
+
+        if let Some(feature) = xsave_layout_mismatch(
+            recorded,
+            tracee_xcr0,
+            ours,
+            our_xcr0,
+            tracee_xsavec && our_xsavec,
+        ) {
+            self.add(
+                Severity::Fatal,
+                format!(
+                    "XSAVE offset/size/alignment differs for state component {};\n\
+                     extended registers can't be restored on this CPU.\n\
+                     Replay on a machine with the same CPU as {}.",
+                    feature,
+                    cpu_description(recorded)
+                ),
+            );
+        }
+    }
+
+    fn check_ticks_semantics(&mut self, trace: &TraceReader) {
+        if !PerfCounters::supports_ticks_semantics(trace.ticks_semantics()) {
+            self.add(
+                Severity::Fatal,
+                "Trace was recorded on a machine that defines ticks differently\n\
+                 to this machine; replay will not work."
+                    .into(),
+            );
+        }
+    }
+
+    fn check_syscallbuf_abi(&mut self, trace: &TraceReader) {
+        let version = trace.syscallbuf_protocol_version();
+        if version != SYSCALLBUF_PROTOCOL_VERSION {
+            self.add(
+                Severity::Fatal,
+                format!(
+                    "Trace uses syscallbuf protocol version {} but this rd speaks version {};\n\
+                     replay the trace with the rd build that recorded it.",
+                    version, SYSCALLBUF_PROTOCOL_VERSION
+                ),
+            );
+            return;
+        }
+        // Older traces didn't record the layout; 0 means "unknown".
+        let hdr_size = trace.syscallbuf_hdr_size();
+        let locals_size = trace.preload_thread_locals_size();
+        if (hdr_size != 0 && hdr_size as usize != size_of::<syscallbuf_hdr>())
+            || (locals_size != 0 && locals_size as usize != PRELOAD_THREAD_LOCALS_SIZE)
+        {
+            self.add(
+                Severity::Fatal,
+                format!(
+                    "Trace was recorded by an rd whose preload library has a different layout\n\
+                     (syscallbuf header {} bytes, thread locals {} bytes; here {} and {});\n\
+                     replay the trace with the rd build that recorded it.",
+                    hdr_size,
+                    locals_size,
+                    size_of::<syscallbuf_hdr>(),
+                    PRELOAD_THREAD_LOCALS_SIZE
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn record(eax_in: u32, ecx_in: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> CPUIDRecord {
+        CPUIDRecord {
+            eax_in,
+            ecx_in,
+            out: CPUIDData { eax, ebx, ecx, edx },
+        }
+    }
+
+    fn le_words(bytes: &[u8; 16]) -> [u32; 4] {
+        let mut words = [0u32; 4];
+        for (i, w) in words.iter_mut().enumerate() {
+            *w = u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        words
+    }
+
+    #[test]
+    fn missing_features_reports_only_lost_bits() {
+        let avx2_rtm_avx512f = (1 << 5) | (1 << 11) | (1 << 16);
+        let recorded = vec![
+            record(CPUID_GETFEATURES, std::u32::MAX, 0x306c3, 0, 1 << 28, 0),
+            record(CPUID_GETEXTENDEDFEATURES, 0, 0, avx2_rtm_avx512f, 0, 0),
+        ];
+        let ours = vec![
+            record(CPUID_GETFEATURES, std::u32::MAX, 0x306c3, 0, 1 << 28, 0),
+            record(CPUID_GETEXTENDEDFEATURES, 0, 0, 1 << 5, 0, 0),
+        ];
+        let names: Vec<&str> = missing_features(&recorded, &ours)
+            .iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, vec!["rtm", "avx512f"]);
+        assert!(missing_features(&ours, &recorded).is_empty());
+    }
+
+    #[test]
+    fn disable_cpuid_features_arg_masks_lost_bits() {
+        let recorded = vec![
+            record(CPUID_GETFEATURES, std::u32::MAX, 0x306c3, 0, 1 << 28, 0),
+            record(CPUID_GETEXTENDEDFEATURES, 0, 0, 0, 0, 0),
+        ];
+        let lost: Vec<&Feature> = FEATURES
+            .iter()
+            .filter(|f| ["avx", "avx512f", "avx512dq", "avx512vbmi"].contains(&f.name))
+            .collect();
+        assert_eq!(
+            disable_cpuid_features_arg(&recorded, &lost),
+            "0x1:ecx=0x10000000,0x7.0:ebx=0x30000,0x7.0:ecx=0x2"
+        );
+    }
+
+    #[test]
+    fn cpu_description_prefers_brand_string() {
+        let [a, b, c, d] = le_words(b"Test CPU @ 1GHz\0");
+        let records = vec![
+            record(CPUID_INTELBRANDSTRING, std::u32::MAX, a, b, c, d),
+            record(CPUID_INTELBRANDSTRING + 1, std::u32::MAX, 0, 0, 0, 0),
+            record(CPUID_INTELBRANDSTRINGEND, std::u32::MAX, 0, 0, 0, 0),
+        ];
+        assert_eq!(cpu_description(&records), "Test CPU @ 1GHz");
+
+        let [ebx, edx, ecx, _] = le_words(b"GenuineIntel\0\0\0\0");
+        let records = vec![
+            record(CPUID_GETVENDORSTRING, std::u32::MAX, 0xd, ebx, ecx, edx),
+            record(CPUID_GETFEATURES, std::u32::MAX, 0x906ea, 0, 0, 0),
+        ];
+        assert_eq!(
+            cpu_description(&records),
+            "GenuineIntel CPU with family/model 0x906e0"
+        );
+    }
+
+    #[test]
+    fn xsave_layout_mismatch_ignores_disabled_components() {
+        let avx = |ebx| record(CPUID_GETXSAVE, 2, 256, ebx, 0, 0);
+        let recorded = vec![avx(576)];
+        assert_eq!(
+            xsave_layout_mismatch(&recorded, 0x7, &[avx(576)], 0x7, false),
+            None
+        );
+        assert_eq!(
+            xsave_layout_mismatch(&recorded, 0x7, &[avx(640)], 0x7, false),
+            Some(2)
+        );
+        // AVX state isn't enabled here so its layout can't matter.
+        assert_eq!(
+            xsave_layout_mismatch(&recorded, 0x7, &[avx(640)], 0x3, false),
+            None
+        );
+    }
+}
@@ -19,19 +19,27 @@ use crate::{
     },
     emu_fs::EmuFileSharedPtr,
     file_monitor::{
-        base_file_monitor::BaseFileMonitor, mmapped_file_monitor::MmappedFileMonitor,
-        proc_fd_dir_monitor::ProcFdDirMonitor, proc_mem_monitor::ProcMemMonitor,
-        stdio_monitor::StdioMonitor, virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+        anon_fd_monitor::{AnonFdKind, AnonFdMonitor},
+        base_file_monitor::BaseFileMonitor,
+        cgroup_file_monitor::CgroupFileMonitor,
+        locale_file_monitor::LocaleFileMonitor,
+        mmapped_file_monitor::MmappedFileMonitor,
+        proc_fd_dir_monitor::ProcFdDirMonitor,
+        proc_mem_monitor::ProcMemMonitor,
+        random_file_monitor::RandomFileMonitor,
+        stdio_monitor::StdioMonitor,
+        virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
         FileMonitor, FileMonitorType,
     },
     kernel_abi::{
-        is_rdcall_notify_syscall_hook_exit_syscall, is_restart_syscall_syscall, is_write_syscall,
-        syscall_number_for_close, syscall_number_for_execve, syscall_number_for_munmap,
-        syscall_number_for_open, syscall_number_for_prctl, CloneTLSType, MmapCallingSemantics,
-        SupportedArch, RD_NATIVE_ARCH,
+        common::clone_args, is_rdcall_notify_syscall_hook_exit_syscall, is_restart_syscall_syscall,
+        is_write_syscall, syscall_number_for_close, syscall_number_for_execve,
+        syscall_number_for_munmap, syscall_number_for_open, syscall_number_for_prctl,
+        CloneParameterOrdering, CloneTLSType, MmapCallingSemantics, SupportedArch, RD_NATIVE_ARCH,
     },
     kernel_metadata::{is_sigreturn, shm_flags_to_mmap_prot, syscall_name},
-    kernel_supplement::{ARCH_GET_CPUID, ARCH_SET_CPUID},
+    kernel_supplement::{ARCH_GET_CPUID, ARCH_SET_CPUID, MADV_KEEPONFORK, MADV_WIPEONFORK},
+    lazy_mappings::materialize_range,
     log::LogLevel::LogDebug,
     preload_interface::{syscallbuf_hdr, SYS_rdcall_reload_auxv},
     registers::{with_converted_registers, Registers},
@@ -51,8 +59,11 @@ use crate::{
         },
         task::{
             replay_task::{ReplayTask, ReplayTaskIgnore},
-            task_common::{read_mem, read_val_mem, write_mem, write_val_mem},
-            task_inner::{ResumeRequest, TicksRequest, WaitRequest, WriteFlags},
+            task_common::{
+                os_clone_attached_thread, read_mem, read_val_mem, will_sigreturn, write_mem,
+                write_val_mem,
+            },
+            task_inner::{CloneFlags, ResumeRequest, TicksRequest, WaitRequest, WriteFlags},
             Task, TaskSharedPtr,
         },
     },
@@ -64,9 +75,9 @@ use crate::{
         trace_task_event::{TraceTaskEvent, TraceTaskEventType},
     },
     util::{
-        ceil_page_size, ceil_page_u64, clone_flags_to_task_flags, extract_clone_parameters,
-        floor_page_size, is_proc_fd_dir, is_proc_mem_file, page_size, resource_path,
-        CloneParameters,
+        ceil_page_size, ceil_page_u64, clone_flags_to_task_flags, extract_clone3_parameters,
+        extract_clone_parameters, floor_page_size, is_dev_random, is_locale_file, is_proc_fd_dir,
+        is_proc_mem_file, page_size, read_clone_args, resource_path, CloneParameters, CLONE_PIDFD,
     },
     wait_status::WaitStatus,
 };
@@ -91,6 +102,7 @@ use std::{
     ffi::{CString, OsStr, OsString},
     mem::size_of,
     os::unix::ffi::{OsStrExt, OsStringExt},
+    rc::Rc,
 };
 use trace_stream::{MappedDataSource, TraceRemoteFd};
 
@@ -308,25 +320,57 @@ fn prepare_clone<Arch: Architecture>(t: &ReplayTask) {
     let mut r = t.regs_ref().clone();
     let mut sys: i32 = r.original_syscallno() as i32;
     let mut flags: i32 = 0;
+    // If we allow CLONE_UNTRACED then the child would escape from rd control
+    // and we can't allow that.
+    // Block CLONE_CHILD_CLEARTID because we'll emulate that ourselves.
+    // Block CLONE_VFORK for the reasons below.
+    // Block CLONE_NEW* from replay, any effects it had were dealt with during
+    // recording.
+    let disallowed_clone_flags = CLONE_UNTRACED
+        | CLONE_CHILD_CLEARTID
+        | CLONE_VFORK
+        | CLONE_NEWIPC
+        | CLONE_NEWNET
+        | CLONE_NEWNS
+        | CLONE_NEWPID
+        | CLONE_NEWUSER
+        | CLONE_NEWUTS
+        | CLONE_NEWCGROUP;
+    let mut maybe_clone3_args: Option<clone_args> = None;
     if Arch::CLONE == sys {
-        // If we allow CLONE_UNTRACED then the child would escape from rd control
-        // and we can't allow that.
-        // Block CLONE_CHILD_CLEARTID because we'll emulate that ourselves.
-        // Block CLONE_VFORK for the reasons below.
-        // Block CLONE_NEW* from replay, any effects it had were dealt with during
-        // recording.
-        let disallowed_clone_flags = CLONE_UNTRACED
-            | CLONE_CHILD_CLEARTID
-            | CLONE_VFORK
-            | CLONE_NEWIPC
-            | CLONE_NEWNET
-            | CLONE_NEWNS
-            | CLONE_NEWPID
-            | CLONE_NEWUSER
-            | CLONE_NEWUTS
-            | CLONE_NEWCGROUP;
         flags = r.arg1() as i32 & !disallowed_clone_flags;
         r.set_arg1(flags as usize);
+    } else if Arch::CLONE3 == sys {
+        // DIFF NOTE: rd replays clone3 as the equivalent clone. What clone3
+        // can do beyond that doesn't matter during replay: set_tid and
+        // CLONE_INTO_CGROUP affect nothing the tracees can observe without
+        // syscalls we replay from the trace, the pidfd is restored from the
+        // trace and we don't track signal handlers (CLONE_CLEAR_SIGHAND).
+        let maybe_args = read_clone_args(t);
+        ed_assert!(
+            t,
+            maybe_args.is_some(),
+            "clone3 succeeded during recording but its clone_args can't be read"
+        );
+        let args = maybe_args.unwrap();
+        let params = extract_clone3_parameters(&args);
+        sys = Arch::CLONE;
+        flags = (args.flags as i32 | (args.exit_signal as i32 & 0xff))
+            & !(disallowed_clone_flags | CLONE_PIDFD);
+        r.set_arg1(flags as usize);
+        r.set_arg2(params.stack.as_usize());
+        r.set_arg3(params.ptid.as_usize());
+        match Arch::CLONE_PARAMETER_ORDERING {
+            CloneParameterOrdering::FlagsStackParentTLSChild => {
+                r.set_arg4(params.tls.as_usize());
+                r.set_arg5(params.ctid.as_usize());
+            }
+            CloneParameterOrdering::FlagsStackParentChildTLS => {
+                r.set_arg4(params.ctid.as_usize());
+                r.set_arg5(params.tls.as_usize());
+            }
+        }
+        maybe_clone3_args = Some(args);
     } else if Arch::VFORK == sys {
         // We can't perform a real vfork, because the kernel won't let the vfork
         // parent return from the syscall until the vfork child has execed or
@@ -393,10 +437,15 @@ fn prepare_clone<Arch: Architecture>(t: &ReplayTask) {
 
     r = t.regs_ref().clone();
     // Restore the saved flags, to hide the fact that we may have
-    // masked out CLONE_UNTRACED/CLONE_CHILD_CLEARTID or changed from vfork to
-    // clone.
+    // masked out CLONE_UNTRACED/CLONE_CHILD_CLEARTID or changed from vfork or
+    // clone3 to clone.
     r.set_arg1(trace_frame_regs.arg1());
     r.set_arg2(trace_frame_regs.arg2());
+    if maybe_clone3_args.is_some() {
+        r.set_arg3(trace_frame_regs.arg3());
+        r.set_arg4(trace_frame_regs.arg4());
+        r.set_arg5(trace_frame_regs.arg5());
+    }
     // Pretend we're still in the system call
     r.set_syscall_result(-ENOSYS as usize);
     r.set_original_syscallno(trace_frame_regs.original_syscallno());
@@ -419,12 +468,24 @@ fn prepare_clone<Arch: Architecture>(t: &ReplayTask) {
     let mut params: CloneParameters = Default::default();
     if Arch::CLONE as isize == t.regs_ref().original_syscallno() {
         params = extract_clone_parameters(t);
+    } else if let Some(args) = maybe_clone3_args {
+        params = extract_clone3_parameters(&args);
+    }
+    let mut task_flags = clone_flags_to_task_flags(flags);
+    // We don't really vfork (see above), but the new task's state should be
+    // what it was during recording.
+    if Arch::VFORK as isize == trace_frame_regs.original_syscallno()
+        || (Arch::CLONE as isize == trace_frame_regs.original_syscallno()
+            && trace_frame_regs.arg1() as i32 & CLONE_VFORK != 0)
+        || maybe_clone3_args.map_or(false, |args| args.flags as i32 & CLONE_VFORK != 0)
+    {
+        task_flags |= CloneFlags::CLONE_VFORK;
     }
     let shr_ptr = t.session();
 
     let new_task_shr_ptr: TaskSharedPtr = shr_ptr.clone_task(
         t,
-        clone_flags_to_task_flags(flags),
+        task_flags,
         params.stack,
         params.tls,
         params.ctid,
@@ -434,7 +495,7 @@ fn prepare_clone<Arch: Architecture>(t: &ReplayTask) {
 
     let new_task: &ReplayTask = new_task_shr_ptr.as_replay_task().unwrap();
 
-    if Arch::CLONE as isize == t.regs_ref().original_syscallno() {
+    if Arch::CLONE as isize == t.regs_ref().original_syscallno() || maybe_clone3_args.is_some() {
         // FIXME: what if registers are non-null and contain an invalid address?
         t.set_data_from_trace(Some(new_task));
 
@@ -446,6 +507,10 @@ fn prepare_clone<Arch: Architecture>(t: &ReplayTask) {
         }
         new_task.set_data_from_trace(Some(t));
         new_task.set_data_from_trace(Some(t));
+        if maybe_clone3_args.is_some() {
+            // The pidfd
+            t.set_data_from_trace(Some(new_task));
+        }
     }
 
     // Fix registers in new task
@@ -457,9 +522,14 @@ fn prepare_clone<Arch: Architecture>(t: &ReplayTask) {
     new_task.set_regs(&new_r);
     new_task.canonicalize_regs(new_task_arch);
 
-    if Arch::CLONE as isize != t.regs_ref().original_syscallno()
-        || CLONE_VM as usize & r.arg1() == 0
-    {
+    let shares_vm = match maybe_clone3_args {
+        Some(args) => args.flags as i32 & CLONE_VM != 0,
+        None => {
+            Arch::CLONE as isize == t.regs_ref().original_syscallno()
+                && CLONE_VM as usize & r.arg1() != 0
+        }
+    };
+    if !shares_vm {
         // It's hard to imagine a scenario in which it would
         // be useful to inherit breakpoints (along with their
         // refcounts) across a non-VM-sharing clone, but for
@@ -546,9 +616,17 @@ pub fn rep_process_syscall(t: &ReplayTask, step: &mut ReplayTraceStep) {
         arch = trace_frame.event().syscall_event().arch();
         trace_regs = trace_frame.regs_ref().clone()
     }
+    // Some syscalls are executed, and the kernel would get EFAULT from pages
+    // we protected for software watchpoints.
+    let vm = t.vm();
+    vm.set_software_watch_protection(t, false);
     with_converted_registers(&trace_regs, arch, |converted_regs| {
         rd_arch_function_selfless!(rep_process_syscall_arch, arch, t, step, converted_regs)
-    })
+    });
+    // An execve replaces the address space, along with its watchpoints.
+    if Rc::ptr_eq(&vm, &t.vm()) {
+        vm.set_software_watch_protection(t, true);
+    }
 }
 
 fn rep_process_syscall_arch<Arch: Architecture>(
@@ -563,10 +641,16 @@ fn rep_process_syscall_arch<Arch: Architecture>(
         "processing {} (exit)",
         syscall_name(sys, Arch::arch())
     );
+    // A trace recorded with `rd record --attach` starts with a snapshot of the
+    // process, recorded as an execve. Its registers are whatever the process
+    // had when rd attached, so they say nothing about a syscall.
+    let is_attach_snapshot =
+        sys == Arch::EXECVE && !t.session().done_initial_exec() && t.trace_reader().attached();
+
     // sigreturns are never restartable, and the value of the
     // syscall-result register after a sigreturn is not actually the
     // syscall result.
-    if trace_regs.syscall_may_restart() && !is_sigreturn(sys, Arch::arch()) {
+    if !is_attach_snapshot && trace_regs.syscall_may_restart() && !is_sigreturn(sys, Arch::arch()) {
         // During recording, when a sys exits with a
         // restart "error", the kernel sometimes restarts the
         // tracee by resetting its $ip to the syscall entry
@@ -594,6 +678,10 @@ fn rep_process_syscall_arch<Arch: Architecture>(
             number: sys,
         }),
     };
+    if is_attach_snapshot {
+        return process_execve(t, step);
+    }
+
     if trace_regs.original_syscallno() == SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO {
         // rd vetoed this syscall. Don't do any post-processing. Do set registers
         // to match any registers rd modified to fool the signal handler.
@@ -605,6 +693,7 @@ fn rep_process_syscall_arch<Arch: Architecture>(
     if trace_regs.syscall_failed() {
         if nsys != Arch::MADVISE
             && nsys != Arch::MPROTECT
+            && nsys != Arch::PKEY_MPROTECT
             && nsys != Arch::SIGRETURN
             && nsys != Arch::RT_SIGRETURN
         {
@@ -679,8 +768,11 @@ fn rep_process_syscall_arch<Arch: Architecture>(
     if nsys == Arch::MADVISE {
         match t.regs_ref().arg3() as i32 {
             MADV_DONTNEED | MADV_REMOVE => (),
+            // Affects the memory of children forked later in the replay.
+            advice if advice as u32 == MADV_WIPEONFORK || advice as u32 == MADV_KEEPONFORK => (),
             _ => return,
         }
+        materialize_range(t, t.regs_ref().arg1().into(), t.regs_ref().arg2());
     }
 
     if nsys == Arch::MADVISE || nsys == Arch::ARCH_PRCTL {
@@ -696,6 +788,7 @@ fn rep_process_syscall_arch<Arch: Architecture>(
         || nsys == Arch::ARCH_PRCTL
         || nsys == Arch::MUNMAP
         || nsys == Arch::MPROTECT
+        || nsys == Arch::PKEY_MPROTECT
         || nsys == Arch::MODIFY_LDT
         || nsys == Arch::SET_THREAD_AREA
     {
@@ -704,7 +797,7 @@ fn rep_process_syscall_arch<Arch: Architecture>(
         r.set_syscallno(t.regs_ref().original_syscallno());
         r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
         t.set_regs(&r);
-        if nsys == Arch::MPROTECT {
+        if nsys == Arch::MPROTECT || nsys == Arch::PKEY_MPROTECT {
             t.vm().fixup_mprotect_growsdown_parameters(t);
         }
         __ptrace_cont(
@@ -727,7 +820,7 @@ fn rep_process_syscall_arch<Arch: Architecture>(
             t,
             t.regs_ref().syscall_result() == trace_regs.syscall_result()
         );
-        if nsys == Arch::MPROTECT {
+        if nsys == Arch::MPROTECT || nsys == Arch::PKEY_MPROTECT {
             let mut r2: Registers = t.regs_ref().clone();
             r2.set_arg1(r.arg1());
             r2.set_arg2(r.arg2());
@@ -778,6 +871,11 @@ fn rep_process_syscall_arch<Arch: Architecture>(
     }
 
     if nsys == Arch::PERF_EVENT_OPEN
+        || nsys == Arch::EVENTFD
+        || nsys == Arch::EVENTFD2
+        || nsys == Arch::SIGNALFD
+        || nsys == Arch::SIGNALFD4
+        || nsys == Arch::TIMERFD_CREATE
         || nsys == Arch::RECVMSG
         || nsys == Arch::RECVMMSG
         || nsys == Arch::SOCKETCALL
@@ -866,7 +964,11 @@ fn rep_process_syscall_arch<Arch: Architecture>(
     }
 
     if nsys == Arch::RDCALL_RELOAD_AUXV {
-        unimplemented!();
+        let tid = t.regs_ref().arg1() as pid_t;
+        let target = t.session().find_task_from_rec_tid(tid);
+        ed_assert!(t, target.is_some(), "No task with recorded tid {}", tid);
+        let target = target.unwrap();
+        target.vm().save_auxv(&**target);
     }
 }
 
@@ -964,7 +1066,7 @@ fn rep_after_enter_syscall_arch<Arch: Architecture>(t: &ReplayTask) {
         t.fd_table().will_write(t, fd);
     }
 
-    if sys == Arch::CLONE || sys == Arch::VFORK || sys == Arch::FORK {
+    if sys == Arch::CLONE || sys == Arch::CLONE3 || sys == Arch::VFORK || sys == Arch::FORK {
         // Create the new task now. It needs to exist before clone/fork/vfork
         // returns so that a ptracer can touch it during PTRACE_EVENT handling.
         prepare_clone::<Arch>(t);
@@ -1015,6 +1117,10 @@ fn rep_after_enter_syscall_arch<Arch: Architecture>(t: &ReplayTask) {
         }
     }
 
+    if sys == Arch::SIGRETURN || sys == Arch::RT_SIGRETURN {
+        will_sigreturn(t, sys);
+    }
+
     if sys == Arch::EXIT {
         // Destroy buffers now to match when we destroyed them during recording.
         // It's possible for another mapping to be created overlapping our
@@ -1215,6 +1321,9 @@ pub fn process_execve(t: &ReplayTask, step: &mut ReplayTraceStep) {
         .exec_fds_to_close
         .clone();
     t.fd_table().close_after_exec(t, &fds_to_close);
+    // The snapshot of an attached process lists the special files it already
+    // had open.
+    handle_opened_files(t, 0);
 
     {
         let arch = t.arch();
@@ -1254,6 +1363,10 @@ pub fn process_execve(t: &ReplayTask, step: &mut ReplayTraceStep) {
     }
 
     let recorded_exe_name: &OsStr = kms[exe_km].fsname();
+    // The scratch memory of the task, then that of each of the other threads
+    // of an attached process, comes last.
+    let attached_threads = tte.attached_threads();
+    let first_scratch = kms.len() - 1 - attached_threads.len();
 
     {
         let arch = t.arch();
@@ -1262,7 +1375,7 @@ pub fn process_execve(t: &ReplayTask, step: &mut ReplayTraceStep) {
         let mut remote = AutoRemoteSyscalls::new(t);
 
         // Now map in all the mappings that we recorded from the real exec.
-        for i in 1..kms.len() - 1 {
+        for i in 1..first_scratch {
             restore_mapped_region(&mut remote, &kms[i], &datas[i]);
         }
 
@@ -1287,14 +1400,40 @@ pub fn process_execve(t: &ReplayTask, step: &mut ReplayTraceStep) {
         );
     }
 
-    init_scratch_memory(t, kms.last().unwrap(), datas.last().unwrap());
+    init_scratch_memory(t, &kms[first_scratch], &datas[first_scratch]);
+
+    if !attached_threads.is_empty() {
+        let threads: Vec<TaskSharedPtr> = {
+            let mut remote = AutoRemoteSyscalls::new(t);
+            attached_threads
+                .iter()
+                .map(|thread| os_clone_attached_thread(&mut remote, thread.tid))
+                .collect()
+        };
+        for (i, (new_task, thread)) in threads.iter().zip(attached_threads).enumerate() {
+            t.session().on_create_task(new_task.clone());
+            let scratch = first_scratch + 1 + i;
+            init_scratch_memory(
+                new_task.as_replay_task().unwrap(),
+                &kms[scratch],
+                &datas[scratch],
+            );
+            new_task.set_regs(&thread.regs);
+            new_task.set_extra_regs(&thread.extra_regs);
+        }
+    }
 
     // Apply final data records --- fixing up the last page in each data segment
     // for zeroing applied by the kernel, and applying monkeypatches.
     t.apply_all_data_records_from_trace();
 
-    // Now it's safe to save the auxv data
-    t.vm().save_auxv(t);
+    // Now it's safe to save the auxv data. The stack of an attached process
+    // can't be walked to find it, so its snapshot carries it instead.
+    if tte.auxv().is_empty() {
+        t.vm().save_auxv(t);
+    } else {
+        t.vm().set_saved_auxv(tte.auxv().to_vec());
+    }
 
     // Notify outer rd if there is one
     unsafe { syscall(SYS_rdcall_reload_auxv as _, t.tid()) };
@@ -1305,11 +1444,21 @@ pub fn restore_mapped_region(
     km: &KernelMapping,
     data: &trace_stream::MappedData,
 ) {
-    ed_assert!(
-        remote.task(),
-        !km.flags().contains(MapFlags::MAP_SHARED),
-        "Shared mappings after exec not supported"
-    );
+    if km.flags().contains(MapFlags::MAP_SHARED) {
+        let attached = remote
+            .task()
+            .as_replay_task()
+            .unwrap()
+            .trace_reader()
+            .attached();
+        ed_assert!(
+            remote.task(),
+            attached,
+            "Shared mappings after exec not supported"
+        );
+        restore_shared_mapped_region(remote, km, data);
+        return;
+    }
 
     let real_file_name;
     let mut device: dev_t = KernelMapping::NO_DEVICE;
@@ -1371,7 +1520,61 @@ pub fn restore_mapped_region(
     );
 }
 
-fn finish_direct_mmap(
+/// Map a shared mapping of the snapshot an attached trace starts with to an
+/// emulated file, as `finish_shared_mmap()` does for shared mmaps. The pages
+/// the process had changed are written by
+/// `ReplayTask::apply_all_data_records_from_trace()`.
+fn restore_shared_mapped_region(
+    remote: &mut AutoRemoteSyscalls,
+    km: &KernelMapping,
+    data: &trace_stream::MappedData,
+) {
+    let emufile: EmuFileSharedPtr = remote
+        .task()
+        .session()
+        .as_replay()
+        .unwrap()
+        .emufs_mut()
+        .get_or_create(km);
+    let offset_pages = km.file_offset_bytes() as usize / page_size();
+    let (real_file, real_file_name) = finish_direct_mmap(
+        remote,
+        km.start(),
+        km.size(),
+        km.prot(),
+        km.flags(),
+        &OsString::from(emufile.borrow().proc_path()),
+        OFlag::O_RDWR,
+        offset_pages,
+    );
+    remote.task().vm().map(
+        remote.task(),
+        km.start(),
+        km.size(),
+        km.prot(),
+        km.flags(),
+        km.file_offset_bytes(),
+        real_file_name.as_os_str(),
+        real_file.st_dev,
+        real_file.st_ino,
+        None,
+        Some(km),
+        Some(emufile),
+        None,
+        None,
+    );
+    if data.source == MappedDataSource::SourceFile {
+        // The emulated file starts out empty.
+        write_mapped_data(
+            remote.task().as_replay_task().unwrap(),
+            km.start(),
+            km.size(),
+            data,
+        );
+    }
+}
+
+pub fn finish_direct_mmap(
     remote: &mut AutoRemoteSyscalls,
     rec_addr: RemotePtr<u8>,
     length: usize,
@@ -1475,6 +1678,20 @@ fn handle_opened_files(t: &ReplayTask, flags_raw: i32) {
             file_monitor = Box::new(ProcMemMonitor::new(t, &o.path));
         } else if is_proc_fd_dir(&o.path) {
             file_monitor = Box::new(ProcFdDirMonitor::new(t, &o.path));
+        } else if is_dev_random(&o.path) {
+            file_monitor = Box::new(RandomFileMonitor::new());
+        } else if let Some(limit) = t
+            .trace_reader()
+            .cgroup_limits()
+            .iter()
+            .rev()
+            .find(|l| l.matches(&o.path))
+        {
+            file_monitor = Box::new(CgroupFileMonitor::new(limit.contents()));
+        } else if is_locale_file(&o.path) {
+            file_monitor = Box::new(LocaleFileMonitor::new(&o.path, &o.backing_file_name));
+        } else if let Some(kind) = AnonFdKind::from_path(&o.path) {
+            file_monitor = Box::new(AnonFdMonitor::new(kind));
         } else if flags.contains(OFlag::O_DIRECT) {
             file_monitor = Box::new(BaseFileMonitor::new())
         } else {
@@ -1953,6 +2170,11 @@ fn process_mremap(t: &ReplayTask, trace_regs: &Registers, step: &mut ReplayTrace
     let new_addr: RemotePtr<Void> = trace_regs.syscall_result().into();
     let new_size: usize = ceil_page_size(trace_regs.arg3());
 
+    if new_size > old_size {
+        // Don't grow a lazy mapping past the end of its staging file.
+        materialize_range(t, old_addr, old_size);
+    }
+
     // The recorded mremap call succeeded, so we know the original mapping can be
     // treated as a single mapping.
     t.vm().ensure_replay_matches_single_recorded_mapping(
@@ -19,13 +19,14 @@ use crate::{
     taskish_uid::{AddressSpaceUid, TaskUid},
     ticks::Ticks,
     trace::trace_frame::FrameTime,
+    util::read_proc_private_memory,
 };
 use nix::sys::mman::ProtFlags;
 use std::{
     cell::{Ref, RefCell},
     cmp::Ordering,
-    collections::BTreeMap,
-    fmt::Display,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::{self, Display},
     io::{stderr, Write},
     mem,
     ops::Bound::{Excluded, Included, Unbounded},
@@ -144,6 +145,11 @@ pub struct ReplayTimeline {
     /// @TODO Lack of a barrier event is indicated with the value being 0
     reverse_execution_barrier_event: FrameTime,
 
+    /// Events at which a task being debugged finished an exec() the debugger
+    /// followed. Reverse execution stops at the beginning of these, while the
+    /// task still has its pre-exec address space.
+    reverse_exec_boundary_events: BTreeSet<FrameTime>,
+
     /// Checkpoints used to accelerate reverse execution.
     reverse_exec_checkpoints: BTreeMap<Mark, Progress>,
 
@@ -156,6 +162,89 @@ pub struct ReplayTimeline {
     /// A single checkpoint that's very close to the current point, used to
     /// accelerate a sequence of reverse singlestep operations.
     reverse_exec_short_checkpoint: Option<Mark>,
+
+    periodic_checkpoint_policy: PeriodicCheckpoints,
+
+    /// Checkpoints taken according to `periodic_checkpoint_policy`.
+    periodic_checkpoints: BTreeMap<Mark, PeriodicCheckpoint>,
+
+    checkpoint_use_clock: u64,
+
+    /// Checkpoints left behind by reverse_singlestep2 at the end of each span
+    /// while bisecting towards a ticks target, oldest first. They make a run
+    /// of reverse singlesteps through a hot loop restart close to the
+    /// destination instead of from a distant reverse-exec checkpoint.
+    reverse_step_marks: VecDeque<Mark>,
+
+    reverse_step_stats: ReverseStepStats,
+}
+
+/// How reverse singlesteps were carried out, so the effect of span bisection
+/// on a workload can be measured. Shown by `rd-perf`.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ReverseStepStats {
+    /// Reverse singlesteps that had to replay.
+    pub reverse_steps: u64,
+    /// Reverse singlesteps answered from the mark map without replaying.
+    pub lazy_reverse_steps: u64,
+    /// Checkpoints restored to seek backwards.
+    pub checkpoint_restores: u64,
+    /// How many of those were marks cached by span bisection.
+    pub cached_mark_restores: u64,
+    /// Spans replayed while bisecting towards a ticks target.
+    pub bisection_spans: u64,
+    /// Ticks replayed with the PMU programmed, i.e. without singlestepping.
+    pub ticks_replayed: u64,
+    /// Singlesteps taken by reverse singlesteps.
+    pub singlesteps: u64,
+}
+
+impl Display for ReverseStepStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "  reverse steps: {} replayed, {} lazy",
+            self.reverse_steps, self.lazy_reverse_steps
+        )?;
+        writeln!(
+            f,
+            "    checkpoints restored: {} ({} cached by bisection)",
+            self.checkpoint_restores, self.cached_mark_restores
+        )?;
+        write!(
+            f,
+            "    bisection spans: {}, ticks replayed: {}, singlesteps: {}",
+            self.bisection_spans, self.ticks_replayed, self.singlesteps
+        )
+    }
+}
+
+/// Checkpoints taken every so many events while the user replays forward, in
+/// addition to the reverse-exec checkpoints below. Unlike those they are kept
+/// when the user moves backwards, so the first reverse-continue after a long
+/// stretch of forward execution doesn't need to replay from the start of the
+/// trace.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct PeriodicCheckpoints {
+    /// Take a checkpoint every this many events. None disables periodic
+    /// checkpoints.
+    pub interval: Option<FrameTime>,
+    /// When the periodic checkpoints together hold more than this many bytes,
+    /// evict the least recently useful ones. None means no limit.
+    pub memory_budget: Option<usize>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct PeriodicCheckpoint {
+    /// The value of `checkpoint_use_clock` when the checkpoint was created or
+    /// last restored, so the smallest value is the least recently useful.
+    last_used: u64,
+    /// What `Mark::checkpoint_memory_cost()` returned for the checkpoint.
+    /// Reading smaps is expensive, so this is measured once, when the next
+    /// periodic checkpoint is taken, and again after the checkpoint has been
+    /// restored. A checkpoint that was just forked shares all its pages, so
+    /// None counts as free.
+    memory_cost: Option<usize>,
 }
 
 impl Drop for ReplayTimeline {
@@ -213,6 +302,15 @@ impl ReplayTimeline {
     /// half.
     const STOP_COUNT_LIMIT: usize = 20;
 
+    /// While replaying towards a reverse singlestep's ticks target, don't
+    /// split the remaining distance into spans shorter than this. Each span
+    /// ends with a checkpoint, which costs about as much as replaying this
+    /// many ticks.
+    const MIN_BISECTION_SPAN_TICKS: Ticks = 100000;
+
+    /// Keep at most this many checkpoints from span bisection.
+    const REVERSE_STEP_MARK_CACHE_SIZE: usize = 8;
+
     pub fn is_running(&self) -> bool {
         self.current.is_some()
     }
@@ -595,6 +693,13 @@ impl ReplayTimeline {
         self.reverse_execution_barrier_event = event;
     }
 
+    /// Make reverse execution stop at the beginning of |event|, an exec(),
+    /// with a |reverse_exec_boundary| break status. Unlike the barrier event,
+    /// reverse execution can then carry on into the pre-exec image.
+    pub fn add_reverse_exec_boundary_event(&mut self, event: FrameTime) {
+        self.reverse_exec_boundary_events.insert(event);
+    }
+
     /// State-changing APIs. These may alter state associated with
     /// current_session().
     /// Reset the current session to the last available session before event
@@ -710,6 +815,7 @@ impl ReplayTimeline {
             result.break_status.singlestep_complete = false;
         }
         self.maybe_add_reverse_exec_checkpoint(CheckpointStrategy::LowOverhead);
+        self.maybe_add_periodic_checkpoint();
 
         let did_hit_breakpoint: bool = result.break_status.hardware_or_software_breakpoint_hit();
         self.evaluate_conditions(&mut result);
@@ -882,6 +988,15 @@ impl ReplayTimeline {
                     final_tuid = Some(final_result.break_status.task.upgrade().unwrap().tuid());
                     final_ticks = Some(result.break_status.task.upgrade().unwrap().tick_count());
                     last_stop_is_watch_or_signal = false;
+                } else if self.is_start_of_reverse_exec_boundary_event() {
+                    maybe_dest = Some(self.mark());
+                    final_result = result.clone();
+                    let t = self.current_session().current_task().unwrap();
+                    final_result.break_status.task = Rc::downgrade(&t);
+                    final_result.break_status.reverse_exec_boundary = true;
+                    final_tuid = Some(t.tuid());
+                    final_ticks = Some(t.tick_count());
+                    last_stop_is_watch_or_signal = false;
                 }
 
                 if self.at_mark(&end) {
@@ -995,17 +1110,86 @@ impl ReplayTimeline {
         stop_filter: &StopFilterFn,
         interrupt_check: &InterruptCheckFn,
     ) -> ReplayResult {
+        self.reverse_step_stats.reverse_steps += 1;
         let m = self.mark();
         self.reverse_singlestep2(&m, tuid, tuid_ticks, stop_filter, interrupt_check)
     }
 
+    pub fn reverse_step_stats(&self) -> &ReverseStepStats {
+        &self.reverse_step_stats
+    }
+
+    pub fn reset_reverse_step_stats(&mut self) {
+        self.reverse_step_stats = ReverseStepStats::default();
+    }
+
+    /// Seek to the point in the current event at which task |tuid| has executed
+    /// exactly |ticks| ticks, going back to the start of the event first if
+    /// the task is already past that. Returns false, leaving the timeline where
+    /// it was, if the task doesn't reach |ticks| during the event.
+    pub fn seek_to_ticks(&mut self, tuid: TaskUid, ticks: Ticks) -> bool {
+        let origin = self.mark();
+        let event = self.current_session().current_trace_frame().time();
+        match self.current_session().find_task_from_task_uid(tuid) {
+            Some(t) if t.tick_count() > ticks => self.seek_to_before_event(event),
+            Some(_) => (),
+            None => return false,
+        }
+
+        self.unapply_breakpoints_and_watchpoints();
+        let mut constraints = StepConstraints::new(RunCommand::RunContinue);
+        loop {
+            let now = self.current_session().current_trace_frame().time();
+            if now > event {
+                break;
+            }
+            let t = self.current_session().current_task().unwrap();
+            let result = if now == event && t.tuid() == tuid {
+                let tick_count = t.tick_count();
+                if tick_count == ticks {
+                    log!(LogDebug, "Seeked to ticks {} at {}", ticks, self.mark());
+                    return true;
+                }
+                if tick_count > ticks {
+                    break;
+                }
+                if constraints.command == RunCommand::RunContinue {
+                    constraints.ticks_target = ticks;
+                }
+                let result = self
+                    .current_session()
+                    .replay_step_with_constraints(&constraints);
+                if result.break_status.approaching_ticks_target {
+                    // Singlestep the rest of the way, as reverse_singlestep does.
+                    constraints = StepConstraints::new(RunCommand::RunSinglestep);
+                }
+                result
+            } else {
+                self.current_session().replay_step(RunCommand::RunContinue)
+            };
+            if result.status == ReplayStatus::ReplayExited {
+                break;
+            }
+        }
+
+        log!(
+            LogDebug,
+            "Task {} doesn't reach ticks {} in event {}",
+            tuid.tid(),
+            ticks,
+            event
+        );
+        self.seek_to_mark(&origin);
+        false
+    }
+
     /// Try to identify an existing Mark which is known to be one singlestep
     /// before 'from', and for which we know singlestepping to 'from' would
     /// trigger no break statuses other than "singlestep_complete".
     /// If we can't, return a None.
     /// Will only return a Mark for the same executing task as 'from', which
     /// must be 't'.
-    pub fn lazy_reverse_singlestep(&self, from: &Mark, t: &ReplayTask) -> Option<Mark> {
+    pub fn lazy_reverse_singlestep(&mut self, from: &Mark, t: &ReplayTask) -> Option<Mark> {
         if self.no_watchpoints_hit_interval_start.is_none()
             || self.no_watchpoints_hit_interval_end.is_none()
         {
@@ -1016,6 +1200,7 @@ impl ReplayTimeline {
             && m < *self.no_watchpoints_hit_interval_end.as_ref().unwrap()
             && !self.has_breakpoint_at_address(t, from.ptr.borrow().proto.regs.ip())
         {
+            self.reverse_step_stats.lazy_reverse_steps += 1;
             return Some(m);
         }
 
@@ -1225,11 +1410,14 @@ impl ReplayTimeline {
                 // We can use the current session, so do nothing.
             } else {
                 // nowhere earlier to go, so restart from beginning.
-                let s = Some(ReplaySession::create(
+                let s = ReplaySession::create(
                     Some(&self.current_session().trace_reader().dir()),
                     *self.current_session().flags(),
-                ));
-                self.current = s;
+                );
+                s.as_replay()
+                    .unwrap()
+                    .share_staging_files(self.current_session());
+                self.current = Some(s);
                 self.breakpoints_applied = false;
                 self.current_at_or_after_mark = None;
             }
@@ -1247,6 +1435,7 @@ impl ReplayTimeline {
             } else {
                 // Return one of the checkpoints at *it.
                 self.current = None;
+                let mut restored = None;
                 self.marks.entry(it).or_insert(Vec::new());
                 for mark_it in &self.marks[&it] {
                     if mark_it.borrow().checkpoint.is_some() {
@@ -1269,10 +1458,22 @@ impl ReplayTimeline {
                         );
                         self.breakpoints_applied = false;
                         self.current_at_or_after_mark = Some(mark_it.clone());
+                        restored = Some(mark_it.clone());
                         break;
                     }
                 }
                 debug_assert!(self.current.is_some());
+                if let Some(mark) = restored {
+                    self.reverse_step_stats.checkpoint_restores += 1;
+                    if self
+                        .reverse_step_marks
+                        .iter()
+                        .any(|m| Rc::ptr_eq(&m.ptr, &mark))
+                    {
+                        self.reverse_step_stats.cached_mark_restores += 1;
+                    }
+                    self.note_checkpoint_used(&mark);
+                }
             }
         }
     }
@@ -1707,6 +1908,17 @@ impl ReplayTimeline {
         true
     }
 
+    fn is_start_of_reverse_exec_boundary_event(&mut self) -> bool {
+        let time = self.current_session().trace_reader().time();
+        if !self.reverse_exec_boundary_events.contains(&time)
+            || self.current_session().current_step_key().in_execution()
+        {
+            return false;
+        }
+        log!(LogDebug, "Found reverse exec boundary at {}", self.mark());
+        true
+    }
+
     /// DIFF NOTE: The rr method is void but here we return a Mark (i.e. now)
     fn update_observable_break_status(&mut self, result: &ReplayResult) -> Mark {
         let now = self.mark();
@@ -1778,36 +1990,66 @@ impl ReplayTimeline {
                 let mut constraints = StepConstraints::new(RunCommand::RunContinue);
                 let mut approaching_ticks_target: bool = false;
                 let mut seen_other_task_break: bool = false;
+                // Rather than one long run up to the ticks target, replay
+                // spans that halve the remaining distance each time and
+                // checkpoint the end of each. Repeated reverse singlesteps
+                // then restart close to where they need to be.
+                let mut span_end: Option<Ticks> = None;
                 while !self.at_mark(&end) {
                     let t = self.current_session().current_task().unwrap();
                     if stop_filter(t.as_replay_task().unwrap())
                         && self.current_session().done_initial_exec()
                     {
                         if t.tuid() == step_tuid {
-                            if t.tick_count() >= ticks_target {
+                            let tick_count = t.tick_count();
+                            if tick_count >= ticks_target {
                                 // Don't step any further.
                                 log!(LogDebug, "Approaching ticks target");
                                 approaching_ticks_target = true;
                                 break;
                             }
                             self.unapply_breakpoints_and_watchpoints();
-                            constraints.ticks_target =
-                                if constraints.command == RunCommand::RunContinue {
-                                    ticks_target
-                                } else {
-                                    0
-                                };
+                            if constraints.command == RunCommand::RunContinue {
+                                if span_end.is_none() {
+                                    span_end = next_bisection_span_end(
+                                        tick_count,
+                                        ticks_target,
+                                        Self::MIN_BISECTION_SPAN_TICKS,
+                                    );
+                                    if span_end.is_some() {
+                                        self.reverse_step_stats.bisection_spans += 1;
+                                    }
+                                }
+                                constraints.ticks_target = span_end.unwrap_or(ticks_target);
+                            } else {
+                                constraints.ticks_target = 0;
+                                self.reverse_step_stats.singlesteps += 1;
+                            }
                             let result: ReplayResult = self
                                 .current_session()
                                 .replay_step_with_constraints(&constraints);
+                            if constraints.command == RunCommand::RunContinue {
+                                self.reverse_step_stats.ticks_replayed +=
+                                    t.tick_count().saturating_sub(tick_count);
+                            }
                             if result.break_status.approaching_ticks_target {
-                                log!(
-                                    LogDebug,
-                                    "   approached ticks target at {}",
-                                    self.current_mark_key()
-                                );
-                                constraints =
-                                    StepConstraints::new(RunCommand::RunSinglestepFastForward);
+                                if span_end.is_some() {
+                                    log!(
+                                        LogDebug,
+                                        "   finished bisection span at {}",
+                                        self.current_mark_key()
+                                    );
+                                    self.cache_reverse_step_mark();
+                                    span_end = None;
+                                } else {
+                                    log!(
+                                        LogDebug,
+                                        "   approached ticks target at {}",
+                                        self.current_mark_key()
+                                    );
+                                    constraints =
+                                        StepConstraints::new(RunCommand::RunSinglestepFastForward);
+                                }
                             }
                         } else {
                             if seen_other_task_break {
@@ -1827,7 +2069,9 @@ impl ReplayTimeline {
                         constraints.ticks_target = 0;
                         self.current_session().replay_step(RunCommand::RunContinue);
                     }
-                    if self.is_start_of_reverse_execution_barrier_event() {
+                    if self.is_start_of_reverse_execution_barrier_event()
+                        || self.is_start_of_reverse_exec_boundary_event()
+                    {
                         seen_barrier = true;
                     }
                     self.maybe_add_reverse_exec_checkpoint(
@@ -1871,6 +2115,13 @@ impl ReplayTimeline {
                 destination_candidate_result.break_status.task_exit = true;
                 destination_candidate_tuid =
                     Some(self.current_session().current_task().unwrap().tuid());
+            } else if self.is_start_of_reverse_exec_boundary_event() {
+                destination_candidate = Some(self.mark());
+                destination_candidate_result
+                    .break_status
+                    .reverse_exec_boundary = true;
+                destination_candidate_tuid =
+                    Some(self.current_session().current_task().unwrap().tuid());
             }
 
             self.no_watchpoints_hit_interval_start = None;
@@ -1893,6 +2144,7 @@ impl ReplayTimeline {
                         constraints
                             .stop_before_states
                             .push(end.ptr.borrow().proto.regs.clone());
+                        self.reverse_step_stats.singlesteps += 1;
                         result = self
                             .current_session()
                             .replay_step_with_constraints(&constraints);
@@ -1963,6 +2215,21 @@ impl ReplayTimeline {
                         Some(self.current_session().current_task().unwrap().tuid());
                     destination_candidate_saw_other_task_break = false;
                     seen_other_task_break = false;
+                } else if self.is_start_of_reverse_exec_boundary_event() {
+                    destination_candidate = Some(self.mark());
+                    log!(
+                        LogDebug,
+                        "Setting candidate to exec boundary {}",
+                        destination_candidate.as_ref().unwrap()
+                    );
+                    destination_candidate_result = result;
+                    destination_candidate_result
+                        .break_status
+                        .reverse_exec_boundary = true;
+                    destination_candidate_tuid =
+                        Some(self.current_session().current_task().unwrap().tuid());
+                    destination_candidate_saw_other_task_break = false;
+                    seen_other_task_break = false;
                 }
 
                 if now >= end {
@@ -2070,6 +2337,82 @@ impl ReplayTimeline {
         self.reverse_exec_checkpoints.insert(m, now);
     }
 
+    pub fn set_periodic_checkpoints(&mut self, policy: PeriodicCheckpoints) {
+        self.periodic_checkpoint_policy = policy;
+    }
+
+    /// Called when the current session has moved forward to a new execution
+    /// point. Adds a checkpoint if there isn't a periodic checkpoint in the
+    /// last `periodic_checkpoint_policy.interval` events.
+    fn maybe_add_periodic_checkpoint(&mut self) {
+        let interval = match self.periodic_checkpoint_policy.interval {
+            Some(interval) if interval > 0 => interval,
+            _ => return,
+        };
+
+        let now = self.current_mark_key().trace_time;
+        let have_recent = self
+            .periodic_checkpoints
+            .keys()
+            .any(|m| m.time() <= now && now - m.time() < interval);
+        if have_recent || !self.current_session().can_clone() {
+            return;
+        }
+
+        for (m, cp) in self.periodic_checkpoints.iter_mut() {
+            if cp.memory_cost.is_none() {
+                cp.memory_cost = m.checkpoint_memory_cost();
+            }
+        }
+
+        let m: Mark = self.add_explicit_checkpoint();
+        log!(LogDebug, "Creating periodic checkpoint at {}", m);
+        self.checkpoint_use_clock += 1;
+        self.periodic_checkpoints.insert(
+            m,
+            PeriodicCheckpoint {
+                last_used: self.checkpoint_use_clock,
+                memory_cost: None,
+            },
+        );
+        self.enforce_periodic_checkpoint_memory_budget();
+    }
+
+    /// Note that the checkpoint of `mark` has just been restored.
+    fn note_checkpoint_used(&mut self, mark: &InternalMarkSharedPtr) {
+        self.checkpoint_use_clock += 1;
+        let clock = self.checkpoint_use_clock;
+        for (m, cp) in self.periodic_checkpoints.iter_mut() {
+            if Rc::ptr_eq(&m.ptr, mark) {
+                cp.last_used = clock;
+                cp.memory_cost = None;
+            }
+        }
+    }
+
+    /// Evict least recently useful periodic checkpoints until they fit in the
+    /// memory budget. The most recently useful checkpoint is always kept.
+    fn enforce_periodic_checkpoint_memory_budget(&mut self) {
+        let budget = match self.periodic_checkpoint_policy.memory_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+
+        let marks: Vec<Mark> = self.periodic_checkpoints.keys().cloned().collect();
+        let checkpoints: Vec<PeriodicCheckpoint> =
+            self.periodic_checkpoints.values().cloned().collect();
+        for i in periodic_checkpoints_to_evict(&checkpoints, budget) {
+            log!(
+                LogDebug,
+                "Evicting periodic checkpoint at {} ({:?} bytes)",
+                marks[i],
+                checkpoints[i].memory_cost
+            );
+            self.remove_explicit_checkpoint(&marks[i]);
+            self.periodic_checkpoints.remove(&marks[i]);
+        }
+    }
+
     fn inter_checkpoint_interval(strategy: CheckpointStrategy) -> Progress {
         if strategy == CheckpointStrategy::LowOverhead {
             Self::LOW_OVERHEAD_INTER_CHECKPOINT_INTERVAL
@@ -2166,6 +2509,23 @@ impl ReplayTimeline {
         }
     }
 
+    /// Checkpoint the end of a bisection span for later reverse singlesteps,
+    /// evicting the oldest such checkpoint if there are too many.
+    fn cache_reverse_step_mark(&mut self) {
+        if !self.can_add_checkpoint() {
+            return;
+        }
+
+        let m: Mark = self.add_explicit_checkpoint();
+        log!(LogDebug, "Caching reverse-step mark at {}", m);
+        self.reverse_step_marks.push_back(m);
+        if self.reverse_step_marks.len() > Self::REVERSE_STEP_MARK_CACHE_SIZE {
+            let old = self.reverse_step_marks.pop_front().unwrap();
+            log!(LogDebug, "Discarding reverse-step mark at {}", old);
+            self.remove_explicit_checkpoint(&old);
+        }
+    }
+
     fn set_short_checkpoint(&mut self) -> Mark {
         if !self.can_add_checkpoint() {
             return self.mark();
@@ -2230,7 +2590,8 @@ impl ReplayTimeline {
             }
         }
 
-        for &i in &to_remove {
+        // Remove from the back so the remaining indices stay valid.
+        for &i in to_remove.iter().rev() {
             result.break_status.watchpoints_hit.remove(i);
         }
     }
@@ -2312,6 +2673,32 @@ impl Mark {
         self.ptr.borrow().proto.key.trace_time
     }
 
+    pub fn ticks(&self) -> Ticks {
+        self.ptr.borrow().proto.key.ticks
+    }
+
+    /// The session cloned at this mark, if it has an explicit checkpoint.
+    pub fn checkpoint_session(&self) -> Option<SessionSharedPtr> {
+        self.ptr.borrow().checkpoint.clone()
+    }
+
+    /// How much memory keeping this mark's checkpoint alive costs: the pages
+    /// that its processes no longer share with any other session because one
+    /// side has written to them since the checkpoint was taken.
+    ///
+    /// Returns None if there is no checkpoint or the cost can't be read.
+    pub fn checkpoint_memory_cost(&self) -> Option<usize> {
+        let session = self.checkpoint_session()?;
+        let mut total = 0;
+        for vm in session.vms() {
+            let maybe_tid = vm.task_set().iter().next().map(|t| t.tid());
+            if let Some(tid) = maybe_tid {
+                total += read_proc_private_memory(tid).ok()?;
+            }
+        }
+        Some(total)
+    }
+
     fn from_internal_mark(ptr: InternalMarkSharedPtr) -> Mark {
         Mark { ptr }
     }
@@ -2517,8 +2904,78 @@ pub enum CheckpointStrategy {
 /// to the current point, in microseconds.
 pub type Progress = i64;
 
+/// Indices of the checkpoints to evict, least recently used first, so that
+/// the rest fit in `budget` bytes. The most recently used one is always kept.
+fn periodic_checkpoints_to_evict(checkpoints: &[PeriodicCheckpoint], budget: usize) -> Vec<usize> {
+    let mut by_age: Vec<usize> = (0..checkpoints.len()).collect();
+    by_age.sort_by_key(|&i| checkpoints[i].last_used);
+    let mut total: usize = checkpoints.iter().filter_map(|cp| cp.memory_cost).sum();
+    let mut evict = Vec::new();
+    for &i in by_age.iter().take(checkpoints.len().saturating_sub(1)) {
+        if total <= budget {
+            break;
+        }
+        total -= checkpoints[i].memory_cost.unwrap_or(0);
+        evict.push(i);
+    }
+    evict
+}
+
+/// Where the next span should end when bisecting the distance from `now` to
+/// `target`, or None if what's left is too short to be worth splitting.
+fn next_bisection_span_end(now: Ticks, target: Ticks, min_span: Ticks) -> Option<Ticks> {
+    let half = target.saturating_sub(now) / 2;
+    if half < min_span {
+        None
+    } else {
+        Some(now + half)
+    }
+}
+
 fn equal_regs(r1: &Registers, r2: &Registers) -> bool {
     // Compare ip()s first since they will usually fail to match, especially
     // when we're comparing InternalMarks with the same MarkKey
     r1.ip() == r2.ip() && r1.matches(r2)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bisection_spans_halve_until_minimum() {
+        let mut now = 0;
+        let mut ends = Vec::new();
+        while let Some(end) = next_bisection_span_end(now, 1000, 100) {
+            ends.push(end);
+            now = end;
+        }
+        assert_eq!(ends, vec![500, 750, 875]);
+        assert_eq!(next_bisection_span_end(1000, 1000, 100), None);
+        assert_eq!(next_bisection_span_end(2000, 1000, 100), None);
+    }
+
+    fn cp(last_used: u64, memory_cost: Option<usize>) -> PeriodicCheckpoint {
+        PeriodicCheckpoint {
+            last_used,
+            memory_cost,
+        }
+    }
+
+    #[test]
+    fn periodic_checkpoints_evicted_least_recently_used_first() {
+        let checkpoints = [cp(3, Some(100)), cp(1, Some(100)), cp(2, Some(100))];
+        assert!(periodic_checkpoints_to_evict(&checkpoints, 300).is_empty());
+        assert_eq!(periodic_checkpoints_to_evict(&checkpoints, 250), vec![1]);
+        assert_eq!(periodic_checkpoints_to_evict(&checkpoints, 150), vec![1, 2]);
+        // The most recently used checkpoint is kept even when over budget.
+        assert_eq!(periodic_checkpoints_to_evict(&checkpoints, 0), vec![1, 2]);
+    }
+
+    #[test]
+    fn unmeasured_periodic_checkpoints_are_free() {
+        let checkpoints = [cp(1, None), cp(2, Some(100)), cp(3, None)];
+        assert!(periodic_checkpoints_to_evict(&checkpoints, 100).is_empty());
+        assert_eq!(periodic_checkpoints_to_evict(&checkpoints, 50), vec![0, 1]);
+    }
+}
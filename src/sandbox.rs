@@ -0,0 +1,198 @@
+//! `rd replay --sandbox`: replay a trace from an untrusted source.
+//!
+//! Replay runs the recorded program again, so a trace received from a third
+//! party is as dangerous as its executables. Before replay starts, rd moves
+//! itself into new user, mount and network namespaces: the only network
+//! interface is the loopback interface (which the debugger uses to talk to
+//! the replay), every mount except /proc, /sys and /dev becomes read-only, and
+//! the temporary file directory is replaced by an empty tmpfs. Since the
+//! namespaces are entered before rd forks the debugger, the debugger is
+//! sandboxed too.
+//!
+//! The sandbox doesn't stop the tracee from reading files, only from changing
+//! them; it is not a substitute for replaying in a virtual machine when the
+//! trace might also be used to exfiltrate data.
+
+use crate::util::tmp_dir;
+use nix::{
+    mount::{mount, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::statvfs::{statvfs, FsFlags},
+    unistd::{getgid, getuid},
+};
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io, mem,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+};
+
+/// Mounts under these are left writable. The kernel doesn't allow remounting
+/// most of them in a user namespace, and replay needs /dev/shm and /proc/<pid>.
+const WRITABLE_MOUNT_PREFIXES: [&str; 3] = ["/proc", "/sys", "/dev"];
+
+/// Enter the replay sandbox (see the module docs) to replay the trace in
+/// `trace_dir`. Must be called before rd starts any other threads or processes.
+pub fn enter_replay_sandbox(trace_dir: &OsStr) -> io::Result<()> {
+    // The tmpfs would hide a trace in the temporary file directory.
+    let tmp = tmp_dir();
+    if fs::canonicalize(trace_dir)?.starts_with(fs::canonicalize(&tmp)?) {
+        return Err(sandbox_error(format!(
+            "Trace {:?} is in the temporary file directory {:?}, which is replaced in the \
+             sandbox. Move the trace or set RD_TMPDIR to another directory.",
+            trace_dir, tmp
+        )));
+    }
+
+    let uid = getuid();
+    let gid = getgid();
+    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWNET)
+        .map_err(|e| {
+            sandbox_error(format!(
+                "Unable to create namespaces: {}. Are unprivileged user namespaces \
+                 enabled (sysctl kernel.unprivileged_userns_clone)?",
+                e
+            ))
+        })?;
+    // We stay the same user inside the namespace so the trace stays readable.
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))?;
+    fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))?;
+
+    bring_up_loopback()?;
+
+    // Don't let our mounts propagate back to the parent namespace.
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .map_err(|e| sandbox_error(format!("Unable to make mounts private: {}", e)))?;
+    for mount_point in mount_points()? {
+        if WRITABLE_MOUNT_PREFIXES
+            .iter()
+            .any(|prefix| mount_point.starts_with(prefix))
+        {
+            continue;
+        }
+        remount_read_only(&mount_point)?;
+    }
+
+    mount(
+        Some("tmpfs"),
+        tmp.as_os_str(),
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("mode=0700"),
+    )
+    .map_err(|e| sandbox_error(format!("Unable to mount a tmpfs on {:?}: {}", tmp, e)))
+}
+
+/// A new network namespace has only a loopback interface, and it is down.
+fn bring_up_loopback() -> io::Result<()> {
+    #[repr(C)]
+    struct IfReq {
+        name: [libc::c_char; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        // The rest of the kernel's union of request types.
+        pad: [u8; 22],
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut req: IfReq = unsafe { mem::zeroed() };
+    req.name[0] = b'l' as libc::c_char;
+    req.name[1] = b'o' as libc::c_char;
+    let mut ret = unsafe { libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut req) };
+    if ret == 0 {
+        req.flags |= libc::IFF_UP as libc::c_short;
+        ret = unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS, &req) };
+    }
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if ret < 0 {
+        return Err(sandbox_error(format!(
+            "Unable to bring up the loopback interface: {}",
+            err
+        )));
+    }
+    Ok(())
+}
+
+/// Every mount point, parents before children.
+fn mount_points() -> io::Result<Vec<PathBuf>> {
+    let mountinfo = fs::read("/proc/self/mountinfo")?;
+    let mut points = Vec::new();
+    for line in mountinfo.split(|&c| c == b'\n') {
+        // "<id> <parent id> <major:minor> <root> <mount point> ..."
+        if let Some(point) = line.split(|&c| c == b' ').nth(4) {
+            points.push(PathBuf::from(OsString::from_vec(unescape_octal(point))));
+        }
+    }
+    Ok(points)
+}
+
+/// mountinfo escapes spaces, tabs, newlines and backslashes as `\ooo`.
+fn unescape_octal(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == b'\\'
+            && i + 3 < s.len()
+            && s[i + 1..i + 4].iter().all(|c| (b'0'..=b'7').contains(c))
+        {
+            out.push((s[i + 1] - b'0') * 64 + (s[i + 2] - b'0') * 8 + (s[i + 3] - b'0'));
+            i += 4;
+        } else {
+            out.push(s[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+fn remount_read_only(mount_point: &Path) -> io::Result<()> {
+    // Mounts inherited from the parent namespace are locked: a remount has to
+    // keep their nosuid, nodev, noexec and atime flags or it is refused.
+    let fs_flags = match statvfs(mount_point) {
+        Ok(s) => s.flags(),
+        // Mounts we can't look at (e.g. another user's FUSE mount) we can't
+        // write to either.
+        Err(_) => return Ok(()),
+    };
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+    for &(fs_flag, ms_flag) in &[
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if fs_flags.contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    mount(None::<&str>, mount_point, None::<&str>, flags, None::<&str>)
+        .map_err(|e| sandbox_error(format!("Unable to make {:?} read-only: {}", mount_point, e)))
+}
+
+fn sandbox_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_mountinfo_paths() {
+        assert_eq!(unescape_octal(b"/mnt/a\\040b"), b"/mnt/a b");
+        assert_eq!(unescape_octal(b"/mnt/a\\134"), b"/mnt/a\\");
+        assert_eq!(unescape_octal(b"/mnt/a\\04"), b"/mnt/a\\04");
+    }
+}
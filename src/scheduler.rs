@@ -42,6 +42,7 @@ use crate::{
     event::{EventType, Switchable, SyscallState},
     kernel_abi::{is_exit_group_syscall, is_exit_syscall, is_sched_yield_syscall, SupportedArch},
     log::{LogDebug, LogWarn},
+    perf_counters::pmu_disabled,
     priority_tup::PriorityTup,
     session::{
         record_session::RecordSession,
@@ -69,7 +70,10 @@ use std::{
     cell::{Cell, RefCell},
     cmp::min,
     collections::{BTreeSet, VecDeque},
-    mem, ptr,
+    fmt::{self, Display, Formatter},
+    mem,
+    ops::Bound::{Excluded, Included},
+    ptr,
     rc::{Rc, Weak},
 };
 
@@ -123,6 +127,10 @@ const HIGH_PRIORITY_ONLY_DURATION_STEP_FACTOR: f64 = 2.0;
 /// Allow this much of overall runtime to be in the "high priority only" interval
 const HIGH_PRIORITY_ONLY_FRACTION: f64 = 0.2;
 
+/// With --no-pmu, how long a task may run without stopping while other tasks
+/// wait to be scheduled before we assume it is spinning on them.
+const NO_PMU_SPIN_TIMEOUT_SECS: f64 = 10.0;
+
 /// Tasks sorted by priority.
 type TaskPrioritySet = BTreeSet<PriorityTup>;
 type TaskQueue = VecDeque<TaskSharedWeakPtr>;
@@ -146,6 +154,9 @@ pub struct Scheduler {
     /// task has been destroyed.
     current_: RefCell<Option<TaskSharedWeakPtr>>,
     current_timeslice_end_: Cell<Ticks>,
+    /// The tid of the current task if it was destroyed, until we switch to
+    /// another task.
+    exited_current_tid: Cell<Option<pid_t>>,
 
     /// At this time (or later) we should refresh these values.
     high_priority_only_intervals_refresh_time: Cell<f64>,
@@ -178,6 +189,11 @@ pub struct Scheduler {
     last_reschedule_in_high_priority_only_interval: Cell<bool>,
 }
 
+/// The rough number of ticks per millisecond of tracee execution that
+/// `DefaultMaxTicks` is derived from. Used to turn `--switch-interval` into a
+/// tick budget.
+pub const TICKS_PER_MS: f64 = 50_000.0;
+
 #[repr(u64)]
 pub enum TicksHowMany {
     /// Like most task schedulers, there are conflicting goals to balance. Lower
@@ -215,6 +231,56 @@ pub struct Rescheduled {
     pub started_new_timeslice: bool,
 }
 
+/// Why the scheduler switched away from a task.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SwitchReason {
+    /// Its timeslice expired.
+    TimesliceExpired,
+    /// A runnable task with a higher priority was waiting.
+    HigherPriority,
+    /// It yielded and another task was at the head of the round-robin queue.
+    Yielded,
+    /// It wasn't runnable, e.g. because it blocked in a syscall.
+    Blocked,
+    /// It exited.
+    Exited,
+    /// It was in an unstable exit.
+    Unstable,
+    /// `rd record --always-switch`.
+    AlwaysSwitch,
+    /// Chaos mode was running only high-priority tasks.
+    HighPriorityOnly,
+    /// Another threadgroup was in an execve.
+    Execve,
+}
+
+impl Display for SwitchReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let disp = match self {
+            SwitchReason::TimesliceExpired => "preempted (tick limit)",
+            SwitchReason::HigherPriority => "preempted (higher priority task)",
+            SwitchReason::Yielded => "yielded",
+            SwitchReason::Blocked => "blocked",
+            SwitchReason::Exited => "exited",
+            SwitchReason::Unstable => "unstable exit",
+            SwitchReason::AlwaysSwitch => "always-switch",
+            SwitchReason::HighPriorityOnly => "preempted (high-priority-only interval)",
+            SwitchReason::Execve => "waiting for execve",
+        };
+
+        write!(f, "{}", disp)
+    }
+}
+
+/// A switch from one task to another. The scheduler hands every switch to
+/// the trace writer, which stores it in the next frame.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TaskSwitch {
+    pub from_tid: pid_t,
+    pub to_tid: pid_t,
+    pub reason: SwitchReason,
+}
+
 impl Scheduler {
     /// DIFF This constructor does NOT call regenerate_affinity_mask() like in rr.
     pub fn new(max_ticks: Ticks, always_switch: bool) -> Scheduler {
@@ -224,6 +290,7 @@ impl Scheduler {
             task_round_robin_queue: Default::default(),
             current_: Default::default(),
             current_timeslice_end_: Default::default(),
+            exited_current_tid: Default::default(),
             high_priority_only_intervals_refresh_time: Default::default(),
             high_priority_only_intervals_start: Default::default(),
             high_priority_only_intervals_duration: Default::default(),
@@ -275,8 +342,11 @@ impl Scheduler {
         self.enable_chaos.set(enable_chaos);
     }
 
+    /// Pretend to have `num_cores` cores. The affinity mask reported to
+    /// tracees is regenerated to match.
     pub fn set_num_cores(&self, num_cores: u32) {
         self.pretend_num_cores_.set(num_cores);
+        self.regenerate_affinity_mask();
     }
 
     /// Schedule a new runnable task (which may be the same as current()).
@@ -308,7 +378,11 @@ impl Scheduler {
                     log!(LogDebug, "  and running; waiting for state change");
                     // |current| is un-switchable, but already running. Wait for it to change
                     // state before "scheduling it", so avoid busy-waiting with our client
-                    curr.wait(Some(self.interrupt_after_elapsed_time()));
+                    if pmu_disabled() {
+                        self.wait_without_pmu(&curr);
+                    } else {
+                        curr.wait(Some(self.interrupt_after_elapsed_time()));
+                    }
                     // @TODO Monitor unswitchable waits stuff
                     result.by_waitpid = true;
                     log!(LogDebug, "  new status is {}", curr.status());
@@ -321,6 +395,12 @@ impl Scheduler {
         }
 
         let mut maybe_next: Option<TaskSharedPtr> = None;
+        // Why current_ isn't run again, if it isn't.
+        let mut switch_reason = if self.in_exec_tgid.get().is_some() {
+            SwitchReason::Execve
+        } else {
+            SwitchReason::Blocked
+        };
         // While a threadgroup is in execve, treat all tasks as blocked.
         while self.in_exec_tgid.get().is_none() {
             self.maybe_reset_high_priority_only_intervals(now);
@@ -340,6 +420,7 @@ impl Scheduler {
 
                         if maybe_next.is_some() {
                             // There is a runnable higher-priority task (different from current btw). Run it.
+                            switch_reason = SwitchReason::HigherPriority;
                             break;
                         }
                     }
@@ -351,22 +432,35 @@ impl Scheduler {
                     // rejected current_ and popped it in a previous iteration of this loop)
                     // -- it must be runnable, and not in an unstable exit.
                     let tick_count = curr.tick_count();
-                    let is_unstable = curr.unstable.get();
-                    if !is_unstable
-                        && !self.always_switch.get()
-                        && (round_robin_task.is_none()
-                            || Rc::ptr_eq(round_robin_task.as_ref().unwrap(), &curr))
-                        && (self.treat_as_high_priority(&curr)
-                            || !self.last_reschedule_in_high_priority_only_interval.get())
-                        && tick_count < self.current_timeslice_end()
-                        && self.is_task_runnable(
-                            curr.as_record_task().unwrap(),
-                            &mut result.by_waitpid,
-                        )
+                    let rejected = if curr.unstable.get() {
+                        Some(SwitchReason::Unstable)
+                    } else if self.always_switch.get() {
+                        Some(SwitchReason::AlwaysSwitch)
+                    } else if round_robin_task
+                        .as_ref()
+                        .map_or(false, |rr| !Rc::ptr_eq(rr, &curr))
+                    {
+                        Some(SwitchReason::Yielded)
+                    } else if !self.treat_as_high_priority(&curr)
+                        && self.last_reschedule_in_high_priority_only_interval.get()
                     {
-                        log!(LogDebug, "  Carrying on with task {}", curr.tid());
-                        self.validate_scheduled_task();
-                        return result;
+                        Some(SwitchReason::HighPriorityOnly)
+                    } else if tick_count >= self.current_timeslice_end() {
+                        Some(SwitchReason::TimesliceExpired)
+                    } else if !self
+                        .is_task_runnable(curr.as_record_task().unwrap(), &mut result.by_waitpid)
+                    {
+                        Some(SwitchReason::Blocked)
+                    } else {
+                        None
+                    };
+                    match rejected {
+                        Some(reason) => switch_reason = reason,
+                        None => {
+                            log!(LogDebug, "  Carrying on with task {}", curr.tid());
+                            self.validate_scheduled_task();
+                            return result;
+                        }
                     }
                     // Having rejected current_, be prepared to run the next task in the
                     // round-robin queue.
@@ -556,19 +650,37 @@ impl Scheduler {
         }
 
         let nt = maybe_next.unwrap();
-        match self.current() {
-            Some(curr) if !Rc::ptr_eq(&curr, &nt) => log!(
-                LogDebug,
-                "Switching from {} ({:?}) to {} ({:?}) (priority {} to {}) at {}",
-                curr.tid(),
-                curr.name(),
-                nt.tid(),
-                nt.name(),
-                curr.as_record_task().unwrap().priority.get(),
-                nt.as_record_task().unwrap().priority.get(),
-                curr.as_record_task().unwrap().trace_writer().time()
-            ),
-            _ => (),
+        let switched_from = match self.current() {
+            Some(curr) if !Rc::ptr_eq(&curr, &nt) => {
+                log!(
+                    LogDebug,
+                    "Switching from {} ({:?}) to {} ({:?}) (priority {} to {}, {}) at {}",
+                    curr.tid(),
+                    curr.name(),
+                    nt.tid(),
+                    nt.name(),
+                    curr.as_record_task().unwrap().priority.get(),
+                    nt.as_record_task().unwrap().priority.get(),
+                    switch_reason,
+                    curr.as_record_task().unwrap().trace_writer().time()
+                );
+                Some((curr.tid(), switch_reason))
+            }
+            Some(_) => None,
+            None => self
+                .exited_current_tid
+                .get()
+                .map(|tid| (tid, SwitchReason::Exited)),
+        };
+        self.exited_current_tid.set(None);
+        if let Some((from_tid, reason)) = switched_from {
+            self.record_session()
+                .trace_writer_mut()
+                .record_switch(TaskSwitch {
+                    from_tid,
+                    to_tid: nt.tid(),
+                    reason,
+                });
         }
 
         self.maybe_reset_high_priority_only_intervals(now);
@@ -641,7 +753,10 @@ impl Scheduler {
         let weak = t.weak_self_clone();
         let maybe_curr = self.current_.borrow().clone();
         match maybe_curr {
-            Some(curr) if curr.ptr_eq(&weak) => *self.current_.borrow_mut() = None,
+            Some(curr) if curr.ptr_eq(&weak) => {
+                *self.current_.borrow_mut() = None;
+                self.exited_current_tid.set(Some(t.tid()));
+            }
             _ => (),
         }
 
@@ -696,6 +811,47 @@ impl Scheduler {
         self.current_timeslice_end_.set(0);
     }
 
+    /// Without ticks we couldn't replay the point at which a PTRACE_INTERRUPT
+    /// stopped `curr`, so it can only be switched away from when it stops by
+    /// itself. If it runs for a long time while other tasks are waiting to be
+    /// scheduled it is probably spinning on one of them, and would do so
+    /// forever: fail the recording instead of hanging.
+    fn wait_without_pmu(&self, curr: &TaskSharedPtr) {
+        let start = monotonic_now_sec();
+        let mut delay = 0.00001;
+        while !curr.try_wait() {
+            if monotonic_now_sec() - start > NO_PMU_SPIN_TIMEOUT_SECS
+                && self.other_task_waiting_to_run(curr)
+            {
+                clean_fatal!(
+                    "Task {} ran for more than {} seconds without a system call while other\n\
+                     tasks were waiting to run. Without perf counters (--no-pmu) rd can't\n\
+                     preempt it, so it is probably spinning on one of them. Record without\n\
+                     --no-pmu to record this program.",
+                    curr.tid(),
+                    NO_PMU_SPIN_TIMEOUT_SECS
+                );
+            }
+            sleep_time(delay);
+            delay = (delay * 2.0).min(0.01);
+        }
+    }
+
+    /// Is there a task other than `curr` that could run if we switched to it?
+    fn other_task_waiting_to_run(&self, curr: &TaskSharedPtr) -> bool {
+        let task_priority_set = self.task_priority_set.borrow();
+        let round_robin_queue = self.task_round_robin_queue.borrow();
+        task_priority_set
+            .iter()
+            .map(|p| &p.2)
+            .chain(round_robin_queue.iter())
+            .filter_map(|w| w.upgrade())
+            .any(|t| {
+                let rt = t.as_record_task().unwrap();
+                !Rc::ptr_eq(&t, curr) && !rt.unstable.get() && !rt.may_be_blocked()
+            })
+    }
+
     pub fn interrupt_after_elapsed_time(&self) -> f64 {
         // Where does the 3 seconds come from?  No especially
         // good reason.  We want this to be pretty high,
@@ -797,28 +953,39 @@ impl Scheduler {
                 let same_priority_range = task_priority_setb.range(start..end);
 
                 if !self.enable_chaos.get() {
-                    let same_priority_vec = match maybe_t {
-                        Some(t)
-                            if t.as_record_task().unwrap().priority.get() == priority
-                                && task_priority_setb.contains(&PriorityTup(
-                                    priority,
-                                    t.stable_serial(),
-                                    t.weak_self_clone(),
-                                )) =>
-                        {
-                            let (lte, gt): (Vec<&PriorityTup>, Vec<&PriorityTup>) =
-                                same_priority_range.partition(|p| {
-                                    // Its not important to exactly specify the weak ptr as its
-                                    // ignored anyways in the cmp
-                                    **p <= PriorityTup(priority, t.stable_serial(), Weak::new())
-                                });
-
-                            gt.iter().chain(lte.iter()).cloned().cloned().collect()
-                        }
-                        _ => same_priority_range.cloned().collect::<Vec<PriorityTup>>(),
-                    };
+                    // Start after `maybe_t` and wrap around. Iterate the set
+                    // directly rather than copying the range: with thousands of
+                    // threads (e.g. Go programs) this runs on every reschedule.
+                    // DIFF NOTE: rr copies the range.
+                    let same_priority_tasks: Box<dyn Iterator<Item = &PriorityTup> + '_> =
+                        match maybe_t {
+                            Some(t)
+                                if t.as_record_task().unwrap().priority.get() == priority
+                                    && task_priority_setb.contains(&PriorityTup(
+                                        priority,
+                                        t.stable_serial(),
+                                        t.weak_self_clone(),
+                                    )) =>
+                            {
+                                // Its not important to exactly specify the weak ptr as its
+                                // ignored anyways in the cmp
+                                let pivot = PriorityTup(priority, t.stable_serial(), Weak::new());
+                                Box::new(
+                                    task_priority_setb
+                                        .range((
+                                            Excluded(pivot.clone()),
+                                            Excluded(PriorityTup(priority + 1, 0, Weak::new())),
+                                        ))
+                                        .chain(task_priority_setb.range((
+                                            Included(PriorityTup(priority, 0, Weak::new())),
+                                            Included(pivot),
+                                        ))),
+                                )
+                            }
+                            _ => Box::new(same_priority_range),
+                        };
 
-                    for PriorityTup(_, _, task_weak) in same_priority_vec {
+                    for PriorityTup(_, _, task_weak) in same_priority_tasks {
                         if self.is_task_runnable(
                             task_weak.upgrade().unwrap().as_record_task().unwrap(),
                             by_waitpid,
@@ -191,7 +191,8 @@ pub mod address_space {
         emu_fs::EmuFileSharedPtr,
         kernel_abi::{
             syscall_instruction, syscall_number_for_brk, syscall_number_for_close,
-            syscall_number_for_munmap, syscall_number_for_openat, SupportedArch,
+            syscall_number_for_mprotect, syscall_number_for_munmap, syscall_number_for_openat,
+            SupportedArch,
         },
         log::LogLevel::LogDebug,
         monitored_shared_memory::MonitoredSharedMemorySharedPtr,
@@ -208,7 +209,7 @@ pub mod address_space {
                 kernel_map_iterator::KernelMapIterator,
                 kernel_mapping::KernelMapping,
                 memory_range::{MemoryRange, MemoryRangeKey},
-                BreakpointType::BkptNone,
+                BreakpointType::{BkptInternal, BkptNone},
                 MappingFlags,
             },
             task::{
@@ -219,6 +220,7 @@ pub mod address_space {
             },
             SessionSharedPtr, SessionSharedWeakPtr,
         },
+        stap_sdt::SdtProbe,
         taskish_uid::{AddressSpaceUid, TaskUid},
         trace::trace_frame::FrameTime,
         util::{ceil_page_size, floor_page_size, page_size, read_auxv, uses_invisible_guard_page},
@@ -229,6 +231,7 @@ pub mod address_space {
         PROT_GROWSDOWN, PROT_GROWSUP,
     };
     use nix::{fcntl::OFlag, sys::mman::munmap, unistd::getpid};
+    use rand::random;
     use std::{
         cell::{Cell, Ref, RefCell, RefMut},
         cmp::{max, min},
@@ -238,6 +241,7 @@ pub mod address_space {
             BTreeMap, HashMap, HashSet,
         },
         ffi::{OsStr, OsString},
+        mem,
         ops::{
             Bound::{self, Included, Unbounded},
             Drop,
@@ -247,6 +251,27 @@ pub mod address_space {
         sync::atomic::{AtomicUsize, Ordering},
     };
 
+    /// Chaos mode never hands out addresses below this, well clear of
+    /// vm.mmap_min_addr and of the traditional non-PIE executable load address.
+    const CHAOS_MODE_ADDR_SPACE_START: usize = 0x40000;
+
+    /// The range a mapping may occupy once stacks have grown to the size chaos
+    /// mode guarantees them.
+    fn adjust_range_for_stack_growth(km: &KernelMapping) -> MemoryRange {
+        let mut start = km.start();
+        if km.flags().contains(MapFlags::MAP_GROWSDOWN) {
+            start = min(
+                start,
+                RemotePtr::new(
+                    km.end()
+                        .as_usize()
+                        .saturating_sub(AddressSpace::chaos_mode_min_stack_size()),
+                ),
+            );
+        }
+        MemoryRange::from_range(start, km.end())
+    }
+
     fn find_offset_of_syscall_instruction_in(arch: SupportedArch, vdso: &[u8]) -> Option<usize> {
         let instruction = syscall_instruction(arch);
         let instruction_size = instruction.len();
@@ -509,8 +534,14 @@ pub mod address_space {
         /// @TODO might we want to have some of these as Option types?
         pub debug_regs_for_exec_read: Vec<u8>,
         pub value_bytes: Vec<u8>,
+        /// What `value_bytes` was when consume_watchpoint_values() was last
+        /// called.
+        pub old_value_bytes: Vec<u8>,
         pub valid: bool,
         pub changed: bool,
+        /// Too big for the debug registers, so implemented by protecting the
+        /// pages it covers instead. See `software_watch_pages`.
+        pub software: bool,
     }
 
     impl Watchpoint {
@@ -522,8 +553,10 @@ pub mod address_space {
                 // @TODO is this default what we really need?
                 debug_regs_for_exec_read: Vec::new(),
                 value_bytes: vec![0u8; num_bytes],
+                old_value_bytes: vec![0u8; num_bytes],
                 valid: false,
                 changed: false,
+                software: false,
             }
         }
         pub fn watch(&mut self, which: RwxBits) {
@@ -601,6 +634,10 @@ pub mod address_space {
         task_set: RefCell<WeakTaskPtrSet>,
         /// All breakpoints set in this VM.
         breakpoints: RefCell<BreakpointMap>,
+        /// The SDT probe sites `rd record --stap-sdt` planted an internal
+        /// breakpoint at.
+        /// DIFF NOTE: Not present in rr.
+        sdt_probes: RefCell<HashMap<RemoteCodePtr, Rc<SdtProbe>>>,
         /// Path of the real executable image this address space was
         /// exec()'d with.
         exe: OsString,
@@ -637,6 +674,10 @@ pub mod address_space {
         /// behalf of debuggers that assume that model.
         watchpoints: RefCell<HashMap<MemoryRange, Watchpoint>>,
         saved_watchpoints: RefCell<Vec<HashMap<MemoryRange, Watchpoint>>>,
+        /// Pages covered by software watchpoints, and the protection we gave
+        /// them in the tracee so that watched accesses fault. Our Mappings
+        /// keep the protection the tracee asked for.
+        software_watch_pages: RefCell<BTreeMap<RemotePtr<Void>, ProtFlags>>,
         /// Tracee memory is read and written through this fd, which is
         /// opened for the tracee's magic /proc/{tid}/mem device.  The
         /// advantage of this over ptrace is that we can access it even
@@ -676,9 +717,7 @@ pub mod address_space {
         /// it is safe to perform remote syscalls.
         pub fn post_exec_syscall(&self, t: &dyn Task) {
             // First locate a syscall instruction we can use for remote syscalls.
-            self.traced_syscall_ip_
-                .set(self.find_syscall_instruction(t));
-            self.privileged_traced_syscall_ip_.set(None);
+            self.init_traced_syscall_ip(t);
             // Now remote syscalls work, we can open_mem_fd.
             t.open_mem_fd();
 
@@ -700,6 +739,14 @@ pub mod address_space {
             *flags |= MappingFlags::IS_THREAD_LOCALS;
         }
 
+        /// Make remote syscalls go through the syscall instruction in the VDSO.
+        /// The rd page isn't mapped yet.
+        pub fn init_traced_syscall_ip(&self, t: &dyn Task) {
+            self.traced_syscall_ip_
+                .set(self.find_syscall_instruction(t));
+            self.privileged_traced_syscall_ip_.set(None);
+        }
+
         /// Change the program data break of this address space to
         /// `addr`. Only called during recording!
         pub fn brk(&self, t: &dyn Task, addr: RemotePtr<Void>, prot: ProtFlags) {
@@ -946,6 +993,7 @@ pub mod address_space {
                 local_addr,
                 monitored,
             );
+            self.software_watch_range_changed(MemoryRange::new_range(addr, num_bytes));
 
             m
         }
@@ -1189,6 +1237,10 @@ pub mod address_space {
                 }
                 None => (),
             }
+            self.software_watch_range_changed(MemoryRange::new_range(
+                floor_page_size(addr),
+                ceil_page_size(addr + num_bytes) - floor_page_size(addr),
+            ));
         }
 
         /// Fix up mprotect registers parameters to take account of PROT_GROWSDOWN.
@@ -1298,6 +1350,8 @@ pub mod address_space {
                 None,
                 None,
             );
+            self.software_watch_range_changed(MemoryRange::new_range(old_addr, old_num_bytes));
+            self.software_watch_range_changed(MemoryRange::new_range(new_addr, new_num_bytes));
         }
 
         /// Notify that data was written to this address space by rd or
@@ -1366,6 +1420,22 @@ pub mod address_space {
                 self.destroy_breakpoint_at(addr);
             }
         }
+        /// Plant an internal breakpoint at the SDT probe site `addr`, unless
+        /// there is one already.
+        pub fn add_sdt_probe(&self, addr: RemoteCodePtr, probe: Rc<SdtProbe>) {
+            if self.sdt_probes.borrow().contains_key(&addr) {
+                return;
+            }
+            if self.add_breakpoint(addr, BkptInternal) {
+                self.sdt_probes.borrow_mut().insert(addr, probe);
+            }
+        }
+
+        /// The SDT probe whose breakpoint is at `addr`, if any.
+        pub fn sdt_probe_at(&self, addr: RemoteCodePtr) -> Option<Rc<SdtProbe>> {
+            self.sdt_probes.borrow().get(&addr).cloned()
+        }
+
         /// Destroy all breakpoints in this VM, regardless of their
         /// reference counts.
         pub fn remove_all_breakpoints(&self) {
@@ -1431,6 +1501,28 @@ pub mod address_space {
                 .unwrap()
                 .watch(Self::access_bits_of(type_));
 
+            if self.allocate_watchpoints() {
+                return true;
+            }
+
+            // DIFF NOTE: rr fails here when the watchpoints don't fit in the
+            // debug registers. During replay we can instead protect the pages
+            // of the range and check each access that faults; see
+            // ReplaySession::handle_software_watchpoint_fault(). Execution
+            // watchpoints always fit in a debug register so aren't handled.
+            {
+                let mut wps = self.watchpoints.borrow_mut();
+                let wp = wps.get_mut(&range).unwrap();
+                if type_ == WatchType::WatchExec
+                    || wp.exec_count > 0
+                    || !self.session().is_replaying()
+                {
+                    return false;
+                }
+                wp.software = true;
+            }
+            log!(LogDebug, "Using a software watchpoint for {}", range);
+            self.update_software_watch_protection();
             self.allocate_watchpoints()
         }
 
@@ -1445,11 +1537,13 @@ pub mod address_space {
             if remove_r {
                 self.watchpoints.borrow_mut().remove(&r);
             }
+            self.update_software_watch_protection();
             self.allocate_watchpoints();
         }
 
         pub fn remove_all_watchpoints(&self) {
             self.watchpoints.borrow_mut().clear();
+            self.update_software_watch_protection();
             self.allocate_watchpoints();
         }
         pub fn all_watchpoints(&self) -> Vec<WatchConfig> {
@@ -1467,6 +1561,7 @@ pub mod address_space {
         pub fn restore_watchpoints(&self) -> bool {
             debug_assert!(!self.saved_watchpoints.borrow().is_empty());
             *self.watchpoints.borrow_mut() = self.saved_watchpoints.borrow_mut().pop().unwrap();
+            self.update_software_watch_protection();
             self.allocate_watchpoints()
         }
 
@@ -1508,7 +1603,8 @@ pub mod address_space {
             let mut for_update_watchpoint: Vec<MemoryRange> = Vec::new();
             for (range, w) in self.watchpoints.borrow().iter() {
                 let watched_bits = w.watched_bits();
-                if watched_bits.contains(RwxBits::WRITE_BIT) {
+                // Software watchpoints are checked when their pages fault
+                if watched_bits.contains(RwxBits::WRITE_BIT) && !w.software {
                     for_update_watchpoint.push(*range);
                 }
             }
@@ -1521,6 +1617,58 @@ pub mod address_space {
             triggered
         }
 
+        /// Is `addr` in a page we protected for a software watchpoint?
+        pub fn is_software_watch_page(&self, addr: RemotePtr<Void>) -> bool {
+            self.software_watch_pages
+                .borrow()
+                .contains_key(&floor_page_size(addr))
+        }
+
+        /// Temporarily give the pages of software watchpoints back their own
+        /// protection (`protect` false), e.g. so the kernel can access them
+        /// during a syscall we execute, or protect them again (`protect` true).
+        pub fn set_software_watch_protection(&self, t: &dyn Task, protect: bool) {
+            self.set_software_watch_protection_in(t, None, protect);
+        }
+
+        /// Like `set_software_watch_protection`, but only for the pages an
+        /// instruction that faulted at `addr` can access, so that it can be
+        /// stepped over.
+        pub fn set_software_watch_protection_at(
+            &self,
+            t: &dyn Task,
+            addr: RemotePtr<Void>,
+            protect: bool,
+        ) {
+            self.set_software_watch_protection_in(t, Some(software_watch_window(addr)), protect);
+        }
+
+        /// An instruction that faulted at `addr`, in a software watchpoint
+        /// page, has just been stepped over. Recheck the software watchpoints
+        /// it could have touched. Returns true if any of them triggered.
+        pub fn notify_software_watch_fault(&self, addr: RemotePtr<Void>) -> bool {
+            let window = software_watch_window(addr);
+            let mut triggered = false;
+            let mut for_update_watchpoint: Vec<MemoryRange> = Vec::new();
+            for (range, w) in self.watchpoints.borrow_mut().iter_mut() {
+                if !w.software {
+                    continue;
+                }
+                if w.read_count > 0 && range.contains_ptr(addr) {
+                    w.changed = true;
+                    triggered = true;
+                } else if w.write_count > 0 && range.intersects(&window) {
+                    for_update_watchpoint.push(*range);
+                }
+            }
+            for range in &for_update_watchpoint {
+                if self.update_watchpoint_value_in(range, &window) {
+                    triggered = true;
+                }
+            }
+            triggered
+        }
+
         /// Return true if any watchpoint has fired. Will keep returning true until
         /// consume_watchpoint_changes() is called.
         pub fn has_any_watchpoint_changes(&self) -> bool {
@@ -1548,6 +1696,22 @@ pub mod address_space {
             self.get_watchpoints_internal(WatchPointFilter::ChangedWatchpoints)
         }
 
+        /// The contents of the watched range `[addr, addr + num_bytes)` when this
+        /// was last called and now, if it is readable.
+        pub fn consume_watchpoint_values(
+            &self,
+            addr: RemotePtr<Void>,
+            num_bytes: usize,
+        ) -> Option<(Vec<u8>, Vec<u8>)> {
+            let mut watchpoints = self.watchpoints.borrow_mut();
+            let w = watchpoints.get_mut(&MemoryRange::new_range(addr, num_bytes))?;
+            if !w.valid {
+                return None;
+            }
+            let old_value = mem::replace(&mut w.old_value_bytes, w.value_bytes.clone());
+            Some((old_value, w.value_bytes.clone()))
+        }
+
         pub fn set_shm_size(&self, addr: RemotePtr<Void>, bytes: usize) {
             self.shm_sizes.borrow_mut().insert(addr, bytes);
         }
@@ -1583,6 +1747,7 @@ pub mod address_space {
             );
 
             self.unmap_internal(t, addr, num_bytes);
+            self.software_watch_range_changed(MemoryRange::new_range(addr, num_bytes));
         }
 
         /// Notification of madvise call.
@@ -1845,6 +2010,9 @@ pub mod address_space {
         pub fn save_auxv(&self, t: &dyn Task) {
             *self.saved_auxv_.borrow_mut() = read_auxv(t);
         }
+        pub fn set_saved_auxv(&self, auxv: Vec<u8>) {
+            *self.saved_auxv_.borrow_mut() = auxv;
+        }
 
         /// Reads the /proc/<pid>/maps entry for a specific address. Does no caching.
         /// If performed on a file in a btrfs file system, this may return the
@@ -1863,8 +2031,78 @@ pub mod address_space {
             8 * 1024 * 1024
         }
 
-        pub fn chaos_mode_find_free_memory(&self, _t: &RecordTask, _len: usize) -> RemotePtr<Void> {
-            unimplemented!()
+        /// Pick a random free address range of `len` bytes for a tracee mmap that
+        /// didn't ask for an address. Returns null if none could be found, in which
+        /// case the kernel picks the address as usual.
+        pub fn chaos_mode_find_free_memory(&self, t: &RecordTask, len: usize) -> RemotePtr<Void> {
+            let addr_space_limit: usize = match t.arch() {
+                SupportedArch::X86 => 1 << 32,
+                SupportedArch::X64 => 1 << 47,
+            };
+            // Half the time, try to allocate at a completely random address. The other
+            // half of the time, we'll try to allocate immediately before or after a
+            // randomly chosen existing mapping.
+            let mut start: RemotePtr<Void> = if random::<bool>() {
+                // Some of these addresses will not be mappable. That's fine, the
+                // kernel will fall back to a valid address if the hint is not valid.
+                floor_page_size(RemotePtr::new(random::<usize>() & (addr_space_limit - 1)))
+            } else {
+                let mem = self.mem.borrow();
+                ed_assert!(t, !mem.is_empty());
+                let map_index = random::<usize>() % mem.len();
+                mem.values().nth(map_index).unwrap().map.start()
+            };
+
+            let len = ceil_page_size(len);
+            let addr_space_start = RemotePtr::<Void>::new(CHAOS_MODE_ADDR_SPACE_START);
+            // Reserve 3 pages at the end of userspace in case MonkeyPatcher wants
+            // to allocate something there.
+            let addr_space_end: RemotePtr<Void> =
+                floor_page_size(RemotePtr::new(addr_space_limit - 3 * page_size()));
+            if len > addr_space_end - addr_space_start {
+                return RemotePtr::null();
+            }
+            // Clamp start so that we're in the usable address space.
+            start = max(start, addr_space_start);
+            start = min(start, addr_space_end - len);
+
+            // Search the address space in one direction all the way to the end,
+            // then in the other direction.
+            let mut forward = random::<bool>();
+            for _ in 0..2 {
+                // Invariant: [addr, addr+len) is always in the usable address space
+                // [addr_space_start, addr_space_end).
+                let mut addr = start;
+                loop {
+                    let r = MemoryRange::new_range(addr, len);
+                    let maps = self.maps_containing_or_after(addr);
+                    // A stack mapping may grow down into r even if it starts after it.
+                    let overlap = maps
+                        .into_iter()
+                        .take_while(|(_, m)| {
+                            m.map.start() < r.end() + Self::chaos_mode_min_stack_size()
+                        })
+                        .map(|(_, m)| adjust_range_for_stack_growth(&m.map))
+                        .find(|range| range.intersects(&r));
+                    let overlap = match overlap {
+                        Some(overlap) => overlap,
+                        None => return addr,
+                    };
+                    if forward {
+                        if overlap.end() + len > addr_space_end {
+                            break;
+                        }
+                        addr = overlap.end();
+                    } else {
+                        if overlap.start() < addr_space_start + len {
+                            break;
+                        }
+                        addr = overlap.start() - len;
+                    }
+                }
+                forward = !forward;
+            }
+            RemotePtr::null()
         }
 
         /// We assume this method always succeeds
@@ -2095,12 +2333,14 @@ pub mod address_space {
                 first_run_event_: Default::default(),
                 // Implicit
                 breakpoints: Default::default(),
+                sdt_probes: Default::default(),
                 watchpoints: Default::default(),
                 mem: Default::default(),
                 shm_sizes: Default::default(),
                 monitored_mem: Default::default(),
                 dont_fork: Default::default(),
                 saved_watchpoints: Default::default(),
+                software_watch_pages: Default::default(),
                 child_mem_fd: Default::default(),
                 privileged_traced_syscall_ip_: Default::default(),
                 saved_auxv_: Default::default(),
@@ -2168,7 +2408,11 @@ pub mod address_space {
                 saved_auxv_: o.saved_auxv_.clone(),
                 first_run_event_: Default::default(),
                 watchpoints: o.watchpoints.clone(),
+                // Cloned tasks inherit the page protection too
+                software_watch_pages: o.software_watch_pages.clone(),
                 breakpoints: o.breakpoints.clone(),
+                // The child's memory has our breakpoints too.
+                sdt_probes: o.sdt_probes.clone(),
                 // rd does not explicitly initialize these.
                 child_mem_fd: Default::default(),
                 dont_fork: Default::default(),
@@ -2268,6 +2512,21 @@ pub mod address_space {
                 num_bytes
             );
 
+            // The memory of any probe breakpoints in the range is gone, so
+            // there's nothing to write back.
+            let range = MemoryRange::new_range(addr, num_bytes);
+            let unmapped_probes: Vec<RemoteCodePtr> = self
+                .sdt_probes
+                .borrow()
+                .keys()
+                .filter(|probe_addr| range.contains_ptr(probe_addr.to_data_ptr::<Void>()))
+                .copied()
+                .collect();
+            for probe_addr in unmapped_probes {
+                self.sdt_probes.borrow_mut().remove(&probe_addr);
+                self.breakpoints.borrow_mut().remove(&probe_addr);
+            }
+
             let unmapper = |slf: &Self, m_key: MemoryRangeKey, rem: MemoryRange| {
                 log!(LogDebug, "  unmapping ({}) ...", rem);
 
@@ -2526,6 +2785,10 @@ pub mod address_space {
             let mut mbm = self.watchpoints.borrow_mut();
             let mut watchpoint_original_mut = mbm.get_mut(watchpoint_range).unwrap();
             watchpoint_original_mut.valid = valid;
+            if !mark_changed_if_changed {
+                // A new watchpoint: there is no earlier value to report.
+                watchpoint_original_mut.old_value_bytes = value_bytes.clone();
+            }
             watchpoint_original_mut.value_bytes = value_bytes;
             if mark_changed_if_changed && changed {
                 watchpoint_original_mut.changed = true;
@@ -2543,10 +2806,139 @@ pub mod address_space {
                 }
             }
             for mr in intersects {
-                self.update_watchpoint_value(&mr, Some(true));
+                if self.watchpoints.borrow()[&mr].software {
+                    self.update_watchpoint_value_in(&mr, &r);
+                } else {
+                    self.update_watchpoint_value(&mr, Some(true));
+                }
                 // We do nothing to track kernel reads of read-write watchpoints...
             }
         }
+
+        /// Like update_watchpoint_value(.., Some(true)), but only rereads the
+        /// part of the watchpoint inside `window`. Software watchpoints can
+        /// be far too big to reread on every access.
+        fn update_watchpoint_value_in(
+            &self,
+            watchpoint_range: &MemoryRange,
+            window: &MemoryRange,
+        ) -> bool {
+            let part = watchpoint_range.intersect(*window);
+            let mut bytes = vec![0u8; part.size()];
+            {
+                let t = self.task_set().iter().next().unwrap();
+                if t.read_bytes_fallible(part.start(), &mut bytes) != Ok(bytes.len()) {
+                    // Part of the window isn't readable; take the slow path,
+                    // which deals with that.
+                    return self.update_watchpoint_value(watchpoint_range, Some(true));
+                }
+            }
+            let offset = part.start() - watchpoint_range.start();
+            let mut wps = self.watchpoints.borrow_mut();
+            let w = wps.get_mut(watchpoint_range).unwrap();
+            let old_bytes = &mut w.value_bytes[offset..offset + bytes.len()];
+            if *old_bytes == *bytes {
+                return false;
+            }
+            old_bytes.copy_from_slice(&bytes);
+            w.changed = true;
+            true
+        }
+
+        /// The protection to give `page` so that the watched accesses of the
+        /// software watchpoints on it fault, or None if it needs none.
+        fn software_watch_prot(&self, page: RemotePtr<Void>) -> Option<ProtFlags> {
+            let own_prot = self.mapping_of(page)?.map.prot();
+            let page_range = MemoryRange::new_range(page, page_size());
+            let prot = software_watch_prot_for(
+                own_prot,
+                self.watchpoints
+                    .borrow()
+                    .iter()
+                    .filter(|(range, w)| w.software && range.intersects(&page_range))
+                    .map(|(_, w)| w),
+            );
+            if prot == own_prot {
+                None
+            } else {
+                Some(prot)
+            }
+        }
+
+        /// Bring the tracee's page protection in line with the current set of
+        /// software watchpoints.
+        fn update_software_watch_protection(&self) {
+            let mut pages: BTreeSet<RemotePtr<Void>> = BTreeSet::new();
+            for (range, w) in self.watchpoints.borrow().iter() {
+                if w.software {
+                    let mut page = floor_page_size(range.start());
+                    while page < range.end() {
+                        pages.insert(page);
+                        page += page_size();
+                    }
+                }
+            }
+            let new_pages: BTreeMap<RemotePtr<Void>, ProtFlags> = pages
+                .into_iter()
+                .filter_map(|page| self.software_watch_prot(page).map(|prot| (page, prot)))
+                .collect();
+
+            let mut changes: BTreeMap<RemotePtr<Void>, ProtFlags> = BTreeMap::new();
+            for (&page, &prot) in self.software_watch_pages.borrow().iter() {
+                if !new_pages.contains_key(&page) {
+                    // No longer watched: back to its own protection.
+                    if let Some(m) = self.mapping_of(page) {
+                        changes.insert(page, m.map.prot());
+                    }
+                } else if new_pages[&page] != prot {
+                    changes.insert(page, new_pages[&page]);
+                }
+            }
+            for (&page, &prot) in new_pages.iter() {
+                if !self.software_watch_pages.borrow().contains_key(&page) {
+                    changes.insert(page, prot);
+                }
+            }
+            *self.software_watch_pages.borrow_mut() = new_pages;
+
+            if changes.is_empty() {
+                return;
+            }
+            let t = self.task_set().iter().next().unwrap();
+            mprotect_pages(&**t, &changes);
+        }
+
+        fn set_software_watch_protection_in(
+            &self,
+            t: &dyn Task,
+            window: Option<MemoryRange>,
+            protect: bool,
+        ) {
+            let pages: BTreeMap<RemotePtr<Void>, ProtFlags> = self
+                .software_watch_pages
+                .borrow()
+                .iter()
+                .filter(|(&page, _)| window.map_or(true, |w| w.contains_ptr(page)))
+                .filter_map(|(&page, &prot)| {
+                    if protect {
+                        Some((page, prot))
+                    } else {
+                        self.mapping_of(page).map(|m| (page, m.map.prot()))
+                    }
+                })
+                .collect();
+            mprotect_pages(t, &pages);
+        }
+
+        /// The tracee has just mapped, unmapped or mprotect()ed `range`, which
+        /// replaced whatever protection we gave its pages for software
+        /// watchpoints. Protect them again as the watchpoints need.
+        fn software_watch_range_changed(&self, range: MemoryRange) {
+            forget_pages(&mut self.software_watch_pages.borrow_mut(), &range);
+            if self.watchpoints.borrow().values().any(|w| w.software) {
+                self.update_software_watch_protection();
+            }
+        }
         fn get_watchpoints_internal(&self, filter: WatchPointFilter) -> Vec<WatchConfig> {
             let mut result: Vec<WatchConfig> = Vec::new();
             for (r, v) in self.watchpoints.borrow_mut().iter_mut() {
@@ -2576,6 +2968,10 @@ pub mod address_space {
         fn get_watch_configs(&self, will_set_task_state: WillSetTaskState) -> Vec<WatchConfig> {
             let mut result: Vec<WatchConfig> = Vec::new();
             for (r, v) in self.watchpoints.borrow_mut().iter_mut() {
+                if v.software {
+                    v.debug_regs_for_exec_read.clear();
+                    continue;
+                }
                 let mut assigned_regs: Option<&mut Vec<u8>> = None;
                 let watching = v.watched_bits();
                 if will_set_task_state == WillSetTaskState::SettingTaskState {
@@ -2936,6 +3332,120 @@ pub mod address_space {
             log!(LogDebug, "Dropped AddressSpace {:?}", self.uid());
         }
     }
+
+    /// The protection to give a page the tracee mapped with `own_prot` so
+    /// that the watched accesses of `watches`, the software watchpoints on
+    /// it, fault.
+    fn software_watch_prot_for<'a>(
+        own_prot: ProtFlags,
+        watches: impl Iterator<Item = &'a Watchpoint>,
+    ) -> ProtFlags {
+        let mut prot = own_prot;
+        for w in watches {
+            if w.read_count > 0 {
+                prot = ProtFlags::PROT_NONE;
+            } else {
+                prot.remove(ProtFlags::PROT_WRITE);
+            }
+        }
+        prot
+    }
+
+    /// The pages a single instruction that faulted at `addr` can access: it
+    /// accesses at most a few bytes, which may straddle into the next page.
+    fn software_watch_window(addr: RemotePtr<Void>) -> MemoryRange {
+        MemoryRange::new_range(floor_page_size(addr), 2 * page_size())
+    }
+
+    /// Drop the pages in `range` from `pages`.
+    fn forget_pages(pages: &mut BTreeMap<RemotePtr<Void>, ProtFlags>, range: &MemoryRange) {
+        pages.retain(|&page, _| !range.intersects(&MemoryRange::new_range(page, page_size())));
+    }
+
+    /// mprotect() each page in `pages` in the tracee, coalescing adjacent pages
+    /// with the same protection.
+    fn mprotect_pages(t: &dyn Task, pages: &BTreeMap<RemotePtr<Void>, ProtFlags>) {
+        if pages.is_empty() {
+            return;
+        }
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let mprotect_syscallno = syscall_number_for_mprotect(remote.arch());
+        let mut iter = pages.iter().peekable();
+        while let Some((&start, &prot)) = iter.next() {
+            let mut end = start + page_size();
+            while let Some(&(&next, &next_prot)) = iter.peek() {
+                if next != end || next_prot != prot {
+                    break;
+                }
+                end += page_size();
+                iter.next();
+            }
+            // Not infallible: the tracee may have unmapped the pages since.
+            rd_syscall!(
+                remote,
+                mprotect_syscallno,
+                start.as_usize(),
+                end - start,
+                prot.bits()
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        fn software_watchpoint(which: RwxBits) -> Watchpoint {
+            let mut w = Watchpoint::new(8);
+            w.watch(which);
+            w.software = true;
+            w
+        }
+
+        #[test]
+        fn software_watch_prot_for_watches() {
+            let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+            let read = software_watchpoint(RwxBits::READ_BIT);
+            let write = software_watchpoint(RwxBits::WRITE_BIT);
+            assert_eq!(software_watch_prot_for(rw, std::iter::empty()), rw);
+            assert_eq!(
+                software_watch_prot_for(rw, [&write].iter().copied()),
+                ProtFlags::PROT_READ
+            );
+            assert_eq!(
+                software_watch_prot_for(rw, [&write, &read].iter().copied()),
+                ProtFlags::PROT_NONE
+            );
+            // The tracee's own protection is never loosened.
+            assert_eq!(
+                software_watch_prot_for(ProtFlags::PROT_NONE, [&write].iter().copied()),
+                ProtFlags::PROT_NONE
+            );
+            assert_eq!(
+                software_watch_prot_for(ProtFlags::PROT_READ, [&write].iter().copied()),
+                ProtFlags::PROT_READ
+            );
+        }
+
+        #[test]
+        fn forget_pages_in_range() {
+            let page = page_size();
+            let mut pages: BTreeMap<RemotePtr<Void>, ProtFlags> = BTreeMap::new();
+            for i in 1..5 {
+                pages.insert(RemotePtr::from(i * page), ProtFlags::PROT_READ);
+            }
+            // Covers the end of page 2 and the start of page 3.
+            forget_pages(
+                &mut pages,
+                &MemoryRange::new_range(RemotePtr::from(2 * page + 8), page),
+            );
+            let left: Vec<RemotePtr<Void>> = pages.keys().copied().collect();
+            assert_eq!(left, vec![RemotePtr::from(page), RemotePtr::from(4 * page)]);
+
+            forget_pages(&mut pages, &MemoryRange::new_range(RemotePtr::null(), 0));
+            assert_eq!(pages.len(), 2);
+        }
+    }
 }
 
 fn configure_watch_registers(
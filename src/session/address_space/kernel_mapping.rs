@@ -261,6 +261,37 @@ impl KernelMapping {
         s
     }
 
+    /// A line exactly as the kernel would print it in /proc/{tid}/maps,
+    /// including the trailing newline.
+    pub fn proc_maps_line(&self) -> Vec<u8> {
+        let map_shared = if self.flags_.contains(MapFlags::MAP_SHARED) {
+            's'
+        } else {
+            'p'
+        };
+        let mut line = format!(
+            "{:08x}-{:08x} {}{} {:08x} {:02x}:{:02x} {} ",
+            self.start().as_usize(),
+            self.end().as_usize(),
+            self.prot_string(),
+            map_shared,
+            self.offset,
+            major(self.device()),
+            minor(self.device()),
+            self.inode()
+        )
+        .into_bytes();
+        if !self.fsname().is_empty() {
+            // The kernel pads the name out to start at column 73.
+            while line.len() < 73 {
+                line.push(b' ');
+            }
+            line.extend_from_slice(self.fsname().as_bytes());
+        }
+        line.push(b'\n');
+        line
+    }
+
     fn prot_string(&self) -> String {
         let mut s = String::with_capacity(3);
         if self.prot_.contains(ProtFlags::PROT_READ) {
@@ -5,30 +5,94 @@ use super::{
 };
 use crate::{
     arch::Architecture,
-    auto_remote_syscalls::AutoRemoteSyscalls,
+    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
     bindings::ptrace::PTRACE_EVENT_EXIT,
     emu_fs::{EmuFs, EmuFsSharedPtr},
-    kernel_abi::SupportedArch,
+    kernel_abi::{syscall_number_for_openat, SupportedArch},
     kernel_metadata::syscall_name,
     log::LogDebug,
     preload_interface::preload_globals,
+    rd::RD_RESERVED_ROOT_DIR_FD,
+    remote_ptr::RemotePtr,
     session::{
         session_inner::{BreakStatus, RunCommand, SessionInner},
         task::{
-            task_common::write_val_mem,
+            task_common::{write_mem, write_val_mem},
             task_inner::{ResumeRequest, TicksRequest, WaitRequest},
             Task,
         },
         Session,
     },
     sig::Sig,
+    trace::trace_frame::TraceFrame,
+    util::{create_temporary_file, write_all},
 };
-use libc::pid_t;
+use libc::{
+    pid_t, CLOCK_REALTIME, CLOCK_REALTIME_COARSE, CLOCK_TAI, EFAULT, EPERM, O_ACCMODE, O_CLOEXEC,
+    O_RDONLY,
+};
+use nix::unistd::unlink;
 use std::{
-    cell::{Ref, RefMut},
+    cell::{Ref, RefCell, RefMut},
+    ffi::OsStr,
+    fs,
+    mem::size_of,
     ops::{Deref, DerefMut},
+    rc::Rc,
 };
 
+/// Supplies the time tracees see while running in a diversion, instead of the
+/// real time of the machine rd is running on.
+pub trait DiversionClock {
+    /// Return `(seconds, nanoseconds)` for a read of clock `clock_id`.
+    fn now(&mut self, clock_id: i32) -> (i64, i64);
+}
+
+/// Starts at the given wall-clock and monotonic times and advances by
+/// `step_ns` on every read. Installing a new one for each diversion makes
+/// repeated `call`s from the same stop see exactly the same times.
+pub struct SteppingClock {
+    realtime_ns: i64,
+    monotonic_ns: i64,
+    /// Time passed since the clock was installed.
+    elapsed_ns: i64,
+    step_ns: i64,
+}
+
+impl SteppingClock {
+    pub fn new(realtime_ns: i64, monotonic_ns: i64, step_ns: i64) -> SteppingClock {
+        SteppingClock {
+            realtime_ns,
+            monotonic_ns,
+            elapsed_ns: 0,
+            step_ns,
+        }
+    }
+
+    /// A clock that starts at the times `frame` was recorded at. Traces from
+    /// before wall-clock times were recorded start at the epoch.
+    pub fn at_frame(frame: &TraceFrame, step_ns: i64) -> SteppingClock {
+        let to_ns = |secs: f64| (secs * 1e9) as i64;
+        SteppingClock::new(
+            to_ns(frame.realtime().unwrap_or(0.0)),
+            to_ns(frame.monotonic_time()),
+            step_ns,
+        )
+    }
+}
+
+impl DiversionClock for SteppingClock {
+    fn now(&mut self, clock_id: i32) -> (i64, i64) {
+        let start = match clock_id {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE | CLOCK_TAI => self.realtime_ns,
+            _ => self.monotonic_ns,
+        };
+        let ns = start + self.elapsed_ns;
+        self.elapsed_ns += self.step_ns;
+        (ns / 1_000_000_000, ns % 1_000_000_000)
+    }
+}
+
 /// A DiversionSession lets you run task(s) forward without replay.
 /// Clone a ReplaySession to a DiversionSession to execute some arbitrary
 /// code for its side effects.
@@ -47,6 +111,9 @@ use std::{
 pub struct DiversionSession {
     session_inner: SessionInner,
     emu_fs: EmuFsSharedPtr,
+    /// When set, clock_gettime(), clock_gettime64(), gettimeofday() and time()
+    /// are answered from this clock rather than executed.
+    clock: RefCell<Option<Box<dyn DiversionClock>>>,
 }
 
 impl Drop for DiversionSession {
@@ -93,6 +160,7 @@ impl Default for DiversionSession {
         DiversionSession {
             session_inner: SessionInner::new(),
             emu_fs: EmuFs::create(),
+            clock: Default::default(),
         }
     }
 }
@@ -106,6 +174,59 @@ impl DiversionSession {
         self.emu_fs.borrow_mut()
     }
 
+    /// Make tracees read the time from `clock` (or the real clocks again, if
+    /// `None`) for the rest of this diversion.
+    pub fn set_clock(&self, clock: Option<Box<dyn DiversionClock>>) {
+        *self.clock.borrow_mut() = clock;
+    }
+
+    /// If a clock is installed and `syscallno` reads the time, write the time
+    /// to the tracee and return the syscall result.
+    fn emulate_clock_read<Arch: Architecture>(
+        &self,
+        t: &dyn Task,
+        syscallno: i32,
+    ) -> Option<isize> {
+        let mut maybe_clock = self.clock.borrow_mut();
+        let clock = maybe_clock.as_mut()?;
+        let regs = t.regs_ref().clone();
+        // clock_gettime64's struct __kernel_timespec has 64-bit fields on every
+        // architecture.
+        let mut word_size = size_of::<Arch::unsigned_word>();
+        let (addr, words, ret) = if syscallno == Arch::CLOCK_GETTIME {
+            let (sec, nsec) = clock.now(regs.arg1_signed() as i32);
+            (regs.arg2(), vec![sec, nsec], 0)
+        } else if syscallno == Arch::CLOCK_GETTIME64 {
+            let (sec, nsec) = clock.now(regs.arg1_signed() as i32);
+            word_size = 8;
+            (regs.arg2(), vec![sec, nsec], 0)
+        } else if syscallno == Arch::GETTIMEOFDAY {
+            let (sec, nsec) = clock.now(CLOCK_REALTIME);
+            (regs.arg1(), vec![sec, nsec / 1000], 0)
+        } else if syscallno == Arch::TIME {
+            let (sec, _) = clock.now(CLOCK_REALTIME);
+            (regs.arg1(), vec![sec], sec as isize)
+        } else {
+            return None;
+        };
+        if addr == 0 {
+            return Some(ret);
+        }
+
+        // Apart from __kernel_timespec, these are all made of native words.
+        let mut bytes = Vec::new();
+        for w in words {
+            if word_size == 4 {
+                bytes.extend_from_slice(&(w as i32).to_le_bytes());
+            } else {
+                bytes.extend_from_slice(&w.to_le_bytes());
+            }
+        }
+        let mut ok = true;
+        write_mem(t, RemotePtr::<u8>::from(addr), &bytes, Some(&mut ok));
+        Some(if ok { ret } else { -EFAULT as isize })
+    }
+
     /// Try make progress in this diversion session. Run task t if possible.
     pub fn diversion_step(
         &self,
@@ -257,6 +378,33 @@ fn process_syscall_arch<Arch: Architecture>(t: &dyn Task, syscallno: i32) {
         return;
     }
 
+    if let Some(ret) = t
+        .session()
+        .as_diversion()
+        .unwrap()
+        .emulate_clock_read::<Arch>(t, syscallno)
+    {
+        finish_emulated_syscall_with_ret(t, ret);
+        return;
+    }
+
+    if syscallno == Arch::OPEN || syscallno == Arch::OPENAT {
+        if let Some(ret) = emulate_proc_open::<Arch>(t, syscallno) {
+            finish_emulated_syscall_with_ret(t, ret);
+            return;
+        }
+    }
+
+    if is_mount_syscall::<Arch>(syscallno) {
+        log!(
+            LogDebug,
+            "Failing syscall {}",
+            syscall_name(syscallno, t.arch())
+        );
+        finish_emulated_syscall_with_ret(t, -EPERM as isize);
+        return;
+    }
+
     // We blacklist these syscalls because the params include
     // namespaced identifiers that are different in replay than
     // recording, and during replay they may refer to different,
@@ -300,6 +448,19 @@ fn process_syscall_arch<Arch: Architecture>(t: &dyn Task, syscallno: i32) {
     execute_syscall(t)
 }
 
+/// Syscalls that change the mount table. The mounts a recording made were never
+/// made during replay, so letting a diversion make any would change rd's own
+/// mount namespace. Container tooling probing for what it may mount copes with
+/// EPERM.
+fn is_mount_syscall<Arch: Architecture>(syscallno: i32) -> bool {
+    syscallno == Arch::MOUNT
+        || syscallno == Arch::UMOUNT
+        || syscallno == Arch::UMOUNT2
+        || syscallno == Arch::PIVOT_ROOT
+        || syscallno == Arch::MOVE_MOUNT
+        || syscallno == Arch::FSMOUNT
+}
+
 fn finish_emulated_syscall_with_ret(t: &dyn Task, ret: isize) {
     t.finish_emulated_syscall();
     let mut r = t.regs_ref().clone();
@@ -313,19 +474,163 @@ fn finish_emulated_syscall_with_ret(t: &dyn Task, ret: isize) {
 fn execute_syscall(t: &dyn Task) {
     t.finish_emulated_syscall();
 
+    // The kernel would get EFAULT from pages protected for software
+    // watchpoints inherited from the replay.
+    let vm = t.vm();
+    vm.set_software_watch_protection(t, false);
+    {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        remote.syscall(
+            remote.initial_regs_ref().original_syscallno() as i32,
+            &[
+                remote.initial_regs_ref().arg1(),
+                remote.initial_regs_ref().arg2(),
+                remote.initial_regs_ref().arg3(),
+                remote.initial_regs_ref().arg4(),
+                remote.initial_regs_ref().arg5(),
+                remote.initial_regs_ref().arg6(),
+            ],
+        );
+        remote
+            .initial_regs_mut()
+            .set_syscall_result(t.regs_ref().syscall_result());
+    }
+    if Rc::ptr_eq(&vm, &t.vm()) {
+        vm.set_software_watch_protection(t, true);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ProcFile {
+    Maps,
+    Status,
+    Mem,
+    MountInfo,
+}
+
+/// If `path` is /proc/{self,thread-self,<pid>}/{maps,status,mem,mountinfo},
+/// return the recorded tid it refers to and which file it is. A `<pid>` is
+/// taken to be a recorded tid, since those are the only ones the tracee knows
+/// about.
+fn parse_proc_path(t: &dyn Task, path: &[u8]) -> Option<(pid_t, ProcFile)> {
+    let rest = path.strip_prefix(b"/proc/")?;
+    let slash = rest.iter().position(|&c| c == b'/')?;
+    let (who, file) = (&rest[..slash], &rest[slash + 1..]);
+    let rec_tid = match who {
+        b"self" => t.tgid(),
+        b"thread-self" => t.rec_tid(),
+        _ => std::str::from_utf8(who).ok()?.parse::<pid_t>().ok()?,
+    };
+    let file = match file {
+        b"maps" => ProcFile::Maps,
+        b"status" => ProcFile::Status,
+        b"mem" => ProcFile::Mem,
+        b"mountinfo" => ProcFile::MountInfo,
+        _ => return None,
+    };
+    Some((rec_tid, file))
+}
+
+/// Diversion tasks are live processes, so their /proc files describe rd's
+/// replay of them: emufs file names in `maps`, replay pids in `status`, and no
+/// process at all behind `/proc/<recorded pid>/mem` or `mountinfo`. Opens of
+/// those files are redirected to content matching what the task saw during
+/// recording.
+///
+/// Returns the result of the emulated open, or None if the syscall should be
+/// executed as usual.
+fn emulate_proc_open<Arch: Architecture>(t: &dyn Task, syscallno: i32) -> Option<isize> {
+    let (path_addr, flags) = if syscallno == Arch::OPENAT {
+        (t.regs_ref().arg2(), t.regs_ref().arg3() as i32)
+    } else {
+        (t.regs_ref().arg1(), t.regs_ref().arg2() as i32)
+    };
+    // Let the kernel report the EFAULT.
+    if t.vm().mapping_of(path_addr.into()).is_none() {
+        return None;
+    }
+    let path = t.read_c_str(path_addr.into());
+    let (rec_tid, file) = parse_proc_path(t, path.as_bytes())?;
+    // /proc/self of a thread group whose leader has exited.
+    let maybe_target = t.session().find_task_from_rec_tid(rec_tid);
+    let target: &dyn Task = match maybe_target.as_ref() {
+        Some(target) => &***target,
+        None if rec_tid == t.tgid() => t,
+        None => return None,
+    };
+
+    let contents = match file {
+        // The live task's mount namespace is the one the diversion runs in,
+        // and diversions can't change it (see `is_mount_syscall`), so only the
+        // pid needs translating.
+        ProcFile::Mem | ProcFile::MountInfo => {
+            let name = if file == ProcFile::Mem {
+                "mem"
+            } else {
+                "mountinfo"
+            };
+            let real_path = format!("/proc/{}/{}", target.tid(), name);
+            return Some(open_in_tracee(t, OsStr::new(&real_path), flags));
+        }
+        // These are read-only; let the kernel fail writable opens.
+        _ if flags & O_ACCMODE != O_RDONLY => return None,
+        ProcFile::Maps => {
+            let mut contents = Vec::new();
+            for (_, m) in &target.vm().maps() {
+                contents.extend_from_slice(&m.recorded_map.proc_maps_line());
+            }
+            contents
+        }
+        ProcFile::Status => synthesize_status(target)?,
+    };
+    log!(LogDebug, "Emulating open of {:?} for {}", path, t.tid());
+
+    let file = create_temporary_file(b"rd-diversion-proc-XXXXXX");
+    write_all(file.fd.as_raw(), &contents);
+    let ret = open_in_tracee(t, &file.name, O_RDONLY | (flags & O_CLOEXEC));
+    unlink(file.name.as_os_str()).unwrap();
+    Some(ret)
+}
+
+/// The live task's status file with the ids it had during recording.
+fn synthesize_status(t: &dyn Task) -> Option<Vec<u8>> {
+    let real = fs::read(format!("/proc/{}/status", t.tid())).ok()?;
+    let tg = t.thread_group();
+    let ppid = tg.borrow().parent().map(|p| p.borrow().tgid);
+    let mut contents = Vec::with_capacity(real.len());
+    for line in real.split(|&c| c == b'\n').filter(|l| !l.is_empty()) {
+        let field = line.split(|&c| c == b':').next().unwrap();
+        let value = match field {
+            b"Tgid" | b"NStgid" => Some(t.tgid()),
+            b"Pid" | b"NSpid" => Some(t.rec_tid()),
+            b"PPid" => ppid,
+            _ => None,
+        };
+        match value {
+            Some(v) => {
+                contents.extend_from_slice(field);
+                contents.extend_from_slice(format!(":\t{}", v).as_bytes());
+            }
+            None => contents.extend_from_slice(line),
+        }
+        contents.push(b'\n');
+    }
+    Some(contents)
+}
+
+/// Open `path` in the tracee, relative to its reserved root dir fd so that a
+/// chroot doesn't get in the way.
+fn open_in_tracee(t: &dyn Task, path: &OsStr, flags: i32) -> isize {
+    let arch = t.arch();
     let mut remote = AutoRemoteSyscalls::new(t);
-    remote.syscall(
-        remote.initial_regs_ref().original_syscallno() as i32,
-        &[
-            remote.initial_regs_ref().arg1(),
-            remote.initial_regs_ref().arg2(),
-            remote.initial_regs_ref().arg3(),
-            remote.initial_regs_ref().arg4(),
-            remote.initial_regs_ref().arg5(),
-            remote.initial_regs_ref().arg6(),
-        ],
-    );
-    remote
-        .initial_regs_mut()
-        .set_syscall_result(t.regs_ref().syscall_result());
+    let mut child_path = AutoRestoreMem::push_cstr(&mut remote, path);
+    // skip leading '/' since we want the path to be relative to the root fd
+    let child_addr = child_path.get().unwrap().as_usize() + 1;
+    rd_syscall!(
+        child_path,
+        syscall_number_for_openat(arch),
+        RD_RESERVED_ROOT_DIR_FD,
+        child_addr,
+        flags
+    )
 }
@@ -1,16 +1,23 @@
 use super::{
-    address_space::{address_space::AddressSpace, Privileged},
+    address_space::{
+        address_space::AddressSpace, kernel_map_iterator::KernelMapIterator,
+        memory_range::MemoryRange, Privileged,
+    },
     on_create_task_common,
     session_common::kill_all_tasks,
     session_inner::PtraceSyscallSeccompOrdering,
     task::{
+        is_singlestep_resume,
         record_task::{
-            self, AllowSyscallbufReset, EmulatedStopType, FlushSyscallbuf, RecordTask,
+            self, AllowSyscallbufReset, EmulatedStopType, FlushSyscallbuf, RecordTask, Sighandlers,
             StashedSignal,
         },
-        task_common::{read_val_mem, write_val_mem},
+        task_common::{
+            did_set_up_sigframe, init_sigaltstack_from_task, read_val_mem, write_val_mem,
+        },
         task_inner::{
-            PtraceData, ResumeRequest, SaveTraceeFdNumber, TaskInner, TicksRequest, WaitRequest,
+            set_up_attached_process, CloneFlags, CloneReason, PtraceData, ResumeRequest,
+            SaveTraceeFdNumber, TaskInner, TicksRequest, WaitRequest,
         },
         TaskSharedWeakPtr,
     },
@@ -20,27 +27,35 @@ use crate::{
     arch::{Architecture, NativeArch},
     arch_structs,
     arch_structs::{robust_list, robust_list_head, siginfo_t as arch_siginfo_t},
+    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
     bindings::{
         audit::{AUDIT_ARCH_I386, AUDIT_ARCH_X86_64},
         kernel::{FUTEX_OWNER_DIED, FUTEX_TID_MASK, FUTEX_WAITERS},
         ptrace::{
-            ptrace, PTRACE_EVENT_EXEC, PTRACE_EVENT_EXIT, PTRACE_EVENT_SECCOMP, PTRACE_GETEVENTMSG,
-            PTRACE_SINGLESTEP, PTRACE_SYSCALL, PTRACE_SYSEMU, PTRACE_SYSEMU_SINGLESTEP,
+            ptrace, PTRACE_DETACH, PTRACE_EVENT_EXEC, PTRACE_EVENT_EXIT, PTRACE_EVENT_SECCOMP,
+            PTRACE_GETEVENTMSG, PTRACE_INTERRUPT, PTRACE_SINGLESTEP, PTRACE_SYSCALL, PTRACE_SYSEMU,
+            PTRACE_SYSEMU_SINGLESTEP,
         },
         signal::{siginfo_t, POLL_IN, SI_KERNEL, SI_MESGQ, SI_QUEUE, SI_TIMER, SI_TKILL, SI_USER},
     },
     commands::record_command::RecordCommand,
+    error_injector::ErrorInjector,
     event::{Event, EventType, SignalDeterministic, Switchable, SyscallEventData, SyscallState},
-    file_monitor::virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+    fd_table::FdTableSharedPtr,
+    file_monitor::{
+        cgroup_file_monitor::CgroupLimit, virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
+    },
     flags::Flags,
     kernel_abi::{
         is_at_syscall_instruction, is_exit_group_syscall, is_pause_syscall,
         is_rdcall_notify_syscall_hook_exit_syscall, is_restart_syscall_syscall, is_write_syscall,
-        native_arch, syscall_number_for_gettid, syscall_number_for_restart_syscall, SupportedArch,
+        native_arch, syscall_number_for_arch_prctl, syscall_number_for_close,
+        syscall_number_for_gettid, syscall_number_for_munmap, syscall_number_for_prctl,
+        syscall_number_for_restart_syscall, SupportedArch,
     },
     kernel_metadata::{errno_name, is_sigreturn, ptrace_event_name, signal_name, syscall_name},
     kernel_supplement::{
-        ERESTARTNOHAND, ERESTARTNOINTR, ERESTARTSYS, ERESTART_RESTARTBLOCK,
+        ARCH_SET_CPUID, ERESTARTNOHAND, ERESTARTNOINTR, ERESTARTSYS, ERESTART_RESTARTBLOCK,
         PTRACE_EVENT_SECCOMP_OBSOLETE, SECCOMP_RET_ACTION, SECCOMP_RET_DATA, SECCOMP_RET_ERRNO,
         SECCOMP_RET_KILL, SECCOMP_RET_TRAP, SYS_SECCOMP,
     },
@@ -50,57 +65,73 @@ use crate::{
         syscallbuf_hdr, syscallbuf_record, SYSCALLBUF_ENABLED_ENV_VAR, SYSCALLBUF_LIB_FILENAME,
         SYSCALLBUF_LIB_FILENAME_PADDED,
     },
+    rd::{RD_MAGIC_SAVE_DATA_FD, RD_RESERVED_ROOT_DIR_FD},
     record_signal::{
-        arm_desched_event, disarm_desched_event, handle_signal, handle_syscallbuf_breakpoint,
-        SignalBlocked, SignalHandled,
+        arm_desched_event, disarm_desched_event, handle_sdt_probe_breakpoint, handle_signal,
+        handle_syscallbuf_breakpoint, SignalBlocked, SignalHandled,
+    },
+    record_syscall::{
+        rec_prepare_restart_syscall, rec_prepare_syscall, rec_process_syscall,
+        record_attach_snapshot,
     },
-    record_syscall::{rec_prepare_restart_syscall, rec_prepare_syscall, rec_process_syscall},
     registers::Registers,
     remote_ptr::{RemotePtr, Void},
     scheduler::Scheduler,
     scoped_fd::ScopedFd,
     seccomp_filter_rewriter::{SeccompFilterRewriter, SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO},
     session::{
+        address_space::kernel_mapping::KernelMapping,
         session_inner::SessionInner,
         task::{Task, TaskSharedPtr},
         Session,
     },
     sig::{self, Sig},
+    stap_sdt::{sdt_probes, SdtProbe},
     thread_group::ThreadGroupSharedPtr,
     ticks::Ticks,
     trace::{
+        trace_frame::FrameTime,
         trace_stream::TraceStream,
         trace_task_event::TraceTaskEvent,
         trace_writer::{CloseStatus, TraceWriter},
     },
     util::{
-        choose_cpu, find, good_random, is_deterministic_signal, resource_path, signal_bit,
-        u8_slice_mut, xsave_area_size, CPUIDData, CPUID_GETEXTENDEDFEATURES, CPUID_GETFEATURES,
-        CPUID_GETXSAVE,
+        choose_cpu, default_action, find, good_random, is_deterministic_signal, resource_path,
+        signal_bit, u8_slice_mut, xsave_area_size, CPUIDData, SignalAction,
+        CPUID_GETEXTENDEDFEATURES, CPUID_GETFEATURES, CPUID_GETXSAVE,
     },
     wait_status::{MaybeStopSignal, WaitStatus},
 };
-use goblin::elf::Elf;
+use goblin::elf::{header::ELFCLASS64, Elf};
 use libc::{
-    pid_t, CLONE_FILES, CLONE_FS, CLONE_SIGHAND, CLONE_SYSVSEM, CLONE_THREAD, CLONE_VM, ENOSYS,
-    SIGBUS, SIGCHLD, SIGFPE, SIGILL, SIGIO, SIGSEGV, SIGSYS, SIGTRAP,
+    dev_t, ino_t, pid_t, prlimit, rlimit, SYS_get_robust_list, SYS_tgkill, CLONE_FILES, CLONE_FS,
+    CLONE_SIGHAND, CLONE_SYSVSEM, CLONE_THREAD, CLONE_VM, ENOSYS, PR_GET_TID_ADDRESS, PR_SET_TSC,
+    PR_TSC_SIGSEGV, RLIMIT_CORE, SIGBUS, SIGCHLD, SIGFPE, SIGILL, SIGIO, SIGSEGV, SIGSYS, SIGTRAP,
 };
 use mem::size_of;
 use nix::{
-    fcntl::{open, OFlag},
-    sys::stat::{stat, Mode, SFlag},
+    errno::Errno,
+    fcntl::{open, readlink, OFlag},
+    sys::stat::{lstat, stat, Mode, SFlag},
     unistd::{access, read, AccessFlags},
 };
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 use std::{
     cell::{Cell, Ref, RefCell, RefMut},
-    cmp::max,
+    cmp::{max, min},
+    collections::HashMap,
     convert::{TryFrom, TryInto},
     env,
     ffi::{OsStr, OsString},
-    fs, mem,
+    fmt::{self, Display},
+    fs,
+    io::Read,
+    mem,
     ops::{Deref, DerefMut},
     os::unix::ffi::{OsStrExt, OsStringExt},
+    ptr,
     rc::Rc,
+    str::FromStr,
 };
 
 const CPUID_RDRAND_FLAG: u32 = 1 << 30;
@@ -108,69 +139,225 @@ const CPUID_RTM_FLAG: u32 = 1 << 11;
 const CPUID_RDSEED_FLAG: u32 = 1 << 18;
 const CPUID_XSAVEOPT_FLAG: u32 = 1 << 0;
 
-#[derive(Clone, Eq, PartialEq)]
-pub struct DisableCPUIDFeatures {
-    /// in: EAX=0x01
-    features_ecx: u32,
-    features_edx: u32,
-    /// in: EAX=0x07 ECX=0
-    extended_features_ebx: u32,
-    extended_features_ecx: u32,
-    extended_features_edx: u32,
-    /// in: EAX=0x0D ECX=1
-    xsave_features_eax: u32,
+/// CPUID EAX=7,ECX=0 bits for AVX-512 and its extensions.
+/// EBX: F, DQ, IFMA, PF, ER, CD, BW, VL
+const CPUID_AVX512_EBX_FLAGS: u32 = 0xdc23_0000;
+/// ECX: VBMI, VBMI2, VNNI, BITALG, VPOPCNTDQ
+const CPUID_AVX512_ECX_FLAGS: u32 = 0x0000_5842;
+/// EDX: 4VNNIW, 4FMAPS, VP2INTERSECT, FP16
+const CPUID_AVX512_EDX_FLAGS: u32 = 0x0080_010c;
+
+/// Most bytes of the `--seed` stream handed out at once. Linux's getrandom()
+/// and /dev/urandom reads return at most this much (32MiB - 1) per call too.
+const MAX_SEEDED_RANDOM_BYTES: usize = (1 << 25) - 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CPUIDRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// Bits to clear from one register of what CPUID returns for `leaf`, and
+/// `subleaf` if there is one (otherwise for every subleaf).
+/// Written `<leaf>[.<subleaf>]:<register>=<bits>`, e.g. `0x7.0:ebx=0x10000`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CPUIDMask {
+    pub leaf: u32,
+    pub subleaf: Option<u32>,
+    pub register: CPUIDRegister,
+    pub bits: u32,
+}
+
+impl CPUIDMask {
+    pub const fn new(leaf: u32, subleaf: Option<u32>, register: CPUIDRegister, bits: u32) -> Self {
+        Self {
+            leaf,
+            subleaf,
+            register,
+            bits,
+        }
+    }
+
+    fn applies_to(&self, eax_in: u32, ecx_in: u32) -> bool {
+        self.leaf == eax_in && self.subleaf.map_or(true, |subleaf| subleaf == ecx_in)
+    }
+}
+
+fn parse_cpuid_number(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    };
+    res.map_err(|_| format!("`{}` is not a number", s))
+}
+
+impl FromStr for CPUIDMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let syntax = || format!("Expected <leaf>[.<subleaf>]:<register>=<bits>, got `{}`", s);
+        let (input, output) = match s.find(':') {
+            Some(pos) => (&s[0..pos], &s[pos + 1..]),
+            None => return Err(syntax()),
+        };
+        let (register, bits) = match output.find('=') {
+            Some(pos) => (output[0..pos].trim(), &output[pos + 1..]),
+            None => return Err(syntax()),
+        };
+        let (leaf, subleaf) = match input.find('.') {
+            Some(pos) => (
+                parse_cpuid_number(&input[0..pos])?,
+                Some(parse_cpuid_number(&input[pos + 1..])?),
+            ),
+            None => (parse_cpuid_number(input)?, None),
+        };
+        let register = match register.to_ascii_lowercase().as_str() {
+            "eax" => CPUIDRegister::Eax,
+            "ebx" => CPUIDRegister::Ebx,
+            "ecx" => CPUIDRegister::Ecx,
+            "edx" => CPUIDRegister::Edx,
+            _ => return Err(format!("Unknown CPUID register `{}`", register)),
+        };
+        Ok(CPUIDMask::new(
+            leaf,
+            subleaf,
+            register,
+            parse_cpuid_number(bits)?,
+        ))
+    }
 }
 
-impl Default for DisableCPUIDFeatures {
-    fn default() -> Self {
-        Self::new()
+impl Display for CPUIDMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.leaf)?;
+        if let Some(subleaf) = self.subleaf {
+            write!(f, ".{}", subleaf)?;
+        }
+        let register = match self.register {
+            CPUIDRegister::Eax => "eax",
+            CPUIDRegister::Ebx => "ebx",
+            CPUIDRegister::Ecx => "ecx",
+            CPUIDRegister::Edx => "edx",
+        };
+        write!(f, ":{}={:#x}", register, self.bits)
     }
 }
 
+/// Named sets of masks that `--disable-cpuid-features` accepts in place of
+/// a `<leaf>[.<subleaf>]:<register>=<bits>` mask.
+const CPUID_PRESETS: &[(&str, &[CPUIDMask])] = &[(
+    "avx512",
+    &[
+        CPUIDMask::new(
+            CPUID_GETEXTENDEDFEATURES,
+            Some(0),
+            CPUIDRegister::Ebx,
+            CPUID_AVX512_EBX_FLAGS,
+        ),
+        CPUIDMask::new(
+            CPUID_GETEXTENDEDFEATURES,
+            Some(0),
+            CPUIDRegister::Ecx,
+            CPUID_AVX512_ECX_FLAGS,
+        ),
+        CPUIDMask::new(
+            CPUID_GETEXTENDEDFEATURES,
+            Some(0),
+            CPUIDRegister::Edx,
+            CPUID_AVX512_EDX_FLAGS,
+        ),
+    ],
+)];
+
+/// CPUID feature bits to hide from tracees, so that a trace can be replayed
+/// on machines that lack those features. This only affects what CPUID
+/// reports; programs that use a feature without checking CPUID still use it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DisableCPUIDFeatures {
+    masks: Vec<CPUIDMask>,
+}
+
 impl DisableCPUIDFeatures {
     pub fn new() -> Self {
-        Self {
-            features_ecx: 0,
-            features_edx: 0,
-            extended_features_ebx: 0,
-            extended_features_ecx: 0,
-            extended_features_edx: 0,
-            xsave_features_eax: 0,
-        }
+        Self::default()
     }
 
+    /// The masks of rr's `--disable-cpuid-features`, `--disable-cpuid-features-ext`
+    /// and `--disable-cpuid-features-xsave`.
     pub fn from(features: (u32, u32), features_ext: (u32, u32, u32), features_xsave: u32) -> Self {
-        Self {
-            features_ecx: features.0,
-            features_edx: features.1,
-            extended_features_ebx: features_ext.0,
-            extended_features_ecx: features_ext.1,
-            extended_features_edx: features_ext.2,
-            xsave_features_eax: features_xsave,
+        let mut res = Self::new();
+        res.disable(CPUIDMask::new(
+            CPUID_GETFEATURES,
+            None,
+            CPUIDRegister::Ecx,
+            features.0,
+        ));
+        res.disable(CPUIDMask::new(
+            CPUID_GETFEATURES,
+            None,
+            CPUIDRegister::Edx,
+            features.1,
+        ));
+        for &(register, bits) in &[
+            (CPUIDRegister::Ebx, features_ext.0),
+            (CPUIDRegister::Ecx, features_ext.1),
+            (CPUIDRegister::Edx, features_ext.2),
+        ] {
+            res.disable(CPUIDMask::new(
+                CPUID_GETEXTENDEDFEATURES,
+                Some(0),
+                register,
+                bits,
+            ));
         }
+        res.disable(CPUIDMask::new(
+            CPUID_GETXSAVE,
+            Some(1),
+            CPUIDRegister::Eax,
+            features_xsave,
+        ));
+        res
+    }
+
+    pub fn disable(&mut self, mask: CPUIDMask) {
+        if mask.bits != 0 {
+            self.masks.push(mask);
+        }
+    }
+
+    /// Add the masks of the preset called `name`. Returns false if there is
+    /// no such preset.
+    pub fn disable_preset(&mut self, name: &str) -> bool {
+        match CPUID_PRESETS.iter().find(|(n, _)| *n == name) {
+            Some((_, masks)) => {
+                for &mask in masks.iter() {
+                    self.disable(mask);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn extend(&mut self, other: &DisableCPUIDFeatures) {
+        self.masks.extend_from_slice(&other.masks);
     }
 
     pub fn any_features_disabled(&self) -> bool {
-        self.features_ecx != 0
-            || self.features_edx != 0
-            || self.extended_features_ebx != 0
-            || self.extended_features_ecx != 0
-            || self.extended_features_edx != 0
-            || self.xsave_features_eax != 0
+        !self.masks.is_empty()
     }
 
     pub fn amend_cpuid_data(&self, eax_in: u32, ecx_in: u32, cpuid_data: &mut CPUIDData) {
         match eax_in {
             CPUID_GETFEATURES => {
-                cpuid_data.ecx &= !(CPUID_RDRAND_FLAG | self.features_ecx);
-                cpuid_data.edx &= !self.features_edx;
+                cpuid_data.ecx &= !CPUID_RDRAND_FLAG;
             }
             CPUID_GETEXTENDEDFEATURES => {
                 if ecx_in == 0 {
-                    cpuid_data.ebx &=
-                        !(CPUID_RDSEED_FLAG | CPUID_RTM_FLAG | self.extended_features_ebx);
-                    cpuid_data.ecx &= !self.extended_features_ecx;
-                    cpuid_data.edx &= !self.extended_features_edx;
+                    cpuid_data.ebx &= !(CPUID_RDSEED_FLAG | CPUID_RTM_FLAG);
                 }
             }
             CPUID_GETXSAVE => {
@@ -178,11 +365,57 @@ impl DisableCPUIDFeatures {
                     // Always disable XSAVEOPT because it's nondeterministic,
                     // possibly depending on context switching behavior. Intel
                     // recommends not using it from user space.
-                    cpuid_data.eax &= !(CPUID_XSAVEOPT_FLAG | self.xsave_features_eax);
+                    cpuid_data.eax &= !CPUID_XSAVEOPT_FLAG;
                 }
             }
             _ => (),
         }
+        for mask in self.masks.iter().filter(|m| m.applies_to(eax_in, ecx_in)) {
+            let register = match mask.register {
+                CPUIDRegister::Eax => &mut cpuid_data.eax,
+                CPUIDRegister::Ebx => &mut cpuid_data.ebx,
+                CPUIDRegister::Ecx => &mut cpuid_data.ecx,
+                CPUIDRegister::Edx => &mut cpuid_data.edx,
+            };
+            *register &= !mask.bits;
+        }
+    }
+}
+
+/// A comma separated list of masks and preset names, or rr's `<CCC>[,<DDD>]`
+/// for the EAX=1 ECX and EDX bits.
+impl FromStr for DisableCPUIDFeatures {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(|item| item.trim()).collect();
+        let mut res = Self::new();
+        if items.len() <= 2 && items.iter().all(|item| parse_cpuid_number(item).is_ok()) {
+            let ecx = parse_cpuid_number(items[0])?;
+            let edx = match items.get(1) {
+                Some(item) => parse_cpuid_number(item)?,
+                None => 0,
+            };
+            res.disable(CPUIDMask::new(
+                CPUID_GETFEATURES,
+                None,
+                CPUIDRegister::Ecx,
+                ecx,
+            ));
+            res.disable(CPUIDMask::new(
+                CPUID_GETFEATURES,
+                None,
+                CPUIDRegister::Edx,
+                edx,
+            ));
+            return Ok(res);
+        }
+        for item in items {
+            if !res.disable_preset(item) {
+                res.disable(item.parse::<CPUIDMask>()?);
+            }
+        }
+        Ok(res)
     }
 }
 
@@ -211,6 +444,18 @@ impl TraceUuid {
     }
 }
 
+/// What to do when a tracee tries to use io_uring. The kernel writes
+/// completions into memory shared with the tracee without any syscall we could
+/// record, so io_uring can't be recorded.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IoUringPolicy {
+    /// Fail io_uring_setup() and friends with ENOSYS, so programs fall back to
+    /// regular syscalls as they would on a kernel without io_uring.
+    Disable,
+    /// Stop recording with an error.
+    Refuse,
+}
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum SyscallBuffering {
     EnableSycallBuf,
@@ -259,11 +504,30 @@ pub struct RecordSession {
 
     use_file_cloning_: bool,
     use_read_cloning_: bool,
+    share_copied_files_: bool,
+    isolate_file_access_: bool,
+    /// Set by `--stap-sdt`.
+    stap_sdt_: bool,
+    /// The SDT probes of each executable file tracees mapped, by device and
+    /// inode. Only used with `--stap-sdt`.
+    sdt_probes_: RefCell<HashMap<(dev_t, ino_t), Rc<Vec<Rc<SdtProbe>>>>>,
+    aslr_disabled_: bool,
+    io_uring_policy_: IoUringPolicy,
+    no_patch_libraries_: Vec<String>,
     /// When true, try to increase the probability of finding bugs.
     enable_chaos_: bool,
     asan_active_: bool,
     /// When true, wait for all tracees to exit before finishing recording.
     wait_for_all_: bool,
+    error_injector_: RefCell<ErrorInjector>,
+    /// Processes killed by a core-dumping signal: their tgid, the signal and
+    /// the event at which it was delivered.
+    core_dump_deaths_: RefCell<Vec<(pid_t, Sig, FrameTime)>>,
+    /// Set by `--seed`. Supplies the bytes returned by getrandom() and
+    /// /dev/(u)random reads.
+    seeded_random_: Option<RefCell<StdRng>>,
+    /// Set by `--cgroup-limit`.
+    cgroup_limits_: Vec<CgroupLimit>,
 
     /// DIFF NOTE: This is simply a normal string in rr.
     /// `None` means the user did not provide any trace dir options and we need
@@ -302,6 +566,119 @@ impl RecordSession {
         flags: &RecordCommand,
         asan_active: bool,
     ) -> SessionSharedPtr {
+        let rec_sess = RecordSession::new_inner(&flags.args[0], flags, asan_active);
+
+        let env: Vec<OsString> = envp
+            .iter()
+            .map(|(k, v)| -> OsString {
+                let mut kv = k.clone();
+                kv.push("=");
+                kv.push(v);
+                kv
+            })
+            .collect();
+        let error_fd: ScopedFd = rec_sess.create_spawn_task_error_pipe();
+        let socket_fd = rec_sess.tracee_socket_fd();
+
+        let mut rc = RecordSession::into_shared(rec_sess, flags);
+        let t = TaskInner::spawn(
+            (*rc).as_ref(),
+            &error_fd,
+            socket_fd,
+            SaveTraceeFdNumber::SaveToSession,
+            exe_path,
+            &flags.args,
+            &env,
+            None,
+        );
+        // The initial_thread_group is set only once so its worth it to use
+        // unsafe
+        unsafe {
+            Rc::get_mut_unchecked(&mut rc)
+                .as_record_mut()
+                .unwrap()
+                .initial_thread_group = Some(t.thread_group());
+        }
+        rc.on_create_task(t);
+        rc
+    }
+
+    /// Record the running process `pid` from its current state onwards, for
+    /// `rd record --attach`. The trace starts with a snapshot of the process
+    /// that replay restores as if it were the result of an exec.
+    pub fn attach(flags: &RecordCommand, pid: pid_t) -> SessionSharedPtr {
+        let exe = check_attachable(pid);
+        let cmd_line: Vec<OsString> = match fs::read(format!("/proc/{}/cmdline", pid)) {
+            Ok(data) => data
+                .split(|&c| c == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| OsStr::from_bytes(arg).to_owned())
+                .collect(),
+            Err(e) => clean_fatal!("Can't read command line of process {}: {:?}", pid, e),
+        };
+        let auxv = match fs::read(format!("/proc/{}/auxv", pid)) {
+            Ok(auxv) => auxv,
+            Err(e) => clean_fatal!("Can't read auxv of process {}: {:?}", pid, e),
+        };
+
+        let rec_sess = RecordSession::new_inner(&exe, flags, false);
+        rec_sess.trace_out.borrow_mut().set_attached(true);
+        let mut rc = RecordSession::into_shared(rec_sess, flags);
+        let (t, thread_tids) = TaskInner::attach((*rc).as_ref(), pid);
+        unsafe {
+            Rc::get_mut_unchecked(&mut rc)
+                .as_record_mut()
+                .unwrap()
+                .initial_thread_group = Some(t.thread_group());
+        }
+        rc.on_create_task(t.clone());
+        back_up_interrupted_syscall(&**t);
+
+        // Take the process through everything an exec would, except that its
+        // registers and address space stay as they are.
+        let extra_regs = t.extra_regs_ref().clone();
+        t.post_exec_for_exe(&exe);
+        t.set_extra_regs(&extra_regs);
+        t.vm().init_traced_syscall_ip(&**t);
+        set_up_attached_process(&**t);
+        t.vm().post_exec_syscall(&**t);
+
+        let rt = t.as_record_task().unwrap();
+        let mut sighandlers = Sighandlers::new();
+        sighandlers.init_from_task(rt);
+        *rt.sighandlers.borrow_mut() = Rc::new(RefCell::new(sighandlers));
+        set_up_attached_thread(rt);
+
+        // The other threads join the process as if it had just created them.
+        let mut threads: Vec<TaskSharedPtr> = Vec::new();
+        for tid in thread_tids {
+            let thread = t.clone_task(
+                CloneReason::AttachedThread,
+                CloneFlags::CLONE_SHARE_VM
+                    | CloneFlags::CLONE_SHARE_FILES
+                    | CloneFlags::CLONE_SHARE_SIGHANDLERS
+                    | CloneFlags::CLONE_SHARE_THREAD_GROUP,
+                RemotePtr::null(),
+                RemotePtr::null(),
+                RemotePtr::null(),
+                tid,
+                None,
+                rc.next_task_serial(),
+                None,
+            );
+            rc.on_create_task(thread.clone());
+            back_up_interrupted_syscall(&**thread);
+            set_up_attached_thread(thread.as_rec_unwrap());
+            threads.push(thread);
+        }
+
+        let thread_rts: Vec<&RecordTask> = threads.iter().map(|tt| tt.as_rec_unwrap()).collect();
+        record_attach_snapshot(rt, &thread_rts, &exe, &cmd_line, auxv);
+        rc
+    }
+
+    /// The part of `new()` that doesn't involve the initial tracee.
+    fn new_inner(trace_name: &OsStr, flags: &RecordCommand, asan_active: bool) -> RecordSession {
         let sched = Scheduler::new(flags.max_ticks, flags.always_switch);
 
         if flags.scarce_fds {
@@ -314,10 +691,11 @@ impl RecordSession {
         let mut rec_sess = RecordSession {
             session_inner: SessionInner::new(),
             trace_out: RefCell::new(TraceWriter::new(
-                &flags.args[0],
+                trace_name,
                 choose_cpu(flags.bind_cpu),
                 flags.output_trace_dir.as_deref(),
                 TicksSemantics::default(),
+                flags.compression,
             )),
             scheduler_: sched,
             initial_thread_group: Default::default(),
@@ -332,9 +710,22 @@ impl RecordSession {
             use_syscall_buffer_: flags.use_syscall_buffer == SyscallBuffering::EnableSycallBuf,
             use_file_cloning_: flags.use_file_cloning,
             use_read_cloning_: flags.use_read_cloning,
+            share_copied_files_: flags.share_copied_files,
+            isolate_file_access_: flags.isolate_file_access,
+            stap_sdt_: flags.stap_sdt,
+            sdt_probes_: Default::default(),
+            aslr_disabled_: flags.disable_aslr,
+            io_uring_policy_: flags.io_uring_policy,
+            no_patch_libraries_: flags.no_patch_libraries.clone(),
             enable_chaos_: Default::default(),
             asan_active_: asan_active,
             wait_for_all_: flags.wait_for_all,
+            error_injector_: RefCell::new(ErrorInjector::new(&flags.inject_errors)),
+            core_dump_deaths_: Default::default(),
+            seeded_random_: flags
+                .seed
+                .map(|seed| RefCell::new(StdRng::seed_from_u64(seed))),
+            cgroup_limits_: flags.cgroup_limits.clone(),
             output_trace_dir: flags.output_trace_dir.clone(),
         };
 
@@ -349,19 +740,19 @@ impl RecordSession {
             SessionInner::has_cpuid_faulting(),
             &flags.disable_cpuid_features,
         );
+        rec_sess
+            .trace_out
+            .borrow_mut()
+            .set_aslr_disabled(flags.disable_aslr);
+        rec_sess
+            .trace_out
+            .borrow_mut()
+            .set_cgroup_limits(&flags.cgroup_limits);
 
-        let env: Vec<OsString> = envp
-            .iter()
-            .map(|(k, v)| -> OsString {
-                let mut kv = k.clone();
-                kv.push("=");
-                kv.push(v);
-                kv
-            })
-            .collect();
-        let error_fd: ScopedFd = rec_sess.create_spawn_task_error_pipe();
-        let socket_fd = rec_sess.tracee_socket_fd();
+        rec_sess
+    }
 
+    fn into_shared(rec_sess: RecordSession, flags: &RecordCommand) -> SessionSharedPtr {
         let mut rc: SessionSharedPtr = Rc::new(Box::new(rec_sess));
         let weak_self = Rc::downgrade(&rc);
         // We never change the weak_self pointer so its a good idea to use
@@ -382,32 +773,13 @@ impl RecordSession {
         match flags.num_cores {
             Some(num_cores) => {
                 // Set the number of cores reported, possibly overriding the chaos mode
-                // setting.
+                // setting. This also regenerates the affinity mask.
                 rs.scheduler().set_num_cores(num_cores);
             }
             // This is necessary for the default case
             None => rs.scheduler().regenerate_affinity_mask(),
         }
 
-        let t = TaskInner::spawn(
-            (*rc).as_ref(),
-            &error_fd,
-            socket_fd,
-            SaveTraceeFdNumber::SaveToSession,
-            exe_path,
-            &flags.args,
-            &env,
-            None,
-        );
-        // The initial_thread_group is set only once so its worth it to use
-        // unsafe
-        unsafe {
-            Rc::get_mut_unchecked(&mut rc)
-                .as_record_mut()
-                .unwrap()
-                .initial_thread_group = Some(t.thread_group());
-        }
-        rc.on_create_task(t);
         rc
     }
 
@@ -513,6 +885,103 @@ impl RecordSession {
         self.use_file_cloning_
     }
 
+    pub fn share_copied_files(&self) -> bool {
+        self.share_copied_files_
+    }
+
+    /// True if `--isolate-file-access` was given.
+    pub fn isolate_file_access(&self) -> bool {
+        self.isolate_file_access_
+    }
+
+    /// True if `--stap-sdt` was given.
+    pub fn stap_sdt(&self) -> bool {
+        self.stap_sdt_
+    }
+
+    /// The SDT probes declared by the file `km` maps. Each file is only read
+    /// once.
+    pub fn sdt_probes_in(&self, km: &KernelMapping) -> Rc<Vec<Rc<SdtProbe>>> {
+        let key = (km.device(), km.inode());
+        if let Some(probes) = self.sdt_probes_.borrow().get(&key) {
+            return probes.clone();
+        }
+        let probes = match fs::read(km.fsname()) {
+            Ok(data) => sdt_probes(&data).into_iter().map(Rc::new).collect(),
+            Err(e) => {
+                log!(
+                    LogWarn,
+                    "Can't read {:?} to look for SDT probes: {}",
+                    km.fsname(),
+                    e
+                );
+                Vec::new()
+            }
+        };
+        let probes = Rc::new(probes);
+        self.sdt_probes_.borrow_mut().insert(key, probes.clone());
+        probes
+    }
+
+    /// True if `--no-aslr` was given.
+    pub fn aslr_disabled(&self) -> bool {
+        self.aslr_disabled_
+    }
+
+    pub fn io_uring_policy(&self) -> IoUringPolicy {
+        self.io_uring_policy_
+    }
+
+    /// Libraries excluded from syscall patching with --no-patch-library.
+    pub fn no_patch_libraries(&self) -> &[String] {
+        &self.no_patch_libraries_
+    }
+
+    pub fn core_dump_deaths(&self) -> Ref<Vec<(pid_t, Sig, FrameTime)>> {
+        self.core_dump_deaths_.borrow()
+    }
+
+    /// If `--inject-errors` says the syscall `t` is entering should fail, return
+    /// the errno to fail it with. Each call is counted once, even if the kernel
+    /// restarts it.
+    pub fn maybe_inject_error(&self, t: &RecordTask) -> Option<i32> {
+        let mut injector = self.error_injector_.borrow_mut();
+        if injector.is_empty() {
+            return None;
+        }
+        let (syscallno, arch) = {
+            let ev = t.ev();
+            if ev.syscall_event().is_restart {
+                // The kernel is restarting a call that was interrupted. It was
+                // already counted (and not failed) when it was first entered.
+                return None;
+            }
+            (ev.syscall_event().number, ev.syscall_event().arch())
+        };
+        injector.maybe_inject(syscallno, arch, t.regs_ref().arg1_signed())
+    }
+
+    /// True if `--seed` was given.
+    pub fn is_seeded(&self) -> bool {
+        self.seeded_random_.is_some()
+    }
+
+    /// The next `len` bytes of the `--seed` stream, if there is one. Like
+    /// the kernel's getrandom(), returns at most MAX_SEEDED_RANDOM_BYTES at a
+    /// time.
+    pub fn seeded_random_bytes(&self, len: usize) -> Option<Vec<u8>> {
+        self.seeded_random_.as_ref().map(|rng| {
+            let mut buf = vec![0u8; min(len, MAX_SEEDED_RANDOM_BYTES)];
+            rng.borrow_mut().fill_bytes(&mut buf);
+            buf
+        })
+    }
+
+    /// The `--cgroup-limit` that spoofs reads of `path`, if any.
+    pub fn cgroup_limit(&self, path: &OsStr) -> Option<&CgroupLimit> {
+        self.cgroup_limits_.iter().rev().find(|l| l.matches(path))
+    }
+
     pub fn set_ignore_sig(&mut self, maybe_sig: Option<Sig>) {
         self.ignore_sig = maybe_sig;
     }
@@ -753,6 +1222,17 @@ impl RecordSession {
             return true;
         }
 
+        if maybe_sig == sig::SIGTRAP
+            && self.stap_sdt()
+            && handle_sdt_probe_breakpoint(t.as_rec_unwrap())
+        {
+            return true;
+        }
+
+        if maybe_sig == sig::SIGTRAP && self.handle_tick_step(t.as_rec_unwrap(), step_state) {
+            return true;
+        }
+
         let deterministic: SignalDeterministic = is_deterministic_signal(&***t);
         // The kernel might have forcibly unblocked the signal. Check whether it
         // was blocked now, before we update our cached sigmask.
@@ -864,6 +1344,37 @@ impl RecordSession {
         true
     }
 
+    /// Swallow the SIGTRAP of a singlestep made to count ticks without a PMU
+    /// (see `should_step_for_ticks`). Once the timeslice is used up, preempt
+    /// the task like a TIME_SLICE_SIGNAL would.
+    fn handle_tick_step(&self, t: &RecordTask, step_state: &mut StepState) -> bool {
+        if t.stepping_for_ticks.get() != Some(true)
+            || !is_singlestep_resume(t.how_last_execution_resumed())
+            || t.emulated_ptrace_cont_command.get() == PTRACE_SINGLESTEP
+            || t.emulated_ptrace_cont_command.get() == PTRACE_SYSEMU_SINGLESTEP
+        {
+            return false;
+        }
+        let trap_reasons = t.compute_trap_reasons();
+        if !trap_reasons.singlestep || trap_reasons.breakpoint || trap_reasons.watchpoint {
+            return false;
+        }
+
+        if t.tick_count() >= self.scheduler().current_timeslice_end() {
+            log!(
+                LogDebug,
+                "  {} used up its timeslice while stepping",
+                t.tid()
+            );
+            t.push_event(Event::sched());
+            // Allow switching after a SCHED. We'll flush the SCHED if and only
+            // if we really do a switch.
+            self.last_task_switchable.set(Switchable::AllowSwitch);
+            step_state.continue_type = ContinueType::DontContinue;
+        }
+        true
+    }
+
     fn handle_ptrace_event(
         &self,
         t: &mut TaskSharedPtr,
@@ -1151,6 +1662,12 @@ impl RecordSession {
                         128 /* Redzone */ +
                         /* this returns 512 when XSAVE unsupported */
                         xsave_area_size();
+                        // A frame on an alternate signal stack ends at the
+                        // stack's top, which for a MINSIGSTKSZ-sized stack is
+                        // well within our overestimate.
+                        if let Some(alt) = did_set_up_sigframe(t) {
+                            sigframe_size = min(sigframe_size, alt.end() - t.regs_ref().sp());
+                        }
 
                         t.ev_mut().transform(EventType::EvSignalHandler);
                         t.signal_delivered(sig);
@@ -1252,6 +1769,10 @@ impl RecordSession {
                 // without letting the task execute at least one instruction, which
                 // we don't want to do here.
                 if is_fatal && Some(sig) != self.get_continue_through_sig() {
+                    let dumps_core = default_action(sig) == SignalAction::DumpCore;
+                    if dumps_core {
+                        suppress_core_dump(t);
+                    }
                     preinject_signal(t);
                     t.resume_execution(
                         ResumeRequest::ResumeCont,
@@ -1261,6 +1782,13 @@ impl RecordSession {
                     );
                     log!(LogWarn,   "Delivered core-dumping signal; may misrecord CLONE_CHILD_CLEARTID memory race");
                     t.thread_group().borrow().destabilize();
+                    if dumps_core {
+                        // The signal delivery event was just recorded.
+                        let event = self.trace_writer().time() - 1;
+                        self.core_dump_deaths_
+                            .borrow_mut()
+                            .push((t.tgid(), sig, event));
+                    }
                 }
 
                 t.signal_delivered(sig);
@@ -1517,20 +2045,18 @@ impl RecordSession {
         let mut si: USiginfo = unsafe { mem::zeroed() };
         let mut ssig: StashedSignal;
         let mut sig: Sig;
-        let mut ssig_addr: *const StashedSignal;
 
         {
             let t = t_shr.as_rec_unwrap();
             loop {
                 match t.peek_stashed_sig_to_deliver() {
                     Some(ssig_obtained) => {
-                        ssig_addr = ssig_obtained;
-                        ssig = unsafe { *ssig_obtained };
+                        ssig = ssig_obtained;
                         si.linux_api = ssig.siginfo;
                         sig = Sig::try_from(unsafe { si.linux_api.si_signo }).unwrap();
                         if Some(sig) == self.get_ignore_sig() {
                             log!(LogDebug, "Declining to deliver {} by user request", sig);
-                            t.pop_stash_sig(ssig_addr);
+                            t.pop_stash_sig(ssig.id);
                             t.stashed_signal_processed();
                         } else {
                             break;
@@ -1604,7 +2130,7 @@ impl RecordSession {
         }
 
         step_state.continue_type = ContinueType::DontContinue;
-        t_shr.as_rec_unwrap().pop_stash_sig(ssig_addr);
+        t_shr.as_rec_unwrap().pop_stash_sig(ssig.id);
         if t_shr.as_rec_unwrap().ev().event_type() != EventType::EvSignal {
             t_shr.as_rec_unwrap().stashed_signal_processed();
         }
@@ -1612,6 +2138,14 @@ impl RecordSession {
         true
     }
 
+    /// Without a PMU ticks only advance while a task is singlestepped, so we
+    /// step `t` whenever it may have to be preempted or interrupted before its
+    /// next system call: when other tasks could need to run, or when a signal
+    /// handler could run in it.
+    fn should_step_for_ticks(&self, t: &RecordTask) -> bool {
+        self.tasks().len() > 1 || t.sighandlers.borrow().borrow().has_user_handler()
+    }
+
     fn task_continue(&self, step_state: StepState) {
         let t = self.scheduler().current().unwrap().clone();
 
@@ -1705,9 +2239,15 @@ impl RecordSession {
                 None => (),
             }
 
-            let mut singlestep = t.as_rec_unwrap().emulated_ptrace_cont_command.get()
-                == PTRACE_SINGLESTEP
-                || t.as_rec_unwrap().emulated_ptrace_cont_command.get() == PTRACE_SYSEMU_SINGLESTEP;
+            let rt = t.as_rec_unwrap();
+            if perf_counters::pmu_disabled() && rt.stepping_for_ticks.get().is_none() {
+                rt.stepping_for_ticks
+                    .set(Some(self.should_step_for_ticks(rt)));
+            }
+
+            let mut singlestep = rt.emulated_ptrace_cont_command.get() == PTRACE_SINGLESTEP
+                || rt.emulated_ptrace_cont_command.get() == PTRACE_SYSEMU_SINGLESTEP
+                || rt.stepping_for_ticks.get() == Some(true);
 
             let t_at_ip = t.ip();
             if singlestep && is_at_syscall_instruction(&**t, t_at_ip) {
@@ -1907,11 +2447,155 @@ impl RecordSession {
 
         log!(LogInfo, "Processing termination request ...");
 
-        // This will write unstable exit events for all tasks.
-        self.kill_all_tasks();
+        if self.trace_writer().attached() {
+            // Give the process back to the user rather than killing it.
+            self.detach_all_tasks();
+        } else {
+            // This will write unstable exit events for all tasks.
+            self.kill_all_tasks();
+        }
         self.close_trace_writer(CloseStatus::CloseOk);
     }
 
+    /// Let the process rd attached to, and every task it created since, carry
+    /// on without rd: undo what rd did to each task, detach from it and forget
+    /// about it. Like `kill_all_tasks()`, this writes unstable exit events for
+    /// all tasks. The rd page and the thread locals stay mapped; nothing in the
+    /// process refers to them.
+    fn detach_all_tasks(&self) {
+        let tasks: Vec<TaskSharedPtr> = self.task_map.borrow().values().cloned().collect();
+        let mut cleaned_fd_tables: Vec<FdTableSharedPtr> = Vec::new();
+        for t in &tasks {
+            let rt = t.as_rec_unwrap();
+            let mut pending_sig: i32 = 0;
+            if !rt.is_stopped.get()
+                && !rt.unstable.get()
+                && rt.ptrace_if_alive(PTRACE_INTERRUPT, RemotePtr::null(), &mut PtraceData::None)
+            {
+                rt.wait(None);
+                // A signal that arrived just now would be lost otherwise.
+                let sig = rt.maybe_stop_sig();
+                if sig.is_sig() && sig.unwrap_sig() != perf_counters::TIME_SLICE_SIGNAL {
+                    pending_sig = sig.unwrap_sig().as_raw();
+                }
+            }
+            rt.hpc.borrow_mut().stop();
+            if !rt.is_stopped.get()
+                || rt.unstable.get()
+                || rt.seen_ptrace_exit_event.get()
+                || rt.maybe_ptrace_event() == PTRACE_EVENT_EXIT
+            {
+                // The task is exiting. Detaching lets it finish.
+                rt.fallible_ptrace(PTRACE_DETACH, RemotePtr::null(), &mut PtraceData::None);
+                continue;
+            }
+
+            let syscall_state = if rt.ev().event_type() == EventType::EvSyscall {
+                Some(rt.ev().syscall_event().state)
+            } else {
+                None
+            };
+            if let Some(state) = syscall_state {
+                match state {
+                    SyscallState::EnteringSyscallPtrace | SyscallState::EnteringSyscall => {
+                        // Skip the syscall, and have the task enter it afresh
+                        // once it's detached.
+                        rec_abort_prepared_syscall(rt);
+                        let mut r = rt.regs_ref().clone();
+                        r.set_syscallno(r.original_syscallno());
+                        r.set_original_syscallno(-1);
+                        r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
+                        rt.set_regs(&r);
+                    }
+                    SyscallState::ProcessingSyscall => {
+                        rec_abort_prepared_syscall(rt);
+                        if !rt.regs_ref().syscall_may_restart() {
+                            log!(
+                                LogWarn,
+                                "{} completed {} as rd detached; its results may be lost",
+                                rt.tid(),
+                                syscall_name(rt.ev().syscall_event().number, rt.arch())
+                            );
+                        }
+                        back_up_interrupted_syscall(rt);
+                    }
+                    _ => (),
+                }
+            }
+
+            {
+                let fds = rt.fd_table();
+                let mut remote = AutoRemoteSyscalls::new(rt);
+                let arch = remote.arch();
+                if !cleaned_fd_tables.iter().any(|f| Rc::ptr_eq(f, &fds)) {
+                    for &fd in &[
+                        RD_MAGIC_SAVE_DATA_FD,
+                        RD_RESERVED_ROOT_DIR_FD,
+                        self.tracee_fd_number(),
+                    ] {
+                        if fd >= 0 {
+                            rd_syscall!(remote, syscall_number_for_close(arch), fd);
+                        }
+                    }
+                    cleaned_fd_tables.push(fds);
+                }
+                rd_infallible_syscall!(
+                    remote,
+                    syscall_number_for_prctl(arch),
+                    PR_SET_TSC,
+                    rt.tsc_mode.get()
+                );
+                if SessionInner::has_cpuid_faulting() {
+                    rd_infallible_syscall!(
+                        remote,
+                        syscall_number_for_arch_prctl(arch),
+                        ARCH_SET_CPUID,
+                        rt.cpuid_mode.get()
+                    );
+                }
+                if !rt.scratch_ptr.get().is_null() {
+                    rd_infallible_syscall!(
+                        remote,
+                        syscall_number_for_munmap(arch),
+                        rt.scratch_ptr.get().as_usize(),
+                        rt.scratch_size.get()
+                    );
+                }
+            }
+            rt.flush_regs();
+
+            log!(LogDebug, "detaching from {} ...", rt.tid());
+            rt.fallible_ptrace(
+                PTRACE_DETACH,
+                RemotePtr::null(),
+                &mut PtraceData::ReadWord(pending_sig as usize),
+            );
+            for stashed in rt.stashed_signals.borrow().iter() {
+                // A deterministic signal will be raised again when the task
+                // re-executes the instruction that raised it.
+                if stashed.deterministic == SignalDeterministic::NondeterministicSig {
+                    unsafe {
+                        libc::syscall(
+                            SYS_tgkill,
+                            rt.real_tgid(),
+                            rt.tid(),
+                            stashed.siginfo.si_signo,
+                        );
+                    }
+                }
+            }
+        }
+
+        for t in tasks.iter().rev() {
+            self.task_map.borrow_mut().remove(&t.rec_tid());
+            t.thread_group().borrow().destabilize();
+            t.destroy(Some(false), self);
+        }
+        // No tasks are left to kill; this forgets the address spaces and
+        // thread groups.
+        self.kill_all_tasks();
+    }
+
     /// Close trace output without flushing syscall buffers or writing
     /// task exit/termination records to the trace.
     pub fn close_trace_writer(&self, status: CloseStatus) {
@@ -2000,8 +2684,10 @@ impl RecordSession {
             .write_task_event(&TraceTaskEvent::for_clone(
                 rec_tid,
                 tid,
+                t.own_namespace_tid(),
                 own_namespace_tid,
-                CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM,
+                (CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM)
+                    as u64,
             ));
 
         self.trace_writer_mut()
@@ -2126,6 +2812,30 @@ impl RecordSession {
     }
 }
 
+/// Stop the kernel from writing a core file when `t`'s process dies of the
+/// core-dumping signal we're about to deliver, by setting its RLIMIT_CORE to 0.
+/// The process never runs again so it can't observe the change, and the
+/// resulting wait status (without the core dump bit) is recorded like any
+/// other. The state at death is in the trace; `rd replay -g` to that event and
+/// gdb's `gcore` produce the core file instead.
+///
+/// A `|program` core_pattern is still run by the kernel, but is told the limit
+/// is 0 and is expected to honor it.
+fn suppress_core_dump(t: &RecordTask) {
+    let no_core = rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { prlimit(t.tgid(), RLIMIT_CORE, &no_core, ptr::null_mut()) } < 0 {
+        log!(
+            LogWarn,
+            "Couldn't suppress the core dump of {}: {:?}",
+            t.tgid(),
+            Errno::last()
+        );
+    }
+}
+
 /// Get `t` into a state where resume_execution with a signal will actually work.
 fn preinject_signal(t: &RecordTask) -> bool {
     let sig = Sig::try_from(t.ev().signal_event().siginfo.si_signo).unwrap();
@@ -2738,6 +3448,121 @@ fn lookup_by_path<T: AsRef<OsStr>>(file: T) -> OsString {
     }
 }
 
+/// Check that rd can record process `pid` from its current state, and return
+/// the path of its executable.
+fn check_attachable(pid: pid_t) -> OsString {
+    let exe = match readlink(format!("/proc/{}/exe", pid).as_str()) {
+        Ok(exe) => exe,
+        Err(e) => clean_fatal!("Can't attach to process {}: {:?}", pid, e),
+    };
+    if let Err(e) = fs::read_dir(format!("/proc/{}/task", pid)) {
+        clean_fatal!("Can't attach to process {}: {:?}", pid, e);
+    }
+    let mut ident = [0u8; 5];
+    let read_ident =
+        fs::File::open(format!("/proc/{}/exe", pid)).and_then(|mut f| f.read_exact(&mut ident));
+    if read_ident.is_err() || &ident[0..4] != b"\x7fELF" || ident[4] != ELFCLASS64 {
+        clean_fatal!("Can't attach to process {}: it isn't a 64-bit process", pid);
+    }
+
+    // The rd page and the thread locals are mapped at fixed addresses.
+    let reserved = MemoryRange::from_range(
+        AddressSpace::rd_page_start(),
+        AddressSpace::preload_thread_locals_start() + AddressSpace::preload_thread_locals_size(),
+    );
+    for km in KernelMapIterator::new_from_tid(pid) {
+        if km.intersects(&reserved) {
+            clean_fatal!(
+                "Can't attach to process {}: {} overlaps rd's own mappings",
+                pid,
+                km
+            );
+        }
+    }
+
+    for fd in &[RD_MAGIC_SAVE_DATA_FD, RD_RESERVED_ROOT_DIR_FD] {
+        if lstat(format!("/proc/{}/fd/{}", pid, fd).as_str()).is_ok() {
+            clean_fatal!(
+                "Can't attach to process {}: rd needs its fd {} for itself",
+                pid,
+                fd
+            );
+        }
+    }
+
+    exe.into()
+}
+
+/// If `t` was stopped in a syscall that will be restarted, back it up to
+/// before the syscall, so that it is entered afresh once rd resumes it.
+fn back_up_interrupted_syscall(t: &dyn Task) {
+    let mut r = t.regs_ref().clone();
+    if r.original_syscallno() >= 0 && r.syscall_may_restart() {
+        let syscallno = if r.syscall_result_signed() == -ERESTART_RESTARTBLOCK as isize {
+            syscall_number_for_restart_syscall(r.arch()) as isize
+        } else {
+            r.original_syscallno()
+        };
+        r.set_ip(r.ip().decrement_by_syscall_insn_length(r.arch()));
+        r.set_syscallno(syscallno);
+    }
+    r.set_original_syscallno(-1);
+    t.set_regs(&r);
+}
+
+/// Do for thread `t` of the process rd attached to what `set_up_process()`
+/// and the exec do for a spawned tracee's threads, and find out the state of
+/// its own that rd keeps track of. TSC and CPUID faulting are per-thread.
+fn set_up_attached_thread(t: &RecordTask) {
+    {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let arch = remote.arch();
+        rd_infallible_syscall!(
+            remote,
+            syscall_number_for_prctl(arch),
+            PR_SET_TSC,
+            PR_TSC_SIGSEGV
+        );
+        if SessionInner::has_cpuid_faulting() {
+            rd_infallible_syscall!(
+                remote,
+                syscall_number_for_arch_prctl(arch),
+                ARCH_SET_CPUID,
+                0
+            );
+        }
+    }
+    init_sigaltstack_from_task(t);
+
+    let mut head: usize = 0;
+    let mut len: usize = 0;
+    if 0 == unsafe { libc::syscall(SYS_get_robust_list, t.tid(), &mut head, &mut len) } {
+        t.robust_futex_list.set(RemotePtr::new(head));
+        t.robust_futex_list_len.set(len);
+    }
+    t.tid_futex.set(tid_address(t));
+}
+
+/// The address set_tid_address() or CLONE_CHILD_CLEARTID gave the kernel for
+/// thread `t`, or null if the kernel can't tell.
+fn tid_address(t: &RecordTask) -> RemotePtr<i32> {
+    let mut remote = AutoRemoteSyscalls::new(t);
+    let arch = remote.arch();
+    let mut mem = AutoRestoreMem::new(&mut remote, None, size_of::<usize>());
+    let addr = mem.get().unwrap();
+    let ret = rd_syscall!(
+        mem,
+        syscall_number_for_prctl(arch),
+        PR_GET_TID_ADDRESS,
+        addr.as_usize()
+    );
+    if ret < 0 {
+        return RemotePtr::null();
+    }
+    let tid_addr: usize = read_val_mem(mem.task(), RemotePtr::<usize>::cast(addr), None);
+    RemotePtr::new(tid_addr)
+}
+
 fn inject_ld_helper_library(env: &mut Vec<(OsString, OsString)>, name: &OsStr, val: Vec<u8>) {
     // Our preload lib should come first if possible, because that will speed up
     // the loading of the other libraries; it's also a good idea to put our audit
@@ -3246,3 +4071,44 @@ unsafe fn assign_sigval<Arch: Architecture>(
     to.sival_ptr = Arch::from_remote_ptr(from.sival_ptr.rptr());
     to.sival_int = from.sival_int;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_disable_cpuid_features() {
+        let features: DisableCPUIDFeatures = "0x7.0:ebx=0x20, 0x80000001:ECX=64".parse().unwrap();
+        assert_eq!(
+            features.masks,
+            vec![
+                CPUIDMask::new(CPUID_GETEXTENDEDFEATURES, Some(0), CPUIDRegister::Ebx, 0x20),
+                CPUIDMask::new(0x8000_0001, None, CPUIDRegister::Ecx, 64),
+            ]
+        );
+        assert_eq!(features.masks[0].to_string(), "0x7.0:ebx=0x20");
+
+        let mut data = CPUIDData {
+            eax: !0,
+            ebx: !0,
+            ecx: !0,
+            edx: !0,
+        };
+        features.amend_cpuid_data(CPUID_GETEXTENDEDFEATURES, 1, &mut data);
+        assert_eq!(data.ebx, !0);
+        features.amend_cpuid_data(CPUID_GETEXTENDEDFEATURES, 0, &mut data);
+        assert_eq!(data.ebx, !(0x20 | CPUID_RDSEED_FLAG | CPUID_RTM_FLAG));
+
+        // rr's <CCC>[,<DDD>]
+        let features: DisableCPUIDFeatures = "0x10,3".parse().unwrap();
+        assert_eq!(
+            features,
+            DisableCPUIDFeatures::from((0x10, 3), (0, 0, 0), 0)
+        );
+
+        let features: DisableCPUIDFeatures = "avx512".parse().unwrap();
+        assert_eq!(features.masks.len(), 3);
+        assert!("avx512,0x1:esp=1".parse::<DisableCPUIDFeatures>().is_err());
+        assert!("0x7.0".parse::<DisableCPUIDFeatures>().is_err());
+    }
+}
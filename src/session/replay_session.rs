@@ -1,11 +1,11 @@
 use super::{
     address_space::{kernel_mapping::KernelMapping, MappingFlags},
     on_create_task_common,
-    session_common::kill_all_tasks,
+    session_common::{kill_all_tasks, kill_tasks},
     session_inner::{is_singlestep, PtraceSyscallSeccompOrdering},
     task::{
         replay_task::ReplayTaskIgnore,
-        task_common::{read_mem, read_val_mem},
+        task_common::{did_set_up_sigframe, read_mem, read_val_mem},
         task_inner::{TrapReasons, WriteFlags, MAX_TICKS_REQUEST},
     },
 };
@@ -17,15 +17,20 @@ use crate::{
         signal::siginfo_t,
     },
     cpuid_bug_detector::CPUIDBugDetector,
+    divergence_repair::BenignDivergences,
     emu_fs::{EmuFs, EmuFsSharedPtr},
     event::{Event, EventType, SignalDeterministic, SignalEventData, SyscallState},
     fast_forward::{fast_forward_through_instruction, FastForwardStatus},
     flags::Flags as ProgramFlags,
-    kernel_abi::{is_execve_syscall, syscall_number_for_exit, SupportedArch},
+    kernel_abi::{
+        is_at_syscall_instruction, is_execve_syscall, syscall_number_for_exit, SupportedArch,
+    },
     kernel_metadata::syscall_name,
+    lazy_mappings::{StagingFiles, StagingFilesSharedPtr},
     log::LogLevel::{LogDebug, LogError},
     perf_counters,
     perf_counters::{PerfCounters, TIME_SLICE_SIGNAL},
+    preflight::Environment,
     preload_interface::{
         mprotect_record, preload_globals, syscallbuf_hdr, syscallbuf_locked_why,
         SYS_rdcall_mprotect_record,
@@ -33,6 +38,7 @@ use crate::{
     registers::{MismatchBehavior, Registers},
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
+    replay_compat::CompatReport,
     replay_syscall::{
         rep_after_enter_syscall, rep_prepare_run_to_syscall, rep_process_syscall,
         restore_mapped_region,
@@ -59,29 +65,30 @@ use crate::{
     thread_group::ThreadGroupSharedPtr,
     ticks::Ticks,
     trace::{
+        trace_dir::trace_data_discarded,
         trace_frame::{FrameTime, TraceFrame},
-        trace_reader::TraceReader,
+        trace_reader::{TraceReader, ValidateSourceFile},
         trace_stream::{MappedData, TraceStream},
+        trace_task_event::TraceTaskEventVariant,
     },
     util::{
-        cpuid, cpuid_compatible, default_action, find_cpuid_record, running_under_rd,
-        should_checksum, should_dump_memory, trapped_instruction_at, trapped_instruction_len,
-        validate_process_memory, xcr0, xsave_enabled, CPUIDData, Completion, SignalAction,
-        TrappedInstruction, CPUID_GETFEATURES, CPUID_GETXSAVE, OSXSAVE_FEATURE_FLAG,
-        XSAVEC_FEATURE_FLAG,
+        default_action, find_cpuid_record, floor_page_size, running_under_rd, should_checksum,
+        should_dump_memory, trapped_instruction_at, trapped_instruction_len,
+        validate_process_memory, Completion, SignalAction, TrappedInstruction,
     },
     wait_status::WaitStatus,
 };
-use libc::{pid_t, ENOSYS, SIGBUS, SIGSEGV, SIGTRAP};
+use libc::{pid_t, CLONE_THREAD, CLONE_VM, ENOSYS, SIGBUS, SIGSEGV, SIGTRAP};
 use nix::sys::mman::{MapFlags, ProtFlags};
 use std::{
     cell::{Cell, Ref, RefCell, RefMut},
     cmp::min,
+    collections::HashMap,
     convert::{TryFrom, TryInto},
     ffi::{OsStr, OsString},
     intrinsics::copy_nonoverlapping,
     mem::size_of,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range},
     rc::{Rc, Weak},
 };
 
@@ -354,6 +361,10 @@ pub struct ReplaySession {
     syscall_bp_vm: RefCell<Option<AddressSpaceSharedPtr>>,
     // @TODO Set to the 0 address on init. More principled solution?!
     syscall_bp_addr: Cell<RemoteCodePtr>,
+    process_filter: Option<Rc<ProcessFilter>>,
+    /// Shared with clones, and with the session that restarts the trace from
+    /// the beginning (see `share_staging_files()`).
+    staging_files: RefCell<StagingFilesSharedPtr>,
 }
 
 #[derive(Copy, Clone)]
@@ -361,6 +372,88 @@ pub struct Flags {
     pub redirect_stdio: bool,
     pub share_private_mappings: bool,
     pub cpu_unbound: bool,
+    /// Divergences from the recording to patch over instead of aborting.
+    pub repair_divergences: BenignDivergences,
+    /// Only run this recorded process (and its threads) once it exists. See
+    /// `ProcessFilter`.
+    pub only_process: Option<pid_t>,
+}
+
+/// Which trace frames `rd replay --only-process` runs.
+///
+/// The chosen process's ancestors have to run until they create it, so every
+/// frame up to that point is replayed. After that only the frames of its
+/// tasks are: those of the threads it clones and of any CLONE_VM (vfork)
+/// children, which share its memory. The other processes' frames are skipped
+/// and their tasks killed; what they did to the chosen process is in its own
+/// frames already (pipe and socket data, futex results, signals), except for
+/// writes to memory they share with it, which replay can't reproduce without
+/// running them.
+pub struct ProcessFilter {
+    /// Time of the event that created the process
+    start_time: FrameTime,
+    /// When each recorded tid belonged to the process. Tids can be reused.
+    tids: HashMap<pid_t, Vec<Range<FrameTime>>>,
+}
+
+impl ProcessFilter {
+    /// Work out the tasks of process `pid` from the task events in `trace`.
+    pub fn new(trace: &mut TraceReader, pid: pid_t) -> ProcessFilter {
+        let mut filter = ProcessFilter {
+            start_time: 0,
+            tids: HashMap::new(),
+        };
+        let mut found = false;
+        let mut first = true;
+        let mut time: FrameTime = 0;
+        while let Some(event) = trace.read_task_event(Some(&mut time)) {
+            let tid = event.tid();
+            let is_first = first;
+            first = false;
+            let joins = match event.event_variant() {
+                // The initial process isn't cloned by anything.
+                TraceTaskEventVariant::Exec(_) => is_first && tid == pid,
+                TraceTaskEventVariant::Clone(ev) => {
+                    // The tid may have been used by an earlier task.
+                    if let Some(range) = filter.tids.get_mut(&tid).and_then(|r| r.last_mut()) {
+                        range.end = min(range.end, time);
+                    }
+                    let flags = ev.clone_flags();
+                    (!found && tid == pid && flags & CLONE_THREAD as u64 == 0)
+                        || (filter.contains(ev.parent_tid(), time)
+                            && flags & (CLONE_THREAD | CLONE_VM) as u64 != 0)
+                }
+                TraceTaskEventVariant::Exit(_) => false,
+            };
+            if joins {
+                if !found {
+                    found = true;
+                    filter.start_time = time;
+                }
+                filter
+                    .tids
+                    .entry(tid)
+                    .or_default()
+                    .push(time..FrameTime::MAX);
+            }
+        }
+        if !found {
+            clean_fatal!("There is no process {} in the trace", pid);
+        }
+        filter
+    }
+
+    /// Whether the task with recorded tid `tid` at `time` is one we replay.
+    fn contains(&self, tid: pid_t, time: FrameTime) -> bool {
+        self.tids
+            .get(&tid)
+            .map_or(false, |ranges| ranges.iter().any(|r| r.contains(&time)))
+    }
+
+    /// Whether the frame of `tid` at `time` should be skipped.
+    fn skips(&self, tid: pid_t, time: FrameTime) -> bool {
+        time > self.start_time && !self.contains(tid, time)
+    }
 }
 
 impl Drop for ReplaySession {
@@ -401,6 +494,8 @@ impl Clone for ReplaySession {
             // Implied
             syscall_bp_vm: Default::default(),
             syscall_bp_addr: Default::default(),
+            process_filter: self.process_filter.clone(),
+            staging_files: RefCell::new(self.staging_files()),
         }
     }
 }
@@ -527,6 +622,16 @@ impl ReplaySession {
         self.emu_fs.borrow()
     }
 
+    pub fn staging_files(&self) -> StagingFilesSharedPtr {
+        self.staging_files.borrow().clone()
+    }
+
+    /// Reuse the staging files of `other`, a session of the same trace, so that
+    /// its data doesn't need decompressing again.
+    pub fn share_staging_files(&self, other: &ReplaySession) {
+        *self.staging_files.borrow_mut() = other.staging_files();
+    }
+
     pub fn emufs_mut(&self) -> RefMut<'_, EmuFs> {
         self.emu_fs.borrow_mut()
     }
@@ -587,36 +692,53 @@ impl ReplaySession {
             fast_forward_status: Default::default(),
             syscall_bp_vm: Default::default(),
             syscall_bp_addr: Default::default(),
+            process_filter: None,
+            staging_files: RefCell::new(StagingFiles::create()),
         };
 
+        let trace_dir = rs.trace_in.borrow().dir();
+        if trace_data_discarded(&trace_dir) {
+            clean_fatal!(
+                "The data of trace {:?} was discarded by `rd record --record-children`.\n\
+                 Only `rd ps` and `rd trace-info` work on it.",
+                trace_dir
+            );
+        }
+
+        if let Some(pid) = flags.only_process {
+            let mut trace = rs.trace_in.borrow().clone();
+            rs.process_filter = Some(Rc::new(ProcessFilter::new(&mut trace, pid)));
+        }
+
         let semantics = rs.trace_in.borrow().ticks_semantics();
         rs.ticks_semantics_ = semantics;
         rs.advance_to_next_trace_frame();
         rs.trace_start_time
             .set(rs.current_trace_frame().monotonic_time());
 
-        if rs.trace_in.borrow().uses_cpuid_faulting() && !SessionInner::has_cpuid_faulting() {
-            clean_fatal!(
-                "Trace was recorded with CPUID faulting enabled, but this\n\
-                          system does not support CPUID faulting."
-            );
-        }
-        if !SessionInner::has_cpuid_faulting()
-            && !cpuid_compatible(rs.trace_in.borrow().cpuid_records())
-        {
+        CompatReport::check(&rs.trace_in.borrow()).enforce();
+        let recorded_without_pmu = rs.trace_in.borrow().recorded_without_pmu();
+        if recorded_without_pmu && !perf_counters::pmu_disabled() {
+            // Commands that replay one trace pick this up from its header.
             clean_fatal!(
-                "Trace was recorded on a machine with different CPUID values\n\
-                          and CPUID faulting is not enabled; replay will not work."
+                "Trace was recorded with --no-pmu, so its tick counts are singlestepped\n\
+                 instructions; pass --no-pmu to replay it with this command."
             );
         }
-        if !PerfCounters::supports_ticks_semantics(rs.ticks_semantics_) {
-            clean_fatal!(
-                "Trace was recorded on a machine that defines ticks differently\n\
-                          to this machine; replay will not work."
-            );
+        if !recorded_without_pmu && perf_counters::pmu_disabled() {
+            // --no-pmu may not have been passed at all: it's implied when the
+            // machine has no usable PMU.
+            match Environment::get().pmu_unavailable_reason() {
+                Some(why) => clean_fatal!(
+                    "Trace was recorded with perf counters, which are unavailable here: {}.\n\
+                     Replay it on a machine with hardware performance counters.",
+                    why
+                ),
+                None => clean_fatal!(
+                    "Trace was recorded with perf counters; replay will not work with --no-pmu."
+                ),
+            }
         }
-
-        check_xsave_compatibility(&rs.trace_in.borrow());
         rs
     }
 
@@ -625,12 +747,14 @@ impl ReplaySession {
             let global_time = self.current_frame_time();
             let tick_count = self.current_trace_frame().ticks();
             let monotonic_time = self.current_trace_frame().monotonic_time();
+            let realtime = self.current_trace_frame().realtime().unwrap_or(0.0);
             *self.current_trace_frame_mut() = TraceFrame::new_with(
                 global_time,
                 0,
                 Event::trace_termination(),
                 tick_count,
                 monotonic_time,
+                realtime,
             );
             return;
         }
@@ -699,6 +823,9 @@ impl ReplaySession {
         // If we restored from a checkpoint, the steps might have been
         // computed already in which case step.action will not be TstepNone.
         if self.current_step.get().action == ReplayTraceStepType::TstepNone {
+            if self.skip_frame_of_other_process() {
+                return result;
+            }
             let rc_t = self.setup_replay_one_trace_frame(maybe_rc_t);
             if self.current_step.get().action == ReplayTraceStepType::TstepNone {
                 // Already at the destination event.
@@ -819,7 +946,9 @@ impl ReplaySession {
                     t.vm().verify(t);
                 }
 
-                if has_deterministic_ticks(ev, self.current_step.get()) {
+                if perf_counters::pmu_disabled()
+                    || has_deterministic_ticks(ev, self.current_step.get())
+                {
                     self.check_ticks_consistency(t, ev);
                 }
 
@@ -850,6 +979,75 @@ impl ReplaySession {
         result
     }
 
+    /// With `--only-process`, skip the current frame if it belongs to another
+    /// process. The first time, the tasks of the other processes are killed.
+    /// Data the frame recorded into our tasks' memory is still written.
+    /// Returns true if the frame was skipped.
+    fn skip_frame_of_other_process(&self) -> bool {
+        let filter = match &self.process_filter {
+            Some(filter) => filter.clone(),
+            None => return false,
+        };
+        let time = self.current_frame_time();
+        if !filter.skips(self.current_trace_frame().tid(), time) {
+            return false;
+        }
+
+        let others: Vec<TaskSharedPtr> = self
+            .task_map
+            .borrow()
+            .values()
+            .filter(|t| !filter.contains(t.rec_tid(), time))
+            .cloned()
+            .collect();
+        if !others.is_empty() {
+            log!(
+                LogDebug,
+                "[event {}] killing {} tasks of other processes",
+                time,
+                others.len()
+            );
+            kill_tasks(self, &others);
+        }
+
+        let mut trace = self.trace_in.borrow_mut();
+        while trace
+            .read_mapped_region(
+                None,
+                Some(ValidateSourceFile::DontValidate),
+                None,
+                None,
+                None,
+            )
+            .is_some()
+        {}
+        while let Some(rec) = trace.peek_raw_data_metadata_for_frame() {
+            let rec_tid = rec.rec_tid;
+            let maybe_t = if filter.contains(rec_tid, time) {
+                self.find_task_from_rec_tid(rec_tid)
+            } else {
+                None
+            };
+            match maybe_t {
+                Some(t) => {
+                    let buf = trace.read_raw_data();
+                    if !buf.addr.is_null() && !buf.data.is_empty() {
+                        t.write_bytes_helper(buf.addr, &buf.data, None, WriteFlags::empty());
+                        t.vm()
+                            .maybe_update_breakpoints(&**t, buf.addr, buf.data.len());
+                    }
+                }
+                None => {
+                    trace.read_raw_data_metadata_for_frame();
+                }
+            }
+        }
+        drop(trace);
+
+        self.advance_to_next_trace_frame();
+        true
+    }
+
     /// Set up rep_trace_step state in t's Session to start replaying towards
     /// the event given by the session's current_trace_frame --- but only if
     /// it's not already set up.
@@ -928,7 +1126,10 @@ impl ReplaySession {
             EventType::EvPatchSyscall => {
                 current_step.action = ReplayTraceStepType::TstepPatchSyscall;
             }
-            EventType::EvSched => {
+            // The probe's `nop` doesn't affect ticks, and its breakpoint
+            // isn't planted during replay, so reaching the recorded registers
+            // is all there is to it.
+            EventType::EvSched | EventType::EvSdtProbe => {
                 current_step = ReplayTraceStep {
                     action: ReplayTraceStepType::TstepProgramAsyncSignalInterrupt,
                     data: ReplayTraceStepData::Target(ReplayTraceStepTarget {
@@ -1150,6 +1351,9 @@ impl ReplaySession {
                     // callframe, and we need to restore the $sp for continued
                     // execution.
                     t.set_regs(trace_frame.regs_ref());
+                    if ev.event_type() == EventType::EvSignalHandler {
+                        did_set_up_sigframe(t);
+                    }
                 }
                 t.validate_regs(Default::default());
                 Completion::Complete
@@ -1200,7 +1404,9 @@ impl ReplaySession {
                 return Completion::Incomplete;
             }
             Some(sig::SIGSEGV) => {
-                if self.handle_unrecorded_cpuid_fault(t, constraints) {
+                if self.handle_unrecorded_cpuid_fault(t, constraints)
+                    || self.handle_software_watchpoint_fault(t, constraints)
+                {
                     return Completion::Incomplete;
                 }
             }
@@ -1403,6 +1609,81 @@ impl ReplaySession {
         true
     }
 
+    /// If `t` faulted on a page we protected for a software watchpoint, step
+    /// it over the faulting instruction with the protection of the pages it
+    /// touches lifted and check whether a watchpoint triggered. The fault
+    /// itself is hidden from the replay; a triggered watchpoint is reported
+    /// as a SIGTRAP, as the debug registers would have.
+    fn handle_software_watchpoint_fault(
+        &self,
+        t: &ReplayTask,
+        constraints: &StepConstraints,
+    ) -> bool {
+        if t.maybe_stop_sig() != SIGSEGV {
+            return false;
+        }
+        let addr = fault_addr(t);
+        if !t.vm().is_software_watch_page(addr) {
+            return false;
+        }
+        log!(
+            LogDebug,
+            "Stepping over software watchpoint fault at {}",
+            addr
+        );
+
+        // An instruction can access more than one watched page, e.g. `movs`.
+        // Each one faults in turn until we've unprotected them all.
+        let mut fault_addrs = vec![addr];
+        t.vm().set_software_watch_protection_at(t, addr, false);
+        loop {
+            t.resume_execution(
+                ResumeRequest::ResumeSinglestep,
+                WaitRequest::ResumeWait,
+                TicksRequest::ResumeUnlimitedTicks,
+                None,
+            );
+            if t.maybe_stop_sig() != SIGSEGV {
+                break;
+            }
+            let next_addr = fault_addr(t);
+            if !t.vm().is_software_watch_page(next_addr)
+                || fault_addrs
+                    .iter()
+                    .any(|&a| floor_page_size(a) == floor_page_size(next_addr))
+            {
+                break;
+            }
+            t.vm().set_software_watch_protection_at(t, next_addr, false);
+            fault_addrs.push(next_addr);
+        }
+        for &a in &fault_addrs {
+            t.vm().set_software_watch_protection_at(t, a, true);
+        }
+        if t.maybe_stop_sig() != SIGTRAP {
+            // The instruction raised a real signal; let the caller deal with it.
+            return false;
+        }
+
+        let mut triggered = false;
+        for &a in &fault_addrs {
+            if t.vm().notify_software_watch_fault(a) {
+                triggered = true;
+            }
+        }
+        // For a singlestep, our step was the requested one, so its SIGTRAP
+        // stays in place.
+        if !constraints.is_singlestep() {
+            if triggered {
+                t.set_debug_status(0);
+                t.set_status(WaitStatus::for_stop_sig(sig::SIGTRAP));
+            } else {
+                t.set_status(WaitStatus::default());
+            }
+        }
+        true
+    }
+
     fn check_ticks_consistency(&self, t: &ReplayTask, ev: &Event) {
         if !self.done_initial_exec() {
             return;
@@ -1411,6 +1692,14 @@ impl ReplaySession {
         let ticks_now = t.tick_count();
         let trace_ticks = self.current_trace_frame().ticks();
 
+        if perf_counters::pmu_disabled() {
+            // Only singlestepped instructions are ticks without a PMU, and
+            // debugger singlesteps add ticks the recording didn't have. The
+            // next stepped stretch of `t` starts from the recorded count.
+            t.ticks.set(trace_ticks);
+            return;
+        }
+
         ed_assert!(
             t,
             ticks_now == trace_ticks,
@@ -1464,7 +1753,9 @@ impl ReplaySession {
                 tick_request,
                 None,
             );
-            self.handle_unrecorded_cpuid_fault(t, constraints);
+            if !self.handle_unrecorded_cpuid_fault(t, constraints) {
+                self.handle_software_watchpoint_fault(t, constraints);
+            }
         } else if constraints.command == RunCommand::RunSinglestepFastForward {
             self.fast_forward_status.set(
                 self.fast_forward_status.get()
@@ -1474,7 +1765,9 @@ impl ReplaySession {
                         &constraints.stop_before_states,
                     ),
             );
-            self.handle_unrecorded_cpuid_fault(t, constraints);
+            if !self.handle_unrecorded_cpuid_fault(t, constraints) {
+                self.handle_software_watchpoint_fault(t, constraints);
+            }
         } else {
             t.resume_execution(resume_how, WaitRequest::ResumeWait, tick_request, None);
             if t.maybe_stop_sig().is_not_sig() {
@@ -1500,7 +1793,9 @@ impl ReplaySession {
                     }
                     _ => (),
                 }
-            } else if self.handle_unrecorded_cpuid_fault(t, constraints) {
+            } else if self.handle_unrecorded_cpuid_fault(t, constraints)
+                || self.handle_software_watchpoint_fault(t, constraints)
+            {
                 return Completion::Incomplete;
             }
         }
@@ -1586,12 +1881,72 @@ impl ReplaySession {
         Completion::Complete
     }
 
+    /// Without a PMU a tick is one singlestepped instruction (see
+    /// `PerfCounters::read_ticks`), and an async event was only recorded if
+    /// the task had been stepped all the way since its previous event. Step
+    /// it the same way until it reaches `ticks`.
+    fn step_to_software_ticks(
+        &self,
+        t: &ReplayTask,
+        constraints: &StepConstraints,
+        ticks: Ticks,
+    ) -> Completion {
+        let regs = self.trace_frame.borrow().regs_ref().clone();
+        log!(
+            LogDebug,
+            "stepping {} instructions to reach {}/{}",
+            ticks as i64 - t.tick_count() as i64,
+            ticks,
+            regs.ip()
+        );
+
+        while t.tick_count() < ticks {
+            ed_assert!(
+                t,
+                !is_at_syscall_instruction(t, t.ip()),
+                "Reached a syscall {} instructions before the recorded event",
+                ticks - t.tick_count()
+            );
+            t.resume_execution(
+                ResumeRequest::ResumeSinglestep,
+                WaitRequest::ResumeWait,
+                TicksRequest::ResumeUnlimitedTicks,
+                None,
+            );
+            guard_unexpected_signal(t);
+
+            if t.maybe_stop_sig() == SIGTRAP {
+                let trap_reasons: TrapReasons = t.compute_trap_reasons();
+                if constraints.is_singlestep()
+                    || (trap_reasons.watchpoint && t.vm().has_any_watchpoint_changes())
+                    || (trap_reasons.breakpoint
+                        && BreakpointType::BkptUser
+                            == t.vm().get_breakpoint_type_for_retired_insn(t.ip()))
+                {
+                    log!(LogDebug, "    trap was debugger singlestep/breakpoint");
+                    return Completion::Incomplete;
+                }
+            }
+        }
+
+        let mut mismatched_regs: Option<Registers> = None;
+        if !is_same_execution_point(t, &regs, 0, &mut mismatched_regs) {
+            // Stepping can't skip past the target, so we diverged.
+            guard_overshoot(t, &regs, ticks, -1, mismatched_regs.as_ref());
+        }
+        Completion::Complete
+    }
+
     fn emulate_async_signal(
         &self,
         t: &ReplayTask,
         constraints: &StepConstraints,
         ticks: Ticks,
     ) -> Completion {
+        if perf_counters::pmu_disabled() {
+            return self.step_to_software_ticks(t, constraints, ticks);
+        }
+
         let regs = self.trace_frame.borrow().regs_ref().clone();
         let ip: RemoteCodePtr = regs.ip();
         let mut did_set_internal_breakpoint: bool = false;
@@ -1929,6 +2284,20 @@ impl ReplaySession {
             return Completion::Incomplete;
         }
 
+        // Recording never patches the libraries it excluded, so a patch there
+        // means we are not replaying the same code.
+        if let Some(m) = t.vm().mapping_of(t.ip().to_data_ptr::<Void>()) {
+            ed_assert!(
+                t,
+                !t.trace_reader()
+                    .unpatched_libraries()
+                    .iter()
+                    .any(|l| l == m.map.fsname()),
+                "Syscall patched in {:?}, which recording left unpatched",
+                m.map.fsname()
+            );
+        }
+
         let arch = t.arch();
         t.canonicalize_regs(arch);
         t.exit_syscall_and_prepare_restart();
@@ -2216,85 +2585,6 @@ impl Session for ReplaySession {
     }
 }
 
-fn tracee_xsave_enabled(trace_in: &TraceReader) -> bool {
-    let maybe_record = find_cpuid_record(trace_in.cpuid_records(), CPUID_GETFEATURES, 0);
-    maybe_record.unwrap().out.ecx & OSXSAVE_FEATURE_FLAG != 0
-}
-
-fn check_xsave_compatibility(trace_in: &TraceReader) {
-    if !tracee_xsave_enabled(trace_in) {
-        // Tracee couldn't use XSAVE so everything should be fine.
-        // If it didn't detect absence of XSAVE and actually executed an XSAVE
-        // and got a fault then replay will probably diverge :-(
-        return;
-    }
-    if !xsave_enabled() {
-        // Replaying on a super old CPU that doesn't even support XSAVE!
-        if !ProgramFlags::get().suppress_environment_warnings {
-            eprintln!(
-                "rr: Tracees had XSAVE but XSAVE is not available\n\
-                now; Replay will probably fail because glibc dynamic loader\n\
-                            uses XSAVE\n\n"
-            );
-        }
-        return;
-    }
-
-    let tracee_xcr0: u64 = trace_in.xcr0();
-    let our_xcr0: u64 = xcr0();
-    let maybe_record = find_cpuid_record(trace_in.cpuid_records(), CPUID_GETXSAVE, 1);
-    let tracee_xsavec: bool = match maybe_record {
-        Some(record) => record.out.eax & XSAVEC_FEATURE_FLAG != 0,
-        None => false,
-    };
-    let data: CPUIDData = cpuid(CPUID_GETXSAVE, 1);
-    let our_xsavec: bool = (data.eax & XSAVEC_FEATURE_FLAG) != 0;
-    if tracee_xsavec && !our_xsavec && !ProgramFlags::get().suppress_environment_warnings {
-        eprintln!(
-            "rd: Tracees had XSAVEC but XSAVEC is not available\n\
-            now; Replay will probably fail because glibc dynamic loader\n\
-                         uses XSAVEC\n\n"
-        );
-    }
-
-    if tracee_xcr0 != our_xcr0 {
-        if !ProgramFlags::get().suppress_environment_warnings {
-            // If the tracee used XSAVE instructions which write different components
-            // to XSAVE instructions executed on our CPU, or examines XCR0 directly,
-            // This will cause divergence. The dynamic linker examines XCR0 so this
-            // is nearly guaranteed.
-            eprintln!(
-                "Trace XCR0 value {:#x} != our XCR0 value {:#x};\n\
-                 Replay will probably fail because glibc dynamic loader examines XCR0\n\n",
-                tracee_xcr0, our_xcr0
-            );
-        }
-    }
-
-    let check_alignment: bool = tracee_xsavec && our_xsavec;
-    // Check that sizes and offsets of supported XSAVE areas area all identical.
-    // An Intel employee promised this on a mailing list...
-    // https://lists.xen.org/archives/html/xen-devel/2013-09/msg00484.html
-    for feature in 2u32..=63 {
-        if (tracee_xcr0 & our_xcr0 & (1u64 << feature as u64)) == 0 {
-            continue;
-        }
-        let maybe_record = find_cpuid_record(trace_in.cpuid_records(), CPUID_GETXSAVE, feature);
-        let data = cpuid(CPUID_GETXSAVE, feature);
-        if maybe_record.is_none()
-            || maybe_record.unwrap().out.eax != data.eax
-            || maybe_record.unwrap().out.ebx != data.ebx
-            || (check_alignment && (maybe_record.unwrap().out.ecx & 2u32) != (data.ecx & 2u32))
-        {
-            clean_fatal!(
-                "XSAVE offset/size/alignment differs for feature {};\n\
-                    H. Peter Anvin said this would never happen!",
-                feature
-            );
-        }
-    }
-}
-
 fn process_grow_map(t: &ReplayTask) {
     let mut data = MappedData::default();
     let km = t
@@ -2310,15 +2600,27 @@ fn treat_signal_event_as_deterministic(ev: &SignalEventData) -> bool {
     ev.deterministic == SignalDeterministic::DeterministicSig && ev.siginfo.si_signo != SIGBUS
 }
 
+/// The address whose access made `t` stop with SIGSEGV.
+fn fault_addr(t: &ReplayTask) -> RemotePtr<Void> {
+    let siginfo = t.get_siginfo();
+    RemotePtr::new(unsafe { siginfo._sifields._sigfault.si_addr } as usize)
+}
+
 fn perform_interrupted_syscall(t: &ReplayTask) {
     t.finish_emulated_syscall();
-    let mut remote = AutoRemoteSyscalls::new(t);
-    let r: Registers = remote.task().regs_ref().clone();
-    let ret = remote.syscall(
-        r.original_syscallno() as i32,
-        &[r.arg1(), r.arg2(), r.arg3(), r.arg4(), r.arg5(), r.arg6()],
-    );
-    remote.initial_regs_mut().set_syscall_result_signed(ret);
+    // The kernel would get EFAULT from pages we protected for software
+    // watchpoints.
+    t.vm().set_software_watch_protection(t, false);
+    {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let r: Registers = remote.task().regs_ref().clone();
+        let ret = remote.syscall(
+            r.original_syscallno() as i32,
+            &[r.arg1(), r.arg2(), r.arg3(), r.arg4(), r.arg5(), r.arg6()],
+        );
+        remote.initial_regs_mut().set_syscall_result_signed(ret);
+    }
+    t.vm().set_software_watch_protection(t, true);
 }
 
 /// Why a skid region?  Interrupts generated by perf counters don't
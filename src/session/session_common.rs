@@ -4,7 +4,10 @@ use crate::{
     log::LogDebug,
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::RemotePtr,
-    session::{task::task_inner::PtraceData, Session},
+    session::{
+        task::{task_inner::PtraceData, TaskSharedPtr},
+        Session,
+    },
     taskish_uid::{AddressSpaceUid, ThreadGroupUid},
     util::is_zombie_process,
 };
@@ -14,7 +17,29 @@ use nix::errno::errno;
 /// Forwarded method definition
 ///
 pub(super) fn kill_all_tasks<S: Session>(sess: &S) {
-    for (_, t) in sess.task_map.borrow().iter() {
+    let tasks: Vec<TaskSharedPtr> = sess.task_map.borrow().values().cloned().collect();
+    kill_tasks(sess, &tasks);
+
+    // Manually clean up the vm map and thread group map
+    // We have to do this ourselves because the session is probably
+    // getting drop()-ed and the thread group and address spaces would
+    // not have been able to reach out to session and do this themselves.
+    // (search for try_session() method in code base for more info)
+    let vm_uids: Vec<AddressSpaceUid> = sess.vm_map().keys().copied().collect();
+    for vm_uid in vm_uids {
+        sess.on_destroy_vm(vm_uid);
+    }
+
+    let tg_uids: Vec<ThreadGroupUid> = sess.thread_group_map().keys().copied().collect();
+    for tg_uid in tg_uids {
+        sess.on_destroy_tg(tg_uid);
+    }
+}
+
+/// Kill `tasks` and forget about them. Fatal signals take down whole thread
+/// groups, so `tasks` should include every live task of their thread groups.
+pub(super) fn kill_tasks<S: Session>(sess: &S, tasks: &[TaskSharedPtr]) {
+    for t in tasks {
         if !t.is_stopped.get() {
             // During recording we might be aborting the recording, in which case
             // one or more tasks might not be stopped. We haven't got any really
@@ -79,8 +104,8 @@ pub(super) fn kill_all_tasks<S: Session>(sess: &S) {
         }
     }
 
-    while !sess.task_map.borrow().is_empty() {
-        let (_, t) = sess.task_map.borrow_mut().pop_last().unwrap();
+    for t in tasks.iter().rev() {
+        sess.task_map.borrow_mut().remove(&t.rec_tid());
         if !t.unstable.get() {
             // Destroy the OS task backing this by sending it SIGKILL and
             // ensuring it was delivered.  After `kill()`, the only
@@ -103,19 +128,4 @@ pub(super) fn kill_all_tasks<S: Session>(sess: &S) {
 
         t.destroy(None, sess);
     }
-
-    // Manually clean up the vm map and thread group map
-    // We have to do this ourselves because the session is probably
-    // getting drop()-ed and the thread group and address spaces would
-    // not have been able to reach out to session and do this themselves.
-    // (search for try_session() method in code base for more info)
-    let vm_uids: Vec<AddressSpaceUid> = sess.vm_map().keys().copied().collect();
-    for vm_uid in vm_uids {
-        sess.on_destroy_vm(vm_uid);
-    }
-
-    let tg_uids: Vec<ThreadGroupUid> = sess.thread_group_map().keys().copied().collect();
-    for tg_uid in tg_uids {
-        sess.on_destroy_tg(tg_uid);
-    }
 }
@@ -48,6 +48,9 @@ pub struct BreakStatus {
     /// List of watchpoints hit; any watchpoint hit causes a stop after the
     /// instruction that triggered the watchpoint has completed.
     pub watchpoints_hit: Vec<WatchConfig>,
+    /// The memory of each data watchpoint in `watchpoints_hit` whose value
+    /// changed, before and after the change.
+    pub watchpoint_values: Vec<WatchpointValues>,
     /// When non-`None`, we stopped because a signal was delivered to `task`.
     pub signal: Option<Box<siginfo_t>>,
     /// True when we stopped because we hit a software breakpoint at `task`'s
@@ -60,6 +63,17 @@ pub struct BreakStatus {
     pub approaching_ticks_target: bool,
     /// True when we stopped because `task` is about to exit.
     pub task_exit: bool,
+    /// True when reverse execution stopped because `task` is about to finish
+    /// an exec() the debugger followed. Its address space is still the
+    /// pre-exec one.
+    pub reverse_exec_boundary: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchpointValues {
+    pub addr: RemotePtr<Void>,
+    pub old_value: Vec<u8>,
+    pub new_value: Vec<u8>,
 }
 
 impl Default for BreakStatus {
@@ -81,7 +95,9 @@ impl BreakStatus {
             singlestep_complete: false,
             approaching_ticks_target: false,
             task_exit: false,
+            reverse_exec_boundary: false,
             watchpoints_hit: vec![],
+            watchpoint_values: vec![],
             signal: None,
         }
     }
@@ -559,6 +575,20 @@ impl SessionInner {
     ) {
         self.assert_fully_initialized();
         break_status.watchpoints_hit = t.vm().consume_watchpoint_changes();
+        break_status.watchpoint_values.clear();
+        for w in break_status.data_watchpoints_hit() {
+            if let Some((old_value, new_value)) =
+                t.vm().consume_watchpoint_values(w.addr, w.num_bytes)
+            {
+                if old_value != new_value {
+                    break_status.watchpoint_values.push(WatchpointValues {
+                        addr: w.addr,
+                        old_value,
+                        new_value,
+                    });
+                }
+            }
+        }
     }
 
     /// XXX Move CloneCompletion/CaptureState etc to ReplayTask/ReplaySession
@@ -548,6 +548,7 @@ pub trait Task: Deref<Target = TaskInner> {
 
     fn open_mem_fd(&self) -> bool;
 
+    #[allow(clippy::result_unit_err)]
     fn read_bytes_fallible(&self, addr: RemotePtr<Void>, buf: &mut [u8]) -> Result<usize, ()>;
 
     fn read_bytes_helper(&self, addr: RemotePtr<Void>, buf: &mut [u8], ok: Option<&mut bool>);
@@ -596,6 +597,6 @@ fn is_signal_triggered_by_ptrace_interrupt(group_stop_sig: MaybeStopSignal) -> b
     group_stop_sig == SIGTRAP || group_stop_sig == SIGSTOP
 }
 
-fn is_singlestep_resume(request: ResumeRequest) -> bool {
+pub fn is_singlestep_resume(request: ResumeRequest) -> bool {
     request == ResumeRequest::ResumeSinglestep || request == ResumeRequest::ResumeSysemuSinglestep
 }
@@ -148,6 +148,13 @@ impl Sighandlers {
         &self.handlers[sig.as_raw() as usize]
     }
 
+    /// Return true if a user sighandler is installed for any signal.
+    pub fn has_user_handler(&self) -> bool {
+        self.handlers
+            .iter()
+            .any(|h| h.disposition() == SignalDisposition::SignalHandler)
+    }
+
     pub fn init_from_current_process(&mut self) {
         for (i, h) in self.handlers.iter_mut().enumerate().skip(1) {
             let mut sa: kernel_sigaction<NativeArch> = Default::default();
@@ -170,6 +177,34 @@ impl Sighandlers {
         }
     }
 
+    /// Like `init_from_current_process()`, but for a process that rd attached
+    /// to, whose dispositions can only be queried with remote syscalls.
+    pub fn init_from_task(&mut self, t: &dyn Task) {
+        let mut remote = AutoRemoteSyscalls::new(t);
+        let arch = remote.arch();
+        let mut mem =
+            AutoRestoreMem::new(&mut remote, None, size_of::<kernel_sigaction<NativeArch>>());
+        let addr = mem.get().unwrap();
+        for (i, h) in self.handlers.iter_mut().enumerate().skip(1) {
+            let ret = rd_syscall!(
+                mem,
+                syscall_number_for_rt_sigaction(arch),
+                i,
+                0,
+                addr.as_usize(),
+                sigaction_sigset_size(arch)
+            );
+            if ret < 0 {
+                // EINVAL means we're querying an unused signal number.
+                debug_assert_eq!(-EINVAL as isize, ret);
+                continue;
+            }
+            let sa: kernel_sigaction<NativeArch> =
+                read_val_mem(mem.task(), RemotePtr::cast(addr), None);
+            h.init_arch::<NativeArch>(&sa);
+        }
+    }
+
     /// For each signal in `table` such that is_user_handler() is
     /// true, reset the disposition of that signal to SIG_DFL, and
     /// clear the resethand flag if it's set.  SIG_IGN signals are
@@ -305,6 +340,9 @@ pub enum SignalDisposition {
 
 #[derive(Copy, Clone)]
 pub struct StashedSignal {
+    /// Identifies this signal among those stashed on its task, for
+    /// `pop_stash_sig()`.
+    pub id: u64,
     pub siginfo: siginfo_t,
     pub deterministic: SignalDeterministic,
 }
@@ -331,6 +369,12 @@ pub type RecordTaskSharedPtr = Rc<RefCell<RecordTask>>;
 pub struct RecordTask {
     pub task_inner: TaskInner,
     pub ticks_at_last_recorded_syscall_exit: Cell<Ticks>,
+    /// Without a PMU ticks only advance while the task is singlestepped (see
+    /// `PerfCounters::read_ticks`). Whether we're doing that is decided at the
+    /// first resume after each recorded event and kept until the next one,
+    /// because replay can only count the same instructions if the whole
+    /// stretch was stepped. None until decided.
+    pub stepping_for_ticks: Cell<Option<bool>>,
 
     /// Scheduler state
     pub registers_at_start_of_last_timeslice: RefCell<Registers>,
@@ -454,7 +498,9 @@ pub struct RecordTask {
     pub pending_events: RefCell<VecDeque<Event>>,
     /// Stashed signal-delivery state, ready to be delivered at
     /// next opportunity.
-    pub stashed_signals: RefCell<VecDeque<Box<StashedSignal>>>,
+    pub stashed_signals: RefCell<VecDeque<StashedSignal>>,
+    /// The id the next stashed signal will get.
+    pub next_stashed_signal_id: Cell<u64>,
     pub stashed_signals_blocking_more_signals: Cell<bool>,
     pub stashed_group_stop: Cell<bool>,
     pub break_at_syscallbuf_traced_syscalls: Cell<bool>,
@@ -494,7 +540,10 @@ impl Task for RecordTask {
         new_serial: u32,
         maybe_other_session: Option<SessionSharedPtr>,
     ) -> TaskSharedPtr {
-        ed_assert_eq!(self, reason, CloneReason::TraceeClone);
+        ed_assert!(
+            self,
+            reason == CloneReason::TraceeClone || reason == CloneReason::AttachedThread
+        );
         let t = clone_task_common(
             self,
             reason,
@@ -1007,6 +1056,7 @@ impl RecordTask {
         let rt = RecordTask {
             task_inner: TaskInner::new(session, tid, None, serial, a, weak_self),
             ticks_at_last_recorded_syscall_exit: Default::default(),
+            stepping_for_ticks: Default::default(),
             time_at_start_of_last_timeslice: Default::default(),
             priority: Default::default(),
             in_round_robin_queue: Default::default(),
@@ -1036,6 +1086,7 @@ impl RecordTask {
             tsc_mode: Cell::new(PR_TSC_ENABLE),
             cpuid_mode: Cell::new(1),
             stashed_signals: Default::default(),
+            next_stashed_signal_id: Default::default(),
             stashed_signals_blocking_more_signals: Default::default(),
             stashed_group_stop: Default::default(),
             break_at_syscallbuf_traced_syscalls: Default::default(),
@@ -1996,12 +2047,12 @@ impl RecordTask {
         }
         let deterministic = is_deterministic_signal(self);
         let siginfo = self.get_siginfo();
-        self.stashed_signals
-            .borrow_mut()
-            .push_back(Box::new(StashedSignal {
-                siginfo,
-                deterministic,
-            }));
+        let id = self.new_stashed_signal_id();
+        self.stashed_signals.borrow_mut().push_back(StashedSignal {
+            id,
+            siginfo,
+            deterministic,
+        });
         // Once we've stashed a signal, stop at the next traced/untraced syscall to
         // check whether we need to process the signal before it runs.
         self.stashed_signals_blocking_more_signals.set(true);
@@ -2047,19 +2098,24 @@ impl RecordTask {
             remove_index.map(|pos| self.stashed_signals.borrow_mut().remove(pos));
         }
 
-        self.stashed_signals.borrow_mut().insert(
-            0,
-            Box::new(StashedSignal {
-                siginfo: *si,
-                deterministic,
-            }),
-        );
+        let id = self.new_stashed_signal_id();
+        self.stashed_signals.borrow_mut().push_front(StashedSignal {
+            id,
+            siginfo: *si,
+            deterministic,
+        });
         self.stashed_signals_blocking_more_signals.set(true);
         self.break_at_syscallbuf_final_instruction.set(true);
         self.break_at_syscallbuf_traced_syscalls.set(true);
         self.break_at_syscallbuf_untraced_syscalls.set(true);
     }
 
+    fn new_stashed_signal_id(&self) -> u64 {
+        let id = self.next_stashed_signal_id.get();
+        self.next_stashed_signal_id.set(id + 1);
+        id
+    }
+
     /// DIFF NOTE: Simply called has_stashed_sig() in rr
     pub fn has_any_stashed_sig(&self) -> bool {
         !self.stashed_signals.borrow().is_empty()
@@ -2084,34 +2140,27 @@ impl RecordTask {
         false
     }
 
-    /// Deliberately returning a *const StashedSignal as we need an addr in pop_stash_sig()
-    pub fn peek_stashed_sig_to_deliver(&self) -> Option<*const StashedSignal> {
-        if self.stashed_signals.borrow().is_empty() {
-            return None;
-        }
+    /// DIFF NOTE: rr returns a pointer into the stash, which pop_stash_sig()
+    /// then searches for. Signals can be stashed and discarded in between
+    /// (e.g. by handle_signal()), so we return a copy and search by id instead.
+    pub fn peek_stashed_sig_to_deliver(&self) -> Option<StashedSignal> {
+        let stashed_signals = self.stashed_signals.borrow();
         // Choose the first non-synthetic-SIGCHLD signal so that if a syscall should
         // be interrupted, we'll interrupt it.
-        for sig in self.stashed_signals.borrow().iter() {
+        for sig in stashed_signals.iter() {
             if !is_synthetic_sigchld(&sig.siginfo) {
-                return Some(&**sig as *const StashedSignal);
+                return Some(*sig);
             }
         }
-        self.stashed_signals
-            .borrow()
-            .get(0)
-            .map(|sig| &**sig as *const StashedSignal)
+        stashed_signals.front().copied()
     }
 
-    /// @TODO Instead of searching by pointer address which can have its issues why not
-    /// store a unique id in a StashedSignal structure or some other approach?
-    pub fn pop_stash_sig(&self, stashed: *const StashedSignal) {
-        let mut remove_index = None;
-        for (pos, it) in self.stashed_signals.borrow().iter().enumerate() {
-            if ptr::eq(&**it as *const StashedSignal, stashed) {
-                remove_index = Some(pos);
-                break;
-            }
-        }
+    pub fn pop_stash_sig(&self, id: u64) {
+        let remove_index = self
+            .stashed_signals
+            .borrow()
+            .iter()
+            .position(|it| it.id == id);
 
         match remove_index {
             Some(pos) => {
@@ -2401,12 +2450,14 @@ impl RecordTask {
         self.record_remote(range.start(), range.size())
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn record_remote_range_fallible(&self, range: MemoryRange) -> Result<usize, ()> {
         self.record_remote_fallible(range.start(), range.size())
     }
 
     /// Record as much as we can of the bytes in this range. Will record only
     /// contiguous mapped data starting at `addr`.
+    #[allow(clippy::result_unit_err)]
     pub fn record_remote_fallible(
         &self,
         addr: RemotePtr<Void>,
@@ -2714,6 +2765,7 @@ impl RecordTask {
             self.ticks_at_last_recorded_syscall_exit
                 .set(self.tick_count());
         }
+        self.stepping_for_ticks.set(None);
 
         let mut maybe_extra_registers = None;
         let mut maybe_record_registers = None;
@@ -3320,6 +3372,12 @@ fn do_preload_init_arch<Arch: Architecture>(t: &RecordTask) {
     write_val_mem(t, in_chaos_ptr, &in_chaos, None);
     t.record_local_for(in_chaos_ptr, &in_chaos);
 
+    let seeded_random: u8 = t.session().as_record().unwrap().is_seeded() as u8;
+    let seeded_random_ptr = RemotePtr::<u8>::cast(Arch::as_rptr(params.globals))
+        + offset_of!(preload_globals, seeded_random);
+    write_val_mem(t, seeded_random_ptr, &seeded_random, None);
+    t.record_local_for(seeded_random_ptr, &seeded_random);
+
     let cores: i32 = t
         .session()
         .as_record()
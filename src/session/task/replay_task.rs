@@ -13,11 +13,13 @@ use crate::{
     arch::Architecture,
     auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
     bindings::kernel::user_desc,
+    divergence_repair::{repair_fpu_pointers, repair_regs, BenignDivergences},
     file_monitor::preserve_file_monitor::PreserveFileMonitor,
     kernel_abi::{
         syscall_number_for_close, syscall_number_for_dup3, syscall_number_for_openat, SupportedArch,
     },
-    log::LogLevel::LogWarn,
+    lazy_mappings::map_raw_data_lazily,
+    log::LogLevel::{LogError, LogWarn},
     preload_interface::syscallbuf_record,
     preload_interface_arch::rdcall_init_buffers_params,
     rd::RD_RESERVED_ROOT_DIR_FD,
@@ -43,6 +45,7 @@ use crate::{
         trace_reader::{RawData, TraceReader},
         trace_stream::MappedData,
     },
+    unwind::format_backtrace,
     util::page_size,
     wait_status::WaitStatus,
 };
@@ -54,6 +57,9 @@ use std::{
     ops::Deref,
 };
 
+/// How much of the stack to log when registers diverge.
+const MAX_DIVERGENCE_BACKTRACE_FRAMES: usize = 32;
+
 pub struct ReplayTask {
     pub task_inner: TaskInner,
 }
@@ -162,6 +168,27 @@ impl ReplayTask {
         // TODO: add perf counter validations (hw int, page faults, insts)
         let trace_frame = self.current_trace_frame();
         let rec_regs = trace_frame.regs_ref();
+        let benign = self
+            .session()
+            .as_replay()
+            .unwrap()
+            .flags()
+            .repair_divergences;
+        if benign.contains(BenignDivergences::FPU_POINTERS)
+            && !trace_frame.extra_regs_ref().is_empty()
+        {
+            repair_fpu_pointers(self, trace_frame.extra_regs_ref());
+        }
+        if !benign.is_empty() && repair_regs(self, rec_regs, benign) {
+            return;
+        }
+        if !self.regs_ref().matches(rec_regs) {
+            log!(
+                LogError,
+                "Backtrace at divergence:\n{}",
+                format_backtrace(self, MAX_DIVERGENCE_BACKTRACE_FRAMES)
+            );
+        }
         Registers::compare_register_files(
             Some(self),
             "replaying",
@@ -245,6 +272,9 @@ impl ReplayTask {
     /// Restore all remaining chunks of saved data for the current trace frame.
     pub fn apply_all_data_records_from_trace(&self) {
         loop {
+            if map_raw_data_lazily(self) {
+                continue;
+            }
             let maybe_buf = self.trace_reader_mut().read_raw_data_for_frame().clone();
             match maybe_buf {
                 Some(buf) => {
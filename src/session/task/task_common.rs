@@ -21,8 +21,9 @@ use crate::{
         },
         prctl::{ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_FS, ARCH_SET_GS},
         ptrace::{
-            PTRACE_ARCH_PRCTL, PTRACE_DETACH, PTRACE_EVENT_EXIT, PTRACE_GETREGS, PTRACE_GETSIGINFO,
-            PTRACE_POKEUSER, PTRACE_SETFPREGS, PTRACE_SETFPXREGS, PTRACE_SETREGS, PTRACE_SETREGSET,
+            PTRACE_ARCH_PRCTL, PTRACE_DETACH, PTRACE_EVENT_EXIT, PTRACE_EVENT_STOP, PTRACE_GETREGS,
+            PTRACE_GETSIGINFO, PTRACE_POKEUSER, PTRACE_SETFPREGS, PTRACE_SETFPXREGS,
+            PTRACE_SETREGS, PTRACE_SETREGSET,
         },
         signal::{siginfo_t, POLL_IN},
     },
@@ -31,14 +32,14 @@ use crate::{
     fast_forward::at_x86_string_instruction,
     file_monitor,
     kernel_abi::{
-        get_syscall_instruction_arch, is_at_syscall_instruction, is_mprotect_syscall,
-        syscall_instruction_length, syscall_number_for_arch_prctl, syscall_number_for_close,
-        syscall_number_for_mprotect, syscall_number_for_munmap, syscall_number_for_openat,
-        syscall_number_for_prctl, syscall_number_for_set_thread_area, x64, x86,
+        get_syscall_instruction_arch, is_at_syscall_instruction, syscall_instruction_length,
+        syscall_number_for_arch_prctl, syscall_number_for_close, syscall_number_for_mprotect,
+        syscall_number_for_munmap, syscall_number_for_openat, syscall_number_for_prctl,
+        syscall_number_for_set_thread_area, syscall_number_for_sigaltstack, x64, x86,
         CloneParameterOrdering, CloneTLSType, FcntlOperation, SupportedArch,
     },
     kernel_metadata::{errno_name, ptrace_req_name},
-    kernel_supplement::ARCH_SET_CPUID,
+    kernel_supplement::{ARCH_SET_CPUID, SS_AUTODISARM},
     log::LogLevel::{LogDebug, LogInfo, LogWarn},
     perf_counters::TIME_SLICE_SIGNAL,
     preload_interface::{
@@ -60,8 +61,8 @@ use crate::{
         task::{
             is_signal_triggered_by_ptrace_interrupt, is_singlestep_resume,
             task_inner::{
-                CapturedState, CloneFlags, CloneReason, PtraceData, ResumeRequest, TicksRequest,
-                TrapReasons, WaitRequest, WriteFlags, MAX_TICKS_REQUEST,
+                CapturedState, CloneFlags, CloneReason, PtraceData, ResumeRequest, SigAltStack,
+                TicksRequest, TrapReasons, WaitRequest, WriteFlags, MAX_TICKS_REQUEST,
             },
             Task, TaskSharedPtr, PRELOAD_THREAD_LOCALS_SIZE,
         },
@@ -71,9 +72,9 @@ use crate::{
     ticks::Ticks,
     util::{
         ceil_page_size, clone_flags_to_task_flags, cpuid, floor_page_size, is_kernel_trap,
-        pwrite_all_fallible, trapped_instruction_at, trapped_instruction_len, u8_slice_mut,
-        xsave_layout_from_trace, xsave_native_layout, TrappedInstruction, XSaveLayout,
-        CPUID_GETFEATURES,
+        pwrite_all_fallible, trapped_instruction_at, trapped_instruction_len, u8_slice,
+        u8_slice_mut, xsave_layout_from_trace, xsave_native_layout, TrappedInstruction,
+        XSaveLayout, CPUID_GETFEATURES,
     },
     wait_status::WaitStatus,
 };
@@ -81,7 +82,7 @@ use file_monitor::LazyOffset;
 use libc::{
     pid_t, pread64, waitpid, CLONE_FILES, CLONE_FS, CLONE_SIGHAND, CLONE_SYSVSEM, CLONE_THREAD,
     CLONE_VM, EAGAIN, ECHILD, EPERM, ESRCH, PR_SET_NAME, PR_SET_SECCOMP, SECCOMP_MODE_FILTER,
-    SEEK_SET, SIGCHLD, SIGTRAP, WNOHANG, __WALL,
+    SEEK_SET, SIGCHLD, SIGTRAP, SS_DISABLE, WNOHANG, __WALL,
 };
 use nix::{
     errno::{errno, Errno},
@@ -1029,6 +1030,27 @@ pub(in super::super) fn os_clone_into(
     )
 }
 
+/// Recreate thread `rec_tid` of the snapshot an attached trace starts with
+/// (see `TraceTaskEventExec::attached_threads()`) in the process of
+/// `remote`'s task. The caller gives it its recorded registers.
+pub fn os_clone_attached_thread(remote: &mut AutoRemoteSyscalls, rec_tid: pid_t) -> TaskSharedPtr {
+    let session = remote.task().session();
+    let serial = session.next_task_serial();
+    os_clone(
+        CloneReason::SessionCloneNonleader,
+        session,
+        remote,
+        rec_tid,
+        serial,
+        // As in `os_clone_into()`.
+        CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM,
+        None,
+        None,
+        None,
+        None,
+    )
+}
+
 /// Make the OS-level calls to create a new fork or clone that
 /// will eventually be a copy of this task and return that Task
 /// metadata.  These methods are used in concert with
@@ -1086,14 +1108,13 @@ fn on_syscall_exit_common_arch<Arch: Architecture>(t: &dyn Task, sys: i32, regs:
         return;
     }
 
-    // mprotect can change the protection status of some mapped regions before
-    // failing.
     // SYS_rdcall_mprotect_record always fails with ENOSYS, though we want to
     // note its usage here.
-    if regs.syscall_failed()
-        && !is_mprotect_syscall(sys, regs.arch())
-        && sys != Arch::RDCALL_MPROTECT_RECORD
-    {
+    // DIFF NOTE: rr also applies a failed mprotect, since the kernel can change
+    // the protection of some mapped regions before failing. But applying the
+    // whole request changes regions the kernel never got to as well, and most
+    // failures (EINVAL, EACCES) change nothing at all.
+    if regs.syscall_failed() && sys != Arch::RDCALL_MPROTECT_RECORD {
         return;
     }
 
@@ -1142,7 +1163,7 @@ fn on_syscall_exit_common_arch<Arch: Architecture>(t: &dyn Task, sys: i32, regs:
         }
     }
 
-    if sys == Arch::MPROTECT {
+    if sys == Arch::MPROTECT || sys == Arch::PKEY_MPROTECT {
         let addr: RemotePtr<Void> = regs.arg1().into();
         let num_bytes: usize = regs.arg2();
         let prot = regs.arg3_signed() as i32;
@@ -1179,6 +1200,15 @@ fn on_syscall_exit_common_arch<Arch: Architecture>(t: &dyn Task, sys: i32, regs:
         return;
     }
 
+    if sys == Arch::SIGALTSTACK {
+        let ss = RemotePtr::<Arch::stack_t>::from(regs.arg1());
+        if !ss.is_null() {
+            let ss = read_val_mem(t, ss, None);
+            t.sigaltstack.set(sigaltstack_from::<Arch>(&ss));
+        }
+        return;
+    }
+
     if sys == Arch::PRCTL {
         let arg1 = t.regs_ref().arg1_signed() as i32;
         match arg1 {
@@ -1243,7 +1273,7 @@ fn on_syscall_exit_common_arch<Arch: Architecture>(t: &dyn Task, sys: i32, regs:
         return;
     }
 
-    if sys == Arch::PWRITEV || sys == Arch::WRITEV {
+    if sys == Arch::PWRITEV || sys == Arch::PWRITEV2 || sys == Arch::WRITEV {
         let fd: i32 = regs.arg1_signed() as i32;
         let mut ranges: Vec<file_monitor::Range> = Vec::new();
         let iovecs = read_mem(
@@ -1296,6 +1326,7 @@ pub(super) fn post_exec_syscall_common(t: &dyn Task) {
     let arch = t.arch();
     t.canonicalize_regs(arch);
     t.vm().post_exec_syscall(t);
+    t.sigaltstack.set(None);
 
     if SessionInner::has_cpuid_faulting() {
         let mut remote = AutoRemoteSyscalls::new(t);
@@ -1555,7 +1586,10 @@ pub(in super::super) fn clone_task_common(
             new_task_session = other_session;
         }
         None => {
-            ed_assert_eq!(clone_this, reason, CloneReason::TraceeClone);
+            ed_assert!(
+                clone_this,
+                reason == CloneReason::TraceeClone || reason == CloneReason::AttachedThread
+            );
         }
     }
     // No longer mutable.
@@ -1637,13 +1671,24 @@ pub(in super::super) fn clone_task_common(
     }
 
     rc_t.top_of_stack.set(stack);
+    // Like the kernel, give every child but those of CLONE_VM clones (other
+    // than vforks) the parent's alternate signal stack.
+    if !flags.contains(CloneFlags::CLONE_SHARE_VM) || flags.contains(CloneFlags::CLONE_VFORK) {
+        rc_t.sigaltstack.set(clone_this.sigaltstack.get());
+    }
     // Clone children, both thread and fork, inherit the parent
     // prname.
     *rc_t.prname.borrow_mut() = clone_this.prname.borrow().clone();
 
-    // wait() before trying to do anything that might need to
-    // use ptrace to access memory
-    rc_t.wait(None);
+    if reason == CloneReason::AttachedThread {
+        // The thread is already in the stop its PTRACE_INTERRUPT asked for.
+        rc_t.did_waitpid(WaitStatus::for_ptrace_event(PTRACE_EVENT_STOP));
+        rc_t.clear_wait_status();
+    } else {
+        // wait() before trying to do anything that might need to
+        // use ptrace to access memory
+        rc_t.wait(None);
+    }
 
     rc_t.post_wait_clone(clone_this, flags);
     if flags.contains(CloneFlags::CLONE_SHARE_THREAD_GROUP) {
@@ -2264,6 +2309,7 @@ pub(in super::super) fn copy_state(t: &dyn Task, state: &CapturedState) {
 
         copy_tls(state, &mut remote);
         *remote.task().thread_areas_.borrow_mut() = state.thread_areas.clone();
+        copy_sigaltstack(state, &mut remote);
         remote.task().syscallbuf_size.set(state.syscallbuf_size);
 
         ed_assert!(
@@ -2306,6 +2352,134 @@ pub(in super::super) fn copy_state(t: &dyn Task, state: &CapturedState) {
     t.ticks.set(state.ticks);
 }
 
+/// The kernel's alternate signal stack for a replay task may not be what it
+/// was during recording, because replay doesn't have the kernel set up
+/// signal frames (which can disarm the stack), and threads made by
+/// os_clone_into() don't have one at all. Diversions have the kernel deliver
+/// signals for real, so give the copy the stack it had during recording.
+fn copy_sigaltstack(state: &CapturedState, remote: &mut AutoRemoteSyscalls) {
+    let arch = remote.arch();
+    rd_arch_function_selfless!(copy_sigaltstack_arch, arch, state, remote);
+}
+
+fn copy_sigaltstack_arch<Arch: Architecture>(
+    state: &CapturedState,
+    remote: &mut AutoRemoteSyscalls,
+) {
+    let mut ss = Arch::stack_t::default();
+    match state.sigaltstack {
+        Some(alt) => Arch::set_stack_t(&mut ss, alt.sp, alt.flags, alt.size),
+        None => Arch::set_stack_t(&mut ss, RemotePtr::null(), SS_DISABLE, 0),
+    }
+    let data = u8_slice(&ss);
+    let mut remote_ss = AutoRestoreMem::new(remote, Some(data), data.len());
+    let addr = remote_ss.get().unwrap();
+    let ret = rd_syscall!(
+        remote_ss,
+        syscall_number_for_sigaltstack(Arch::arch()),
+        addr.as_usize(),
+        0
+    );
+    // A fork()ed copy already has the stack, and the kernel refuses to change
+    // it while the task is running on it.
+    ed_assert!(
+        remote_ss.task(),
+        ret == 0 || ret == -EPERM as isize,
+        "sigaltstack failed: {}",
+        errno_name(-ret as i32)
+    );
+    remote_ss.task().sigaltstack.set(state.sigaltstack);
+}
+
+/// Ask the kernel for `t`'s alternate signal stack, for a process rd
+/// attached to rather than saw set it up.
+pub fn init_sigaltstack_from_task(t: &dyn Task) {
+    let arch = t.arch();
+    rd_arch_function_selfless!(init_sigaltstack_from_task_arch, arch, t)
+}
+
+fn init_sigaltstack_from_task_arch<Arch: Architecture>(t: &dyn Task) {
+    let mut remote = AutoRemoteSyscalls::new(t);
+    let mut remote_ss = AutoRestoreMem::new(&mut remote, None, size_of::<Arch::stack_t>());
+    let addr = remote_ss.get().unwrap();
+    rd_infallible_syscall!(
+        remote_ss,
+        syscall_number_for_sigaltstack(Arch::arch()),
+        0,
+        addr.as_usize()
+    );
+    let ss = read_val_mem(
+        remote_ss.task(),
+        RemotePtr::<Arch::stack_t>::cast(addr),
+        None,
+    );
+    t.sigaltstack.set(sigaltstack_from::<Arch>(&ss));
+}
+
+/// Call when the kernel has just set up a signal handler frame for `t`
+/// (during replay: when we've restored one from the trace), with `t`'s stack
+/// pointer pointing to it. Returns the alternate signal stack the frame is
+/// on, if it's on one.
+///
+/// Setting up the frame disarms an SS_AUTODISARM stack (except for x86 frames
+/// for handlers without SA_SIGINFO, which we don't distinguish).
+pub fn did_set_up_sigframe(t: &dyn Task) -> Option<SigAltStack> {
+    let alt = t.sigaltstack.get()?;
+    if alt.autodisarm() {
+        t.sigaltstack.set(None);
+    }
+    if alt.contains(t.regs_ref().sp()) {
+        Some(alt)
+    } else {
+        None
+    }
+}
+
+/// Call when `t` enters sigreturn/rt_sigreturn `sys`. rt_sigreturn restores
+/// the alternate signal stack the kernel saved in the signal frame.
+pub fn will_sigreturn(t: &dyn Task, sys: i32) {
+    let arch = t.arch();
+    rd_arch_function_selfless!(will_sigreturn_arch, arch, t, sys)
+}
+
+fn will_sigreturn_arch<Arch: Architecture>(t: &dyn Task, sys: i32) {
+    if sys != Arch::RT_SIGRETURN {
+        return;
+    }
+    // The handler has returned to the frame's restorer, popping the frame's
+    // return address, so `sp` points to the rest of the frame: on x64 the
+    // ucontext (uc_flags and uc_link come before uc_stack), on x86 the signal
+    // number, the siginfo and ucontext pointers and the siginfo first.
+    let uc_stack_offset: usize = match Arch::arch() {
+        SupportedArch::X64 => 16,
+        SupportedArch::X86 => 12 + size_of::<x86::siginfo_t>() + 8,
+    };
+    let mut ok = true;
+    let ss = read_val_mem(
+        t,
+        RemotePtr::<Arch::stack_t>::from(t.regs_ref().sp().as_usize() + uc_stack_offset),
+        Some(&mut ok),
+    );
+    // If the frame is bad the task is about to get a SIGSEGV instead.
+    if ok {
+        t.sigaltstack.set(sigaltstack_from::<Arch>(&ss));
+    }
+}
+
+fn sigaltstack_from<Arch: Architecture>(ss: &Arch::stack_t) -> Option<SigAltStack> {
+    let (sp, flags, size) = Arch::get_stack_t(ss);
+    let autodisarm = flags & SS_AUTODISARM as i32;
+    if flags & !autodisarm == SS_DISABLE {
+        None
+    } else {
+        Some(SigAltStack {
+            sp,
+            size,
+            flags: autodisarm,
+        })
+    }
+}
+
 fn copy_tls(state: &CapturedState, remote: &mut AutoRemoteSyscalls) {
     let arch = remote.arch();
     rd_arch_function_selfless!(copy_tls_arch, arch, state, remote);
@@ -1,11 +1,13 @@
 use crate::{
     arch::Architecture,
+    auto_remote_syscalls::{AutoRemoteSyscalls, AutoRestoreMem},
     bindings::{
         kernel::{sock_fprog, user, user_desc, CAP_SYS_ADMIN, NT_X86_XSTATE},
         ptrace::{
-            ptrace, PTRACE_CONT, PTRACE_EVENT_CLONE, PTRACE_EVENT_EXIT, PTRACE_EVENT_FORK,
-            PTRACE_EVENT_SECCOMP, PTRACE_EVENT_VFORK, PTRACE_GETEVENTMSG, PTRACE_GETREGSET,
-            PTRACE_GET_THREAD_AREA, PTRACE_O_EXITKILL, PTRACE_O_TRACECLONE, PTRACE_O_TRACEEXEC,
+            ptrace, PTRACE_CONT, PTRACE_DETACH, PTRACE_EVENT_CLONE, PTRACE_EVENT_EXEC,
+            PTRACE_EVENT_EXIT, PTRACE_EVENT_FORK, PTRACE_EVENT_SECCOMP, PTRACE_EVENT_STOP,
+            PTRACE_EVENT_VFORK, PTRACE_GETEVENTMSG, PTRACE_GETREGSET, PTRACE_GET_THREAD_AREA,
+            PTRACE_INTERRUPT, PTRACE_O_EXITKILL, PTRACE_O_TRACECLONE, PTRACE_O_TRACEEXEC,
             PTRACE_O_TRACEEXIT, PTRACE_O_TRACEFORK, PTRACE_O_TRACESECCOMP, PTRACE_O_TRACESYSGOOD,
             PTRACE_O_TRACEVFORK, PTRACE_PEEKDATA, PTRACE_PEEKUSER, PTRACE_POKEDATA,
             PTRACE_POKEUSER, PTRACE_SEIZE, PTRACE_SETREGS, PTRACE_SETREGSET,
@@ -22,9 +24,13 @@ use crate::{
         stdio_monitor::StdioMonitor,
     },
     flags::Flags,
-    kernel_abi::{is_ioctl_syscall, SupportedArch, RD_NATIVE_ARCH},
+    kernel_abi::{
+        is_ioctl_syscall, syscall_number_for_close, syscall_number_for_connect,
+        syscall_number_for_dup3, syscall_number_for_openat, syscall_number_for_socket,
+        SupportedArch, RD_NATIVE_ARCH,
+    },
     kernel_metadata::{errno_name, ptrace_req_name, syscall_name},
-    kernel_supplement::PTRACE_EVENT_SECCOMP_OBSOLETE,
+    kernel_supplement::{PTRACE_EVENT_SECCOMP_OBSOLETE, SS_AUTODISARM},
     log::LogLevel::{LogDebug, LogWarn},
     perf_counters::PerfCounters,
     preload_interface::{preload_globals, syscallbuf_hdr, PRELOAD_THREAD_LOCALS_SIZE},
@@ -57,17 +63,22 @@ use crate::{
 };
 use bit_field::BitField;
 use libc::{
-    __errno_location, _exit, fork, iovec, pid_t, prctl, syscall, uid_t, SYS_write, EAGAIN, EBADF,
-    EINVAL, ENOMEM, ENOSYS, EPERM, ESRCH, PR_SET_NO_NEW_PRIVS, PR_SET_PDEATHSIG, PR_SET_SECCOMP,
-    PR_SET_TSC, PR_TSC_SIGSEGV, SECCOMP_MODE_FILTER, SIGKILL, SIGSTOP, STDERR_FILENO,
-    STDOUT_FILENO,
+    __errno_location, _exit, c_char, c_ulong, fork, iovec, personality, pid_t, prctl, sa_family_t,
+    sockaddr_un, syscall, uid_t, waitpid, SYS_write, __WALL, ADDR_NO_RANDOMIZE, AF_UNIX, AT_FDCWD,
+    EAGAIN, EBADF, EINVAL, ENOMEM, ENOSYS, EPERM, ESRCH, O_CLOEXEC, O_DIRECTORY, O_PATH, O_WRONLY,
+    PR_SET_NO_NEW_PRIVS, PR_SET_PDEATHSIG, PR_SET_SECCOMP, PR_SET_TSC, PR_TSC_SIGSEGV,
+    SECCOMP_MODE_FILTER, SIGKILL, SIGSTOP, SOCK_CLOEXEC, SOCK_STREAM, STDERR_FILENO, STDOUT_FILENO,
 };
 use nix::{
     errno::{errno, Errno},
     fcntl::{fcntl, open, readlink, FcntlArg, OFlag},
+    sched::{sched_setaffinity, CpuSet},
     sys::{
         signal::{kill, sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal},
-        socket::{socketpair, AddressFamily, SockFlag, SockType},
+        socket::{
+            accept4, bind, listen, socket, socketpair, AddressFamily, SockAddr, SockFlag, SockType,
+            UnixAddr,
+        },
         stat::{lstat, stat, FileStat, Mode},
     },
     unistd::{dup2, execve, getpid, getuid, setsid, Pid},
@@ -78,7 +89,8 @@ use std::{
     cell::{Cell, Ref, RefCell, RefMut},
     cmp::{max, min},
     ffi::{c_void, CStr, CString, OsStr, OsString},
-    mem::{size_of, size_of_val},
+    fs,
+    mem::{size_of, size_of_val, zeroed},
     ops::Deref,
     os::{raw::c_int, unix::ffi::OsStrExt},
     ptr,
@@ -119,6 +131,37 @@ bitflags! {
         const CLONE_CLEARTID = 1 << 4;
         /// Set the thread area to what's specified by the `tls` arg.
         const CLONE_SET_TLS = 1 << 5;
+        /// The parent is suspended until the child execs or exits (CLONE_VFORK).
+        const CLONE_VFORK = 1 << 6;
+    }
+}
+
+/// An alternate signal stack registered with sigaltstack().
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SigAltStack {
+    pub sp: RemotePtr<Void>,
+    pub size: usize,
+    /// The flags passed to sigaltstack(), i.e. 0 or SS_AUTODISARM.
+    pub flags: i32,
+}
+
+impl SigAltStack {
+    /// The top of the stack.
+    pub fn end(&self) -> RemotePtr<Void> {
+        self.sp + self.size
+    }
+
+    /// True if a (downwards growing) stack whose pointer is `sp` is on this
+    /// stack. This is the kernel's on_sig_stack() check, minus its special
+    /// case for SS_AUTODISARM.
+    pub fn contains(&self, sp: RemotePtr<Void>) -> bool {
+        sp > self.sp && sp - self.sp <= self.size
+    }
+
+    /// The kernel disables the stack while a signal handler runs on it, and
+    /// sigreturn() reenables it.
+    pub fn autodisarm(&self) -> bool {
+        self.flags as u32 & SS_AUTODISARM != 0
     }
 }
 
@@ -347,6 +390,10 @@ pub struct TaskInner {
     /// The `stack` argument passed to `clone()`, which for
     /// "threads" is the top of the user-allocated stack.
     pub(in super::super) top_of_stack: Cell<RemotePtr<Void>>,
+    /// The alternate signal stack, as the kernel sees it during recording.
+    /// During replay signal frames are not set up by the kernel, so the
+    /// kernel's idea of it can differ from this (see copy_state()).
+    pub(in super::super) sigaltstack: Cell<Option<SigAltStack>>,
     /// The most recent status of this task as returned by
     /// waitpid().
     pub(in super::super) wait_status: Cell<WaitStatus>,
@@ -391,6 +438,7 @@ pub struct CapturedState {
     /// DIFF NOTE: This is signed in rr
     pub scratch_size: usize,
     pub top_of_stack: RemotePtr<Void>,
+    pub sigaltstack: Option<SigAltStack>,
     pub cloned_file_data_offset: u64,
     pub thread_locals: ThreadLocals,
     pub rec_tid: pid_t,
@@ -414,6 +462,7 @@ impl Default for CapturedState {
             scratch_ptr: Default::default(),
             scratch_size: Default::default(),
             top_of_stack: Default::default(),
+            sigaltstack: Default::default(),
             cloned_file_data_offset: Default::default(),
             thread_locals: unsafe { std::mem::zeroed() },
             rec_tid: Default::default(),
@@ -435,6 +484,9 @@ pub enum CloneReason {
     /// Cloning a task into the same session to recreate threads while
     /// restoring a checkpoint
     SessionCloneNonleader,
+    /// Creating the task for a thread of a process rd attached to, which
+    /// `TaskInner::attach()` already saw stop
+    AttachedThread,
 }
 
 pub enum SaveTraceeFdNumber<'a> {
@@ -1073,6 +1125,11 @@ impl TaskInner {
         self.wait_status.get()
     }
 
+    /// How this was last resumed; meaningful while it is stopped.
+    pub fn how_last_execution_resumed(&self) -> ResumeRequest {
+        self.how_last_execution_resumed.get()
+    }
+
     /// Return the ptrace event as of the last call to `wait()/try_wait()`.
     pub fn maybe_ptrace_event(&self) -> MaybePtraceEvent {
         self.wait_status.get().maybe_ptrace_event()
@@ -1345,6 +1402,7 @@ impl TaskInner {
             extra_registers: Default::default(),
             session_: session.weak_self.clone(),
             top_of_stack: Default::default(),
+            sigaltstack: Default::default(),
             seen_ptrace_exit_event: Default::default(),
             thread_locals: RefCell::new([0u8; PRELOAD_THREAD_LOCALS_SIZE]),
             expecting_ptrace_interrupt_stop: Default::default(),
@@ -1369,6 +1427,11 @@ impl TaskInner {
         self.stable_serial.get()
     }
 
+    /// The alternate signal stack, if one is enabled.
+    pub fn sigaltstack(&self) -> Option<SigAltStack> {
+        self.sigaltstack.get()
+    }
+
     /// Grab state from this task into a structure that we can use to
     /// initialize a new task via os_clone_into/os_fork_into and copy_state.
     pub(in super::super) fn capture_state(&self) -> CapturedState {
@@ -1398,6 +1461,7 @@ impl TaskInner {
             wait_status: self.wait_status.get(),
             ticks: self.ticks.get(),
             top_of_stack: self.top_of_stack.get(),
+            sigaltstack: self.sigaltstack.get(),
             thread_locals: *self.fetch_preload_thread_locals(),
         }
     }
@@ -1627,44 +1691,10 @@ impl TaskInner {
             SaveTraceeFdNumber::SaveFdTo(v) => *v = fd_number,
         }
 
-        let maybe_cpu_index: Option<u32>;
-        {
-            let trace = session.trace_stream().unwrap();
-            maybe_cpu_index = session.cpu_binding(&trace);
-        }
-        let is_recording = session.is_recording();
-        if let Some(mut cpu_index) = maybe_cpu_index {
-            // Set CPU affinity now, after we've created any helper threads
-            // (so they aren't affected), but before we create any
-            // tracees (so they are all affected).
-            // Note that we're binding rd itself to the same CPU as the
-            // tracees, since this seems to help performance.
-            if !set_cpu_affinity(cpu_index) {
-                if SessionInner::has_cpuid_faulting() && !is_recording {
-                    cpu_index = choose_cpu(BindCPU::RandomCPU).unwrap();
-                    if !set_cpu_affinity(cpu_index) {
-                        fatal!(
-                            "Can't bind to requested CPU {} even after we re-selected it",
-                            cpu_index
-                        )
-                    }
-                    // DIFF NOTE: The logic is slightly different in rr.
-                    if cpu_index != maybe_cpu_index.unwrap() {
-                        log!(LogWarn,
-                                     "Bound to CPU {} instead of selected {} because the latter is not available;\n\
-                                Hoping tracee doesn't use LSL instruction!", cpu_index, maybe_cpu_index.unwrap());
-                    }
-
-                    let mut trace_mut = session.trace_stream_mut().unwrap();
-                    trace_mut.set_bound_cpu(Some(cpu_index));
-                } else {
-                    fatal!(
-                        "Can't bind to requested CPU {}, and CPUID faulting not available",
-                        cpu_index
-                    )
-                }
-            }
-        }
+        // Set CPU affinity now, after we've created any helper threads
+        // (so they aren't affected), but before we create any
+        // tracees (so they are all affected).
+        bind_to_cpu(session);
 
         let mut tid: pid_t;
         // After fork() in a multithreaded program, the child can safely call only
@@ -1711,14 +1741,7 @@ impl TaskInner {
         // any abnormal exit of the rd process will leave the child paused and
         // parented by the init process, i.e. effectively leaked. After PTRACE_SEIZE
         // with PTRACE_O_EXITKILL, the tracee will die if rd dies.
-        let mut options = PTRACE_O_TRACESYSGOOD | PTRACE_O_TRACEFORK | PTRACE_O_TRACECLONE;
-        if !Flags::get().disable_ptrace_exit_events {
-            options |= PTRACE_O_TRACEEXIT;
-        }
-        if session.is_recording() {
-            options |= PTRACE_O_TRACEVFORK | PTRACE_O_TRACESECCOMP | PTRACE_O_TRACEEXEC;
-        }
-
+        let options = ptrace_options(session);
         let mut res = unsafe { ptrace(PTRACE_SEIZE, tid, 0, options | PTRACE_O_EXITKILL) };
         if res < 0 && errno() == EINVAL {
             // PTRACE_O_EXITKILL was added in kernel 3.8, and we only need
@@ -1743,34 +1766,13 @@ impl TaskInner {
             }
             fatal!("PTRACE_SEIZE failed for tid `{}`{}", tid, hint);
         }
-        let next_t_serial = session.next_task_serial();
-        let wrapped_t = Rc::new_cyclic(|weak_self| {
-            let t = session.new_task(
-                tid,
-                rec_tid,
-                next_t_serial,
-                RD_NATIVE_ARCH,
-                weak_self.clone(),
-            );
-            t
-        });
-
-        let tg = session.create_initial_tg(wrapped_t.clone());
-        *wrapped_t.tg.borrow_mut() = Some(tg);
-        let addr_space = session.create_vm(&**wrapped_t, None, None);
-        *wrapped_t.as_.borrow_mut() = Some(addr_space);
-        *wrapped_t.fds.borrow_mut() = Some(FdTable::create(&**wrapped_t));
+        let wrapped_t = new_initial_task(session, tid, rec_tid);
         let fds: FdTableSharedPtr = wrapped_t.fds.borrow().as_ref().unwrap().clone();
         setup_fd_table(&**wrapped_t, &fds, fd_number);
 
         // Install signal handler here, so that when creating the first RecordTask
         // it sees the exact same signal state in the parent as will be in the child.
-        let sa = SigAction::new(
-            SigHandler::Handler(handle_alarm_signal),
-            SaFlags::empty(), // No SA_RESTART, so waitpid() will be interrupted
-            SigSet::empty(),
-        );
-        unsafe { sigaction(Signal::SIGALRM, &sa) }.unwrap();
+        install_alarm_handler();
 
         wrapped_t.wait(None);
         if wrapped_t.maybe_ptrace_event() == PTRACE_EVENT_EXIT {
@@ -1797,6 +1799,96 @@ impl TaskInner {
         wrapped_t
     }
 
+    /// Seize the running process `pid` and stop all its threads, for
+    /// `rd record --attach`. Unlike `spawn()`, nothing is set up in the
+    /// process: the caller does that with remote syscalls once the process
+    /// has its address space (see `set_up_attached_process()`). Returns the
+    /// task for the thread group leader and the tids of the other threads,
+    /// which are stopped but get their tasks later (see
+    /// `CloneReason::AttachedThread`).
+    pub(in super::super) fn attach(
+        session: &dyn Session,
+        pid: pid_t,
+    ) -> (TaskSharedPtr, Vec<pid_t>) {
+        debug_assert_eq!(session.tasks().len(), 0);
+        debug_assert!(session.is_recording());
+
+        // The process was started unbound, unlike a spawned tracee.
+        let maybe_cpu_index = bind_to_cpu(session);
+        let bind_thread = |tid: pid_t| {
+            if let Some(cpu_index) = maybe_cpu_index {
+                let mut mask = CpuSet::new();
+                mask.set(cpu_index as usize).unwrap();
+                if let Err(e) = sched_setaffinity(Pid::from_raw(tid), &mask) {
+                    fatal!("Couldn't bind thread {} to CPU {}: {:?}", tid, cpu_index, e);
+                }
+            }
+        };
+
+        // No PTRACE_O_EXITKILL: rd detaches from the process when recording
+        // ends and lets it carry on.
+        let options = ptrace_options(session);
+        if 0 != unsafe { ptrace(PTRACE_SEIZE, pid, 0, options) } {
+            let hint = if errno() == EPERM {
+                "; is it already traced, or does /proc/sys/kernel/yama/ptrace_scope forbid it?"
+            } else {
+                ""
+            };
+            clean_fatal!(
+                "PTRACE_SEIZE of process {} failed: {}{}",
+                pid,
+                errno_name(errno()),
+                hint
+            );
+        }
+        if 0 != unsafe { ptrace(PTRACE_INTERRUPT, pid, 0, 0) } {
+            fatal!("PTRACE_INTERRUPT of process {} failed", pid);
+        }
+        bind_thread(pid);
+        install_alarm_handler();
+        if !wait_for_attach_stop(pid, pid) {
+            clean_fatal!("Process {} exited while rd was attaching to it", pid);
+        }
+
+        // Threads can come and go until all of them are stopped, so keep
+        // going over /proc/PID/task until there are no new ones.
+        let mut seen: Vec<pid_t> = vec![pid];
+        let mut threads: Vec<pid_t> = Vec::new();
+        loop {
+            let new_tids: Vec<pid_t> = thread_tids(pid)
+                .into_iter()
+                .filter(|tid| !seen.contains(tid))
+                .collect();
+            if new_tids.is_empty() {
+                break;
+            }
+            for tid in new_tids {
+                seen.push(tid);
+                if 0 == unsafe { ptrace(PTRACE_SEIZE, tid, 0, options) } {
+                    if 0 != unsafe { ptrace(PTRACE_INTERRUPT, tid, 0, 0) } {
+                        // It exited.
+                        continue;
+                    }
+                } else if errno() != EPERM {
+                    // It exited.
+                    continue;
+                }
+                // Otherwise a thread rd already seized started it, so it's
+                // traced already (PTRACE_O_TRACECLONE) and starts out stopped.
+                bind_thread(tid);
+                if wait_for_attach_stop(pid, tid) {
+                    threads.push(tid);
+                }
+            }
+        }
+
+        let wrapped_t = new_initial_task(session, pid, None);
+        wrapped_t.did_waitpid(WaitStatus::for_ptrace_event(PTRACE_EVENT_STOP));
+        wrapped_t.clear_wait_status();
+
+        (wrapped_t, threads)
+    }
+
     pub(in super::super) fn preload_thread_locals(&self) -> Option<NonNull<c_void>> {
         preload_thread_locals_local_addr(&self.vm())
     }
@@ -1860,6 +1952,151 @@ fn run_initial_child(
     // Never returns!
 }
 
+/// Bind rd to the CPU the session's tracees should run on, if any, and return
+/// it. Note that we're binding rd itself to the same CPU as the tracees,
+/// since this seems to help performance.
+fn bind_to_cpu(session: &dyn Session) -> Option<u32> {
+    let maybe_cpu_index: Option<u32>;
+    {
+        let trace = session.trace_stream().unwrap();
+        maybe_cpu_index = session.cpu_binding(&trace);
+    }
+    let is_recording = session.is_recording();
+    let mut cpu_index = maybe_cpu_index?;
+    if !set_cpu_affinity(cpu_index) {
+        if SessionInner::has_cpuid_faulting() && !is_recording {
+            cpu_index = choose_cpu(BindCPU::RandomCPU).unwrap();
+            if !set_cpu_affinity(cpu_index) {
+                fatal!(
+                    "Can't bind to requested CPU {} even after we re-selected it",
+                    cpu_index
+                )
+            }
+            // DIFF NOTE: The logic is slightly different in rr.
+            if cpu_index != maybe_cpu_index.unwrap() {
+                log!(
+                    LogWarn,
+                    "Bound to CPU {} instead of selected {} because the latter is not available;\n\
+                        Hoping tracee doesn't use LSL instruction!",
+                    cpu_index,
+                    maybe_cpu_index.unwrap()
+                );
+            }
+
+            let mut trace_mut = session.trace_stream_mut().unwrap();
+            trace_mut.set_bound_cpu(Some(cpu_index));
+        } else {
+            fatal!(
+                "Can't bind to requested CPU {}, and CPUID faulting not available",
+                cpu_index
+            )
+        }
+    }
+    Some(cpu_index)
+}
+
+/// The tids of the threads of process `pid`.
+fn thread_tids(pid: pid_t) -> Vec<pid_t> {
+    match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(e) => fatal!("Can't list the threads of process {}: {:?}", pid, e),
+    }
+}
+
+/// Wait for thread `tid` of process `pid`, which rd is attaching to, to stop
+/// for rd's PTRACE_INTERRUPT, letting the signals and ptrace events it runs
+/// into before that through. Returns false if the thread exited instead.
+fn wait_for_attach_stop(pid: pid_t, tid: pid_t) -> bool {
+    loop {
+        let mut raw_status: i32 = 0;
+        if tid != unsafe { waitpid(tid, &mut raw_status, __WALL) } {
+            fatal!("waitpid({}) failed: {}", tid, errno_name(errno()));
+        }
+        let status = WaitStatus::new(raw_status);
+        log!(LogDebug, "  attaching: waitpid({}) status {}", tid, status);
+        if status.exit_code().is_some() || status.fatal_sig().is_some() {
+            return false;
+        }
+        if status.maybe_group_stop_sig().is_sig() {
+            // The stop PTRACE_INTERRUPT asked for.
+            return true;
+        }
+
+        let event = status.maybe_ptrace_event();
+        let mut sig_raw = 0;
+        if event == PTRACE_EVENT_EXEC || (event == PTRACE_EVENT_EXIT && tid == pid) {
+            clean_fatal!(
+                "Process {} exec'd or exited while rd was attaching to it",
+                pid
+            );
+        } else if event == PTRACE_EVENT_FORK || event == PTRACE_EVENT_VFORK {
+            // The child is a process of its own, which rd doesn't record. Let it
+            // go once it's stopped.
+            let mut child: c_ulong = 0;
+            if 0 == unsafe { ptrace(PTRACE_GETEVENTMSG, tid, 0, &mut child) } {
+                unsafe {
+                    waitpid(child as pid_t, &mut raw_status, __WALL);
+                    ptrace(PTRACE_DETACH, child as pid_t, 0, 0);
+                }
+            }
+        } else if !event.is_ptrace_event() && status.maybe_stop_sig().is_sig() {
+            // A signal was about to be delivered before the interrupt took
+            // effect. Let it through as it would have been without rd.
+            sig_raw = status.maybe_stop_sig().unwrap_sig().as_raw();
+        }
+        // Threads it starts are picked up from /proc/PID/task.
+        if 0 != unsafe { ptrace(PTRACE_CONT, tid, 0, sig_raw) } && errno() != ESRCH {
+            fatal!("PTRACE_CONT of thread {} failed", tid);
+        }
+    }
+}
+
+/// The PTRACE_O_ options to seize the initial tracee with.
+fn ptrace_options(session: &dyn Session) -> u32 {
+    let mut options = PTRACE_O_TRACESYSGOOD | PTRACE_O_TRACEFORK | PTRACE_O_TRACECLONE;
+    if !Flags::get().disable_ptrace_exit_events {
+        options |= PTRACE_O_TRACEEXIT;
+    }
+    if session.is_recording() {
+        options |= PTRACE_O_TRACEVFORK | PTRACE_O_TRACESECCOMP | PTRACE_O_TRACEEXEC;
+    }
+    options
+}
+
+/// Create the task for the initial tracee `tid`, which rd just seized, along
+/// with its thread group, address space and fd table.
+fn new_initial_task(session: &dyn Session, tid: pid_t, rec_tid: Option<pid_t>) -> TaskSharedPtr {
+    let next_t_serial = session.next_task_serial();
+    let wrapped_t = Rc::new_cyclic(|weak_self| {
+        let t = session.new_task(
+            tid,
+            rec_tid,
+            next_t_serial,
+            RD_NATIVE_ARCH,
+            weak_self.clone(),
+        );
+        t
+    });
+
+    let tg = session.create_initial_tg(wrapped_t.clone());
+    *wrapped_t.tg.borrow_mut() = Some(tg);
+    let addr_space = session.create_vm(&**wrapped_t, None, None);
+    *wrapped_t.as_.borrow_mut() = Some(addr_space);
+    *wrapped_t.fds.borrow_mut() = Some(FdTable::create(&**wrapped_t));
+    wrapped_t
+}
+
+fn install_alarm_handler() {
+    let sa = SigAction::new(
+        SigHandler::Handler(handle_alarm_signal),
+        SaFlags::empty(), // No SA_RESTART, so waitpid() will be interrupted
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGALRM, &sa) }.unwrap();
+}
+
 fn create_seccomp_filter() -> SeccompFilter {
     let mut f = SeccompFilter::new();
     for e in AddressSpace::rd_page_syscalls() {
@@ -1896,6 +2133,124 @@ fn setup_fd_table(t: &dyn Task, fds: &FdTableSharedPtr, tracee_socket_fd_number:
     );
 }
 
+/// Do for the process rd attached to what `set_up_process()` does for a
+/// spawned one, with remote syscalls: reserve rd's fds and connect the
+/// tracee socket, then set up the fd table to match. The rd page must
+/// already be mapped.
+pub(in super::super) fn set_up_attached_process(t: &dyn Task) {
+    let session = t.session();
+    let pid = t.tid();
+    let arch = t.arch();
+
+    // rd's end of the tracee socket. The tracee connects to it by name, since
+    // there's no other way to hand it a socket.
+    let listener = match socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    ) {
+        Ok(fd) => ScopedFd::from_raw(fd),
+        Err(e) => fatal!("socket() failed: {:?}", e),
+    };
+    let name = format!("rd-attach-{}-{}", getpid(), pid);
+    let addr = UnixAddr::new_abstract(name.as_bytes()).unwrap();
+    if let Err(e) = bind(listener.as_raw(), &SockAddr::Unix(addr)) {
+        fatal!("Couldn't bind tracee socket: {:?}", e);
+    }
+    if let Err(e) = listen(listener.as_raw(), 1) {
+        fatal!("Couldn't listen on tracee socket: {:?}", e);
+    }
+
+    // Find a usable FD number in the tracee, as spawn() does in rd.
+    let mut fd_number: i32 = RD_RESERVED_SOCKET_FD;
+    while lstat(format!("/proc/{}/fd/{}", pid, fd_number).as_str()).is_ok() {
+        fd_number += 1;
+    }
+
+    let mut remote = AutoRemoteSyscalls::new(t);
+    for &(path, flags, target_fd) in &[
+        ("/dev/null", O_WRONLY | O_CLOEXEC, RD_MAGIC_SAVE_DATA_FD),
+        (
+            "/",
+            O_PATH | O_DIRECTORY | O_CLOEXEC,
+            RD_RESERVED_ROOT_DIR_FD,
+        ),
+    ] {
+        let fd = {
+            let mut remote_path = AutoRestoreMem::push_cstr(&mut remote, path);
+            let remote_addr = remote_path.get().unwrap();
+            rd_syscall!(
+                remote_path,
+                syscall_number_for_openat(arch),
+                AT_FDCWD,
+                remote_addr.as_usize(),
+                flags
+            ) as i32
+        };
+        if fd < 0 {
+            fatal!(
+                "Process {} couldn't open {}: {}",
+                pid,
+                path,
+                errno_name(-fd)
+            );
+        }
+        rd_infallible_syscall!(
+            remote,
+            syscall_number_for_dup3(arch),
+            fd,
+            target_fd,
+            O_CLOEXEC
+        );
+        rd_infallible_syscall!(remote, syscall_number_for_close(arch), fd);
+    }
+
+    let sock = rd_infallible_syscall!(
+        remote,
+        syscall_number_for_socket(arch),
+        AF_UNIX,
+        SOCK_STREAM | SOCK_CLOEXEC,
+        0
+    ) as i32;
+    let mut sun: sockaddr_un = unsafe { zeroed() };
+    sun.sun_family = AF_UNIX as sa_family_t;
+    // sun_path[0] stays 0: the name is in the abstract namespace.
+    for (i, &b) in name.as_bytes().iter().enumerate() {
+        sun.sun_path[i + 1] = b as c_char;
+    }
+    let sun_len = size_of::<sa_family_t>() + 1 + name.len();
+    {
+        let mut remote_sun =
+            AutoRestoreMem::new(&mut remote, Some(&u8_slice(&sun)[0..sun_len]), sun_len);
+        let remote_addr = remote_sun.get().unwrap();
+        rd_infallible_syscall!(
+            remote_sun,
+            syscall_number_for_connect(arch),
+            sock,
+            remote_addr.as_usize(),
+            sun_len
+        );
+    }
+    rd_infallible_syscall!(
+        remote,
+        syscall_number_for_dup3(arch),
+        sock,
+        fd_number,
+        O_CLOEXEC
+    );
+    rd_infallible_syscall!(remote, syscall_number_for_close(arch), sock);
+    match accept4(listener.as_raw(), SockFlag::SOCK_CLOEXEC) {
+        Ok(fd) => *session.tracee_socket_fd().borrow_mut() = ScopedFd::from_raw(fd),
+        Err(e) => fatal!("Couldn't accept tracee socket connection: {:?}", e),
+    }
+    session.tracee_socket_fd_number.set(fd_number);
+    drop(remote);
+
+    let fds: FdTableSharedPtr = t.fd_table();
+    setup_fd_table(t, &fds, fd_number);
+}
+
 /// Prepare this process and its ancestors for recording/replay by
 /// preventing direct access to sources of nondeterminism, and ensuring
 /// that rd bugs don't adversely affect the underlying system.
@@ -1980,6 +2335,22 @@ fn set_up_process(
         spawned_child_fatal_error(err_fd, "error setting up prctl");
     }
 
+    // With `record --no-aslr`, the tracees (and, following the trace header,
+    // their replay) start out with ASLR disabled. The personality is
+    // inherited across fork and exec.
+    let aslr_disabled = match session.as_record() {
+        Some(rs) => rs.aslr_disabled(),
+        None => session
+            .as_replay()
+            .map_or(false, |rs| rs.trace_reader().aslr_disabled()),
+    };
+    if aslr_disabled {
+        let persona = unsafe { personality(0xffffffff) };
+        if 0 > persona || 0 > unsafe { personality((persona | ADDR_NO_RANDOMIZE) as c_ulong) } {
+            spawned_child_fatal_error(err_fd, "error disabling ASLR with personality()");
+        }
+    }
+
     // If we're in setuid_sudo mode, we have CAP_SYS_ADMIN, so we don't need to
     // set NO_NEW_PRIVS here in order to install the seccomp filter later. In,
     // emulate any potentially privileged, operations, so we might as well set
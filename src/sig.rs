@@ -49,6 +49,9 @@ impl Sig {
         self.0
     }
 
+    /// # Safety
+    ///
+    /// `sig` must be a valid signal number; nothing checks it.
     pub unsafe fn from_raw_unchecked(sig: i32) -> Self {
         Self(sig)
     }
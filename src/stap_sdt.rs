@@ -0,0 +1,437 @@
+//! SystemTap SDT (USDT) probes, as described by the `.note.stapsdt` ELF notes
+//! that `<sys/sdt.h>` emits. See
+//! https://sourceware.org/systemtap/wiki/UserSpaceProbeImplementation
+//!
+//! With `rd record --stap-sdt`, we plant an internal breakpoint on the `nop`
+//! at each probe site of the executable files the tracees map, and record
+//! every hit as an `EvSdtProbe` event with the probe's argument values.
+//! Probes guarded by a semaphore only fire if something else set it; we
+//! don't write to tracee memory to do so.
+
+use crate::{
+    gdb_register::{
+        GdbRegister, DREG_EAX, DREG_EBP, DREG_EBX, DREG_ECX, DREG_EDI, DREG_EDX, DREG_EIP,
+        DREG_ESI, DREG_ESP, DREG_R10, DREG_R11, DREG_R12, DREG_R13, DREG_R14, DREG_R15, DREG_R8,
+        DREG_R9, DREG_RAX, DREG_RBP, DREG_RBX, DREG_RCX, DREG_RDI, DREG_RDX, DREG_RIP, DREG_RSI,
+        DREG_RSP,
+    },
+    kernel_abi::SupportedArch,
+    log::LogLevel::LogWarn,
+    registers::Registers,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::{RemotePtr, Void},
+    session::{
+        address_space::kernel_mapping::KernelMapping,
+        task::{record_task::RecordTask, Task},
+    },
+};
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use nix::sys::mman::ProtFlags;
+use std::{convert::TryInto, str};
+
+const NT_STAPSDT: u32 = 3;
+const STAPSDT_NOTE_NAME: &str = "stapsdt";
+const STAPSDT_NOTE_SECTION: &str = ".note.stapsdt";
+const STAPSDT_BASE_SECTION: &str = ".stapsdt.base";
+/// What `<sys/sdt.h>` puts at each probe site on x86.
+const NOP_INSN: u8 = 0x90;
+
+/// One argument of a probe, e.g. `-4@%edi` or `8@-16(%rbp)`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SdtArg {
+    /// Size in bytes. Negative for signed values. 0 if the note didn't say.
+    pub size: i8,
+    /// The operand in AT&T assembler syntax.
+    pub operand: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SdtProbe {
+    pub provider: String,
+    pub name: String,
+    /// Link-time address of the probe's `nop`. Add the load bias to get the
+    /// runtime address.
+    pub pc: u64,
+    /// Link-time address of the probe's semaphore, if it has one. Tracers
+    /// increment it to tell the program a probe is being watched; rd doesn't.
+    pub semaphore: Option<u64>,
+    pub args: Vec<SdtArg>,
+    /// Where the probe's `nop` is in the file, if a loadable segment holds it.
+    pub file_offset: Option<u64>,
+}
+
+/// All SDT probes declared in the ELF file `data`. Returns an empty Vec if
+/// `data` isn't ELF or has no probes.
+pub fn sdt_probes(data: &[u8]) -> Vec<SdtProbe> {
+    let elf = match Elf::parse(data) {
+        Ok(elf) => elf,
+        Err(_) => return Vec::new(),
+    };
+    // If prelink or similar moved the binary after linking, the notes still
+    // hold the original addresses. The difference between where
+    // .stapsdt.base is now and where the notes say it was gives the shift.
+    let actual_base = elf
+        .section_headers
+        .iter()
+        .find(|sh| match elf.shdr_strtab.get(sh.sh_name) {
+            Some(Ok(name)) => name == STAPSDT_BASE_SECTION,
+            _ => false,
+        })
+        .map(|sh| sh.sh_addr);
+
+    let mut probes = Vec::new();
+    if let Some(notes) = elf.iter_note_sections(data, Some(STAPSDT_NOTE_SECTION)) {
+        for note in notes.flatten() {
+            if note.n_type != NT_STAPSDT || note.name != STAPSDT_NOTE_NAME {
+                continue;
+            }
+            if let Some(mut probe) = parse_note_desc(note.desc, elf.is_64, actual_base) {
+                probe.file_offset = elf
+                    .program_headers
+                    .iter()
+                    .find(|ph| {
+                        ph.p_type == PT_LOAD
+                            && ph.p_vaddr <= probe.pc
+                            && probe.pc - ph.p_vaddr < ph.p_filesz
+                    })
+                    .map(|ph| ph.p_offset + (probe.pc - ph.p_vaddr));
+                probes.push(probe);
+            }
+        }
+    }
+    probes
+}
+
+fn parse_note_desc(desc: &[u8], is_64: bool, actual_base: Option<u64>) -> Option<SdtProbe> {
+    let word_size = if is_64 { 8 } else { 4 };
+    let word = |i: usize| -> Option<u64> {
+        let bytes = desc.get(i * word_size..(i + 1) * word_size)?;
+        Some(if is_64 {
+            u64::from_le_bytes(bytes.try_into().unwrap())
+        } else {
+            u32::from_le_bytes(bytes.try_into().unwrap()) as u64
+        })
+    };
+    let mut pc = word(0)?;
+    let base = word(1)?;
+    let mut semaphore = word(2)?;
+    if let Some(actual_base) = actual_base {
+        if base != 0 {
+            pc = pc.wrapping_add(actual_base.wrapping_sub(base));
+            if semaphore != 0 {
+                semaphore = semaphore.wrapping_add(actual_base.wrapping_sub(base));
+            }
+        }
+    }
+
+    let mut strings = desc[3 * word_size..].split(|&b| b == 0);
+    let provider = str::from_utf8(strings.next()?).ok()?.to_owned();
+    let name = str::from_utf8(strings.next()?).ok()?.to_owned();
+    let args = str::from_utf8(strings.next().unwrap_or(&[])).ok()?;
+
+    Some(SdtProbe {
+        provider,
+        name,
+        pc,
+        semaphore: if semaphore == 0 {
+            None
+        } else {
+            Some(semaphore)
+        },
+        args: args.split_whitespace().map(parse_arg).collect(),
+        file_offset: None,
+    })
+}
+
+fn parse_arg(arg: &str) -> SdtArg {
+    if let Some(pos) = arg.find('@') {
+        if let Ok(size) = arg[0..pos].parse::<i8>() {
+            return SdtArg {
+                size,
+                operand: arg[pos + 1..].to_owned(),
+            };
+        }
+    }
+    // Old versions of sdt.h don't emit the size.
+    SdtArg {
+        size: 0,
+        operand: arg.to_owned(),
+    }
+}
+
+/// Plant a breakpoint at each probe site in `km`, which `t` just mapped.
+/// Does nothing unless `km` is an executable mapping of a file.
+pub fn plant_sdt_probes(t: &RecordTask, km: &KernelMapping) {
+    if !km.prot().contains(ProtFlags::PROT_EXEC) || km.inode() == 0 {
+        return;
+    }
+    let probes = t.session().as_record().unwrap().sdt_probes_in(km);
+    let start_offset = km.file_offset_bytes();
+    let end_offset = start_offset + km.size() as u64;
+    for probe in probes.iter() {
+        let file_offset = match probe.file_offset {
+            Some(offset) if start_offset <= offset && offset < end_offset => offset,
+            _ => continue,
+        };
+        let addr =
+            RemoteCodePtr::from(km.start().as_usize() + (file_offset - start_offset) as usize);
+        // The breakpoint must replace exactly one instruction for the tracee
+        // to carry on right after it without us stepping over anything.
+        let mut insn = [0u8; 1];
+        match t.read_bytes_fallible(addr.to_data_ptr::<Void>(), &mut insn) {
+            Ok(1) if insn[0] == NOP_INSN => (),
+            _ => {
+                log!(
+                    LogWarn,
+                    "No nop at {}:{} probe site {} in {:?}; not planting it",
+                    probe.provider,
+                    probe.name,
+                    addr,
+                    km.fsname()
+                );
+                continue;
+            }
+        }
+        t.vm().add_sdt_probe(addr, probe.clone());
+    }
+}
+
+/// The value of `arg` for `t`, which is right after its probe's `nop`. None if
+/// we can't evaluate the operand, e.g. because it names a symbol.
+pub fn sdt_arg_value(t: &dyn Task, arg: &SdtArg) -> Option<i64> {
+    let size = match arg.size {
+        0 if t.arch() == SupportedArch::X64 => 8,
+        0 => 4,
+        size => size,
+    };
+    let operand = arg.operand.as_str();
+    let raw = if let Some(imm) = operand.strip_prefix('$') {
+        parse_int(imm)? as u64
+    } else if let Some(name) = operand.strip_prefix('%') {
+        register_value(t.regs_ref(), name)?
+    } else {
+        let addr = memory_operand_addr(t.regs_ref(), operand)?;
+        let mut buf = [0u8; 8];
+        let len = (size as i16).abs() as usize;
+        let bytes = buf.get_mut(0..len)?;
+        match t.read_bytes_fallible(RemotePtr::new(addr), bytes) {
+            Ok(read) if read == len => (),
+            _ => return None,
+        }
+        u64::from_le_bytes(buf)
+    };
+    Some(match size {
+        1 => raw as u8 as i64,
+        -1 => raw as i8 as i64,
+        2 => raw as u16 as i64,
+        -2 => raw as i16 as i64,
+        4 => raw as u32 as i64,
+        -4 => raw as i32 as i64,
+        8 | -8 => raw as i64,
+        _ => return None,
+    })
+}
+
+/// A decimal or `0x` hexadecimal integer, possibly negative.
+fn parse_int(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let v = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if negative { v.wrapping_neg() } else { v })
+}
+
+/// The address a `disp(%base,%index,scale)` operand refers to. Any of the
+/// parts may be missing, but `disp` must be a number.
+fn memory_operand_addr(regs: &Registers, operand: &str) -> Option<usize> {
+    let open = operand.find('(')?;
+    let inner = operand[open + 1..].strip_suffix(')')?;
+    let disp = if open == 0 {
+        0
+    } else {
+        parse_int(&operand[0..open])?
+    };
+    let register = |part: &str| register_value(regs, part.trim().strip_prefix('%')?);
+    let mut parts = inner.split(',');
+    let base = match parts.next()?.trim() {
+        "" => 0,
+        part => register(part)?,
+    };
+    let index = match parts.next() {
+        Some(part) => register(part)?,
+        None => 0,
+    };
+    let scale = match parts.next() {
+        Some(part) => part.trim().parse::<u64>().ok()?,
+        None => 1,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    let addr = base
+        .wrapping_add(index.wrapping_mul(scale))
+        .wrapping_add(disp as u64);
+    Some(match regs.arch() {
+        SupportedArch::X86 => addr as u32 as usize,
+        SupportedArch::X64 => addr as usize,
+    })
+}
+
+/// The value of register `name` (e.g. `rdi`, `r8d`, `ax` or `al`), zero
+/// extended.
+fn register_value(regs: &Registers, name: &str) -> Option<u64> {
+    const LEGACY: [(&str, GdbRegister, GdbRegister); 9] = [
+        ("ax", DREG_RAX, DREG_EAX),
+        ("bx", DREG_RBX, DREG_EBX),
+        ("cx", DREG_RCX, DREG_ECX),
+        ("dx", DREG_RDX, DREG_EDX),
+        ("si", DREG_RSI, DREG_ESI),
+        ("di", DREG_RDI, DREG_EDI),
+        ("bp", DREG_RBP, DREG_EBP),
+        ("sp", DREG_RSP, DREG_ESP),
+        ("ip", DREG_RIP, DREG_EIP),
+    ];
+    const NUMBERED: [GdbRegister; 8] = [
+        DREG_R8, DREG_R9, DREG_R10, DREG_R11, DREG_R12, DREG_R13, DREG_R14, DREG_R15,
+    ];
+    let is_x64 = regs.arch() == SupportedArch::X64;
+    let legacy = |base: &str| {
+        LEGACY
+            .iter()
+            .find(|l| l.0 == base)
+            .map(|l| if is_x64 { l.1 } else { l.2 })
+    };
+
+    // The register, how many of its low bits `name` refers to, and how far
+    // up they start.
+    let (reg, bits, shift) = if let Some(rest) = name.strip_prefix('r').filter(|_| is_x64) {
+        let digits_end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits_end > 0 {
+            let n = rest[0..digits_end].parse::<usize>().ok()?;
+            let bits = match &rest[digits_end..] {
+                "" => 64,
+                "d" => 32,
+                "w" => 16,
+                "b" => 8,
+                _ => return None,
+            };
+            (*NUMBERED.get(n.checked_sub(8)?)?, bits, 0)
+        } else {
+            (legacy(rest)?, 64, 0)
+        }
+    } else if let Some(rest) = name.strip_prefix('e') {
+        (legacy(rest)?, 32, 0)
+    } else if let Some(reg) = legacy(name) {
+        (reg, 16, 0)
+    } else if let Some(base) = name.strip_suffix('h') {
+        (legacy(&format!("{}x", base))?, 8, 8)
+    } else if matches!(name, "sil" | "dil" | "bpl" | "spl") && is_x64 {
+        (legacy(&name[0..2])?, 8, 0)
+    } else {
+        let base = name.strip_suffix('l')?;
+        (legacy(&format!("{}x", base))?, 8, 0)
+    };
+
+    let mut buf = [0u8; 8];
+    regs.read_register(&mut buf, reg)?;
+    let mask = if bits == 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    };
+    Some((u64::from_le_bytes(buf) >> shift) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stapsdt_note() {
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&0x1234u64.to_le_bytes());
+        desc.extend_from_slice(&0x2000u64.to_le_bytes());
+        desc.extend_from_slice(&0x4010u64.to_le_bytes());
+        desc.extend_from_slice(b"libc\0setjmp\08@%rdi -4@%esi 8@-16(%rbp)\0");
+
+        let probe = parse_note_desc(&desc, true, Some(0x3000)).unwrap();
+        assert_eq!(probe.provider, "libc");
+        assert_eq!(probe.name, "setjmp");
+        assert_eq!(probe.pc, 0x2234);
+        assert_eq!(probe.semaphore, Some(0x5010));
+        assert_eq!(
+            probe.args,
+            vec![
+                SdtArg {
+                    size: 8,
+                    operand: "%rdi".into()
+                },
+                SdtArg {
+                    size: -4,
+                    operand: "%esi".into()
+                },
+                SdtArg {
+                    size: 8,
+                    operand: "-16(%rbp)".into()
+                },
+            ]
+        );
+
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&0x100u32.to_le_bytes());
+        desc.extend_from_slice(&0u32.to_le_bytes());
+        desc.extend_from_slice(&0u32.to_le_bytes());
+        desc.extend_from_slice(b"p\0n\0\0");
+        let probe = parse_note_desc(&desc, false, None).unwrap();
+        assert_eq!(probe.pc, 0x100);
+        assert_eq!(probe.semaphore, None);
+        assert!(probe.args.is_empty());
+
+        assert!(parse_note_desc(&desc[0..8], false, None).is_none());
+    }
+
+    #[test]
+    fn operand_registers_and_addresses() {
+        let mut regs = Registers::new(SupportedArch::X64);
+        regs.set_di(0x1122_3344_5566_7788);
+        regs.set_cx(0xabcd);
+        regs.set_r8(0xffff_ffff_0000_0010);
+        regs.set_sp(RemotePtr::new(0x7fff_0000));
+
+        assert_eq!(register_value(&regs, "rdi"), Some(0x1122_3344_5566_7788));
+        assert_eq!(register_value(&regs, "edi"), Some(0x5566_7788));
+        assert_eq!(register_value(&regs, "di"), Some(0x7788));
+        assert_eq!(register_value(&regs, "dil"), Some(0x88));
+        assert_eq!(register_value(&regs, "cl"), Some(0xcd));
+        assert_eq!(register_value(&regs, "ch"), Some(0xab));
+        assert_eq!(register_value(&regs, "r8"), Some(0xffff_ffff_0000_0010));
+        assert_eq!(register_value(&regs, "r8d"), Some(0x10));
+        assert_eq!(register_value(&regs, "r8b"), Some(0x10));
+        assert_eq!(register_value(&regs, "r8dd"), None);
+        assert_eq!(register_value(&regs, "r7"), None);
+        assert_eq!(register_value(&regs, "r16"), None);
+        assert_eq!(register_value(&regs, "xyz"), None);
+
+        assert_eq!(memory_operand_addr(&regs, "-16(%rsp)"), Some(0x7ffe_fff0));
+        assert_eq!(memory_operand_addr(&regs, "(%rsp)"), Some(0x7fff_0000));
+        assert_eq!(
+            memory_operand_addr(&regs, "0x8(%rsp,%r8d,4)"),
+            Some(0x7fff_0048)
+        );
+        assert_eq!(memory_operand_addr(&regs, "counter(%rip)"), None);
+        assert_eq!(memory_operand_addr(&regs, "%rsp"), None);
+
+        let mut regs = Registers::new(SupportedArch::X86);
+        regs.set_di(0x1234_5678);
+        assert_eq!(register_value(&regs, "edi"), Some(0x1234_5678));
+        assert_eq!(register_value(&regs, "rdi"), None);
+        assert_eq!(register_value(&regs, "r8"), None);
+        assert_eq!(register_value(&regs, "dil"), None);
+    }
+}
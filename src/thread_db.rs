@@ -1,6 +1,7 @@
 use crate::{
     bindings::{kernel::user_regs_struct, thread_db},
-    kernel_abi::SupportedArch,
+    extra_registers::Format,
+    kernel_abi::{SupportedArch, RD_NATIVE_ARCH},
     log::LogDebug,
     remote_ptr::{RemotePtr, Void},
     session::task::TaskSharedPtr,
@@ -187,51 +188,44 @@ impl ThreadDb {
         self.symbols.insert(name, address);
     }
 
-    pub fn new(tgid: pid_t) -> Box<ThreadDb> {
+    /// Load libthread_db. Returns `None` if it's missing or unusable, e.g. on
+    /// systems that don't use glibc; TLS lookups are then not supported.
+    ///
+    /// DIFF NOTE: rr's constructor can't fail; it records whether loading
+    /// worked in `loaded`.
+    pub fn new(tgid: pid_t) -> Option<Box<ThreadDb>> {
         let thread_db_library = unsafe { libc::dlopen(LIBRARY_NAME.as_ptr() as _, libc::RTLD_NOW) };
         if thread_db_library.is_null() {
-            fatal!("load_library dlopen failed: {:?}", unsafe {
+            log!(LogDebug, "load_library dlopen failed: {:?}", unsafe {
                 CStr::from_ptr(libc::dlerror())
             });
+            return None;
         }
 
-        let ptr = find_function(thread_db_library, b"td_thr_tls_get_addr\0");
-        if ptr.is_null() {
-            fatal!("Could not find function td_thr_tls_get_addr");
-        }
-        let td_thr_tls_get_addr_fn: TdThrTlsGetAddrFn = unsafe { mem::transmute(ptr) };
-
-        let ptr = find_function(thread_db_library, b"td_ta_delete\0");
-        if ptr.is_null() {
-            fatal!("Could not find function td_ta_delete");
-        }
-        let td_ta_delete_fn: TdTaDeleteFn = unsafe { mem::transmute(ptr) };
-
-        let ptr = find_function(thread_db_library, b"td_symbol_list\0");
-        if ptr.is_null() {
-            fatal!("Could not find function td_symbol_list");
-        }
-        let td_symbol_list_fn: TdSymbolListFn = unsafe { mem::transmute(ptr) };
-
-        let ptr = find_function(thread_db_library, b"td_ta_new\0");
-        if ptr.is_null() {
-            fatal!("Could not find function td_ta_new");
+        let maybe_db = unsafe { Self::from_library(thread_db_library, tgid) };
+        if maybe_db.is_none() {
+            unsafe { libc::dlclose(thread_db_library) };
         }
-        let td_ta_new_fn: TdTaNewFn = unsafe { mem::transmute(ptr) };
+        maybe_db
+    }
 
-        let ptr = find_function(thread_db_library, b"td_ta_map_lwp2thr\0");
-        if ptr.is_null() {
-            fatal!("Could not find function td_ta_map_lwp2thr");
-        }
-        let td_ta_map_lwp2thr_fn: TdTaMapLwp2ThrFn = unsafe { mem::transmute(ptr) };
+    unsafe fn from_library(thread_db_library: *mut c_void, tgid: pid_t) -> Option<Box<ThreadDb>> {
+        let td_thr_tls_get_addr_fn: TdThrTlsGetAddrFn =
+            mem::transmute(find_function(thread_db_library, b"td_thr_tls_get_addr\0")?);
+        let td_ta_delete_fn: TdTaDeleteFn =
+            mem::transmute(find_function(thread_db_library, b"td_ta_delete\0")?);
+        let td_symbol_list_fn: TdSymbolListFn =
+            mem::transmute(find_function(thread_db_library, b"td_symbol_list\0")?);
+        let td_ta_new_fn: TdTaNewFn =
+            mem::transmute(find_function(thread_db_library, b"td_ta_new\0")?);
+        let td_ta_map_lwp2thr_fn: TdTaMapLwp2ThrFn =
+            mem::transmute(find_function(thread_db_library, b"td_ta_map_lwp2thr\0")?);
 
         let mut symbol_names: BTreeSet<OsString> = Default::default();
-        unsafe {
-            let mut syms = td_symbol_list_fn();
-            while !std::ptr::eq(*syms, std::ptr::null()) {
-                symbol_names.insert(OsStr::from_bytes(CStr::from_ptr(*syms).to_bytes()).to_owned());
-                syms = syms.add(1);
-            }
+        let mut syms = td_symbol_list_fn();
+        while !std::ptr::eq(*syms, std::ptr::null()) {
+            symbol_names.insert(OsStr::from_bytes(CStr::from_ptr(*syms).to_bytes()).to_owned());
+            syms = syms.add(1);
         }
         log!(LogDebug, "load_library OK");
         // Enclose in a Box so as to keep prochandle.db
@@ -251,7 +245,7 @@ impl ThreadDb {
         b.prochandle.db = &mut *b as *mut ThreadDb;
         b.prochandle.tgid = tgid;
 
-        b
+        Some(b)
     }
 
     /// Look up the symbol `symbol`. If not found return `None`
@@ -260,16 +254,18 @@ impl ThreadDb {
     }
 }
 
-fn find_function(thread_db_library: *mut c_void, name: &[u8]) -> *mut c_void {
-    let ret = unsafe { libc::dlsym(thread_db_library, name.as_ptr() as _) };
+unsafe fn find_function(thread_db_library: *mut c_void, name: &[u8]) -> Option<*mut c_void> {
+    let ret = libc::dlsym(thread_db_library, name.as_ptr() as _);
     if ret.is_null() {
         log!(
             LogDebug,
             "load_library failed to find {:?}",
             OsStr::from_bytes(&name[0..name.len() - 1])
         );
+        None
+    } else {
+        Some(ret)
     }
-    ret
 }
 
 #[no_mangle]
@@ -313,7 +309,10 @@ pub unsafe extern "C" fn ps_pdread(
     // We need any task associated with the thread group.  Here we assume
     // that all the tasks in the thread group share VM, which is enforced
     // by clone(2).
-    let task = (*(*h).thread_group).task_set().iter().next().unwrap();
+    let task = match (*(*h).thread_group).task_set().iter().next() {
+        Some(task) => task,
+        None => return thread_db::PS_ERR,
+    };
     let buf = slice::from_raw_parts_mut(buffer as *mut u8, len);
     task.read_bytes_helper(uaddr, buf, Some(&mut ok));
     log!(LogDebug, "ps_pdread(): {:?}", ok);
@@ -324,6 +323,9 @@ pub unsafe extern "C" fn ps_pdread(
     }
 }
 
+/// DIFF NOTE: This and the other callbacks that would modify the tracee are
+/// fatal in rr. libthread_db doesn't need them to look up TLS addresses, and a
+/// replay can't be modified anyway, so just report failure.
 #[no_mangle]
 pub unsafe extern "C" fn ps_pdwrite(
     _: *mut ps_prochandle,
@@ -331,7 +333,8 @@ pub unsafe extern "C" fn ps_pdwrite(
     _: *const c_void,
     _: thread_db::size_t,
 ) -> thread_db::ps_err_e {
-    fatal!("fn ps_pdwrite() not implemented");
+    log!(LogDebug, "ps_pdwrite() not supported");
+    thread_db::PS_ERR
 }
 
 #[no_mangle]
@@ -343,11 +346,14 @@ pub unsafe extern "C" fn ps_lgetregs(
     if (*h).thread_group.is_null() {
         fatal!("unexpected ps_lgetregs() call with uninitialized thread_group");
     }
-    // DIFF NOTE: In rr there is simply a debug_assert to make sure task is not null; we unwrap
-    let task = (*(*h).thread_group)
+    // DIFF NOTE: In rr there is simply a debug_assert to make sure task is not null
+    let task = match (*(*h).thread_group)
         .session()
         .find_task_from_rec_tid(rec_tid)
-        .unwrap();
+    {
+        Some(task) => task,
+        None => return thread_db::PS_BADLID,
+    };
 
     let regs = task.regs_ref().get_ptrace();
     copy_nonoverlapping(
@@ -365,16 +371,40 @@ pub unsafe extern "C" fn ps_lsetregs(
     _: thread_db::lwpid_t,
     _: *mut thread_db::elf_greg_t,
 ) -> thread_db::ps_err_e {
-    fatal!("fn ps_lsetregs() not implemented");
+    log!(LogDebug, "ps_lsetregs() not supported");
+    thread_db::PS_ERR
 }
 
+/// DIFF NOTE: Fatal in rr.
 #[no_mangle]
 pub unsafe extern "C" fn ps_lgetfpregs(
-    _: *mut ps_prochandle,
-    _: thread_db::lwpid_t,
-    _: *mut thread_db::prfpregset_t,
+    h: *mut ps_prochandle,
+    rec_tid: thread_db::lwpid_t,
+    result: *mut thread_db::prfpregset_t,
 ) -> thread_db::ps_err_e {
-    fatal!("fn ps_lgetfpregs() not implemented");
+    if (*h).thread_group.is_null() {
+        fatal!("unexpected ps_lgetfpregs() call with uninitialized thread_group");
+    }
+    let task = match (*(*h).thread_group)
+        .session()
+        .find_task_from_rec_tid(rec_tid)
+    {
+        Some(task) => task,
+        None => return thread_db::PS_BADLID,
+    };
+
+    let extra_regs = task.extra_regs_ref();
+    if extra_regs.format() != Format::XSave {
+        return thread_db::PS_NOFREGS;
+    }
+    let fpregs = extra_regs.get_user_fpregs_struct(RD_NATIVE_ARCH);
+    copy_nonoverlapping(
+        fpregs.as_ptr(),
+        result as *mut u8,
+        fpregs.len().min(mem::size_of::<thread_db::prfpregset_t>()),
+    );
+    log!(LogDebug, "ps_lgetfpregs() returns OK");
+    thread_db::PS_OK
 }
 
 #[no_mangle]
@@ -383,7 +413,8 @@ pub extern "C" fn ps_lsetfpregs(
     _: thread_db::lwpid_t,
     _: *const thread_db::prfpregset_t,
 ) -> thread_db::ps_err_e {
-    fatal!("fn ps_lsetfpregs() not implemented");
+    log!(LogDebug, "ps_lsetfpregs() not supported");
+    thread_db::PS_ERR
 }
 
 #[no_mangle]
@@ -403,11 +434,14 @@ pub unsafe extern "C" fn ps_get_thread_area(
     if (*h).thread_group.is_null() {
         fatal!("unexpected ps_get_thread_area() call with uninitialized thread_group");
     }
-    // DIFF NOTE: In rr there is simply a debug_assert to make sure task is not null; we unwrap
-    let task: TaskSharedPtr = (*(*h).thread_group)
+    // DIFF NOTE: In rr there is simply a debug_assert to make sure task is not null
+    let task: TaskSharedPtr = match (*(*h).thread_group)
         .session()
         .find_task_from_rec_tid(rec_tid)
-        .unwrap();
+    {
+        Some(task) => task,
+        None => return thread_db::PS_BADLID,
+    };
 
     if task.arch() == SupportedArch::X86 {
         let uval = val as u32;
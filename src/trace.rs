@@ -1,7 +1,13 @@
 pub mod compressed_reader;
 pub mod compressed_writer;
+pub mod object_store;
+pub mod trace_checksum;
+pub mod trace_codec;
+pub mod trace_dir;
 pub mod trace_frame;
+pub mod trace_pack;
 pub mod trace_reader;
+pub mod trace_splice;
 pub mod trace_stream;
 pub mod trace_task_event;
 pub mod trace_writer;
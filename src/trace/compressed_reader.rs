@@ -3,7 +3,6 @@ use crate::{
     trace::compressed_writer::BlockHeader,
     util::{read_to_end, u8_slice_mut},
 };
-use brotli_sys::{BrotliDecoderDecompress, BROTLI_DECODER_RESULT_SUCCESS};
 use nix::{
     fcntl::OFlag,
     sys::uio::pread,
@@ -147,6 +146,12 @@ impl CompressedReader {
                 ));
             }
 
+            // Don't decompress blocks we're going to skip entirely.
+            let skipped = self.skip_block(size)?;
+            if skipped > 0 {
+                size -= skipped;
+                continue;
+            }
             self.refill_buffer()?;
         }
 
@@ -195,7 +200,7 @@ impl CompressedReader {
             u8_slice_mut(&mut header),
             &mut offset,
         )? {
-            uncompressed_bytes += header.uncompressed_length as u64;
+            uncompressed_bytes += header.uncompressed_length() as u64;
             offset += header.compressed_length as u64;
         }
         Ok(uncompressed_bytes)
@@ -213,6 +218,40 @@ impl CompressedReader {
         }
     }
 
+    /// If the next block holds at most `max_size` bytes, move past it without
+    /// decompressing it and return its uncompressed length. Otherwise return 0.
+    /// Only call this when the buffer is exhausted.
+    fn skip_block(&mut self, max_size: usize) -> io::Result<usize> {
+        let mut header = BlockHeader::default();
+        let mut offset = self.fd_offset;
+        if !read_all(
+            &self.fd.as_ref().unwrap().borrow(),
+            u8_slice_mut(&mut header),
+            &mut offset,
+        )? {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Unexpected EOF encountered while reading a block header in CompressedReader",
+            ));
+        }
+        let uncompressed_length = header.uncompressed_length() as usize;
+        if uncompressed_length > max_size {
+            return Ok(0);
+        }
+        self.fd_offset = offset + header.compressed_length as u64;
+        let ch: u8 = 0;
+        self.eof = match pread(
+            self.fd.as_ref().unwrap().borrow().as_raw(),
+            &mut ch.to_le_bytes(),
+            self.fd_offset.try_into().unwrap(),
+        ) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
+        };
+        Ok(uncompressed_length)
+    }
+
     fn refill_buffer(&mut self) -> io::Result<()> {
         let mut header_vec: Vec<u8> = vec![0; size_of::<BlockHeader>()];
 
@@ -267,9 +306,18 @@ impl CompressedReader {
             Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
         };
 
-        self.buffer.resize(header.uncompressed_length as usize, 0);
+        let codec = match header.codec() {
+            Some(codec) => codec,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Block compressed with a codec unknown to this version of rd",
+                ))
+            }
+        };
+        self.buffer.resize(header.uncompressed_length() as usize, 0);
         self.buffer_read_pos = 0;
-        if !do_decompress(compressed_buf.as_slice(), &mut self.buffer) {
+        if !codec.decompress(compressed_buf.as_slice(), &mut self.buffer) {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
                 "There was a Decompression Error",
@@ -293,20 +341,6 @@ pub fn read_all(fd: &ScopedFd, data: &mut [u8], offset: &mut u64) -> io::Result<
     }
 }
 
-pub fn do_decompress(compressed: &[u8], uncompressed: &mut [u8]) -> bool {
-    let mut out_size = uncompressed.len();
-    let decompress_result = unsafe {
-        BrotliDecoderDecompress(
-            compressed.len(),
-            compressed.as_ptr(),
-            &raw mut out_size,
-            uncompressed.as_mut_ptr(),
-        )
-    };
-
-    decompress_result == BROTLI_DECODER_RESULT_SUCCESS && out_size == uncompressed.len()
-}
-
 impl BufRead for CompressedReader {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
         // If available to read bytes are "empty" and we have not yet reached EOF
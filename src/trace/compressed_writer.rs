@@ -1,9 +1,4 @@
-use crate::{scoped_fd::ScopedFd, util::write_all};
-use brotli_sys::{
-    BrotliEncoderCompressStream, BrotliEncoderCreateInstance, BrotliEncoderDestroyInstance,
-    BrotliEncoderSetParameter, BROTLI_OPERATION_FINISH, BROTLI_OPERATION_PROCESS,
-    BROTLI_PARAM_QUALITY,
-};
+use crate::{scoped_fd::ScopedFd, trace::trace_codec::TraceCodec, util::write_all};
 use nix::{fcntl::OFlag, sys::stat::Mode, unistd::fsync};
 use std::{
     cmp::min,
@@ -12,7 +7,6 @@ use std::{
     io::{Error, ErrorKind, Result, Write},
     mem::size_of,
     path::Path,
-    ptr,
     ptr::copy_nonoverlapping,
     slice,
     sync::{Arc, Condvar, Mutex},
@@ -32,10 +26,31 @@ pub enum WaitFlag {
     NoWait,
 }
 
+/// DIFF NOTE: rr blocks are always brotli. We keep the codec's tag in the top
+/// byte of the second word. Blocks are much smaller than 16MB, so this byte was
+/// always 0 (brotli) in traces from before codecs were pluggable.
 #[derive(Copy, Clone, Default)]
 pub struct BlockHeader {
     pub compressed_length: u32,
-    pub uncompressed_length: u32,
+    uncompressed_length_and_codec: u32,
+}
+
+const UNCOMPRESSED_LENGTH_MASK: u32 = 0x00ff_ffff;
+
+impl BlockHeader {
+    pub fn uncompressed_length(&self) -> u32 {
+        self.uncompressed_length_and_codec & UNCOMPRESSED_LENGTH_MASK
+    }
+
+    /// None if the block was written by a newer rd with a codec we don't know.
+    pub fn codec(&self) -> Option<TraceCodec> {
+        TraceCodec::from_tag((self.uncompressed_length_and_codec >> 24) as u8)
+    }
+
+    fn set_uncompressed_length_and_codec(&mut self, length: u32, codec: TraceCodec) {
+        debug_assert!(length <= UNCOMPRESSED_LENGTH_MASK);
+        self.uncompressed_length_and_codec = length | ((codec.tag() as u32) << 24);
+    }
 }
 
 /// CompressedWriter opens an output file and writes compressed blocks to it.
@@ -50,7 +65,7 @@ pub struct BlockHeader {
 /// 'write'. The producer thread may block in 'write' if 'buffer_size' bytes are
 /// being compressed.
 ///
-/// Each data block is compressed independently using brotli.
+/// Each data block is compressed independently using the writer's codec.
 pub struct CompressedWriter {
     /// Immutable while threads are running
     fd: ScopedFd,
@@ -94,7 +109,13 @@ impl CompressedWriter {
         !self.error
     }
 
-    pub fn new(filename: &OsStr, block_size: usize, num_threads: usize) -> CompressedWriter {
+    pub fn new(
+        filename: &OsStr,
+        block_size: usize,
+        num_threads: usize,
+        codec: TraceCodec,
+    ) -> CompressedWriter {
+        assert!(block_size <= UNCOMPRESSED_LENGTH_MASK as usize);
         let fd = ScopedFd::open_path_with_mode(
             filename,
             OFlag::O_CLOEXEC
@@ -180,20 +201,24 @@ impl CompressedWriter {
                                         g.next_thread_end_pos,
                                         g.next_thread_pos + block_size as u64,
                                     );
-                                    // header.uncompressed_length must be <= block_size,
+                                    // The uncompressed length must be <= block_size,
                                     // therefore fits in a size_t.
-                                    header.uncompressed_length = (g.next_thread_pos
+                                    let uncompressed_length: u32 = (g.next_thread_pos
                                         - g.thread_pos[thread_index].unwrap())
                                     .try_into()
                                     .unwrap();
+                                    header.set_uncompressed_length_and_codec(
+                                        uncompressed_length,
+                                        codec,
+                                    );
 
                                     let offset_in_input_buf = g.thread_pos[thread_index].unwrap();
                                     drop(g);
                                     let compressed_length: usize = unsafe {
-                                        do_compress(
+                                        codec.compress(
                                             buffer,
                                             offset_in_input_buf,
-                                            header.uncompressed_length as usize,
+                                            uncompressed_length as usize,
                                             &mut outputbuf[size_of::<BlockHeader>()..],
                                         )
                                     };
@@ -375,60 +400,3 @@ impl Write for CompressedWriter {
         Ok(())
     }
 }
-
-/// See <http://robert.ocallahan.org/2017/07/selecting-compression-algorithm-for-rr.html>
-const RD_BROTLI_LEVEL: u32 = 5;
-
-unsafe fn do_compress(
-    shared_buf: &[u8],
-    mut stream_offset: u64,
-    mut uncompressed_len: usize,
-    output_buf: &mut [u8],
-) -> usize {
-    let state = BrotliEncoderCreateInstance(None, None, ptr::null_mut());
-    if state.is_null() {
-        fatal!("BrotliEncoderCreateInstance failed");
-    }
-
-    if 0 == BrotliEncoderSetParameter(state, BROTLI_PARAM_QUALITY, RD_BROTLI_LEVEL) {
-        fatal!("Brotli initialization failed");
-    }
-
-    let mut ret: usize = 0;
-    let mut output_buf_len: usize = output_buf.len();
-    let mut outp: *mut u8 = &raw mut output_buf[0];
-    while uncompressed_len > 0 {
-        let shared_buf_offset: usize = (stream_offset % shared_buf.len() as u64) as usize;
-        let mut amount: usize = min(uncompressed_len, shared_buf.len() - shared_buf_offset);
-        let mut inp = &raw const shared_buf[shared_buf_offset];
-        if 0 == BrotliEncoderCompressStream(
-            state,
-            BROTLI_OPERATION_PROCESS,
-            &mut amount,
-            &raw mut inp,
-            &mut output_buf_len,
-            &raw mut outp,
-            &raw mut ret,
-        ) {
-            fatal!("Brotli compression failed");
-        }
-        let consumed = inp as u64 - &raw const shared_buf[shared_buf_offset] as u64;
-        stream_offset += consumed;
-        uncompressed_len -= consumed as usize;
-    }
-    let mut zero: usize = 0;
-    if 0 == BrotliEncoderCompressStream(
-        state,
-        BROTLI_OPERATION_FINISH,
-        &raw mut zero,
-        ptr::null_mut(),
-        &mut output_buf_len,
-        &raw mut outp,
-        &raw mut ret,
-    ) {
-        fatal!("Brotli compression failed");
-    }
-
-    BrotliEncoderDestroyInstance(state);
-    ret
-}
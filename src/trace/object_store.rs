@@ -0,0 +1,171 @@
+//! A content-addressed store for the executables and libraries that recording
+//! copies into traces (see `rd record --share-copied-files`).
+//!
+//! Objects live in `objects/` next to the trace directories, named after the
+//! CRC32 and size of their contents, and each trace gets a hardlink to the
+//! object rather than its own copy. The link count doubles as a reference
+//! count: deleting a trace directory drops its links, and an object whose link
+//! count is back to 1 isn't used by any trace. `rd rm` deletes those with
+//! `collect_garbage`.
+
+use crate::log::LogLevel::LogDebug;
+use nix::unistd::getpid;
+use std::{
+    ffi::OsStr,
+    fs,
+    fs::{hard_link, rename, File, Permissions},
+    io,
+    io::{Read, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Files are hashed, compared and copied this many bytes at a time so large
+/// libraries are never read into memory whole.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hardlink the contents of `file_name` to `dest`, via the object store for
+/// the traces next to `trace_dir`. Returns false if that isn't possible (e.g.
+/// the store is on another filesystem), in which case the caller should copy
+/// the file itself.
+pub fn link_shared_copy(trace_dir: &OsStr, file_name: &OsStr, dest: &OsStr) -> bool {
+    match try_link_shared_copy(Path::new(trace_dir), Path::new(file_name), Path::new(dest)) {
+        Ok(linked) => linked,
+        Err(e) => {
+            log!(
+                LogDebug,
+                "Couldn't share {:?} through the object store: {:?}",
+                file_name,
+                e
+            );
+            false
+        }
+    }
+}
+
+fn try_link_shared_copy(trace_dir: &Path, file_name: &Path, dest: &Path) -> io::Result<bool> {
+    let objects_dir = match trace_dir.parent() {
+        Some(parent) => parent.join("objects"),
+        None => return Ok(false),
+    };
+    fs::create_dir_all(&objects_dir)?;
+
+    let (crc, len) = hash_file(file_name)?;
+    let object = objects_dir.join(format!("{:08x}-{}", crc, len));
+    if object.exists() {
+        // CRC32 isn't collision resistant, so make sure this really is the
+        // same file.
+        if !same_contents(file_name, &object)? {
+            return Ok(false);
+        }
+    } else {
+        // Write under a temporary name first so a concurrent recording never
+        // links a partially written object.
+        let tmp = temp_object_path(&object);
+        if copy_and_hash(file_name, &tmp)? != (crc, len) {
+            // The file changed while we were looking at it, so the name we
+            // picked doesn't describe what we copied.
+            fs::remove_file(&tmp)?;
+            return Ok(false);
+        }
+        fs::set_permissions(&tmp, Permissions::from_mode(0o444))?;
+        rename(&tmp, &object)?;
+    }
+
+    hard_link(&object, dest)?;
+    Ok(true)
+}
+
+fn temp_object_path(object: &Path) -> PathBuf {
+    let mut name = object.file_name().unwrap().to_owned();
+    name.push(format!(".tmp{}", getpid()));
+    object.with_file_name(name)
+}
+
+/// Delete the objects in `objects_dir` that no trace links to any more.
+/// Returns how many were deleted.
+pub fn collect_garbage(objects_dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    let entries = match fs::read_dir(objects_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        // Temporary files belong to a recording that's still writing them.
+        if entry.file_name().to_string_lossy().contains(".tmp") {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && metadata.nlink() == 1 {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// The CRC32 and length of the contents of `file_name`.
+fn hash_file(file_name: &Path) -> io::Result<(u32, u64)> {
+    copy_and_hash_to(file_name, &mut io::sink())
+}
+
+/// Copy `file_name` to a new file `dest`, returning the CRC32 and length of
+/// what was copied.
+fn copy_and_hash(file_name: &Path, dest: &Path) -> io::Result<(u32, u64)> {
+    let mut out = File::create(dest)?;
+    let result = copy_and_hash_to(file_name, &mut out).and_then(|r| out.sync_all().map(|_| r));
+    if result.is_err() {
+        // Don't leave a partial copy behind; `collect_garbage` skips it.
+        let _ = fs::remove_file(dest);
+    }
+    result
+}
+
+fn copy_and_hash_to(file_name: &Path, out: &mut dyn Write) -> io::Result<(u32, u64)> {
+    let mut f = File::open(file_name)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut len = 0u64;
+    loop {
+        let nread = f.read(&mut buf)?;
+        if nread == 0 {
+            return Ok((hasher.finalize(), len));
+        }
+        hasher.update(&buf[0..nread]);
+        out.write_all(&buf[0..nread])?;
+        len += nread as u64;
+    }
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut fa = File::open(a)?;
+    let mut fb = File::open(b)?;
+    if fa.metadata()?.len() != fb.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut buf_a = vec![0u8; CHUNK_SIZE];
+    let mut buf_b = vec![0u8; CHUNK_SIZE];
+    loop {
+        let nread = read_fully(&mut fa, &mut buf_a)?;
+        if read_fully(&mut fb, &mut buf_b)? != nread || buf_a[0..nread] != buf_b[0..nread] {
+            return Ok(false);
+        }
+        if nread == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Like `read`, but only returns less than `buf.len()` bytes at end of file.
+fn read_fully(f: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut nread = 0;
+    while nread < buf.len() {
+        match f.read(&mut buf[nread..])? {
+            0 => break,
+            n => nread += n,
+        }
+    }
+    Ok(nread)
+}
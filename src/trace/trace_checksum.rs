@@ -0,0 +1,629 @@
+//! `rd checksum`: CRC32s of every block of a trace, kept in a manifest in the
+//! trace directory, so a damaged trace (bad disk, truncated copy, ...) is
+//! noticed up front instead of as a confusing divergence deep into a replay.
+//!
+//! The manifest (`checksums`) lists every file of the trace with its size and
+//! the checksum of each of its blocks: each compressed block of the
+//! substreams, and each 1 MiB of other files, e.g. saved copies of mapped
+//! files. `rd checksum --verify` reads the whole trace and compares it with
+//! the manifest, or creates the manifest if there is none yet (after checking
+//! that every compressed block decompresses). On huge traces that takes a
+//! while, so it can be rate limited, and its position is saved to
+//! `checksums.progress` every few seconds so an interrupted run resumes where
+//! it stopped.
+//!
+//! `check_manifest` is the fast check run before replay and by `rd fsck`: that
+//! the manifest's signature is good and that every file it lists still has
+//! the recorded size.
+//!
+//! The manifest is signed with an HMAC-SHA256 of its contents, keyed with a
+//! secret key that only the user has (see `load_key`), so a manifest that was
+//! damaged or edited is rejected. The block checksums themselves are CRC32s:
+//! the signature makes them trustworthy, and they only need to catch changes
+//! to the blocks.
+//!
+//! The manifest also names the key it was signed with (by a MAC of a fixed
+//! string, which doesn't reveal the key). A trace checksummed on another
+//! machine has a manifest signed with a key we don't have: its signature
+//! can't be checked, so it is only compared with the trace, and callers say
+//! so, rather than reporting the trace as damaged.
+
+use crate::{
+    log::LogLevel::LogInfo,
+    trace::{
+        compressed_writer::BlockHeader,
+        trace_stream::{substream, Substream},
+    },
+    util::{good_random, u8_slice, u8_slice_mut},
+};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem::{self, size_of},
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const MANIFEST_NAME: &str = "checksums";
+const PROGRESS_NAME: &str = "checksums.progress";
+
+/// Files other than the substreams are checksummed in blocks of this size.
+const RAW_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// How often `verify_checksums` saves its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bump when the manifest format changes.
+const MANIFEST_VERSION: u32 = 3;
+
+/// Size of the keys `load_key` creates.
+const KEY_SIZE: usize = 32;
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+struct FileChecksums {
+    name: OsString,
+    size: u64,
+    /// The CRC32 of each block, in order.
+    blocks: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    files: Vec<FileChecksums>,
+    /// `key_id()` of the key `signature` was made with
+    key_id: String,
+    /// Hex HMAC-SHA256 of `files`
+    signature: String,
+}
+
+impl Manifest {
+    fn new(files: Vec<FileChecksums>, key: &[u8]) -> Manifest {
+        Manifest {
+            version: MANIFEST_VERSION,
+            key_id: key_id(key),
+            signature: to_hex(&mac_of(&files, key).finalize().into_bytes()),
+            files,
+        }
+    }
+
+    /// Load the manifest of the trace in `dir`. If it was signed with `key`,
+    /// check its signature. A manifest signed with another key, or loaded
+    /// without a key, isn't checked: see `signed_with`.
+    fn load(dir: &Path, key: Option<&[u8]>) -> io::Result<Option<Manifest>> {
+        let data = match fs::read(dir.join(MANIFEST_NAME)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let manifest = match serde_json::from_slice::<Manifest>(&data) {
+            Ok(m) if m.version == MANIFEST_VERSION => m,
+            _ => {
+                return Err(invalid_data(format!(
+                    "The checksum manifest {:?} is damaged or was made by another version of \
+                     rd. Delete it and run `rd checksum --verify` to create a new one.",
+                    dir.join(MANIFEST_NAME)
+                )))
+            }
+        };
+        if let Some(key) = key.filter(|&key| manifest.signed_with(key)) {
+            let signature_ok = match from_hex(&manifest.signature) {
+                Some(signature) => mac_of(&manifest.files, key).verify(&signature).is_ok(),
+                None => false,
+            };
+            if !signature_ok {
+                return Err(invalid_data(format!(
+                    "The signature of the checksum manifest {:?} is bad: it was modified.",
+                    dir.join(MANIFEST_NAME)
+                )));
+            }
+        }
+        Ok(Some(manifest))
+    }
+
+    fn signed_with(&self, key: &[u8]) -> bool {
+        self.key_id == key_id(key)
+    }
+}
+
+/// Identifies `key` without revealing it.
+fn key_id(key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(b"rd checksum manifest key id");
+    to_hex(&mac.finalize().into_bytes()[..8])
+}
+
+fn mac_of(files: &[FileChecksums], key: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(b"rd checksum manifest");
+    mac.update(&serde_json::to_vec(files).unwrap());
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Where the key manifests are signed with is kept, unless
+/// `RD_MANIFEST_KEY` names another file.
+pub fn default_key_path() -> PathBuf {
+    if let Some(path) = env::var_os("RD_MANIFEST_KEY") {
+        return PathBuf::from(path);
+    }
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    config_dir.join("rd").join("manifest-key")
+}
+
+/// Read the key manifests are signed with from `path`, or from
+/// `default_key_path()` if `path` is None. If there's no key at the default
+/// path and `create` is set, a random key is created there, readable only by
+/// the user. Returns None if there's no key.
+pub fn load_key(path: Option<&Path>, create: bool) -> io::Result<Option<Vec<u8>>> {
+    let key_path = match path {
+        Some(path) => return fs::read(path).map(Some),
+        None => default_key_path(),
+    };
+    match fs::read(&key_path) {
+        Ok(key) => return Ok(Some(key)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && create => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if let Some(parent) = key_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut key = vec![0u8; KEY_SIZE];
+    good_random(&mut key);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&key_path)?
+        .write_all(&key)?;
+    log!(LogInfo, "Created manifest signing key {:?}", key_path);
+    Ok(Some(key))
+}
+
+/// How far an interrupted `verify_checksums` got.
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    /// The signature of the manifest being verified, or None if one is being
+    /// created.
+    verifying: Option<String>,
+    /// The files to checksum, in order.
+    names: Vec<OsString>,
+    /// The files checksummed so far.
+    done: Vec<FileChecksums>,
+    /// The blocks of `names[done.len()]` before `offset`.
+    current: FileChecksums,
+    offset: u64,
+    problems: Vec<String>,
+}
+
+pub struct ChecksumReport {
+    /// True if there was no manifest, so one was created (unless there were
+    /// problems).
+    pub created: bool,
+    /// True if the manifest was signed with another key, so the trace was
+    /// compared with it without checking its signature.
+    pub other_key: bool,
+    pub files: usize,
+    pub blocks: usize,
+    /// What's wrong with the trace. Empty if it's intact.
+    pub problems: Vec<String>,
+}
+
+/// Checksum every block of `trace_dir` and compare the result with its
+/// manifest, or create the manifest, signed with `key`, if it has none. A
+/// manifest signed with another key is compared with, but not replaced.
+/// Reads at most `rate_limit` bytes per second, if given. Picks up from the
+/// saved progress of an earlier, interrupted call.
+pub fn verify_checksums(
+    trace_dir: &OsStr,
+    key: &[u8],
+    rate_limit: Option<u64>,
+) -> io::Result<ChecksumReport> {
+    let dir = Path::new(trace_dir);
+    let manifest = Manifest::load(dir, Some(key))?;
+    let verifying = manifest.as_ref().map(|m| m.signature.clone());
+    let names = trace_files(dir)?;
+    let mut progress = match load_progress(dir) {
+        Some(p) if p.verifying == verifying && p.names == names => {
+            log!(
+                LogInfo,
+                "Resuming checksums of {:?} at file {} of {}",
+                trace_dir,
+                p.done.len() + 1,
+                p.names.len()
+            );
+            p
+        }
+        _ => Progress {
+            verifying,
+            names,
+            done: Vec::new(),
+            current: Default::default(),
+            offset: 0,
+            problems: Vec::new(),
+        },
+    };
+
+    let start = Instant::now();
+    let mut bytes_read: u64 = 0;
+    let mut last_saved = Instant::now();
+    let mut buf = Vec::new();
+    while progress.done.len() < progress.names.len() {
+        let name = progress.names[progress.done.len()].clone();
+        let is_substream = Substream::iter().any(|&s| name == substream(s).name);
+        let file = File::open(dir.join(&name))?;
+        loop {
+            let block = if is_substream {
+                read_compressed_block(&file, progress.offset, &mut buf)
+            } else {
+                read_raw_block(&file, progress.offset, &mut buf)
+            };
+            match block {
+                Ok(0) => break,
+                Ok(len) => {
+                    progress.current.blocks.push(crc32fast::hash(&buf[..len]));
+                    progress.offset += len as u64;
+                    bytes_read += len as u64;
+                }
+                Err(e) => {
+                    progress.problems.push(format!(
+                        "{:?}: block {}: {}",
+                        name,
+                        progress.current.blocks.len(),
+                        e
+                    ));
+                    break;
+                }
+            }
+            if let Some(rate) = rate_limit {
+                let due = Duration::from_secs_f64(bytes_read as f64 / rate as f64);
+                let elapsed = start.elapsed();
+                if due > elapsed {
+                    thread::sleep(due - elapsed);
+                }
+            }
+            if last_saved.elapsed() >= PROGRESS_INTERVAL {
+                save_progress(dir, &progress)?;
+                last_saved = Instant::now();
+            }
+        }
+        let mut finished = mem::take(&mut progress.current);
+        finished.name = name;
+        finished.size = file.metadata()?.len();
+        progress.done.push(finished);
+        progress.offset = 0;
+    }
+
+    let mut report = ChecksumReport {
+        created: manifest.is_none(),
+        other_key: manifest.as_ref().map_or(false, |m| !m.signed_with(key)),
+        files: progress.done.len(),
+        blocks: progress.done.iter().map(|f| f.blocks.len()).sum(),
+        problems: progress.problems,
+    };
+    match manifest {
+        Some(manifest) => compare(&manifest.files, &progress.done, &mut report.problems),
+        None if report.problems.is_empty() => {
+            let data = serde_json::to_vec_pretty(&Manifest::new(progress.done, key)).unwrap();
+            let tmp_path = dir.join(format!("{}.tmp", MANIFEST_NAME));
+            fs::write(&tmp_path, data)?;
+            fs::rename(&tmp_path, dir.join(MANIFEST_NAME))?;
+        }
+        None => (),
+    }
+    remove_if_exists(&dir.join(PROGRESS_NAME))?;
+    Ok(report)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ManifestCheck {
+    /// The trace has no manifest.
+    Missing,
+    /// The manifest's signature is good and every file it lists has the
+    /// recorded size.
+    Intact,
+    /// Every file the manifest lists has the recorded size, but there's no key
+    /// to check the manifest's signature with.
+    Unsigned,
+    /// Every file the manifest lists has the recorded size, but the manifest
+    /// was signed with another key, e.g. on another machine, so its signature
+    /// wasn't checked.
+    OtherKey,
+}
+
+/// The fast check: the manifest of `trace_dir` is signed with `key` and every
+/// file it lists has the recorded size. Returns an InvalidData error
+/// describing the first problem found. A manifest signed with another key
+/// only has its sizes checked.
+pub fn check_manifest(trace_dir: &OsStr, key: Option<&[u8]>) -> io::Result<ManifestCheck> {
+    let dir = Path::new(trace_dir);
+    let manifest = match Manifest::load(dir, key)? {
+        Some(manifest) => manifest,
+        None => return Ok(ManifestCheck::Missing),
+    };
+    for f in &manifest.files {
+        let size = match fs::metadata(dir.join(&f.name)) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(invalid_data(format!("{:?} is missing", f.name)))
+            }
+            Err(e) => return Err(e),
+        };
+        if size != f.size {
+            return Err(invalid_data(format!(
+                "{:?} is {} bytes, but was {} bytes when it was checksummed",
+                f.name, size, f.size
+            )));
+        }
+    }
+    Ok(match key {
+        Some(key) if manifest.signed_with(key) => ManifestCheck::Intact,
+        Some(_) => ManifestCheck::OtherKey,
+        None => ManifestCheck::Unsigned,
+    })
+}
+
+/// Forget the checksums of `trace_dir`. Call this before rewriting files of
+/// the trace.
+pub fn discard_checksums(trace_dir: &OsStr) -> io::Result<()> {
+    remove_if_exists(&Path::new(trace_dir).join(MANIFEST_NAME))?;
+    remove_if_exists(&Path::new(trace_dir).join(PROGRESS_NAME))
+}
+
+fn compare(expected: &[FileChecksums], actual: &[FileChecksums], problems: &mut Vec<String>) {
+    for e in expected {
+        let a = match actual.iter().find(|a| a.name == e.name) {
+            Some(a) => a,
+            None => {
+                problems.push(format!("{:?} is missing", e.name));
+                continue;
+            }
+        };
+        if a.size != e.size {
+            problems.push(format!(
+                "{:?} is {} bytes, but was {} bytes when it was checksummed",
+                e.name, a.size, e.size
+            ));
+        }
+        let bad_blocks: Vec<String> = (0..e.blocks.len().max(a.blocks.len()))
+            .filter(|&i| e.blocks.get(i) != a.blocks.get(i))
+            .map(|i| i.to_string())
+            .collect();
+        if !bad_blocks.is_empty() {
+            problems.push(format!(
+                "{:?}: block(s) {} don't match",
+                e.name,
+                bad_blocks.join(", ")
+            ));
+        }
+    }
+    for a in actual {
+        if !expected.iter().any(|e| e.name == a.name) {
+            problems.push(format!("{:?} isn't in the checksum manifest", a.name));
+        }
+    }
+}
+
+/// The files of the trace in `dir` that get checksummed, sorted.
+fn trace_files(dir: &Path) -> io::Result<Vec<OsString>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        // Skip the manifest, the progress file and their temporary copies.
+        if !entry.file_type()?.is_file() || name.to_string_lossy().starts_with(MANIFEST_NAME) {
+            continue;
+        }
+        names.push(name);
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Read the compressed block at `offset` of `file`, header included, into
+/// `buf` and check that it decompresses. Returns its length, or 0 at the end
+/// of the file.
+fn read_compressed_block(file: &File, offset: u64, buf: &mut Vec<u8>) -> io::Result<usize> {
+    let mut header = BlockHeader::default();
+    let header_len = size_of::<BlockHeader>();
+    match read_full_at(file, u8_slice_mut(&mut header), offset)? {
+        0 => return Ok(0),
+        n if n < header_len => return Err(invalid_data("truncated block header")),
+        _ => (),
+    }
+    let len = header_len + header.compressed_length as usize;
+    buf.resize(len, 0);
+    buf[..header_len].copy_from_slice(u8_slice(&header));
+    if read_full_at(file, &mut buf[header_len..], offset + header_len as u64)? < len - header_len {
+        return Err(invalid_data("truncated block"));
+    }
+    let codec = header
+        .codec()
+        .ok_or_else(|| invalid_data("block compressed with a codec unknown to this rd"))?;
+    let mut uncompressed = vec![0u8; header.uncompressed_length() as usize];
+    if !codec.decompress(&buf[header_len..], &mut uncompressed) {
+        return Err(invalid_data("block doesn't decompress"));
+    }
+    Ok(len)
+}
+
+/// Read up to `RAW_BLOCK_SIZE` bytes at `offset` of `file` into `buf`.
+/// Returns how many were read, 0 at the end of the file.
+fn read_raw_block(file: &File, offset: u64, buf: &mut Vec<u8>) -> io::Result<usize> {
+    buf.resize(RAW_BLOCK_SIZE, 0);
+    read_full_at(file, buf, offset)
+}
+
+/// Like `FileExt::read_exact_at`, but returns how much was read if the file
+/// ends first.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut nread = 0;
+    while nread < buf.len() {
+        match file.read_at(&mut buf[nread..], offset + nread as u64) {
+            Ok(0) => break,
+            Ok(n) => nread += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(nread)
+}
+
+fn load_progress(dir: &Path) -> Option<Progress> {
+    let data = fs::read(dir.join(PROGRESS_NAME)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Replace the progress file with a rename, so being interrupted while saving
+/// leaves the previous one.
+fn save_progress(dir: &Path, progress: &Progress) -> io::Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", PROGRESS_NAME));
+    fs::write(&tmp_path, serde_json::to_vec(progress).unwrap())?;
+    fs::rename(&tmp_path, dir.join(PROGRESS_NAME))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn detects_changes() {
+        let dir = env::temp_dir().join(format!("rd-checksum-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..3 * RAW_BLOCK_SIZE / 2)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        fs::write(dir.join("mmap_clone_1_a"), &data).unwrap();
+        fs::write(dir.join("version"), b"85\n").unwrap();
+        let trace_dir = dir.as_os_str();
+        let key = Some(&b"key"[..]);
+
+        assert_eq!(
+            check_manifest(trace_dir, key).unwrap(),
+            ManifestCheck::Missing
+        );
+        let report = verify_checksums(trace_dir, b"key", None).unwrap();
+        assert!(report.created && report.problems.is_empty());
+        assert_eq!((report.files, report.blocks), (2, 3));
+        assert_eq!(
+            check_manifest(trace_dir, key).unwrap(),
+            ManifestCheck::Intact
+        );
+        assert_eq!(
+            check_manifest(trace_dir, None).unwrap(),
+            ManifestCheck::Unsigned
+        );
+
+        let mut damaged = data.clone();
+        damaged[RAW_BLOCK_SIZE + 1] ^= 1;
+        fs::write(dir.join("mmap_clone_1_a"), &damaged).unwrap();
+        // Same size, so only the full check notices.
+        assert_eq!(
+            check_manifest(trace_dir, key).unwrap(),
+            ManifestCheck::Intact
+        );
+        let report = verify_checksums(trace_dir, b"key", None).unwrap();
+        assert!(!report.created);
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].contains("block(s) 1 don't match"));
+
+        fs::write(dir.join("mmap_clone_1_a"), &data[..10]).unwrap();
+        assert!(check_manifest(trace_dir, key).is_err());
+
+        // Editing the manifest to match the damaged trace breaks its signature.
+        let manifest_path = dir.join(MANIFEST_NAME);
+        let mut manifest: Manifest =
+            serde_json::from_slice(&fs::read(&manifest_path).unwrap()).unwrap();
+        manifest.files[0].size = 10;
+        fs::write(&manifest_path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+        assert!(check_manifest(trace_dir, key).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_signed_elsewhere() {
+        let dir = env::temp_dir().join(format!("rd-checksum-other-key-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = vec![7u8; RAW_BLOCK_SIZE + 10];
+        fs::write(dir.join("mmap_clone_1_a"), &data).unwrap();
+        let trace_dir = dir.as_os_str();
+        let report = verify_checksums(trace_dir, b"their key", None).unwrap();
+        assert!(report.created && !report.other_key);
+
+        // Only the sizes can be checked, and they match.
+        let key = Some(&b"our key"[..]);
+        assert_eq!(
+            check_manifest(trace_dir, key).unwrap(),
+            ManifestCheck::OtherKey
+        );
+        let report = verify_checksums(trace_dir, b"our key", None).unwrap();
+        assert!(!report.created && report.other_key && report.problems.is_empty());
+        // It isn't replaced with one signed with our key.
+        assert_eq!(
+            check_manifest(trace_dir, key).unwrap(),
+            ManifestCheck::OtherKey
+        );
+
+        // The contents are still compared with it.
+        let mut damaged = data.clone();
+        damaged[0] ^= 1;
+        fs::write(dir.join("mmap_clone_1_a"), &damaged).unwrap();
+        let report = verify_checksums(trace_dir, b"our key", None).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        fs::write(dir.join("mmap_clone_1_a"), &data[..10]).unwrap();
+        assert!(check_manifest(trace_dir, key).is_err());
+
+        // With the key it was signed with, a modified manifest is damaged.
+        fs::write(dir.join("mmap_clone_1_a"), &data).unwrap();
+        let manifest_path = dir.join(MANIFEST_NAME);
+        let mut manifest: Manifest =
+            serde_json::from_slice(&fs::read(&manifest_path).unwrap()).unwrap();
+        manifest.files[0].blocks[0] ^= 1;
+        fs::write(&manifest_path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+        assert_eq!(
+            check_manifest(trace_dir, key).unwrap(),
+            ManifestCheck::OtherKey
+        );
+        assert!(check_manifest(trace_dir, Some(b"their key")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
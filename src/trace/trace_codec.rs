@@ -0,0 +1,266 @@
+use brotli_sys::{
+    BrotliDecoderDecompress, BrotliEncoderCompressStream, BrotliEncoderCreateInstance,
+    BrotliEncoderDestroyInstance, BrotliEncoderSetParameter, BROTLI_DECODER_RESULT_SUCCESS,
+    BROTLI_OPERATION_FINISH, BROTLI_OPERATION_PROCESS, BROTLI_PARAM_QUALITY,
+};
+use std::{borrow::Cow, cmp::min, ptr, str::FromStr};
+
+/// The codecs that trace blocks can be compressed with. Every block written
+/// by CompressedWriter records its codec (see BlockHeader), so readers don't
+/// need to be told and a single file may mix codecs.
+///
+/// DIFF NOTE: rr always uses brotli. Adding a codec means adding a variant
+/// here with a new tag; tags must never be reused.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceCodec {
+    /// The default, and the only codec in traces from older rd versions.
+    Brotli,
+    /// No compression. Fastest to record, at the cost of disk space.
+    Stored,
+    /// Compresses almost as well as brotli for much less CPU time.
+    Zstd,
+    /// Faster than zstd, but compresses less.
+    Lz4,
+}
+
+impl Default for TraceCodec {
+    fn default() -> Self {
+        TraceCodec::Brotli
+    }
+}
+
+impl TraceCodec {
+    pub const ALL: [TraceCodec; 4] = [
+        TraceCodec::Brotli,
+        TraceCodec::Stored,
+        TraceCodec::Zstd,
+        TraceCodec::Lz4,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceCodec::Brotli => "brotli",
+            TraceCodec::Stored => "none",
+            TraceCodec::Zstd => "zstd",
+            TraceCodec::Lz4 => "lz4",
+        }
+    }
+
+    pub(super) fn tag(self) -> u8 {
+        match self {
+            TraceCodec::Brotli => 0,
+            TraceCodec::Stored => 1,
+            TraceCodec::Zstd => 2,
+            TraceCodec::Lz4 => 3,
+        }
+    }
+
+    pub(super) fn from_tag(tag: u8) -> Option<TraceCodec> {
+        TraceCodec::ALL.iter().copied().find(|c| c.tag() == tag)
+    }
+
+    /// Compress `uncompressed_len` bytes starting at `stream_offset` of the
+    /// ring buffer `shared_buf` into `output_buf`. Returns the compressed
+    /// size, or 0 if `output_buf` is too small.
+    pub(super) unsafe fn compress(
+        self,
+        shared_buf: &[u8],
+        stream_offset: u64,
+        uncompressed_len: usize,
+        output_buf: &mut [u8],
+    ) -> usize {
+        match self {
+            TraceCodec::Brotli => {
+                brotli_compress(shared_buf, stream_offset, uncompressed_len, output_buf)
+            }
+            TraceCodec::Stored => {
+                stored_compress(shared_buf, stream_offset, uncompressed_len, output_buf)
+            }
+            TraceCodec::Zstd => {
+                let input = contiguous(shared_buf, stream_offset, uncompressed_len);
+                zstd::bulk::compress_to_buffer(&input, output_buf, RD_ZSTD_LEVEL).unwrap_or(0)
+            }
+            TraceCodec::Lz4 => {
+                let input = contiguous(shared_buf, stream_offset, uncompressed_len);
+                lz4_flex::block::compress_into(&input, output_buf).unwrap_or(0)
+            }
+        }
+    }
+
+    /// Returns false unless `compressed` decompresses to exactly
+    /// `uncompressed.len()` bytes.
+    pub(super) fn decompress(self, compressed: &[u8], uncompressed: &mut [u8]) -> bool {
+        match self {
+            TraceCodec::Brotli => brotli_decompress(compressed, uncompressed),
+            TraceCodec::Stored => {
+                if compressed.len() != uncompressed.len() {
+                    return false;
+                }
+                uncompressed.copy_from_slice(compressed);
+                true
+            }
+            TraceCodec::Zstd => {
+                zstd::bulk::decompress_to_buffer(compressed, uncompressed).ok()
+                    == Some(uncompressed.len())
+            }
+            TraceCodec::Lz4 => {
+                lz4_flex::block::decompress_into(compressed, uncompressed).ok()
+                    == Some(uncompressed.len())
+            }
+        }
+    }
+}
+
+impl FromStr for TraceCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match TraceCodec::ALL.iter().find(|c| c.name() == s) {
+            Some(&c) => Ok(c),
+            None => Err(format!(
+                "Unknown compression `{}`. Expected one of: {}",
+                s,
+                TraceCodec::ALL
+                    .iter()
+                    .map(|c| c.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+/// See <http://robert.ocallahan.org/2017/07/selecting-compression-algorithm-for-rr.html>
+const RD_BROTLI_LEVEL: u32 = 5;
+
+/// Favor speed, since blocks are compressed while recording.
+const RD_ZSTD_LEVEL: i32 = 1;
+
+unsafe fn brotli_compress(
+    shared_buf: &[u8],
+    mut stream_offset: u64,
+    mut uncompressed_len: usize,
+    output_buf: &mut [u8],
+) -> usize {
+    let state = BrotliEncoderCreateInstance(None, None, ptr::null_mut());
+    if state.is_null() {
+        fatal!("BrotliEncoderCreateInstance failed");
+    }
+
+    if 0 == BrotliEncoderSetParameter(state, BROTLI_PARAM_QUALITY, RD_BROTLI_LEVEL) {
+        fatal!("Brotli initialization failed");
+    }
+
+    let mut ret: usize = 0;
+    let mut output_buf_len: usize = output_buf.len();
+    let mut outp: *mut u8 = &raw mut output_buf[0];
+    while uncompressed_len > 0 {
+        let shared_buf_offset: usize = (stream_offset % shared_buf.len() as u64) as usize;
+        let mut amount: usize = min(uncompressed_len, shared_buf.len() - shared_buf_offset);
+        let mut inp = &raw const shared_buf[shared_buf_offset];
+        if 0 == BrotliEncoderCompressStream(
+            state,
+            BROTLI_OPERATION_PROCESS,
+            &mut amount,
+            &raw mut inp,
+            &mut output_buf_len,
+            &raw mut outp,
+            &raw mut ret,
+        ) {
+            fatal!("Brotli compression failed");
+        }
+        let consumed = inp as u64 - &raw const shared_buf[shared_buf_offset] as u64;
+        stream_offset += consumed;
+        uncompressed_len -= consumed as usize;
+    }
+    let mut zero: usize = 0;
+    if 0 == BrotliEncoderCompressStream(
+        state,
+        BROTLI_OPERATION_FINISH,
+        &raw mut zero,
+        ptr::null_mut(),
+        &mut output_buf_len,
+        &raw mut outp,
+        &raw mut ret,
+    ) {
+        fatal!("Brotli compression failed");
+    }
+
+    BrotliEncoderDestroyInstance(state);
+    ret
+}
+
+fn brotli_decompress(compressed: &[u8], uncompressed: &mut [u8]) -> bool {
+    let mut out_size = uncompressed.len();
+    let decompress_result = unsafe {
+        BrotliDecoderDecompress(
+            compressed.len(),
+            compressed.as_ptr(),
+            &raw mut out_size,
+            uncompressed.as_mut_ptr(),
+        )
+    };
+
+    decompress_result == BROTLI_DECODER_RESULT_SUCCESS && out_size == uncompressed.len()
+}
+
+/// The `len` bytes starting at `stream_offset` of the ring buffer
+/// `shared_buf`, copied out only if they wrap around its end.
+fn contiguous(shared_buf: &[u8], stream_offset: u64, len: usize) -> Cow<[u8]> {
+    let shared_buf_offset = (stream_offset % shared_buf.len() as u64) as usize;
+    if shared_buf_offset + len <= shared_buf.len() {
+        return Cow::Borrowed(&shared_buf[shared_buf_offset..shared_buf_offset + len]);
+    }
+    let mut data = vec![0u8; len];
+    stored_compress(shared_buf, stream_offset, len, &mut data);
+    Cow::Owned(data)
+}
+
+fn stored_compress(
+    shared_buf: &[u8],
+    mut stream_offset: u64,
+    uncompressed_len: usize,
+    output_buf: &mut [u8],
+) -> usize {
+    if output_buf.len() < uncompressed_len {
+        return 0;
+    }
+    let mut copied = 0;
+    while copied < uncompressed_len {
+        let shared_buf_offset = (stream_offset % shared_buf.len() as u64) as usize;
+        let amount = min(
+            uncompressed_len - copied,
+            shared_buf.len() - shared_buf_offset,
+        );
+        output_buf[copied..copied + amount]
+            .copy_from_slice(&shared_buf[shared_buf_offset..shared_buf_offset + amount]);
+        copied += amount;
+        stream_offset += amount as u64;
+    }
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        for &codec in &TraceCodec::ALL {
+            let mut compressed = vec![0u8; data.len() * 2];
+            // Start near the end of the ring buffer so the block wraps around.
+            let ring_offset = data.len() - 100;
+            let mut ring = data[100..].to_vec();
+            ring.extend_from_slice(&data[..100]);
+            let len =
+                unsafe { codec.compress(&ring, ring_offset as u64, data.len(), &mut compressed) };
+            assert_ne!(len, 0);
+            let mut out = vec![0u8; data.len()];
+            assert!(codec.decompress(&compressed[..len], &mut out));
+            assert_eq!(out, data);
+            assert_eq!(codec.name().parse::<TraceCodec>(), Ok(codec));
+            assert_eq!(TraceCodec::from_tag(codec.tag()), Some(codec));
+        }
+    }
+}
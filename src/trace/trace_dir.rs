@@ -0,0 +1,295 @@
+use crate::{
+    trace::{
+        compressed_reader::CompressedReader,
+        compressed_writer::{self, CompressedWriter},
+        object_store::collect_garbage,
+        trace_codec::TraceCodec,
+        trace_stream::{substream, Substream},
+    },
+    util::{dir_exists, ensure_dir, find},
+};
+use libc::EEXIST;
+use nix::{
+    errno::{errno, Errno},
+    sys::stat::Mode,
+    unistd::{mkdir, unlink},
+    Error,
+};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    fs::File,
+    io,
+    io::Write,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::symlink,
+    },
+    path::Path,
+};
+
+/// Name of the symlink in the trace save directory that points at the most
+/// recent recording.
+pub const LATEST_TRACE_NAME: &str = "latest-trace";
+
+/// Marker left in a trace directory whose data was discarded by
+/// `rd record --record-children`.
+const DATA_DISCARDED_NAME: &str = "data-discarded";
+
+/// The trace save directory and the traces in it.
+///
+/// Each recording gets its own directory named `<exe>-<n>`, where `<exe>` is
+/// the basename of the recorded executable and `<n>` is the smallest number
+/// that makes the name unique. `latest-trace` is a relative symlink to the
+/// most recent of those.
+#[derive(Clone, Debug)]
+pub struct TraceDir {
+    root: OsString,
+}
+
+impl TraceDir {
+    /// The trace save directory for this rd invocation: $RD_TRACE_DIR if set,
+    /// otherwise the default (usually ~/.local/share/rd).
+    ///
+    /// DIFF NOTE: rr only looks at _RR_TRACE_DIR. We accept _RD_TRACE_DIR
+    /// too for compatibility with older rd versions.
+    pub fn from_env() -> TraceDir {
+        let maybe_dir = env::var_os("RD_TRACE_DIR").or_else(|| env::var_os("_RD_TRACE_DIR"));
+        match maybe_dir {
+            Some(dir) if !dir.is_empty() => TraceDir::with_root(dir),
+            _ => TraceDir::with_root(default_rd_trace_dir()),
+        }
+    }
+
+    pub fn with_root<T: Into<OsString>>(root: T) -> TraceDir {
+        TraceDir { root: root.into() }
+    }
+
+    pub fn root(&self) -> &OsStr {
+        &self.root
+    }
+
+    pub fn latest_trace_symlink(&self) -> OsString {
+        self.join(OsStr::new(LATEST_TRACE_NAME))
+    }
+
+    /// Create the directory for a new recording of `exe_path` and return its
+    /// path. If `maybe_output_trace_dir` is given (`rd record -o`) that exact
+    /// directory is created instead; it must not exist yet.
+    pub fn create_trace_dir(
+        &self,
+        exe_path: &OsStr,
+        maybe_output_trace_dir: Option<&OsStr>,
+    ) -> OsString {
+        // DIFF NOTE: Make trace dirs only S_IRWXU to be conservative. rr adds Mode::S_IRWXG also.
+        if let Some(output_trace_dir) = maybe_output_trace_dir {
+            return match mkdir(output_trace_dir, Mode::S_IRWXU) {
+                Ok(_) => output_trace_dir.to_owned(),
+                Err(e) if EEXIST == errno() => {
+                    fatal!("Directory {:?} already exists: {:?}", output_trace_dir, e)
+                }
+                Err(e) => fatal!(
+                    "Unable to create trace directory {:?}: {:?}",
+                    output_trace_dir,
+                    e
+                ),
+            };
+        }
+
+        ensure_dir(&self.root, "trace directory", Mode::S_IRWXU);
+
+        // Find a unique trace directory name.
+        let prefix = self.join(Path::new(exe_path).file_name().unwrap());
+        let mut nonce = 0;
+        loop {
+            let mut dir = prefix.clone().into_vec();
+            write!(dir, "-{}", nonce).unwrap();
+            nonce += 1;
+            match mkdir(dir.as_slice(), Mode::S_IRWXU) {
+                Ok(_) => return OsString::from_vec(dir),
+                Err(_) if EEXIST == errno() => continue,
+                Err(e) => fatal!(
+                    "Unable to create trace directory {:?}: {:?}",
+                    OsStr::from_bytes(&dir),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Point `latest-trace` at `trace_dir`.
+    pub fn update_latest_trace(&self, trace_dir: &OsStr) {
+        let link_name = self.latest_trace_symlink();
+        // Try to update the symlink to `trace_dir`.  We only try attempt
+        // to set the symlink once.  If the link is re-created after
+        // we `unlink()` it, then another rd process is racing with us
+        // and it "won".  The link is then valid and points at some
+        // very-recent trace, so that's good enough.
+        //
+        // DIFF NOTE: rr swallows any error on unlink. We don't for now.
+        match unlink(link_name.as_os_str()) {
+            Err(Error::Sys(Errno::ENOENT)) => (),
+            Err(e) => fatal!("Unable to unlink {:?}: {:?}", link_name, e),
+            Ok(_) => (),
+        }
+
+        // Link only the trace name, not the full path, so moving a directory full
+        // of traces around doesn't break the latest-trace link.
+        let trace_name = Path::new(trace_dir).file_name().unwrap();
+        match symlink(trace_name, &link_name) {
+            Err(e) if errno() != EEXIST => {
+                fatal!(
+                    "Failed to update symlink {:?} to {:?}: {:?}",
+                    link_name,
+                    trace_name,
+                    e
+                );
+            }
+            _ => (),
+        }
+    }
+
+    /// Turn the trace argument of a command into a trace directory. No
+    /// argument means the latest trace. Single-component names are looked up
+    /// first in the current directory, next in the trace save directory.
+    /// Anything else is a path and is returned as is.
+    pub fn resolve<T: AsRef<OsStr>>(&self, maybe_trace_name: Option<T>) -> OsString {
+        let trace_name = match maybe_trace_name {
+            Some(name) if !name.as_ref().is_empty() => name.as_ref().to_os_string(),
+            _ => return self.latest_trace_symlink(),
+        };
+
+        if find(trace_name.as_bytes(), b"/").is_none() {
+            if dir_exists(trace_name.as_os_str()) {
+                return trace_name;
+            }
+
+            let resolved_trace_name = self.join(&trace_name);
+            if dir_exists(resolved_trace_name.as_os_str()) {
+                return resolved_trace_name;
+            }
+        }
+
+        trace_name
+    }
+
+    /// Delete `trace_dir`, which must be a trace, along with `latest-trace` if
+    /// it points there. Then delete the shared objects that were only used by
+    /// it (or by traces deleted before). Returns how many objects were deleted.
+    pub fn remove_trace(&self, trace_dir: &OsStr) -> io::Result<usize> {
+        let trace_dir = fs::canonicalize(trace_dir)?;
+        if !trace_dir.join("version").is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a trace directory", trace_dir),
+            ));
+        }
+        let latest_trace = self.latest_trace_symlink();
+        let is_latest = fs::canonicalize(&latest_trace).map_or(false, |p| p == trace_dir);
+
+        fs::remove_dir_all(&trace_dir)?;
+        if is_latest {
+            fs::remove_file(&latest_trace)?;
+        }
+        match trace_dir.parent() {
+            Some(parent) => collect_garbage(&parent.join("objects")),
+            None => Ok(0),
+        }
+    }
+
+    fn join(&self, name: &OsStr) -> OsString {
+        let mut path = self.root.clone().into_vec();
+        path.push(b'/');
+        path.extend_from_slice(name.as_bytes());
+        OsString::from_vec(path)
+    }
+}
+
+/// @TODO Look at logic again carefully
+fn default_rd_trace_dir() -> OsString {
+    let mut dot_dir: Vec<u8> = Vec::new();
+    let home = match env::var_os("HOME") {
+        Some(found_home) if !found_home.is_empty() => {
+            dot_dir.extend_from_slice(found_home.as_bytes());
+            dot_dir.extend_from_slice(b"/.rd");
+            found_home
+        }
+        // @TODO This seems to be an implicit outcome of what we have in rr
+        _ => OsString::new(),
+    };
+
+    let mut xdg_dir: Vec<u8> = Vec::new();
+    match env::var_os("XDG_DATA_HOME") {
+        Some(xdg_data_home) if !xdg_data_home.is_empty() => {
+            xdg_dir.extend_from_slice(xdg_data_home.as_bytes());
+            xdg_dir.extend_from_slice(b"/rd");
+        }
+        _ => {
+            xdg_dir.extend_from_slice(home.as_bytes());
+            xdg_dir.extend_from_slice(b"/.local/share/rd");
+        }
+    }
+
+    // If XDG dir does not exist but ~/.rd does, prefer ~/.rd for backwards
+    // compatibility.
+    if dir_exists(xdg_dir.as_slice()) {
+        OsString::from_vec(xdg_dir)
+    } else if dir_exists(dot_dir.as_slice()) {
+        OsString::from_vec(dot_dir)
+    } else if !xdg_dir.is_empty() {
+        OsString::from_vec(xdg_dir)
+    } else {
+        OsStr::from_bytes(b"/tmp/rd").to_os_string()
+    }
+}
+
+/// Delete everything in `trace_dir` except what `rd ps` and `rd trace-info`
+/// need (the version file with the trace header, and the task events), and
+/// leave a marker so replay can say why it can't proceed.
+pub fn discard_trace_data(trace_dir: &OsStr) -> io::Result<()> {
+    let tasks = substream(Substream::Tasks).name;
+    for entry in fs::read_dir(trace_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "version" || name == tasks || !entry.file_type()?.is_file() {
+            continue;
+        }
+        fs::remove_file(entry.path())?;
+    }
+    File::create(Path::new(trace_dir).join(DATA_DISCARDED_NAME))?;
+    Ok(())
+}
+
+pub fn trace_data_discarded(trace_dir: &OsStr) -> bool {
+    Path::new(trace_dir).join(DATA_DISCARDED_NAME).exists()
+}
+
+/// Rewrite every substream of `trace_dir` with its blocks compressed by
+/// `codec`. Each file is replaced with a rename, so an interrupted run leaves
+/// a readable trace, possibly with some files still in the old codec.
+pub fn recompress_trace(trace_dir: &OsStr, codec: TraceCodec) -> io::Result<()> {
+    for &s in Substream::iter() {
+        let data = substream(s);
+        let path = Path::new(trace_dir).join(data.name);
+        let tmp_path = Path::new(trace_dir).join(format!("{}.recompress", data.name));
+        match fs::remove_file(&tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+
+        let mut reader = CompressedReader::new(path.as_os_str());
+        let mut writer =
+            CompressedWriter::new(tmp_path.as_os_str(), data.block_size, data.threads, codec);
+        io::copy(&mut reader, &mut writer)?;
+        writer.close(Some(compressed_writer::Sync::Sync));
+        if !writer.good() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unable to write {:?}", tmp_path),
+            ));
+        }
+        fs::rename(&tmp_path, &path)?;
+    }
+    Ok(())
+}
@@ -2,6 +2,7 @@ use crate::{
     event::Event,
     extra_registers::{ExtraRegisters, Format},
     registers::Registers,
+    scheduler::TaskSwitch,
     ticks::Ticks,
 };
 use libc::pid_t;
@@ -21,6 +22,12 @@ pub struct TraceFrame {
     pub(super) ev: Event,
     pub(super) ticks_: Ticks,
     pub(super) monotonic_time_: f64,
+    /// Seconds since the Unix epoch, or 0 if the trace predates wall-clock
+    /// timestamps.
+    pub(super) realtime_: f64,
+    /// Task switches the scheduler made since the previous frame. Always
+    /// empty in traces recorded before rd stored them.
+    pub(super) switches_: Vec<TaskSwitch>,
     pub(super) recorded_regs: Registers,
     /// Only used when has_exec_info, but variable length (and usually not
     /// present) so we don't want to stuff it into exec_info
@@ -40,6 +47,7 @@ impl TraceFrame {
         event: Event,
         tick_count: Ticks,
         monotonic_time: f64,
+        realtime: f64,
     ) -> TraceFrame {
         TraceFrame {
             global_time,
@@ -47,6 +55,8 @@ impl TraceFrame {
             ev: event,
             ticks_: tick_count,
             monotonic_time_: monotonic_time,
+            realtime_: realtime,
+            switches_: Vec::new(),
             recorded_regs: Registers::default(),
             recorded_extra_regs: ExtraRegisters::default(),
         }
@@ -59,6 +69,8 @@ impl TraceFrame {
             ev: Event::default(),
             ticks_: 0,
             monotonic_time_: 0.0,
+            realtime_: 0.0,
+            switches_: Vec::new(),
             recorded_regs: Registers::default(),
             recorded_extra_regs: ExtraRegisters::default(),
         }
@@ -79,6 +91,21 @@ impl TraceFrame {
     pub fn monotonic_time(&self) -> f64 {
         self.monotonic_time_
     }
+    /// Wall-clock time this frame was recorded at, in seconds since the Unix
+    /// epoch. `None` for traces recorded before rd stored it.
+    pub fn realtime(&self) -> Option<f64> {
+        if self.realtime_ > 0.0 {
+            Some(self.realtime_)
+        } else {
+            None
+        }
+    }
+    pub fn switches(&self) -> &[TaskSwitch] {
+        &self.switches_
+    }
+    pub fn set_switches(&mut self, switches: Vec<TaskSwitch>) {
+        self.switches_ = switches;
+    }
 
     pub fn regs_ref(&self) -> &Registers {
         &self.recorded_regs
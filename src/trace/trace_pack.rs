@@ -0,0 +1,214 @@
+//! `rd pack`: make a trace self-contained, so it can be replayed on another
+//! machine, by copying the files its mappings are replayed from into the trace
+//! directory.
+//!
+//! Files are deduplicated by content: the CRC32 and size of each file are
+//! compared first and the bytes only on a match, since CRC32 isn't collision
+//! resistant. Files already in the trace (clones, copies and hardlinks) are kept
+//! under their names and only outside files are copied in, as
+//! `mmap_pack_<n>_<basename>`. Each copy is hashed again once written. The mmap
+//! records are then rewritten to name the packed files, with their stat data
+//! zeroed as in rr so replay doesn't compare them with the original files.
+
+use crate::{
+    log::LogLevel::LogInfo,
+    trace::{
+        compressed_reader::CompressedReader,
+        compressed_writer::{self, CompressedWriter},
+        trace_codec::TraceCodec,
+        trace_stream::{substream, Substream},
+    },
+    trace_capnp::m_map,
+};
+use capnp::{
+    message,
+    message::ReaderOptions,
+    serialize::OwnedSegments,
+    serialize_packed::{read_message, write_message},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt, fs, io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+struct ContentKey {
+    crc32: u32,
+    size: u64,
+}
+
+impl ContentKey {
+    fn of(data: &[u8]) -> ContentKey {
+        ContentKey {
+            crc32: crc32fast::hash(data),
+            size: data.len() as u64,
+        }
+    }
+}
+
+/// Pack `trace_dir` (see the module docs). The rewritten mmaps file is
+/// compressed with `codec`.
+pub fn pack_trace(trace_dir: &OsStr, codec: TraceCodec) -> io::Result<()> {
+    let dir = Path::new(trace_dir);
+    let mmaps = substream(Substream::Mmaps);
+    let mmaps_path = dir.join(mmaps.name);
+
+    let mut maps: Vec<message::Reader<OwnedSegments>> = Vec::new();
+    let mut reader = CompressedReader::new(mmaps_path.as_os_str());
+    while !reader.at_end() {
+        maps.push(read_message(&mut reader, ReaderOptions::new()).map_err(invalid_data)?);
+    }
+
+    // Every file the trace maps, files in the trace directory first so that
+    // they're the ones kept when an outside file has the same contents.
+    let mut backing_names: Vec<OsString> = Vec::new();
+    for map_msg in &maps {
+        if let Some(name) = backing_file_name(map_msg)? {
+            if !backing_names.contains(&name) {
+                backing_names.push(name);
+            }
+        }
+    }
+    backing_names.sort_by_key(|name| name.as_bytes().starts_with(b"/"));
+
+    // Check every file is readable before changing anything.
+    let mut keys: HashMap<OsString, ContentKey> = HashMap::new();
+    for name in &backing_names {
+        // Path::join leaves absolute names as they are.
+        let data = fs::read(dir.join(name)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Unable to read {:?}, which the trace maps: {}", name, e),
+            )
+        })?;
+        keys.insert(name.clone(), ContentKey::of(&data));
+    }
+
+    let mut packed_names: HashMap<OsString, OsString> = HashMap::new();
+    let mut packed_by_key: HashMap<ContentKey, Vec<OsString>> = HashMap::new();
+    let mut copied = 0;
+    let mut nonce = 0;
+    for name in &backing_names {
+        let key = keys[name];
+        let path = dir.join(name);
+        let mut existing = None;
+        for candidate in packed_by_key.get(&key).into_iter().flatten() {
+            if fs::read(dir.join(candidate))? == fs::read(&path)? {
+                existing = Some(candidate.clone());
+                break;
+            }
+        }
+        let packed_name = match existing {
+            Some(packed_name) => packed_name,
+            None if !name.as_bytes().starts_with(b"/") => name.clone(),
+            None => {
+                // Names from an earlier `rd pack` may be taken.
+                let packed_name = loop {
+                    nonce += 1;
+                    let mut packed_name = OsString::from(format!("mmap_pack_{}_", nonce));
+                    packed_name.push(Path::new(name).file_name().unwrap_or_default());
+                    if !dir.join(&packed_name).exists() {
+                        break packed_name;
+                    }
+                };
+                copy_verified(&path, &dir.join(&packed_name), key)?;
+                copied += 1;
+                packed_name
+            }
+        };
+        packed_by_key
+            .entry(key)
+            .or_insert_with(Vec::new)
+            .push(packed_name.clone());
+        packed_names.insert(name.clone(), packed_name);
+    }
+
+    let new_mmaps_path = dir.join(format!("{}.pack", mmaps.name));
+    match fs::remove_file(&new_mmaps_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let mut writer = CompressedWriter::new(
+        new_mmaps_path.as_os_str(),
+        mmaps.block_size,
+        mmaps.threads,
+        codec,
+    );
+    for map_msg in &maps {
+        let mut new_msg = message::Builder::new_default();
+        new_msg
+            .set_root(map_msg.get_root::<m_map::Reader>().map_err(invalid_data)?)
+            .map_err(invalid_data)?;
+        if let Some(name) = backing_file_name(map_msg)? {
+            let mut map = new_msg.get_root::<m_map::Builder>().map_err(invalid_data)?;
+            map.set_stat_mode(0);
+            map.set_stat_uid(0);
+            map.set_stat_gid(0);
+            map.set_stat_m_time(0);
+            map.get_source()
+                .init_file()
+                .set_backing_file_name(packed_names[&name].as_bytes());
+        }
+        write_message(&mut writer, &new_msg)?;
+    }
+    writer.close(Some(compressed_writer::Sync::Sync));
+    if !writer.good() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unable to write {:?}", new_mmaps_path),
+        ));
+    }
+    fs::rename(&new_mmaps_path, &mmaps_path)?;
+
+    // Files in the trace that turned out to be duplicates aren't needed any more.
+    let still_used: HashSet<&OsString> = packed_names.values().collect();
+    let mut removed = 0;
+    for name in &backing_names {
+        if !name.as_bytes().starts_with(b"/") && !still_used.contains(name) {
+            fs::remove_file(dir.join(name))?;
+            removed += 1;
+        }
+    }
+    log!(
+        LogInfo,
+        "Packed {:?}: copied {} file(s) in, removed {} duplicate(s)",
+        trace_dir,
+        copied,
+        removed
+    );
+    Ok(())
+}
+
+/// The backing file of a mapping replayed from a file, as recorded.
+fn backing_file_name(map_msg: &message::Reader<OwnedSegments>) -> io::Result<Option<OsString>> {
+    let map = map_msg.get_root::<m_map::Reader>().map_err(invalid_data)?;
+    match map.get_source().which().map_err(invalid_data)? {
+        m_map::source::File(f) => {
+            let name = f.get_backing_file_name().map_err(invalid_data)?;
+            Ok(Some(OsStr::from_bytes(name).to_owned()))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn copy_verified(from: &Path, to: &Path, key: ContentKey) -> io::Result<()> {
+    fs::copy(from, to)?;
+    let copy_key = ContentKey::of(&fs::read(to)?);
+    if copy_key != key {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Copy of {:?} doesn't match the original. Did it change while being packed?",
+                from
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_data<E: fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
@@ -3,40 +3,41 @@
 use crate::{
     bindings::{signal::siginfo_t, sysexits::EX_DATAERR},
     event::{
-        Event, EventType, OpenedFd,
+        Event, EventType, OpenedFd, SdtProbeEventData,
         SignalDeterministic::{DeterministicSig, NondeterministicSig},
         SignalEventData, SignalResolvedDisposition, SyscallEventData, SyscallState,
         SyscallbufFlushEventData,
     },
     extra_registers::{ExtraRegisters, Format},
-    kernel_abi::{SupportedArch, RD_NATIVE_ARCH},
+    file_monitor::cgroup_file_monitor::CgroupLimit,
+    kernel_abi::{x64, x86, SupportedArch, RD_NATIVE_ARCH},
     log::LogLevel::{LogDebug, LogError},
     perf_counters::TicksSemantics,
     preload_interface::mprotect_record,
     registers::Registers,
     remote_ptr::{RemotePtr, Void},
+    scheduler::{SwitchReason, TaskSwitch},
     session::{address_space::kernel_mapping::KernelMapping, record_session::TraceUuid},
     trace::{
         compressed_reader::{CompressedReader, CompressedReaderState},
+        trace_dir::TraceDir,
         trace_frame::{FrameTime, TraceFrame},
         trace_stream::{
-            latest_trace_symlink, to_trace_arch, trace_save_dir, MappedData,
+            to_trace_arch, MappedData,
             MappedDataSource::{SourceFile, SourceTrace, SourceZero},
             RawDataMetadata, Substream, TraceRemoteFd, TraceStream, SUBSTREAMS, TRACE_VERSION,
         },
         trace_task_event::{
-            TraceTaskEvent, TraceTaskEventClone, TraceTaskEventExec, TraceTaskEventExit,
-            TraceTaskEventVariant,
+            AttachedThread, TraceTaskEvent, TraceTaskEventClone, TraceTaskEventExec,
+            TraceTaskEventExit, TraceTaskEventVariant,
         },
     },
     trace_capnp::{
         frame, header, m_map, signal, task_event, Arch as TraceArch,
-        SignalDisposition as TraceSignalDisposition, SyscallState as TraceSyscallState,
-        TicksSemantics as TraceTicksSemantics,
-    },
-    util::{
-        dir_exists, find, find_cpuid_record, xsave_layout_from_trace, CPUIDRecord, CPUID_GETXSAVE,
+        SignalDisposition as TraceSignalDisposition, SwitchReason as TraceSwitchReason,
+        SyscallState as TraceSyscallState, TicksSemantics as TraceTicksSemantics,
     },
+    util::{find_cpuid_record, page_size, xsave_layout_from_trace, CPUIDRecord, CPUID_GETXSAVE},
     wait_status::WaitStatus,
 };
 use capnp::{message::ReaderOptions, serialize_packed::read_message};
@@ -50,18 +51,38 @@ use nix::{
     unistd::{access, AccessFlags},
 };
 use std::{
+    cmp::min,
     collections::HashMap,
     convert::{TryFrom, TryInto},
     ffi::{OsStr, OsString},
     fs::File,
-    io::{BufRead, BufReader, Read},
+    io,
+    io::{BufRead, BufReader, Read, Write},
+    iter,
     mem::{size_of, swap},
     ops::{Deref, DerefMut},
-    os::unix::ffi::{OsStrExt, OsStringExt},
+    os::unix::ffi::OsStrExt,
     process::exit,
     ptr::copy_nonoverlapping,
 };
 
+/// Largest raw data record we'll read, e.g. the result of one huge read().
+/// Traces may come from third parties, so sizes and addresses decoded from one
+/// are checked before we allocate memory for them or write them to a tracee,
+/// and a corrupt trace is a fatal error rather than a crash or worse.
+const MAX_RAW_DATA_SIZE: u64 = 1 << 32;
+
+/// Largest total size of the raw data records of one frame.
+const MAX_FRAME_RAW_DATA_SIZE: u64 = 1 << 34;
+
+/// One past the highest user-space address of any tracee (x86-64 with 5-level
+/// paging). Raw data for one frame may be for tasks of different
+/// architectures, so we don't try to be more precise.
+const USER_ADDRESS_LIMIT: u64 = 1 << 56;
+
+/// Most entries we'll accept in an exec's command line.
+const MAX_CMD_LINE_ARGS: u32 = 1 << 20;
+
 /// Read the next mapped region descriptor and return it.
 /// Also returns where to get the mapped data in `data`, if it's not `None`.
 /// If `found` is not `None`, set `found` to indicate whether a descriptor
@@ -103,6 +124,15 @@ pub struct TraceReader {
     uuid_: TraceUuid,
     trace_uses_cpuid_faulting: bool,
     preload_thread_locals_recorded_: bool,
+    switches_recorded_: bool,
+    recorded_without_pmu_: bool,
+    aslr_disabled_: bool,
+    attached_: bool,
+    unpatched_libraries_: Vec<OsString>,
+    cgroup_limits_: Vec<CgroupLimit>,
+    syscallbuf_protocol_version_: u16,
+    syscallbuf_hdr_size_: u32,
+    preload_thread_locals_size_: u32,
 }
 
 impl Deref for TraceReader {
@@ -134,12 +164,19 @@ impl TraceReader {
 
         let mem_writes = frame.get_mem_writes().unwrap();
         self.raw_recs = Vec::new();
+        let mut total_size: u64 = 0;
         let mut it = mem_writes.iter();
         while let Some(w) = it.next_back() {
+            let size = w.get_size();
+            total_size = total_size.saturating_add(size);
+            if size > MAX_RAW_DATA_SIZE || total_size > MAX_FRAME_RAW_DATA_SIZE {
+                fatal!("Invalid raw data size {} in trace", size);
+            }
+            check_tracee_range(w.get_addr(), size, "Raw data");
             self.raw_recs.push(RawDataMetadata {
                 addr: RemotePtr::new(w.get_addr().try_into().unwrap()),
-                size: w.get_size().try_into().unwrap(),
-                rec_tid: w.get_tid(),
+                size: size.try_into().unwrap(),
+                rec_tid: i32_to_tid(w.get_tid()),
             });
         }
 
@@ -152,11 +189,28 @@ impl TraceReader {
         ret.ticks_ = frame.get_ticks() as u64;
         ret.monotonic_time_ = frame.get_monotonic_sec();
         self.monotonic_time_ = ret.monotonic_time_;
+        ret.realtime_ = frame.get_realtime_sec();
+        ret.switches_ = frame
+            .get_switches()
+            .unwrap()
+            .iter()
+            .map(|sw| TaskSwitch {
+                from_tid: i32_to_tid(sw.get_from_tid()),
+                to_tid: i32_to_tid(sw.get_to_tid()),
+                reason: match sw.get_reason() {
+                    Ok(reason) => from_trace_switch_reason(reason),
+                    Err(_) => fatal!("Invalid switch reason in trace"),
+                },
+            })
+            .collect();
 
         let arch = from_trace_arch(frame.get_arch().unwrap());
         ret.recorded_regs = Registers::new(arch);
         let reg_data = frame.get_registers().unwrap().get_raw().unwrap();
         if !reg_data.is_empty() {
+            if reg_data.len() != ptrace_regs_size(arch) {
+                fatal!("Invalid register data length {}", reg_data.len());
+            }
             ret.recorded_regs.set_from_ptrace_for_arch(arch, reg_data);
         }
         let extra_reg_data = frame.get_extra_registers().unwrap().get_raw().unwrap();
@@ -191,9 +245,31 @@ impl TraceReader {
                 ret.ev = from_trace_signal(EventType::EvSignalHandler, s)
             }
             frame::event::Exit(()) => ret.ev = Event::exit(),
+            frame::event::SdtProbe(Ok(p)) => {
+                let args = p
+                    .get_args()
+                    .unwrap()
+                    .iter()
+                    .map(|arg| {
+                        if arg.get_known() {
+                            Some(arg.get_value())
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                ret.ev = Event::new_sdt_probe_event(SdtProbeEventData {
+                    provider: String::from_utf8_lossy(p.get_provider().unwrap()).into_owned(),
+                    name: String::from_utf8_lossy(p.get_name().unwrap()).into_owned(),
+                    args,
+                });
+            }
             frame::event::SyscallbufFlush(r) => {
                 ret.ev = Event::new_syscallbuf_flush_event(SyscallbufFlushEventData::new());
                 let mprotect_records = r.get_mprotect_records().unwrap();
+                if mprotect_records.len() % size_of::<mprotect_record>() != 0 {
+                    fatal!("Invalid mprotect records length");
+                }
                 let records = &mut ret.ev.syscallbuf_flush_event_mut().mprotect_records;
                 records.resize(
                     mprotect_records.len() / size_of::<mprotect_record>(),
@@ -206,6 +282,9 @@ impl TraceReader {
                         records.len() * size_of::<mprotect_record>(),
                     );
                 }
+                for rec in records.iter() {
+                    check_tracee_range(rec.start, rec.size, "mprotect record");
+                }
             }
             frame::event::Syscall(r) => {
                 ret.ev = Event::new_syscall_event(SyscallEventData::new(
@@ -230,12 +309,32 @@ impl TraceReader {
                     }
                     frame::event::syscall::extra::OpenedFds(Ok(rr)) => {
                         for fd in rr.iter() {
+                            // Empty for files that weren't saved, and in older traces.
+                            let backing_file_name = fd.get_backing_file_name().unwrap();
+                            if backing_file_name.first() != Some(&b'/')
+                                && backing_file_name.contains(&b'/')
+                            {
+                                fatal!(
+                                    "Invalid backing file name {:?}",
+                                    OsStr::from_bytes(backing_file_name)
+                                );
+                            }
                             let opened_fd = OpenedFd {
                                 path: OsStr::from_bytes(fd.get_path().unwrap()).to_os_string(),
                                 fd: fd.get_fd(),
                                 device: fd.get_device(),
                                 // On x86 ino_t is a u32 and on x86_64 ino_t is a u64
                                 inode: fd.get_inode().try_into().unwrap(),
+                                backing_file_name: if backing_file_name.is_empty()
+                                    || backing_file_name[0] == b'/'
+                                {
+                                    OsStr::from_bytes(backing_file_name).to_os_string()
+                                } else {
+                                    let mut name = self.dir();
+                                    name.push("/");
+                                    name.push(OsStr::from_bytes(backing_file_name));
+                                    name
+                                },
                             };
                             syscall_ev.opened.push(opened_fd);
                         }
@@ -276,6 +375,7 @@ impl TraceReader {
             let map_msg = read_message(mmaps, ReaderOptions::new()).unwrap();
 
             let map = map_msg.get_root::<m_map::Reader>().unwrap();
+            check_mapping(&map);
             if time_constraint == TimeConstraint::CurrentTimeOnly {
                 if map.get_frame_time() as u64 != saved_global_time {
                     restore = true;
@@ -315,6 +415,18 @@ impl TraceReader {
                         m_map::source::File(f) => {
                             data.source = SourceFile;
                             let backing_file_name_int = f.get_backing_file_name().unwrap();
+                            // Names relative to the trace directory never have
+                            // directory components; don't let a trace reach
+                            // outside its directory with `..`.
+                            if backing_file_name_int.is_empty()
+                                || (backing_file_name_int[0] != b'/'
+                                    && backing_file_name_int.contains(&b'/'))
+                            {
+                                fatal!(
+                                    "Invalid backing file name {:?}",
+                                    OsStr::from_bytes(backing_file_name_int)
+                                );
+                            }
                             let is_clone = backing_file_name_int.starts_with(b"mmap_clone_");
                             let is_copy = backing_file_name_int.starts_with(b"mmap_copy_");
                             let mut backing_file_name_vec: Vec<u8> = Vec::new();
@@ -407,6 +519,13 @@ impl TraceReader {
         None
     }
 
+    /// Iterate over the remaining task events (clone, exec, exit, ...) in the
+    /// trace. Doesn't need a ReplaySession, so trace inspection commands can
+    /// use it directly.
+    pub fn task_events(&mut self) -> impl Iterator<Item = TraceTaskEvent> + '_ {
+        iter::from_fn(move || self.read_task_event(None))
+    }
+
     /// Read a task event (clone or exec record) from the trace.
     /// Returns `None` at the end of the trace.
     /// Sets `time` (if non-None) to the global time of the event.
@@ -429,8 +548,13 @@ impl TraceReader {
         let te: TraceTaskEvent;
         match task.which().unwrap() {
             task_event::Clone(r) => {
-                let clone_flags_ = r.get_flags();
+                // Older traces only have the flags truncated to 32 bits.
+                let clone_flags_ = match r.get_flags64() {
+                    0 => r.get_flags() as u32 as u64,
+                    flags => flags,
+                };
                 let parent_tid_ = i32_to_tid(r.get_parent_tid());
+                let parent_own_ns_tid_ = i32_to_tid(r.get_parent_own_ns_tid());
                 let own_ns_tid_ = i32_to_tid(r.get_own_ns_tid());
                 log!(
                     LogDebug,
@@ -442,6 +566,7 @@ impl TraceReader {
                 te = TraceTaskEvent {
                     variant: TraceTaskEventVariant::Clone(TraceTaskEventClone {
                         parent_tid_,
+                        parent_own_ns_tid_,
                         own_ns_tid_,
                         clone_flags_,
                     }),
@@ -451,16 +576,49 @@ impl TraceReader {
             task_event::Exec(r) => {
                 let file_name_ = r.get_file_name().unwrap();
                 let cmd_line_reader = r.get_cmd_line().unwrap();
+                if cmd_line_reader.len() > MAX_CMD_LINE_ARGS {
+                    fatal!("Invalid command line length {}", cmd_line_reader.len());
+                }
                 let mut cmd_line_: Vec<OsString> = Vec::new();
                 for cmd in cmd_line_reader.iter() {
                     cmd_line_.push(OsStr::from_bytes(cmd.unwrap()).to_os_string());
                 }
                 let exe_base_ = r.get_exe_base().into();
+                let auxv_ = r.get_auxv().unwrap().to_vec();
+                let mut attached_threads_: Vec<AttachedThread> = Vec::new();
+                for a in r.get_attached_threads().unwrap().iter() {
+                    let arch = from_trace_arch(a.get_arch().unwrap());
+                    let reg_data = a.get_registers().unwrap().get_raw().unwrap();
+                    if reg_data.len() != ptrace_regs_size(arch) {
+                        fatal!("Invalid register data length {}", reg_data.len());
+                    }
+                    let mut regs = Registers::new(arch);
+                    regs.set_from_ptrace_for_arch(arch, reg_data);
+                    let mut extra_regs = ExtraRegisters::new(arch);
+                    let extra_reg_data = a.get_extra_registers().unwrap().get_raw().unwrap();
+                    if !extra_reg_data.is_empty()
+                        && !extra_regs.set_to_raw_data(
+                            arch,
+                            Format::XSave,
+                            extra_reg_data,
+                            xsave_layout_from_trace(self.cpuid_records()),
+                        )
+                    {
+                        fatal!("Invalid XSAVE data in trace");
+                    }
+                    attached_threads_.push(AttachedThread {
+                        tid: i32_to_tid(a.get_tid()),
+                        regs,
+                        extra_regs,
+                    });
+                }
                 te = TraceTaskEvent {
                     variant: TraceTaskEventVariant::Exec(TraceTaskEventExec {
                         file_name_: OsStr::from_bytes(file_name_).to_os_string(),
                         cmd_line_,
                         exe_base_,
+                        auxv_,
+                        attached_threads_,
                     }),
                     tid_,
                 }
@@ -504,10 +662,49 @@ impl TraceReader {
             .reader_mut(Substream::RawData)
             .read(&mut d.data)
             .unwrap();
-        debug_assert_eq!(nread, d.data.len());
+        if nread != d.data.len() {
+            fatal!(
+                "Trace raw data truncated: expected {} bytes, got {}",
+                d.data.len(),
+                nread
+            );
+        }
         Some(d)
     }
 
+    /// Like read_raw_data_for_frame, but copies the data bytes to `out` a chunk
+    /// at a time instead of returning them, so records of any size can be read.
+    pub fn read_raw_data_for_frame_into(
+        &mut self,
+        out: &mut dyn Write,
+    ) -> io::Result<Option<RawDataMetadata>> {
+        let rec = match self.raw_recs.pop() {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0u8; min(rec.size, 1024 * 1024)];
+        let mut left = rec.size;
+        while left > 0 {
+            let chunk = &mut buf[0..min(left, buf.len())];
+            let nread = self.reader_mut(Substream::RawData).read(chunk)?;
+            if nread != chunk.len() {
+                fatal!(
+                    "Trace raw data truncated: expected {} bytes, got {}",
+                    rec.size,
+                    rec.size - left + nread
+                );
+            }
+            out.write_all(chunk)?;
+            left -= nread;
+        }
+        Ok(Some(rec))
+    }
+
+    /// The next raw data record for the last-read frame, without consuming it.
+    pub fn peek_raw_data_metadata_for_frame(&self) -> Option<&RawDataMetadata> {
+        self.raw_recs.last()
+    }
+
     /// Like read_raw_data_for_frame, but doesn't actually read the data bytes.
     /// Simply return the raw metadata or `None` if there are no records left.
     pub fn read_raw_data_metadata_for_frame(&mut self) -> Option<RawDataMetadata> {
@@ -577,7 +774,7 @@ impl TraceReader {
     /// Open the trace in 'dir'. When 'dir' is the `None`, open the
     /// latest trace.
     pub fn new<T: AsRef<OsStr>>(maybe_dir: Option<T>) -> TraceReader {
-        let mut trace_stream = TraceStream::new(&resolve_trace_name(maybe_dir), 1);
+        let mut trace_stream = TraceStream::new(&TraceDir::from_env().resolve(maybe_dir), 1);
 
         let mut readers: HashMap<Substream, CompressedReader> = HashMap::new();
         for &s in SUBSTREAMS.iter() {
@@ -679,6 +876,28 @@ impl TraceReader {
         }
         let xcr0_ = header.get_xcr0();
         let preload_thread_locals_recorded_ = header.get_preload_thread_locals_recorded();
+        let switches_recorded_ = header.get_switches_recorded();
+        let recorded_without_pmu_ = header.get_recorded_without_pmu();
+        let aslr_disabled_ = header.get_aslr_disabled();
+        let attached_ = header.get_attached();
+        let unpatched_libraries_: Vec<OsString> = header
+            .get_unpatched_libraries()
+            .unwrap()
+            .iter()
+            .map(|fsname| OsStr::from_bytes(fsname.unwrap()).to_os_string())
+            .collect();
+        let cgroup_limits_: Vec<CgroupLimit> = header
+            .get_cgroup_limits()
+            .unwrap()
+            .iter()
+            .map(|spec| match spec.unwrap().parse() {
+                Ok(limit) => limit,
+                Err(e) => fatal!("Invalid cgroup limit in trace: {}", e),
+            })
+            .collect();
+        let syscallbuf_protocol_version_ = header.get_syscallbuf_protocol_version();
+        let syscallbuf_hdr_size_ = header.get_syscallbuf_hdr_size();
+        let preload_thread_locals_size_ = header.get_preload_thread_locals_size();
         let ticks_semantics_ = from_trace_ticks_semantics(header.get_ticks_semantics().unwrap());
         let uuid_from_trace = header.get_uuid().unwrap();
         let mut uuid_ = TraceUuid::zero();
@@ -699,6 +918,15 @@ impl TraceReader {
             uuid_,
             trace_uses_cpuid_faulting,
             preload_thread_locals_recorded_,
+            switches_recorded_,
+            recorded_without_pmu_,
+            aslr_disabled_,
+            attached_,
+            unpatched_libraries_,
+            cgroup_limits_,
+            syscallbuf_protocol_version_,
+            syscallbuf_hdr_size_,
+            preload_thread_locals_size_,
             monotonic_time_: 0.0,
             raw_recs: vec![],
         }
@@ -736,6 +964,58 @@ impl TraceReader {
     pub fn preload_thread_locals_recorded(&self) -> bool {
         self.preload_thread_locals_recorded_
     }
+
+    /// True if the frames of this trace carry the task switches the scheduler
+    /// made. Older traces don't have them.
+    pub fn switches_recorded(&self) -> bool {
+        self.switches_recorded_
+    }
+
+    /// True if the trace was recorded with `--no-pmu`, i.e. without any ticks.
+    pub fn recorded_without_pmu(&self) -> bool {
+        self.recorded_without_pmu_
+    }
+
+    /// True if the tracees were recorded with ASLR disabled (`--no-aslr`), in
+    /// which case they are replayed with it disabled too.
+    pub fn aslr_disabled(&self) -> bool {
+        self.aslr_disabled_
+    }
+
+    /// True if the trace was recorded with `rd record --attach`, so it starts
+    /// with a snapshot of an already running process instead of an exec.
+    pub fn attached(&self) -> bool {
+        self.attached_
+    }
+
+    /// The mapped files whose syscalls recording left unpatched because of
+    /// `rd record --no-patch-library`.
+    pub fn unpatched_libraries(&self) -> &[OsString] {
+        &self.unpatched_libraries_
+    }
+
+    /// The `rd record --cgroup-limit`s the trace was recorded with.
+    pub fn cgroup_limits(&self) -> &[CgroupLimit] {
+        &self.cgroup_limits_
+    }
+
+    /// The SYSCALLBUF_PROTOCOL_VERSION of the rd that recorded the trace.
+    pub fn syscallbuf_protocol_version(&self) -> u16 {
+        self.syscallbuf_protocol_version_
+    }
+
+    /// Size of `syscallbuf_hdr` when the trace was recorded, or 0 if the
+    /// trace predates us recording it.
+    pub fn syscallbuf_hdr_size(&self) -> u32 {
+        self.syscallbuf_hdr_size_
+    }
+
+    /// Size of `preload_thread_locals` when the trace was recorded, or 0 if
+    /// the trace predates us recording it.
+    pub fn preload_thread_locals_size(&self) -> u32 {
+        self.preload_thread_locals_size_
+    }
+
     pub fn uuid(&self) -> &TraceUuid {
         &self.uuid_
     }
@@ -756,6 +1036,31 @@ impl TraceReader {
     }
 }
 
+/// True if the header of the trace in `trace_dir` says it was recorded with
+/// `--no-pmu`. False if the header can't be read: `TraceReader::new` reports
+/// that properly later. Unlike `TraceReader::new` this doesn't depend on
+/// `Flags`, so it can be used while working them out.
+pub fn trace_recorded_without_pmu(trace_dir: &OsStr) -> bool {
+    let mut version_path = trace_dir.to_os_string();
+    version_path.push("/version");
+    let mut buf_reader = match File::open(&version_path) {
+        Ok(f) => BufReader::new(f),
+        Err(_) => return false,
+    };
+    let mut version_str = String::new();
+    if buf_reader.read_line(&mut version_str).is_err()
+        || version_str.trim().parse::<u32>() != Ok(TRACE_VERSION)
+    {
+        return false;
+    }
+    match read_message(&mut buf_reader, ReaderOptions::new()) {
+        Ok(header_msg) => header_msg
+            .get_root::<header::Reader>()
+            .map_or(false, |header| header.get_recorded_without_pmu()),
+        Err(_) => false,
+    }
+}
+
 fn from_trace_arch(arch: TraceArch) -> SupportedArch {
     match arch {
         TraceArch::X86 => SupportedArch::X86,
@@ -817,6 +1122,20 @@ fn from_trace_signal(event_type: EventType, signal: signal::Reader) -> Event {
     Event::new_signal_event(event_type, sig_event)
 }
 
+fn from_trace_switch_reason(reason: TraceSwitchReason) -> SwitchReason {
+    match reason {
+        TraceSwitchReason::TimesliceExpired => SwitchReason::TimesliceExpired,
+        TraceSwitchReason::HigherPriority => SwitchReason::HigherPriority,
+        TraceSwitchReason::Yielded => SwitchReason::Yielded,
+        TraceSwitchReason::Blocked => SwitchReason::Blocked,
+        TraceSwitchReason::Exited => SwitchReason::Exited,
+        TraceSwitchReason::Unstable => SwitchReason::Unstable,
+        TraceSwitchReason::AlwaysSwitch => SwitchReason::AlwaysSwitch,
+        TraceSwitchReason::HighPriorityOnly => SwitchReason::HighPriorityOnly,
+        TraceSwitchReason::Execve => SwitchReason::Execve,
+    }
+}
+
 fn from_trace_ticks_semantics(semantics: TraceTicksSemantics) -> TicksSemantics {
     match semantics {
         TraceTicksSemantics::RetiredConditionalBranches => {
@@ -826,33 +1145,51 @@ fn from_trace_ticks_semantics(semantics: TraceTicksSemantics) -> TicksSemantics
     }
 }
 
-fn i32_to_tid(tid: i32) -> pid_t {
-    if tid <= 0 {
-        fatal!("Invalid tid");
+/// Size of the ptrace register data recorded for a task of architecture `arch`.
+fn ptrace_regs_size(arch: SupportedArch) -> usize {
+    match arch {
+        SupportedArch::X86 => size_of::<x86::user_regs_struct>(),
+        SupportedArch::X64 => size_of::<x64::user_regs_struct>(),
     }
-    tid
 }
 
-fn resolve_trace_name<T: AsRef<OsStr>>(maybe_trace_name: Option<T>) -> OsString {
-    if maybe_trace_name.is_none() {
-        return latest_trace_symlink();
+fn check_tracee_range(addr: u64, size: u64, what: &str) {
+    match addr.checked_add(size) {
+        Some(end) if end <= USER_ADDRESS_LIMIT => (),
+        _ => fatal!(
+            "{} at {:#x} of size {:#x} is outside the tracee address space",
+            what,
+            addr,
+            size
+        ),
     }
+}
 
-    let trace_name = maybe_trace_name.unwrap().as_ref().to_os_string();
-    // Single-component paths are looked up first in the current directory, next
-    // in the default trace dir.
-    if find(trace_name.as_bytes(), b"/").is_none() {
-        if dir_exists(trace_name.as_os_str()) {
-            return trace_name;
-        }
-
-        let mut resolved_trace_name: Vec<u8> = Vec::from(trace_save_dir().as_bytes());
-        resolved_trace_name.push(b'/');
-        resolved_trace_name.extend_from_slice(trace_name.as_bytes());
-        if dir_exists(resolved_trace_name.as_slice()) {
-            return OsString::from_vec(resolved_trace_name);
-        }
+/// Check the mapping is something the kernel could have created, before
+/// replay tries to create it.
+fn check_mapping(map: &m_map::Reader) {
+    let (start, end) = (map.get_start(), map.get_end());
+    if start >= end || start % page_size() as u64 != 0 || end % page_size() as u64 != 0 {
+        fatal!("Invalid mapping [{:#x}, {:#x}) in trace", start, end);
     }
+    check_tracee_range(start, end - start, "Mapping");
+    if map.get_file_offset_bytes() < 0 {
+        fatal!("Invalid file offset bytes");
+    }
+    if ProtFlags::from_bits(map.get_prot()).is_none()
+        || MapFlags::from_bits(map.get_flags()).is_none()
+    {
+        fatal!(
+            "Invalid protection {:#x} or flags {:#x} of mapping in trace",
+            map.get_prot(),
+            map.get_flags()
+        );
+    }
+}
 
-    trace_name
+fn i32_to_tid(tid: i32) -> pid_t {
+    if tid <= 0 {
+        fatal!("Invalid tid");
+    }
+    tid
 }
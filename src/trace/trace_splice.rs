@@ -0,0 +1,584 @@
+//! `rd record --record-children=failed`: splice the data of processes that
+//! exited successfully out of a trace, keeping the processes that failed
+//! replayable.
+//!
+//! A failed process can only be replayed along with its ancestors, which
+//! created it and whose memory it may have started out sharing, so those are
+//! retained too. Every other process keeps its frames up to and including its
+//! first exec, since until then it may share memory with its parent, and loses
+//! everything after it: the task then simply never runs again during replay.
+//! The process tree and exit statuses in the tasks substream are all kept, so
+//! `rd ps` still shows every process.
+//!
+//! Dropping frames is only safe if nothing retained observes their effects, so
+//! a process is retained after all if a frame it would lose
+//! - writes the memory of a task that is kept, e.g. through a monitored shared
+//!   file mapping,
+//! - creates a MAP_SHARED mapping, or
+//! - belongs to a task whose tid is reused later, since its task would still
+//!   exist during replay.
+//!
+//! Frames are numbered by their position in the events substream. Mmap and
+//! task event records name the frame they were written before, and are
+//! renumbered to follow the frames that remain.
+
+use crate::{
+    log::LogLevel::LogInfo,
+    trace::{
+        compressed_reader::CompressedReader,
+        compressed_writer::{self, CompressedWriter},
+        trace_codec::TraceCodec,
+        trace_frame::FrameTime,
+        trace_stream::{substream, Substream},
+    },
+    trace_capnp::{frame, m_map, task_event},
+    wait_status::WaitStatus,
+};
+use capnp::{
+    message,
+    message::ReaderOptions,
+    serialize::OwnedSegments,
+    serialize_packed::{read_message, write_message},
+};
+use libc::{pid_t, CLONE_THREAD, MAP_SHARED};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// What `retain_failed_processes` kept of a trace.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Retained {
+    /// No process failed. The trace was left alone, for the caller to discard.
+    Nothing,
+    /// Every process had to be retained, so the trace was left alone.
+    Everything,
+    /// The data of this many processes was (partly) dropped.
+    Failed { dropped_processes: usize },
+}
+
+/// Splice the data of the processes of `trace_dir` that don't need it out of
+/// the trace (see the module docs). `root_failed` is whether the recording as
+/// a whole failed, which marks the initial process failed even if it has no
+/// exit record. The rewritten substreams are compressed with `codec`.
+pub fn retain_failed_processes(
+    trace_dir: &OsStr,
+    root_failed: bool,
+    codec: TraceCodec,
+) -> io::Result<Retained> {
+    let dir = Path::new(trace_dir);
+    let tasks = read_all(&dir.join(substream(Substream::Tasks).name))?;
+    let maps = read_all(&dir.join(substream(Substream::Mmaps).name))?;
+
+    let mut tree = ProcessTree::default();
+    for task_msg in &tasks {
+        let task = task_msg
+            .get_root::<task_event::Reader>()
+            .map_err(invalid_data)?;
+        let time = task.get_frame_time() as FrameTime;
+        let tid = task.get_tid();
+        match task.which().map_err(invalid_data)? {
+            task_event::Clone(c) => {
+                let flags = match c.get_flags64() {
+                    0 => c.get_flags() as u32 as u64,
+                    flags => flags,
+                };
+                tree.add_clone(time, tid, c.get_parent_tid(), flags);
+            }
+            task_event::Exec(_) => tree.add_exec(time, tid),
+            task_event::Exit(e) => tree.add_exit(time, tid, WaitStatus::new(e.get_exit_status())),
+        }
+    }
+    if root_failed && !tree.processes.is_empty() {
+        tree.processes[0].failed = true;
+    }
+
+    let mut shared_map_times = HashSet::new();
+    for map_msg in &maps {
+        let map = map_msg.get_root::<m_map::Reader>().map_err(invalid_data)?;
+        if map.get_flags() & MAP_SHARED != 0 {
+            shared_map_times.insert(map.get_frame_time() as FrameTime);
+        }
+    }
+
+    let events_path = dir.join(substream(Substream::Events).name);
+    let mut frames = Vec::new();
+    let mut events = CompressedReader::new(events_path.as_os_str());
+    while !events.at_end() {
+        let frame_msg = read_message(&mut events, ReaderOptions::new()).map_err(invalid_data)?;
+        frames.push(FrameInfo::of(&frame_msg)?);
+    }
+
+    if !tree.retain_failed(&frames, &shared_map_times) {
+        return Ok(Retained::Nothing);
+    }
+    let drop_after = tree.drop_after();
+    let kept: Vec<bool> = frames
+        .iter()
+        .enumerate()
+        .map(|(i, f)| !tree.drops(&drop_after, f.tid, i as FrameTime + 1))
+        .collect();
+    if kept.iter().all(|&k| k) {
+        return Ok(Retained::Everything);
+    }
+
+    // kept_before[t - 1] is the number of kept frames before frame t.
+    let mut kept_before: Vec<FrameTime> = vec![0];
+    for &k in &kept {
+        kept_before.push(kept_before.last().unwrap() + k as FrameTime);
+    }
+    let frame_kept = |time: FrameTime| {
+        time.checked_sub(1)
+            .and_then(|i| kept.get(i as usize))
+            .copied()
+            .unwrap_or(true)
+    };
+    let new_time = |time: FrameTime| kept_before[(time.max(1) as usize - 1).min(kept.len())] + 1;
+
+    let mut splice = Splice::new(dir, codec);
+
+    let mut writer = splice.writer(Substream::Events);
+    let mut raw_writer = splice.writer(Substream::RawData);
+    let mut raw_reader =
+        CompressedReader::new(dir.join(substream(Substream::RawData).name).as_os_str());
+    events.rewind();
+    for (f, &k) in frames.iter().zip(&kept) {
+        let frame_msg = read_message(&mut events, ReaderOptions::new()).map_err(invalid_data)?;
+        if k {
+            write_message(&mut writer, &frame_msg)?;
+            let copied = io::copy(&mut (&mut raw_reader).take(f.raw_size), &mut raw_writer)?;
+            if copied != f.raw_size {
+                return Err(invalid_data("Raw data ends early"));
+            }
+        } else {
+            raw_reader.skip(f.raw_size as usize)?;
+        }
+    }
+    splice.finish(Substream::Events, writer)?;
+    splice.finish(Substream::RawData, raw_writer)?;
+
+    let mut writer = splice.writer(Substream::Mmaps);
+    let mut kept_files: HashSet<OsString> = HashSet::new();
+    let mut dropped_files: HashSet<OsString> = HashSet::new();
+    for map_msg in &maps {
+        let map = map_msg.get_root::<m_map::Reader>().map_err(invalid_data)?;
+        let time = map.get_frame_time() as FrameTime;
+        let k = frame_kept(time);
+        if let m_map::source::File(f) = map.get_source().which().map_err(invalid_data)? {
+            let name = OsStr::from_bytes(f.get_backing_file_name().map_err(invalid_data)?);
+            if k {
+                kept_files.insert(name.to_owned());
+            } else {
+                dropped_files.insert(name.to_owned());
+            }
+        }
+        if k {
+            let mut new_msg = message::Builder::new_default();
+            new_msg.set_root(map).map_err(invalid_data)?;
+            let mut new_map = new_msg.get_root::<m_map::Builder>().map_err(invalid_data)?;
+            new_map.set_frame_time(new_time(time) as i64);
+            write_message(&mut writer, &new_msg)?;
+        }
+    }
+    splice.finish(Substream::Mmaps, writer)?;
+
+    // The records of tasks that lose their frames are kept for `rd ps` but
+    // numbered as if they came right after the last kept frame before them.
+    // Replay only looks for a clone or exec record at the frame it's
+    // replaying, so it skips them.
+    let mut writer = splice.writer(Substream::Tasks);
+    for task_msg in &tasks {
+        let task = task_msg
+            .get_root::<task_event::Reader>()
+            .map_err(invalid_data)?;
+        let time = task.get_frame_time() as FrameTime;
+        let mut new_msg = message::Builder::new_default();
+        new_msg.set_root(task).map_err(invalid_data)?;
+        let mut new_task = new_msg
+            .get_root::<task_event::Builder>()
+            .map_err(invalid_data)?;
+        let renumbered = if frame_kept(time) {
+            new_time(time)
+        } else {
+            new_time(time) - 1
+        };
+        new_task.set_frame_time(renumbered as i64);
+        write_message(&mut writer, &new_msg)?;
+    }
+    splice.finish(Substream::Tasks, writer)?;
+
+    splice.commit()?;
+
+    // Copies of mapped files only the dropped frames used aren't needed any more.
+    for name in dropped_files.difference(&kept_files) {
+        if !name.as_bytes().starts_with(b"/") {
+            fs::remove_file(dir.join(name))?;
+        }
+    }
+
+    let dropped_processes = drop_after.iter().filter(|d| d.is_some()).count();
+    log!(
+        LogInfo,
+        "Spliced {:?}: dropped {} of {} frames, of {} process(es)",
+        trace_dir,
+        frames.len() - *kept_before.last().unwrap() as usize,
+        frames.len(),
+        dropped_processes
+    );
+    Ok(Retained::Failed { dropped_processes })
+}
+
+/// What retention needs to know about a frame.
+#[derive(Clone, Debug, Default)]
+struct FrameInfo {
+    tid: pid_t,
+    /// Bytes of raw data the frame's memory writes take up.
+    raw_size: u64,
+    /// Tasks other than `tid` whose memory the frame writes.
+    written_tids: Vec<pid_t>,
+}
+
+impl FrameInfo {
+    fn of(frame_msg: &message::Reader<OwnedSegments>) -> io::Result<FrameInfo> {
+        let frame = frame_msg
+            .get_root::<frame::Reader>()
+            .map_err(invalid_data)?;
+        let tid = frame.get_tid();
+        let mut info = FrameInfo {
+            tid,
+            ..Default::default()
+        };
+        for w in frame.get_mem_writes().map_err(invalid_data)?.iter() {
+            info.raw_size = info.raw_size.saturating_add(w.get_size());
+            if w.get_tid() != tid && !info.written_tids.contains(&w.get_tid()) {
+                info.written_tids.push(w.get_tid());
+            }
+        }
+        Ok(info)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Process {
+    parent: Option<usize>,
+    /// Time of the clone that created the process.
+    created_at: FrameTime,
+    /// Time of its first exec.
+    exec_time: Option<FrameTime>,
+    failed: bool,
+    retained: bool,
+    tids: Vec<pid_t>,
+}
+
+/// The processes of a trace, in the order they were created.
+#[derive(Default)]
+struct ProcessTree {
+    processes: Vec<Process>,
+    /// For each tid, the times tasks with it were created and their process.
+    tids: HashMap<pid_t, Vec<(FrameTime, usize)>>,
+}
+
+impl ProcessTree {
+    /// The process of the task `tid` at `time`.
+    fn process_of(&self, tid: pid_t, time: FrameTime) -> Option<usize> {
+        self.tids
+            .get(&tid)?
+            .iter()
+            .rev()
+            .find(|&&(start, _)| start <= time)
+            .map(|&(_, p)| p)
+    }
+
+    fn add_process(&mut self, time: FrameTime, tid: pid_t, parent: Option<usize>) {
+        let p = self.processes.len();
+        self.processes.push(Process {
+            parent,
+            created_at: time,
+            ..Default::default()
+        });
+        self.add_task(time, tid, p);
+    }
+
+    fn add_task(&mut self, time: FrameTime, tid: pid_t, p: usize) {
+        self.processes[p].tids.push(tid);
+        self.tids.entry(tid).or_default().push((time, p));
+    }
+
+    fn add_clone(&mut self, time: FrameTime, tid: pid_t, parent_tid: pid_t, flags: u64) {
+        match self.process_of(parent_tid, time) {
+            Some(p) if flags & CLONE_THREAD as u64 != 0 => self.add_task(time, tid, p),
+            parent => self.add_process(time, tid, parent),
+        }
+    }
+
+    fn add_exec(&mut self, time: FrameTime, tid: pid_t) {
+        match self.process_of(tid, time) {
+            Some(p) => {
+                let process = &mut self.processes[p];
+                process.exec_time.get_or_insert(time);
+            }
+            // The initial process (or the one rd attached to) isn't cloned by
+            // anything.
+            None => {
+                self.add_process(time, tid, None);
+                self.processes.last_mut().unwrap().exec_time = Some(time);
+            }
+        }
+    }
+
+    fn add_exit(&mut self, time: FrameTime, tid: pid_t, status: WaitStatus) {
+        if status.exit_code() != Some(0) {
+            if let Some(p) = self.process_of(tid, time) {
+                self.processes[p].failed = true;
+            }
+        }
+    }
+
+    fn retain(&mut self, mut p: usize) {
+        loop {
+            self.processes[p].retained = true;
+            match self.processes[p].parent {
+                Some(parent) => p = parent,
+                None => break,
+            }
+        }
+    }
+
+    /// For each process, the time after which its frames are dropped, or
+    /// `None` if they're all kept.
+    fn drop_after(&self) -> Vec<Option<FrameTime>> {
+        let mut drop_after: Vec<Option<FrameTime>> = Vec::with_capacity(self.processes.len());
+        for process in &self.processes {
+            let d = if process.retained {
+                None
+            } else if process.parent.map_or(false, |parent| {
+                dropped(drop_after[parent], process.created_at)
+            }) {
+                // The clone that created it isn't replayed.
+                Some(0)
+            } else {
+                process.exec_time
+            };
+            drop_after.push(d);
+        }
+        drop_after
+    }
+
+    /// Whether the frame of task `tid` at `time` is dropped. The frames of
+    /// tasks we don't know about are kept.
+    fn drops(&self, drop_after: &[Option<FrameTime>], tid: pid_t, time: FrameTime) -> bool {
+        self.process_of(tid, time)
+            .map_or(false, |p| dropped(drop_after[p], time))
+    }
+
+    /// Retain the failed processes and the ones whose frames can't safely be
+    /// dropped (see the module docs). Returns false if no process failed.
+    fn retain_failed(
+        &mut self,
+        frames: &[FrameInfo],
+        shared_map_times: &HashSet<FrameTime>,
+    ) -> bool {
+        let failed: Vec<usize> = (0..self.processes.len())
+            .filter(|&p| self.processes[p].failed)
+            .collect();
+        if failed.is_empty() {
+            return false;
+        }
+        for p in failed {
+            self.retain(p);
+        }
+
+        for p in 0..self.processes.len() {
+            let reused = self.processes[p]
+                .tids
+                .iter()
+                .any(|tid| self.tids[tid].last().unwrap().1 != p);
+            if reused {
+                self.retain(p);
+            }
+        }
+
+        loop {
+            let drop_after = self.drop_after();
+            let mut unsafe_drops = Vec::new();
+            for (i, f) in frames.iter().enumerate() {
+                let time = i as FrameTime + 1;
+                let p = match self.process_of(f.tid, time) {
+                    Some(p) if dropped(drop_after[p], time) => p,
+                    _ => continue,
+                };
+                if shared_map_times.contains(&time)
+                    || f.written_tids.iter().any(|&tid| {
+                        self.process_of(tid, time)
+                            .map_or(true, |w| !dropped(drop_after[w], time))
+                    })
+                {
+                    unsafe_drops.push(p);
+                }
+            }
+            if unsafe_drops.is_empty() {
+                return true;
+            }
+            for p in unsafe_drops {
+                self.retain(p);
+            }
+        }
+    }
+}
+
+fn dropped(drop_after: Option<FrameTime>, time: FrameTime) -> bool {
+    drop_after.map_or(false, |after| time > after)
+}
+
+/// The substreams being rewritten. Each is written next to the original and
+/// they're only renamed over the originals once all of them are complete.
+struct Splice<'a> {
+    dir: &'a Path,
+    codec: TraceCodec,
+    written: Vec<Substream>,
+    committed: bool,
+}
+
+impl<'a> Splice<'a> {
+    fn new(dir: &'a Path, codec: TraceCodec) -> Splice<'a> {
+        Splice {
+            dir,
+            codec,
+            written: Vec::new(),
+            committed: false,
+        }
+    }
+
+    fn tmp_path(&self, s: Substream) -> PathBuf {
+        self.dir.join(format!("{}.splice", substream(s).name))
+    }
+
+    fn writer(&self, s: Substream) -> CompressedWriter {
+        let data = substream(s);
+        CompressedWriter::new(
+            self.tmp_path(s).as_os_str(),
+            data.block_size,
+            data.threads,
+            self.codec,
+        )
+    }
+
+    fn finish(&mut self, s: Substream, mut writer: CompressedWriter) -> io::Result<()> {
+        writer.close(Some(compressed_writer::Sync::Sync));
+        if !writer.good() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unable to write {:?}", self.tmp_path(s)),
+            ));
+        }
+        self.written.push(s);
+        Ok(())
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        self.committed = true;
+        for &s in &self.written {
+            fs::rename(self.tmp_path(s), self.dir.join(substream(s).name))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Splice<'a> {
+    fn drop(&mut self) {
+        // Left over if the splice failed.
+        if !self.committed {
+            for &s in Substream::iter() {
+                fs::remove_file(self.tmp_path(s)).ok();
+            }
+        }
+    }
+}
+
+fn read_all(path: &Path) -> io::Result<Vec<message::Reader<OwnedSegments>>> {
+    let mut messages = Vec::new();
+    let mut reader = CompressedReader::new(path.as_os_str());
+    while !reader.at_end() {
+        messages.push(read_message(&mut reader, ReaderOptions::new()).map_err(invalid_data)?);
+    }
+    Ok(messages)
+}
+
+fn invalid_data<E: fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(tid: pid_t) -> FrameInfo {
+        FrameInfo {
+            tid,
+            ..Default::default()
+        }
+    }
+
+    /// Process 1 execs, forks 2 and 3, which exec and exit with `statuses`.
+    fn fork_two(statuses: [i32; 2]) -> (ProcessTree, Vec<FrameInfo>) {
+        let mut tree = ProcessTree::default();
+        tree.add_exec(1, 1);
+        tree.add_clone(2, 2, 1, 0);
+        tree.add_clone(3, 3, 1, 0);
+        tree.add_exec(4, 2);
+        tree.add_exec(5, 3);
+        tree.add_exit(8, 2, WaitStatus::for_exit_code(statuses[0]));
+        tree.add_exit(8, 3, WaitStatus::for_exit_code(statuses[1]));
+        let frames = [1, 1, 1, 2, 3, 2, 3, 1].iter().map(|&t| frame(t)).collect();
+        (tree, frames)
+    }
+
+    fn kept(tree: &ProcessTree, frames: &[FrameInfo]) -> Vec<bool> {
+        let drop_after = tree.drop_after();
+        frames
+            .iter()
+            .enumerate()
+            .map(|(i, f)| !tree.drops(&drop_after, f.tid, i as FrameTime + 1))
+            .collect()
+    }
+
+    #[test]
+    fn retains_failed_processes() {
+        let (mut tree, frames) = fork_two([0, 1]);
+        assert!(tree.retain_failed(&frames, &HashSet::new()));
+        // Process 2 keeps its frames up to its exec.
+        assert_eq!(
+            kept(&tree, &frames),
+            [true, true, true, true, true, false, true, true]
+        );
+
+        let (mut tree, frames) = fork_two([0, 0]);
+        assert!(!tree.retain_failed(&frames, &HashSet::new()));
+    }
+
+    #[test]
+    fn retains_unsafe_drops() {
+        let (mut tree, mut frames) = fork_two([0, 1]);
+        frames[5].written_tids.push(3);
+        assert!(tree.retain_failed(&frames, &HashSet::new()));
+        assert!(kept(&tree, &frames).iter().all(|&k| k));
+
+        let (mut tree, frames) = fork_two([0, 1]);
+        assert!(tree.retain_failed(&frames, &[6].iter().copied().collect()));
+        assert!(kept(&tree, &frames).iter().all(|&k| k));
+    }
+
+    #[test]
+    fn drops_children_of_dropped_frames() {
+        let (mut tree, mut frames) = fork_two([0, 1]);
+        // Process 2 forks 4 after its exec.
+        tree.add_clone(6, 4, 2, 0);
+        frames.push(frame(4));
+        assert!(tree.retain_failed(&frames, &HashSet::new()));
+        assert!(!kept(&tree, &frames)[8]);
+    }
+}
@@ -4,17 +4,14 @@ use crate::{
     taskish_uid::TaskUid,
     trace::trace_frame::FrameTime,
     trace_capnp::Arch as TraceArch,
-    util::{dir_exists, ensure_dir, get_num_cpus, real_path},
+    util::{get_num_cpus, real_path},
 };
-use libc::{pid_t, EEXIST};
-use nix::{errno::errno, sys::stat::Mode, unistd::mkdir};
+use libc::pid_t;
 use std::{
     cmp::min,
-    env,
     ffi::{OsStr, OsString},
     io::Write,
     os::unix::ffi::{OsStrExt, OsStringExt},
-    path::Path,
     slice::Iter,
 };
 
@@ -221,118 +218,6 @@ pub struct MappedData {
     pub file_size_bytes: usize,
 }
 
-pub(super) fn make_trace_dir(exe_path: &OsStr, maybe_output_trace_dir: Option<&OsStr>) -> OsString {
-    match maybe_output_trace_dir {
-        Some(output_trace_dir) => {
-            // DIFF NOTE: Make trace dirs only S_IRWXU to be conservative. rr adds Mode::S_IRWXG also.
-            // save trace dir in given output trace dir with option -o
-            let ret = mkdir(output_trace_dir, Mode::S_IRWXU);
-            match ret {
-                Ok(_) => output_trace_dir.to_owned(),
-                Err(e) if EEXIST == errno() => {
-                    // directory already exists
-                    fatal!("Directory {:?} already exists: {:?}", output_trace_dir, e)
-                }
-                Err(e) => fatal!(
-                    "Unable to create trace directory {:?}: {:?}",
-                    output_trace_dir,
-                    e
-                ),
-            }
-        }
-        None => {
-            // save trace dir set in _RD_TRACE_DIR or in the default trace dir
-            ensure_dir(
-                trace_save_dir().as_os_str(),
-                "trace directory",
-                Mode::S_IRWXU,
-            );
-
-            // Find a unique trace directory name.
-            let mut nonce = 0;
-            let mut ret;
-            let mut dir;
-            let mut ss: Vec<u8> = Vec::from(trace_save_dir().as_bytes());
-            ss.push(b'/');
-            ss.extend_from_slice(Path::new(exe_path).file_name().unwrap().as_bytes());
-            loop {
-                dir = Vec::from(ss.as_slice());
-                write!(dir, "-{}", nonce).unwrap();
-                nonce += 1;
-                // DIFF NOTE: Make trace dirs only S_IRWXU to be conservative. rr adds Mode::S_IRWXG also.
-                ret = mkdir(dir.as_slice(), Mode::S_IRWXU);
-                if ret.is_ok() || EEXIST != errno() {
-                    break;
-                }
-            }
-
-            let os_dir = OsString::from_vec(dir);
-            match ret {
-                Err(e) => fatal!("Unable to create trace directory {:?}: {:?}", os_dir, e),
-                Ok(_) => os_dir,
-            }
-        }
-    }
-}
-
-/// @TODO Look at logic again carefully
-pub(super) fn default_rd_trace_dir() -> OsString {
-    let cached_dir: OsString;
-    let mut dot_dir: Vec<u8> = Vec::new();
-    let maybe_home = env::var_os("HOME");
-    let home: OsString;
-    match maybe_home {
-        Some(found_home) if !found_home.is_empty() => {
-            dot_dir.extend_from_slice(found_home.as_bytes());
-            dot_dir.extend_from_slice(b"/.rd");
-            home = found_home;
-        }
-        // @TODO This seems to be an implicit outcome of what we have in rr
-        _ => home = OsString::new(),
-    }
-
-    let mut xdg_dir: Vec<u8> = Vec::new();
-    let maybe_xdg_data_home = env::var_os("XDG_DATA_HOME");
-    match maybe_xdg_data_home {
-        Some(xdg_data_home) if !xdg_data_home.is_empty() => {
-            xdg_dir.extend_from_slice(xdg_data_home.as_bytes());
-            xdg_dir.extend_from_slice(b"/rd");
-        }
-        _ => {
-            xdg_dir.extend_from_slice(home.as_bytes());
-            xdg_dir.extend_from_slice(b"/.local/share/rd");
-        }
-    }
-
-    // If XDG dir does not exist but ~/.rd does, prefer ~/.rd for backwards
-    // compatibility.
-    if dir_exists(xdg_dir.as_slice()) {
-        cached_dir = OsString::from_vec(xdg_dir);
-    } else if dir_exists(dot_dir.as_slice()) {
-        cached_dir = OsString::from_vec(dot_dir);
-    } else if !xdg_dir.is_empty() {
-        cached_dir = OsString::from_vec(xdg_dir);
-    } else {
-        cached_dir = OsStr::from_bytes(b"/tmp/rd").to_os_string();
-    }
-
-    cached_dir
-}
-
-pub(super) fn trace_save_dir() -> OsString {
-    let maybe_output_dir = env::var_os("_RD_TRACE_DIR");
-    match maybe_output_dir {
-        Some(dir) if !dir.is_empty() => dir,
-        _ => default_rd_trace_dir(),
-    }
-}
-
-pub(super) fn latest_trace_symlink() -> OsString {
-    let mut sym: Vec<u8> = Vec::from(trace_save_dir().as_bytes());
-    sym.extend_from_slice(b"/latest-trace");
-    OsString::from_vec(sym)
-}
-
 pub(super) fn to_trace_arch(arch: SupportedArch) -> TraceArch {
     match arch {
         SupportedArch::X86 => TraceArch::X86,
@@ -1,4 +1,6 @@
 use crate::{
+    extra_registers::ExtraRegisters,
+    registers::Registers,
     remote_ptr::{RemotePtr, Void},
     wait_status::WaitStatus,
 };
@@ -68,11 +70,13 @@ impl TraceTaskEvent {
     pub fn for_clone(
         tid: pid_t,
         parent_tid: pid_t,
+        parent_own_ns_tid: pid_t,
         own_ns_tid: pid_t,
-        clone_flags: i32,
+        clone_flags: u64,
     ) -> TraceTaskEvent {
         let variant = TraceTaskEventVariant::Clone(TraceTaskEventClone {
             parent_tid_: parent_tid,
+            parent_own_ns_tid_: parent_own_ns_tid,
             own_ns_tid_: own_ns_tid,
             clone_flags_: clone_flags,
         });
@@ -85,6 +89,8 @@ impl TraceTaskEvent {
             file_name_: file_name.to_owned(),
             cmd_line_: cmd_line.to_owned(),
             exe_base_: RemotePtr::null(),
+            auxv_: Vec::new(),
+            attached_threads_: Vec::new(),
         });
         TraceTaskEvent { variant, tid_: tid }
     }
@@ -100,18 +106,24 @@ impl TraceTaskEvent {
 #[derive(Clone)]
 pub struct TraceTaskEventClone {
     pub(super) parent_tid_: pid_t,
+    pub(super) parent_own_ns_tid_: pid_t,
     pub(super) own_ns_tid_: pid_t,
-    pub(super) clone_flags_: i32,
+    pub(super) clone_flags_: u64,
 }
 
 impl TraceTaskEventClone {
     pub fn parent_tid(&self) -> pid_t {
         self.parent_tid_
     }
+    /// The parent's tid in its own pid namespace. 0 if the trace predates
+    /// this being recorded.
+    pub fn parent_own_ns_tid(&self) -> pid_t {
+        self.parent_own_ns_tid_
+    }
     pub fn own_ns_tid(&self) -> pid_t {
         self.own_ns_tid_
     }
-    pub fn clone_flags(&self) -> i32 {
+    pub fn clone_flags(&self) -> u64 {
         self.clone_flags_
     }
 }
@@ -121,6 +133,8 @@ pub struct TraceTaskEventExec {
     pub(super) file_name_: OsString,
     pub(super) cmd_line_: Vec<OsString>,
     pub(super) exe_base_: RemotePtr<Void>,
+    pub(super) auxv_: Vec<u8>,
+    pub(super) attached_threads_: Vec<AttachedThread>,
 }
 
 impl TraceTaskEventExec {
@@ -139,6 +153,35 @@ impl TraceTaskEventExec {
     pub fn set_exe_base(&mut self, ptr: RemotePtr<Void>) {
         self.exe_base_ = ptr;
     }
+
+    /// The auxiliary vector recorded for the snapshot an attached trace starts
+    /// with. Empty for real execs.
+    pub fn auxv(&self) -> &[u8] {
+        &self.auxv_
+    }
+
+    pub fn set_auxv(&mut self, auxv: Vec<u8>) {
+        self.auxv_ = auxv;
+    }
+
+    /// The other threads of the process, for the snapshot an attached trace
+    /// starts with. Empty for real execs.
+    pub fn attached_threads(&self) -> &[AttachedThread] {
+        &self.attached_threads_
+    }
+
+    pub fn set_attached_threads(&mut self, attached_threads: Vec<AttachedThread>) {
+        self.attached_threads_ = attached_threads;
+    }
+}
+
+/// A thread of a process that `rd record --attach` attached to, other than the
+/// one the snapshot of the process is recorded for.
+#[derive(Clone)]
+pub struct AttachedThread {
+    pub tid: pid_t,
+    pub regs: Registers,
+    pub extra_regs: ExtraRegisters,
 }
 
 #[derive(Clone)]
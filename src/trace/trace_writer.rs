@@ -4,13 +4,17 @@ use crate::{
     bindings::signal::siginfo_t,
     event::{Event, EventType, SignalDeterministic, SignalResolvedDisposition, SyscallState},
     extra_registers::ExtraRegisters,
+    file_monitor::cgroup_file_monitor::CgroupLimit,
     kernel_abi::{syscall_number_for_restart_syscall, RD_NATIVE_ARCH},
     kernel_supplement::{btrfs_ioctl_clone_range_args, BTRFS_IOC_CLONE_, BTRFS_IOC_CLONE_RANGE_},
     log::LogLevel::LogDebug,
-    perf_counters::{PerfCounters, TicksSemantics},
-    preload_interface::{mprotect_record, SYSCALLBUF_PROTOCOL_VERSION},
+    perf_counters::{pmu_disabled, PerfCounters, TicksSemantics},
+    preload_interface::{
+        mprotect_record, syscallbuf_hdr, PRELOAD_THREAD_LOCALS_SIZE, SYSCALLBUF_PROTOCOL_VERSION,
+    },
     registers::Registers,
     remote_ptr::{RemotePtr, Void},
+    scheduler::{SwitchReason, TaskSwitch},
     scoped_fd::ScopedFd,
     session::{
         address_space::kernel_mapping::KernelMapping,
@@ -19,34 +23,34 @@ use crate::{
     },
     trace::{
         compressed_writer::CompressedWriter,
+        object_store,
+        trace_codec::TraceCodec,
+        trace_dir::TraceDir,
         trace_stream::{
-            latest_trace_symlink, make_trace_dir, substream, to_trace_arch, MappedData,
-            MappedDataSource, RawDataMetadata, Substream, TraceRemoteFd, TraceStream, SUBSTREAMS,
-            TRACE_VERSION,
+            substream, to_trace_arch, MappedData, MappedDataSource, RawDataMetadata, Substream,
+            TraceRemoteFd, TraceStream, SUBSTREAMS, TRACE_VERSION,
         },
         trace_task_event::{TraceTaskEvent, TraceTaskEventVariant},
     },
     trace_capnp::{
         frame, header, m_map, m_map::source::Which::Trace, signal, task_event,
-        SignalDisposition as TraceSignalDisposition, SyscallState as TraceSyscallState,
-        TicksSemantics as TraceTicksSemantics,
+        SignalDisposition as TraceSignalDisposition, SwitchReason as TraceSwitchReason,
+        SyscallState as TraceSyscallState, TicksSemantics as TraceTicksSemantics,
     },
     util::{
         all_cpuid_records, copy_file, monotonic_now_sec, probably_not_interactive,
-        should_copy_mmap_region, write_all, xcr0, CPUIDRecord,
+        realtime_now_sec, should_copy_mmap_region, write_all, xcr0, CPUIDRecord,
     },
 };
 use capnp::{message, serialize_packed::write_message};
-use libc::{dev_t, ino_t, ioctl, pid_t, EEXIST, STDOUT_FILENO};
+use libc::{dev_t, ino_t, ioctl, pid_t, STDOUT_FILENO};
 use nix::{
-    errno::{errno, Errno},
     fcntl::{flock, readlink, FlockArg::LockExclusiveNonblock, OFlag},
     sys::{
         mman::{MapFlags, ProtFlags},
         stat::Mode,
     },
     unistd::unlink,
-    Error,
 };
 use std::{
     collections::HashMap,
@@ -58,7 +62,6 @@ use std::{
     ops::{Deref, DerefMut},
     os::unix::{
         ffi::{OsStrExt, OsStringExt},
-        fs::symlink,
         io::FromRawFd,
     },
     path::Path,
@@ -130,6 +133,8 @@ pub struct TraceWriter {
     /// are immutable.
     files_assumed_immutable: HashMap<(dev_t, ino_t), OsString>,
     raw_recs: Vec<RawDataMetadata>,
+    /// Task switches made since the last frame was written
+    switches: Vec<TaskSwitch>,
     cpuid_records: Vec<CPUIDRecord>,
     ticks_semantics_: TicksSemantics,
     /// Keep the 'incomplete' (later renamed to 'version') file open until we
@@ -137,6 +142,10 @@ pub struct TraceWriter {
     version_fd: ScopedFd,
     mmap_count: u32,
     has_cpuid_faulting_: bool,
+    aslr_disabled_: bool,
+    attached_: bool,
+    unpatched_libraries: Vec<OsString>,
+    cgroup_limits: Vec<CgroupLimit>,
     supports_file_data_cloning_: bool,
 }
 
@@ -176,6 +185,7 @@ impl TraceWriter {
         // DIFF NOTE: In rr ticks are signed. In rd they are not.
         frame.set_ticks(t.tick_count() as i64);
         frame.set_monotonic_sec(monotonic_now_sec());
+        frame.set_realtime_sec(realtime_now_sec());
 
         {
             let mut mem_writes = frame.reborrow().init_mem_writes(self.raw_recs.len() as u32);
@@ -187,6 +197,16 @@ impl TraceWriter {
             }
         }
         self.raw_recs.clear();
+        {
+            let mut switches = frame.reborrow().init_switches(self.switches.len() as u32);
+            for (i, sw) in self.switches.iter().enumerate() {
+                let mut w = switches.reborrow().get(i as u32);
+                w.set_from_tid(sw.from_tid);
+                w.set_to_tid(sw.to_tid);
+                w.set_reason(to_trace_switch_reason(sw.reason));
+            }
+        }
+        self.switches.clear();
         frame.set_arch(to_trace_arch(t.arch()));
         {
             match maybe_registers {
@@ -240,6 +260,18 @@ impl TraceWriter {
                 EventType::EvExit => {
                     event.set_exit(());
                 }
+                EventType::EvSdtProbe => {
+                    let e = ev.sdt_probe_event();
+                    let mut probe = event.init_sdt_probe();
+                    probe.set_provider(e.provider.as_bytes());
+                    probe.set_name(e.name.as_bytes());
+                    let mut args = probe.init_args(e.args.len() as u32);
+                    for (i, &arg) in e.args.iter().enumerate() {
+                        let mut a = args.reborrow().get(i as u32);
+                        a.set_known(arg.is_some());
+                        a.set_value(arg.unwrap_or(0));
+                    }
+                }
                 EventType::EvSyscallbufFlush => {
                     let e = ev.syscallbuf_flush_event();
                     let data = unsafe {
@@ -286,6 +318,7 @@ impl TraceWriter {
                                 o.set_path(opened.path.as_bytes());
                                 o.set_device(opened.device);
                                 o.set_inode(opened.inode.into());
+                                o.set_backing_file_name(opened.backing_file_name.as_bytes());
                             }
                         }
                         None => (),
@@ -379,7 +412,9 @@ impl TraceWriter {
                     src.reborrow()
                         .init_file()
                         .set_backing_file_name(backing_file_name.as_bytes());
-                } else if should_copy_mmap_region(km, stat) {
+                } else if t.session().as_record().unwrap().isolate_file_access()
+                    || should_copy_mmap_region(km, stat)
+                {
                     // Make executable files accessible to debuggers by copying the whole
                     // thing into the trace directory. We don't get to compress the data and
                     // the entire file is copied, not just the used region, which is why we
@@ -391,7 +426,7 @@ impl TraceWriter {
                     // shared mappings so situations where a shared-mapped executable contains
                     // usable debug info should be very rare at best...
                     if km.prot().contains(ProtFlags::PROT_EXEC)
-                        && self.copy_file(&file_name, &mut backing_file_name)
+                        && self.copy_file(t, &file_name, &mut backing_file_name)
                         && !km.flags().contains(MapFlags::MAP_SHARED)
                     {
                         src.reborrow()
@@ -499,6 +534,11 @@ impl TraceWriter {
         });
     }
 
+    /// Note a task switch. It's stored in the next frame written.
+    pub fn record_switch(&mut self, switch: TaskSwitch) {
+        self.switches.push(switch);
+    }
+
     /// Write a task event (clone or exec record) to the trace.
     pub fn write_task_event(&mut self, event: &TraceTaskEvent) {
         let mut task_msg = message::Builder::new_default();
@@ -511,8 +551,10 @@ impl TraceWriter {
             TraceTaskEventVariant::Clone(e) => {
                 let mut clone = task.init_clone();
                 clone.set_parent_tid(e.parent_tid());
+                clone.set_parent_own_ns_tid(e.parent_own_ns_tid());
                 clone.set_own_ns_tid(e.own_ns_tid());
-                clone.set_flags(e.clone_flags());
+                clone.set_flags(e.clone_flags() as i32);
+                clone.set_flags64(e.clone_flags());
             }
             TraceTaskEventVariant::Exec(e) => {
                 let mut exec = task.init_exec();
@@ -523,6 +565,20 @@ impl TraceWriter {
                     cmd_line.set(i as u32, event_cmd_line[i].as_bytes());
                 }
                 exec.set_exe_base(e.exe_base().as_usize() as u64);
+                exec.set_auxv(e.auxv());
+                let threads = e.attached_threads();
+                let mut attached = exec.init_attached_threads(threads.len() as u32);
+                for (i, thread) in threads.iter().enumerate() {
+                    let mut a = attached.reborrow().get(i as u32);
+                    a.set_tid(thread.tid);
+                    a.set_arch(to_trace_arch(thread.regs.arch()));
+                    a.reborrow()
+                        .init_registers()
+                        .set_raw(thread.regs.get_ptrace_for_self_arch());
+                    a.reborrow()
+                        .init_extra_registers()
+                        .set_raw(thread.extra_regs.data_bytes());
+                }
             }
             TraceTaskEventVariant::Exit(e) => {
                 task.init_exit().set_exit_status(e.exit_status().get());
@@ -549,22 +605,31 @@ impl TraceWriter {
     /// Create a trace where the traces are bound to cpu `bind_to_cpu`. This
     /// data is recorded in the trace. If `bind_to_cpu` is `None` then the tracees
     /// were not bound.
-    /// The trace name is determined by `file_name` and _RD_TRACE_DIR (if set)
+    /// The trace name is determined by `file_name` and RD_TRACE_DIR (if set)
     /// or by setting -o=<OUTPUT_TRACE_DIR>.
     pub fn new(
         file_name: &OsStr,
         bind_to_cpu: Option<u32>,
         output_trace_dir: Option<&OsStr>,
         ticks_semantics_: TicksSemantics,
+        codec: TraceCodec,
     ) -> TraceWriter {
         let mut tw = TraceWriter {
-            trace_stream: TraceStream::new(&make_trace_dir(file_name, output_trace_dir), 1),
+            trace_stream: TraceStream::new(
+                &TraceDir::from_env().create_trace_dir(file_name, output_trace_dir),
+                1,
+            ),
             ticks_semantics_,
             mmap_count: 0,
             has_cpuid_faulting_: false,
+            aslr_disabled_: false,
+            attached_: false,
+            unpatched_libraries: vec![],
+            cgroup_limits: vec![],
             writers: Default::default(),
             files_assumed_immutable: Default::default(),
             raw_recs: vec![],
+            switches: vec![],
             cpuid_records: vec![],
             version_fd: ScopedFd::new(),
             supports_file_data_cloning_: false,
@@ -575,7 +640,12 @@ impl TraceWriter {
         for &s in Substream::iter() {
             tw.writers.insert(
                 s,
-                CompressedWriter::new(&tw.path(s), substream(s).block_size, substream(s).threads),
+                CompressedWriter::new(
+                    &tw.path(s),
+                    substream(s).block_size,
+                    substream(s).threads,
+                    codec,
+                ),
             );
         }
 
@@ -640,6 +710,32 @@ impl TraceWriter {
         tw
     }
 
+    /// Record whether the tracees run with ASLR disabled.
+    pub fn set_aslr_disabled(&mut self, aslr_disabled: bool) {
+        self.aslr_disabled_ = aslr_disabled;
+    }
+
+    /// Record that the trace starts with a snapshot of an attached process.
+    pub fn set_attached(&mut self, attached: bool) {
+        self.attached_ = attached;
+    }
+
+    pub fn attached(&self) -> bool {
+        self.attached_
+    }
+
+    /// Record that syscalls in the mapped file `fsname` are never patched.
+    pub fn add_unpatched_library(&mut self, fsname: &OsStr) {
+        if !self.unpatched_libraries.iter().any(|l| l == fsname) {
+            self.unpatched_libraries.push(fsname.to_owned());
+        }
+    }
+
+    /// Record the `--cgroup-limit`s whose cgroup files are spoofed.
+    pub fn set_cgroup_limits(&mut self, cgroup_limits: &[CgroupLimit]) {
+        self.cgroup_limits = cgroup_limits.to_vec();
+    }
+
     /// Called after the calling thread is actually bound to `bind_to_cpu`.
     pub fn setup_cpuid_records(
         &mut self,
@@ -688,7 +784,25 @@ impl TraceWriter {
             PerfCounters::default_ticks_semantics(),
         ));
         header.set_syscallbuf_protocol_version(SYSCALLBUF_PROTOCOL_VERSION);
+        header.set_syscallbuf_hdr_size(size_of::<syscallbuf_hdr>() as u32);
+        header.set_preload_thread_locals_size(PRELOAD_THREAD_LOCALS_SIZE as u32);
         header.set_preload_thread_locals_recorded(true);
+        header.set_switches_recorded(true);
+        header.set_recorded_without_pmu(pmu_disabled());
+        header.set_aslr_disabled(self.aslr_disabled_);
+        header.set_attached(self.attached_);
+        let mut unpatched = header
+            .reborrow()
+            .init_unpatched_libraries(self.unpatched_libraries.len() as u32);
+        for (i, fsname) in self.unpatched_libraries.iter().enumerate() {
+            unpatched.set(i as u32, fsname.as_bytes());
+        }
+        let mut cgroup_limits = header
+            .reborrow()
+            .init_cgroup_limits(self.cgroup_limits.len() as u32);
+        for (i, limit) in self.cgroup_limits.iter().enumerate() {
+            cgroup_limits.set(i as u32, &limit.to_string());
+        }
         // Add a random UUID to the trace metadata. This lets tools identify a trace
         // easily.
         match maybe_uuid {
@@ -723,41 +837,46 @@ impl TraceWriter {
     /// We got far enough into recording that we should set this as the latest
     /// trace.
     pub fn make_latest_trace(&self) {
-        let link_name = latest_trace_symlink();
-        // Try to update the symlink to `self`.  We only try attempt
-        // to set the symlink once.  If the link is re-created after
-        // we `unlink()` it, then another rd process is racing with us
-        // and it "won".  The link is then valid and points at some
-        // very-recent trace, so that's good enough.
-        //
-        // DIFF NOTE: rr swallows any error on unlink. We don't for now.
-        match unlink(link_name.as_os_str()) {
-            Err(Error::Sys(Errno::ENOENT)) => (),
-            Err(e) => fatal!("Unable to unlink {:?}: {:?}", link_name, e),
-            Ok(_) => (),
-        }
-
-        // Link only the trace name, not the full path, so moving a directory full
-        // of traces around doesn't break the latest-trace link.
-        let trace_name_path = Path::new(&self.trace_dir);
-        let trace_name = trace_name_path.file_name().unwrap();
-        match symlink(trace_name, &link_name) {
-            Err(e) if errno() != EEXIST => {
-                fatal!(
-                    "Failed to update symlink {:?} to {:?}: {:?}",
-                    link_name,
-                    trace_name,
-                    e
-                );
-            }
-            _ => (),
-        }
+        TraceDir::from_env().update_latest_trace(&self.trace_dir);
     }
 
     pub fn ticks_semantics(&self) -> TicksSemantics {
         self.ticks_semantics_
     }
 
+    /// Save `file_name`, which `t` has just opened, in the trace directory and
+    /// return the name of the saved file. As for mapped files, the file is
+    /// cloned or hardlinked if possible and only copied otherwise. Later
+    /// mappings of the file use the saved file too.
+    pub fn save_opened_file(
+        &mut self,
+        t: &RecordTask,
+        file_name: &OsStr,
+        stat: &libc::stat,
+    ) -> Option<OsString> {
+        if let Some(name) = self
+            .files_assumed_immutable
+            .get(&(stat.st_dev, stat.st_ino))
+        {
+            return Some(name.clone());
+        }
+
+        let file_name = try_make_process_file_name(t, file_name);
+        let mut backing_file_name = OsString::new();
+        if !self.try_clone_file(t, &file_name, &mut backing_file_name)
+            && !self.try_hardlink_file(&file_name, &mut backing_file_name)
+            && !self.copy_file(t, &file_name, &mut backing_file_name)
+        {
+            return None;
+        }
+        // The saved file is named after mmap_count; don't let the next file
+        // saved before another mapping is written collide with this one.
+        self.mmap_count += 1;
+        self.files_assumed_immutable
+            .insert((stat.st_dev, stat.st_ino), backing_file_name.clone());
+        Some(backing_file_name)
+    }
+
     fn try_hardlink_file(&self, file_name: &OsStr, new_name: &mut OsString) -> bool {
         let base_file_name = Path::new(file_name).file_name().unwrap();
         let mut path: Vec<u8> = Vec::new();
@@ -819,12 +938,25 @@ impl TraceWriter {
         true
     }
 
-    fn copy_file(&self, file_name: &OsStr, new_name: &mut OsString) -> bool {
+    fn copy_file(&self, t: &RecordTask, file_name: &OsStr, new_name: &mut OsString) -> bool {
         let base_file_name = Path::new(file_name).file_name().unwrap();
         let mut path: Vec<u8> = Vec::new();
         write!(path, "mmap_clone_{}_", self.mmap_count).unwrap();
         path.extend_from_slice(base_file_name.as_bytes());
 
+        if t.session().as_record().unwrap().share_copied_files() {
+            let mut dest_path = Vec::<u8>::new();
+            dest_path.extend_from_slice(self.dir().as_bytes());
+            dest_path.extend_from_slice(b"/");
+            dest_path.extend_from_slice(&path);
+            if object_store::link_shared_copy(&self.dir(), file_name, OsStr::from_bytes(&dest_path))
+            {
+                new_name.clear();
+                new_name.push(OsStr::from_bytes(&path));
+                return true;
+            }
+        }
+
         let src = ScopedFd::open_path(file_name, OFlag::O_RDONLY);
         if !src.is_open() {
             return false;
@@ -926,6 +1058,20 @@ fn try_make_process_file_name(t: &RecordTask, file_name: &OsStr) -> OsString {
     OsString::from_vec(process_file_name)
 }
 
+fn to_trace_switch_reason(reason: SwitchReason) -> TraceSwitchReason {
+    match reason {
+        SwitchReason::TimesliceExpired => TraceSwitchReason::TimesliceExpired,
+        SwitchReason::HigherPriority => TraceSwitchReason::HigherPriority,
+        SwitchReason::Yielded => TraceSwitchReason::Yielded,
+        SwitchReason::Blocked => TraceSwitchReason::Blocked,
+        SwitchReason::Exited => TraceSwitchReason::Exited,
+        SwitchReason::Unstable => TraceSwitchReason::Unstable,
+        SwitchReason::AlwaysSwitch => TraceSwitchReason::AlwaysSwitch,
+        SwitchReason::HighPriorityOnly => TraceSwitchReason::HighPriorityOnly,
+        SwitchReason::Execve => TraceSwitchReason::Execve,
+    }
+}
+
 fn to_trace_ticks_semantics(semantics: TicksSemantics) -> TraceTicksSemantics {
     match semantics {
         TicksSemantics::TicksRetiredConditionalBranches => {
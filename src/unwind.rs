@@ -0,0 +1,1057 @@
+//! A stack unwinder over the memory and registers of a task, for backtraces
+//! that don't depend on the gdb client's unwinder: the `rd-unwind` gdb command
+//! and rd's own reports (e.g. register divergences at replay).
+//!
+//! Unwind info is the DWARF CFI in each object's `.eh_frame`, found through
+//! the PT_GNU_EH_FRAME segment (`.eh_frame_hdr`) of the ELF headers mapped in
+//! the task. Everything is read from the task's memory, so at replay it comes
+//! from the binaries saved in the trace rather than whatever is on disk now.
+//! Where there is no CFI for a pc (or it uses DWARF expressions, which we
+//! don't evaluate) we fall back to following the frame pointer chain.
+
+use crate::{
+    gdb_expression::ExpressionTarget, gdb_register::*, kernel_abi::SupportedArch,
+    memory_search::Symbolizer, remote_ptr::RemotePtr, session::task::Task,
+};
+use goblin::elf::program_header::{PF_X, PT_GNU_EH_FRAME, PT_LOAD};
+use std::{cmp::max, fmt::Write, os::unix::ffi::OsStrExt};
+
+/// One frame of a backtrace.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StackFrame {
+    /// The pc of the innermost frame, the return address of the others.
+    pub ip: u64,
+    /// The stack pointer in this frame.
+    pub sp: u64,
+    /// True if the step into this frame used CFI, false if it followed the
+    /// frame pointer. Always true for the innermost frame.
+    pub from_cfi: bool,
+}
+
+/// The span of an ELF object's PT_LOAD segments in memory and where its
+/// `.eh_frame_hdr` is.
+#[derive(Copy, Clone, Debug)]
+pub struct UnwindModule {
+    pub start: u64,
+    pub end: u64,
+    pub eh_frame_hdr: u64,
+}
+
+/// Unwind `t`'s stack, innermost frame first.
+pub fn unwind(t: &dyn Task, max_frames: usize) -> Vec<StackFrame> {
+    unwind_with(t, t.arch(), &unwind_modules(t), max_frames)
+}
+
+/// Unwind `t`'s stack and describe where each frame is, as
+/// `Symbolizer::describe` does.
+pub fn symbolized_backtrace(t: &dyn Task, max_frames: usize) -> Vec<(StackFrame, String)> {
+    let mut symbolizer = Symbolizer::default();
+    unwind(t, max_frames)
+        .into_iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            // Describe the call instruction rather than what follows it, which
+            // may be in the next function if the call doesn't return.
+            let addr = if i == 0 { frame.ip } else { frame.ip - 1 };
+            (
+                frame,
+                symbolizer.describe(t, RemotePtr::from(addr as usize)),
+            )
+        })
+        .collect()
+}
+
+/// A backtrace of `t` with one `#<n> <ip> in <symbol>` line per frame.
+pub fn format_backtrace(t: &dyn Task, max_frames: usize) -> String {
+    let mut out = String::new();
+    for (i, (frame, location)) in symbolized_backtrace(t, max_frames).iter().enumerate() {
+        writeln!(
+            out,
+            "#{:<3} {:#x} in {}{}",
+            i,
+            frame.ip,
+            location,
+            if frame.from_cfi {
+                ""
+            } else {
+                " (frame pointer)"
+            }
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// The ELF objects mapped in `t` that have an `.eh_frame_hdr`.
+pub fn unwind_modules(t: &dyn Task) -> Vec<UnwindModule> {
+    let arch = t.arch();
+    let mut modules = Vec::new();
+    for (_, m) in &t.vm().maps() {
+        let fsname = m.map.fsname();
+        if m.map.file_offset_bytes() != 0
+            || !(fsname.as_bytes().starts_with(b"/") || fsname == "[vdso]")
+        {
+            continue;
+        }
+        if let Some(module) = read_module(t, arch, m.map.start().as_usize() as u64) {
+            modules.push(module);
+        }
+    }
+    modules
+}
+
+/// DWARF register numbers of the stack pointer, frame pointer and return
+/// address column, and how DWARF register numbers map to gdb's.
+struct ArchInfo {
+    word: usize,
+    sp: usize,
+    fp: usize,
+    ra: usize,
+    regs: &'static [GdbRegister],
+}
+
+const X64_INFO: ArchInfo = ArchInfo {
+    word: 8,
+    sp: 7,
+    fp: 6,
+    ra: 16,
+    regs: &[
+        DREG_RAX, DREG_RDX, DREG_RCX, DREG_RBX, DREG_RSI, DREG_RDI, DREG_RBP, DREG_RSP, DREG_R8,
+        DREG_R9, DREG_R10, DREG_R11, DREG_R12, DREG_R13, DREG_R14, DREG_R15, DREG_RIP,
+    ],
+};
+
+const X86_INFO: ArchInfo = ArchInfo {
+    word: 4,
+    sp: 4,
+    fp: 5,
+    ra: 8,
+    regs: &[
+        DREG_EAX, DREG_ECX, DREG_EDX, DREG_EBX, DREG_ESP, DREG_EBP, DREG_ESI, DREG_EDI, DREG_EIP,
+    ],
+};
+
+const NUM_REGS: usize = 17;
+
+type RegisterValues = [Option<u64>; NUM_REGS];
+
+fn arch_info(arch: SupportedArch) -> &'static ArchInfo {
+    match arch {
+        SupportedArch::X64 => &X64_INFO,
+        SupportedArch::X86 => &X86_INFO,
+    }
+}
+
+/// Unwind from the registers of `target`. `modules` says where to find CFI.
+pub fn unwind_with<T: ExpressionTarget + ?Sized>(
+    target: &T,
+    arch: SupportedArch,
+    modules: &[UnwindModule],
+    max_frames: usize,
+) -> Vec<StackFrame> {
+    let info = arch_info(arch);
+    let mut regs: RegisterValues = [None; NUM_REGS];
+    for (i, &r) in info.regs.iter().enumerate() {
+        regs[i] = target.read_register(r);
+    }
+
+    let mut frames = Vec::new();
+    let mut from_cfi = true;
+    let mut signal_frame = false;
+    while frames.len() < max_frames {
+        let (ip, sp) = match (regs[info.ra], regs[info.sp]) {
+            (Some(ip), Some(sp)) if ip != 0 => (ip, sp),
+            _ => break,
+        };
+        frames.push(StackFrame { ip, sp, from_cfi });
+
+        // A return address is just after the call, which may be the first
+        // instruction of the next function. Signal frames start at the
+        // interrupted instruction itself.
+        let lookup_pc = if frames.len() == 1 || signal_frame {
+            ip
+        } else {
+            ip - 1
+        };
+        let next = match step_cfi(target, info, modules, lookup_pc, &regs) {
+            Some((next, is_signal_frame)) => {
+                from_cfi = true;
+                signal_frame = is_signal_frame;
+                next
+            }
+            None => match step_frame_pointer(target, info, &regs) {
+                Some(next) => {
+                    from_cfi = false;
+                    signal_frame = false;
+                    next
+                }
+                None => break,
+            },
+        };
+        // The stack grows down, so outer frames must be higher up. This also
+        // stops us from looping forever on corrupt stacks.
+        match next[info.sp] {
+            Some(next_sp) if next_sp > sp || (next_sp == sp && next[info.ra] != Some(ip)) => (),
+            _ => break,
+        }
+        regs = next;
+    }
+    frames
+}
+
+fn read_word<T: ExpressionTarget + ?Sized>(target: &T, word: usize, addr: u64) -> Option<u64> {
+    let mut buf = [0u8; 8];
+    if !target.read_memory(addr as usize, &mut buf[0..word]) {
+        return None;
+    }
+    Some(u64::from_le_bytes(buf))
+}
+
+fn step_frame_pointer<T: ExpressionTarget + ?Sized>(
+    target: &T,
+    info: &ArchInfo,
+    regs: &RegisterValues,
+) -> Option<RegisterValues> {
+    let fp = regs[info.fp].filter(|&fp| fp != 0)?;
+    let w = info.word as u64;
+    let mut next: RegisterValues = [None; NUM_REGS];
+    next[info.fp] = Some(read_word(target, info.word, fp)?);
+    next[info.ra] = Some(read_word(target, info.word, fp + w)?);
+    next[info.sp] = Some(fp + 2 * w);
+    Some(next)
+}
+
+fn step_cfi<T: ExpressionTarget + ?Sized>(
+    target: &T,
+    info: &ArchInfo,
+    modules: &[UnwindModule],
+    pc: u64,
+    regs: &RegisterValues,
+) -> Option<(RegisterValues, bool)> {
+    let module = modules.iter().find(|m| m.start <= pc && pc < m.end)?;
+    let fde_addr = find_fde(target, info, module.eh_frame_hdr, pc)?;
+    let fde = Fde::read(target, info, fde_addr)?;
+    if pc < fde.pc_begin || pc >= fde.pc_begin + fde.pc_range {
+        return None;
+    }
+    let row = fde.row_for(pc)?;
+
+    let cfa = match row.cfa {
+        CfaRule::RegOffset(r, offset) => (regs.get(r as usize).copied()?? as i64 + offset) as u64,
+        CfaRule::Expression => return None,
+    };
+    let mut next = *regs;
+    for (r, rule) in row.regs.iter().enumerate() {
+        next[r] = match *rule {
+            RegRule::Unspecified | RegRule::SameValue => regs[r],
+            RegRule::Undefined => None,
+            RegRule::Offset(n) => read_word(target, info.word, (cfa as i64 + n) as u64),
+            RegRule::ValOffset(n) => Some((cfa as i64 + n) as u64),
+            RegRule::Register(r2) => regs.get(r2 as usize).copied().flatten(),
+            RegRule::Expression => return None,
+        };
+    }
+    if row.regs[info.ra] == RegRule::Unspecified {
+        return None;
+    }
+    next[info.sp] = Some(cfa);
+    Some((next, fde.cie.signal_frame))
+}
+
+fn read_module<T: ExpressionTarget + ?Sized>(
+    target: &T,
+    arch: SupportedArch,
+    base: u64,
+) -> Option<UnwindModule> {
+    let is_64 = arch == SupportedArch::X64;
+    let mut ehdr = [0u8; 64];
+    if !target.read_memory(base as usize, &mut ehdr[0..if is_64 { 64 } else { 52 }]) {
+        return None;
+    }
+    if &ehdr[0..4] != b"\x7fELF" || ehdr[4] != if is_64 { 2 } else { 1 } {
+        return None;
+    }
+    let u16_at = |b: &[u8], o: usize| u16::from_le_bytes([b[o], b[o + 1]]) as u64;
+    let u32_at = |b: &[u8], o: usize| u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]);
+    let u64_at = |b: &[u8], o: usize| {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(&b[o..o + 8]);
+        u64::from_le_bytes(v)
+    };
+    let (phoff, phentsize, phnum) = if is_64 {
+        (u64_at(&ehdr, 32), u16_at(&ehdr, 54), u16_at(&ehdr, 56))
+    } else {
+        (
+            u32_at(&ehdr, 28) as u64,
+            u16_at(&ehdr, 42),
+            u16_at(&ehdr, 44),
+        )
+    };
+    if phentsize < if is_64 { 56 } else { 32 } || phnum == 0 || phnum > 256 {
+        return None;
+    }
+    let mut phdrs = vec![0u8; (phentsize * phnum) as usize];
+    if !target.read_memory((base + phoff) as usize, &mut phdrs) {
+        return None;
+    }
+
+    // (type, flags, offset, vaddr, memsz)
+    let segments: Vec<(u32, u32, u64, u64, u64)> = phdrs
+        .chunks(phentsize as usize)
+        .map(|ph| {
+            if is_64 {
+                (
+                    u32_at(ph, 0),
+                    u32_at(ph, 4),
+                    u64_at(ph, 8),
+                    u64_at(ph, 16),
+                    u64_at(ph, 40),
+                )
+            } else {
+                (
+                    u32_at(ph, 0),
+                    u32_at(ph, 24),
+                    u32_at(ph, 4) as u64,
+                    u32_at(ph, 8) as u64,
+                    u32_at(ph, 20) as u64,
+                )
+            }
+        })
+        .collect();
+
+    // The mapping at file offset 0 is the segment containing the ELF header.
+    let first_load = segments
+        .iter()
+        .find(|s| s.0 == PT_LOAD && s.2 == 0)
+        .or_else(|| segments.iter().find(|s| s.0 == PT_LOAD))?;
+    let bias = base.wrapping_sub(first_load.3 & !0xfff);
+    let eh_frame_hdr = segments.iter().find(|s| s.0 == PT_GNU_EH_FRAME)?.3;
+    let (start, end) = segments
+        .iter()
+        .filter(|s| s.0 == PT_LOAD && s.1 & PF_X != 0)
+        .fold((u64::MAX, 0), |(start, end), s| {
+            (start.min(s.3), max(end, s.3 + s.4))
+        });
+    if start >= end {
+        return None;
+    }
+    Some(UnwindModule {
+        start: bias.wrapping_add(start),
+        end: bias.wrapping_add(end),
+        eh_frame_hdr: bias.wrapping_add(eh_frame_hdr),
+    })
+}
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_DATAREL_SDATA4: u8 = 0x3b;
+
+/// Find the address of the FDE covering `pc` using the binary search table
+/// in `.eh_frame_hdr`, or a linear scan of `.eh_frame` if there is none.
+fn find_fde<T: ExpressionTarget + ?Sized>(
+    target: &T,
+    info: &ArchInfo,
+    hdr: u64,
+    pc: u64,
+) -> Option<u64> {
+    let mut buf = [0u8; 20];
+    if !target.read_memory(hdr as usize, &mut buf) {
+        return None;
+    }
+    if buf[0] != 1 {
+        return None;
+    }
+    let (eh_frame_ptr_enc, fde_count_enc, table_enc) = (buf[1], buf[2], buf[3]);
+    let mut cursor = Cursor::new(&buf[4..], hdr + 4, info.word);
+    let eh_frame = cursor.encoded(eh_frame_ptr_enc, hdr)?;
+    let fde_count = if fde_count_enc == DW_EH_PE_OMIT {
+        0
+    } else {
+        cursor.encoded(fde_count_enc, hdr)?
+    };
+    let table = cursor.addr();
+
+    if table_enc != DW_EH_PE_DATAREL_SDATA4 || fde_count == 0 {
+        return scan_eh_frame(target, info, eh_frame, pc);
+    }
+    // Entries are (initial location, FDE address), sorted by location.
+    let entry = |i: u64| -> Option<(u64, u64)> {
+        let mut e = [0u8; 8];
+        if !target.read_memory((table + i * 8) as usize, &mut e) {
+            return None;
+        }
+        let loc = i32::from_le_bytes([e[0], e[1], e[2], e[3]]) as i64;
+        let fde = i32::from_le_bytes([e[4], e[5], e[6], e[7]]) as i64;
+        Some((hdr.wrapping_add(loc as u64), hdr.wrapping_add(fde as u64)))
+    };
+    let (mut lo, mut hi) = (0, fde_count);
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if entry(mid)?.0 <= pc {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let (loc, fde) = entry(lo)?;
+    if loc > pc {
+        return None;
+    }
+    Some(fde)
+}
+
+fn scan_eh_frame<T: ExpressionTarget + ?Sized>(
+    target: &T,
+    info: &ArchInfo,
+    eh_frame: u64,
+    pc: u64,
+) -> Option<u64> {
+    let mut addr = eh_frame;
+    // Don't wander through memory forever if there's no terminator.
+    for _ in 0..100_000 {
+        let (length, header) = entry_length(target, addr)?;
+        if length == 0 {
+            return None;
+        }
+        let mut id = [0u8; 4];
+        if !target.read_memory((addr + header) as usize, &mut id) {
+            return None;
+        }
+        if u32::from_le_bytes(id) != 0 {
+            if let Some(fde) = Fde::read(target, info, addr) {
+                if fde.pc_begin <= pc && pc < fde.pc_begin + fde.pc_range {
+                    return Some(addr);
+                }
+            }
+        }
+        addr += header + length;
+    }
+    None
+}
+
+/// The length of the CIE or FDE at `addr` and the size of the length field.
+fn entry_length<T: ExpressionTarget + ?Sized>(target: &T, addr: u64) -> Option<(u64, u64)> {
+    let mut buf = [0u8; 12];
+    if !target.read_memory(addr as usize, &mut buf[0..4]) {
+        return None;
+    }
+    match u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) {
+        0xffff_ffff => {
+            if !target.read_memory(addr as usize, &mut buf) {
+                return None;
+            }
+            let mut v = [0u8; 8];
+            v.copy_from_slice(&buf[4..12]);
+            Some((u64::from_le_bytes(v), 12))
+        }
+        length => Some((length as u64, 4)),
+    }
+}
+
+/// Read the CIE or FDE at `addr`, returning its contents after the length
+/// field and the address those start at.
+fn read_entry<T: ExpressionTarget + ?Sized>(target: &T, addr: u64) -> Option<(Vec<u8>, u64)> {
+    let (length, header) = entry_length(target, addr)?;
+    // Real entries are small. Don't allocate gigabytes for garbage.
+    if length < 4 || length > 1 << 20 {
+        return None;
+    }
+    let mut data = vec![0u8; length as usize];
+    if !target.read_memory((addr + header) as usize, &mut data) {
+        return None;
+    }
+    Some((data, addr + header))
+}
+
+/// Reads DWARF data from a buffer that was at `addr` in the task.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    base: u64,
+    word: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8], base: u64, word: usize) -> Cursor<'a> {
+        Cursor {
+            data,
+            pos: 0,
+            base,
+            word,
+        }
+    }
+
+    fn addr(&self) -> u64 {
+        self.base + self.pos as u64
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let b = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(b)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn unsigned(&mut self, n: usize) -> Option<u64> {
+        let mut v = [0u8; 8];
+        v[0..n].copy_from_slice(self.bytes(n)?);
+        Some(u64::from_le_bytes(v))
+    }
+
+    fn signed(&mut self, n: usize) -> Option<i64> {
+        let shift = 64 - 8 * n as u32;
+        Some(((self.unsigned(n)? << shift) as i64) >> shift)
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                result |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                result |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    result |= -1i64 << shift;
+                }
+                return Some(result);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'a [u8]> {
+        let len = self.data[self.pos..].iter().position(|&b| b == 0)?;
+        let s = self.bytes(len)?;
+        self.pos += 1;
+        Some(s)
+    }
+
+    /// Read a pointer in DW_EH_PE encoding `enc`. `datarel` is the base for
+    /// DW_EH_PE_datarel, i.e. the start of `.eh_frame_hdr`. Indirect
+    /// pointers aren't followed; we only need them to skip personality
+    /// routine pointers.
+    fn encoded(&mut self, enc: u8, datarel: u64) -> Option<u64> {
+        if enc == DW_EH_PE_OMIT {
+            return Some(0);
+        }
+        let field_addr = self.addr();
+        let value = match enc & 0x0f {
+            0x00 => self.unsigned(self.word)?,
+            0x01 => self.uleb128()?,
+            0x02 => self.unsigned(2)?,
+            0x03 => self.unsigned(4)?,
+            0x04 => self.unsigned(8)?,
+            0x09 => self.sleb128()? as u64,
+            0x0a => self.signed(2)? as u64,
+            0x0b => self.signed(4)? as u64,
+            0x0c => self.signed(8)? as u64,
+            _ => return None,
+        };
+        let value = match enc & 0x70 {
+            0x00 => value,
+            0x10 => field_addr.wrapping_add(value),
+            0x30 => datarel.wrapping_add(value),
+            _ => return None,
+        };
+        Some(if self.word == 4 {
+            value & 0xffff_ffff
+        } else {
+            value
+        })
+    }
+}
+
+struct Cie {
+    word: usize,
+    code_alignment: u64,
+    data_alignment: i64,
+    ra: usize,
+    fde_encoding: u8,
+    has_augmentation_data: bool,
+    signal_frame: bool,
+    instructions: Vec<u8>,
+    instructions_addr: u64,
+}
+
+impl Cie {
+    fn read<T: ExpressionTarget + ?Sized>(target: &T, info: &ArchInfo, addr: u64) -> Option<Cie> {
+        let (data, base) = read_entry(target, addr)?;
+        let mut c = Cursor::new(&data, base, info.word);
+        if c.unsigned(4)? != 0 {
+            return None;
+        }
+        let version = c.u8()?;
+        let augmentation = c.cstr()?;
+        if augmentation.starts_with(b"eh") {
+            c.bytes(info.word)?;
+        }
+        let code_alignment = c.uleb128()?;
+        let data_alignment = c.sleb128()?;
+        let ra = if version == 1 {
+            c.u8()? as usize
+        } else {
+            c.uleb128()? as usize
+        };
+        let mut cie = Cie {
+            word: info.word,
+            code_alignment,
+            data_alignment,
+            ra,
+            fde_encoding: 0,
+            has_augmentation_data: augmentation.first() == Some(&b'z'),
+            signal_frame: false,
+            instructions: Vec::new(),
+            instructions_addr: 0,
+        };
+        if cie.has_augmentation_data {
+            let length = c.uleb128()? as usize;
+            let end = c.pos + length;
+            for &a in &augmentation[1..] {
+                match a {
+                    b'R' => cie.fde_encoding = c.u8()?,
+                    b'L' => {
+                        c.u8()?;
+                    }
+                    b'P' => {
+                        let enc = c.u8()?;
+                        c.encoded(enc & 0x7f, 0)?;
+                    }
+                    b'S' => cie.signal_frame = true,
+                    _ => break,
+                }
+            }
+            c.pos = end;
+        }
+        if ra >= NUM_REGS || c.pos > data.len() {
+            return None;
+        }
+        cie.instructions_addr = c.addr();
+        cie.instructions = data[c.pos..].to_vec();
+        Some(cie)
+    }
+}
+
+struct Fde {
+    cie: Cie,
+    pc_begin: u64,
+    pc_range: u64,
+    instructions: Vec<u8>,
+    instructions_addr: u64,
+}
+
+impl Fde {
+    fn read<T: ExpressionTarget + ?Sized>(target: &T, info: &ArchInfo, addr: u64) -> Option<Fde> {
+        let (data, base) = read_entry(target, addr)?;
+        let mut c = Cursor::new(&data, base, info.word);
+        let cie_pointer = c.unsigned(4)?;
+        if cie_pointer == 0 {
+            // This is a CIE.
+            return None;
+        }
+        let cie = Cie::read(target, info, base.wrapping_sub(cie_pointer))?;
+        let pc_begin = c.encoded(cie.fde_encoding, 0)?;
+        let pc_range = c.encoded(cie.fde_encoding & 0x0f, 0)?;
+        if cie.has_augmentation_data {
+            let length = c.uleb128()? as usize;
+            c.bytes(length)?;
+        }
+        let instructions_addr = c.addr();
+        Some(Fde {
+            cie,
+            pc_begin,
+            pc_range,
+            instructions: data[c.pos..].to_vec(),
+            instructions_addr,
+        })
+    }
+
+    /// Run the CFA program up to `pc`.
+    fn row_for(&self, pc: u64) -> Option<Row> {
+        let mut row = Row::default();
+        run_cfa_program(
+            &self.cie,
+            &self.cie.instructions,
+            self.cie.instructions_addr,
+            u64::MAX,
+            0,
+            &mut row,
+            None,
+        )?;
+        let initial = row.clone();
+        run_cfa_program(
+            &self.cie,
+            &self.instructions,
+            self.instructions_addr,
+            pc,
+            self.pc_begin,
+            &mut row,
+            Some(&initial),
+        )?;
+        Some(row)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CfaRule {
+    RegOffset(u64, i64),
+    Expression,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum RegRule {
+    Unspecified,
+    Undefined,
+    SameValue,
+    Offset(i64),
+    ValOffset(i64),
+    Register(u64),
+    Expression,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Row {
+    cfa: CfaRule,
+    regs: [RegRule; NUM_REGS],
+}
+
+impl Default for Row {
+    fn default() -> Self {
+        Row {
+            cfa: CfaRule::Expression,
+            regs: [RegRule::Unspecified; NUM_REGS],
+        }
+    }
+}
+
+fn set_rule(row: &mut Row, reg: u64, rule: RegRule) {
+    // Registers we don't track (e.g. vector registers) don't matter for
+    // finding the caller.
+    if let Some(r) = row.regs.get_mut(reg as usize) {
+        *r = rule;
+    }
+}
+
+/// Execute CFA instructions until the location advances past `pc`.
+/// `initial` is the row after the CIE's instructions, for DW_CFA_restore.
+fn run_cfa_program(
+    cie: &Cie,
+    instructions: &[u8],
+    addr: u64,
+    pc: u64,
+    mut loc: u64,
+    row: &mut Row,
+    initial: Option<&Row>,
+) -> Option<()> {
+    let mut c = Cursor::new(instructions, addr, cie.word);
+    let mut stack: Vec<Row> = Vec::new();
+    let code = cie.code_alignment;
+    let data = cie.data_alignment;
+    let restore = |row: &mut Row, reg: u64| {
+        let rule = initial.map_or(RegRule::Unspecified, |i| {
+            i.regs
+                .get(reg as usize)
+                .copied()
+                .unwrap_or(RegRule::Unspecified)
+        });
+        set_rule(row, reg, rule);
+    };
+    while !c.at_end() {
+        let op = c.u8()?;
+        let advance = match op >> 6 {
+            1 => Some((op & 0x3f) as u64 * code),
+            2 => {
+                let offset = c.uleb128()? as i64 * data;
+                set_rule(row, (op & 0x3f) as u64, RegRule::Offset(offset));
+                None
+            }
+            3 => {
+                restore(row, (op & 0x3f) as u64);
+                None
+            }
+            _ => match op {
+                0x00 => None,
+                0x01 => {
+                    loc = c.encoded(cie.fde_encoding, 0)?;
+                    if loc > pc {
+                        return Some(());
+                    }
+                    None
+                }
+                0x02 => Some(c.unsigned(1)? * code),
+                0x03 => Some(c.unsigned(2)? * code),
+                0x04 => Some(c.unsigned(4)? * code),
+                0x05 => {
+                    let reg = c.uleb128()?;
+                    let offset = c.uleb128()? as i64 * data;
+                    set_rule(row, reg, RegRule::Offset(offset));
+                    None
+                }
+                0x06 => {
+                    let reg = c.uleb128()?;
+                    restore(row, reg);
+                    None
+                }
+                0x07 => {
+                    let reg = c.uleb128()?;
+                    set_rule(row, reg, RegRule::Undefined);
+                    None
+                }
+                0x08 => {
+                    let reg = c.uleb128()?;
+                    set_rule(row, reg, RegRule::SameValue);
+                    None
+                }
+                0x09 => {
+                    let reg = c.uleb128()?;
+                    let reg2 = c.uleb128()?;
+                    set_rule(row, reg, RegRule::Register(reg2));
+                    None
+                }
+                0x0a => {
+                    stack.push(row.clone());
+                    None
+                }
+                0x0b => {
+                    *row = stack.pop()?;
+                    None
+                }
+                0x0c => {
+                    let reg = c.uleb128()?;
+                    let offset = c.uleb128()? as i64;
+                    row.cfa = CfaRule::RegOffset(reg, offset);
+                    None
+                }
+                0x0d => {
+                    let reg = c.uleb128()?;
+                    match row.cfa {
+                        CfaRule::RegOffset(_, offset) => row.cfa = CfaRule::RegOffset(reg, offset),
+                        CfaRule::Expression => row.cfa = CfaRule::RegOffset(reg, 0),
+                    }
+                    None
+                }
+                0x0e => {
+                    let offset = c.uleb128()? as i64;
+                    if let CfaRule::RegOffset(reg, _) = row.cfa {
+                        row.cfa = CfaRule::RegOffset(reg, offset);
+                    }
+                    None
+                }
+                0x0f => {
+                    let length = c.uleb128()? as usize;
+                    c.bytes(length)?;
+                    row.cfa = CfaRule::Expression;
+                    None
+                }
+                0x10 | 0x16 => {
+                    let reg = c.uleb128()?;
+                    let length = c.uleb128()? as usize;
+                    c.bytes(length)?;
+                    set_rule(row, reg, RegRule::Expression);
+                    None
+                }
+                0x11 => {
+                    let reg = c.uleb128()?;
+                    let offset = c.sleb128()? * data;
+                    set_rule(row, reg, RegRule::Offset(offset));
+                    None
+                }
+                0x12 => {
+                    let reg = c.uleb128()?;
+                    let offset = c.sleb128()? * data;
+                    row.cfa = CfaRule::RegOffset(reg, offset);
+                    None
+                }
+                0x13 => {
+                    let offset = c.sleb128()? * data;
+                    if let CfaRule::RegOffset(reg, _) = row.cfa {
+                        row.cfa = CfaRule::RegOffset(reg, offset);
+                    }
+                    None
+                }
+                0x14 => {
+                    let reg = c.uleb128()?;
+                    let offset = c.uleb128()? as i64 * data;
+                    set_rule(row, reg, RegRule::ValOffset(offset));
+                    None
+                }
+                0x15 => {
+                    let reg = c.uleb128()?;
+                    let offset = c.sleb128()? * data;
+                    set_rule(row, reg, RegRule::ValOffset(offset));
+                    None
+                }
+                // DW_CFA_GNU_args_size
+                0x2e => {
+                    c.uleb128()?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                0x2f => {
+                    let reg = c.uleb128()?;
+                    let offset = -(c.uleb128()? as i64) * data;
+                    set_rule(row, reg, RegRule::Offset(offset));
+                    None
+                }
+                _ => return None,
+            },
+        };
+        if let Some(delta) = advance {
+            loc = loc.wrapping_add(delta);
+            if loc > pc {
+                return Some(());
+            }
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: u64 = 0x1000;
+    const EH_FRAME_HDR: u64 = 0x2000;
+    const STACK: u64 = 0x7000;
+
+    struct FakeTarget {
+        memory: Vec<u8>,
+        regs: Vec<(GdbRegister, u64)>,
+    }
+
+    impl ExpressionTarget for FakeTarget {
+        fn read_memory(&self, addr: usize, buf: &mut [u8]) -> bool {
+            match self.memory.get(addr..addr + buf.len()) {
+                Some(m) => {
+                    buf.copy_from_slice(m);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn read_register(&self, r: GdbRegister) -> Option<u64> {
+            self.regs.iter().find(|(reg, _)| *reg == r).map(|(_, v)| *v)
+        }
+    }
+
+    fn put(memory: &mut [u8], addr: u64, bytes: &[u8]) {
+        memory[addr as usize..addr as usize + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// A function at TEXT..TEXT+0x20 with the usual `push %rbp; mov
+    /// %rsp,%rbp` prologue, described the way gcc does, and an
+    /// `.eh_frame_hdr` pointing at it.
+    fn memory_with_cfi() -> Vec<u8> {
+        let mut memory = vec![0u8; 0x8000];
+        let eh_frame = EH_FRAME_HDR + 0x100;
+        let cie: &[u8] = &[
+            0x14, 0, 0, 0, // length
+            0, 0, 0, 0, // CIE id
+            1, b'z', b'R', 0, // version, augmentation
+            1, 0x78, 16, // code align 1, data align -8, ra column 16
+            1, 0x1b, // augmentation data: pcrel|sdata4 FDE pointers
+            0x0c, 7, 8, // def_cfa rsp+8
+            0x90, 1, // offset r16 at cfa-8
+            0, 0, // padding
+        ];
+        put(&mut memory, eh_frame, cie);
+        let fde = eh_frame + cie.len() as u64;
+        let pc_begin = (TEXT as i64 - (fde + 8) as i64) as i32;
+        let mut fde_bytes = vec![
+            0x1c, 0, 0, 0, // length
+        ];
+        fde_bytes.extend_from_slice(&((fde + 4 - eh_frame) as u32).to_le_bytes());
+        fde_bytes.extend_from_slice(&pc_begin.to_le_bytes());
+        fde_bytes.extend_from_slice(&0x20u32.to_le_bytes());
+        fde_bytes.extend_from_slice(&[
+            0,    // augmentation data length
+            0x41, // advance_loc 1 (after push %rbp)
+            0x0e, 16, // def_cfa_offset 16
+            0x86, 2,    // offset rbp at cfa-16
+            0x43, // advance_loc 3 (after mov %rsp,%rbp)
+            0x0d, 6, // def_cfa_register rbp
+            0, 0, 0, 0, 0, 0, 0, // padding
+        ]);
+        put(&mut memory, fde, &fde_bytes);
+
+        let mut hdr = vec![1, 0x1b, 0x03, 0x3b];
+        hdr.extend_from_slice(&((eh_frame - (EH_FRAME_HDR + 4)) as u32).to_le_bytes());
+        hdr.extend_from_slice(&1u32.to_le_bytes());
+        hdr.extend_from_slice(&((TEXT - EH_FRAME_HDR) as u32).to_le_bytes());
+        hdr.extend_from_slice(&((fde - EH_FRAME_HDR) as u32).to_le_bytes());
+        put(&mut memory, EH_FRAME_HDR, &hdr);
+        memory
+    }
+
+    const MODULES: &[UnwindModule] = &[UnwindModule {
+        start: TEXT,
+        end: TEXT + 0x20,
+        eh_frame_hdr: EH_FRAME_HDR,
+    }];
+
+    #[test]
+    fn unwind_through_cfi() {
+        let mut memory = memory_with_cfi();
+        // In the body of the function: rbp points at the saved rbp, with the
+        // return address above it. The caller has no CFI and a frame pointer
+        // chain ending in a null rbp.
+        put(&mut memory, STACK, &(STACK + 0x40).to_le_bytes());
+        put(&mut memory, STACK + 8, &0x5555u64.to_le_bytes());
+        put(&mut memory, STACK + 0x40, &0u64.to_le_bytes());
+        put(&mut memory, STACK + 0x48, &0x6666u64.to_le_bytes());
+        let target = FakeTarget {
+            memory,
+            regs: vec![
+                (DREG_RIP, TEXT + 0x10),
+                (DREG_RSP, STACK - 0x20),
+                (DREG_RBP, STACK),
+            ],
+        };
+        let frames = unwind_with(&target, SupportedArch::X64, MODULES, 10);
+        let ips: Vec<(u64, bool)> = frames.iter().map(|f| (f.ip, f.from_cfi)).collect();
+        assert_eq!(
+            ips,
+            vec![(TEXT + 0x10, true), (0x5555, true), (0x6666, false)]
+        );
+        assert_eq!(frames[1].sp, STACK + 16);
+    }
+
+    #[test]
+    fn unwind_in_prologue() {
+        let mut memory = memory_with_cfi();
+        // At the first instruction the return address is at rsp and rbp
+        // still belongs to the caller.
+        put(&mut memory, STACK, &0x5555u64.to_le_bytes());
+        let target = FakeTarget {
+            memory,
+            regs: vec![(DREG_RIP, TEXT), (DREG_RSP, STACK), (DREG_RBP, 0)],
+        };
+        let frames = unwind_with(&target, SupportedArch::X64, MODULES, 10);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].ip, 0x5555);
+        assert_eq!(frames[1].sp, STACK + 8);
+    }
+}
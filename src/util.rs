@@ -6,7 +6,7 @@ use crate::{
     },
     event::{Event, EventType, SignalDeterministic, SyscallState},
     flags::{Checksum, DumpOn, Flags},
-    kernel_abi::{native_arch, CloneParameterOrdering, SupportedArch},
+    kernel_abi::{common::clone_args, native_arch, CloneParameterOrdering, SupportedArch},
     kernel_supplement::sig_set_t,
     log::LogLevel::{LogDebug, LogError, LogWarn},
     preload_interface::{preload_globals, syscallbuf_hdr, syscallbuf_record},
@@ -35,8 +35,8 @@ use crate::{
 };
 use libc::{
     pid_t, pwrite64, siginfo_t, ucontext_t, CLONE_CHILD_CLEARTID, CLONE_CHILD_SETTID, CLONE_FILES,
-    CLONE_PARENT_SETTID, CLONE_SETTLS, CLONE_SIGHAND, CLONE_THREAD, CLONE_VM, EEXIST, EINVAL, EIO,
-    ENOENT, PATH_MAX, SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGTRAP, STDERR_FILENO,
+    CLONE_PARENT_SETTID, CLONE_SETTLS, CLONE_SIGHAND, CLONE_THREAD, CLONE_VFORK, CLONE_VM, EEXIST,
+    EINVAL, EIO, ENOENT, PATH_MAX, SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGTRAP, STDERR_FILENO,
     _SC_NPROCESSORS_ONLN,
 };
 use nix::{
@@ -596,6 +596,7 @@ pub fn ensure_dir(dir: &OsStr, dir_type: &str, mode: Mode) {
 /// Like pwrite64(2) but we try to write all bytes by looping on short writes.
 ///
 /// Slightly different from rr. Employs Result.
+#[allow(clippy::result_unit_err)]
 pub fn pwrite_all_fallible(fd: i32, buf_initial: &[u8], mut offset: isize) -> Result<usize, ()> {
     let mut written: usize = 0;
     let mut cur_size = buf_initial.len();
@@ -666,6 +667,25 @@ pub fn monotonic_now_sec() -> f64 {
     tp.tv_sec as f64 + (tp.tv_nsec as f64 / 1e9)
 }
 
+/// Get the current wall-clock time in units of seconds since the Unix epoch.
+pub fn realtime_now_sec() -> f64 {
+    let mut tp: libc::timespec = unsafe { zeroed() };
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut tp) };
+    assert_eq!(ret, 0);
+    tp.tv_sec as f64 + (tp.tv_nsec as f64 / 1e9)
+}
+
+/// Is `file_name` compiled locale data or a message catalog that glibc maps on
+/// setlocale()/gettext()? Opens of these are saved in the trace and served by a
+/// LocaleFileMonitor. Keep in sync with is_locale_file in preload_interface.h.
+pub fn is_locale_file(file_name: &OsStr) -> bool {
+    let name = file_name.as_bytes();
+    name.starts_with(b"/usr/lib/locale/")
+        || name.starts_with(b"/usr/lib64/locale/")
+        || name.starts_with(b"/usr/share/locale/")
+        || name.starts_with(b"/usr/share/locale-langpack/")
+}
+
 pub fn should_copy_mmap_region(mapping: &KernelMapping, stat: &libc::stat) -> bool {
     let v = env::var_os("RD_COPY_ALL_FILES");
     if v.is_some() {
@@ -1107,25 +1127,76 @@ fn extract_clone_parameters_arch<Arch: Architecture>(regs: &Registers) -> CloneP
         result.tls = RemotePtr::from(regs.arg5());
         result.ctid = RemotePtr::from(regs.arg4());
     }
-    let flags: i32 = regs.arg1() as i32;
-    // If these flags aren't set, the corresponding clone parameters may be
-    // invalid pointers, so make sure they're ignored.
+    ignore_unused_clone_parameters(regs.arg1() as i32, &mut result);
+    result
+}
+
+/// If these flags aren't set, the corresponding clone parameters may be
+/// invalid pointers, so make sure they're ignored.
+fn ignore_unused_clone_parameters(flags: i32, params: &mut CloneParameters) {
     if flags & CLONE_PARENT_SETTID == 0 {
-        result.ptid = RemotePtr::null();
+        params.ptid = RemotePtr::null();
     }
     if flags & (CLONE_CHILD_SETTID | CLONE_CHILD_CLEARTID) == 0 {
-        result.ctid = RemotePtr::null();
+        params.ctid = RemotePtr::null();
     }
     if flags & CLONE_SETTLS == 0 {
-        result.tls = RemotePtr::null();
+        params.tls = RemotePtr::null();
     }
-    result
 }
 
 pub fn extract_clone_parameters(t: &dyn Task) -> CloneParameters {
     rd_arch_function_selfless!(extract_clone_parameters_arch, t.arch(), &t.regs_ref())
 }
 
+/// clone3() flags that don't fit in clone()'s.
+pub const CLONE_CLEAR_SIGHAND: u64 = 0x1_0000_0000;
+pub const CLONE_INTO_CGROUP: u64 = 0x2_0000_0000;
+/// Not in our libc yet.
+pub const CLONE_PIDFD: i32 = 0x1000;
+
+/// The size of the first version of `struct clone_args`, the smallest
+/// clone3() accepts.
+const CLONE_ARGS_SIZE_VER0: usize = 64;
+
+/// Read the `struct clone_args` the clone3() syscall `t` is entering passes.
+/// Fields past the size it passes read as zero. Returns None if the kernel
+/// will fail the call because the size is too small or the struct can't be
+/// read.
+pub fn read_clone_args(t: &dyn Task) -> Option<clone_args> {
+    let regs = t.regs_ref().clone();
+    if regs.arg2() < CLONE_ARGS_SIZE_VER0 {
+        return None;
+    }
+    let mut args: clone_args = Default::default();
+    let len = min(regs.arg2(), size_of::<clone_args>());
+    let bytes = unsafe { slice::from_raw_parts_mut(&raw mut args as *mut u8, len) };
+    let mut ok = true;
+    t.read_bytes_helper(RemotePtr::from(regs.arg1()), bytes, Some(&mut ok));
+    if ok {
+        Some(args)
+    } else {
+        None
+    }
+}
+
+/// Like `extract_clone_parameters()`, for a clone3() call passing `args`.
+pub fn extract_clone3_parameters(args: &clone_args) -> CloneParameters {
+    let mut result = CloneParameters {
+        // The kernel takes the lowest address of the stack and its size.
+        stack: if args.stack == 0 {
+            RemotePtr::null()
+        } else {
+            RemotePtr::from((args.stack + args.stack_size) as usize)
+        },
+        ptid: RemotePtr::from(args.parent_tid as usize),
+        tls: RemotePtr::from(args.tls as usize),
+        ctid: RemotePtr::from(args.child_tid as usize),
+    };
+    ignore_unused_clone_parameters(args.flags as i32, &mut result);
+    result
+}
+
 /// Convert the flags passed to the clone() syscall, `flags_arg`, into
 /// the format understood by `clone_task_common()`.
 pub fn clone_flags_to_task_flags(flags_arg: i32) -> CloneFlags {
@@ -1149,6 +1220,9 @@ pub fn clone_flags_to_task_flags(flags_arg: i32) -> CloneFlags {
     if CLONE_FILES & flags_arg == CLONE_FILES {
         flags |= CloneFlags::CLONE_SHARE_FILES
     }
+    if CLONE_VFORK & flags_arg == CLONE_VFORK {
+        flags |= CloneFlags::CLONE_VFORK
+    }
     flags
 }
 
@@ -1457,6 +1531,37 @@ pub fn read_proc_status_fields(tid: pid_t, matches_for: &[&[u8]]) -> io::Result<
     Ok(result)
 }
 
+/// Bytes of memory mapped only by process `tid` (i.e. not shared with any other
+/// process, including pages a fork()ed child has not yet written to), as
+/// reported by `/proc/<tid>/smaps_rollup`.
+pub fn read_proc_private_memory(tid: pid_t) -> io::Result<usize> {
+    let mut contents = Vec::new();
+    File::open(format!("/proc/{}/smaps_rollup", tid))?.read_to_end(&mut contents)?;
+    Ok(parse_smaps_private_kb(&contents) * 1024)
+}
+
+/// Sum the Private_Clean and Private_Dirty fields (in kB) of smaps-formatted
+/// `contents`.
+fn parse_smaps_private_kb(contents: &[u8]) -> usize {
+    let mut total = 0;
+    for line in contents.split(|&c| c == b'\n') {
+        let field = if line.starts_with(b"Private_Clean:") {
+            &line[b"Private_Clean:".len()..]
+        } else if line.starts_with(b"Private_Dirty:") {
+            &line[b"Private_Dirty:".len()..]
+        } else {
+            continue;
+        };
+        let kb = field
+            .split(|&c| c == b' ' || c == b'\t')
+            .find(|s| !s.is_empty())
+            .and_then(|s| str::from_utf8(s).ok())
+            .and_then(|s| s.parse::<usize>().ok());
+        total += kb.unwrap_or(0);
+    }
+    total
+}
+
 /// Returns true if we succeeded, false if we failed because the
 /// requested CPU does not exist/is not available.
 pub fn set_cpu_affinity(cpu: u32) -> bool {
@@ -1546,24 +1651,6 @@ pub fn cpuid_faulting_works() -> bool {
     *CPUID_FAULTING_WORKS
 }
 
-pub fn cpuid_compatible(trace_records: &[CPUIDRecord]) -> bool {
-    // We could compare all CPUID records but that might be fragile (it's hard to
-    // be sure the values don't change in ways applications don't care about).
-    // Let's just check the microarch for now.
-    let cpuid_data = cpuid(CPUID_GETFEATURES, 0);
-    let cpu_type: u32 = cpuid_data.eax & 0xF0FF0;
-    let maybe_trace_cpuid_data = find_cpuid_record(trace_records, CPUID_GETFEATURES, 0);
-    match maybe_trace_cpuid_data {
-        None => {
-            fatal!("GETFEATURES missing???");
-        }
-        Some(trace_cpuid_data) => {
-            let trace_cpu_type: u32 = trace_cpuid_data.out.eax & 0xF0FF0;
-            cpu_type == trace_cpu_type
-        }
-    }
-}
-
 pub fn has_effective_caps(mut caps: u64) -> bool {
     let header = native_arch::cap_header {
         version: _LINUX_CAPABILITY_VERSION_3 as _,
@@ -1646,9 +1733,47 @@ pub fn is_proc_mem_file(filename_os: &OsStr) -> bool {
     filename.starts_with(b"/proc/") && filename.ends_with(b"/mem")
 }
 
+pub fn is_dev_random(filename_os: &OsStr) -> bool {
+    let filename = filename_os.as_bytes();
+    filename == b"/dev/urandom" || filename == b"/dev/random"
+}
+
 pub fn is_proc_fd_dir(filename_os: &OsStr) -> bool {
     let filename = filename_os.as_bytes();
-    filename.starts_with(b"/proc/") && (filename.ends_with(b"/fd") || filename.ends_with(b"/fd/"))
+    filename.starts_with(b"/proc/")
+        && (filename.ends_with(b"/fd")
+            || filename.ends_with(b"/fd/")
+            || filename.ends_with(b"/fdinfo")
+            || filename.ends_with(b"/fdinfo/"))
+}
+
+/// If `filename_os` is of the form /proc/{self,thread-self,<pid>}/fdinfo/<fd>
+/// return the pid and fd. `self` and `thread-self` resolve to `tgid` and `tid`.
+pub fn parse_proc_fdinfo_file(
+    filename_os: &OsStr,
+    tgid: pid_t,
+    tid: pid_t,
+) -> Option<(pid_t, i32)> {
+    let filename = filename_os.as_bytes();
+    if !filename.starts_with(b"/proc/") {
+        return None;
+    }
+    let mut parts = filename[b"/proc/".len()..].split(|&c| c == b'/');
+    let pid = parts.next()?;
+    if parts.next()? != b"fdinfo" {
+        return None;
+    }
+    let fd = parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let pid = match pid {
+        b"self" => tgid,
+        b"thread-self" => tid,
+        _ => String::from_utf8_lossy(pid).parse::<pid_t>().ok()?,
+    };
+    let fd = String::from_utf8_lossy(fd).parse::<i32>().ok()?;
+    Some((pid, fd))
 }
 
 pub fn check_for_leaks() {
@@ -2388,6 +2513,69 @@ mod tests {
         assert_eq!(maybe_num.unwrap(), 0);
         assert_eq!(b"mango", sl);
     }
+
+    #[test]
+    fn parse_proc_fdinfo_file_test() {
+        let parse = |f: &str| parse_proc_fdinfo_file(OsStr::new(f), 100, 101);
+        assert_eq!(Some((123, 4)), parse("/proc/123/fdinfo/4"));
+        assert_eq!(None, parse("/proc/123/fdinfo"));
+        assert_eq!(None, parse("/proc/123/fd/4"));
+        assert_eq!(Some((100, 4)), parse("/proc/self/fdinfo/4"));
+        assert_eq!(Some((101, 4)), parse("/proc/thread-self/fdinfo/4"));
+        assert_eq!(None, parse("/proc/other/fdinfo/4"));
+        assert_eq!(None, parse("/tmp/123/fdinfo/4"));
+    }
+
+    #[test]
+    fn parse_smaps_private_kb_test() {
+        let smaps = b"55d0a4a2b000-7ffd2b5f5000 ---p 00000000 00:00 0   [rollup]\n\
+Rss:                1168 kB\n\
+Pss:                 204 kB\n\
+Shared_Clean:        964 kB\n\
+Shared_Dirty:          0 kB\n\
+Private_Clean:        80 kB\n\
+Private_Dirty:       124 kB\n\
+Referenced:         1168 kB\n";
+        assert_eq!(204, parse_smaps_private_kb(smaps));
+        assert_eq!(0, parse_smaps_private_kb(b""));
+    }
+
+    #[test]
+    fn is_locale_file_test() {
+        assert!(is_locale_file(OsStr::new("/usr/lib/locale/locale-archive")));
+        assert!(is_locale_file(OsStr::new(
+            "/usr/lib/locale/C.UTF-8/LC_CTYPE"
+        )));
+        assert!(is_locale_file(OsStr::new(
+            "/usr/share/locale/de/LC_MESSAGES/coreutils.mo"
+        )));
+        assert!(!is_locale_file(OsStr::new("/usr/lib/libc.so.6")));
+        assert!(!is_locale_file(OsStr::new("/usr/lib/localed")));
+    }
+
+    #[test]
+    fn extract_clone3_parameters_test() {
+        let args = clone_args {
+            flags: (CLONE_VM | CLONE_SETTLS | CLONE_CHILD_CLEARTID) as u64 | CLONE_CLEAR_SIGHAND,
+            child_tid: 0x1000,
+            parent_tid: 0x2000,
+            stack: 0x10000,
+            stack_size: 0x4000,
+            tls: 0x3000,
+            ..Default::default()
+        };
+        let params = extract_clone3_parameters(&args);
+        assert_eq!(params.stack, RemotePtr::from(0x14000usize));
+        assert_eq!(params.ctid, RemotePtr::from(0x1000usize));
+        assert_eq!(params.tls, RemotePtr::from(0x3000usize));
+        // No CLONE_PARENT_SETTID
+        assert!(params.ptid.is_null());
+
+        let params = extract_clone3_parameters(&Default::default());
+        assert!(params.stack.is_null());
+        assert!(params.ctid.is_null());
+        assert!(params.tls.is_null());
+    }
 }
 
 /// Setting these causes us to trace instructions after
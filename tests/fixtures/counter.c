@@ -0,0 +1,17 @@
+/* Recorded by tests/gdb_integration.rs. */
+#include <stdio.h>
+
+static int counter;
+
+int square(int x) { return x * x; }
+
+void bump(void) { counter++; }
+
+int main(void) {
+  int i;
+  for (i = 0; i < 5; i++) {
+    bump();
+  }
+  printf("counter=%d\n", counter);
+  return 0;
+}
@@ -0,0 +1,41 @@
+/* Recorded by tests/gdb_integration.rs. Reads code into a mapping big enough
+   for replay to page the read's recorded data in on demand (see
+   src/lazy_mappings.rs), then runs it. */
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/mman.h>
+#include <unistd.h>
+
+#define CODE_ADDR ((void*)0x70000000)
+#define CODE_SIZE (16 * 1024 * 1024)
+
+/* mov $42, %eax; ret */
+static const unsigned char code[] = { 0xb8, 0x2a, 0x00, 0x00, 0x00, 0xc3 };
+
+void code_mapped(void) {}
+
+int main(void) {
+  char name[] = "/tmp/rd-lazy-mapping-test-XXXXXX";
+  int fd = mkstemp(name);
+  void* p;
+  int (*f)(void);
+
+  if (fd < 0 || write(fd, code, sizeof(code)) != sizeof(code) ||
+      ftruncate(fd, CODE_SIZE) < 0 || lseek(fd, 0, SEEK_SET) < 0) {
+    return 1;
+  }
+  unlink(name);
+  p = mmap(CODE_ADDR, CODE_SIZE, PROT_READ | PROT_WRITE | PROT_EXEC,
+           MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0);
+  if (p != CODE_ADDR) {
+    return 1;
+  }
+  code_mapped();
+  if (read(fd, p, CODE_SIZE) != CODE_SIZE) {
+    return 1;
+  }
+  f = (int (*)(void))p;
+  printf("result=%d\n", f());
+  return 0;
+}
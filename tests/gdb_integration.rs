@@ -0,0 +1,125 @@
+//! End-to-end tests of the gdb server: replay fixture programs under the real
+//! gdb (see harness/mod.rs) and check what gdb prints.
+
+mod harness;
+
+use harness::{assert_in_order, Recording};
+
+#[test]
+fn breakpoints() {
+    let rec = match Recording::new("counter") {
+        Some(rec) => rec,
+        None => return,
+    };
+    let out = rec.debug(&[
+        "break bump",
+        "continue",
+        "continue",
+        "continue",
+        "print counter",
+        "delete",
+        "continue",
+    ]);
+    assert_in_order(
+        &out,
+        &[
+            "Breakpoint 1 at",
+            "Breakpoint 1, bump",
+            "$1 = 2",
+            "counter=5",
+        ],
+    );
+}
+
+#[test]
+fn reverse_execution() {
+    let rec = match Recording::new("counter") {
+        Some(rec) => rec,
+        None => return,
+    };
+    let out = rec.debug(&[
+        "break bump",
+        "continue",
+        "continue",
+        "continue",
+        "print counter",
+        "reverse-continue",
+        "print counter",
+        "reverse-finish",
+        "reverse-stepi",
+        "print counter",
+    ]);
+    assert_in_order(&out, &["$1 = 2", "$2 = 1", "$3 = 1"]);
+}
+
+#[test]
+fn checkpoints() {
+    let rec = match Recording::new("counter") {
+        Some(rec) => rec,
+        None => return,
+    };
+    let out = rec.debug(&[
+        "break bump",
+        "continue",
+        "checkpoint",
+        "continue",
+        "continue",
+        "print counter",
+        "restart 1",
+        "print counter",
+    ]);
+    assert_in_order(&out, &["Checkpoint 1 at", "$1 = 2", "$2 = 0"]);
+}
+
+#[test]
+fn diversion_calls() {
+    let rec = match Recording::new("counter") {
+        Some(rec) => rec,
+        None => return,
+    };
+    let out = rec.debug(&[
+        "break bump",
+        "continue",
+        "call square(7)",
+        // The call ran in a diversion: the replay itself is untouched.
+        "print counter",
+        "continue",
+        "print counter",
+    ]);
+    assert_in_order(&out, &["$1 = 49", "$2 = 0", "$3 = 1"]);
+}
+
+#[test]
+fn rd_commands() {
+    let rec = match Recording::new("counter") {
+        Some(rec) => rec,
+        None => return,
+    };
+    let out = rec.debug(&[
+        "break bump",
+        "continue",
+        "when",
+        "print $rd_event > 0",
+        "when-ticks",
+    ]);
+    assert_in_order(&out, &["Current event: ", "$1 = 1", "Current tick: "]);
+}
+
+#[test]
+fn breakpoint_in_lazy_mapping() {
+    let rec = match Recording::new("lazy_mapping") {
+        Some(rec) => rec,
+        None => return,
+    };
+    // The breakpoint is set before replay maps the read's data in on demand,
+    // which must not lose it.
+    let out = rec.debug(&[
+        "break code_mapped",
+        "continue",
+        "continue",
+        "break *0x70000000",
+        "continue",
+        "print $pc",
+    ]);
+    assert_in_order(&out, &["Breakpoint 2, 0x0000000070000000", "$1 ="]);
+}
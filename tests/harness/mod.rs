@@ -0,0 +1,173 @@
+//! Record fixture programs with rd and replay them under the real gdb.
+//!
+//! A `Recording` compiles `tests/fixtures/<name>.c`, records it into a
+//! scratch directory and can then replay it any number of times with
+//! `Recording::debug`. That runs `rd replay`, which launches gdb itself and
+//! hands it the server's address through the params pipe, exactly as for a
+//! user. gdb runs in batch mode: it executes the given commands and exits,
+//! which ends the replay.
+//!
+//! Recording needs a C compiler and usable perf counters and debugging needs
+//! gdb. When one of those is missing `Recording::new` returns `None` after
+//! saying why, and tests should return without checking anything. Tests that
+//! never run gdb use `Recording::without_gdb`, which doesn't need it. Any
+//! other recording failure is a bug and fails the test.
+
+// Each test crate uses a different part of the harness.
+#![allow(dead_code)]
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const RD: &str = env!("CARGO_BIN_EXE_rd");
+
+/// What `rd record` prints when it fails because the perf counters it found
+/// can't be used (see perf_counters.rs). Machines without any PMU fall back to
+/// --no-pmu by themselves, so this is e.g. perf_event_paranoid or an unknown CPU.
+const PMU_UNAVAILABLE: &[&str] = &[
+    "Permission denied to use 'perf_event_open'",
+    "Unable to open performance counter",
+    "Failed to open performance counter",
+    "Failed to initialize counter",
+    "Unknown CPU vendor",
+    "AMD CPUs not supported",
+    "unknown. If it's a variant of a known",
+    "currently unsupported",
+];
+
+pub struct Recording {
+    scratch: PathBuf,
+    trace_dir: PathBuf,
+}
+
+impl Recording {
+    /// Record `fixture` for tests that debug it with `debug`.
+    pub fn new(fixture: &str) -> Option<Recording> {
+        Recording::record(fixture, &["cc", "gdb"])
+    }
+
+    /// Record `fixture` for tests that only use the trace, not gdb.
+    pub fn without_gdb(fixture: &str) -> Option<Recording> {
+        Recording::record(fixture, &["cc"])
+    }
+
+    fn record(fixture: &str, tools: &[&str]) -> Option<Recording> {
+        for tool in tools {
+            if !runs(Command::new(tool).arg("--version")) {
+                eprintln!("skipping: `{}` isn't available", tool);
+                return None;
+            }
+        }
+
+        static NONCE: AtomicUsize = AtomicUsize::new(0);
+        let scratch = env::temp_dir().join(format!(
+            "rd-gdb-test-{}-{}-{}",
+            fixture,
+            std::process::id(),
+            NONCE.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&scratch).unwrap();
+        let recording = Recording {
+            trace_dir: scratch.join("trace"),
+            scratch,
+        };
+
+        let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(format!("{}.c", fixture));
+        let exe = recording.scratch.join(fixture);
+        let compiled = Command::new("cc")
+            .args(&["-g", "-O0", "-o"])
+            .arg(&exe)
+            .arg(&source)
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "Compiling {:?} failed:\n{}",
+            source,
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+
+        let recorded = recording
+            .rd()
+            .arg("record")
+            .arg("-o")
+            .arg(&recording.trace_dir)
+            .arg(&exe)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&recorded.stderr);
+        if !recorded.status.success() {
+            if PMU_UNAVAILABLE.iter().any(|e| stderr.contains(e)) {
+                eprintln!("skipping: perf counters are unusable here:\n{}", stderr);
+                return None;
+            }
+            panic!("`rd record` failed:\n{}", stderr);
+        }
+        Some(recording)
+    }
+
+    /// Replay under gdb, run `commands` and return everything gdb printed.
+    /// The first plain `continue` connects gdb to the replay, so commands
+    /// before it can only set things up (e.g. breakpoints).
+    pub fn debug(&self, commands: &[&str]) -> String {
+        let mut cmd = self.rd();
+        cmd.arg("replay")
+            .arg(&self.trace_dir)
+            .args(&["--", "-batch", "-nx"]);
+        for c in commands {
+            cmd.arg("-ex").arg(c);
+        }
+        let out = cmd.output().unwrap();
+        let text = output_text(&out);
+        assert!(out.status.success(), "rd replay failed:\n{}", text);
+        text
+    }
+
+    pub fn trace_dir(&self) -> &Path {
+        &self.trace_dir
+    }
+
+    fn rd(&self) -> Command {
+        let mut cmd = Command::new(RD);
+        // Keep `latest-trace` and temporary files out of the user's directories.
+        cmd.env("RD_TRACE_DIR", self.scratch.join("save"))
+            .env("RD_TMPDIR", &self.scratch);
+        cmd
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.scratch);
+    }
+}
+
+/// Assert that `needles` appear in `haystack` in this order.
+pub fn assert_in_order(haystack: &str, needles: &[&str]) {
+    let mut rest = haystack;
+    for needle in needles {
+        match rest.find(needle) {
+            Some(i) => rest = &rest[i + needle.len()..],
+            None => panic!(
+                "Expected {:?} (in order {:?}) in gdb output:\n{}",
+                needle, needles, haystack
+            ),
+        }
+    }
+}
+
+fn runs(cmd: &mut Command) -> bool {
+    cmd.output().map_or(false, |out| out.status.success())
+}
+
+fn output_text(out: &Output) -> String {
+    let mut text = String::from_utf8_lossy(&out.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&out.stderr));
+    text
+}
@@ -0,0 +1,38 @@
+//! Replay through the `rd` library (src/replay.rs) from outside the crate,
+//! the way an embedding program would.
+
+mod harness;
+
+use harness::Recording;
+use rd::{flags::Flags, replay::ReplaySessionBuilder};
+use std::path::Path;
+
+#[test]
+fn replay_to_the_end() {
+    let rec = match Recording::without_gdb("counter") {
+        Some(rec) => rec,
+        None => return,
+    };
+    // This test isn't installed next to rd's helper binaries; the rd binary is.
+    let rd_dir = Path::new(env!("CARGO_BIN_EXE_rd")).parent().unwrap();
+    assert!(Flags::init(Flags {
+        resource_path: Some(rd_dir.join("../")),
+        ..Flags::default()
+    }));
+
+    let mut replayer = ReplaySessionBuilder::new()
+        .trace_dir(rec.trace_dir())
+        .build();
+    let mut last_event = replayer.current_frame_time();
+    let mut saw_registers = false;
+    while replayer.step().is_some() {
+        let event = replayer.current_frame_time();
+        assert!(event >= last_event);
+        last_event = event;
+        saw_registers |= replayer.registers().is_some();
+    }
+    assert!(saw_registers);
+    assert!(last_event > 1);
+    // Once every tracee has exited there's nothing left to step.
+    assert!(replayer.step().is_none());
+}